@@ -0,0 +1,5 @@
+fn main() {
+    // generates the gRPC service in proto/dsr.proto, which needs protoc on the PATH or in PROTOC
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/dsr.proto").expect("couldn't compile proto/dsr.proto");
+}
@@ -0,0 +1,49 @@
+/* The C ABI of dsr, built as a shared library with
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ * Every call blocks until it's done. A handle can be used from one thread at a time. */
+#ifndef DSR_H
+#define DSR_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call worked */
+#define DS_OK 0
+/* There's no live record at the key */
+#define DS_NOT_FOUND 1
+/* The call failed, and ds_last_error says why */
+#define DS_ERROR (-1)
+
+typedef struct DsStore DsStore;
+
+/* Open, creating it if needed, a store in the datastore at path, which can be a file name, a
+ * sqlite: URL or memory: for one in memory. Returns NULL on failure. */
+DsStore *ds_open(const char *path, const char *store);
+
+/* Close a store from ds_open. NULL is ignored. */
+void ds_close(DsStore *store);
+
+/* Get the value of a record, pointing value at a NUL terminated copy of it that's freed with
+ * ds_free and setting len, if it isn't NULL, to its length. Returns DS_OK, DS_NOT_FOUND or DS_ERROR. */
+int ds_get(const DsStore *store, const char *key, char **value, size_t *len);
+
+/* Set a record to len bytes of value, kept as text if they're valid UTF-8. Returns DS_OK or DS_ERROR. */
+int ds_set(const DsStore *store, const char *key, const char *value, size_t len);
+
+/* Delete a record, if there is one. Returns DS_OK or DS_ERROR. */
+int ds_del(const DsStore *store, const char *key);
+
+/* Free a value from ds_get. NULL is ignored. */
+void ds_free(char *value);
+
+/* Why the last call on this thread that failed did, valid until the next one fails. NULL if none has. */
+const char *ds_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,113 @@
+use std::{fmt, str::FromStr};
+use sqlx::{sqlite::SqliteConnection, QueryBuilder, Row};
+use crate::{KVLite, DsError};
+
+/// What a client of a served store may do with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Read records, but not set or delete them
+    ReadOnly,
+    ReadWrite,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::ReadOnly => write!(f, "read-only"),
+            Role::ReadWrite => write!(f, "read-write"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "read-only" | "ro" | "read" => Ok(Role::ReadOnly),
+            "read-write" | "rw" | "write" => Ok(Role::ReadWrite),
+            _ => Err(format!("unknown role {}, expected read-only or read-write", s)),
+        }
+    }
+}
+
+/// Backends a client named in an acl can be authenticated by, as the prefix of its name
+const BACKENDS: [&str; 3] = ["token:", "user:", "jwt:"];
+
+/// Whether a name in an acl says which backend authenticates the client, like token:<name>,
+/// user:<name> or jwt:<subject>, so a token and a user with the same name aren't the same client
+pub(crate) fn qualified(name: &str) -> bool {
+    BACKENDS.iter().any(|backend| name.strip_prefix(backend).is_some_and(|name| !name.is_empty()))
+}
+
+impl KVLite {
+    async fn create_acl_table(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, role TEXT NOT NULL)
+            "#, self.kv_name.object("acl")))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// The clients granted a role on the store when it's served, as token:<name>, user:<name> or
+    /// jwt:<subject>, in name order. When there are any, clients without a role are refused.
+    pub async fn acl(&self) -> Result<Vec<(String, Role)>, DsError> {
+        let acl = self.kv_name.object("acl");
+        let mut conn = self.pool.acquire().await?;
+        let exists = self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(acl.name.as_str())
+            .build())
+            .await?
+            .is_some();
+        if !exists {
+            return Ok(vec![]);
+        }
+        self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT name, role FROM {} ORDER BY name
+            "#, acl))
+            .build())
+            .await?
+            .iter()
+            .map(|row| {
+                let role = row.get::<String, &str>("role").parse().map_err(|e: String| DsError::Sql(sqlx::Error::Decode(e.into())))?;
+                Ok((row.get("name"), role))
+            })
+            .collect()
+    }
+
+    /// Give a client a role on the store, replacing any it had. The client is named with the
+    /// backend that authenticates it, as token:<name>, user:<name> or jwt:<subject>.
+    pub async fn grant(&self, name: &str, role: Role) -> Result<(), DsError> {
+        if !qualified(name) {
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!("{} doesn't say how the client is authenticated, expected token:<name>, user:<name> or jwt:<subject>", name).into())));
+        }
+        let mut conn = self.pool.acquire().await?;
+        self.create_acl_table(&mut conn).await?;
+        self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (name, role) VALUES (
+            "#, self.kv_name.object("acl")))
+            .push_bind(name)
+            .push(",")
+            .push_bind(role.to_string())
+            .push(") ON CONFLICT (name) DO UPDATE SET role = excluded.role")
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Take away a client's role on the store, returning whether it had one
+    pub async fn revoke(&self, name: &str) -> Result<bool, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_acl_table(&mut conn).await?;
+        Ok(self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE name =
+            "#, self.kv_name.object("acl")))
+            .push_bind(name)
+            .build())
+            .await?
+            .rows_affected() > 0)
+    }
+}
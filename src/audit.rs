@@ -0,0 +1,203 @@
+use std::{ffi::{c_char, c_int}, ptr, slice, time::SystemTime};
+use libsqlite3_sys as ffi;
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::{SqliteConnection, SqliteRow}, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Table, history::epoch_secs, to_time};
+
+/// A write to an audited store, as its trigger recorded it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Entries count up from 1 in the order they were written
+    pub seq: i64,
+    pub at: SystemTime,
+    pub kind: ChangeKind,
+    pub key: String,
+    /// Hex SHA-256 of the value the write replaced or removed, None if the key didn't exist
+    pub old_hash: Option<String>,
+    /// Source of the write, which deletes don't have
+    pub source: Option<String>,
+    /// The user and process that wrote it
+    pub user: Option<String>,
+    pub pid: Option<u32>,
+}
+
+/// Register the functions audit triggers call with a new connection. Writes to an audited store
+/// fail on connections without them, so only dsr can write to one.
+pub(crate) async fn register_functions(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    type Function = unsafe extern "C" fn(*mut ffi::sqlite3_context, c_int, *mut *mut ffi::sqlite3_value);
+    let functions: [(&[u8], c_int, c_int, Function); 3] = [
+        (b"dsr_sha256\0", 1, ffi::SQLITE_DETERMINISTIC, sha256),
+        (b"dsr_user\0", 0, 0, user),
+        (b"dsr_pid\0", 0, 0, pid),
+    ];
+    let mut handle = conn.lock_handle().await?;
+    let db = handle.as_raw_handle().as_ptr();
+    for (name, args, flags, function) in functions {
+        // SAFETY: the handle is locked for the call, the names are NUL terminated and static, and
+        // the functions take no user data to free
+        let code = unsafe {
+            ffi::sqlite3_create_function_v2(db, name.as_ptr() as *const c_char, args, ffi::SQLITE_UTF8 | flags, ptr::null_mut(), Some(function), None, None, None)
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(sqlx::Error::Configuration(format!("couldn't register the audit functions, error {}", code).into()));
+        }
+    }
+    Ok(())
+}
+
+/// Hex SHA-256 of a value's bytes, NULL for NULL
+unsafe extern "C" fn sha256(ctx: *mut ffi::sqlite3_context, _: c_int, argv: *mut *mut ffi::sqlite3_value) {
+    let value = *argv;
+    if ffi::sqlite3_value_type(value) == ffi::SQLITE_NULL {
+        ffi::sqlite3_result_null(ctx);
+        return;
+    }
+    // the pointer must be read before the length, text is converted to a blob by the first
+    let data = ffi::sqlite3_value_blob(value) as *const u8;
+    let len = ffi::sqlite3_value_bytes(value) as usize;
+    let bytes = match data.is_null() {
+        true => &[][..],
+        false => slice::from_raw_parts(data, len),
+    };
+    let hash: String = Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect();
+    ffi::sqlite3_result_text(ctx, hash.as_ptr() as *const c_char, hash.len() as c_int, ffi::SQLITE_TRANSIENT());
+}
+
+/// The user running the process, NULL if it isn't known
+unsafe extern "C" fn user(ctx: *mut ffi::sqlite3_context, _: c_int, _: *mut *mut ffi::sqlite3_value) {
+    match std::env::var("USER").or_else(|_| std::env::var("USERNAME")) {
+        Ok(user) => ffi::sqlite3_result_text(ctx, user.as_ptr() as *const c_char, user.len() as c_int, ffi::SQLITE_TRANSIENT()),
+        Err(_) => ffi::sqlite3_result_null(ctx),
+    }
+}
+
+unsafe extern "C" fn pid(ctx: *mut ffi::sqlite3_context, _: c_int, _: *mut *mut ffi::sqlite3_value) {
+    ffi::sqlite3_result_int64(ctx, std::process::id() as i64);
+}
+
+impl KVLite {
+    fn audit_table(&self) -> Table {
+        self.kv_name.object("audit")
+    }
+
+    /// Whether every write to the store is recorded in its audit log
+    pub async fn is_audited(&self) -> Result<bool, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="trigger" AND name=
+            "#)
+            .push_bind(self.kv_name.object("audit_insert").name)
+            .build())
+            .await?
+            .is_some())
+    }
+
+    /// Start recording every write to the store in an audit log, with when it was made, by which
+    /// user, process and source, and a hash of the value it replaced. It's kept by triggers, so
+    /// writes from every process are recorded. Turning auditing off keeps the log that was recorded.
+    pub async fn set_audited(&self, audited: bool) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let sql = match audited {
+            true => format!(r#"
+                    CREATE TABLE IF NOT EXISTS {audit} (
+                        seq INTEGER PRIMARY KEY AUTOINCREMENT, at INTEGER NOT NULL, kind TEXT NOT NULL,
+                        key TEXT NOT NULL COLLATE {collation}, old_hash TEXT, source TEXT, user TEXT, pid INTEGER
+                    );
+                    CREATE TRIGGER IF NOT EXISTS {insert} AFTER INSERT ON {table} BEGIN
+                        INSERT INTO {audit} (at, kind, key, source, user, pid)
+                            VALUES ({now}, 'set', NEW.key, NEW.source, dsr_user(), dsr_pid());
+                    END;
+                    CREATE TRIGGER IF NOT EXISTS {update} AFTER UPDATE ON {table} BEGIN
+                        INSERT INTO {audit} (at, kind, key, old_hash, user, pid)
+                            SELECT {now}, 'delete', OLD.key, dsr_sha256(OLD.value), dsr_user(), dsr_pid() WHERE OLD.key IS NOT NEW.key;
+                        INSERT INTO {audit} (at, kind, key, old_hash, source, user, pid)
+                            VALUES ({now}, 'set', NEW.key, CASE WHEN OLD.key IS NEW.key THEN dsr_sha256(OLD.value) END, NEW.source, dsr_user(), dsr_pid());
+                    END;
+                    CREATE TRIGGER IF NOT EXISTS {delete} AFTER DELETE ON {table} BEGIN
+                        INSERT INTO {audit} (at, kind, key, old_hash, user, pid)
+                            VALUES ({now}, CASE WHEN OLD.expires_at <= {now} THEN 'expired' ELSE 'delete' END, OLD.key, dsr_sha256(OLD.value), dsr_user(), dsr_pid());
+                    END;
+                "#,
+                audit = self.audit_table().unqualified(),
+                collation = self.collation.sql(),
+                table = self.kv_name,
+                insert = self.kv_name.object("audit_insert"),
+                update = self.kv_name.object("audit_update"),
+                delete = self.kv_name.object("audit_delete"),
+                now = "CAST(strftime('%s', 'now') AS INTEGER)"),
+            false => format!(r#"
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                "#, self.kv_name.object("audit_insert"), self.kv_name.object("audit_update"), self.kv_name.object("audit_delete")),
+        };
+        self.execute(&mut conn, QueryBuilder::new(sql).build()).await?;
+        Ok(())
+    }
+
+    fn audit_entry(row: &SqliteRow) -> AuditEntry {
+        AuditEntry {
+            seq: row.get("seq"),
+            at: to_time(row.get("at")),
+            kind: row.get::<&str, &str>("kind").parse().unwrap_or(ChangeKind::Set),
+            key: row.get("key"),
+            old_hash: row.get("old_hash"),
+            source: row.get("source"),
+            user: row.get("user"),
+            pid: row.get::<Option<i64>, &str>("pid").map(|pid| pid as u32),
+        }
+    }
+
+    /// The entries of the store's audit log, oldest first, for one key or all of them, made since a
+    /// time. With a limit only the latest entries are returned. Empty if the store was never audited.
+    pub async fn audit_log(&self, key: Option<&str>, since: Option<SystemTime>, limit: Option<u64>) -> Result<Vec<AuditEntry>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if !self.has_audit_log(&mut conn).await? {
+            return Ok(vec![]);
+        }
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT * FROM {} WHERE true
+            "#, self.audit_table()));
+        if let Some(key) = key {
+            query.push(" AND key = ").push_bind(self.canonical_key(key).into_owned());
+        }
+        if let Some(since) = since {
+            query.push(" AND at >= ").push_bind(epoch_secs(since));
+        }
+        query.push(" ORDER BY seq DESC");
+        if let Some(limit) = limit {
+            query.push(" LIMIT ").push_bind(limit as i64);
+        }
+        let mut entries: Vec<AuditEntry> = self.fetch_all(&mut conn, query.build()).await?
+            .iter()
+            .map(KVLite::audit_entry)
+            .collect();
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Delete the entries of the store's audit log made before a time, returning how many were deleted
+    pub async fn prune_audit(&self, before: SystemTime) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if !self.has_audit_log(&mut conn).await? {
+            return Ok(0);
+        }
+        Ok(self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE at <
+            "#, self.audit_table()))
+            .push_bind(epoch_secs(before))
+            .build())
+            .await?
+            .rows_affected())
+    }
+
+    async fn has_audit_log(&self, conn: &mut SqliteConnection) -> Result<bool, DsError> {
+        Ok(self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(self.audit_table().name)
+            .build())
+            .await?
+            .is_some())
+    }
+}
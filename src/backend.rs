@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, future::Future, sync::Mutex, time::SystemTime};
+use sqlx::Row;
+use crate::{KVLite, DsError, Filter, ListOptions, SetOptions, glob_match};
+
+/// The record operations every backend for a datastore has, so code written against them runs on
+/// KVLite or on MemoryBackend without a file. Everything else KVLite does is SQLite's alone.
+pub trait KvBackend: Send + Sync {
+    /// The value of a live record, NotFound if there isn't one
+    fn get_bytes(&self, key: &str) -> impl Future<Output = Result<Vec<u8>, DsError>> + Send;
+
+    /// Set a record, with a TTL if the options have one and checked against their tag
+    fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> impl Future<Output = Result<(), DsError>> + Send;
+
+    /// Delete a record, whether or not it exists
+    fn del(&self, key: &str) -> impl Future<Output = Result<(), DsError>> + Send;
+
+    fn contains(&self, key: &str) -> impl Future<Output = Result<bool, DsError>> + Send;
+
+    /// The keys of live records matching a filter, in key order, at most limit of them
+    fn list_keys(&self, filter: &Filter, limit: Option<u64>) -> impl Future<Output = Result<Vec<String>, DsError>> + Send;
+}
+
+impl KvBackend for KVLite {
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        self.get_bytes(key).await
+    }
+
+    async fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        self.set_with(key, value, options).await
+    }
+
+    async fn del(&self, key: &str) -> Result<(), DsError> {
+        self.del(key).await
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, DsError> {
+        self.contains(key).await
+    }
+
+    async fn list_keys(&self, filter: &Filter, limit: Option<u64>) -> Result<Vec<String>, DsError> {
+        let options = ListOptions { sort: true, limit, filter: filter.clone(), ..Default::default() };
+        Ok(self.keys(&options).await?.iter().map(|row| row.get("key")).collect())
+    }
+}
+
+struct MemoryRecord {
+    value: Vec<u8>,
+    expires_at: Option<SystemTime>,
+}
+
+impl MemoryRecord {
+    fn is_live(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > SystemTime::now())
+    }
+}
+
+/// A backend keeping its records in a map for as long as it lives, for tests that want a store's
+/// semantics without touching disk. Expired records are dropped as they're found.
+#[derive(Default)]
+pub struct MemoryBackend {
+    records: Mutex<BTreeMap<String, MemoryRecord>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    fn records(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, MemoryRecord>> {
+        // a panic while holding the lock can't leave the map half written
+        self.records.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl KvBackend for MemoryBackend {
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        let mut records = self.records();
+        match records.get(key) {
+            Some(record) if record.is_live() => Ok(record.value.clone()),
+            Some(_) => {
+                records.remove(key);
+                Err(DsError::NotFound)
+            },
+            None => Err(DsError::NotFound),
+        }
+    }
+
+    async fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        if let Some(tag) = options.tag {
+            tag.check(value)?;
+        }
+        let expires_at = options.ttl.map(|ttl| SystemTime::now() + ttl);
+        self.records().insert(key.to_string(), MemoryRecord { value: value.to_vec(), expires_at });
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<(), DsError> {
+        self.records().remove(key);
+        Ok(())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, DsError> {
+        match KvBackend::get_bytes(self, key).await {
+            Ok(_) => Ok(true),
+            Err(DsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list_keys(&self, filter: &Filter, limit: Option<u64>) -> Result<Vec<String>, DsError> {
+        let mut records = self.records();
+        records.retain(|_, record| record.is_live());
+        let matches = |key: &str, record: &MemoryRecord| {
+            filter.prefix.as_ref().is_none_or(|prefix| key.starts_with(prefix.as_str()))
+                && filter.glob.as_ref().is_none_or(|glob| glob_match(glob, key))
+                && filter.value_contains.as_ref().is_none_or(|contains| {
+                    contains.is_empty() || record.value.windows(contains.len()).any(|window| window == contains.as_bytes())
+                })
+        };
+        Ok(records.iter()
+            .filter(|(key, record)| matches(key, record))
+            .map(|(key, _)| key.clone())
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .collect())
+    }
+}
@@ -0,0 +1,143 @@
+use std::{ffi::{CStr, CString}, fs::{self, File}, io::{self, BufReader, BufWriter, Read}, path::{Path, PathBuf}, ptr, thread, time::Duration};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use libsqlite3_sys as ffi;
+use crate::{KVLite, DsError};
+
+/// Pages copied at a time, letting other connections write to the datastore in between
+const PAGES_PER_STEP: i32 = 256;
+/// How long to wait before trying again when another connection holds a lock
+const STEP_WAIT: Duration = Duration::from_millis(10);
+/// How long another connection can hold a lock before the backup or restore gives up with StoreLocked
+const MAX_WAIT: Duration = Duration::from_secs(5);
+/// Bytes every gzip file starts with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+impl KVLite {
+    /// Copy the whole datastore, every store in it, to a file with SQLite's online backup API,
+    /// compressing it with gzip if asked. Other connections can keep writing while it runs, and the
+    /// backup holds the datastore as it was when it finished. The file is only replaced once the
+    /// backup is complete.
+    pub async fn backup(&self, path: &str, gzip: bool) -> Result<(), DsError> {
+        let partial = PathBuf::from(format!("{}.partial", path));
+        let res = {
+            let mut conn = self.pool.acquire().await?;
+            let mut handle = conn.lock_handle().await?;
+            let src = handle.as_raw_handle().as_ptr();
+            Database::open(&partial, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE)
+                .and_then(|dest| copy(src, dest.0, PAGES_PER_STEP))
+        };
+        let res = res.and_then(|_| match gzip {
+            true => {
+                let mut encoder = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+                io::copy(&mut File::open(&partial)?, &mut encoder)?;
+                encoder.finish()?;
+                Ok(fs::remove_file(&partial)?)
+            },
+            false => Ok(fs::rename(&partial, path)?),
+        });
+        if res.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        res
+    }
+
+    /// Replace the whole datastore, every store in it, with a backup, which may be gzip compressed.
+    /// Other connections see the datastore change all at once; stores that aren't in the backup,
+    /// including this one, are gone after.
+    pub async fn restore(&self, path: &str) -> Result<(), DsError> {
+        let mut magic = [0; 2];
+        let gzip = matches!(File::open(path)?.read_exact(&mut magic), Ok(()) if magic == GZIP_MAGIC);
+        let decompressed = std::env::temp_dir().join(format!("dsr-restore-{}.db", std::process::id()));
+        let src = match gzip {
+            true => {
+                io::copy(&mut GzDecoder::new(BufReader::new(File::open(path)?)), &mut File::create(&decompressed)?)?;
+                decompressed.clone()
+            },
+            false => PathBuf::from(path),
+        };
+
+        let res = async {
+            let src = Database::open(&src, ffi::SQLITE_OPEN_READONLY)?;
+            let mut conn = self.pool.acquire().await?;
+            let mut handle = conn.lock_handle().await?;
+            // all at once, so no one sees the datastore half restored
+            copy(src.0, handle.as_raw_handle().as_ptr(), -1)
+        }.await;
+        if gzip {
+            let _ = fs::remove_file(&decompressed);
+        }
+        res
+    }
+}
+
+/// A connection to a database file outside the pool, closed when dropped
+struct Database(*mut ffi::sqlite3);
+
+impl Database {
+    fn open(path: &Path, flags: i32) -> Result<Database, DsError> {
+        let path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|e| DsError::Sql(sqlx::Error::Configuration(Box::new(e))))?;
+        let mut db = ptr::null_mut();
+        // SAFETY: the path is NUL terminated, and the handle is closed by Drop even if opening failed
+        let code = unsafe { ffi::sqlite3_open_v2(path.as_ptr(), &mut db, flags, ptr::null()) };
+        let db = Database(db);
+        match code {
+            ffi::SQLITE_OK => Ok(db),
+            code => Err(error(db.0, code)),
+        }
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from sqlite3_open_v2 and isn't used after this
+        unsafe { ffi::sqlite3_close(self.0) };
+    }
+}
+
+/// Copy the main database of one connection over another's, some pages at a time or all at once
+/// if pages is negative
+fn copy(src: *mut ffi::sqlite3, dest: *mut ffi::sqlite3, pages: i32) -> Result<(), DsError> {
+    let main = c"main";
+    // SAFETY: both handles are open and not used by anything else until this returns
+    unsafe {
+        let backup = ffi::sqlite3_backup_init(dest, main.as_ptr(), src, main.as_ptr());
+        if backup.is_null() {
+            return Err(error(dest, ffi::sqlite3_errcode(dest)));
+        }
+        let mut waited = Duration::ZERO;
+        let code = loop {
+            match ffi::sqlite3_backup_step(backup, pages) {
+                ffi::SQLITE_OK => waited = Duration::ZERO,
+                ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED if waited < MAX_WAIT => {
+                    thread::sleep(STEP_WAIT);
+                    waited += STEP_WAIT;
+                },
+                code => break code,
+            }
+        };
+        ffi::sqlite3_backup_finish(backup);
+        match code {
+            ffi::SQLITE_DONE => Ok(()),
+            code => Err(error(dest, code)),
+        }
+    }
+}
+
+pub(crate) fn error(db: *mut ffi::sqlite3, code: i32) -> DsError {
+    match code & 0xff {
+        ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => DsError::StoreLocked,
+        ffi::SQLITE_READONLY => DsError::ReadOnly,
+        _ => {
+            // SAFETY: sqlite3_errstr always returns a static string, and sqlite3_errmsg one owned
+            // by the handle that lives until its next call
+            let message = unsafe {
+                match db.is_null() {
+                    true => CStr::from_ptr(ffi::sqlite3_errstr(code)),
+                    false => CStr::from_ptr(ffi::sqlite3_errmsg(db)),
+                }
+            };
+            DsError::Sql(sqlx::Error::Configuration(message.to_string_lossy().into_owned().into()))
+        },
+    }
+}
@@ -0,0 +1,107 @@
+use std::{future::{poll_fn, Future}, pin::Pin, task::Poll, time::{Duration, Instant}};
+use crate::{KVLite, DsError};
+
+/// Put in front of the keys bench writes, so they're easy to find if it's interrupted
+pub const BENCH_PREFIX: &str = "dsr-bench/";
+
+/// How bench loads a store
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Records set, read and deleted
+    pub ops: u64,
+    /// Operations in flight at once, each on a connection of its own up to the pool's size
+    pub concurrency: usize,
+    /// Bytes in each value
+    pub value_size: usize,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions { ops: 10_000, concurrency: 1, value_size: 100 }
+    }
+}
+
+/// Throughput and latencies of one kind of operation in a bench run
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    /// set, get or delete
+    pub op: &'static str,
+    pub ops: u64,
+    /// How long all of them took
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl BenchResult {
+    fn new(op: &'static str, elapsed: Duration, mut latencies: Vec<Duration>) -> BenchResult {
+        latencies.sort();
+        let percentile = |p: usize| latencies.get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1))).copied().unwrap_or_default();
+        BenchResult { op, ops: latencies.len() as u64, elapsed, p50: percentile(50), p90: percentile(90), p99: percentile(99), max: percentile(100) }
+    }
+
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl KVLite {
+    /// Set, get and then delete ops records under BENCH_PREFIX, with up to concurrency of each in
+    /// flight at once, and time them. The store is used as it was opened, so runs with different
+    /// journal modes, synchronous settings or pool sizes can be compared. Records already under the
+    /// prefix are overwritten and deleted.
+    pub async fn bench(&self, options: &BenchOptions) -> Result<Vec<BenchResult>, DsError> {
+        let value = "x".repeat(options.value_size);
+        let value = value.as_str();
+        let key = |i: u64| format!("{}{}", BENCH_PREFIX, i);
+        let set = self.bench_op("set", options, |i| async move { self.set(&key(i), value).await }).await?;
+        let get = self.bench_op("get", options, |i| async move { self.get(&key(i)).await.map(drop) }).await?;
+        let delete = self.bench_op("delete", options, |i| async move { self.del(&key(i)).await }).await?;
+        Ok(vec![set, get, delete])
+    }
+
+    /// Run op for each of 0..ops, split between concurrency workers each running one at a time
+    async fn bench_op<F, Fut>(&self, name: &'static str, options: &BenchOptions, op: F) -> Result<BenchResult, DsError>
+        where F: Fn(u64) -> Fut + Copy, Fut: Future<Output = Result<(), DsError>>
+    {
+        let workers = options.concurrency.max(1) as u64;
+        let start = Instant::now();
+        let runs: Vec<_> = (0..workers)
+            .map(|worker| async move {
+                let mut latencies = vec![];
+                for i in (worker..options.ops).step_by(workers as usize) {
+                    let start = Instant::now();
+                    op(i).await?;
+                    latencies.push(start.elapsed());
+                }
+                Ok::<_, DsError>(latencies)
+            })
+            .collect();
+        let mut latencies = vec![];
+        for run in join_all(runs).await {
+            latencies.extend(run?);
+        }
+        Ok(BenchResult::new(name, start.elapsed(), latencies))
+    }
+}
+
+/// Wait for all of some futures, polling them together on this task
+async fn join_all<F: Future>(futures: Vec<F>) -> Vec<F::Output> {
+    let mut futures: Vec<Pin<Box<F>>> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            if output.is_none() {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(done) => *output = Some(done),
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending { Poll::Pending } else { Poll::Ready(()) }
+    }).await;
+    outputs.into_iter().flatten().collect()
+}
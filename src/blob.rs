@@ -0,0 +1,306 @@
+use std::{ffi::CString, fs::{self, File}, io::{self, BufWriter, Read, Write}, path::Path, ptr, time::Duration};
+use libsqlite3_sys as ffi;
+use sqlx::{Connection, QueryBuilder, Row};
+use tokio::{sync::mpsc, time::timeout};
+use tokio_stream::{Stream, StreamExt, wrappers::ReceiverStream};
+use crate::{KVLite, DsError, ChangeKind, RecordMeta, SetOptions, Stored, Table, backup::error};
+
+/// Bytes copied between a file and a value at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long a streamed value's reader can leave a chunk untaken before the stream gives up, so a
+/// stalled HTTP client can't keep a pooled connection and its read transaction open
+const STREAM_DEADLINE: Duration = Duration::from_secs(30);
+
+impl KVLite {
+    /// Whether a value can be written a piece at a time, which needs every trigger on the store
+    /// to be fine with seeing it as zeros first. Only a max value size's are, since they just
+    /// check its length. Values kept in the store's blobs are written whole.
+    async fn is_streamable(&self) -> Result<bool, DsError> {
+        if !self.value_transforms.is_empty() || self.dedup.is_some() {
+            return Ok(false);
+        }
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="trigger" AND sql LIKE '%NEW.value%' AND tbl_name=
+            "#)
+            .push_bind(self.kv_name.name.as_str())
+            .push(" AND name NOT IN (")
+            .push_bind(self.kv_name.object("quota_insert").name)
+            .push(",")
+            .push_bind(self.kv_name.object("quota_update").name)
+            .push(") LIMIT 1")
+            .build())
+            .await?
+            .is_none())
+    }
+
+    /// Set a record from a file, copied into the value a chunk at a time with SQLite's incremental
+    /// BLOB I/O instead of being read into memory first, and stored as a BLOB. Stores that transform
+    /// or deduplicate their values, check their type, or keep versions, checksums, an audit log or a search index
+    /// need the whole value at once, as does a tag, so then the file is read and set as set_with would.
+    pub async fn set_from_file(&self, key: &str, path: &Path, options: &SetOptions) -> Result<(), DsError> {
+        if options.tag.is_some() || !self.is_streamable().await? {
+            return self.set_with(key, &fs::read(path)?, options).await;
+        }
+        let key = &self.canonical_key(key)[..];
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (key, value, expires_at, content_type, created_at, updated_at, tag, source) VALUES (
+            "#, self.kv_name));
+        query.push_bind(key).push(", zeroblob(").push_bind(len as i64).push(")");
+        self.push_upsert(&mut query, &Stored::from_options(options));
+        self.execute(&mut tx, query.build()).await?;
+        let rowid: i64 = self.fetch_one(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT rowid FROM {} WHERE key=
+            "#, self.kv_name))
+            .push_bind(key)
+            .build())
+            .await?
+            .get("rowid");
+        {
+            let mut handle = tx.lock_handle().await?;
+            Blob::open(handle.as_raw_handle().as_ptr(), &self.kv_name, rowid, true)?.write_from(&mut file, len)?;
+        }
+        tx.commit().await?;
+
+        self.notify(ChangeKind::Set, key, None);
+        Ok(())
+    }
+
+    /// Write the value of a record to a file, copied out a chunk at a time with SQLite's incremental
+    /// BLOB I/O instead of being read into memory first, and return the record's metadata. A store
+    /// that transforms or deduplicates its values is read the usual way, to undo them.
+    pub async fn get_to_file(&self, key: &str, path: &Path) -> Result<RecordMeta, DsError> {
+        if !self.value_transforms.is_empty() || self.dedup.is_some() {
+            let (value, meta) = self.get_with_meta(key).await?;
+            fs::write(path, value)?;
+            return Ok(meta);
+        }
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        // one read transaction, so the value can't change while it's copied
+        let mut tx = conn.begin().await?;
+        let row = self.fetch_record(&mut tx, "rowid, ", key).await?;
+        let res = {
+            let mut handle = tx.lock_handle().await?;
+            let db = handle.as_raw_handle().as_ptr();
+            File::create(path).map_err(DsError::from).and_then(|file| {
+                let mut file = BufWriter::new(file);
+                Blob::open(db, &self.kv_name, row.get("rowid"), false)?.read_to(&mut file)?;
+                Ok(file.flush()?)
+            })
+        };
+        if res.is_err() {
+            let _ = fs::remove_file(path);
+        }
+        res?;
+        tx.commit().await?;
+        Ok(Self::record_meta(&row))
+    }
+
+    /// Stream the value of a record a chunk at a time with SQLite's incremental BLOB I/O, along with
+    /// the record's metadata, holding a read transaction until the stream ends or is dropped so the
+    /// value can't change while it's read. A value that fits in one chunk is read before returning,
+    /// so nothing is held while it's sent, and a reader that leaves a chunk untaken for longer than
+    /// STREAM_DEADLINE has the stream end with an error and the transaction released. A store that
+    /// transforms or deduplicates its values is read the usual way, to undo them, and its values
+    /// streamed whole.
+    pub async fn stream_value(&self, key: &str) -> Result<(RecordMeta, impl Stream<Item = Result<Vec<u8>, DsError>> + Send + 'static), DsError> {
+        self.stream_value_within(key, STREAM_DEADLINE).await
+    }
+
+    /// Stream a value as stream_value does, giving its reader a deadline to take each chunk by
+    pub(crate) async fn stream_value_within(&self, key: &str, deadline: Duration) -> Result<(RecordMeta, impl Stream<Item = Result<Vec<u8>, DsError>> + Send + 'static), DsError> {
+        let (chunks, received) = mpsc::channel(1);
+        if !self.value_transforms.is_empty() || self.dedup.is_some() {
+            let (value, meta) = self.get_with_meta(key).await?;
+            let _ = chunks.send(Ok(value)).await;
+            return Ok((meta, ReceiverStream::new(received)));
+        }
+        let key = &self.canonical_key(key)[..];
+        let mut tx = self.pool.begin().await?;
+        let row = self.fetch_record(&mut tx, "rowid, ", key).await?;
+        let meta = Self::record_meta(&row);
+        let (table, rowid, len) = (self.kv_name.clone(), row.get::<i64, &str>("rowid"), meta.size);
+        if len <= CHUNK_SIZE as u64 {
+            let mut value = vec![0; len as usize];
+            if len > 0 {
+                let mut handle = tx.lock_handle().await?;
+                Blob::open(handle.as_raw_handle().as_ptr(), &table, rowid, false)?.read_at(0, &mut value)?;
+            }
+            tx.commit().await?;
+            let _ = chunks.send(Ok(value)).await;
+            return Ok((meta, ReceiverStream::new(received)));
+        }
+        tokio::spawn(async move {
+            let mut offset = 0;
+            // stops once the stream is dropped and a send fails
+            while offset < len {
+                let mut chunk = vec![0; CHUNK_SIZE.min((len - offset) as usize)];
+                let read = match tx.lock_handle().await {
+                    Ok(mut handle) => Blob::open(handle.as_raw_handle().as_ptr(), &table, rowid, false)
+                        .and_then(|blob| blob.read_at(offset, &mut chunk)),
+                    Err(e) => Err(e.into()),
+                };
+                let failed = read.is_err();
+                match timeout(deadline, chunks.send(read.map(|_| chunk))).await {
+                    Ok(Ok(())) if !failed => {},
+                    Ok(_) => return,
+                    Err(_) => {
+                        // rolled back, giving the connection back to the pool before the reader
+                        // is told, whenever it gets round to it
+                        drop(tx);
+                        let _ = chunks.send(Err(io::Error::new(io::ErrorKind::TimedOut, "the value wasn't read in time").into())).await;
+                        return;
+                    },
+                }
+                offset += CHUNK_SIZE as u64;
+            }
+            let _ = tx.commit().await;
+        });
+        Ok((meta, ReceiverStream::new(received)))
+    }
+
+    /// Set a record from a stream of chunks adding up to len bytes, written into the value as they
+    /// arrive with SQLite's incremental BLOB I/O, in one transaction, so a large value is never
+    /// held in memory. Fails without writing anything if the stream doesn't add up to len. The
+    /// stores set_from_file reads whole have the chunks collected and set as set_with would.
+    pub async fn set_from_stream<B: AsRef<[u8]>>(&self, key: &str, len: u64, chunks: impl Stream<Item = Result<B, DsError>>, options: &SetOptions) -> Result<(), DsError> {
+        tokio::pin!(chunks);
+        let mismatch = || DsError::from(io::Error::new(io::ErrorKind::InvalidData, format!("the value isn't the {} bytes it was said to be", len)));
+        if options.tag.is_some() || !self.is_streamable().await? {
+            let mut value = Vec::with_capacity(len as usize);
+            while let Some(chunk) = chunks.next().await {
+                value.extend_from_slice(chunk?.as_ref());
+            }
+            if value.len() as u64 != len {
+                return Err(mismatch());
+            }
+            return self.set_with(key, &value, options).await;
+        }
+        let key = &self.canonical_key(key)[..];
+
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (key, value, expires_at, content_type, created_at, updated_at, tag, source) VALUES (
+            "#, self.kv_name));
+        query.push_bind(key).push(", zeroblob(").push_bind(len as i64).push(")");
+        self.push_upsert(&mut query, &Stored::from_options(options));
+        self.execute(&mut tx, query.build()).await?;
+        let rowid: i64 = self.fetch_one(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT rowid FROM {} WHERE key=
+            "#, self.kv_name))
+            .push_bind(key)
+            .build())
+            .await?
+            .get("rowid");
+        // dropping the transaction on an error rolls the record back
+        let mut offset = 0;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            let chunk = chunk.as_ref();
+            if offset + chunk.len() as u64 > len {
+                return Err(mismatch());
+            }
+            let mut handle = tx.lock_handle().await?;
+            Blob::open(handle.as_raw_handle().as_ptr(), &self.kv_name, rowid, true)?.write_at(offset, chunk)?;
+            offset += chunk.len() as u64;
+        }
+        if offset != len {
+            return Err(mismatch());
+        }
+        tx.commit().await?;
+
+        self.notify(ChangeKind::Set, key, None);
+        Ok(())
+    }
+}
+
+/// An open handle on one value for incremental I/O, closed when dropped
+struct Blob {
+    db: *mut ffi::sqlite3,
+    blob: *mut ffi::sqlite3_blob,
+}
+
+impl Blob {
+    fn open(db: *mut ffi::sqlite3, table: &Table, rowid: i64, write: bool) -> Result<Blob, DsError> {
+        let schema = CString::new(table.schema.as_deref().unwrap_or("main"))
+            .map_err(|e| DsError::Sql(sqlx::Error::Configuration(Box::new(e))))?;
+        let name = CString::new(table.name.as_str())
+            .map_err(|e| DsError::Sql(sqlx::Error::Configuration(Box::new(e))))?;
+        let mut blob = ptr::null_mut();
+        // SAFETY: the handle is open and locked by the caller, the names are NUL terminated, and the
+        // blob handle is closed by Drop
+        let code = unsafe {
+            ffi::sqlite3_blob_open(db, schema.as_ptr(), name.as_ptr(), c"value".as_ptr(), rowid, write as i32, &mut blob)
+        };
+        let blob = Blob { db, blob };
+        match code {
+            ffi::SQLITE_OK => Ok(blob),
+            code => Err(error(db, code)),
+        }
+    }
+
+    /// Fill the value, already len bytes long, from the start of a reader
+    fn write_from(&self, reader: &mut impl Read, len: u64) -> Result<(), DsError> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < len {
+            let n = reader.read(&mut buf[..CHUNK_SIZE.min((len - offset) as usize)])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file got shorter while it was read").into());
+            }
+            self.write_at(offset, &buf[..n])?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Overwrite part of the value from an offset, which the data has to fit within
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), DsError> {
+        // SAFETY: the buffer holds data.len() bytes, and values are never longer than an i32 can count
+        let code = unsafe { ffi::sqlite3_blob_write(self.blob, data.as_ptr().cast(), data.len() as i32, offset as i32) };
+        match code {
+            ffi::SQLITE_OK => Ok(()),
+            code => Err(error(self.db, code)),
+        }
+    }
+
+    /// Copy the whole value to a writer
+    fn read_to(&self, writer: &mut impl Write) -> Result<(), DsError> {
+        // SAFETY: the blob handle is open
+        let len = unsafe { ffi::sqlite3_blob_bytes(self.blob) } as u64;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < len {
+            let n = (len - offset).min(CHUNK_SIZE as u64) as usize;
+            self.read_at(offset, &mut buf[..n])?;
+            writer.write_all(&buf[..n])?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Fill a buffer with the value from an offset, which the buffer has to fit within
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), DsError> {
+        // SAFETY: the buffer has room for buf.len() bytes, and sqlite checks the range is within the value
+        let code = unsafe { ffi::sqlite3_blob_read(self.blob, buf.as_mut_ptr().cast(), buf.len() as i32, offset as i32) };
+        match code {
+            ffi::SQLITE_OK => Ok(()),
+            code => Err(error(self.db, code)),
+        }
+    }
+}
+
+impl Drop for Blob {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from sqlite3_blob_open, or is null if that failed, which is a no-op
+        unsafe { ffi::sqlite3_blob_close(self.blob) };
+    }
+}
@@ -0,0 +1,107 @@
+use std::{future::Future, time::Duration};
+use sqlx::sqlite::SqliteRow;
+use tokio::runtime::{Builder, Runtime};
+use crate::{KVLite, KVLiteOptions, DsError, Filter, ListOptions, RecordMeta, SetOptions};
+
+/// A store for synchronous programs and build scripts, which owns a Tokio runtime and blocks on
+/// it until each call is done. The common record operations have methods of their own, and
+/// block_on runs anything else KVLite has. Calls mustn't be made from inside another runtime.
+pub struct KVLiteBlocking {
+    runtime: Runtime,
+    store: KVLite,
+}
+
+impl KVLiteBlocking {
+    /// Open a store as KVLite::new does
+    pub fn new(filename: &str, kv_name: &str, read_only: bool, create_new: bool) -> Result<KVLiteBlocking, DsError> {
+        KVLiteBlocking::with_runtime(|| KVLite::new(filename, kv_name, read_only, create_new))
+    }
+
+    /// Open a store with options as KVLiteOptions::open does
+    pub fn open(options: KVLiteOptions, kv_name: &str) -> Result<KVLiteBlocking, DsError> {
+        KVLiteBlocking::with_runtime(|| options.open(kv_name))
+    }
+
+    fn with_runtime<F: Future<Output = Result<KVLite, DsError>>>(open: impl FnOnce() -> F) -> Result<KVLiteBlocking, DsError> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let store = runtime.block_on(open())?;
+        Ok(KVLiteBlocking { runtime, store })
+    }
+
+    /// The async store, for anything not wrapped here, run with block_on
+    pub fn store(&self) -> &KVLite {
+        &self.store
+    }
+
+    /// Run a call on the async store to completion, like `store.block_on(|s| s.datastore_stats())`
+    pub fn block_on<'a, F: Future>(&'a self, call: impl FnOnce(&'a KVLite) -> F) -> F::Output {
+        self.runtime.block_on(call(&self.store))
+    }
+
+    pub fn get(&self, key: &str) -> Result<String, DsError> {
+        self.block_on(|store| store.get(key))
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        self.block_on(|store| store.get_bytes(key))
+    }
+
+    pub fn get_with_meta(&self, key: &str) -> Result<(Vec<u8>, RecordMeta), DsError> {
+        self.block_on(|store| store.get_with_meta(key))
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), DsError> {
+        self.block_on(|store| store.set(key, value))
+    }
+
+    pub fn set_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<(), DsError> {
+        self.block_on(|store| store.set_with_ttl(key, value, ttl))
+    }
+
+    pub fn set_bytes(&self, key: &str, value: &[u8]) -> Result<(), DsError> {
+        self.block_on(|store| store.set_bytes(key, value))
+    }
+
+    pub fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        self.block_on(|store| store.set_with(key, value, options))
+    }
+
+    pub fn incr(&self, key: &str, amount: i64) -> Result<i64, DsError> {
+        self.block_on(|store| store.incr(key, amount))
+    }
+
+    pub fn del(&self, key: &str) -> Result<(), DsError> {
+        self.block_on(|store| store.del(key))
+    }
+
+    pub fn contains(&self, key: &str) -> Result<bool, DsError> {
+        self.block_on(|store| store.contains(key))
+    }
+
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>, DsError> {
+        self.block_on(|store| store.ttl(key))
+    }
+
+    pub fn keys(&self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.block_on(|store| store.keys(options))
+    }
+
+    pub fn values(&self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.block_on(|store| store.values(options))
+    }
+
+    pub fn records(&self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.block_on(|store| store.records(options))
+    }
+
+    pub fn count(&self, filter: &Filter) -> Result<u64, DsError> {
+        self.block_on(|store| store.count(filter))
+    }
+}
+
+impl Drop for KVLiteBlocking {
+    fn drop(&mut self) {
+        // the pool's connections are closed on the runtime, which is gone once this returns
+        self.runtime.block_on(self.store.pool.close());
+    }
+}
@@ -0,0 +1,153 @@
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Stored, ValueTag, push_live};
+
+impl KVLite {
+    /// Add values to the start of a list, the last given ending up first, creating the list if it
+    /// doesn't exist. Returns the list's new length.
+    pub async fn lpush(&self, key: &str, values: &[&str]) -> Result<u64, DsError> {
+        self.update_collection(key, ValueTag::List, |items| {
+            for value in values {
+                items.insert(0, value.to_string());
+            }
+            items.len() as u64
+        })
+        .await
+    }
+
+    /// Add values to the end of a list, creating the list if it doesn't exist. Returns the list's
+    /// new length.
+    pub async fn rpush(&self, key: &str, values: &[&str]) -> Result<u64, DsError> {
+        self.update_collection(key, ValueTag::List, |items| {
+            items.extend(values.iter().map(|value| value.to_string()));
+            items.len() as u64
+        })
+        .await
+    }
+
+    /// Remove and return the first value of a list, None if it's empty or doesn't exist.
+    /// A list left empty is deleted.
+    pub async fn lpop(&self, key: &str) -> Result<Option<String>, DsError> {
+        self.update_collection(key, ValueTag::List, |items| match items.is_empty() {
+            true => None,
+            false => Some(items.remove(0)),
+        })
+        .await
+    }
+
+    /// The values of a list from start to stop, both included. Negative positions count back from
+    /// the end, so 0 to -1 is the whole list. A list that doesn't exist is empty.
+    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, DsError> {
+        let items = self.read_collection(key, ValueTag::List).await?;
+        let len = items.len() as i64;
+        let position = |i: i64| if i < 0 { len + i } else { i };
+        let (start, stop) = (position(start).max(0), position(stop).min(len - 1));
+        if start > stop {
+            return Ok(vec![]);
+        }
+        Ok(items[start as usize..=stop as usize].to_vec())
+    }
+
+    /// Add members to a set, creating it if it doesn't exist. Returns how many weren't already in it.
+    pub async fn sadd(&self, key: &str, members: &[&str]) -> Result<u64, DsError> {
+        self.update_collection(key, ValueTag::Set, |items| {
+            let before = items.len();
+            for member in members {
+                if !items.iter().any(|item| item == member) {
+                    items.push(member.to_string());
+                }
+            }
+            items.sort();
+            (items.len() - before) as u64
+        })
+        .await
+    }
+
+    /// Remove members from a set, returning how many were in it. A set left empty is deleted.
+    pub async fn srem(&self, key: &str, members: &[&str]) -> Result<u64, DsError> {
+        self.update_collection(key, ValueTag::Set, |items| {
+            let before = items.len();
+            items.retain(|item| !members.contains(&item.as_str()));
+            (before - items.len()) as u64
+        })
+        .await
+    }
+
+    /// The members of a set in order, none if it doesn't exist
+    pub async fn smembers(&self, key: &str) -> Result<Vec<String>, DsError> {
+        self.read_collection(key, ValueTag::Set).await
+    }
+
+    /// The items of a list or set, failing if the record holds anything else
+    async fn read_collection(&self, key: &str, tag: ValueTag) -> Result<Vec<String>, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.collection_in(&mut conn, key, tag).await?.map(|(items, _)| items).unwrap_or_default())
+    }
+
+    /// The items of a live list or set and when it expires
+    async fn collection_in(&self, conn: &mut SqliteConnection, key: &str, tag: ValueTag) -> Result<Option<(Vec<String>, Option<i64>)>, DsError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT value, tag, expires_at FROM {} WHERE key=
+            "#, self.readable_records()));
+        query.push_bind(key);
+        push_live(&mut query, &mut true);
+        let row = match self.fetch_optional(conn, query.build()).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        if row.get::<Option<&str>, &str>("tag") != Some(tag.to_string().as_str()) {
+            return Err(DsError::Sql(sqlx::Error::Decode(format!("{} isn't a {}", key, tag).into())));
+        }
+        serde_json::from_slice(row.get("value"))
+            .map(|items| Some((items, row.get("expires_at"))))
+            .map_err(|e| DsError::Sql(sqlx::Error::Decode(Box::new(e))))
+    }
+
+    /// Change the items of a list or set in one transaction, keeping its TTL, and delete it if none
+    /// are left. A record that doesn't exist starts with no items, and nothing is written if the
+    /// items didn't change.
+    async fn update_collection<T>(&self, key: &str, tag: ValueTag, update: impl FnOnce(&mut Vec<String>) -> T) -> Result<T, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        let expired = self.purge(&mut tx).await?;
+
+        let (mut items, expires_at) = match self.collection_in(&mut tx, key, tag).await {
+            Ok(Some((items, expires_at))) => (items, expires_at),
+            Ok(None) => (vec![], None),
+            Err(e) => {
+                tx.rollback().await?;
+                return Err(e);
+            },
+        };
+        let original = items.clone();
+        let res = update(&mut items);
+        let value = serde_json::to_string(&items).unwrap_or_default();
+        let change = match items.is_empty() {
+            _ if items == original => None,
+            true => Some(ChangeKind::Delete),
+            false => Some(ChangeKind::Set),
+        };
+        let written = match change {
+            Some(ChangeKind::Set) => self.set_in(&mut tx, key, value.as_str(), Stored { expires_at, tag: Some(tag), ..Default::default() }).await,
+            Some(_) => self.del_in(&mut tx, key).await,
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            tx.rollback().await?;
+            return Err(e);
+        }
+        tx.commit().await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        match change {
+            Some(ChangeKind::Set) => self.notify(ChangeKind::Set, key, Some(&value)),
+            Some(kind) => self.notify(kind, key, None),
+            None => (),
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,404 @@
+use std::{collections::HashMap, io, os::unix::fs::PermissionsExt, path::Path, sync::Arc, time::{Duration, Instant, SystemTime}};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines}, net::{UnixListener, UnixStream, unix::{OwnedReadHalf, OwnedWriteHalf}}, sync::{Mutex, mpsc}};
+use tokio_stream::{Stream, StreamExt, wrappers::ReceiverStream};
+use crate::{KVLite, KVLiteOptions, DsError, ChangeEvent, ChangeFilter, ChangeKind, ListOptions, RecordMeta, SetOptions, Filter, CHANGE_CAPACITY, replica::base64_value, to_time};
+
+/// A command sent to the daemon, one JSON object per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Request {
+    Get { store: String, key: String },
+    Set {
+        store: String,
+        key: String,
+        #[serde(with = "base64_value")]
+        value: Option<Vec<u8>>,
+        ttl: Option<u64>,
+        source: Option<String>,
+    },
+    Contains { store: String, key: String },
+    Delete { store: String, key: String },
+    Ttl { store: String, key: String },
+    Keys { store: String, prefix: Option<String> },
+    /// Checking for changes from other processes passing a ChangeFilter expression every interval_ms
+    Watch { store: String, filter: String, interval_ms: u64 },
+}
+
+/// The daemon's answer to a command, one JSON object per line. A watch is answered with a change
+/// per line until the client disconnects.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "lowercase")]
+enum Reply {
+    Done,
+    Record {
+        #[serde(with = "base64_value")]
+        value: Option<Vec<u8>>,
+        content_type: Option<String>,
+        tag: Option<String>,
+        expires_at: Option<i64>,
+    },
+    Bool { value: bool },
+    Ttl { secs: Option<u64> },
+    Keys { keys: Vec<String> },
+    Change { kind: String, key: String, value: Option<String> },
+    Error { kind: ErrorKind, message: String },
+}
+
+/// Which DsError a command failed with, so the client fails the same way
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    NotFound,
+    StoreLocked,
+    ReadOnly,
+    Other,
+}
+
+impl From<DsError> for Reply {
+    fn from(e: DsError) -> Self {
+        let kind = match e {
+            DsError::NotFound => ErrorKind::NotFound,
+            DsError::StoreLocked => ErrorKind::StoreLocked,
+            DsError::ReadOnly => ErrorKind::ReadOnly,
+            _ => ErrorKind::Other,
+        };
+        Reply::Error { kind, message: e.to_string() }
+    }
+}
+
+/// The stores the daemon has opened, each kept open with its pool for every client after, until
+/// the daemon has been idle for a while
+struct Stores {
+    options: KVLiteOptions,
+    open: Mutex<Open>,
+}
+
+struct Open {
+    stores: HashMap<String, Arc<KVLite>>,
+    /// When a command last finished
+    last_used: Instant,
+    /// Watches streaming changes, which keep the stores open
+    watches: usize,
+}
+
+impl Stores {
+    /// A store, opened again if it isn't open
+    async fn get(&self, name: &str) -> Result<Arc<KVLite>, DsError> {
+        let mut open = self.open.lock().await;
+        open.last_used = Instant::now();
+        if let Some(store) = open.stores.get(name) {
+            return Ok(store.clone());
+        }
+        let store = Arc::new(self.options.clone().open(name).await?);
+        open.stores.insert(name.to_string(), store.clone());
+        Ok(store)
+    }
+
+    async fn touch(&self) {
+        self.open.lock().await.last_used = Instant::now();
+    }
+
+    async fn watching(&self, watching: bool) {
+        let mut open = self.open.lock().await;
+        match watching {
+            true => open.watches += 1,
+            false => open.watches -= 1,
+        }
+        open.last_used = Instant::now();
+    }
+
+    /// Close every store's connections once no command has run for the idle period and nothing's
+    /// watching, so no connection holds the datastore file open. The next command opens it again.
+    async fn close_idle(&self, idle: Duration) {
+        loop {
+            let wait = {
+                let mut open = self.open.lock().await;
+                let elapsed = open.last_used.elapsed();
+                if elapsed >= idle && open.watches == 0 && !open.stores.is_empty() {
+                    for (_, store) in open.stores.drain() {
+                        // waits for commands still using a connection to finish with it
+                        store.pool.close().await;
+                    }
+                }
+                idle.saturating_sub(elapsed).max(idle / 10)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Record the datastore's size in its stats history every interval, for Stats --history
+async fn sample_stats(options: KVLiteOptions, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = options.clone().sample_stats().await {
+            eprintln!("warning: couldn't sample the datastore's stats: {}", e);
+        }
+    }
+}
+
+/// Serve the datastore on a Unix socket, which only the user running the daemon can connect to,
+/// keeping each store a client uses open with its connection pool. After the idle period without
+/// a command, if given, its connections are closed until the next command, so the file can be
+/// safely copied. Every sampling interval, if given, the datastore's size is recorded in its
+/// stats history. A socket left behind by a daemon that's no longer running is replaced.
+pub async fn serve(options: KVLiteOptions, socket: &Path, idle: Option<Duration>, sampling: Option<Duration>) -> io::Result<()> {
+    if socket.exists() {
+        if UnixStream::connect(socket).await.is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("a daemon is already listening on {}", socket.display())));
+        }
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+
+    let open = Open { stores: HashMap::new(), last_used: Instant::now(), watches: 0 };
+    let stores = Arc::new(Stores { options, open: Mutex::new(open) });
+    if let Some(idle) = idle {
+        let stores = stores.clone();
+        tokio::spawn(async move { stores.close_idle(idle).await });
+    }
+    if let Some(interval) = sampling {
+        tokio::spawn(sample_stats(stores.options.clone(), interval));
+    }
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let stores = stores.clone();
+        tokio::spawn(async move {
+            // a client sending garbage only ends its own connection
+            let _ = handle(&stores, stream).await;
+        });
+    }
+}
+
+/// Answer the commands sent over one connection until the client disconnects
+async fn handle(stores: &Stores, stream: UnixStream) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let reply = Reply::Error { kind: ErrorKind::Other, message: format!("invalid request: {}", e) };
+                send(&mut writer, &reply).await?;
+                continue;
+            },
+        };
+        if let Request::Watch { store, filter, interval_ms } = request {
+            stores.watching(true).await;
+            let res = watch(stores, &store, &filter, Duration::from_millis(interval_ms), &mut writer).await;
+            stores.watching(false).await;
+            return res;
+        }
+        let reply = run(stores, request).await.unwrap_or_else(Reply::from);
+        stores.touch().await;
+        send(&mut writer, &reply).await?;
+    }
+    Ok(())
+}
+
+async fn run(stores: &Stores, request: Request) -> Result<Reply, DsError> {
+    Ok(match request {
+        Request::Get { store, key } => {
+            let (value, meta) = stores.get(&store).await?.get_with_meta(&key).await?;
+            Reply::Record {
+                value: Some(value),
+                content_type: meta.content_type,
+                tag: meta.tag.map(|tag| tag.to_string()),
+                expires_at: meta.expires_at.map(epoch_secs),
+            }
+        },
+        Request::Set { store, key, value, ttl, source } => {
+            let store = stores.get(&store).await?;
+            let options = SetOptions { ttl: ttl.map(Duration::from_secs), source, ..Default::default() };
+            store.set_with(&key, &value.unwrap_or_default(), &options).await?;
+            store.purge_expired().await?;
+            Reply::Done
+        },
+        Request::Contains { store, key } => Reply::Bool { value: stores.get(&store).await?.contains(&key).await? },
+        Request::Delete { store, key } => {
+            let store = stores.get(&store).await?;
+            store.del(&key).await?;
+            store.purge_expired().await?;
+            Reply::Done
+        },
+        Request::Ttl { store, key } => Reply::Ttl { secs: stores.get(&store).await?.ttl(&key).await?.map(|ttl| ttl.as_secs()) },
+        Request::Keys { store, prefix } => {
+            let options = ListOptions { filter: Filter { prefix, ..Default::default() }, ..Default::default() };
+            let keys = stores.get(&store).await?.keys(&options).await?
+                .iter()
+                .map(|row| sqlx::Row::get(row, "key"))
+                .collect();
+            Reply::Keys { keys }
+        },
+        Request::Watch { .. } => unreachable!("watches are streamed by handle"),
+    })
+}
+
+/// Stream the changes to a store passing a filter, made by any process, until the client disconnects
+async fn watch(stores: &Stores, store: &str, filter: &str, interval: Duration, writer: &mut OwnedWriteHalf) -> io::Result<()> {
+    let filter: ChangeFilter = match filter.parse() {
+        Ok(filter) => filter,
+        Err(message) => return send(writer, &Reply::Error { kind: ErrorKind::Other, message }).await,
+    };
+    let changes = async { stores.get(store).await?.watch_filtered(&filter, interval).await };
+    let mut changes = match changes.await {
+        Ok(changes) => Box::pin(changes),
+        Err(e) => return send(writer, &Reply::from(e)).await,
+    };
+    while let Some(change) = changes.next().await {
+        let reply = match change {
+            Ok(change) => Reply::Change { kind: change.kind.to_string(), key: change.key, value: change.value },
+            Err(e) => Reply::from(e),
+        };
+        send(writer, &reply).await?;
+    }
+    Ok(())
+}
+
+async fn send<T: Serialize>(writer: &mut OwnedWriteHalf, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
+
+fn epoch_secs(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |secs| secs.as_secs() as i64)
+}
+
+/// A connection to a daemon, running commands on the stores of the datastore it serves
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    /// Connect to the daemon listening on a socket, None if no daemon is
+    pub async fn connect(socket: &Path) -> io::Result<Option<Client>> {
+        match UnixStream::connect(socket).await {
+            Ok(stream) => {
+                let (reader, writer) = stream.into_split();
+                Ok(Some(Client { lines: BufReader::new(reader).lines(), writer }))
+            },
+            Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn request(&mut self, request: &Request) -> Result<Reply, DsError> {
+        send(&mut self.writer, request).await?;
+        self.reply().await
+    }
+
+    async fn reply(&mut self) -> Result<Reply, DsError> {
+        let line = match self.lines.next_line().await? {
+            Some(line) => line,
+            None => return Err(DsError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "the daemon closed the connection"))),
+        };
+        match serde_json::from_str(&line).map_err(io::Error::from)? {
+            Reply::Error { kind, message } => Err(match kind {
+                ErrorKind::NotFound => DsError::NotFound,
+                ErrorKind::StoreLocked => DsError::StoreLocked,
+                ErrorKind::ReadOnly => DsError::ReadOnly,
+                ErrorKind::Other => DsError::Sql(sqlx::Error::Configuration(message.into())),
+            }),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Get a record's value and metadata, like KVLite::get_with_meta. Only the key, expiry, content
+    /// type, size and tag of the metadata are known.
+    pub async fn get_with_meta(&mut self, store: &str, key: &str) -> Result<(Vec<u8>, RecordMeta), DsError> {
+        match self.request(&Request::Get { store: store.to_string(), key: key.to_string() }).await? {
+            Reply::Record { value, content_type, tag, expires_at } => {
+                let value = value.unwrap_or_default();
+                let meta = RecordMeta {
+                    key: key.to_string(),
+                    size: value.len() as u64,
+                    expires_at: expires_at.map(to_time),
+                    content_type,
+                    created_at: None,
+                    updated_at: None,
+                    tag: tag.and_then(|tag| tag.parse().ok()),
+                    pinned: false,
+                    source: None,
+                };
+                Ok((value, meta))
+            },
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Set a record, with a TTL and source if given, like KVLite::set_with
+    pub async fn set(&mut self, store: &str, key: &str, value: &[u8], ttl: Option<Duration>, source: Option<&str>) -> Result<(), DsError> {
+        let request = Request::Set {
+            store: store.to_string(),
+            key: key.to_string(),
+            value: Some(value.to_vec()),
+            ttl: ttl.map(|ttl| ttl.as_secs()),
+            source: source.map(str::to_string),
+        };
+        match self.request(&request).await? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn contains(&mut self, store: &str, key: &str) -> Result<bool, DsError> {
+        match self.request(&Request::Contains { store: store.to_string(), key: key.to_string() }).await? {
+            Reply::Bool { value } => Ok(value),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn del(&mut self, store: &str, key: &str) -> Result<(), DsError> {
+        match self.request(&Request::Delete { store: store.to_string(), key: key.to_string() }).await? {
+            Reply::Done => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn ttl(&mut self, store: &str, key: &str) -> Result<Option<Duration>, DsError> {
+        match self.request(&Request::Ttl { store: store.to_string(), key: key.to_string() }).await? {
+            Reply::Ttl { secs } => Ok(secs.map(Duration::from_secs)),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// The keys of the live records in a store, starting with a prefix if given
+    pub async fn keys(&mut self, store: &str, prefix: Option<&str>) -> Result<Vec<String>, DsError> {
+        match self.request(&Request::Keys { store: store.to_string(), prefix: prefix.map(str::to_string) }).await? {
+            Reply::Keys { keys } => Ok(keys),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Stream the changes made to a store by any process, like KVLite::watch_filtered. The connection is
+    /// only used for the watch after this.
+    pub async fn watch(mut self, store: &str, filter: &ChangeFilter, interval: Duration) -> Result<impl Stream<Item = Result<ChangeEvent, DsError>>, DsError> {
+        let request = Request::Watch { store: store.to_string(), filter: filter.to_string(), interval_ms: interval.as_millis() as u64 };
+        send(&mut self.writer, &request).await?;
+        let (events, received) = mpsc::channel(CHANGE_CAPACITY);
+        tokio::spawn(async move {
+            // stops once the stream is dropped and a send fails, closing the connection
+            loop {
+                let event = match self.reply().await {
+                    Ok(Reply::Change { kind, key, value }) => Ok(ChangeEvent { kind: kind.parse().unwrap_or(ChangeKind::Set), key, value }),
+                    Ok(reply) => Err(unexpected(reply)),
+                    Err(e) => Err(e),
+                };
+                let failed = event.is_err();
+                if events.send(event).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(ReceiverStream::new(received))
+    }
+}
+
+fn unexpected(reply: Reply) -> DsError {
+    DsError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply from the daemon: {:?}", reply)))
+}
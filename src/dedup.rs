@@ -0,0 +1,249 @@
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, Table, push_live};
+
+/// What a record's value is replaced with when it's kept in the store's blobs, followed by the
+/// SHA-256 of the value as hex
+pub(crate) const BLOB_REF: &str = "dsr-blob:";
+
+/// Whether a value is kept in the blobs of a store deduplicating values of at least min_size bytes.
+/// A value that looks like a reference is kept there whatever its size, so it's read back as written.
+fn is_deduplicated(min_size: u64, value: &[u8]) -> bool {
+    value.len() as u64 >= min_size || value.starts_with(BLOB_REF.as_bytes())
+}
+
+/// Columns of a store's records besides value, with rowid for the search index's joins
+pub(crate) const RECORD_COLUMNS: &[&str] = &["rowid", "key", "expires_at", "content_type", "created_at", "updated_at", "tag", "pinned", "source"];
+
+/// A LEFT JOIN of a store's blobs as b to a table aliased alias, matching the blob each row's value
+/// refers to, if it refers to one
+pub(crate) fn blob_join(alias: &str, blobs: &Table) -> String {
+    format!("LEFT JOIN {blobs} AS b ON substr({alias}.value, 1, {len}) = '{reference}' AND b.hash = substr({alias}.value, {start})",
+        blobs = blobs, alias = alias, len = BLOB_REF.len(), reference = BLOB_REF, start = BLOB_REF.len() + 1)
+}
+
+/// What to read the rows of a table of a store's values from, given its columns besides value: the
+/// table itself, or if the store keeps values in blobs, a query of it with the same columns that
+/// reads each value from the blob it refers to, so they're read as they were written
+pub(crate) fn resolved_table(table: &Table, blobs: Option<&Table>, columns: &[&str]) -> String {
+    match blobs {
+        Some(blobs) => {
+            let columns: String = columns.iter().map(|column| format!(", r.{}", column)).collect();
+            format!("(SELECT coalesce(b.value, r.value) AS value{} FROM {} AS r {})", columns, table, blob_join("r", blobs))
+        },
+        None => table.to_string(),
+    }
+}
+
+impl KVLite {
+    fn blobs_table(&self) -> Table {
+        self.kv_name.object("blobs")
+    }
+
+    /// The store's blobs, if it keeps values in them
+    pub(crate) fn blobs(&self) -> Option<Table> {
+        self.dedup.map(|_| self.blobs_table())
+    }
+
+    /// The store's records with their values as written, as resolved_table reads them
+    pub(crate) fn readable_records(&self) -> String {
+        resolved_table(&self.kv_name, self.blobs().as_ref(), RECORD_COLUMNS)
+    }
+
+    /// The size from which values are kept once in the store's blobs, None if they aren't
+    pub fn dedup_min_size(&self) -> Option<u64> {
+        self.dedup
+    }
+
+    /// Keep every value of at least min_size bytes written to the store from now on once, in a
+    /// table of blobs keyed by its SHA-256, with the records holding it referring to it, or stop if
+    /// None. Saved with the store. Records already in it are rewritten to match in one transaction,
+    /// and how many were is returned. Values are deduplicated after the store's value transforms, so
+    /// values encrypted with their own nonces never match. Values are read from their blobs wherever
+    /// they're read, value filters included, but full-text search, incr, append, prepend, strlen
+    /// and cas refuse to run. A store with a type or a max value size can't
+    /// deduplicate its values, as they're checked as stored. Blobs no record, version, snapshot or
+    /// change feed event refers to any more are deleted by compact.
+    pub async fn set_dedup(&mut self, min_size: Option<u64>) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if min_size.is_some() {
+            self.check_unconstrained(&mut conn, "deduplicate its values").await?;
+        }
+        self.create_settings(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        match min_size {
+            Some(min_size) => {
+                self.save_setting(&mut tx, "dedup", &min_size.to_string()).await?;
+                self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                        CREATE TABLE IF NOT EXISTS {} (hash TEXT PRIMARY KEY, value NOT NULL)
+                    "#, self.blobs_table()))
+                    .build())
+                    .await?;
+            },
+            None => self.delete_setting(&mut tx, "dedup").await?,
+        }
+
+        let rows = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT key, value FROM {}
+            "#, self.kv_name))
+            .build())
+            .await?;
+        let mut rewritten = 0;
+        for row in &rows {
+            let key: String = row.get("key");
+            let stored: Vec<u8> = row.get("value");
+            let value = match self.dedup {
+                Some(_) if stored.starts_with(BLOB_REF.as_bytes()) => self.load_blob(&mut tx, &stored).await?,
+                _ => stored.clone(),
+            };
+            let restored = match min_size {
+                Some(min_size) if is_deduplicated(min_size, &value) => self.store_blob(&mut tx, &value).await?.into_bytes(),
+                _ => value,
+            };
+            if restored == stored {
+                continue;
+            }
+            let mut query = QueryBuilder::new(format!(r#"
+                    UPDATE {} SET value =
+                "#, self.kv_name));
+            match String::from_utf8(restored) {
+                Ok(text) => query.push_bind(text),
+                Err(e) => query.push_bind(e.into_bytes()),
+            };
+            self.execute(&mut tx, query.push(" WHERE key = ").push_bind(key).build()).await?;
+            rewritten += 1;
+        }
+
+        match min_size {
+            Some(_) => {
+                self.sweep_blobs(&mut tx, &self.kv_name).await?;
+            },
+            None => {
+                self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                        DROP TABLE IF EXISTS {}
+                    "#, self.blobs_table()))
+                    .build())
+                    .await?;
+            },
+        }
+        tx.commit().await?;
+
+        self.dedup = min_size;
+        Ok(rewritten)
+    }
+
+    /// What's written to a record for a value as it's stored: a reference to it, kept in the
+    /// store's blobs, if the store deduplicates values like it, otherwise None to write it as it is
+    pub(crate) async fn dedup_value(&self, conn: &mut SqliteConnection, value: &[u8]) -> Result<Option<String>, DsError> {
+        match self.dedup {
+            Some(min_size) if is_deduplicated(min_size, value) => self.store_blob(conn, value).await.map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// A value as it's stored, looking it up in the store's blobs if the record refers to one
+    pub(crate) async fn resolve_value(&self, conn: &mut SqliteConnection, value: Vec<u8>) -> Result<Vec<u8>, DsError> {
+        match self.dedup.is_some() && value.starts_with(BLOB_REF.as_bytes()) {
+            true => self.load_blob(conn, &value).await,
+            false => Ok(value),
+        }
+    }
+
+    async fn store_blob(&self, conn: &mut SqliteConnection, value: &[u8]) -> Result<String, DsError> {
+        let hash: String = Sha256::digest(value).iter().map(|byte| format!("{:02x}", byte)).collect();
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (hash, value) VALUES (
+            "#, self.blobs_table()));
+        query.push_bind(hash.as_str()).push(",");
+        // kept as text when it can be, like values that aren't deduplicated
+        match std::str::from_utf8(value) {
+            Ok(text) => query.push_bind(text),
+            Err(_) => query.push_bind(value),
+        };
+        self.execute(conn, query.push(") ON CONFLICT (hash) DO NOTHING").build()).await?;
+        Ok(format!("{}{}", BLOB_REF, hash))
+    }
+
+    async fn load_blob(&self, conn: &mut SqliteConnection, reference: &[u8]) -> Result<Vec<u8>, DsError> {
+        let hash = String::from_utf8_lossy(&reference[BLOB_REF.len()..]).into_owned();
+        let row = self.fetch_optional(conn, QueryBuilder::new(format!(r#"
+                SELECT value FROM {} WHERE hash=
+            "#, self.blobs_table()))
+            .push_bind(hash.as_str())
+            .build())
+            .await?;
+        match row {
+            Some(row) => Ok(row.get("value")),
+            None => Err(DsError::Sql(sqlx::Error::Decode(format!("the blob {} a record refers to is missing", hash).into()))),
+        }
+    }
+
+    /// Delete the blobs of a store that nothing refers to any more, in its records, history,
+    /// snapshots or change feed, returning how many were deleted
+    pub(crate) async fn sweep_blobs(&self, conn: &mut SqliteConnection, store: &Table) -> Result<u64, DsError> {
+        let blobs = store.object("blobs");
+        if !self.table_exists(conn, &blobs).await? {
+            return Ok(0);
+        }
+        let mut referrers = vec![store.clone()];
+        for suffix in ["history", "snapshot_records", "feed"] {
+            if self.table_exists(conn, &store.object(suffix)).await? {
+                referrers.push(store.object(suffix));
+            }
+        }
+        let referenced: Vec<String> = referrers.iter()
+            .map(|table| format!("SELECT substr(value, {}) FROM {} WHERE substr(value, 1, {}) = '{}'", BLOB_REF.len() + 1, table, BLOB_REF.len(), BLOB_REF))
+            .collect();
+        Ok(self.execute(conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE hash NOT IN ({})
+            "#, blobs, referenced.join(" UNION ")))
+            .build())
+            .await?
+            .rows_affected())
+    }
+
+    /// The blobs of a store, in this datastore or an attached one, if it has any
+    pub(crate) async fn blobs_of(&self, conn: &mut SqliteConnection, store: &Table) -> Result<Option<Table>, DsError> {
+        let blobs = store.object("blobs");
+        Ok(self.table_exists(conn, &blobs).await?.then_some(blobs))
+    }
+
+    /// Whether a table exists, in the datastore it's in when that's attached
+    pub(crate) async fn table_exists(&self, conn: &mut SqliteConnection, table: &Table) -> Result<bool, DsError> {
+        let master = match &table.schema {
+            Some(schema) => Table::in_schema(schema, "sqlite_master"),
+            None => Table::new("sqlite_master"),
+        };
+        Ok(self.fetch_optional(conn, QueryBuilder::new(format!(r#"
+                SELECT name FROM {} WHERE type="table" AND name=
+            "#, master))
+            .push_bind(table.name.as_str())
+            .build())
+            .await?
+            .is_some())
+    }
+
+    /// Bytes of values kept in a store's blobs, and bytes that deduplicating them saved: how much
+    /// more the live records referring to them would take up holding their own copies
+    pub(crate) async fn dedup_stats(&self, conn: &mut SqliteConnection, store: &Table) -> Result<(u64, u64), DsError> {
+        let blobs = store.object("blobs");
+        if !self.table_exists(conn, &blobs).await? {
+            return Ok((0, 0));
+        }
+        let blob_bytes = self.fetch_one(conn, QueryBuilder::new(format!(r#"
+                SELECT coalesce(sum(length(CAST(value AS BLOB))), 0) AS bytes FROM {}
+            "#, blobs))
+            .build())
+            .await?
+            .get::<i64, &str>("bytes") as u64;
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT coalesce(sum(length(CAST(b.value AS BLOB))), 0) AS bytes FROM {} AS r JOIN {} AS b ON r.value = '{}' || b.hash
+            "#, store, blobs, BLOB_REF));
+        push_live(&mut query, &mut false);
+        let referenced = self.fetch_one(conn, query.build())
+            .await?
+            .get::<i64, &str>("bytes") as u64;
+        Ok((blob_bytes, referenced.saturating_sub(blob_bytes)))
+    }
+}
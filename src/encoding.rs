@@ -0,0 +1,128 @@
+use std::{str::FromStr, fmt};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{KVLite, DsError, SetOptions};
+
+/// How set_as serializes values, recorded as the record's content type so get_as can read it back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Encoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    fn from_content_type(content_type: &str) -> Option<Encoding> {
+        [
+            Encoding::Json,
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack,
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor,
+        ].into_iter().find(|encoding| encoding.content_type() == content_type)
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, DsError> {
+        let error = |e: String| DsError::Sql(sqlx::Error::Decode(format!("value can't be written as {}: {}", self, e).into()));
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| error(e.to_string())),
+            #[cfg(feature = "msgpack")]
+            // named fields, so structs can gain fields and still be read
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| error(e.to_string())),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => {
+                let mut bytes = vec![];
+                ciborium::into_writer(value, &mut bytes).map_err(|e| error(e.to_string()))?;
+                Ok(bytes)
+            },
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DsError> {
+        let error = |e: String| DsError::Sql(sqlx::Error::Decode(format!("value isn't valid {}: {}", self, e).into()));
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| error(e.to_string())),
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| error(e.to_string())),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(|e| error(e.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "json"),
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => write!(f, "msgpack"),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Encoding::Json),
+            #[cfg(feature = "msgpack")]
+            "msgpack" | "messagepack" => Ok(Encoding::MessagePack),
+            #[cfg(feature = "cbor")]
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(format!("unknown or disabled encoding {}", s)),
+        }
+    }
+}
+
+impl KVLite {
+    /// The encoding set_as uses for the store, JSON unless another was chosen
+    pub async fn encoding(&self) -> Result<Encoding, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.load_setting(&mut conn, &self.kv_name, "encoding").await?
+            .and_then(|encoding| encoding.parse().ok())
+            .unwrap_or(Encoding::Json))
+    }
+
+    /// Choose the encoding set_as uses for the store, saved with it
+    pub async fn set_encoding(&self, encoding: Encoding) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_settings(&mut conn).await?;
+        self.save_setting(&mut conn, "encoding", &encoding.to_string()).await
+    }
+
+    /// Serialize a value in the store's encoding and set it
+    pub async fn set_as<T: Serialize>(&self, key: &str, value: &T) -> Result<(), DsError> {
+        self.set_as_with(key, value, self.encoding().await?).await
+    }
+
+    /// Serialize a value in an encoding and set it
+    pub async fn set_as_with<T: Serialize>(&self, key: &str, value: &T, encoding: Encoding) -> Result<(), DsError> {
+        let options = SetOptions { content_type: Some(encoding.content_type().to_string()), ..Default::default() };
+        self.set_with(key, &encoding.encode(value)?, &options).await
+    }
+
+    /// Get a value and deserialize it, in the encoding its content type names
+    /// or the store's encoding if it has none
+    pub async fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<T, DsError> {
+        let (value, meta) = self.get_with_meta(key).await?;
+        let encoding = match meta.content_type.as_deref().and_then(Encoding::from_content_type) {
+            Some(encoding) => encoding,
+            None => self.encoding().await?,
+        };
+        encoding.decode(&value)
+    }
+}
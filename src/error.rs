@@ -0,0 +1,66 @@
+use std::{fmt, io};
+
+/// What went wrong using a datastore
+#[derive(Debug)]
+pub enum DsError {
+    /// The record, version or store asked for doesn't exist
+    NotFound,
+    /// Another connection held the datastore's lock for longer than the busy timeout
+    StoreLocked,
+    /// A write to a datastore opened read-only
+    ReadOnly,
+    /// A store name that can't be used, like one clashing with a store's own tables
+    InvalidStoreName(String),
+    /// A store migrated by a newer version of dsr, whose schema this one doesn't know
+    SchemaTooNew { store: String, version: i64 },
+    Io(io::Error),
+    /// Any other database error, including values that aren't valid for how they're read or written
+    Sql(sqlx::Error),
+}
+
+impl fmt::Display for DsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DsError::NotFound => write!(f, "not found"),
+            DsError::StoreLocked => write!(f, "the datastore is locked by another connection"),
+            DsError::ReadOnly => write!(f, "the datastore was opened read-only"),
+            DsError::InvalidStoreName(name) => write!(f, "{:?} can't be used as a store name", name),
+            DsError::SchemaTooNew { store, version } => write!(f, "{} is at schema version {}, newer than this version of dsr supports", store, version),
+            DsError::Io(e) => write!(f, "{}", e),
+            DsError::Sql(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DsError::Io(e) => Some(e),
+            DsError::Sql(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for DsError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => DsError::NotFound,
+            sqlx::Error::Io(e) => DsError::Io(e),
+            // SQLITE_BUSY and SQLITE_LOCKED, with any extended code in the upper bits
+            sqlx::Error::Database(db) => match db.code().and_then(|code| code.parse::<i32>().ok()).map(|code| code & 0xff) {
+                Some(5 | 6) => DsError::StoreLocked,
+                // SQLITE_READONLY
+                Some(8) => DsError::ReadOnly,
+                _ => DsError::Sql(sqlx::Error::Database(db)),
+            },
+            e => DsError::Sql(e),
+        }
+    }
+}
+
+impl From<io::Error> for DsError {
+    fn from(e: io::Error) -> Self {
+        DsError::Io(e)
+    }
+}
@@ -0,0 +1,191 @@
+use std::time::{Duration, SystemTime};
+use sqlx::{sqlite::{SqliteConnection, SqliteRow}, QueryBuilder, Row};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, wrappers::ReceiverStream};
+use crate::{KVLite, DsError, ChangeKind, Table, CHANGE_CAPACITY, dedup, to_time};
+
+/// A write to a store with a change feed, as its trigger appended it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedEvent {
+    /// Events count up from 1 in the order they were written, and are never renumbered, so the
+    /// last one read is where to tail from next
+    pub seq: i64,
+    pub at: SystemTime,
+    pub kind: ChangeKind,
+    pub key: String,
+    /// The value set, as it was written, None for deletes and expiries
+    pub value: Option<Vec<u8>>,
+    /// Source of the write, which deletes don't have
+    pub source: Option<String>,
+}
+
+impl KVLite {
+    fn feed_table(&self) -> Table {
+        self.kv_name.object("feed")
+    }
+
+    /// Whether every write to the store is appended to its change feed
+    pub async fn has_change_feed(&self) -> Result<bool, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="trigger" AND name=
+            "#)
+            .push_bind(self.kv_name.object("feed_insert").name)
+            .build())
+            .await?
+            .is_some())
+    }
+
+    /// Start appending every write to the store to a change feed, numbered in order and holding
+    /// the value set, for other systems to tail with tail_changes and copy or index the store
+    /// incrementally. Unlike the change log it isn't trimmed as the store is written, only by
+    /// prune_changes once its readers are past. It's kept by triggers, so writes from every
+    /// process are appended. Turning the feed off keeps the events appended so far.
+    pub async fn set_change_feed(&self, on: bool) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let sql = match on {
+            true => format!(r#"
+                    CREATE TABLE IF NOT EXISTS {feed} (
+                        seq INTEGER PRIMARY KEY AUTOINCREMENT, at INTEGER NOT NULL, kind TEXT NOT NULL,
+                        key TEXT NOT NULL, value, source TEXT
+                    );
+                    CREATE TRIGGER IF NOT EXISTS {insert} AFTER INSERT ON {table} BEGIN
+                        INSERT INTO {feed} (at, kind, key, value, source) VALUES ({now}, 'set', NEW.key, NEW.value, NEW.source);
+                    END;
+                    CREATE TRIGGER IF NOT EXISTS {update} AFTER UPDATE ON {table} BEGIN
+                        INSERT INTO {feed} (at, kind, key) SELECT {now}, 'delete', OLD.key WHERE OLD.key IS NOT NEW.key;
+                        INSERT INTO {feed} (at, kind, key, value, source) VALUES ({now}, 'set', NEW.key, NEW.value, NEW.source);
+                    END;
+                    CREATE TRIGGER IF NOT EXISTS {delete} AFTER DELETE ON {table} BEGIN
+                        INSERT INTO {feed} (at, kind, key) VALUES ({now}, CASE WHEN OLD.expires_at <= {now} THEN 'expired' ELSE 'delete' END, OLD.key);
+                    END;
+                "#,
+                feed = self.feed_table().unqualified(),
+                table = self.kv_name,
+                insert = self.kv_name.object("feed_insert"),
+                update = self.kv_name.object("feed_update"),
+                delete = self.kv_name.object("feed_delete"),
+                now = "CAST(strftime('%s', 'now') AS INTEGER)"),
+            false => format!(r#"
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                "#, self.kv_name.object("feed_insert"), self.kv_name.object("feed_update"), self.kv_name.object("feed_delete")),
+        };
+        self.execute(&mut conn, QueryBuilder::new(sql).build()).await?;
+        Ok(())
+    }
+
+    /// The query reading the feed after a position, up to a limit, bound in that order. Values
+    /// kept in the store's blobs are read from there, as the records referring to them may be gone.
+    fn feed_sql(&self) -> String {
+        let (value, blobs) = match self.blobs() {
+            Some(blobs) => ("coalesce(b.value, f.value)", dedup::blob_join("f", &blobs)),
+            None => ("f.value", String::new()),
+        };
+        format!(r#"
+                SELECT f.seq, f.at, f.kind, f.key, {} AS value, f.source FROM {} AS f {}
+                WHERE f.seq > ? ORDER BY f.seq LIMIT ?
+            "#, value, self.feed_table(), blobs)
+    }
+
+    fn feed_event(row: &SqliteRow, decode: &impl Fn(Vec<u8>) -> Result<Vec<u8>, DsError>) -> Result<FeedEvent, DsError> {
+        Ok(FeedEvent {
+            seq: row.get("seq"),
+            at: to_time(row.get("at")),
+            kind: row.get::<&str, &str>("kind").parse().unwrap_or(ChangeKind::Set),
+            key: row.get("key"),
+            value: row.get::<Option<Vec<u8>>, &str>("value").map(decode).transpose()?,
+            source: row.get("source"),
+        })
+    }
+
+    /// Up to limit events of the store's change feed after a position, oldest first, 0 to read
+    /// it from the start. Empty if the store never had a feed.
+    pub async fn feed_since(&self, since: i64, limit: u64) -> Result<Vec<FeedEvent>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if !self.has_feed_table(&mut conn).await? {
+            return Ok(vec![]);
+        }
+        let sql = self.feed_sql();
+        let rows = self.fetch_all(&mut conn, sqlx::query(&sql)
+            .bind(since)
+            .bind(limit.min(i64::MAX as u64) as i64))
+            .await?;
+        let decode = self.value_decoder();
+        rows.iter().map(|row| KVLite::feed_event(row, &decode)).collect()
+    }
+
+    /// Stream the events of the store's change feed after a position in order, then each one
+    /// appended by any process as it's appended, by polling the feed. Fails if the store has
+    /// never had a feed.
+    pub async fn tail_changes(&self, since: i64, interval: Duration) -> Result<impl Stream<Item = Result<FeedEvent, DsError>> + 'static, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if !self.has_feed_table(&mut conn).await? {
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!("{} has no change feed, store change-feed starts one", self.kv_name.name).into())));
+        }
+        drop(conn);
+
+        let (events, received) = mpsc::channel(CHANGE_CAPACITY);
+        let pool = self.pool.clone();
+        let sql = self.feed_sql();
+        let decode = self.value_decoder();
+        tokio::spawn(async move {
+            let mut seq = since;
+            // stops once the stream is dropped and a send fails
+            loop {
+                let rows = match sqlx::query(&sql).bind(seq).bind(CHANGE_CAPACITY as i64).fetch_all(&pool).await {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        let _ = events.send(Err(e.into())).await;
+                        return;
+                    },
+                };
+                let caught_up = rows.len() < CHANGE_CAPACITY;
+                for row in rows {
+                    let event = KVLite::feed_event(&row, &decode);
+                    if let Ok(event) = &event {
+                        seq = event.seq;
+                    }
+                    let failed = event.is_err();
+                    if events.send(event).await.is_err() || failed {
+                        return;
+                    }
+                }
+                if events.is_closed() {
+                    return;
+                }
+                if caught_up {
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        });
+        Ok(ReceiverStream::new(received))
+    }
+
+    /// Delete the events of the store's change feed up to and including a position, once every
+    /// reader is past it, returning how many were deleted. Later events keep their positions.
+    pub async fn prune_changes(&self, through: i64) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if !self.has_feed_table(&mut conn).await? {
+            return Ok(0);
+        }
+        Ok(self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE seq <=
+            "#, self.feed_table()))
+            .push_bind(through)
+            .build())
+            .await?
+            .rows_affected())
+    }
+
+    async fn has_feed_table(&self, conn: &mut SqliteConnection) -> Result<bool, DsError> {
+        Ok(self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(self.feed_table().name)
+            .build())
+            .await?
+            .is_some())
+    }
+}
@@ -0,0 +1,163 @@
+use std::{cell::RefCell, ffi::{c_char, c_int, CStr, CString}, mem, ptr, slice};
+use crate::{KVLiteBlocking, DsError, SetOptions};
+
+/// The call worked
+pub const DS_OK: c_int = 0;
+/// There's no live record at the key
+pub const DS_NOT_FOUND: c_int = 1;
+/// The call failed, and ds_last_error says why
+pub const DS_ERROR: c_int = -1;
+
+/// An open store, from ds_open, for programs in C, or Python through ctypes, calling the functions
+/// declared in include/dsr.h
+pub struct DsStore(KVLiteBlocking);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(e: impl ToString) {
+    // a message with a NUL in it is cut short there rather than lost
+    let message = e.to_string();
+    let message = message.split('\0').next().unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+}
+
+/// The status a result is returned as, recording its error for ds_last_error
+fn status(res: Result<(), DsError>) -> c_int {
+    match res {
+        Ok(()) => DS_OK,
+        Err(DsError::NotFound) => DS_NOT_FOUND,
+        Err(e) => {
+            set_error(e);
+            DS_ERROR
+        },
+    }
+}
+
+/// A C string argument as a str, None if it's null or not UTF-8
+///
+/// # Safety
+/// s must be null or point to a NUL terminated string that lives as long as the str is used
+unsafe fn arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        set_error("a string argument was null");
+        return None;
+    }
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => Some(s),
+        Err(e) => {
+            set_error(e);
+            None
+        },
+    }
+}
+
+/// Open, creating it if needed, a store in the datastore at path, which can be a file name, a
+/// sqlite: URL or memory: for one in memory. Returns null on failure, and the handle is freed with ds_close.
+///
+/// # Safety
+/// path and store must be NUL terminated strings
+#[no_mangle]
+pub unsafe extern "C" fn ds_open(path: *const c_char, store: *const c_char) -> *mut DsStore {
+    let (Some(path), Some(store)) = (arg(path), arg(store)) else {
+        return ptr::null_mut();
+    };
+    match KVLiteBlocking::new(path, store, false, true) {
+        Ok(store) => Box::into_raw(Box::new(DsStore(store))),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        },
+    }
+}
+
+/// Close a store from ds_open. Null is ignored.
+///
+/// # Safety
+/// store must be null or a handle from ds_open that hasn't been closed
+#[no_mangle]
+pub unsafe extern "C" fn ds_close(store: *mut DsStore) {
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+/// Get the value of a record, pointing value at a copy of it that's freed with ds_free and
+/// setting len to its length. The copy is also NUL terminated, so text can be read as a C string.
+/// Returns DS_OK, DS_NOT_FOUND or DS_ERROR.
+///
+/// # Safety
+/// store must be a handle from ds_open, key a NUL terminated string, and value and len valid to
+/// write to, though len can be null
+#[no_mangle]
+pub unsafe extern "C" fn ds_get(store: *const DsStore, key: *const c_char, value: *mut *mut c_char, len: *mut usize) -> c_int {
+    let (Some(store), Some(key)) = (store.as_ref(), arg(key)) else {
+        return DS_ERROR;
+    };
+    status(store.0.get_bytes(key).map(|bytes| {
+        if !len.is_null() {
+            *len = bytes.len();
+        }
+        *value = into_raw(bytes);
+    }))
+}
+
+/// Set a record to len bytes of value, kept as text if they're valid UTF-8. Returns DS_OK or DS_ERROR.
+///
+/// # Safety
+/// store must be a handle from ds_open, key a NUL terminated string and value point to len bytes
+#[no_mangle]
+pub unsafe extern "C" fn ds_set(store: *const DsStore, key: *const c_char, value: *const c_char, len: usize) -> c_int {
+    let (Some(store), Some(key)) = (store.as_ref(), arg(key)) else {
+        return DS_ERROR;
+    };
+    let value = match len {
+        0 => &[][..],
+        len => slice::from_raw_parts(value.cast::<u8>(), len),
+    };
+    status(store.0.set_with(key, value, &SetOptions::default()))
+}
+
+/// Delete a record, if there is one. Returns DS_OK or DS_ERROR.
+///
+/// # Safety
+/// store must be a handle from ds_open and key a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn ds_del(store: *const DsStore, key: *const c_char) -> c_int {
+    let (Some(store), Some(key)) = (store.as_ref(), arg(key)) else {
+        return DS_ERROR;
+    };
+    status(store.0.del(key))
+}
+
+/// Free a value from ds_get. Null is ignored.
+///
+/// # Safety
+/// value must be null or from ds_get, and not freed already
+#[no_mangle]
+pub unsafe extern "C" fn ds_free(value: *mut c_char) {
+    if value.is_null() {
+        return;
+    }
+    let start = value.cast::<u8>().sub(mem::size_of::<usize>());
+    let len = start.cast::<usize>().read_unaligned();
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(start, mem::size_of::<usize>() + len + 1)));
+}
+
+/// Why the last call on this thread that failed did, valid until the next one fails. Null if none has.
+#[no_mangle]
+pub extern "C" fn ds_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Hand a value to C, NUL terminated and after its length, which ds_free reads to free it
+fn into_raw(value: Vec<u8>) -> *mut c_char {
+    let mut buf = Vec::with_capacity(mem::size_of::<usize>() + value.len() + 1);
+    buf.extend_from_slice(&value.len().to_ne_bytes());
+    buf.extend_from_slice(&value);
+    buf.push(0);
+    let start = Box::into_raw(buf.into_boxed_slice()).cast::<u8>();
+    // SAFETY: the buffer is longer than a usize
+    unsafe { start.add(mem::size_of::<usize>()).cast() }
+}
@@ -0,0 +1,359 @@
+use std::{str::FromStr, fmt, io::{BufRead, BufReader, Read, Write}};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde_json::Value;
+
+/// File formats records can be exported to and imported from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON object mapping keys to values
+    Json,
+    /// key,value lines, quoted where needed
+    Csv,
+    /// KEY=value lines as read by dotenv
+    Env,
+    /// A binary sequence of [key, value] MessagePack arrays, one per record
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// A Parquet file with key, value, created_at and updated_at columns, for analytics tools.
+    /// Records can only be exported to it.
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Csv => write!(f, "csv"),
+            Format::Env => write!(f, "env"),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => write!(f, "msgpack"),
+            #[cfg(feature = "parquet")]
+            Format::Parquet => write!(f, "parquet"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "env" | "dotenv" => Ok(Format::Env),
+            #[cfg(feature = "msgpack")]
+            "msgpack" | "messagepack" => Ok(Format::MessagePack),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(format!("unknown or disabled format {}", s)),
+        }
+    }
+}
+
+/// Write records in a format
+pub fn export(records: &[(String, String)], format: Format) -> Result<String, String> {
+    let mut writer = RecordWriter::new(vec![], format)?;
+    for (key, value) in records {
+        writer.write(key, value)?;
+    }
+    String::from_utf8(writer.finish()?).map_err(|_| format!("{} can't be written as text", format))
+}
+
+/// Writes records in a format one at a time, so an export never holds the whole store in memory
+pub struct RecordWriter<W: Write + Send> {
+    inner: Inner<W>,
+    count: u64,
+}
+
+enum Inner<W: Write + Send> {
+    Json(W),
+    Csv(Box<csv::Writer<W>>),
+    Env(W),
+    #[cfg(feature = "msgpack")]
+    MessagePack(W),
+    #[cfg(feature = "parquet")]
+    Parquet(Box<ParquetWriter<W>>),
+}
+
+impl<W: Write + Send> RecordWriter<W> {
+    pub fn new(writer: W, format: Format) -> Result<RecordWriter<W>, String> {
+        let inner = match format {
+            Format::Json => Inner::Json(writer),
+            Format::Csv => Inner::Csv(Box::new(csv::Writer::from_writer(writer))),
+            Format::Env => Inner::Env(writer),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => Inner::MessagePack(writer),
+            #[cfg(feature = "parquet")]
+            Format::Parquet => Inner::Parquet(Box::new(ParquetWriter::new(writer).map_err(|e| e.to_string())?)),
+        };
+        Ok(RecordWriter { inner, count: 0 })
+    }
+
+    pub fn write(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.write_with_times(key, value, None, None)
+    }
+
+    /// Write a record along with when it was created and last updated, in seconds since the epoch.
+    /// Only formats with columns for them keep the times.
+    #[cfg_attr(not(feature = "parquet"), allow(unused_variables))]
+    pub fn write_with_times(&mut self, key: &str, value: &str, created_at: Option<i64>, updated_at: Option<i64>) -> Result<(), String> {
+        let first = self.count == 0;
+        self.count += 1;
+        match &mut self.inner {
+            // laid out as serde_json's pretty printer would
+            Inner::Json(writer) => {
+                let key = serde_json::to_string(key).map_err(|e| e.to_string())?;
+                let value = serde_json::to_string(value).map_err(|e| e.to_string())?;
+                write!(writer, "{}  {}: {}", if first { "{\n" } else { ",\n" }, key, value).map_err(|e| e.to_string())
+            },
+            Inner::Csv(writer) => writer.write_record([key, value]).map_err(|e| e.to_string()),
+            Inner::Env(writer) => {
+                if key.is_empty() || key.contains(|c: char| c == '=' || c == '#' || c.is_whitespace()) {
+                    return Err(format!("key {:?} can't be written as an env variable", key));
+                }
+                writeln!(writer, "{}=\"{}\"", key, escape_env(value)).map_err(|e| e.to_string())
+            },
+            #[cfg(feature = "msgpack")]
+            Inner::MessagePack(writer) => rmp_serde::encode::write(writer, &(key, value)).map_err(|e| e.to_string()),
+            #[cfg(feature = "parquet")]
+            Inner::Parquet(writer) => writer.write(key, value, created_at, updated_at).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Finish the output, flushing it and handing back the writer
+    pub fn finish(self) -> Result<W, String> {
+        let mut writer = match self.inner {
+            Inner::Json(mut writer) => {
+                writer.write_all(if self.count == 0 { b"{}\n" } else { b"\n}\n" }).map_err(|e| e.to_string())?;
+                writer
+            },
+            Inner::Csv(writer) => writer.into_inner().map_err(|e| e.to_string())?,
+            Inner::Env(writer) => writer,
+            #[cfg(feature = "msgpack")]
+            Inner::MessagePack(writer) => writer,
+            #[cfg(feature = "parquet")]
+            Inner::Parquet(writer) => writer.finish().map_err(|e| e.to_string())?,
+        };
+        writer.flush().map_err(|e| e.to_string())?;
+        Ok(writer)
+    }
+}
+
+/// Rows buffered in a parquet row group at most, bounding the memory an export uses
+#[cfg(feature = "parquet")]
+const PARQUET_ROW_GROUP: usize = 64 * 1024;
+
+/// Buffers records into row groups of columns, written out as each fills up
+#[cfg(feature = "parquet")]
+struct ParquetWriter<W: Write + Send> {
+    file: parquet::file::writer::SerializedFileWriter<W>,
+    keys: Vec<parquet::data_type::ByteArray>,
+    values: Vec<parquet::data_type::ByteArray>,
+    created_at: Vec<Option<i64>>,
+    updated_at: Vec<Option<i64>>,
+}
+
+#[cfg(feature = "parquet")]
+impl<W: Write + Send> ParquetWriter<W> {
+    fn new(writer: W) -> parquet::errors::Result<ParquetWriter<W>> {
+        use std::sync::Arc;
+        use parquet::{basic::Compression, file::{properties::WriterProperties, writer::SerializedFileWriter}, schema::parser::parse_message_type};
+
+        let schema = parse_message_type("
+            message record {
+                REQUIRED BYTE_ARRAY key (UTF8);
+                REQUIRED BYTE_ARRAY value (UTF8);
+                OPTIONAL INT64 created_at (TIMESTAMP(MILLIS, true));
+                OPTIONAL INT64 updated_at (TIMESTAMP(MILLIS, true));
+            }
+        ")?;
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        Ok(ParquetWriter {
+            file: SerializedFileWriter::new(writer, Arc::new(schema), Arc::new(properties))?,
+            keys: vec![],
+            values: vec![],
+            created_at: vec![],
+            updated_at: vec![],
+        })
+    }
+
+    fn write(&mut self, key: &str, value: &str, created_at: Option<i64>, updated_at: Option<i64>) -> parquet::errors::Result<()> {
+        self.keys.push(key.as_bytes().into());
+        self.values.push(value.as_bytes().into());
+        self.created_at.push(created_at.map(|secs| secs * 1000));
+        self.updated_at.push(updated_at.map(|secs| secs * 1000));
+        if self.keys.len() == PARQUET_ROW_GROUP {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> parquet::errors::Result<()> {
+        use parquet::data_type::{ByteArrayType, Int64Type};
+
+        if self.keys.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = self.file.next_row_group()?;
+        for strings in [&mut self.keys, &mut self.values] {
+            let mut column = row_group.next_column()?.expect("a column for every field");
+            column.typed::<ByteArrayType>().write_batch(strings, None, None)?;
+            column.close()?;
+            strings.clear();
+        }
+        for times in [&mut self.created_at, &mut self.updated_at] {
+            // missing times are nulls, left out of the values and marked by a definition level of 0
+            let levels: Vec<i16> = times.iter().map(|time| time.is_some() as i16).collect();
+            let values: Vec<i64> = times.iter().flatten().copied().collect();
+            let mut column = row_group.next_column()?.expect("a column for every field");
+            column.typed::<Int64Type>().write_batch(&values, Some(&levels), None)?;
+            column.close()?;
+            times.clear();
+        }
+        row_group.close()?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> parquet::errors::Result<W> {
+        self.flush()?;
+        self.file.into_inner()
+    }
+}
+
+/// Read records written in a format
+pub fn import(input: &str, format: Format) -> Result<Vec<(String, String)>, String> {
+    let mut records = vec![];
+    read_records(input.as_bytes(), format, |key, value| {
+        records.push((key, value));
+        Ok(())
+    })?;
+    Ok(records)
+}
+
+/// Read records written in a format one at a time, without holding the whole input in memory
+pub fn read_records(reader: impl Read, format: Format, mut on_record: impl FnMut(String, String) -> Result<(), String>) -> Result<(), String> {
+    match format {
+        Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+            deserializer.deserialize_map(RecordVisitor(&mut on_record)).map_err(|e| e.to_string())?;
+            deserializer.end().map_err(|e| e.to_string())
+        },
+        Format::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(reader);
+            for record in reader.records() {
+                let record = record.map_err(|e| e.to_string())?;
+                match (record.get(0), record.get(1), record.len()) {
+                    (Some(key), Some(value), 2) => on_record(key.to_string(), value.to_string())?,
+                    _ => return Err(format!("expected key,value on line {}", record.position().map_or(0, |pos| pos.line()))),
+                }
+            }
+            Ok(())
+        },
+        Format::Env => {
+            for (i, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line.map_err(|e| e.to_string())?;
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let line = line.strip_prefix("export ").unwrap_or(line);
+                let (key, value) = line.split_once('=').ok_or(format!("expected KEY=value on line {}", i + 1))?;
+                on_record(key.trim().to_string(), unquote_env(value.trim()))?;
+            }
+            Ok(())
+        },
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack => {
+            let mut reader = BufReader::new(reader);
+            while !reader.fill_buf().map_err(|e| e.to_string())?.is_empty() {
+                let (key, value): (String, String) = rmp_serde::from_read(&mut reader).map_err(|e| e.to_string())?;
+                on_record(key, value)?;
+            }
+            Ok(())
+        },
+        #[cfg(feature = "parquet")]
+        Format::Parquet => Err("records can't be imported from parquet, only exported to it".to_string()),
+    }
+}
+
+/// Hands each entry of a JSON object to a callback as it's parsed
+struct RecordVisitor<'a, F>(&'a mut F);
+
+impl<'de, F: FnMut(String, String) -> Result<(), String>> Visitor<'de> for RecordVisitor<'_, F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an object mapping keys to values")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some((key, value)) = map.next_entry::<String, Value>()? {
+            let value = match value {
+                Value::String(value) => value,
+                value => value.to_string(),
+            };
+            (self.0)(key, value).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// The environment variable a key is exported as: uppercased, with every character that can't be
+/// in a shell variable name replaced by _, and _ in front if it would start with a digit, so
+/// app.db-host becomes APP_DB_HOST
+pub fn env_name(key: &str) -> String {
+    let mut name: String = key.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn escape_env(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '$' => escaped.push_str("\\$"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unquote_env(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return value[1..value.len() - 1].to_string();
+    }
+    let value = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(value) => value,
+        None => return value.to_string(),
+    };
+
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
@@ -0,0 +1,46 @@
+use regex::bytes::RegexBuilder;
+use sqlx::Row;
+use tokio_stream::{Stream, StreamExt};
+use crate::{KVLite, DsError, Filter, ListOptions};
+
+/// How grep matches records
+#[derive(Debug, Default, Clone)]
+pub struct GrepOptions {
+    /// Match letters of either case
+    pub ignore_case: bool,
+    /// Also match the pattern against keys, so a record matches if either does
+    pub keys: bool,
+    /// Only records passing this filter are read
+    pub filter: Filter,
+}
+
+impl KVLite {
+    /// Stream the live records whose value, or key if asked, matches a regular expression, in key
+    /// order. Values are matched as get returns them, after undoing the store's transforms, and as
+    /// bytes, so binary values can be matched too. Records are read one at a time as the stream is
+    /// polled rather than all at once.
+    pub async fn grep(&self, pattern: &str, options: &GrepOptions) -> Result<impl Stream<Item = Result<(String, Vec<u8>), DsError>> + 'static, DsError> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(options.ignore_case)
+            .build()
+            .map_err(|e| DsError::Sql(sqlx::Error::Configuration(e.to_string().into())))?;
+        let decode = self.value_decoder();
+        let keys = options.keys;
+        let rows = self.stream_records(&ListOptions { sort: true, filter: options.filter.clone(), ..Default::default() }).await?;
+        Ok(rows.filter_map(move |row| {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => return Some(Err(e)),
+            };
+            let key: String = row.get("key");
+            let value = match decode(row.get("value")) {
+                Ok(value) => value,
+                Err(e) => return Some(Err(e)),
+            };
+            match regex.is_match(&value) || (keys && regex.is_match(key.as_bytes())) {
+                true => Some(Ok((key, value))),
+                false => None,
+            }
+        }))
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::SystemTime;
+use sqlx::{sqlite::SqliteConnectOptions, QueryBuilder, Row};
+use crate::{KVLite, DsError, Collation, Table, history::epoch_secs, natural_cmp, now, to_time};
+
+/// Table the datastore's samples are kept in, which isn't a store of its own
+const SAMPLES: &str = "dsr_stats_history";
+
+/// The size of a datastore at one time, sampled by the daemon to chart its growth
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsSample {
+    pub at: SystemTime,
+    /// Size of the datastore file in bytes
+    pub file_size: u64,
+    /// Live records across every store
+    pub records: u64,
+}
+
+impl KVLite {
+    /// Record the datastore's size and records now in its stats history
+    pub async fn sample_stats(&self) -> Result<StatsSample, DsError> {
+        let stats = self.datastore_stats().await?;
+        let sample = StatsSample { at: to_time(now()), file_size: stats.file_size, records: stats.records() };
+        let mut conn = self.pool.acquire().await?;
+        self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (at INTEGER NOT NULL, file_size INTEGER NOT NULL, records INTEGER NOT NULL)
+            "#, Table::new(SAMPLES)))
+            .build())
+            .await?;
+        self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (at, file_size, records) VALUES (
+            "#, Table::new(SAMPLES)))
+            .push_bind(epoch_secs(sample.at))
+            .push(",")
+            .push_bind(sample.file_size as i64)
+            .push(",")
+            .push_bind(sample.records as i64)
+            .push(")")
+            .build())
+            .await?;
+        Ok(sample)
+    }
+
+    /// Sample a datastore's stats without opening one of its stores, which would create it
+    pub(crate) async fn sample_stats_at(options: SqliteConnectOptions) -> Result<StatsSample, DsError> {
+        let options = options.collation("natural", natural_cmp);
+        let pool = KVLite::pool_options(1).connect_with(options).await?;
+        // the store name is never read, as with stats_at
+        let store = KVLite::with_pool(pool, "store", Collation::Binary);
+        let sample = store.sample_stats().await;
+        // the connection isn't kept, so a daemon closing the datastore when it's idle can
+        store.pool.close().await;
+        sample
+    }
+
+    /// The datastore's samples, oldest first, empty if it's never been sampled
+    pub async fn stats_history(&self) -> Result<Vec<StatsSample>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if !self.table_exists(&mut conn, &Table::new(SAMPLES)).await? {
+            return Ok(vec![]);
+        }
+        Ok(self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT at, file_size, records FROM {} ORDER BY at, rowid
+            "#, Table::new(SAMPLES)))
+            .build())
+            .await?
+            .iter()
+            .map(|row| StatsSample {
+                at: to_time(row.get("at")),
+                file_size: row.get::<i64, &str>("file_size") as u64,
+                records: row.get::<i64, &str>("records") as u64,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,404 @@
+use std::{io, net::SocketAddr, path::Path, pin::Pin, sync::Arc, time::{Duration, SystemTime}};
+use sqlx::Row;
+use tokio::net::TcpListener;
+use tokio_stream::{Stream, StreamExt, wrappers::TcpListenerStream};
+use tonic::{Code, Request, Response, Status, metadata::{Ascii, MetadataValue}, transport::{Channel, Server}};
+use crate::{KVLite, DsError, ChangeEvent, ChangeFilter, ChangeKind, Filter, KvBackend, ListOptions, Role, SetOptions, server::Auth};
+
+/// The messages and service generated from proto/dsr.proto
+pub mod proto {
+    tonic::include_proto!("dsr");
+}
+
+use proto::{store_client::StoreClient, store_server::StoreServer};
+
+mod cache;
+mod queue;
+use cache::Cache;
+use queue::Queue;
+pub use queue::QueuedWrite;
+
+/// How often the change log is polled for a watch that doesn't say
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+fn status(e: DsError) -> Status {
+    match e {
+        DsError::NotFound => Status::not_found(e.to_string()),
+        DsError::StoreLocked => Status::aborted(e.to_string()),
+        DsError::ReadOnly => Status::failed_precondition(e.to_string()),
+        DsError::InvalidStoreName(_) => Status::invalid_argument(e.to_string()),
+        e => Status::internal(e.to_string()),
+    }
+}
+
+/// The error a server's status stands for, the reverse of status
+fn error(status: Status) -> DsError {
+    match status.code() {
+        Code::NotFound => DsError::NotFound,
+        Code::Aborted => DsError::StoreLocked,
+        Code::FailedPrecondition => DsError::ReadOnly,
+        Code::Unavailable => DsError::Io(io::Error::new(io::ErrorKind::ConnectionRefused, status.message().to_string())),
+        _ => DsError::Sql(sqlx::Error::Configuration(status.message().into())),
+    }
+}
+
+/// The Store service, answering for one store
+struct Service {
+    store: Arc<KVLite>,
+    auth: Arc<Auth>,
+}
+
+impl Service {
+    /// Check a call's credentials and role as the HTTP server does, from its authorization
+    /// metadata. Returns the source of the call's writes, None if the server is open.
+    async fn authorize<T>(&self, request: &Request<T>, writing: bool) -> Result<Option<String>, Status> {
+        if self.auth.is_open() {
+            return Ok(None);
+        }
+        let identity = self.auth.identify(&request.metadata().clone().into_headers()).await
+            .ok_or_else(|| Status::unauthenticated("missing or unknown credentials"))?;
+        match self.auth.role(&identity) {
+            Some(Role::ReadWrite) => Ok(Some(identity.source)),
+            Some(Role::ReadOnly) if !writing => Ok(Some(identity.source)),
+            _ => Err(Status::permission_denied("the client can't do that with the store")),
+        }
+    }
+}
+
+type Streamed<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl proto::store_server::Store for Service {
+    async fn get(&self, request: Request<proto::GetRequest>) -> Result<Response<proto::GetReply>, Status> {
+        self.authorize(&request, false).await?;
+        let request = request.into_inner();
+        // read before the value, so a write in between makes the value look older than it is, never newer
+        let version = self.store.session().await.map_err(status)?.to_string();
+        if request.cached_version.as_deref() == Some(version.as_str()) {
+            return match self.store.contains(&request.key).await.map_err(status)? {
+                true => Ok(Response::new(proto::GetReply { value: vec![], version, not_modified: true })),
+                false => Err(status(DsError::NotFound)),
+            };
+        }
+        let value = self.store.get_bytes(&request.key).await.map_err(status)?;
+        Ok(Response::new(proto::GetReply { value, version, not_modified: false }))
+    }
+
+    async fn set(&self, request: Request<proto::SetRequest>) -> Result<Response<proto::SetReply>, Status> {
+        let source = self.authorize(&request, true).await?;
+        let request = request.into_inner();
+        let options = SetOptions {
+            ttl: request.ttl.map(Duration::from_secs),
+            tag: request.tag.map(|tag| tag.parse()).transpose().map_err(Status::invalid_argument)?,
+            source,
+            ..Default::default()
+        };
+        self.store.set_with(&request.key, request.value.as_slice(), &options).await.map_err(status)?;
+        Ok(Response::new(proto::SetReply {}))
+    }
+
+    async fn delete(&self, request: Request<proto::DeleteRequest>) -> Result<Response<proto::DeleteReply>, Status> {
+        self.authorize(&request, true).await?;
+        self.store.del(&request.into_inner().key).await.map_err(status)?;
+        Ok(Response::new(proto::DeleteReply {}))
+    }
+
+    type ScanStream = Streamed<proto::Record>;
+
+    async fn scan(&self, request: Request<proto::ScanRequest>) -> Result<Response<Self::ScanStream>, Status> {
+        self.authorize(&request, false).await?;
+        let request = request.into_inner();
+        let options = ListOptions {
+            sort: true,
+            limit: request.limit,
+            filter: Filter { prefix: request.prefix, value_contains: request.contains, glob: request.glob, ..Default::default() },
+            ..Default::default()
+        };
+        let store = self.store.clone();
+        let records = match request.values {
+            true => self.store.stream_records(&options).await,
+            false => self.store.stream_keys(&options).await,
+        };
+        let records = records.map_err(status)?.map(move |row| {
+            let row = row.map_err(status)?;
+            let value = match request.values {
+                true => store.untransform_value(row.get("value")).map_err(status)?,
+                false => vec![],
+            };
+            Ok(proto::Record { key: row.get("key"), value })
+        });
+        Ok(Response::new(Box::pin(records)))
+    }
+
+    type WatchStream = Streamed<proto::Change>;
+
+    async fn watch(&self, request: Request<proto::WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        self.authorize(&request, false).await?;
+        let request = request.into_inner();
+        let filter: ChangeFilter = request.filter.parse().map_err(Status::invalid_argument)?;
+        let interval = match request.interval_ms {
+            0 => WATCH_INTERVAL,
+            ms => Duration::from_millis(ms),
+        };
+        let changes = self.store.watch_filtered(&filter, interval).await.map_err(status)?.map(|change| {
+            let change = change.map_err(status)?;
+            Ok(proto::Change { kind: change.kind.to_string(), key: change.key, value: change.value })
+        });
+        Ok(Response::new(Box::pin(changes)))
+    }
+}
+
+/// Serve a store over gRPC, with the Store service in proto/dsr.proto, to the clients auth lets in.
+/// Calls send credentials as an HTTP request would, in authorization metadata. Usage isn't counted,
+/// so tokens' quotas don't apply.
+pub async fn serve(store: KVLite, addr: SocketAddr, auth: Auth) -> io::Result<()> {
+    serve_on(Arc::new(store), TcpListener::bind(addr).await?, auth).await
+}
+
+pub(crate) async fn serve_on(store: Arc<KVLite>, listener: TcpListener, auth: Auth) -> io::Result<()> {
+    Server::builder()
+        .add_service(StoreServer::new(Service { store, auth: Arc::new(auth) }))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+        .map_err(io::Error::other)
+}
+
+/// What replaying a client's queued writes did
+#[derive(Debug, Default)]
+pub struct Replayed {
+    pub applied: u64,
+    /// Writes to records changed on the server since the client last knew their values, which
+    /// weren't sent
+    pub conflicts: Vec<QueuedWrite>,
+    /// Writes the server refused, with why
+    pub rejected: Vec<(QueuedWrite, DsError)>,
+}
+
+/// Whether an error is the server not being reachable, as opposed to it refusing a call
+fn unreachable(e: &DsError) -> bool {
+    matches!(e, DsError::Io(e) if e.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+/// A connection to a store served with serve --grpc, with the record operations of KvBackend
+#[derive(Clone)]
+pub struct Client {
+    inner: StoreClient<Channel>,
+    url: String,
+    /// Sent with every call when the server wants a bearer token
+    authorization: Option<MetadataValue<Ascii>>,
+    /// Where values read are kept, if they are
+    cache: Option<Arc<Cache>>,
+    /// Where writes are kept while the server can't be reached, if they are
+    queue: Option<Arc<Queue>>,
+}
+
+impl Client {
+    /// Connect to a server at an http:// URL, like http://127.0.0.1:50051, sending a bearer token
+    /// with every call if given
+    pub async fn connect(url: &str, token: Option<&str>) -> Result<Client, DsError> {
+        let inner = StoreClient::connect(url.to_string()).await.map_err(io::Error::other)?;
+        Client::new(url, token, inner)
+    }
+
+    /// A client for a server at an http:// URL that connects when it's first used, and keeps the
+    /// sets and deletes it can't send, because the server can't be reached or writes made before
+    /// are still waiting, in a SQLite file until they're sent with replay
+    pub async fn connect_queued(url: &str, token: Option<&str>, queue: &Path) -> Result<Client, DsError> {
+        let channel = Channel::from_shared(url.to_string()).map_err(io::Error::other)?.connect_lazy();
+        let mut client = Client::new(url, token, StoreClient::new(channel))?;
+        client.queue = Some(Arc::new(Queue::open(queue).await?));
+        Ok(client)
+    }
+
+    fn new(url: &str, token: Option<&str>, inner: StoreClient<Channel>) -> Result<Client, DsError> {
+        let authorization = token.map(|token| format!("Bearer {}", token).parse())
+            .transpose()
+            .map_err(|_| DsError::Sql(sqlx::Error::Configuration("the token can't be sent in metadata".into())))?;
+        Ok(Client { inner, url: url.to_string(), authorization, cache: None, queue: None })
+    }
+
+    /// Keep the values read with get in a SQLite file, and only have the server send a value
+    /// again once its store has been written since it was cached. Every write to the store
+    /// changes its version, so a busy store's values are sent again just as often.
+    pub async fn cache_in(mut self, path: &Path) -> Result<Client, DsError> {
+        self.cache = Some(Arc::new(Cache::open(path).await?));
+        Ok(self)
+    }
+
+    /// The writes queued for the server, oldest first
+    pub async fn queued(&self) -> Result<Vec<QueuedWrite>, DsError> {
+        match &self.queue {
+            Some(queue) => queue.pending(&self.url).await,
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Send the queued writes to the server in the order they were made. A write to a record whose
+    /// value on the server is neither the one the client last knew it to have nor the one written
+    /// is a conflict, and isn't sent. Conflicting and refused writes are taken off the queue and
+    /// returned. Replaying stops, keeping the rest queued, if the server can't be reached.
+    /// Records are read and written in separate calls, so a write made by another client in
+    /// between isn't seen as a conflict.
+    pub async fn replay(&self) -> Result<Replayed, DsError> {
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return Ok(Replayed::default()),
+        };
+        let mut replayed = Replayed::default();
+        for write in queue.pending(&self.url).await? {
+            if let Some(base) = &write.base {
+                let request = proto::GetRequest { key: write.key.clone(), cached_version: None };
+                let current = match self.inner.clone().get(self.request(request)).await.map_err(error) {
+                    Ok(reply) => Some(reply.into_inner().value),
+                    Err(DsError::NotFound) => None,
+                    Err(e) => return Err(e),
+                };
+                if current != *base && current != write.value {
+                    queue.remove(&write).await?;
+                    replayed.conflicts.push(write);
+                    continue;
+                }
+            }
+            match self.send(&write).await {
+                Ok(()) => replayed.applied += 1,
+                Err(e) if unreachable(&e) => return Err(e),
+                Err(e) => {
+                    queue.remove(&write).await?;
+                    replayed.rejected.push((write, e));
+                    continue;
+                },
+            }
+            queue.remove(&write).await?;
+        }
+        Ok(replayed)
+    }
+
+    /// Send a write, a set that's since expired being as good as sent
+    async fn send(&self, write: &QueuedWrite) -> Result<(), DsError> {
+        let value = match &write.value {
+            Some(value) => value,
+            None => {
+                self.inner.clone().delete(self.request(proto::DeleteRequest { key: write.key.clone() })).await.map_err(error)?;
+                return Ok(());
+            },
+        };
+        let ttl = match write.expires_at.map(|at| at.duration_since(SystemTime::now())) {
+            // rounded up, so a TTL sent as soon as it's set isn't cut short by a second
+            Some(Ok(ttl)) => Some(ttl.as_millis().div_ceil(1000) as u64),
+            Some(Err(_)) => return Ok(()),
+            None => None,
+        };
+        let request = proto::SetRequest { key: write.key.clone(), value: value.clone(), ttl, tag: write.tag.clone() };
+        self.inner.clone().set(self.request(request)).await.map_err(error)?;
+        Ok(())
+    }
+
+    /// Send a write, or queue it if there's a queue and the server can't be reached or other
+    /// writes are already waiting for it
+    async fn write(&self, write: QueuedWrite) -> Result<(), DsError> {
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return self.send(&write).await,
+        };
+        if queue.pending(&self.url).await?.is_empty() {
+            match self.send(&write).await {
+                Err(e) if unreachable(&e) => (),
+                sent => return sent,
+            }
+        }
+        // what the client last read is the base conflicts are found against
+        let base = match &self.cache {
+            Some(cache) => cache.get(&self.url, &write.key).await?.map(|(_, value)| Some(value)),
+            None => None,
+        };
+        queue.push(&self.url, &QueuedWrite { base, ..write }).await
+    }
+
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request.metadata_mut().insert("authorization", authorization.clone());
+        }
+        request
+    }
+
+    /// The live records matching a filter, in key order, at most limit of them. Values are only
+    /// sent when asked for, and are empty otherwise.
+    pub async fn scan(&self, filter: &Filter, limit: Option<u64>, values: bool) -> Result<Vec<(String, Vec<u8>)>, DsError> {
+        let request = proto::ScanRequest {
+            prefix: filter.prefix.clone(),
+            glob: filter.glob.clone(),
+            contains: filter.value_contains.clone(),
+            limit,
+            values,
+        };
+        let mut records = self.inner.clone().scan(self.request(request)).await.map_err(error)?.into_inner();
+        let mut scanned = vec![];
+        while let Some(record) = records.next().await {
+            let record = record.map_err(error)?;
+            scanned.push((record.key, record.value));
+        }
+        Ok(scanned)
+    }
+
+    /// Stream the changes to the store passing a filter, made by any process, until the stream is dropped
+    pub async fn watch(&self, filter: &ChangeFilter, interval: Duration) -> Result<impl Stream<Item = Result<ChangeEvent, DsError>>, DsError> {
+        let request = proto::WatchRequest { filter: filter.to_string(), interval_ms: interval.as_millis() as u64 };
+        let changes = self.inner.clone().watch(self.request(request)).await.map_err(error)?.into_inner();
+        Ok(changes.map(|change| {
+            let change = change.map_err(error)?;
+            Ok(ChangeEvent { kind: change.kind.parse().unwrap_or(ChangeKind::Set), key: change.key, value: change.value })
+        }))
+    }
+}
+
+impl KvBackend for Client {
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => {
+                let reply = self.inner.clone().get(self.request(proto::GetRequest { key: key.to_string(), cached_version: None })).await.map_err(error)?;
+                return Ok(reply.into_inner().value);
+            },
+        };
+        let cached = cache.get(&self.url, key).await?;
+        let request = proto::GetRequest { key: key.to_string(), cached_version: cached.as_ref().map(|(version, _)| version.clone()) };
+        match self.inner.clone().get(self.request(request)).await.map_err(error).map(Response::into_inner) {
+            Ok(reply) if reply.not_modified => Ok(cached.map(|(_, value)| value).unwrap_or_default()),
+            Ok(reply) => {
+                cache.put(&self.url, key, &reply.version, &reply.value).await?;
+                Ok(reply.value)
+            },
+            Err(DsError::NotFound) => {
+                cache.remove(&self.url, key).await?;
+                Err(DsError::NotFound)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        self.write(QueuedWrite {
+            expires_at: options.ttl.map(|ttl| SystemTime::now() + ttl),
+            tag: options.tag.map(|tag| tag.to_string()),
+            ..QueuedWrite::new(key, Some(value.to_vec()))
+        })
+        .await
+    }
+
+    async fn del(&self, key: &str) -> Result<(), DsError> {
+        self.write(QueuedWrite::new(key, None)).await
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, DsError> {
+        match KvBackend::get_bytes(self, key).await {
+            Ok(_) => Ok(true),
+            Err(DsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list_keys(&self, filter: &Filter, limit: Option<u64>) -> Result<Vec<String>, DsError> {
+        Ok(self.scan(filter, limit, false).await?.into_iter().map(|(key, _)| key).collect())
+    }
+}
@@ -0,0 +1,54 @@
+use std::{path::Path, str::FromStr};
+use sqlx::{Row, sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions}};
+use crate::DsError;
+
+/// Values a client has read from servers, each with the version of the store it was read at,
+/// kept in a SQLite file so they outlive the process
+#[derive(Debug)]
+pub(super) struct Cache {
+    pool: SqlitePool,
+}
+
+impl Cache {
+    pub(super) async fn open(path: &Path) -> Result<Cache, DsError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", path.display()))?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new().max_connections(1).connect_with(options).await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS cache (url TEXT NOT NULL, key TEXT NOT NULL, version TEXT NOT NULL, value BLOB NOT NULL, PRIMARY KEY (url, key))")
+            .execute(&pool)
+            .await?;
+        Ok(Cache { pool })
+    }
+
+    /// The version and value cached for a key of the store at a URL
+    pub(super) async fn get(&self, url: &str, key: &str) -> Result<Option<(String, Vec<u8>)>, DsError> {
+        Ok(sqlx::query("SELECT version, value FROM cache WHERE url = ? AND key = ?")
+            .bind(url)
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| (row.get("version"), row.get("value"))))
+    }
+
+    pub(super) async fn put(&self, url: &str, key: &str, version: &str, value: &[u8]) -> Result<(), DsError> {
+        sqlx::query("INSERT OR REPLACE INTO cache (url, key, version, value) VALUES (?, ?, ?, ?)")
+            .bind(url)
+            .bind(key)
+            .bind(version)
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn remove(&self, url: &str, key: &str) -> Result<(), DsError> {
+        sqlx::query("DELETE FROM cache WHERE url = ? AND key = ?")
+            .bind(url)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
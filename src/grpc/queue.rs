@@ -0,0 +1,105 @@
+use std::{path::Path, str::FromStr, time::SystemTime};
+use sqlx::{Row, sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions}};
+use crate::{DsError, history::epoch_secs, to_time};
+
+/// A write made while its server couldn't be reached, waiting to be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedWrite {
+    pub key: String,
+    /// The value set, None for a delete
+    pub value: Option<Vec<u8>>,
+    pub expires_at: Option<SystemTime>,
+    pub tag: Option<String>,
+    pub queued_at: SystemTime,
+    /// The value the client last knew the record to have when the write was made, None if it
+    /// knew it didn't have one, or unknown if it didn't know
+    pub(super) base: Option<Option<Vec<u8>>>,
+    pub(super) id: i64,
+}
+
+impl QueuedWrite {
+    pub(super) fn new(key: &str, value: Option<Vec<u8>>) -> QueuedWrite {
+        QueuedWrite { key: key.to_string(), value, expires_at: None, tag: None, queued_at: SystemTime::now(), base: None, id: 0 }
+    }
+}
+
+/// Writes kept in a SQLite file until their servers can be reached, in the order they were made
+#[derive(Debug)]
+pub(super) struct Queue {
+    pool: SqlitePool,
+}
+
+impl Queue {
+    pub(super) async fn open(path: &Path) -> Result<Queue, DsError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", path.display()))?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new().max_connections(1).connect_with(options).await?;
+        sqlx::query(r#"
+                CREATE TABLE IF NOT EXISTS queue (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT, url TEXT NOT NULL, key TEXT NOT NULL, value BLOB,
+                    expires_at INTEGER, tag TEXT, queued_at INTEGER NOT NULL, base_known INTEGER NOT NULL, base BLOB
+                )
+            "#)
+            .execute(&pool)
+            .await?;
+        Ok(Queue { pool })
+    }
+
+    /// The writes queued for the server at a URL, oldest first
+    pub(super) async fn pending(&self, url: &str) -> Result<Vec<QueuedWrite>, DsError> {
+        Ok(sqlx::query("SELECT id, key, value, expires_at, tag, queued_at, base_known, base FROM queue WHERE url = ? ORDER BY id")
+            .bind(url)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| QueuedWrite {
+                key: row.get("key"),
+                value: row.get("value"),
+                expires_at: row.get::<Option<i64>, &str>("expires_at").map(to_time),
+                tag: row.get("tag"),
+                queued_at: to_time(row.get("queued_at")),
+                base: row.get::<bool, &str>("base_known").then(|| row.get("base")),
+                id: row.get("id"),
+            })
+            .collect())
+    }
+
+    /// Queue a write for the server at a URL. Its base is the value of the last write queued for
+    /// the key if there is one, as that's what the record will have when it's sent, or else the
+    /// one given.
+    pub(super) async fn push(&self, url: &str, write: &QueuedWrite) -> Result<(), DsError> {
+        let mut tx = self.pool.begin().await?;
+        let last = sqlx::query("SELECT value FROM queue WHERE url = ? AND key = ? ORDER BY id DESC LIMIT 1")
+            .bind(url)
+            .bind(&write.key)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let base = match last {
+            Some(row) => Some(row.get::<Option<Vec<u8>>, &str>("value")),
+            None => write.base.clone(),
+        };
+        sqlx::query("INSERT INTO queue (url, key, value, expires_at, tag, queued_at, base_known, base) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(url)
+            .bind(&write.key)
+            .bind(&write.value)
+            .bind(write.expires_at.map(epoch_secs))
+            .bind(&write.tag)
+            .bind(epoch_secs(write.queued_at))
+            .bind(base.is_some())
+            .bind(base.flatten())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub(super) async fn remove(&self, write: &QueuedWrite) -> Result<(), DsError> {
+        sqlx::query("DELETE FROM queue WHERE id = ?")
+            .bind(write.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use sqlx::{sqlite::{SqliteConnection, SqliteRow}, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Filter, Stored, Table, dedup, to_time};
+
+/// A value a record had while its store was versioned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// Versions of a key count up from 1
+    pub version: i64,
+    /// The value set, None if the record was deleted or expired
+    pub value: Option<Vec<u8>>,
+    pub content_type: Option<String>,
+    pub expires_at: Option<SystemTime>,
+    /// When the version was written
+    pub at: SystemTime,
+    /// Who wrote the version, if the write had a source
+    pub source: Option<String>,
+}
+
+impl KVLite {
+    fn history_table(&self) -> Table {
+        self.kv_name.object("history")
+    }
+
+    /// The history with its values as written, read from the store's blobs if it keeps them there
+    fn readable_history(&self) -> String {
+        dedup::resolved_table(&self.history_table(), self.blobs().as_ref(), &["key", "version", "content_type", "expires_at", "deleted", "at", "source"])
+    }
+
+    /// Whether every write to the store is kept in its history table
+    pub async fn is_versioned(&self) -> Result<bool, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="trigger" AND name=
+            "#)
+            .push_bind(self.kv_name.object("history_insert").name)
+            .build())
+            .await?
+            .is_some())
+    }
+
+    /// Start keeping every write to the store in a history table, maintained by triggers so
+    /// writes from every process are kept. Turning versioning off deletes the history.
+    pub async fn set_versioned(&self, versioned: bool) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        match versioned {
+            true => self.create_history(&mut conn).await,
+            false => {
+                self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                        DROP TRIGGER IF EXISTS {insert};
+                        DROP TRIGGER IF EXISTS {update};
+                        DROP TRIGGER IF EXISTS {delete};
+                        DROP TABLE IF EXISTS {history};
+                    "#,
+                        history = self.history_table(),
+                        insert = self.kv_name.object("history_insert"),
+                        update = self.kv_name.object("history_update"),
+                        delete = self.kv_name.object("history_delete")))
+                    .build())
+                    .await?;
+                Ok(())
+            },
+        }
+    }
+
+    async fn create_history(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        let history = self.history_table();
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {history} (
+                    key TEXT NOT NULL COLLATE {collation}, version INTEGER NOT NULL, value, content_type TEXT,
+                    expires_at INTEGER, deleted INTEGER NOT NULL DEFAULT 0, at INTEGER NOT NULL, source TEXT,
+                    PRIMARY KEY (key, version)
+                );
+                CREATE TRIGGER IF NOT EXISTS {insert} AFTER INSERT ON {table} BEGIN
+                    INSERT INTO {history} (key, version, value, content_type, expires_at, at, source)
+                        SELECT NEW.key, coalesce(max(version), 0) + 1, NEW.value, NEW.content_type, NEW.expires_at, {now}, NEW.source
+                        FROM {history} WHERE key = NEW.key;
+                END;
+                CREATE TRIGGER IF NOT EXISTS {update} AFTER UPDATE ON {table} BEGIN
+                    INSERT INTO {history} (key, version, value, content_type, expires_at, at, source)
+                        SELECT NEW.key, coalesce(max(version), 0) + 1, NEW.value, NEW.content_type, NEW.expires_at, {now}, NEW.source
+                        FROM {history} WHERE key = NEW.key;
+                END;
+                CREATE TRIGGER IF NOT EXISTS {delete} AFTER DELETE ON {table} BEGIN
+                    INSERT INTO {history} (key, version, deleted, at)
+                        SELECT OLD.key, coalesce(max(version), 0) + 1, 1, {now}
+                        FROM {history} WHERE key = OLD.key;
+                END;
+            "#,
+                history = history.unqualified(),
+                table = self.kv_name,
+                insert = self.kv_name.object("history_insert"),
+                update = self.kv_name.object("history_update"),
+                delete = self.kv_name.object("history_delete"),
+                collation = self.collation.sql(),
+                now = "CAST(strftime('%s', 'now') AS INTEGER)"))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Keep the source of each version in a history made before sources were kept
+    pub(crate) async fn upgrade_history(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        let columns: Vec<String> = self.fetch_all(conn, QueryBuilder::new("SELECT name FROM pragma_table_info(")
+            .push_bind(self.history_table().name)
+            .push(")")
+            .build())
+            .await?
+            .iter()
+            .map(|row| row.get("name"))
+            .collect();
+        if columns.is_empty() || columns.iter().any(|column| column == "source") {
+            return Ok(());
+        }
+        let mut tx = conn.begin().await?;
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                ALTER TABLE {history} ADD COLUMN source TEXT;
+                DROP TRIGGER {insert};
+                DROP TRIGGER {update};
+            "#,
+                history = self.history_table(),
+                insert = self.kv_name.object("history_insert"),
+                update = self.kv_name.object("history_update")))
+            .build())
+            .await?;
+        self.create_history(&mut tx).await?;
+        Ok(tx.commit().await?)
+    }
+
+    /// A version read from the history, with its value as written
+    fn version(&self, row: &SqliteRow) -> Result<Version, DsError> {
+        Ok(Version {
+            version: row.get("version"),
+            value: match row.get::<bool, &str>("deleted") {
+                true => None,
+                false => Some(self.untransform_value(row.get::<Option<Vec<u8>>, &str>("value").unwrap_or_default())?),
+            },
+            content_type: row.get("content_type"),
+            expires_at: row.get::<Option<i64>, &str>("expires_at").map(to_time),
+            at: to_time(row.get("at")),
+            source: row.get("source"),
+        })
+    }
+
+    /// Every version of a key, oldest first
+    pub async fn history(&self, key: &str) -> Result<Vec<Version>, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let rows = self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT * FROM {} WHERE key=
+            "#, self.readable_history()))
+            .push_bind(key)
+            .push(" ORDER BY version")
+            .build())
+            .await?;
+        rows.iter().map(|row| self.version(row)).collect()
+    }
+
+    /// One version of a key
+    pub async fn get_version(&self, key: &str, version: i64) -> Result<Version, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.get_version_in(&mut conn, key, Some(version)).await
+    }
+
+    /// A version of a key, or its latest version when None
+    async fn get_version_in(&self, conn: &mut SqliteConnection, key: &str, version: Option<i64>) -> Result<Version, DsError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT * FROM {} WHERE key=
+            "#, self.readable_history()));
+        query.push_bind(key);
+        match version {
+            Some(version) => query.push(" AND version=").push_bind(version),
+            None => query.push(" ORDER BY version DESC LIMIT 1"),
+        };
+        self.version(&self.fetch_one(conn, query.build()).await?)
+    }
+
+    /// The version of a key that was current at a past time, found from the store's history, so
+    /// NotFound if it hadn't been written since the store was versioned. Its value is None if it was
+    /// deleted by then.
+    pub async fn get_as_of(&self, key: &str, at: SystemTime) -> Result<Version, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let version = self.version(&self.fetch_one(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT * FROM {} WHERE key=
+            "#, self.readable_history()))
+            .push_bind(key)
+            .push(" AND at <= ")
+            .push_bind(epoch_secs(at))
+            .push(" ORDER BY version DESC LIMIT 1")
+            .build())
+            .await?)?;
+        Ok(match version.expires_at {
+            Some(expires_at) if expires_at <= at => Version { value: None, ..version },
+            _ => version,
+        })
+    }
+
+    /// The records matching a filter that existed at a past time, with the version each had then,
+    /// in key order. Only writes made since the store was versioned are known. Versions don't keep
+    /// types, so a filter by update time or type fails.
+    pub async fn records_as_of(&self, at: SystemTime, filter: &Filter) -> Result<Vec<(String, Version)>, DsError> {
+        if filter.updated_since.is_some() || filter.updated_before.is_some() || filter.value_tag.is_some() {
+            return Err(DsError::Sql(sqlx::Error::Configuration("the history can't be filtered by update time or type".into())));
+        }
+        self.check_filter(filter)?;
+        let mut conn = self.pool.acquire().await?;
+        let at = epoch_secs(at);
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT history.* FROM {readable} history
+                JOIN (SELECT key, max(version) AS version FROM {history} WHERE at <= 
+            "#, readable = self.readable_history(), history = self.history_table()));
+        query.push_bind(at)
+            .push(" GROUP BY key) latest USING (key, version) WHERE NOT deleted AND (expires_at IS NULL OR expires_at > ")
+            .push_bind(at)
+            .push(")");
+        filter.push_conditions(&self.kv_name, None, &mut query, &mut true);
+        query.push(" ORDER BY key");
+        let rows = self.fetch_all(&mut conn, query.build()).await?;
+        rows.iter().map(|row| Ok((row.get("key"), self.version(row)?))).collect()
+    }
+
+    /// Delete the values of every version of some keys from the history, if the store is versioned,
+    /// keeping only the versions recording their deletion
+    pub(crate) async fn erase_history(&self, conn: &mut SqliteConnection, keys: &[&str]) -> Result<(), DsError> {
+        let versioned = self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(self.history_table().name)
+            .build())
+            .await?
+            .is_some();
+        if !versioned || keys.is_empty() {
+            return Ok(());
+        }
+        let mut query = QueryBuilder::new(format!("DELETE FROM {} WHERE NOT deleted AND key IN (", self.history_table()));
+        let mut separated = query.separated(", ");
+        for key in keys {
+            separated.push_bind(*key);
+        }
+        query.push(")");
+        self.execute(conn, query.build()).await?;
+        Ok(())
+    }
+
+    /// Restore a key to an earlier version, the one before its latest if not given,
+    /// deleting the record if it didn't exist then. The restore is itself a new version.
+    /// Returns the version restored.
+    pub async fn rollback(&self, key: &str, to: Option<i64>) -> Result<i64, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+
+        let to = match to {
+            Some(to) => to,
+            None => self.get_version_in(&mut tx, key, None).await?.version - 1,
+        };
+        let version = self.get_version_in(&mut tx, key, Some(to)).await?;
+        let stored = Stored {
+            expires_at: version.expires_at.map(|expires_at| expires_at.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs() as i64),
+            content_type: version.content_type.as_deref(),
+            tag: None,
+            source: None,
+        };
+        match &version.value {
+            Some(value) => match std::str::from_utf8(value) {
+                Ok(text) => self.set_in(&mut tx, key, text, stored).await?,
+                Err(_) => self.set_in(&mut tx, key, value.as_slice(), stored).await?,
+            },
+            None => self.del_in(&mut tx, key).await?,
+        }
+        tx.commit().await?;
+
+        match &version.value {
+            Some(value) => self.notify(ChangeKind::Set, key, Some(&String::from_utf8_lossy(value))),
+            None => self.notify(ChangeKind::Delete, key, None),
+        }
+        Ok(to)
+    }
+}
+
+pub(crate) fn epoch_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |secs| secs.as_secs() as i64)
+}
@@ -0,0 +1,146 @@
+use std::{process::{Command, Stdio}, thread};
+use sqlx::{sqlite::SqliteConnection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeEvent, ChangeFilter};
+
+/// A shell command run whenever a write through a store handle passes a filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    /// Hooks count up from 1 in the order they were added
+    pub id: i64,
+    /// Which changes run the command. Hooks only see the key and kind of a change, so tag: terms
+    /// and changed aren't allowed.
+    pub filter: ChangeFilter,
+    /// Run with sh -c, or cmd /C on Windows, with DSR_STORE, DSR_KEY, DSR_KIND and, for sets,
+    /// DSR_VALUE in its environment
+    pub command: String,
+}
+
+impl Hook {
+    /// Start the hook's command for a change without waiting for it, its output going where the
+    /// handle's does. It's reaped on another thread, so a long-running handle doesn't collect zombies.
+    fn run(&self, store: &str, event: &ChangeEvent) {
+        #[cfg(unix)]
+        let mut command = {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&self.command);
+            command
+        };
+        #[cfg(not(unix))]
+        let mut command = {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(&self.command);
+            command
+        };
+        command.stdin(Stdio::null())
+            .env("DSR_STORE", store)
+            .env("DSR_KEY", &event.key)
+            .env("DSR_KIND", event.kind.to_string());
+        match &event.value {
+            Some(value) => command.env("DSR_VALUE", value),
+            None => command.env_remove("DSR_VALUE"),
+        };
+        match command.spawn() {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            },
+            Err(e) => eprintln!("warning: hook {} couldn't be run: {}", self.id, e),
+        }
+    }
+}
+
+impl KVLite {
+    async fn create_hook_table(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY AUTOINCREMENT, filter TEXT NOT NULL, command TEXT NOT NULL)
+            "#, self.kv_name.object("hooks")))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// The hooks saved with the store, oldest first
+    pub(crate) async fn load_hooks(&self, conn: &mut SqliteConnection) -> Result<Vec<Hook>, DsError> {
+        let hooks = self.kv_name.object("hooks");
+        let exists = self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(hooks.name.as_str())
+            .build())
+            .await?
+            .is_some();
+        if !exists {
+            return Ok(vec![]);
+        }
+        Ok(self.fetch_all(conn, QueryBuilder::new(format!(r#"
+                SELECT id, filter, command FROM {} ORDER BY id
+            "#, hooks))
+            .build())
+            .await?
+            .iter()
+            .map(|row| Hook {
+                id: row.get("id"),
+                filter: row.get::<String, &str>("filter").parse().unwrap_or_default(),
+                command: row.get("command"),
+            })
+            .collect())
+    }
+
+    /// The hooks the handle runs, oldest first
+    pub fn hooks(&self) -> Vec<Hook> {
+        self.hooks.read().unwrap().clone()
+    }
+
+    /// Read the store's hooks again, picking up those added or removed through other handles
+    pub async fn reload_hooks(&self) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let hooks = self.load_hooks(&mut conn).await?;
+        *self.hooks.write().unwrap() = hooks;
+        Ok(())
+    }
+
+    /// Run a shell command whenever a write through any handle opened after this passes a filter,
+    /// saved with the store. Returns the hook's id.
+    pub async fn add_hook(&mut self, filter: &ChangeFilter, command: &str) -> Result<i64, DsError> {
+        if !filter.tags.is_empty() || filter.changed_only {
+            return Err(DsError::Sql(sqlx::Error::Configuration("hooks can't filter by tag: or changed".into())));
+        }
+        let mut conn = self.pool.acquire().await?;
+        self.create_hook_table(&mut conn).await?;
+        let id = self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (filter, command) VALUES (
+            "#, self.kv_name.object("hooks")))
+            .push_bind(filter.to_string())
+            .push(",")
+            .push_bind(command)
+            .push(")")
+            .build())
+            .await?
+            .last_insert_rowid();
+
+        self.hooks.get_mut().unwrap().push(Hook { id, filter: filter.clone(), command: command.to_string() });
+        Ok(id)
+    }
+
+    /// Stop running a hook, returning whether there was one with the id
+    pub async fn remove_hook(&mut self, id: i64) -> Result<bool, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_hook_table(&mut conn).await?;
+        let removed = self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE id =
+            "#, self.kv_name.object("hooks")))
+            .push_bind(id)
+            .build())
+            .await?
+            .rows_affected() > 0;
+
+        self.hooks.get_mut().unwrap().retain(|hook| hook.id != id);
+        Ok(removed)
+    }
+
+    /// Start the hooks a change passes
+    pub(crate) fn run_hooks(&self, event: &ChangeEvent) {
+        for hook in self.hooks.read().unwrap().iter().filter(|hook| hook.filter.matches(event, None)) {
+            hook.run(&self.kv_name.name, event);
+        }
+    }
+}
@@ -0,0 +1,7452 @@
+use std::{str::FromStr, collections::{HashMap, HashSet}, io::Read, sync::{Arc, atomic::{AtomicU64, Ordering}}, fmt, cmp, ops::{RangeBounds, Bound}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use sqlx::{sqlite::{SqlitePool, SqlitePoolOptions, SqliteConnectOptions, SqliteRow, SqliteConnection, SqliteArguments, SqliteQueryResult}, query::Query, QueryBuilder, Row, Connection, Sqlite, Execute, Encode, Type};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{Stream, StreamExt, wrappers::{BroadcastStream, ReceiverStream}};
+use format::Format;
+
+mod acl;
+pub use acl::Role;
+mod audit;
+pub use audit::AuditEntry;
+mod backend;
+pub use backend::{KvBackend, MemoryBackend};
+mod backup;
+mod bench;
+pub use bench::{BenchOptions, BenchResult, BENCH_PREFIX};
+mod blob;
+#[cfg(feature = "blocking")]
+mod blocking;
+#[cfg(feature = "blocking")]
+pub use blocking::KVLiteBlocking;
+mod collection;
+mod dedup;
+mod encoding;
+pub use encoding::Encoding;
+mod error;
+pub use error::DsError;
+mod feed;
+pub use feed::FeedEvent;
+pub mod format;
+mod grep;
+pub use grep::GrepOptions;
+mod growth;
+pub use growth::StatsSample;
+mod history;
+mod hooks;
+pub use hooks::Hook;
+pub use history::Version;
+mod lock;
+mod migrate;
+pub use migrate::{Migration, SchemaVersion, MIGRATIONS, SCHEMA_VERSION};
+mod maintain;
+pub use maintain::{MaintenanceReport, Recommendation};
+mod merge;
+pub use merge::{MergeChoice, MergeConflict};
+mod options;
+pub use options::KVLiteOptions;
+mod pointer;
+mod queue;
+mod record;
+pub use record::Record;
+mod refs;
+pub use refs::OnReferenced;
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "libsql"))]
+mod remote;
+#[cfg(feature = "libsql")]
+pub use remote::LibsqlBackend;
+#[cfg(feature = "mysql")]
+pub use remote::MySqlBackend;
+#[cfg(feature = "postgres")]
+pub use remote::PostgresBackend;
+mod replica;
+pub use replica::{Change, Changes, Session};
+mod search;
+mod snapshot;
+pub use snapshot::{Snapshot, SavedSnapshot};
+mod sync;
+pub use sync::{SyncStrategy, SyncReport};
+mod tags;
+mod template;
+pub use template::Template;
+mod transaction;
+pub use transaction::Transaction;
+mod transfer;
+mod transform;
+pub use transform::{Codec, KeyTransform, ValueTransform};
+mod verify;
+pub use verify::{Corrupted, Repair, Verification};
+mod watch;
+pub use watch::ChangeFilter;
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(feature = "scripting")]
+pub use script::EvalError;
+#[cfg(all(feature = "daemon", unix))]
+pub mod daemon;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod resp;
+#[cfg(feature = "tui")]
+pub mod tui;
+
+type SqliteQuery<'q> = Query<'q, Sqlite, SqliteArguments<'q>>;
+
+/// Quote an identifier for SQL, so it can hold spaces, quotes and reserved words
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// A table, or another object like an index or trigger, named by the user or after a store.
+/// It's quoted when formatted, so every name is safe to put into SQL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Table {
+    /// The attached database the table is in, the main database if None
+    schema: Option<String>,
+    name: String,
+}
+
+impl Table {
+    fn new(name: &str) -> Table {
+        Table { schema: None, name: name.to_string() }
+    }
+
+    fn in_schema(schema: &str, name: &str) -> Table {
+        Table { schema: Some(schema.to_string()), name: name.to_string() }
+    }
+
+    /// An object belonging to a store, named after it and in the same schema
+    fn object(&self, suffix: &str) -> Table {
+        Table { schema: self.schema.clone(), name: format!("{}_{}", self.name, suffix) }
+    }
+
+    /// The store's own table if there's no suffix, otherwise one of its objects
+    fn object_or_self(&self, suffix: &Option<&str>) -> Table {
+        match suffix {
+            Some(suffix) => self.object(suffix),
+            None => self.clone(),
+        }
+    }
+
+    /// The quoted name without its schema, as CREATE INDEX ... ON and trigger bodies need
+    fn unqualified(&self) -> String {
+        quote(&self.name)
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.schema {
+            Some(schema) => write!(f, "{}.{}", quote(schema), quote(&self.name)),
+            None => write!(f, "{}", quote(&self.name)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueryStats {
+    pub statements: u64,
+    pub rows: u64,
+}
+
+/// The type every value in a store must have
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    /// Text or bytes
+    Any,
+    /// Valid UTF-8 text
+    Text,
+    /// Integers, as incr writes them
+    Integer,
+    /// Valid JSON
+    Json,
+    /// The key of a record in another store, or in this store when None
+    Reference(Option<String>),
+}
+
+impl ValueType {
+    /// A condition true for values of other types
+    fn invalid_sql(&self, value: &str) -> Option<String> {
+        match self {
+            ValueType::Any => None,
+            ValueType::Text | ValueType::Reference(_) => Some(format!("typeof({0}) NOT IN ('text', 'integer')", value)),
+            ValueType::Integer => Some(format!("CAST(CAST({0} AS INTEGER) AS TEXT) IS NOT CAST({0} AS TEXT)", value)),
+            ValueType::Json => Some(format!("typeof({0}) = 'blob' OR NOT json_valid({0})", value)),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Any => write!(f, "any"),
+            ValueType::Text => write!(f, "text"),
+            ValueType::Integer => write!(f, "integer"),
+            ValueType::Json => write!(f, "json"),
+            ValueType::Reference(None) => write!(f, "reference"),
+            ValueType::Reference(Some(store)) => write!(f, "reference:{}", store),
+        }
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(store) = s.strip_prefix("reference:") {
+            return Ok(ValueType::Reference(Some(store.to_string())));
+        }
+        match s.to_lowercase().as_str() {
+            "any" => Ok(ValueType::Any),
+            "text" => Ok(ValueType::Text),
+            "integer" => Ok(ValueType::Integer),
+            "json" => Ok(ValueType::Json),
+            "reference" => Ok(ValueType::Reference(None)),
+            _ => Err(format!("unknown value type {}, expected any, text, integer, json, reference or reference:<store>", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Explain {
+    Off,
+    /// Print each statement, skipping writes
+    Sql,
+    /// Print each statement and the query plan of reads, skipping writes
+    QueryPlan,
+}
+
+/// Ordering and equality of keys, chosen when a store is created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Collation {
+    /// Compare keys byte by byte
+    Binary,
+    /// Compare keys ignoring ASCII case
+    Nocase,
+    /// Compare runs of digits by their numeric value, so key2 sorts before key10
+    Natural,
+    /// Compare keys using the ICU collation rules of a locale
+    #[cfg(feature = "icu")]
+    Locale(String),
+}
+
+impl Collation {
+    fn sql(&self) -> String {
+        match self {
+            Collation::Binary => "BINARY".to_string(),
+            Collation::Nocase => "NOCASE".to_string(),
+            // natural is a keyword, so it has to be quoted
+            Collation::Natural => "\"natural\"".to_string(),
+            #[cfg(feature = "icu")]
+            Collation::Locale(locale) => format!("\"icu_{}\"", locale),
+        }
+    }
+
+    fn from_schema(sql: &str) -> Collation {
+        // only look at the column definitions, store names can hold anything
+        let start = sql.to_ascii_uppercase().rfind("KEY TEXT PRIMARY KEY").unwrap_or(0);
+        let sql = &sql[start..];
+        let upper = sql.to_ascii_uppercase();
+        if upper.contains("COLLATE \"NATURAL\"") {
+            return Collation::Natural
+        } else if upper.contains("COLLATE NOCASE") {
+            return Collation::Nocase
+        }
+
+        #[cfg(feature = "icu")]
+        if let Some(start) = upper.find("COLLATE \"ICU_") {
+            let locale = &sql[start + "COLLATE \"ICU_".len()..];
+            if let Some(end) = locale.find('"') {
+                return Collation::Locale(locale[..end].to_string())
+            }
+        }
+
+        Collation::Binary
+    }
+
+    #[cfg(feature = "icu")]
+    fn register(&self, options: SqliteConnectOptions) -> Result<SqliteConnectOptions, DsError> {
+        use icu_collator::{CollatorBorrowed, options::CollatorOptions};
+
+        match self {
+            Collation::Locale(locale) => {
+                let parsed = locale.parse::<icu_locale_core::Locale>()
+                    .map_err(|e| DsError::Sql(sqlx::Error::Configuration(Box::new(e))))?;
+                let collator = CollatorBorrowed::try_new((&parsed).into(), CollatorOptions::default())
+                    .map_err(|e| DsError::Sql(sqlx::Error::Configuration(Box::new(e))))?;
+                Ok(options.collation(format!("icu_{}", locale), move |a, b| collator.compare(a, b).then_with(|| a.cmp(b))))
+            },
+            _ => Ok(options),
+        }
+    }
+}
+
+impl fmt::Display for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Collation::Binary => write!(f, "binary"),
+            Collation::Nocase => write!(f, "nocase"),
+            Collation::Natural => write!(f, "natural"),
+            #[cfg(feature = "icu")]
+            Collation::Locale(locale) => write!(f, "icu:{}", locale),
+        }
+    }
+}
+
+impl FromStr for Collation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(feature = "icu")]
+        if let Some(locale) = s.strip_prefix("icu:") {
+            return locale.parse::<icu_locale_core::Locale>()
+                .map(|locale| Collation::Locale(locale.to_string()))
+                .map_err(|e| format!("invalid locale {}: {}", locale, e))
+        }
+
+        match s.to_lowercase().as_str() {
+            "binary" => Ok(Collation::Binary),
+            "nocase" => Ok(Collation::Nocase),
+            "natural" => Ok(Collation::Natural),
+            _ => Err(format!("unknown collation {}", s)),
+        }
+    }
+}
+
+/// Compare two strings treating each run of ASCII digits as a number.
+/// Ties fall back to a byte comparison so distinct keys never compare equal.
+pub fn natural_cmp(a: &str, b: &str) -> cmp::Ordering {
+    let (mut a_chars, mut b_chars) = (a.as_bytes(), b.as_bytes());
+
+    while let (Some(&x), Some(&y)) = (a_chars.first(), b_chars.first()) {
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let a_len = a_chars.iter().take_while(|c| c.is_ascii_digit()).count();
+            let b_len = b_chars.iter().take_while(|c| c.is_ascii_digit()).count();
+            let a_num = trim_zeros(&a_chars[..a_len]);
+            let b_num = trim_zeros(&b_chars[..b_len]);
+
+            let ord = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+            if ord != cmp::Ordering::Equal {
+                return ord;
+            }
+            a_chars = &a_chars[a_len..];
+            b_chars = &b_chars[b_len..];
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            a_chars = &a_chars[1..];
+            b_chars = &b_chars[1..];
+        }
+    }
+
+    a_chars.len().cmp(&b_chars.len()).then_with(|| a.cmp(b))
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+    &digits[zeros..]
+}
+
+/// Conditions a record has to meet to be listed, evaluated by SQLite
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    /// Only records whose key starts with this prefix
+    pub prefix: Option<String>,
+    /// Only records whose value contains this text
+    pub value_contains: Option<String>,
+    /// Only records whose key matches this glob pattern, as glob_match does
+    pub glob: Option<String>,
+    /// Only records whose key has been given this tag with tag_key
+    pub key_tag: Option<String>,
+    /// Only records last written at or after this time
+    pub updated_since: Option<SystemTime>,
+    /// Only records last written before this time. Records written by older versions, which
+    /// don't know when they were last written, go by when they were created, or else pass.
+    pub updated_before: Option<SystemTime>,
+    /// Only records set with this type
+    pub value_tag: Option<ValueTag>,
+}
+
+impl Filter {
+    /// Add the filter's conditions to a query of a table of the store's records, its history or a
+    /// snapshot of it. Those read straight from a store that keeps values in blobs pass its blobs,
+    /// so values are matched as written rather than by their references.
+    fn push_conditions<'a>(&'a self, kv_name: &Table, blobs: Option<&Table>, query: &mut QueryBuilder<'a, Sqlite>, has_where: &mut bool) {
+        if let Some(prefix) = &self.prefix {
+            push_where(query, has_where);
+            query.push("substr(key, 1, length(").push_bind(prefix).push(")) = ").push_bind(prefix);
+        }
+        if let Some(contains) = &self.value_contains {
+            push_where(query, has_where);
+            match blobs {
+                Some(blobs) => {
+                    let reference = format!("substr(value, 1, {}) = '{}'", dedup::BLOB_REF.len(), dedup::BLOB_REF);
+                    query.push(format!("CASE WHEN {} THEN substr(value, {}) IN (SELECT hash FROM {} WHERE instr(value, ",
+                            reference, dedup::BLOB_REF.len() + 1, blobs))
+                        .push_bind(contains)
+                        .push(") > 0) ELSE instr(value, ")
+                        .push_bind(contains)
+                        .push(") > 0 END");
+                },
+                None => {
+                    query.push("instr(value, ").push_bind(contains).push(") > 0");
+                },
+            }
+        }
+        if let Some(glob) = &self.glob {
+            // sqlite's GLOB also has character classes, so [ is matched as itself
+            push_where(query, has_where);
+            query.push("key GLOB ").push_bind(glob.replace('[', "[[]"));
+        }
+        if let Some(tag) = &self.key_tag {
+            push_where(query, has_where);
+            query.push(format!("key IN (SELECT key FROM {} WHERE tag = ", kv_name.object("key_tags"))).push_bind(tag).push(")");
+        }
+        if let Some(since) = self.updated_since {
+            push_where(query, has_where);
+            query.push("updated_at >= ").push_bind(history::epoch_secs(since));
+        }
+        if let Some(before) = self.updated_before {
+            push_where(query, has_where);
+            query.push("coalesce(updated_at, created_at, 0) < ").push_bind(history::epoch_secs(before));
+        }
+        if let Some(tag) = self.value_tag {
+            push_where(query, has_where);
+            query.push("tag = ").push_bind(tag.to_string());
+        }
+    }
+}
+
+fn push_where(query: &mut QueryBuilder<'_, Sqlite>, has_where: &mut bool) {
+    query.push(if *has_where { " AND " } else { " WHERE " });
+    *has_where = true;
+}
+
+/// Seconds since the unix epoch, the unit expires_at is stored in
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as i64)
+}
+
+fn to_time(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+/// Restrict a query to records that haven't expired
+fn push_live(query: &mut QueryBuilder<'_, Sqlite>, has_where: &mut bool) {
+    push_where(query, has_where);
+    query.push("(expires_at IS NULL OR expires_at > ").push_bind(now()).push(")");
+}
+
+/// What a sorted listing is ordered by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// The key, using the store's collation
+    #[default]
+    Key,
+    /// The value, records with equal values ordered by key
+    Value,
+    /// When the record was last written, records written in the same second ordered by key
+    UpdatedAt,
+    /// A random order, different every time, for sampling. Reversing it changes nothing.
+    Random,
+}
+
+impl SortBy {
+    fn order_by(&self, reverse: bool) -> &'static str {
+        match (self, reverse) {
+            (SortBy::Key, false) => " ORDER BY key",
+            (SortBy::Key, true) => " ORDER BY key DESC",
+            (SortBy::Value, false) => " ORDER BY value, key",
+            (SortBy::Value, true) => " ORDER BY value DESC, key DESC",
+            (SortBy::UpdatedAt, false) => " ORDER BY updated_at, key",
+            (SortBy::UpdatedAt, true) => " ORDER BY updated_at DESC, key DESC",
+            (SortBy::Random, _) => " ORDER BY RANDOM()",
+        }
+    }
+}
+
+impl fmt::Display for SortBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortBy::Key => write!(f, "key"),
+            SortBy::Value => write!(f, "value"),
+            SortBy::UpdatedAt => write!(f, "updated_at"),
+            SortBy::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "key" => Ok(SortBy::Key),
+            "value" => Ok(SortBy::Value),
+            "updated_at" | "updated" => Ok(SortBy::UpdatedAt),
+            "random" => Ok(SortBy::Random),
+            _ => Err(format!("can't sort by {}, expected key, value, updated_at or random", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ListOptions {
+    /// Order results by `by`
+    pub sort: bool,
+    /// Order results by `by`, last first
+    pub reverse: bool,
+    /// What results are ordered by, the key unless set
+    pub by: SortBy,
+    /// Return at most this many results
+    pub limit: Option<u64>,
+    /// Only return keys that come after this key in key order
+    pub after: Option<String>,
+    pub filter: Filter,
+}
+
+/// Opaque token marking where a paged listing left off
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub key: String,
+    pub reverse: bool,
+}
+
+impl Cursor {
+    /// Listing options that continue after this cursor
+    pub fn list_options(&self, limit: Option<u64>) -> ListOptions {
+        ListOptions {
+            sort: true,
+            reverse: self.reverse,
+            limit,
+            after: Some(self.key.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.reverse { 'r' } else { 'f' })?;
+        for byte in self.key.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cursor {}", s);
+
+        let reverse = match s.get(..1) {
+            Some("f") => false,
+            Some("r") => true,
+            _ => return Err(invalid()),
+        };
+        let hex = &s[1..];
+        if !hex.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+
+        Ok(Cursor { key: String::from_utf8(bytes).map_err(|_| invalid())?, reverse })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Set,
+    Delete,
+    /// The record's TTL lapsed and it was purged
+    Expired,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeKind::Set => write!(f, "set"),
+            ChangeKind::Delete => write!(f, "delete"),
+            ChangeKind::Expired => write!(f, "expired"),
+        }
+    }
+}
+
+impl FromStr for ChangeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "set" => Ok(ChangeKind::Set),
+            "delete" => Ok(ChangeKind::Delete),
+            "expired" => Ok(ChangeKind::Expired),
+            _ => Err(format!("unknown change {}", s)),
+        }
+    }
+}
+
+/// A change made to a record, through a KVLite handle or read from the change log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub key: String,
+    /// The new value, for sets
+    pub value: Option<String>,
+}
+
+/// Match a key against a glob pattern, where * matches any run of characters and ? any single character
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    let mut backtrack = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, k));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    k = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether a character separates the levels of a hierarchical key, like app.db.host or app/db/host
+fn is_key_separator(c: char) -> bool {
+    c == '.' || c == '/'
+}
+
+/// The levels of a hierarchical key, so app, db and host for app.db.host. Empty levels, from
+/// separators at the start or next to each other, are skipped.
+pub fn key_levels(key: &str) -> impl Iterator<Item = &str> {
+    key.split(is_key_separator).filter(|level| !level.is_empty())
+}
+
+/// A key cut off after its first depth levels below a prefix it starts with, so app.db.host is
+/// app.db at depth 1 below app. Keys without more levels than that are returned whole.
+pub fn key_at_depth<'a>(key: &'a str, prefix: &str, depth: usize) -> &'a str {
+    let start = if key.starts_with(prefix) { prefix.len() } else { 0 };
+    let mut levels = 0;
+    let mut in_level = false;
+    for (i, c) in key[start..].char_indices() {
+        if !is_key_separator(c) {
+            in_level = true;
+            continue;
+        }
+        if in_level {
+            levels += 1;
+            if levels == depth {
+                return &key[..start + i];
+            }
+        }
+        in_level = false;
+    }
+    key
+}
+
+/// Metadata about a record, without its value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordMeta {
+    pub key: String,
+    /// Size of the value in bytes
+    pub size: u64,
+    /// When the record expires, if it has a TTL
+    pub expires_at: Option<SystemTime>,
+    /// MIME type of the value, if one was given when it was set
+    pub content_type: Option<String>,
+    /// When the record was first set, unknown for records written by older versions
+    pub created_at: Option<SystemTime>,
+    /// When the record was last set
+    pub updated_at: Option<SystemTime>,
+    /// Type the value was set with, if any
+    pub tag: Option<ValueTag>,
+    /// Whether the record is pinned, so it never expires
+    pub pinned: bool,
+    /// Who last wrote the record, if the write had a source
+    pub source: Option<String>,
+}
+
+impl RecordMeta {
+    /// The content type without parameters like charset
+    fn mime(&self) -> Option<&str> {
+        self.content_type.as_deref().map(|content_type| content_type.split(';').next().unwrap_or("").trim())
+    }
+
+    /// Whether the value is JSON according to its type or content type
+    pub fn is_json(&self) -> bool {
+        matches!(self.tag, Some(ValueTag::Json | ValueTag::List | ValueTag::Set)) || self.mime().is_some_and(|mime| mime == "application/json" || mime.ends_with("+json"))
+    }
+
+    /// Whether the content type says the value isn't meant to be read as text
+    pub fn is_binary(&self) -> bool {
+        self.mime().is_some_and(|mime| !(mime.starts_with("text/") || self.is_json() || mime.ends_with("+xml")
+            || matches!(mime, "application/xml" | "application/javascript" | "application/toml" | "application/yaml")))
+    }
+}
+
+/// The type of a single record's value, checked whenever it's set with the tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueTag {
+    Int,
+    Float,
+    /// true or false
+    Bool,
+    Json,
+    /// A JSON array of strings, kept by lpush, rpush and lpop
+    List,
+    /// A JSON array of distinct strings, kept by sadd and srem
+    Set,
+    /// Where the record's content is kept outside the store, an absolute path or a file:, http:
+    /// or https: URL, read by get_resolved
+    Pointer,
+}
+
+impl ValueTag {
+    /// Fail unless a value can be read as this type
+    fn check(&self, value: &[u8]) -> Result<(), DsError> {
+        let text = std::str::from_utf8(value).ok();
+        let valid = match self {
+            // written the way sqlite casts integers to text, so incr agrees
+            ValueTag::Int => text.is_some_and(|text| text.parse::<i64>().is_ok_and(|int| int.to_string() == text)),
+            ValueTag::Float => text.and_then(|text| text.parse::<f64>().ok()).is_some_and(f64::is_finite),
+            ValueTag::Bool => matches!(text, Some("true") | Some("false")),
+            ValueTag::Json => serde_json::from_slice::<serde_json::Value>(value).is_ok(),
+            ValueTag::List => serde_json::from_slice::<Vec<String>>(value).is_ok(),
+            ValueTag::Set => serde_json::from_slice::<Vec<String>>(value)
+                .is_ok_and(|members| members.iter().collect::<HashSet<_>>().len() == members.len()),
+            ValueTag::Pointer => text.is_some_and(|text| pointer::Target::parse(text).is_some()),
+        };
+        match valid {
+            true => Ok(()),
+            false => Err(DsError::Sql(sqlx::Error::Decode(format!("{} is not a valid {}", String::from_utf8_lossy(value), self).into()))),
+        }
+    }
+}
+
+impl fmt::Display for ValueTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueTag::Int => write!(f, "int"),
+            ValueTag::Float => write!(f, "float"),
+            ValueTag::Bool => write!(f, "bool"),
+            ValueTag::Json => write!(f, "json"),
+            ValueTag::List => write!(f, "list"),
+            ValueTag::Set => write!(f, "set"),
+            ValueTag::Pointer => write!(f, "pointer"),
+        }
+    }
+}
+
+impl FromStr for ValueTag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "int" => Ok(ValueTag::Int),
+            "float" => Ok(ValueTag::Float),
+            "bool" => Ok(ValueTag::Bool),
+            "json" => Ok(ValueTag::Json),
+            "list" => Ok(ValueTag::List),
+            "set" => Ok(ValueTag::Set),
+            "pointer" => Ok(ValueTag::Pointer),
+            _ => Err(format!("unknown type {}, expected int, float, bool, json, list, set or pointer", s)),
+        }
+    }
+}
+
+/// How a record is written by KVLite::set_with
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SetOptions {
+    /// Expire the record after this long
+    pub ttl: Option<Duration>,
+    /// MIME type stored alongside the value
+    pub content_type: Option<String>,
+    /// Type the value must have, stored alongside it
+    pub tag: Option<ValueTag>,
+    /// Who the write is recorded as coming from, instead of the store handle's source
+    pub source: Option<String>,
+}
+
+/// What's stored alongside a value when a record is written
+#[derive(Debug, Default, Clone, Copy)]
+struct Stored<'a> {
+    /// Seconds since the unix epoch
+    expires_at: Option<i64>,
+    content_type: Option<&'a str>,
+    tag: Option<ValueTag>,
+    /// Overrides the store handle's source
+    source: Option<&'a str>,
+}
+
+impl<'a> Stored<'a> {
+    fn from_options(options: &'a SetOptions) -> Stored<'a> {
+        Stored {
+            expires_at: options.ttl.map(|ttl| now() + ttl.as_secs() as i64),
+            content_type: options.content_type.as_deref(),
+            tag: options.tag,
+            source: options.source.as_deref(),
+        }
+    }
+
+    fn expiring(ttl: Duration) -> Stored<'a> {
+        Stored { expires_at: Some(now() + ttl.as_secs() as i64), ..Default::default() }
+    }
+}
+
+/// Size of a datastore, from datastore_stats
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatastoreStats {
+    /// Size of the datastore file in bytes
+    pub file_size: u64,
+    /// Every store in the datastore, in order
+    pub stores: Vec<StoreStats>,
+}
+
+impl DatastoreStats {
+    /// Live records across every store
+    pub fn records(&self) -> u64 {
+        self.stores.iter().map(|store| store.records).sum()
+    }
+
+    /// Bytes of the keys of live records across every store
+    pub fn key_bytes(&self) -> u64 {
+        self.stores.iter().map(|store| store.key_bytes).sum()
+    }
+
+    /// Bytes of the values of live records across every store
+    pub fn value_bytes(&self) -> u64 {
+        self.stores.iter().map(|store| store.value_bytes).sum()
+    }
+
+    pub fn dedup_saved_bytes(&self) -> u64 {
+        self.stores.iter().map(|store| store.dedup_saved_bytes).sum()
+    }
+}
+
+/// Size of one store, counting only live records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreStats {
+    pub store: String,
+    pub records: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    /// Bytes of the values kept once in the store's blobs, if it deduplicates them
+    pub blob_bytes: u64,
+    /// Bytes the live records referring to blobs would take up beyond them holding their own copies
+    pub dedup_saved_bytes: u64,
+}
+
+/// What an import does with records whose keys are already in the store
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ImportStrategy {
+    /// Replace them with the imported records
+    #[default]
+    Overwrite,
+    /// Keep them, importing only the records the store doesn't have
+    Skip,
+    /// Import nothing if there are any
+    Fail,
+    /// Replace those last written before the imported records were, usually when their file was
+    /// last modified, and keep those written since
+    Newest { written_at: SystemTime },
+}
+
+/// How many records an import wrote and left as they were, by import_with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Records imported under keys the store didn't have
+    pub added: u64,
+    /// Records the store had that were replaced by imported ones
+    pub overwritten: u64,
+    /// Imported records left out because the store already had their keys
+    pub skipped: u64,
+}
+
+/// Outcome of renaming every key with a prefix
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PrefixRename {
+    /// Number of records renamed
+    pub renamed: u64,
+    /// New keys that already belong to records outside the prefix. Nothing is renamed when there are any.
+    pub conflicts: Vec<String>,
+}
+
+/// A single command run by KVLite::batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Set(String, String),
+    Get(String),
+    Contains(String),
+    Delete(String),
+}
+
+/// A write applied with others in one transaction by KVLite::write_all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordWrite {
+    Set { key: String, value: Vec<u8>, options: SetOptions },
+    Delete(String),
+}
+
+/// What a BatchOp returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchResult {
+    Done,
+    /// The value of a get, None if the record doesn't exist
+    Value(Option<String>),
+    Contains(bool),
+}
+
+const CHANGE_CAPACITY: usize = 1024;
+/// Rows a streamed listing reads ahead of its consumer
+const STREAM_CAPACITY: usize = 256;
+/// Entries kept in a store's change log, older ones are pruned when expired records are
+const CHANGE_LOG_LIMIT: i64 = 10000;
+/// Records written per INSERT when importing, well under SQLite's limit on bound parameters
+const IMPORT_BATCH: usize = 1000;
+/// Suffixes of the tables a store keeps besides its own, each named <store>_<suffix>
+const STORE_TABLES: &[&str] = &["changes", "history", "settings", "search", "locks", "audit", "hooks", "snapshots",
+    "snapshot_records", "acl", "checksums", "queue", "key_tags", "blobs", "feed"];
+
+pub struct KVLite {
+    pool: SqlitePool,
+    kv_name: Table,
+    collation: Collation,
+    // shared with the tasks streaming listings
+    statements: Arc<AtomicU64>,
+    rows: Arc<AtomicU64>,
+    explain: Explain,
+    changes: broadcast::Sender<ChangeEvent>,
+    /// Seconds expired records stay readable before they're purged
+    grace: i64,
+    /// Who the handle's writes are recorded as coming from
+    source: Option<String>,
+    /// Whether deletes overwrite what they free, so it can't be recovered from the file
+    secure_delete: bool,
+    /// Seconds records set without a TTL live for, if the store gives them one
+    default_ttl: Option<i64>,
+    /// Applied in order to every key given to the store
+    key_transforms: Vec<KeyTransform>,
+    /// Applied in order to every value written to the store, and undone when it's read
+    value_transforms: Vec<ValueTransform>,
+    /// Bytes from which values are kept once in the store's blobs, if they are
+    dedup: Option<u64>,
+    /// Derived from the passphrase values are encrypted with, never saved
+    #[cfg(feature = "value-encryption")]
+    value_key: Option<[u8; 32]>,
+    /// Run when a write through the handle passes their filter
+    hooks: std::sync::RwLock<Vec<Hook>>,
+}
+
+impl KVLite {
+    async fn execute(&self, conn: &mut SqliteConnection, query: SqliteQuery<'_>) -> Result<SqliteQueryResult, DsError> {
+        if self.explain != Explain::Off {
+            eprintln!("{}", Self::format_sql(query.sql()));
+            return Ok(SqliteQueryResult::default());
+        }
+
+        let res = query.execute(conn).await?;
+        self.record(res.rows_affected());
+        Ok(res)
+    }
+
+    async fn fetch_all(&self, conn: &mut SqliteConnection, query: SqliteQuery<'_>) -> Result<Vec<SqliteRow>, DsError> {
+        self.explain_read(conn, query.sql()).await?;
+        let rows = query.fetch_all(conn).await?;
+        self.record(rows.len() as u64);
+        Ok(rows)
+    }
+
+    async fn fetch_optional(&self, conn: &mut SqliteConnection, query: SqliteQuery<'_>) -> Result<Option<SqliteRow>, DsError> {
+        self.explain_read(conn, query.sql()).await?;
+        let row = query.fetch_optional(conn).await?;
+        self.record(row.is_some() as u64);
+        Ok(row)
+    }
+
+    async fn fetch_one(&self, conn: &mut SqliteConnection, query: SqliteQuery<'_>) -> Result<SqliteRow, DsError> {
+        self.explain_read(conn, query.sql()).await?;
+        let row = query.fetch_one(conn).await?;
+        self.record(1);
+        Ok(row)
+    }
+
+    async fn explain_read(&self, conn: &mut SqliteConnection, sql: &str) -> Result<(), DsError> {
+        if self.explain == Explain::Off {
+            return Ok(());
+        }
+        eprintln!("{}", Self::format_sql(sql));
+
+        if self.explain == Explain::QueryPlan {
+            // unbound parameters are treated as NULL, which doesn't change the plan
+            let plan = sqlx::query(&format!("EXPLAIN QUERY PLAN {}", sql))
+                .fetch_all(conn)
+                .await?;
+            let mut depths = HashMap::new();
+            for step in plan {
+                let depth = depths.get(&step.get::<i64, &str>("parent")).map_or(1, |depth| depth + 1);
+                depths.insert(step.get::<i64, &str>("id"), depth);
+                eprintln!("{}{}", "  ".repeat(depth), step.get::<String, &str>("detail"));
+            }
+        }
+        Ok(())
+    }
+
+    fn format_sql(sql: &str) -> String {
+        sql.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    pub fn set_explain(&mut self, explain: Explain) {
+        self.explain = explain;
+    }
+
+    /// Record the handle's writes as coming from a source, like a user, token, import file or
+    /// peer, kept with each record and with each version in a versioned store. Deletes aren't
+    /// attributed.
+    pub fn set_source(&mut self, source: Option<String>) {
+        self.source = source;
+    }
+
+    /// The source a write is recorded with
+    fn source(&self, stored: &Stored) -> Option<String> {
+        stored.source.or(self.source.as_deref()).map(str::to_string)
+    }
+
+    /// When a write expires, the store's default TTL from now if it wasn't given one
+    fn expires_at(&self, stored: &Stored) -> Option<i64> {
+        stored.expires_at.or(self.default_ttl.map(|ttl| now() + ttl))
+    }
+
+    fn notify(&self, kind: ChangeKind, key: &str, value: Option<&str>) {
+        if self.explain != Explain::Off {
+            return;
+        }
+        let event = ChangeEvent { kind, key: key.to_string(), value: value.map(str::to_string) };
+        self.run_hooks(&event);
+        // sending only fails when there are no subscribers
+        let _ = self.changes.send(event);
+    }
+
+    /// Stream changes made through this handle to keys matching a glob pattern.
+    /// A subscriber that falls more than 1024 events behind skips the events it missed.
+    pub fn subscribe(&self, pattern: &str) -> impl Stream<Item = ChangeEvent> + 'static {
+        let pattern = pattern.to_string();
+        BroadcastStream::new(self.changes.subscribe())
+            .filter_map(move |event| match event {
+                Ok(event) if glob_match(&pattern, &event.key) => Some(event),
+                _ => None,
+            })
+    }
+
+    /// Stream changes to keys matching a glob pattern made by any process, by polling the store's change log.
+    /// Only changes made after the call are streamed, and sets carry the record's value when it's read.
+    pub async fn watch(&self, pattern: &str, interval: Duration) -> Result<impl Stream<Item = Result<ChangeEvent, DsError>> + 'static, DsError> {
+        self.watch_filtered(&ChangeFilter::glob(pattern), interval).await
+    }
+
+    /// The latest changes made to the store by any process, newest first, from its change log.
+    /// Sets carry the record's value as it is now.
+    pub async fn recent_changes(&self, limit: u64) -> Result<Vec<ChangeEvent>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let rows = self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT c.key, c.kind, s.value FROM {} AS c LEFT JOIN {} AS s ON s.key = c.key AND c.kind = 'set'
+                ORDER BY c.seq DESC LIMIT
+            "#, self.kv_name.object("changes"), self.readable_records()))
+            .push_bind(limit as i64)
+            .build())
+            .await?;
+        rows.iter()
+            .map(|row| Ok(ChangeEvent {
+                kind: row.get::<String, &str>("kind").parse().unwrap_or(ChangeKind::Set),
+                key: row.get("key"),
+                value: row.get::<Option<Vec<u8>>, &str>("value")
+                    .map(|value| self.untransform_value(value))
+                    .transpose()?
+                    .map(|value| String::from_utf8_lossy(&value).into_owned()),
+            }))
+            .collect()
+    }
+
+    fn record(&self, rows: u64) {
+        self.statements.fetch_add(1, Ordering::Relaxed);
+        self.rows.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> QueryStats {
+        QueryStats {
+            statements: self.statements.load(Ordering::Relaxed),
+            rows: self.rows.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn create_store_table(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<(), DsError>{
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE {} (key TEXT PRIMARY KEY COLLATE {}, value TEXT, expires_at INTEGER, content_type TEXT, created_at INTEGER, updated_at INTEGER, tag TEXT, pinned INTEGER NOT NULL DEFAULT 0, source TEXT);
+            "#, kv_name, self.collation.sql()))
+            .build())
+            .await?;
+        self.create_expiry_index(conn, kv_name).await?;
+        self.create_change_log(conn, kv_name).await?;
+        self.create_key_tags(conn, kv_name).await?;
+        self.set_schema_version(conn, kv_name, SCHEMA_VERSION).await
+    }
+
+    async fn create_expiry_index(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<(), DsError>{
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE INDEX IF NOT EXISTS {} ON {} (expires_at);
+            "#, kv_name.object("expires_at"), kv_name.unqualified()))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Log every write to a store with triggers, so changes made by other processes can be watched
+    async fn create_change_log(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<(), DsError>{
+        // trigger bodies can only refer to tables in their own schema, so they use unqualified names
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {log} (seq INTEGER PRIMARY KEY AUTOINCREMENT, key TEXT NOT NULL, kind TEXT NOT NULL);
+                CREATE TRIGGER IF NOT EXISTS {insert} AFTER INSERT ON {table} BEGIN
+                    INSERT INTO {changes} (key, kind) VALUES (NEW.key, 'set');
+                END;
+                CREATE TRIGGER IF NOT EXISTS {update} AFTER UPDATE ON {table} BEGIN
+                    INSERT INTO {changes} (key, kind) SELECT OLD.key, 'delete' WHERE OLD.key IS NOT NEW.key;
+                    INSERT INTO {changes} (key, kind) VALUES (NEW.key, 'set');
+                END;
+                CREATE TRIGGER IF NOT EXISTS {delete} AFTER DELETE ON {table} BEGIN
+                    INSERT INTO {changes} (key, kind) VALUES (OLD.key,
+                        CASE WHEN OLD.expires_at <= CAST(strftime('%s', 'now') AS INTEGER) THEN 'expired' ELSE 'delete' END);
+                END;
+            "#,
+                log = kv_name.object("changes"),
+                insert = kv_name.object("log_insert"),
+                update = kv_name.object("log_update"),
+                delete = kv_name.object("log_delete"),
+                table = kv_name.unqualified(),
+                changes = kv_name.object("changes").unqualified()))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Bring a store created by an older version up to the current schema
+    async fn upgrade_store_table(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<(), DsError> {
+        let mut query = QueryBuilder::new("SELECT name FROM pragma_table_info(");
+        query.push_bind(kv_name.name.as_str());
+        if let Some(schema) = &kv_name.schema {
+            query.push(", ").push_bind(schema.as_str());
+        }
+        let columns: Vec<String> = self.fetch_all(conn, query.push(")").build())
+            .await?
+            .iter()
+            .map(|row| row.get("name"))
+            .collect();
+        if columns.is_empty() {
+            return Err(DsError::NotFound);
+        }
+
+        if !columns.iter().any(|column| column == "expires_at") {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    ALTER TABLE {} ADD COLUMN expires_at INTEGER
+                "#, kv_name))
+                .build())
+                .await?;
+            self.create_expiry_index(conn, kv_name).await?;
+        }
+        if !columns.iter().any(|column| column == "content_type") {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    ALTER TABLE {} ADD COLUMN content_type TEXT
+                "#, kv_name))
+                .build())
+                .await?;
+        }
+        // records written before timestamps were kept have neither
+        if !columns.iter().any(|column| column == "created_at") {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    ALTER TABLE {0} ADD COLUMN created_at INTEGER;
+                    ALTER TABLE {0} ADD COLUMN updated_at INTEGER;
+                "#, kv_name))
+                .build())
+                .await?;
+        }
+        if !columns.iter().any(|column| column == "tag") {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    ALTER TABLE {} ADD COLUMN tag TEXT
+                "#, kv_name))
+                .build())
+                .await?;
+        }
+        if !columns.iter().any(|column| column == "pinned") {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    ALTER TABLE {} ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0
+                "#, kv_name))
+                .build())
+                .await?;
+        }
+        if !columns.iter().any(|column| column == "source") {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    ALTER TABLE {} ADD COLUMN source TEXT
+                "#, kv_name))
+                .build())
+                .await?;
+        }
+        // the triggers go with the table if it's dropped, so check for them rather than the log
+        let master = match &kv_name.schema {
+            Some(schema) => Table::in_schema(schema, "sqlite_master"),
+            None => Table::new("sqlite_master"),
+        };
+        let triggers = self.fetch_optional(conn, QueryBuilder::new(format!(r#"
+                SELECT name FROM {} WHERE type="trigger" AND name=
+            "#, master))
+            .push_bind(kv_name.object("log_insert").name)
+            .build())
+            .await?;
+        if triggers.is_none() {
+            self.create_change_log(conn, kv_name).await?;
+        }
+        Ok(())
+    }
+
+    /// Encrypt every page of the datastore with SQLCipher, keyed by a passphrase.
+    /// A new datastore is encrypted with it, and opening an existing one fails unless it matches.
+    #[cfg(feature = "encryption")]
+    pub fn encrypted(options: SqliteConnectOptions, passphrase: &str) -> SqliteConnectOptions {
+        options.pragma("key", format!("'{}'", passphrase.replace('\'', "''")))
+    }
+
+    /// Options for a datastore file, or for a datastore kept in memory for as long as the store's
+    /// connections are open if filename is memory:
+    pub fn options(filename: &str, read_only: bool, create_new: bool) -> Result<SqliteConnectOptions, DsError> {
+        // every connection to sqlite::memory: options shares one datastore
+        let filename = match filename {
+            options::MEMORY => "sqlite::memory:",
+            filename => filename,
+        };
+        Ok(SqliteConnectOptions::from_str(filename)?
+            .create_if_missing(create_new)
+            .read_only(read_only))
+    }
+
+    pub async fn new(filename: &str, kv_name: &str, read_only: bool, create_new: bool) -> Result<KVLite, DsError> {
+        KVLite::connect_with(KVLite::options(filename, read_only, create_new)?, kv_name, Collation::Binary).await
+    }
+
+    /// Open a store, creating it with the given key collation if it doesn't exist yet.
+    /// An existing store keeps the collation it was created with.
+    pub async fn connect_with(options: SqliteConnectOptions, kv_name: &str, collation: Collation) -> Result<KVLite, DsError> {
+        KVLite::connect_pool(options, options::DEFAULT_POOL_SIZE, kv_name, collation).await
+    }
+
+    fn with_pool(pool: SqlitePool, kv_name: &str, collation: Collation) -> KVLite {
+        KVLite {
+            pool,
+            kv_name: Table::new(kv_name),
+            collation,
+            statements: Arc::default(),
+            rows: Arc::default(),
+            explain: Explain::Off,
+            changes: broadcast::channel(CHANGE_CAPACITY).0,
+            grace: 0,
+            source: None,
+            secure_delete: false,
+            default_ttl: None,
+            key_transforms: vec![],
+            value_transforms: vec![],
+            dedup: None,
+            #[cfg(feature = "value-encryption")]
+            value_key: None,
+            hooks: Default::default(),
+        }
+    }
+
+    /// Stats of a datastore without opening one of its stores, which would create it
+    pub(crate) async fn stats_at(options: SqliteConnectOptions) -> Result<DatastoreStats, DsError> {
+        let options = options.collation("natural", natural_cmp);
+        let pool = KVLite::pool_options(1).connect_with(options).await?;
+        // the store name is never read, datastore_stats only looks at the schema and the stores it lists
+        KVLite::with_pool(pool, "store", Collation::Binary).datastore_stats().await
+    }
+
+    /// Pools whose connections have the functions audited stores' triggers call
+    fn pool_options(pool_size: u32) -> SqlitePoolOptions {
+        // connections are kept until the pool closes, an in-memory datastore is gone with the last of them
+        SqlitePoolOptions::new()
+            .max_connections(pool_size)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .after_connect(|conn, _| Box::pin(async move { audit::register_functions(conn).await }))
+    }
+
+    /// Refuse a name for a store, in this datastore or the attached one the table is in, that
+    /// SQLite or the datastore keeps for itself, or that names a table of another store, which
+    /// opening it as a store would alter. Names are quoted wherever they're used, so any other is fine.
+    async fn check_store_name(&self, conn: &mut SqliteConnection, store: &Table) -> Result<(), DsError> {
+        let name = store.name.as_str();
+        if name.is_empty() || name.to_ascii_lowercase().starts_with("sqlite_") || name == "_ds_meta" {
+            return Err(DsError::InvalidStoreName(name.to_string()));
+        }
+        for suffix in STORE_TABLES {
+            let owner = match name.strip_suffix(suffix).and_then(|owner| owner.strip_suffix('_')) {
+                Some(owner) => Table { schema: store.schema.clone(), name: owner.to_string() },
+                None => continue,
+            };
+            if self.table_exists(conn, &owner).await? {
+                return Err(DsError::InvalidStoreName(name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect_pool(options: SqliteConnectOptions, pool_size: u32, kv_name: &str, collation: Collation) -> Result<KVLite, DsError> {
+        let options = options.collation("natural", natural_cmp);
+        #[cfg(feature = "icu")]
+        let options = collation.register(options)?;
+        let pool = KVLite::pool_options(pool_size).connect_with(options.clone()).await?;
+        let mut store = KVLite::with_pool(pool, kv_name, collation);
+
+        let mut conn = store.pool.acquire().await?;
+        store.check_store_name(&mut conn, &Table::new(kv_name)).await?;
+        let table = store.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT sql FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(kv_name)
+            .build())
+            .await?;
+        drop(conn);
+
+        match table {
+            Some(table) => {
+                store.collation = Collation::from_schema(table.get("sql"));
+                let mut conn = store.pool.acquire().await?;
+                store.migrate_in(&mut conn).await?;
+                store.grace = store.load_setting(&mut conn, &store.kv_name, "grace").await?.and_then(|grace| grace.parse().ok()).unwrap_or(0);
+                store.secure_delete = store.load_setting(&mut conn, &store.kv_name, "secure_delete").await?.as_deref() == Some("true");
+                store.default_ttl = store.load_setting(&mut conn, &store.kv_name, "default_ttl").await?.and_then(|ttl| ttl.parse().ok());
+                store.key_transforms = store.load_setting(&mut conn, &store.kv_name, "key_transform").await?.map(|saved| transform::parse_key_transforms(&saved)).unwrap_or_default();
+                if let Some(saved) = store.load_setting(&mut conn, &store.kv_name, "value_transform").await? {
+                    store.value_transforms = transform::parse_value_transforms(&saved)?;
+                }
+                store.dedup = store.load_setting(&mut conn, &store.kv_name, "dedup").await?.and_then(|size| size.parse().ok());
+                store.hooks = store.load_hooks(&mut conn).await?.into();
+                drop(conn);
+
+                // locale collations are only known once the schema has been read
+                #[cfg(feature = "icu")]
+                if matches!(store.collation, Collation::Locale(_)) {
+                    store.pool.close().await;
+                    store.pool = KVLite::pool_options(pool_size).connect_with(store.collation.register(options)?).await?;
+                }
+            },
+            None => {
+                let mut conn = store.pool.acquire().await?;
+                match store.create_store_table(&mut conn, &Table::new(kv_name)).await {
+                    // a read-only datastore only has the stores already in it
+                    Err(DsError::ReadOnly) => return Err(DsError::NotFound),
+                    res => res?,
+                }
+            },
+        }
+
+        Ok(store)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<String, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.get_in(&mut conn, key).await
+    }
+
+    async fn get_in(&self, conn: &mut SqliteConnection, key: &str) -> Result<String, DsError> {
+        String::from_utf8(self.get_bytes_in(conn, key).await?)
+            .map_err(|_| DsError::Sql(sqlx::Error::Decode(format!("value of {} is not valid UTF-8", key).into())))
+    }
+
+    /// Get the value of a record as bytes, whether it was stored as text or a BLOB
+    pub async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.get_bytes_in(&mut conn, key).await
+    }
+
+    async fn get_bytes_in(&self, conn: &mut SqliteConnection, key: &str) -> Result<Vec<u8>, DsError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT value FROM {} WHERE key=
+            "#, self.kv_name));
+        query.push_bind(key);
+        self.push_readable(&mut query);
+        let row = self.fetch_one(conn, query.build()).await?;
+        let value = self.resolve_value(conn, row.get("value")).await?;
+        self.untransform_value(value)
+    }
+
+    pub async fn stat(&self, key: &str) -> Result<RecordMeta, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let row = self.fetch_record(&mut conn, "", key).await?;
+        Ok(Self::record_meta(&row))
+    }
+
+    /// Get the value of a record as bytes along with its metadata
+    pub async fn get_with_meta(&self, key: &str) -> Result<(Vec<u8>, RecordMeta), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let row = self.fetch_record(&mut conn, "value, ", key).await?;
+        let value = self.resolve_value(&mut conn, row.get("value")).await?;
+        Ok((self.untransform_value(value)?, Self::record_meta(&row)))
+    }
+
+    async fn fetch_record(&self, conn: &mut SqliteConnection, columns: &str, key: &str) -> Result<SqliteRow, DsError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT {}key, length(CAST(value AS BLOB)) AS size, expires_at, content_type, created_at, updated_at, tag, pinned, source FROM {} WHERE key=
+            "#, columns, self.kv_name));
+        query.push_bind(key);
+        self.push_readable(&mut query);
+        self.fetch_one(conn, query.build()).await
+    }
+
+    /// Restrict a query on a single record to one that hasn't expired, or is still in the store's grace period
+    fn push_readable(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        query.push(" AND (expires_at IS NULL OR expires_at > ").push_bind(now() - self.grace).push(")");
+    }
+
+    /// Records that expire within a window, soonest first, including those in the store's grace period
+    pub async fn expiring(&self, within: Duration) -> Result<Vec<RecordMeta>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let rows = self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT key, length(CAST(value AS BLOB)) AS size, expires_at, content_type, created_at, updated_at, tag, pinned, source FROM {} WHERE expires_at <= 
+            "#, self.kv_name))
+            .push_bind(now() + within.as_secs() as i64)
+            .push(" AND expires_at > ")
+            .push_bind(now() - self.grace)
+            .push(" ORDER BY expires_at")
+            .build())
+            .await?;
+        Ok(rows.iter().map(Self::record_meta).collect())
+    }
+
+    /// A setting saved with a store, which needn't be this one
+    async fn load_setting(&self, conn: &mut SqliteConnection, store: &Table, name: &str) -> Result<Option<String>, DsError> {
+        let settings = store.object("settings");
+        let exists = self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(settings.name.as_str())
+            .build())
+            .await?
+            .is_some();
+        if !exists {
+            return Ok(None);
+        }
+        Ok(self.fetch_optional(conn, QueryBuilder::new(format!(r#"
+                SELECT value FROM {} WHERE name=
+            "#, settings))
+            .push_bind(name)
+            .build())
+            .await?
+            .map(|row| row.get("value")))
+    }
+
+    /// Keep expired records readable for a while before they're purged, so a lease can still be
+    /// renewed late. Reads of a record in its grace period return it with its expiry in the past.
+    /// The grace period is saved with the store.
+    pub async fn set_grace(&mut self, grace: Duration) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_settings(&mut conn).await?;
+        self.save_setting(&mut conn, "grace", &grace.as_secs().to_string()).await?;
+
+        self.grace = grace.as_secs() as i64;
+        Ok(())
+    }
+
+    /// Give records set without a TTL this one, or stop if None, so a store of sessions or caches
+    /// can't grow forever. Saved with the store; records already in it keep the TTL they have.
+    pub async fn set_default_ttl(&mut self, ttl: Option<Duration>) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_settings(&mut conn).await?;
+        match ttl {
+            Some(ttl) => self.save_setting(&mut conn, "default_ttl", &ttl.as_secs().to_string()).await?,
+            None => self.delete_setting(&mut conn, "default_ttl").await?,
+        }
+
+        self.default_ttl = ttl.map(|ttl| ttl.as_secs() as i64);
+        Ok(())
+    }
+
+    /// The TTL records set without one are given
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl.map(|ttl| Duration::from_secs(ttl as u64))
+    }
+
+    /// Overwrite what the store's deletes free with zeros, rather than leaving deleted values in the
+    /// file where they could be recovered. Deleting securely also erases the records' past values
+    /// from a versioned store's history and truncates the write-ahead log. Saved with the store,
+    /// so every handle deletes securely.
+    pub async fn set_secure_delete(&mut self, secure: bool) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_settings(&mut conn).await?;
+        self.save_setting(&mut conn, "secure_delete", &secure.to_string()).await?;
+
+        self.secure_delete = secure;
+        Ok(())
+    }
+
+    /// Delete securely with this handle, as set_secure_delete does, without saving it with the store
+    pub fn delete_securely(&mut self) {
+        self.secure_delete = true;
+    }
+
+    /// Have the connection overwrite what it deletes from now on, if deletes are secure.
+    /// The pragma has to be set before the delete, it doesn't scrub pages already freed.
+    async fn secure_connection(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        if self.secure_delete {
+            self.execute(conn, QueryBuilder::new("PRAGMA secure_delete = ON").build()).await?;
+        }
+        Ok(())
+    }
+
+    /// After a secure delete, erase other copies of the deleted values: their versions in the
+    /// history and the pages of the write-ahead log
+    async fn scrub(&self, conn: &mut SqliteConnection, keys: &[&str]) -> Result<(), DsError> {
+        if !self.secure_delete {
+            return Ok(());
+        }
+        self.erase_history(conn, keys).await?;
+        self.execute(conn, QueryBuilder::new("PRAGMA wal_checkpoint(TRUNCATE)").build()).await?;
+        Ok(())
+    }
+
+    async fn create_settings(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, value TEXT)
+            "#, self.kv_name.object("settings")))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    async fn save_setting(&self, conn: &mut SqliteConnection, name: &str, value: &str) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                INSERT OR REPLACE INTO {} (name, value) VALUES (
+            "#, self.kv_name.object("settings")))
+            .push_bind(name)
+            .push(",")
+            .push_bind(value)
+            .push(")")
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_setting(&self, conn: &mut SqliteConnection, name: &str) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE name=
+            "#, self.kv_name.object("settings")))
+            .push_bind(name)
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// The type every value in the store must have
+    pub async fn value_type(&self) -> Result<ValueType, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.load_setting(&mut conn, &self.kv_name, "type").await?
+            .and_then(|value_type| value_type.parse().ok())
+            .unwrap_or(ValueType::Any))
+    }
+
+    /// Fail if the store's values aren't stored as they're written, for a setting whose triggers
+    /// would check them as they're stored
+    fn check_stored_as_written(&self, setting: &str) -> Result<(), DsError> {
+        if self.dedup.is_some() {
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!("a store that deduplicates its values can't have a {}", setting).into())));
+        }
+        match self.value_transforms.is_empty() {
+            true => Ok(()),
+            false => Err(DsError::Sql(sqlx::Error::Configuration(format!("a store that transforms its values can't have a {}", setting).into()))),
+        }
+    }
+
+    /// Fail if the store has a type or a max value size, whose triggers check values as they're
+    /// stored, for a setting that changes how they're stored
+    async fn check_unconstrained(&self, conn: &mut SqliteConnection, setting: &str) -> Result<(), DsError> {
+        let typed = self.load_setting(conn, &self.kv_name, "type").await?
+            .and_then(|value_type| value_type.parse::<ValueType>().ok())
+            .is_some_and(|value_type| value_type != ValueType::Any);
+        let limited = self.load_setting(conn, &self.kv_name, "max_value_size").await?.is_some();
+        match typed || limited {
+            true => Err(DsError::Sql(sqlx::Error::Configuration(format!("a store with a type or max value size can't {}", setting).into()))),
+            false => Ok(()),
+        }
+    }
+
+    /// Require every value in the store to have a type, checked by triggers on every write from any process.
+    /// Fails without changing anything if a record already has a value of another type, or if
+    /// the store transforms or deduplicates its values, as the triggers only see them as stored.
+    pub async fn set_value_type(&self, value_type: ValueType) -> Result<(), DsError> {
+        if value_type != ValueType::Any {
+            self.check_stored_as_written("type")?;
+        }
+        let mut conn = self.pool.acquire().await?;
+        if let ValueType::Reference(Some(store)) = &value_type {
+            if !self.stores().await?.contains(store) {
+                return Err(DsError::Sql(sqlx::Error::Configuration(format!("no store named {} to reference", store).into())));
+            }
+        }
+        self.create_settings(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        self.save_setting(&mut tx, "type", &value_type.to_string()).await?;
+
+        if let Some(check) = value_type.invalid_sql("value") {
+            let invalid = self.fetch_optional(&mut tx, QueryBuilder::new(format!(r#"
+                    SELECT key FROM {} WHERE {} LIMIT 1
+                "#, self.kv_name, check))
+                .build())
+                .await?;
+            if let Some(row) = invalid {
+                let key = row.get::<String, &str>("key");
+                tx.rollback().await?;
+                return Err(DsError::Sql(sqlx::Error::Decode(format!("the value of {} isn't {}", key, value_type).into())));
+            }
+        }
+
+        let insert = self.kv_name.object("type_insert");
+        let update = self.kv_name.object("type_update");
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                DROP TRIGGER IF EXISTS {};
+                DROP TRIGGER IF EXISTS {};
+            "#, insert, update))
+            .build())
+            .await?;
+        if let Some(check) = value_type.invalid_sql("NEW.value") {
+            let raise = format!("RAISE(ABORT, 'values in this store must be {}')", value_type.to_string().replace('\'', "''"));
+            self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    CREATE TRIGGER {insert} BEFORE INSERT ON {table} WHEN {check} BEGIN SELECT {raise}; END;
+                    CREATE TRIGGER {update} BEFORE UPDATE OF value ON {table} WHEN {check} BEGIN SELECT {raise}; END;
+                "#, insert = insert, update = update, table = self.kv_name.unqualified(), check = check, raise = raise))
+                .build())
+                .await?;
+        }
+
+        Ok(tx.commit().await?)
+    }
+
+    /// The most bytes a value in the store can have
+    pub async fn max_value_size(&self) -> Result<Option<u64>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.load_setting(&mut conn, &self.kv_name, "max_value_size").await?
+            .and_then(|size| size.parse().ok()))
+    }
+
+    /// Limit the bytes of every value in the store, or lift the limit if None, checked by triggers
+    /// on every write from any process. Fails without changing anything if a value is already larger,
+    /// or if the store transforms or deduplicates its values, as the triggers only see them as stored.
+    pub async fn set_max_value_size(&self, max: Option<u64>) -> Result<(), DsError> {
+        if max.is_some() {
+            self.check_stored_as_written("max value size")?;
+        }
+        let mut conn = self.pool.acquire().await?;
+        self.create_settings(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        match max {
+            Some(max) => self.save_setting(&mut tx, "max_value_size", &max.to_string()).await?,
+            None => self.delete_setting(&mut tx, "max_value_size").await?,
+        }
+
+        if let Some(max) = max {
+            let larger = self.fetch_optional(&mut tx, QueryBuilder::new(format!(r#"
+                    SELECT key FROM {} WHERE length(CAST(value AS BLOB)) > 
+                "#, self.kv_name))
+                .push_bind(max as i64)
+                .push(" LIMIT 1")
+                .build())
+                .await?;
+            if let Some(row) = larger {
+                let key = row.get::<String, &str>("key");
+                tx.rollback().await?;
+                return Err(DsError::Sql(sqlx::Error::Decode(format!("the value of {} is larger than {} bytes", key, max).into())));
+            }
+        }
+
+        let insert = self.kv_name.object("quota_insert");
+        let update = self.kv_name.object("quota_update");
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                DROP TRIGGER IF EXISTS {};
+                DROP TRIGGER IF EXISTS {};
+            "#, insert, update))
+            .build())
+            .await?;
+        if let Some(max) = max {
+            let check = format!("length(CAST(NEW.value AS BLOB)) > {}", max);
+            let raise = format!("RAISE(ABORT, 'values in this store must be at most {} bytes')", max);
+            self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    CREATE TRIGGER {insert} BEFORE INSERT ON {table} WHEN {check} BEGIN SELECT {raise}; END;
+                    CREATE TRIGGER {update} BEFORE UPDATE OF value ON {table} WHEN {check} BEGIN SELECT {raise}; END;
+                "#, insert = insert, update = update, table = self.kv_name.unqualified(), check = check, raise = raise))
+                .build())
+                .await?;
+        }
+
+        Ok(tx.commit().await?)
+    }
+
+    /// How long expired records stay readable
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.grace as u64)
+    }
+
+    fn record_meta(row: &SqliteRow) -> RecordMeta {
+        RecordMeta {
+            key: row.get("key"),
+            size: row.get::<i64, &str>("size") as u64,
+            expires_at: row.get::<Option<i64>, &str>("expires_at").map(to_time),
+            content_type: row.get("content_type"),
+            created_at: row.get::<Option<i64>, &str>("created_at").map(to_time),
+            updated_at: row.get::<Option<i64>, &str>("updated_at").map(to_time),
+            tag: row.get::<Option<String>, &str>("tag").and_then(|tag| tag.parse().ok()),
+            pinned: row.get("pinned"),
+            source: row.get("source"),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.set_in(&mut conn, key, value, Stored::default()).await?;
+
+        self.notify(ChangeKind::Set, key, Some(value));
+        Ok(())
+    }
+
+    /// Set the value of a record that expires after ttl
+    pub async fn set_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<(), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.set_in(&mut conn, key, value, Stored::expiring(ttl)).await?;
+
+        self.notify(ChangeKind::Set, key, Some(value));
+        Ok(())
+    }
+
+    /// Set the value of a record to raw bytes, stored as a BLOB
+    pub async fn set_bytes(&self, key: &str, value: &[u8]) -> Result<(), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.set_in(&mut conn, key, value, Stored::default()).await?;
+
+        self.notify(ChangeKind::Set, key, Some(&String::from_utf8_lossy(value)));
+        Ok(())
+    }
+
+    /// Set the value of a record to raw bytes that expire after ttl
+    pub async fn set_bytes_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.set_in(&mut conn, key, value, Stored::expiring(ttl)).await?;
+
+        self.notify(ChangeKind::Set, key, Some(&String::from_utf8_lossy(value)));
+        Ok(())
+    }
+
+    /// Set a record from bytes with a TTL or content type. Values that are valid UTF-8 are kept
+    /// as text so they sort, filter and compare like other values; anything else is stored as a BLOB.
+    pub async fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        if let Some(tag) = options.tag {
+            tag.check(value)?;
+        }
+        let stored = Stored::from_options(options);
+        match std::str::from_utf8(value) {
+            Ok(text) => self.set_in(&mut conn, key, text, stored).await?,
+            Err(_) => self.set_in(&mut conn, key, value, stored).await?,
+        }
+
+        self.notify(ChangeKind::Set, key, Some(&String::from_utf8_lossy(value)));
+        Ok(())
+    }
+
+    async fn set_in<'a, V>(&self, conn: &mut SqliteConnection, key: &'a str, value: V, stored: Stored<'a>) -> Result<(), DsError>
+    where
+        V: 'a + Send + Encode<'a, Sqlite> + Type<Sqlite> + AsRef<[u8]>,
+    {
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (key, value, expires_at, content_type, created_at, updated_at, tag, source) VALUES (
+            "#, self.kv_name));
+        query.push_bind(key).push(",");
+        let transformed = self.transform_value(value.as_ref())?;
+        let reference = self.dedup_value(conn, transformed.as_deref().unwrap_or(value.as_ref())).await?;
+        // transformed values are kept as text when they can be, like values that aren't
+        match (reference, transformed) {
+            (Some(reference), _) => query.push_bind(reference),
+            (None, Some(transformed)) => match String::from_utf8(transformed) {
+                Ok(text) => query.push_bind(text),
+                Err(e) => query.push_bind(e.into_bytes()),
+            },
+            (None, None) => query.push_bind(value),
+        };
+        self.push_upsert(&mut query, &stored);
+        self.execute(conn, query.build()).await?;
+        Ok(())
+    }
+
+    /// Finish an insert of a record begun with its key and value, with what's stored alongside
+    /// the value and how it overwrites a record that's already there
+    fn push_upsert<'a>(&self, query: &mut QueryBuilder<'a, Sqlite>, stored: &Stored<'a>) {
+        let now = now();
+        query
+            .push(",")
+            .push_bind(self.expires_at(stored))
+            .push(",")
+            .push_bind(stored.content_type)
+            .push(",")
+            .push_bind(now)
+            .push(",")
+            .push_bind(now)
+            .push(",")
+            .push_bind(stored.tag.map(|tag| tag.to_string()))
+            .push(",")
+            .push_bind(self.source(stored))
+            // overwriting keeps when the record was created, unless it had expired
+            .push(r#") ON CONFLICT (key) DO UPDATE SET
+                value = excluded.value, expires_at = CASE WHEN pinned THEN NULL ELSE excluded.expires_at END,
+                content_type = excluded.content_type, tag = excluded.tag, source = excluded.source,
+                created_at = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(" THEN excluded.created_at ELSE created_at END, updated_at = excluded.updated_at");
+    }
+
+    /// Set a record and return the value it had before, None if it didn't exist. The read and write
+    /// are one transaction, so no other write can come between them.
+    pub async fn getset(&self, key: &str, value: &str) -> Result<Option<String>, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        let expired = self.purge(&mut tx).await?;
+
+        let old = match self.get_in(&mut tx, key).await {
+            Ok(old) => Some(old),
+            Err(DsError::NotFound) => None,
+            Err(e) => {
+                tx.rollback().await?;
+                return Err(e);
+            },
+        };
+        if let Err(e) = self.set_in(&mut tx, key, value, Stored::default()).await {
+            tx.rollback().await?;
+            return Err(e);
+        }
+        tx.commit().await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        self.notify(ChangeKind::Set, key, Some(value));
+        Ok(old)
+    }
+
+    /// Set a record only if its value is expected, or only if it doesn't exist when expected is None.
+    /// The check and write are a single statement, so concurrent writers can't interleave. Returns whether the record was set.
+    pub async fn cas(&self, key: &str, expected: Option<&str>, new: &str) -> Result<bool, DsError> {
+        self.cas_with(key, expected, new.as_bytes(), &SetOptions::default()).await
+    }
+
+    /// Compare and set a record with a TTL and content type, like cas
+    pub async fn cas_with(&self, key: &str, expected: Option<&str>, value: &[u8], options: &SetOptions) -> Result<bool, DsError> {
+        self.check_untransformed("cas")?;
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        if let Some(tag) = options.tag {
+            tag.check(value)?;
+        }
+        let stored = Stored::from_options(options);
+        let set = match std::str::from_utf8(value) {
+            Ok(text) => self.cas_in(&mut conn, key, expected, text, stored).await?,
+            Err(_) => self.cas_in(&mut conn, key, expected, value, stored).await?,
+        };
+
+        if set {
+            self.notify(ChangeKind::Set, key, Some(&String::from_utf8_lossy(value)));
+        }
+        Ok(set)
+    }
+
+    async fn cas_in<'a, V>(&self, conn: &mut SqliteConnection, key: &'a str, expected: Option<&'a str>, value: V, stored: Stored<'a>) -> Result<bool, DsError>
+    where
+        V: 'a + Send + Encode<'a, Sqlite> + Type<Sqlite>,
+    {
+        let mut query = match expected {
+            None => {
+                let mut query = QueryBuilder::new(format!(r#"
+                        INSERT INTO {} (key, value, expires_at, content_type, created_at, updated_at, tag, source) VALUES (
+                    "#, self.kv_name));
+                query.push_bind(key)
+                    .push(",")
+                    .push_bind(value)
+                    .push(",")
+                    .push_bind(self.expires_at(&stored))
+                    .push(",")
+                    .push_bind(stored.content_type)
+                    .push(",")
+                    .push_bind(now())
+                    .push(",")
+                    .push_bind(now())
+                    .push(",")
+                    .push_bind(stored.tag.map(|tag| tag.to_string()))
+                    .push(",")
+                    .push_bind(self.source(&stored))
+                    // an expired record counts as absent, so it's replaced
+                    .push(r#") ON CONFLICT (key) DO UPDATE SET
+                        value = excluded.value, expires_at = excluded.expires_at, content_type = excluded.content_type, tag = excluded.tag,
+                        source = excluded.source,
+                        created_at = excluded.created_at, updated_at = excluded.updated_at
+                        WHERE expires_at <= "#)
+                    .push_bind(now());
+                query
+            },
+            Some(expected) => {
+                let mut query = QueryBuilder::new(format!(r#"
+                        UPDATE {} SET value = 
+                    "#, self.kv_name));
+                query.push_bind(value)
+                    .push(", expires_at = CASE WHEN pinned THEN NULL ELSE ")
+                    .push_bind(self.expires_at(&stored))
+                    .push(" END, content_type = ")
+                    .push_bind(stored.content_type)
+                    .push(", tag = ")
+                    .push_bind(stored.tag.map(|tag| tag.to_string()))
+                    .push(", source = ")
+                    .push_bind(self.source(&stored))
+                    .push(", updated_at = ")
+                    .push_bind(now())
+                    .push(" WHERE key = ")
+                    .push_bind(key)
+                    // values may be stored as text or BLOBs, so compare their bytes
+                    .push(" AND CAST(value AS BLOB) = CAST(")
+                    .push_bind(expected)
+                    .push(" AS BLOB)");
+                push_live(&mut query, &mut true);
+                query
+            },
+        };
+        Ok(self.execute(conn, query.build()).await?.rows_affected() > 0)
+    }
+
+    /// Add amount to an integer value in a single statement, returning the new value.
+    /// Missing and expired records count as 0 and get the store's default TTL; a record's TTL is kept.
+    pub async fn incr(&self, key: &str, amount: i64) -> Result<i64, DsError> {
+        self.check_untransformed("incr")?;
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+
+        let now = now();
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (key, value, created_at, updated_at, source, expires_at) VALUES (
+            "#, self.kv_name));
+        query.push_bind(key)
+            .push(",")
+            .push_bind(amount)
+            .push(",")
+            .push_bind(now)
+            .push(",")
+            .push_bind(now)
+            .push(",")
+            .push_bind(self.source.as_deref())
+            .push(",")
+            .push_bind(self.expires_at(&Stored::default()))
+            .push(r#") ON CONFLICT (key) DO UPDATE SET
+                source = excluded.source,
+                value = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(r#" THEN excluded.value ELSE CAST(value AS INTEGER) + excluded.value END,
+                created_at = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(r#" THEN excluded.created_at ELSE created_at END,
+                updated_at = excluded.updated_at,
+                expires_at = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(r#" THEN excluded.expires_at ELSE expires_at END,
+                tag = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(r#" THEN NULL ELSE tag END
+                WHERE expires_at <= "#)
+            .push_bind(now)
+            // values typed as anything but an int aren't counters, even if they look like one
+            .push(r#" OR (CAST(CAST(value AS INTEGER) AS TEXT) = value AND (tag IS NULL OR tag = 'int'))
+                RETURNING CAST(value AS INTEGER) AS value"#);
+
+        // the statement writes, so in explain mode it's only printed
+        if self.explain != Explain::Off {
+            self.execute(&mut conn, query.build()).await?;
+            return Ok(0);
+        }
+        // fetch every row so the statement runs to completion and its write is committed
+        let value: i64 = match self.fetch_all(&mut conn, query.build()).await?.first() {
+            Some(row) => row.get("value"),
+            None => return Err(DsError::Sql(sqlx::Error::Decode(format!("value of {} is not an integer", key).into()))),
+        };
+
+        self.notify(ChangeKind::Set, key, Some(&value.to_string()));
+        Ok(value)
+    }
+
+    /// Add text to the end of a value in a single statement, returning its new length in bytes.
+    /// Missing and expired records are created with just the text; records set with a type can't be appended to.
+    pub async fn append(&self, key: &str, text: &str) -> Result<u64, DsError> {
+        self.concat(key, text, false).await
+    }
+
+    /// Add text to the start of a value in a single statement, like append
+    pub async fn prepend(&self, key: &str, text: &str) -> Result<u64, DsError> {
+        self.concat(key, text, true).await
+    }
+
+    async fn concat(&self, key: &str, text: &str, prepend: bool) -> Result<u64, DsError> {
+        self.check_untransformed(if prepend { "prepend" } else { "append" })?;
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+
+        let now = now();
+        let joined = match prepend {
+            true => "excluded.value || value",
+            false => "value || excluded.value",
+        };
+        // the value is only sent back when someone is listening for the change
+        let notify = self.explain == Explain::Off && (self.changes.receiver_count() > 0 || !self.hooks.read().unwrap().is_empty());
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (key, value, created_at, updated_at, source, expires_at) VALUES (
+            "#, self.kv_name));
+        query.push_bind(key)
+            .push(",")
+            .push_bind(text)
+            .push(",")
+            .push_bind(now)
+            .push(",")
+            .push_bind(now)
+            .push(",")
+            .push_bind(self.source.as_deref())
+            .push(",")
+            .push_bind(self.expires_at(&Stored::default()))
+            .push(r#") ON CONFLICT (key) DO UPDATE SET
+                source = excluded.source,
+                value = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            // || makes text, so bytes are cast back to stay bytes
+            .push(format!(r#" THEN excluded.value WHEN typeof(value) = 'blob' THEN CAST({joined} AS BLOB) ELSE {joined} END,
+                created_at = CASE WHEN expires_at <= "#, joined = joined))
+            .push_bind(now)
+            .push(r#" THEN excluded.created_at ELSE created_at END,
+                updated_at = excluded.updated_at,
+                expires_at = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(r#" THEN excluded.expires_at ELSE expires_at END,
+                content_type = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(r#" THEN NULL ELSE content_type END,
+                tag = CASE WHEN expires_at <= "#)
+            .push_bind(now)
+            .push(r#" THEN NULL ELSE tag END
+                WHERE expires_at <= "#)
+            .push_bind(now)
+            .push(" OR tag IS NULL RETURNING length(CAST(value AS BLOB)) AS length, CASE WHEN ")
+            .push_bind(notify)
+            .push(" THEN value END AS value");
+
+        // the statement writes, so in explain mode it's only printed
+        if self.explain != Explain::Off {
+            self.execute(&mut conn, query.build()).await?;
+            return Ok(0);
+        }
+        // fetch every row so the statement runs to completion and its write is committed
+        let row = match self.fetch_all(&mut conn, query.build()).await?.pop() {
+            Some(row) => row,
+            None => return Err(DsError::Sql(sqlx::Error::Decode(format!("{} was set with a type, so it can't be added to", key).into()))),
+        };
+
+        if notify {
+            let value: Vec<u8> = row.get("value");
+            self.notify(ChangeKind::Set, key, Some(&String::from_utf8_lossy(&value)));
+        }
+        Ok(row.get::<i64, &str>("length") as u64)
+    }
+
+    /// The length of a value in bytes, 0 if the record doesn't exist
+    pub async fn strlen(&self, key: &str) -> Result<u64, DsError> {
+        self.check_untransformed("strlen")?;
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT length(CAST(value AS BLOB)) AS length FROM {} WHERE key=
+            "#, self.kv_name));
+        query.push_bind(key);
+        self.push_readable(&mut query);
+        Ok(self.fetch_optional(&mut conn, query.build()).await?
+            .map_or(0, |row| row.get::<i64, &str>("length") as u64))
+    }
+
+    /// Pin a record so it never expires, clearing its TTL. While it's pinned,
+    /// setting it with a TTL keeps it without one.
+    pub async fn pin(&self, key: &str) -> Result<(), DsError> {
+        self.set_pinned(key, true).await
+    }
+
+    /// Unpin a record, so it can be given a TTL again
+    pub async fn unpin(&self, key: &str) -> Result<(), DsError> {
+        self.set_pinned(key, false).await
+    }
+
+    async fn set_pinned(&self, key: &str, pinned: bool) -> Result<(), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                UPDATE {} SET pinned =
+            "#, self.kv_name));
+        query.push_bind(pinned);
+        if pinned {
+            query.push(", expires_at = NULL");
+        }
+        query.push(" WHERE key = ").push_bind(key);
+        push_live(&mut query, &mut true);
+        match self.execute(&mut conn, query.build()).await?.rows_affected() {
+            0 if self.explain == Explain::Off => Err(DsError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Time left before a record expires, or None if it never expires
+    pub async fn ttl(&self, key: &str) -> Result<Option<Duration>, DsError> {
+        let expires_at = self.stat(key).await?.expires_at;
+        Ok(expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now()).unwrap_or_default()))
+    }
+
+    /// Delete expired records past the grace period, returning their keys
+    async fn purge(&self, conn: &mut SqliteConnection) -> Result<Vec<String>, DsError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE expires_at <= 
+            "#, self.kv_name));
+        query.push_bind(now() - self.grace).push(" RETURNING key");
+
+        // the statement writes, so in explain mode it's only printed
+        if self.explain != Explain::Off {
+            self.execute(conn, query.build()).await?;
+            return Ok(vec![]);
+        }
+        let expired = self.fetch_all(conn, query.build()).await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {log} WHERE seq <= (SELECT max(seq) FROM {log}) - {}
+            "#, CHANGE_LOG_LIMIT, log = self.kv_name.object("changes")))
+            .build())
+            .await?;
+        Ok(expired)
+    }
+
+    /// Delete expired records, returning how many were removed
+    pub async fn purge_expired(&self) -> Result<u64, DsError> {
+        Ok(self.purge_expired_keys().await?.len() as u64)
+    }
+
+    /// Delete expired records, returning their keys. Each expiry is notified, so watchers and the
+    /// store's hooks see it.
+    pub async fn purge_expired_keys(&self) -> Result<Vec<String>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let expired = self.purge(&mut conn).await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        Ok(expired)
+    }
+
+    pub async fn del(&self, key: &str) -> Result<(), DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.secure_connection(&mut conn).await?;
+        self.del_in(&mut conn, key).await?;
+        self.scrub(&mut conn, &[key]).await?;
+
+        self.notify(ChangeKind::Delete, key, None);
+        Ok(())
+    }
+
+    /// Delete every record matching a filter in one statement, returning how many were deleted
+    pub async fn del_matching(&self, filter: &Filter) -> Result<u64, DsError> {
+        self.check_filter(filter)?;
+        let mut conn = self.pool.acquire().await?;
+        self.secure_connection(&mut conn).await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                DELETE FROM {}
+            "#, self.kv_name));
+        filter.push_conditions(&self.kv_name, self.blobs().as_ref(), &mut query, &mut false);
+        query.push(" RETURNING key");
+
+        // the statement writes, so in explain mode it's only printed
+        if self.explain != Explain::Off {
+            self.execute(&mut conn, query.build()).await?;
+            return Ok(0);
+        }
+        let deleted = self.fetch_all(&mut conn, query.build()).await?;
+        let keys: Vec<&str> = deleted.iter().map(|row| row.get("key")).collect();
+        self.scrub(&mut conn, &keys).await?;
+        for key in keys {
+            self.notify(ChangeKind::Delete, key, None);
+        }
+        Ok(deleted.len() as u64)
+    }
+
+    /// Delete every record in the store
+    pub async fn clear(&self) -> Result<u64, DsError> {
+        self.del_matching(&Filter::default()).await
+    }
+
+    async fn del_in(&self, conn: &mut SqliteConnection, key: &str) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE key=
+            "#, self.kv_name))
+            .push_bind(key)
+            .build())
+            .await?;
+        Ok(())
+    }
+
+
+    /// Run commands in order over one connection, stopping at the first error.
+    /// With atomic they all run in one transaction and nothing is written if any fails.
+    pub async fn batch(&self, ops: &[BatchOp], atomic: bool) -> Result<Vec<BatchResult>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        if !atomic {
+            let mut results = Vec::with_capacity(ops.len());
+            for op in ops {
+                results.push(self.batch_op(&mut conn, op).await?);
+                self.notify_op(op);
+            }
+            return Ok(results);
+        }
+
+        drop(conn);
+        let mut tx = self.transaction().await?;
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            results.push(tx.apply(op).await?);
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    async fn batch_op(&self, conn: &mut SqliteConnection, op: &BatchOp) -> Result<BatchResult, DsError> {
+        Ok(match op {
+            BatchOp::Set(key, value) => {
+                self.set_in(conn, &self.canonical_key(key), value.as_str(), Stored::default()).await?;
+                BatchResult::Done
+            },
+            BatchOp::Get(key) => match self.get_in(conn, &self.canonical_key(key)).await {
+                Ok(value) => BatchResult::Value(Some(value)),
+                Err(DsError::NotFound) => BatchResult::Value(None),
+                Err(e) => return Err(e),
+            },
+            BatchOp::Contains(key) => match self.get_bytes_in(conn, &self.canonical_key(key)).await {
+                Ok(_) => BatchResult::Contains(true),
+                Err(DsError::NotFound) => BatchResult::Contains(false),
+                Err(e) => return Err(e),
+            },
+            BatchOp::Delete(key) => {
+                self.del_in(conn, &self.canonical_key(key)).await?;
+                BatchResult::Done
+            },
+        })
+    }
+
+    fn notify_op(&self, op: &BatchOp) {
+        match op {
+            BatchOp::Set(key, value) => self.notify(ChangeKind::Set, &self.canonical_key(key), Some(value)),
+            BatchOp::Delete(key) => self.notify(ChangeKind::Delete, &self.canonical_key(key), None),
+            BatchOp::Get(_) | BatchOp::Contains(_) => (),
+        }
+    }
+
+    /// Set several records in one transaction
+    pub async fn mset(&self, records: &[(&str, &str)]) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        for (key, value) in records {
+            self.set_in(&mut tx, &self.canonical_key(key), *value, Stored::default()).await?;
+        }
+        tx.commit().await?;
+
+        for (key, value) in records {
+            self.notify(ChangeKind::Set, &self.canonical_key(key), Some(value));
+        }
+        Ok(())
+    }
+
+    /// Get the values of several records in one transaction, None for those that don't exist
+    pub async fn mget(&self, keys: &[&str]) -> Result<Vec<Option<String>>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(match self.get_in(&mut tx, &self.canonical_key(key)).await {
+                Ok(value) => Some(value),
+                Err(DsError::NotFound) => None,
+                Err(e) => return Err(e),
+            });
+        }
+        tx.commit().await?;
+        Ok(values)
+    }
+
+    /// Delete several records in one transaction
+    pub async fn mdel(&self, keys: &[&str]) -> Result<(), DsError> {
+        let keys: Vec<_> = keys.iter().map(|key| self.canonical_key(key)).collect();
+        let keys: Vec<&str> = keys.iter().map(|key| &key[..]).collect();
+        let mut conn = self.pool.acquire().await?;
+        self.secure_connection(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        for key in &keys {
+            self.del_in(&mut tx, key).await?;
+        }
+        tx.commit().await?;
+        self.scrub(&mut conn, &keys).await?;
+
+        for key in &keys {
+            self.notify(ChangeKind::Delete, key, None);
+        }
+        Ok(())
+    }
+
+    /// Apply writes in order in one transaction, nothing is written if any fails
+    pub async fn write_all(&self, writes: &[RecordWrite]) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        for write in writes {
+            match write {
+                RecordWrite::Set { key, value, options } => {
+                    if let Some(tag) = options.tag {
+                        tag.check(value)?;
+                    }
+                    let stored = Stored::from_options(options);
+                    let key = &self.canonical_key(key);
+                    match std::str::from_utf8(value) {
+                        Ok(text) => self.set_in(&mut tx, key, text, stored).await?,
+                        Err(_) => self.set_in(&mut tx, key, value.as_slice(), stored).await?,
+                    }
+                },
+                RecordWrite::Delete(key) => self.del_in(&mut tx, &self.canonical_key(key)).await?,
+            }
+        }
+        tx.commit().await?;
+
+        for write in writes {
+            match write {
+                RecordWrite::Set { key, value, .. } => self.notify(ChangeKind::Set, &self.canonical_key(key), Some(&String::from_utf8_lossy(value))),
+                RecordWrite::Delete(key) => self.notify(ChangeKind::Delete, &self.canonical_key(key), None),
+            }
+        }
+        Ok(())
+    }
+
+    /// The query of a listing, reading values from the store's blobs if it keeps them there
+    fn list_query<'a>(kv_name: &Table, dedup: bool, columns: &str, options: &'a ListOptions) -> QueryBuilder<'a, Sqlite> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT {} FROM {}
+            "#, columns, dedup::resolved_table(kv_name, dedup.then(|| kv_name.object("blobs")).as_ref(), dedup::RECORD_COLUMNS)));
+        let mut has_where = false;
+        push_live(&mut query, &mut has_where);
+        if let Some(after) = &options.after {
+            push_where(&mut query, &mut has_where);
+            query.push(if options.reverse { "key < " } else { "key > " })
+                .push_bind(after);
+        }
+        options.filter.push_conditions(kv_name, None, &mut query, &mut has_where);
+        if options.sort || options.reverse {
+            query.push(options.by.order_by(options.reverse));
+        }
+        if let Some(limit) = options.limit {
+            query.push(" LIMIT ").push_bind(limit as i64);
+        }
+        query
+    }
+
+    async fn list(&self, columns: &str, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.check_listing(options)?;
+        let mut conn = self.pool.acquire().await?;
+        let mut query = Self::list_query(&self.kv_name, self.dedup.is_some(), columns, options);
+        self.fetch_all(&mut conn, query.build()).await
+    }
+
+    /// Stream a listing's rows as they're read instead of collecting them. A task reads a few rows
+    /// ahead on its own connection, which is held until the stream ends or is dropped.
+    async fn stream(&self, columns: &'static str, options: &ListOptions) -> Result<impl Stream<Item = Result<SqliteRow, DsError>> + 'static, DsError> {
+        self.check_listing(options)?;
+        let mut conn = self.pool.acquire().await?;
+        let dedup = self.dedup.is_some();
+        self.explain_read(&mut conn, Self::list_query(&self.kv_name, dedup, columns, options).sql()).await?;
+
+        let (rows, received) = mpsc::channel(STREAM_CAPACITY);
+        let kv_name = self.kv_name.clone();
+        let options = options.clone();
+        let (statements, counted) = (self.statements.clone(), self.rows.clone());
+        tokio::spawn(async move {
+            let mut query = Self::list_query(&kv_name, dedup, columns, &options);
+            let mut stream = query.build().fetch(&mut *conn);
+            statements.fetch_add(1, Ordering::Relaxed);
+            while let Some(row) = stream.next().await {
+                counted.fetch_add(row.is_ok() as u64, Ordering::Relaxed);
+                // stops once the stream is dropped and a send fails
+                if rows.send(row.map_err(DsError::from)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(ReceiverStream::new(received))
+    }
+
+    /// Stream the keys of a listing, like keys but without holding them all in memory
+    pub async fn stream_keys(&self, options: &ListOptions) -> Result<impl Stream<Item = Result<SqliteRow, DsError>> + 'static, DsError> {
+        self.stream("key", options).await
+    }
+
+    /// Stream the values of a listing, like values but without holding them all in memory
+    pub async fn stream_values(&self, options: &ListOptions) -> Result<impl Stream<Item = Result<SqliteRow, DsError>> + 'static, DsError> {
+        self.stream("value", options).await
+    }
+
+    /// Stream the records of a listing, like records but without holding them all in memory
+    pub async fn stream_records(&self, options: &ListOptions) -> Result<impl Stream<Item = Result<SqliteRow, DsError>> + 'static, DsError> {
+        self.stream("key,value,created_at,updated_at", options).await
+    }
+
+    pub async fn keys(&self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        let rows = self.list("key", options).await?;
+
+        Ok(rows)
+    }
+
+    /// The values of a listing, as they're stored, for untransform_value to read as written
+    pub async fn values(&self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        let rows = self.list("value", options).await?;
+
+        Ok(rows)
+    }
+
+    /// The records of a listing, with their values as they're stored, like values
+    pub async fn records(&self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        let rows = self.list("key,value,created_at,updated_at", options).await?;
+        Ok(rows)
+    }
+
+    /// The key of a live record chosen at random from those matching a filter, None if there are none
+    pub async fn random_key(&self, filter: &Filter) -> Result<Option<String>, DsError> {
+        let options = ListOptions { sort: true, by: SortBy::Random, limit: Some(1), filter: filter.clone(), ..Default::default() };
+        Ok(self.keys(&options).await?.first().map(|row| row.get("key")))
+    }
+
+    /// Get the records whose keys fall within a range, ordered by key
+    pub async fn range<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<Vec<SqliteRow>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key,value FROM {} WHERE 
+            "#, self.readable_records()));
+        match (range.start_bound(), range.end_bound()) {
+            (Bound::Included(start), Bound::Included(end)) => {
+                query.push("key BETWEEN ").push_bind(*start).push(" AND ").push_bind(*end);
+            },
+            (start, end) => {
+                match start {
+                    Bound::Included(start) => query.push("key >= ").push_bind(*start),
+                    Bound::Excluded(start) => query.push("key > ").push_bind(*start),
+                    Bound::Unbounded => query.push("1"),
+                };
+                match end {
+                    Bound::Included(end) => query.push(" AND key <= ").push_bind(*end),
+                    Bound::Excluded(end) => query.push(" AND key < ").push_bind(*end),
+                    Bound::Unbounded => &mut query,
+                };
+            },
+        }
+        push_live(&mut query, &mut true);
+        query.push(" ORDER BY key");
+
+        self.fetch_all(&mut conn, query.build()).await
+    }
+
+    pub async fn contains(&self, key: &str) -> Result<bool, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT value FROM {} WHERE key=
+            "#, self.kv_name));
+        query.push_bind(key);
+        push_live(&mut query, &mut true);
+        match self.fetch_optional(&mut conn, query.build()).await? {
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    /// Compare the store with another store in the datastore. Each row has the key, its change
+    /// (+ if it's only in this store, - if it's only in the other and ~ if their values differ)
+    /// and the record's value in this store and in the other as BLOBs, NULL where it's missing.
+    pub async fn diff(&self, against: &str) -> Result<Vec<SqliteRow>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+
+        let against = Table::new(against);
+        self.upgrade_store_table(&mut conn, &against).await?;
+        let blobs = self.blobs_of(&mut conn, &against).await?;
+        self.diff_in(&mut conn, &against, blobs.as_ref()).await
+    }
+
+    /// Compare the store with the store of the same name in another datastore file, like diff
+    pub async fn diff_datastore(&self, filename: &str) -> Result<Vec<SqliteRow>, DsError> {
+        let path = filename.strip_prefix("sqlite://")
+            .or_else(|| filename.strip_prefix("sqlite:"))
+            .unwrap_or(filename);
+        // attaching would create it
+        std::fs::metadata(path)?;
+
+        let mut conn = self.pool.acquire().await?;
+        self.execute(&mut conn, QueryBuilder::new("ATTACH DATABASE ")
+            .push_bind(path)
+            .push(" AS other")
+            .build())
+            .await?;
+        let against = Table::in_schema("other", &self.kv_name.name);
+        let res = match self.upgrade_store_table(&mut conn, &against).await {
+            Ok(()) => match self.blobs_of(&mut conn, &against).await {
+                Ok(blobs) => self.diff_in(&mut conn, &against, blobs.as_ref()).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        self.execute(&mut conn, QueryBuilder::new("DETACH DATABASE other")
+            .build())
+            .await?;
+
+        res
+    }
+
+    /// Compare the store with records exported to a file, as if they were a store, like diff
+    pub async fn diff_records(&self, reader: impl Read + Send + 'static, format: Format) -> Result<Vec<SqliteRow>, DsError> {
+        let records = tokio::task::spawn_blocking(move || {
+                let mut records = vec![];
+                format::read_records(reader, format, |key, value| {
+                    records.push((key, value));
+                    Ok(())
+                })
+                .map(|_| records)
+            })
+            .await
+            .map_err(|e| DsError::Io(e.into()))?
+            .map_err(|e| DsError::Sql(sqlx::Error::Decode(e.into())))?;
+
+        let mut conn = self.pool.acquire().await?;
+        self.execute(&mut conn, QueryBuilder::new("ATTACH DATABASE ':memory:' AS other")
+            .build())
+            .await?;
+        let against = Table::in_schema("other", &self.kv_name.name);
+        let res = async {
+            self.create_store_table(&mut conn, &against).await?;
+            for batch in records.chunks(IMPORT_BATCH) {
+                let mut query = QueryBuilder::new(format!(r#"
+                        INSERT OR REPLACE INTO {} (key, value)
+                    "#, against));
+                query.push_values(batch, |mut row, (key, value)| {
+                    row.push_bind(key.as_str()).push_bind(value.as_str());
+                });
+                self.execute(&mut conn, query.build()).await?;
+            }
+            self.diff_in(&mut conn, &against, None).await
+        }.await;
+        self.execute(&mut conn, QueryBuilder::new("DETACH DATABASE other")
+            .build())
+            .await?;
+
+        res
+    }
+
+    /// Compare the store with another table of records, whose values are read from blobs if given
+    async fn diff_in(&self, conn: &mut SqliteConnection, against: &Table, against_blobs: Option<&Table>) -> Result<Vec<SqliteRow>, DsError> {
+        // expired records are left out of both sides; each condition needs its own bind of now
+        let live = |alias: &str| format!("({alias}.expires_at IS NULL OR {alias}.expires_at > ?)", alias=alias);
+        let now = now();
+        let store = self.comparable_records(conn, "diff_store", &["key", "expires_at"]).await?;
+        let mut builder = QueryBuilder::new(format!(r#"
+                SELECT key, '+' AS change, CAST(value AS BLOB) AS value, NULL AS other_value FROM {store} AS a WHERE {live_a}
+                    AND key NOT IN (SELECT key FROM {against} AS b WHERE {live_b})
+                UNION ALL
+                SELECT key, '-' AS change, NULL AS value, CAST(value AS BLOB) AS other_value FROM {against} AS b WHERE {live_b}
+                    AND key NOT IN (SELECT key FROM {store} AS a WHERE {live_a})
+                UNION ALL
+                SELECT a.key, '~' AS change, CAST(a.value AS BLOB) AS value, CAST(b.value AS BLOB) AS other_value
+                    FROM {store} AS a JOIN {against} AS b ON a.key=b.key
+                    WHERE a.value IS NOT b.value AND {live_a} AND {live_b}
+                ORDER BY key
+            "#, store=store, against=dedup::resolved_table(against, against_blobs, dedup::RECORD_COLUMNS), live_a=live("a"), live_b=live("b")));
+        let mut query = builder.build();
+        for _ in 0..6 {
+            query = query.bind(now);
+        }
+        let res = self.fetch_all(conn, query).await;
+        self.execute(conn, QueryBuilder::new("DROP TABLE IF EXISTS temp.diff_store").build()).await?;
+        res
+    }
+
+    /// Replace the prefix of every key starting with old by new, in one transaction
+    pub async fn rename_prefix(&self, old: &str, new: &str) -> Result<PrefixRename, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let prefix = Filter { prefix: Some(old.to_string()), ..Default::default() };
+        let expired = self.purge(&mut tx).await?;
+
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key, value FROM {}
+            "#, self.kv_name));
+        prefix.push_conditions(&self.kv_name, None, &mut query, &mut false);
+        let moved = self.fetch_all(&mut tx, query.build()).await?;
+
+        let mut query = QueryBuilder::new("SELECT ");
+        query.push_bind(new)
+            .push(" || substr(key, length(")
+            .push_bind(old)
+            .push(format!(") + 1) AS target FROM {}", self.kv_name));
+        prefix.push_conditions(&self.kv_name, None, &mut query, &mut false);
+        query.push(format!(" AND target IN (SELECT key FROM {} WHERE substr(key, 1, length(", self.kv_name))
+            .push_bind(old)
+            .push(")) != ")
+            .push_bind(old)
+            .push(")");
+        let conflicts: Vec<String> = self.fetch_all(&mut tx, query.build()).await?
+            .iter()
+            .map(|row| row.get("target"))
+            .collect();
+        if !conflicts.is_empty() {
+            tx.rollback().await?;
+            return Ok(PrefixRename { renamed: 0, conflicts });
+        }
+
+        // move the records through a temporary table so keys being renamed can't collide with each other
+        let mut query = QueryBuilder::new(format!(r#"
+                CREATE TEMP TABLE renamed AS SELECT * FROM {}
+            "#, self.kv_name));
+        prefix.push_conditions(&self.kv_name, None, &mut query, &mut false);
+        self.execute(&mut tx, query.build()).await?;
+        self.execute(&mut tx, QueryBuilder::new("UPDATE temp.renamed SET key = ")
+            .push_bind(new)
+            .push(" || substr(key, length(")
+            .push_bind(old)
+            .push(") + 1)")
+            .build())
+            .await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                DELETE FROM {}
+            "#, self.kv_name));
+        prefix.push_conditions(&self.kv_name, None, &mut query, &mut false);
+        self.execute(&mut tx, query.build()).await?;
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                INSERT INTO {} SELECT * FROM temp.renamed
+            "#, self.kv_name))
+            .build())
+            .await?;
+        self.execute(&mut tx, QueryBuilder::new("DROP TABLE temp.renamed").build()).await?;
+        tx.commit().await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        for record in &moved {
+            let key: String = record.get("key");
+            self.notify(ChangeKind::Delete, &key, None);
+            self.notify(ChangeKind::Set, &format!("{}{}", new, &key[old.len()..]), Some(&String::from_utf8_lossy(record.get("value"))));
+        }
+        Ok(PrefixRename { renamed: moved.len() as u64, conflicts })
+    }
+
+    /// Rename a record, keeping its value, TTL and metadata. Without overwrite a record already
+    /// at the new key is left alone and nothing is renamed. Returns whether the record was renamed.
+    pub async fn rename(&self, old: &str, new: &str, overwrite: bool) -> Result<bool, DsError> {
+        let (old, new) = (&self.canonical_key(old)[..], &self.canonical_key(new)[..]);
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let expired = self.purge(&mut tx).await?;
+
+        let value = match self.move_target(&mut tx, old, new, overwrite).await {
+            Ok(Some((value, _))) => value,
+            res => {
+                tx.rollback().await?;
+                return res.map(|_| false);
+            },
+        };
+        // move the record through a temporary table, as the new key may name the same record under the store's collation
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                CREATE TEMP TABLE renamed AS SELECT * FROM {} WHERE key=
+            "#, self.kv_name))
+            .push_bind(old)
+            .build())
+            .await?;
+        self.execute(&mut tx, QueryBuilder::new("UPDATE temp.renamed SET key = ").push_bind(new).build()).await?;
+        self.del_in(&mut tx, old).await?;
+        self.del_in(&mut tx, new).await?;
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                INSERT INTO {} SELECT * FROM temp.renamed
+            "#, self.kv_name))
+            .build())
+            .await?;
+        self.execute(&mut tx, QueryBuilder::new("DROP TABLE temp.renamed").build()).await?;
+        tx.commit().await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        self.notify(ChangeKind::Delete, old, None);
+        self.notify(ChangeKind::Set, new, Some(&value));
+        Ok(true)
+    }
+
+    /// Copy a record to a new key with the same value, TTL and metadata, as a newly created record.
+    /// Without overwrite a record already at the new key is left alone. Returns whether the record was copied.
+    pub async fn copy(&self, src: &str, dst: &str, overwrite: bool) -> Result<bool, DsError> {
+        let (src, dst) = (&self.canonical_key(src)[..], &self.canonical_key(dst)[..]);
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let expired = self.purge(&mut tx).await?;
+
+        let value = match self.move_target(&mut tx, src, dst, overwrite).await {
+            Ok(Some((_, true))) => {
+                tx.rollback().await?;
+                return Ok(true);
+            },
+            Ok(Some((value, false))) => value,
+            res => {
+                tx.rollback().await?;
+                return res.map(|_| false);
+            },
+        };
+        let now = now();
+        self.del_in(&mut tx, dst).await?;
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (key, value, expires_at, content_type, created_at, updated_at, tag, pinned, source)
+                    SELECT 
+            "#, self.kv_name))
+            .push_bind(dst)
+            .push(", value, expires_at, content_type, ")
+            .push_bind(now)
+            .push(", ")
+            .push_bind(now)
+            .push(", tag, pinned, ")
+            .push_bind(self.source.as_deref())
+            .push(format!(" FROM {} WHERE key=", self.kv_name))
+            .push_bind(src)
+            .build())
+            .await?;
+        tx.commit().await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        self.notify(ChangeKind::Set, dst, Some(&value));
+        Ok(true)
+    }
+
+    /// Check that a record can be renamed or copied to another key, returning its value and whether
+    /// the other key names the record itself under the store's collation. None when the other key
+    /// is taken by a live record and mustn't be overwritten.
+    async fn move_target(&self, conn: &mut SqliteConnection, from: &str, to: &str, overwrite: bool) -> Result<Option<(String, bool)>, DsError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key, value FROM {} WHERE key=
+            "#, self.kv_name));
+        query.push_bind(from);
+        self.push_readable(&mut query);
+        let from_row = self.fetch_one(conn, query.build()).await?;
+
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key FROM {} WHERE key=
+            "#, self.kv_name));
+        query.push_bind(to);
+        push_live(&mut query, &mut true);
+        let to_key = self.fetch_optional(conn, query.build()).await?.map(|row| row.get::<String, &str>("key"));
+
+        let itself = to_key.as_deref() == Some(from_row.get::<&str, &str>("key"));
+        if to_key.is_some() && !itself && !overwrite {
+            return Ok(None);
+        }
+        Ok(Some((String::from_utf8_lossy(from_row.get("value")).into_owned(), itself)))
+    }
+
+    /// Write records into the store in one transaction, returning how many were written.
+    /// With replace, records that aren't imported are deleted, otherwise they are kept.
+    pub async fn import(&self, records: &[(String, String)], replace: bool) -> Result<u64, DsError> {
+        let batches = tokio_stream::iter(records.chunks(IMPORT_BATCH).map(|batch| Ok(batch.to_vec())));
+        let mut conn = self.pool.acquire().await?;
+        let report = self.import_batches(&mut conn, batches, replace, ImportStrategy::Overwrite).await?;
+        Ok(report.added + report.overwritten)
+    }
+
+    /// Import records read from a file or stdin. The input is parsed on a blocking thread
+    /// while batches of records are written here, all in one transaction.
+    pub async fn import_from(&self, reader: impl Read + Send + 'static, format: Format, replace: bool) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let report = self.import_batches(&mut conn, Self::read_batches(reader, format), replace, ImportStrategy::Overwrite).await?;
+        Ok(report.added + report.overwritten)
+    }
+
+    /// Import records read from a file or stdin like import_from, keeping existing records, deciding
+    /// what to do with each imported record whose key the store already has by a strategy
+    pub async fn import_with(&self, reader: impl Read + Send + 'static, format: Format, strategy: ImportStrategy) -> Result<ImportReport, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.import_batches(&mut conn, Self::read_batches(reader, format), false, strategy).await
+    }
+
+    /// Import records read from a file or stdin as fast as SQLite allows, for loading millions
+    /// at once: like import_from, in one transaction of multi-row INSERTs prepared once and reused,
+    /// but with synchronous off for the connection until it's done, so nothing waits for the disk
+    /// before the commit. The process dying meanwhile loses only the load, but the machine losing
+    /// power can corrupt the datastore, so it's for loads into datastores that can be made again.
+    pub async fn load_from(&self, reader: impl Read + Send + 'static, format: Format, replace: bool) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let synchronous: i64 = self.fetch_one(&mut conn, QueryBuilder::new("PRAGMA synchronous").build()).await?.get(0);
+        self.execute(&mut conn, QueryBuilder::new("PRAGMA synchronous = OFF").build()).await?;
+        let res = self.import_batches(&mut conn, Self::read_batches(reader, format), replace, ImportStrategy::Overwrite).await;
+        // the connection goes back to the pool, so it's put back as it was
+        self.execute(&mut conn, QueryBuilder::new(format!("PRAGMA synchronous = {}", synchronous)).build()).await?;
+        res.map(|report| report.added + report.overwritten)
+    }
+
+    /// Batches of records parsed from a reader on a blocking thread while they're written
+    fn read_batches(reader: impl Read + Send + 'static, format: Format) -> impl Stream<Item = Result<Vec<(String, String)>, DsError>> + Unpin {
+        let (batches, received) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let mut batch = Vec::with_capacity(IMPORT_BATCH);
+            let res = format::read_records(reader, format, |key, value| {
+                batch.push((key, value));
+                if batch.len() == IMPORT_BATCH {
+                    // fails once the writer has stopped, which reports its own error
+                    batches.blocking_send(Ok(std::mem::take(&mut batch))).map_err(|_| "import stopped".to_string())?;
+                }
+                Ok(())
+            });
+            let _ = batches.blocking_send(res.map(|_| batch).map_err(|e| DsError::Sql(sqlx::Error::Decode(e.into()))));
+        });
+        ReceiverStream::new(received)
+    }
+
+    async fn import_batches(&self, conn: &mut SqliteConnection, mut batches: impl Stream<Item = Result<Vec<(String, String)>, DsError>> + Unpin, replace: bool, strategy: ImportStrategy) -> Result<ImportReport, DsError> {
+        let mut tx = conn.begin().await?;
+
+        let mut removed = vec![];
+        if replace {
+            removed = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                    SELECT key FROM {}
+                "#, self.kv_name))
+                .build())
+                .await?
+                .iter()
+                .map(|row| row.get::<String, &str>("key"))
+                .collect();
+            self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    DELETE FROM {}
+                "#, self.kv_name))
+                .build())
+                .await?;
+        }
+
+        // imported records are only kept around when someone is listening for the changes
+        let notify = self.explain == Explain::Off && self.changes.receiver_count() > 0;
+        let mut imported = vec![];
+        let mut report = ImportReport::default();
+        while let Some(batch) = batches.next().await {
+            let mut batch = batch?;
+            if batch.is_empty() {
+                continue;
+            }
+            if !self.key_transforms.is_empty() {
+                for (key, _) in batch.iter_mut() {
+                    *key = self.canonical_key(key).into_owned();
+                }
+            }
+
+            // a replaced store has nothing left to conflict with
+            let existing = match replace {
+                true => HashMap::new(),
+                false => self.import_conflicts(&mut tx, &batch).await?,
+            };
+            if let (ImportStrategy::Fail, Some((key, _))) = (strategy, batch.iter().find(|(key, _)| existing.contains_key(key))) {
+                return Err(DsError::Sql(sqlx::Error::Configuration(format!("{} is already in the store, so nothing was imported", key).into())));
+            }
+            let before = batch.len();
+            batch.retain(|(key, _)| match (strategy, existing.get(key)) {
+                (ImportStrategy::Skip, Some(_)) => false,
+                (ImportStrategy::Newest { written_at }, Some(&updated_at)) => updated_at <= history::epoch_secs(written_at),
+                _ => true,
+            });
+            report.skipped += (before - batch.len()) as u64;
+            let overwritten = batch.iter().filter(|(key, _)| existing.contains_key(key)).count() as u64;
+            report.overwritten += overwritten;
+            report.added += batch.len() as u64 - overwritten;
+            if batch.is_empty() {
+                continue;
+            }
+            let now = now();
+            let mut query = QueryBuilder::new(format!(r#"
+                    INSERT INTO {} (key, value, created_at, updated_at, source)
+                "#, self.kv_name));
+            query.push_values(&batch, |mut row, (key, value)| {
+                row.push_bind(key.as_str()).push_bind(value.as_str()).push_bind(now).push_bind(now).push_bind(self.source.as_deref());
+            });
+            query.push(r#" ON CONFLICT (key) DO UPDATE SET
+                    value = excluded.value, expires_at = NULL, content_type = NULL, tag = NULL, source = excluded.source,
+                    created_at = CASE WHEN expires_at <= "#)
+                .push_bind(now)
+                .push(" THEN excluded.created_at ELSE created_at END, updated_at = excluded.updated_at");
+            self.execute(&mut tx, query.build()).await?;
+
+            if notify {
+                imported.extend(batch);
+            }
+        }
+        tx.commit().await?;
+
+        let imported_keys: HashSet<&str> = imported.iter().map(|(key, _)| key.as_str()).collect();
+        for key in removed.iter().filter(|key| notify && !imported_keys.contains(key.as_str())) {
+            self.notify(ChangeKind::Delete, key, None);
+        }
+        for (key, value) in &imported {
+            self.notify(ChangeKind::Set, key, Some(value));
+        }
+        Ok(report)
+    }
+
+    /// When each live record with a key in a batch of imported records was last written
+    async fn import_conflicts(&self, conn: &mut SqliteConnection, batch: &[(String, String)]) -> Result<HashMap<String, i64>, DsError> {
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key, coalesce(updated_at, created_at, 0) AS updated_at FROM {} WHERE key IN (
+            "#, self.kv_name));
+        let mut separated = query.separated(", ");
+        for (key, _) in batch {
+            separated.push_bind(key.as_str());
+        }
+        query.push(")");
+        push_live(&mut query, &mut true);
+        Ok(self.fetch_all(conn, query.build())
+            .await?
+            .iter()
+            .map(|row| (row.get("key"), row.get("updated_at")))
+            .collect())
+    }
+
+    /// Names of the stores in the datastore, in order
+    pub async fn stores(&self) -> Result<Vec<String>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+
+        let rows = self.fetch_all(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master AS m WHERE type='table'
+                    AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name='key' AND pk=1)
+                    AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name='value')
+                    AND NOT EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE pk=2)
+                ORDER BY name
+            "#)
+            .build())
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("name")).collect())
+    }
+
+    /// Number of live records matching a filter
+    pub async fn count(&self, filter: &Filter) -> Result<u64, DsError> {
+        self.check_filter(filter)?;
+        let mut conn = self.pool.acquire().await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT count(*) AS count FROM {}
+            "#, self.kv_name));
+        let mut has_where = false;
+        push_live(&mut query, &mut has_where);
+        filter.push_conditions(&self.kv_name, self.blobs().as_ref(), &mut query, &mut has_where);
+        Ok(self.fetch_one(&mut conn, query.build()).await?.get::<i64, &str>("count") as u64)
+    }
+
+    /// Size of the datastore file and of every store in it
+    pub async fn datastore_stats(&self) -> Result<DatastoreStats, DsError> {
+        let stores = self.stores().await?;
+        let mut conn = self.pool.acquire().await?;
+        let file_size = self.fetch_one(&mut conn, QueryBuilder::new(r#"
+                SELECT page_count * page_size AS size FROM pragma_page_count(), pragma_page_size()
+            "#)
+            .build())
+            .await?
+            .get::<i64, &str>("size") as u64;
+
+        let mut stats = DatastoreStats { file_size, stores: vec![] };
+        for store in stores {
+            let mut query = QueryBuilder::new(format!(r#"
+                    SELECT count(*) AS records, coalesce(sum(length(CAST(key AS BLOB))), 0) AS key_bytes,
+                        coalesce(sum(length(CAST(value AS BLOB))), 0) AS value_bytes FROM {}
+                "#, Table::new(&store)));
+            push_live(&mut query, &mut false);
+            let row = self.fetch_one(&mut conn, query.build()).await?;
+            let (blob_bytes, dedup_saved_bytes) = self.dedup_stats(&mut conn, &Table::new(&store)).await?;
+            stats.stores.push(StoreStats {
+                records: row.get::<i64, &str>("records") as u64,
+                key_bytes: row.get::<i64, &str>("key_bytes") as u64,
+                value_bytes: row.get::<i64, &str>("value_bytes") as u64,
+                blob_bytes,
+                dedup_saved_bytes,
+                store,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// Problems SQLite finds in the datastore file, empty if there are none
+    pub async fn integrity_check(&self) -> Result<Vec<String>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let rows = self.fetch_all(&mut conn, QueryBuilder::new("PRAGMA quick_check").build()).await?;
+        Ok(rows.iter()
+            .map(|row| row.get::<String, usize>(0))
+            .filter(|problem| problem != "ok")
+            .collect())
+    }
+
+    /// Delete a store and all of its records
+    pub async fn drop_store(&self, name: &str) -> Result<(), DsError> {
+        if !self.stores().await?.iter().any(|store| store == name) {
+            return Err(DsError::NotFound);
+        }
+        let name = Table::new(name);
+
+        let mut conn = self.pool.acquire().await?;
+        self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                DROP TABLE {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+            "#, name, name.object("changes"), name.object("history"), name.object("settings"), name.object("search"), name.object("locks"), name.object("audit"),
+                name.object("hooks"), name.object("snapshots"), name.object("snapshot_records"), name.object("acl"), name.object("checksums"), name.object("queue"), name.object("key_tags"), name.object("blobs"),
+                name.object("feed")))
+            .build())
+            .await?;
+        self.remove_schema_version(&mut conn, &name).await?;
+
+        Ok(())
+    }
+
+    /// Swap the names of two stores and of everything belonging to them in one transaction, so a
+    /// store built offline can take the place of one in use at once. Readers see one store or the
+    /// other, never a mix of them. Each store keeps its settings and history. Search indexes are
+    /// dropped and rebuilt by the next search, and handles already open keep the settings they loaded.
+    pub async fn swap_stores(&self, a: &str, b: &str) -> Result<(), DsError> {
+        let stores = self.stores().await?;
+        if !stores.iter().any(|store| store == a) || !stores.iter().any(|store| store == b) {
+            return Err(DsError::NotFound);
+        }
+        if a == b {
+            return Ok(());
+        }
+        let (a, b, swap) = (Table::new(a), Table::new(b), Table::new("dsr_swap"));
+
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        // the index names the table it reads values from in a string, which renaming doesn't change
+        for store in [&a, &b] {
+            self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TABLE IF EXISTS {};
+                "#, store.object("search_insert"), store.object("search_update"), store.object("search_delete"), store.object("search")))
+                .build())
+                .await?;
+        }
+
+        // renaming the tables rewrites their triggers and indexes to match, but not their names
+        let a_tables = self.store_tables(&mut tx, &a).await?;
+        let b_tables = self.store_tables(&mut tx, &b).await?;
+        for (tables, from, to) in [(&a_tables, &a, &swap), (&b_tables, &b, &a), (&a_tables, &swap, &b)] {
+            for suffix in tables {
+                self.execute(&mut tx, QueryBuilder::new(format!(
+                        "ALTER TABLE {} RENAME TO {}", from.object_or_self(suffix), to.object_or_self(suffix).unqualified()))
+                    .build())
+                    .await?;
+            }
+        }
+
+        let mut objects = vec![];
+        for (tables, from, to) in [(&a_tables, &a, &b), (&b_tables, &b, &a)] {
+            let mut query = QueryBuilder::new(r#"
+                    SELECT type, name, sql FROM sqlite_master WHERE type IN ('trigger', 'index') AND sql IS NOT NULL AND tbl_name IN (
+                "#);
+            let mut separated = query.separated(", ");
+            for suffix in tables {
+                separated.push_bind(to.object_or_self(suffix).name);
+            }
+            query.push(")");
+            for row in self.fetch_all(&mut tx, query.build()).await? {
+                let (kind, name, sql): (String, String, String) = (row.get("type"), row.get("name"), row.get("sql"));
+                let renamed = match name.strip_prefix(&format!("{}_", from.name)) {
+                    Some(rest) => format!("{}_{}", to.name, rest),
+                    None => name.clone(),
+                };
+                let sql = sql.replacen(&quote(&name), &quote(&renamed), 1);
+                objects.push((kind, name, sql));
+            }
+        }
+        // all dropped before any are created, as each store's new names are the other's old ones
+        for (kind, name, _) in &objects {
+            self.execute(&mut tx, QueryBuilder::new(format!("DROP {} {}", kind.to_uppercase(), quote(name))).build()).await?;
+        }
+        for (_, _, sql) in &objects {
+            self.execute(&mut tx, QueryBuilder::new(sql.as_str()).build()).await?;
+        }
+        self.swap_schema_versions(&mut tx, &a, &b).await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    /// The tables of a store that exist, by suffix, with None for the store's own table
+    async fn store_tables(&self, conn: &mut SqliteConnection, store: &Table) -> Result<Vec<Option<&'static str>>, DsError> {
+        let mut tables = vec![None];
+        for suffix in ["changes", "history", "settings", "locks", "audit", "hooks", "snapshots", "snapshot_records", "acl", "checksums", "queue", "key_tags", "blobs", "feed"] {
+            let exists = self.fetch_optional(conn, QueryBuilder::new(r#"
+                    SELECT name FROM sqlite_master WHERE type="table" AND name=
+                "#)
+                .push_bind(store.object(suffix).name)
+                .build())
+                .await?
+                .is_some();
+            if exists {
+                tables.push(Some(suffix));
+            }
+        }
+        Ok(tables)
+    }
+
+    async fn copy_store(&self, conn: &mut SqliteConnection, dst: &Table) -> Result<(), DsError> {
+        self.check_store_name(conn, dst).await?;
+        let mut tx = conn.begin().await?;
+
+        self.create_store_table(&mut tx, dst).await?;
+        // the copy has none of the store's settings, so its values are copied as written
+        let columns = dedup::RECORD_COLUMNS[1..].join(", ");
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (value, {columns}) SELECT value, {columns} FROM {}
+            "#, dst, self.readable_records(), columns = columns))
+            .build())
+            .await?;
+        self.untransform_table(&mut tx, dst).await?;
+
+        Ok(tx.commit().await?)
+    }
+
+    pub async fn clone_store(&self, dst: &str, filename: Option<&str>) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+
+        let filename = match filename {
+            Some(filename) => filename,
+            None => return self.copy_store(&mut conn, &Table::new(dst)).await,
+        };
+        let path = filename.strip_prefix("sqlite://")
+            .or_else(|| filename.strip_prefix("sqlite:"))
+            .unwrap_or(filename);
+
+        self.execute(&mut conn, QueryBuilder::new("ATTACH DATABASE ")
+            .push_bind(path)
+            .push(" AS clone")
+            .build())
+            .await?;
+        let res = self.copy_store(&mut conn, &Table::in_schema("clone", dst)).await;
+        self.execute(&mut conn, QueryBuilder::new("DETACH DATABASE clone")
+            .build())
+            .await?;
+
+        res
+    }
+
+    /// Create a new datastore file holding only the live records matching a filter, in a store of
+    /// the same name, and vacuum it so it's as small as it can be. Returns how many records were exported.
+    pub async fn export_to_sqlite(&self, filename: &str, filter: &Filter) -> Result<u64, DsError> {
+        let path = filename.strip_prefix("sqlite://")
+            .or_else(|| filename.strip_prefix("sqlite:"))
+            .unwrap_or(filename);
+        if std::path::Path::new(path).exists() {
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!("{} already exists", path).into())));
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        self.execute(&mut conn, QueryBuilder::new("ATTACH DATABASE ")
+            .push_bind(path)
+            .push(" AS export")
+            .build())
+            .await?;
+        let res = match self.export_records(&mut conn, &Table::in_schema("export", &self.kv_name.name), filter).await {
+            Ok(exported) => self.execute(&mut conn, QueryBuilder::new("VACUUM export").build()).await.map(|_| exported),
+            Err(e) => Err(e),
+        };
+        self.execute(&mut conn, QueryBuilder::new("DETACH DATABASE export")
+            .build())
+            .await?;
+
+        res
+    }
+
+    async fn export_records(&self, conn: &mut SqliteConnection, dst: &Table, filter: &Filter) -> Result<u64, DsError> {
+        self.check_filter(filter)?;
+        let mut tx = conn.begin().await?;
+
+        self.create_store_table(&mut tx, dst).await?;
+        let columns = dedup::RECORD_COLUMNS[1..].join(", ");
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (value, {columns}) SELECT value, {columns} FROM {}
+            "#, dst, self.readable_records(), columns = columns));
+        let mut has_where = false;
+        push_live(&mut query, &mut has_where);
+        filter.push_conditions(&self.kv_name, None, &mut query, &mut has_where);
+        let exported = self.execute(&mut tx, query.build()).await?.rows_affected();
+        self.untransform_table(&mut tx, dst).await?;
+        // the copies aren't changes anyone could be watching for
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                DELETE FROM {}
+            "#, dst.object("changes")))
+            .build())
+            .await?;
+
+        tx.commit().await?;
+        Ok(exported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{QueryBuilder, Row, sqlite::SqliteRow};
+
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio_stream::StreamExt;
+
+    use crate::{format::{self, Format}, DsError, BenchOptions, IMPORT_BATCH, Encoding, KVLite, KVLiteOptions, KvBackend, MemoryBackend, Table, ValueType, ValueTag, OnReferenced, SetOptions, BatchOp, BatchResult, ListOptions, SortBy, StoreStats, Filter, Collation, Cursor, ChangeEvent, ChangeFilter, ChangeKind, RecordMeta, Session, PrefixRename, Recommendation, SyncStrategy, SyncReport, ImportStrategy, ImportReport, Template, Hook, Codec, GrepOptions, KeyTransform, ValueTransform, Version, Corrupted, Repair, SchemaVersion, SCHEMA_VERSION, natural_cmp, glob_match, key_levels, key_at_depth};
+
+    async fn setup_store(kv_name: &str) -> Result<KVLite, DsError> {
+        let store = KVLite::new("sqlite://tmp.db", kv_name, false, true).await?;
+        
+        
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new(format!(r#"
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+                DROP TABLE IF EXISTS {};
+            "#, kv_name, Table::new(kv_name).object("settings"), Table::new(kv_name).object("key_tags"), Table::new(kv_name).object("blobs"),
+                Table::new(kv_name).object("feed"), Table::new(kv_name).object("history"), Table::new(kv_name).object("acl"), Table::new(kv_name).object("hooks")))
+            .build()
+            .execute(&mut conn)
+            .await?;
+
+        store.create_store_table(&mut conn, &Table::new(kv_name)).await?;
+        drop(conn);
+
+        // reopened so no settings are kept from before they were dropped
+        store.pool.close().await;
+        KVLite::new("sqlite://tmp.db", kv_name, false, true).await
+    }
+
+    #[tokio::test]
+    async fn test_kv_lite() -> Result<(), DsError> {
+        let store = setup_store("store").await?;
+
+        for i in 0..100 {
+            assert!(matches!(store.set(&format!("key{}", i), &format!("value{}", i)).await, Ok(())))
+        }
+
+        assert!(store.get("not in store").await.is_err());
+        for i in 0..100 {
+            assert_eq!(store.get(&format!("key{}", i)).await.unwrap(), format!("value{}", i))
+        }
+
+        let mut keys = store.keys(&ListOptions::default()).await.unwrap();
+        keys.sort_by(|a, b| 
+                a.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
+            .cmp(
+                &b.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
+            ));
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(key.get::<String, &str>("key"), format!("key{}", i))
+        }
+
+        let mut values = store.values(&ListOptions::default()).await.unwrap();
+        values.sort_by(|a, b| 
+            a.get::<String,&str>("value")[5..].parse::<i32>().unwrap()
+        .cmp(
+            &b.get::<String,&str>("value")[5..].parse::<i32>().unwrap()
+        ));
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(value.get::<String, &str>("value"), format!("value{}", i))
+        }
+
+        let mut records = store.records(&ListOptions::default()).await.unwrap();
+        records.sort_by(|a, b| 
+            a.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
+        .cmp(
+            &b.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
+        ));
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record.get::<String, &str>("key"), format!("key{}", i));
+            assert_eq!(record.get::<String, &str>("value"), format!("value{}", i));
+        }
+
+        assert!(matches!(store.contains("not in store").await, Ok(false)));
+        for i in 0..100 {
+            assert!(matches!(store.contains(&format!("key{}", i)).await, Ok(true)))
+        }
+
+        assert!(matches!(store.del("key1").await, Ok(())));
+        assert!(matches!(store.contains("key1").await, Ok(false)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_diff() -> Result<(), DsError> {
+        let staging = setup_store("diff_staging").await?;
+        let prod = setup_store("diff_prod").await?;
+
+        staging.set("same", "value").await?;
+        prod.set("same", "value").await?;
+        staging.set("changed", "new").await?;
+        prod.set("changed", "old").await?;
+        staging.set("added", "value").await?;
+        prod.set("removed", "value").await?;
+
+        let changes: Vec<(String, String)> = staging.diff("diff_prod").await?
+            .iter()
+            .map(|row| (row.get("change"), row.get("key")))
+            .collect();
+        assert_eq!(changes, vec![
+            ("+".to_string(), "added".to_string()),
+            ("~".to_string(), "changed".to_string()),
+            ("-".to_string(), "removed".to_string()),
+        ]);
+
+        assert!(staging.diff("not_a_store").await.is_err());
+
+        let rows = staging.diff("diff_prod").await?;
+        assert_eq!(rows[1].get::<Option<Vec<u8>>, &str>("value"), Some(b"new".to_vec()));
+        assert_eq!(rows[1].get::<Option<Vec<u8>>, &str>("other_value"), Some(b"old".to_vec()));
+        assert_eq!(rows[0].get::<Option<Vec<u8>>, &str>("other_value"), None);
+
+        let export = br#"{"same": "value", "changed": "old", "removed": "value"}"#;
+        let diff = |rows: Vec<SqliteRow>| rows.iter().map(|row| (row.get("change"), row.get("key"))).collect::<Vec<(String, String)>>();
+        assert_eq!(diff(staging.diff_records(&export[..], Format::Json).await?), changes);
+        assert!(staging.diff_records(&b"{"[..], Format::Json).await.is_err());
+
+        let _ = std::fs::remove_file("tmp_diff.db");
+        let other = KVLite::new("sqlite://tmp_diff.db", "diff_staging", false, true).await?;
+        other.set("changed", "old").await?;
+        other.set("same", "value").await?;
+        other.set("removed", "value").await?;
+        assert_eq!(diff(staging.diff_datastore("sqlite://tmp_diff.db").await?), changes);
+        assert!(prod.diff_datastore("sqlite://tmp_diff.db").await.is_err());
+        assert!(staging.diff_datastore("tmp_diff_missing.db").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clone_store() -> Result<(), DsError> {
+        let store = setup_store("clone_src").await?;
+        for i in 0..10 {
+            store.set(&format!("key{}", i), &format!("value{}", i)).await?;
+        }
+
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("DROP TABLE IF EXISTS clone_dst")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        store.clone_store("clone_dst", None).await?;
+        let clone = KVLite::new("sqlite://tmp.db", "clone_dst", false, false).await?;
+        assert!(clone.diff("clone_src").await?.is_empty());
+
+        assert!(store.clone_store("clone_dst", None).await.is_err());
+
+        let _ = std::fs::remove_file("tmp_clone.db");
+        store.clone_store("clone_dst", Some("sqlite://tmp_clone.db")).await?;
+        let clone = KVLite::new("sqlite://tmp_clone.db", "clone_dst", false, false).await?;
+        for i in 0..10 {
+            assert_eq!(clone.get(&format!("key{}", i)).await?, format!("value{}", i));
+        }
+
+        // clones are plain stores, so values kept compressed or in blobs are copied as written
+        let mut store = setup_store("clone_stored").await?;
+        store.set_value_transforms(vec![ValueTransform::Gzip]).await?;
+        store.set_dedup(Some(16)).await?;
+        let large = "x".repeat(100);
+        store.set("large", &large).await?;
+        store.set("small", "value").await?;
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("DROP TABLE IF EXISTS clone_stored_dst")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+        store.clone_store("clone_stored_dst", None).await?;
+        let clone = KVLite::new("sqlite://tmp.db", "clone_stored_dst", false, false).await?;
+        assert_eq!(clone.get("large").await?, large);
+        assert_eq!(clone.get("small").await?, "value");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync() -> Result<(), DsError> {
+        let store = setup_store("sync_local").await?;
+        store.set("a", "one").await?;
+        store.set("b", "two").await?;
+        store.set_with_ttl("expired", "gone", Duration::ZERO).await?;
+
+        let _ = std::fs::remove_file("tmp_sync.db");
+        let report = store.sync("sqlite://tmp_sync.db", SyncStrategy::Push).await?;
+        assert_eq!(report, SyncReport { pushed: vec!["a".to_string(), "b".to_string()], ..Default::default() });
+        assert_eq!(store.sync("sqlite://tmp_sync.db", SyncStrategy::Push).await?, SyncReport::default());
+
+        let other = KVLite::new("sqlite://tmp_sync.db", "sync_local", false, false).await?;
+        assert_eq!(other.get("a").await?, "one");
+        assert!(!other.contains("expired").await?);
+        other.set("c", "three").await?;
+        other.set("b", "changed").await?;
+        store.set("a", "newer").await?;
+        // b was changed last in the other store
+        let mut conn = other.pool.acquire().await?;
+        QueryBuilder::new("UPDATE sync_local SET updated_at = updated_at + 10 WHERE key = 'b'")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        assert_eq!(store.sync("sqlite://tmp_sync.db", SyncStrategy::Pull).await?,
+            SyncReport { pulled: vec!["a".to_string(), "b".to_string(), "c".to_string()], ..Default::default() });
+        assert_eq!(store.get("a").await?, "one");
+        store.set("a", "newer").await?;
+        store.set("d", "four").await?;
+
+        let report = store.sync("sqlite://tmp_sync.db", SyncStrategy::TwoWay { newest_wins: false }).await?;
+        assert_eq!(report, SyncReport { pushed: vec!["d".to_string()], conflicts: vec!["a".to_string()], ..Default::default() });
+        assert_eq!(other.get("a").await?, "one");
+
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("UPDATE sync_local SET updated_at = updated_at + 10 WHERE key = 'a'")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+        let report = store.sync("sqlite://tmp_sync.db", SyncStrategy::TwoWay { newest_wins: true }).await?;
+        assert_eq!(report, SyncReport { pushed: vec!["a".to_string()], ..Default::default() });
+        assert_eq!(other.get("a").await?, "newer");
+        assert_eq!(other.stat("a").await?.updated_at, store.stat("a").await?.updated_at);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer() -> Result<(), DsError> {
+        let store = setup_store("transfer_a").await?;
+        let other = setup_store("transfer_b").await?;
+        for i in 0..(IMPORT_BATCH + 5) {
+            store.set(&format!("users/{}", i), &i.to_string()).await?;
+        }
+        store.set("other", "value").await?;
+        store.set_with_ttl("users/expired", "gone", Duration::ZERO).await?;
+        other.set("users/0", "old").await?;
+        let users = Filter { prefix: Some("users/".to_string()), ..Default::default() };
+
+        assert_eq!(store.transfer("transfer_b", None, &users, false).await?, IMPORT_BATCH as u64 + 5);
+        assert_eq!(other.get("users/0").await?, "0");
+        assert!(!other.contains("users/expired").await?);
+        assert!(!other.contains("other").await?);
+        assert!(store.contains("users/0").await?);
+        assert!(store.transfer("transfer_a", None, &users, false).await.is_err());
+
+        let _ = std::fs::remove_file("tmp_transfer.db");
+        assert_eq!(store.transfer("moved", Some("sqlite://tmp_transfer.db"), &users, true).await?, IMPORT_BATCH as u64 + 5);
+        assert_eq!(store.count(&users).await?, 0);
+        assert_eq!(store.get("other").await?, "value");
+        let mut moved = KVLite::new("sqlite://tmp_transfer.db", "moved", false, false).await?;
+        assert_eq!(moved.count(&Filter::default()).await?, IMPORT_BATCH as u64 + 5);
+        assert_eq!(moved.get(&format!("users/{}", IMPORT_BATCH)).await?, IMPORT_BATCH.to_string());
+
+        // a store that transforms or deduplicates its values would be given them as they're written
+        store.set("users/again", "value").await?;
+        let mut transformed = setup_store("transfer_transformed").await?;
+        transformed.set_value_transforms(vec![ValueTransform::Base64]).await?;
+        assert!(store.transfer("transfer_transformed", None, &users, false).await.is_err());
+        assert!(!transformed.contains("users/again").await?);
+        let mut deduplicated = setup_store("transfer_deduplicated").await?;
+        deduplicated.set_dedup(Some(1)).await?;
+        assert!(store.transfer("transfer_deduplicated", None, &users, false).await.is_err());
+        moved.set_dedup(Some(1)).await?;
+        assert!(store.transfer("moved", Some("sqlite://tmp_transfer.db"), &users, true).await.is_err());
+        assert!(store.contains("users/again").await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_analyze_report() -> Result<(), DsError> {
+        let _ = std::fs::remove_file("tmp_maintain.db");
+        let store = KVLite::new("sqlite://tmp_maintain.db", "store", false, true).await?;
+        let big = "x".repeat(2 * 1024 * 1024);
+        store.set("big", &big).await?;
+        store.set("small", "value").await?;
+        store.set_with_ttl("expired", "value", Duration::ZERO).await?;
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("DROP INDEX store_expires_at")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        let report = store.analyze_report().await?;
+        assert_eq!(report.oversized, vec![("store".to_string(), "big".to_string(), big.len() as u64)]);
+        assert_eq!(report.expired, vec![("store".to_string(), 1)]);
+        assert_eq!(report.recommendations, vec![
+            Recommendation::Purge("store".to_string()),
+            Recommendation::CreateIndex("store".to_string()),
+            Recommendation::Analyze,
+        ]);
+
+        store.del("big").await?;
+        let report = store.analyze_report().await?;
+        assert!(report.free_bytes as usize >= big.len());
+        assert_eq!(report.recommendations.last(), Some(&Recommendation::Vacuum));
+
+        store.fix(&report.recommendations).await?;
+        let report = store.analyze_report().await?;
+        assert_eq!(report.recommendations, vec![]);
+        assert!(report.expired.is_empty());
+        assert!((report.free_bytes as usize) < big.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact() -> Result<(), DsError> {
+        let _ = std::fs::remove_file("tmp_compact.db");
+        let store = KVLite::new("sqlite://tmp_compact.db", "store", false, true).await?;
+        let big = "x".repeat(1024 * 1024);
+        for incremental in [false, true, true] {
+            store.set("big", &big).await?;
+            store.del("big").await?;
+            assert!(store.compact(incremental).await? as usize > big.len() / 2);
+        }
+        assert_eq!(store.compact(true).await?, 0);
+        assert!(std::fs::metadata("tmp_compact.db")?.len() < big.len() as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secure_delete() -> Result<(), DsError> {
+        let _ = std::fs::remove_file("tmp_secure.db");
+        let contains = |secret: &str| -> Result<bool, DsError> {
+            let file = std::fs::read("tmp_secure.db")?;
+            Ok(file.windows(secret.len()).any(|window| window == secret.as_bytes()))
+        };
+        let mut store = KVLite::new("sqlite://tmp_secure.db", "store", false, true).await?;
+        store.set_versioned(true).await?;
+        store.set("plain", "plain-secret-5f2a").await?;
+        store.del("plain").await?;
+        assert!(contains("plain-secret-5f2a")?);
+
+        store.set_secure_delete(true).await?;
+        store.set("a", "first-secret-9c1d").await?;
+        store.set("a", "second-secret-9c1d").await?;
+        store.mset(&[("b", "third-secret-9c1d"), ("c", "fourth-secret-9c1d")]).await?;
+        store.del("a").await?;
+        store.mdel(&["b"]).await?;
+        store.del_matching(&Filter { prefix: Some("c".to_string()), ..Default::default() }).await?;
+        for secret in ["first-secret-9c1d", "second-secret-9c1d", "third-secret-9c1d", "fourth-secret-9c1d"] {
+            assert!(!contains(secret)?);
+        }
+        assert!(store.history("a").await?.iter().all(|version| version.value.is_none()));
+
+        let reopened = KVLite::new("sqlite://tmp_secure.db", "store", false, true).await?;
+        reopened.set("d", "fifth-secret-9c1d").await?;
+        reopened.del("d").await?;
+        assert!(!contains("fifth-secret-9c1d")?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_to_sqlite() -> Result<(), DsError> {
+        let store = setup_store("export_sqlite").await?;
+        store.set("app/a", "one").await?;
+        store.set("app/b", "two").await?;
+        store.set("other", "three").await?;
+        store.set_with_ttl("app/expired", "gone", Duration::ZERO).await?;
+
+        let _ = std::fs::remove_file("tmp_export.db");
+        let filter = Filter { prefix: Some("app/".to_string()), ..Default::default() };
+        assert_eq!(store.export_to_sqlite("sqlite://tmp_export.db", &filter).await?, 2);
+        assert!(store.export_to_sqlite("sqlite://tmp_export.db", &filter).await.is_err());
+
+        let exported = KVLite::new("sqlite://tmp_export.db", "export_sqlite", false, false).await?;
+        let keys: Vec<String> = exported.keys(&ListOptions { sort: true, ..Default::default() }).await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+        assert_eq!(keys, vec!["app/a", "app/b"]);
+        assert_eq!(exported.get("app/b").await?, "two");
+        assert_eq!(exported.stores().await?, vec!["export_sqlite"]);
+
+        let _ = std::fs::remove_file("tmp_export_recent.db");
+        let filter = Filter { updated_since: Some(SystemTime::now() + Duration::from_secs(60)), ..Default::default() };
+        assert_eq!(store.export_to_sqlite("sqlite://tmp_export_recent.db", &filter).await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stats() -> Result<(), DsError> {
+        let store = setup_store("stats").await?;
+        let before = store.stats();
+
+        store.set("key", "value").await?;
+        store.set("other", "value").await?;
+        store.keys(&ListOptions::default()).await?;
+
+        let after = store.stats();
+        assert_eq!(after.statements - before.statements, 3);
+        assert_eq!(after.rows - before.rows, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut keys = vec!["key10", "key2", "key02", "key1", "Key3", "key", "key1a"];
+        keys.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(keys, vec!["Key3", "key", "key1", "key1a", "key02", "key2", "key10"]);
+    }
+
+    #[tokio::test]
+    async fn test_collation() -> Result<(), DsError> {
+        let mut conn = setup_store("collation").await?.pool.acquire().await?;
+        QueryBuilder::new("DROP TABLE IF EXISTS collation")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        let store = KVLite::connect_with(KVLite::options("sqlite://tmp.db", false, true)?, "collation", Collation::Natural).await?;
+        for key in ["key10", "key2", "key1"] {
+            store.set(key, "value").await?;
+        }
+        let keys: Vec<String> = store.keys(&ListOptions { sort: true, ..Default::default() }).await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+        assert_eq!(keys, vec!["key1", "key2", "key10"]);
+
+        let reopened = KVLite::new("sqlite://tmp.db", "collation", false, false).await?;
+        assert_eq!(reopened.collation, Collation::Natural);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn test_eval() -> Result<(), DsError> {
+        let store = setup_store("eval").await?;
+        store.set("count", "9").await?;
+
+        let script = r#"
+            let count = parse_int(get("count"));
+            if count >= 10 { throw "limit reached"; }
+            set("count", count + 1);
+            del("missing");
+            count + 1
+        "#;
+        assert_eq!(store.eval(script).await.unwrap(), "10");
+        assert_eq!(store.get("count").await?, "10");
+
+        store.eval(r#"set("partial", 1); set("count", parse_int(get("count")) + 1);"#).await.unwrap();
+        assert!(store.eval(script).await.is_err());
+        assert_eq!(store.get("count").await?, "11");
+
+        assert!(store.eval(r#"set("rolled_back", 1); throw "fail";"#).await.is_err());
+        assert!(!store.contains("rolled_back").await?);
+        assert_eq!(store.eval(r#"contains("partial")"#).await.unwrap(), "true");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tui")]
+    #[tokio::test]
+    async fn test_tui() -> Result<(), DsError> {
+        use ratatui::crossterm::event::KeyCode;
+        use crate::tui::{self, App, Preview};
+
+        let store = setup_store("tui").await?;
+        for key in ["apple", "Banana", "cherry", "blueberry"] {
+            store.set(key, key).await?;
+        }
+        let mut app = App::new();
+        tui::reload(&store, &mut app).await;
+        assert_eq!(app.keys, vec!["Banana", "apple", "blueberry", "cherry"]);
+        assert_eq!(app.selected(), Some("Banana"));
+
+        // the search ignores case and keeps the selected key selected while it still matches
+        tui::move_selection(&mut app, KeyCode::End);
+        tui::move_selection(&mut app, KeyCode::Up);
+        app.search = "B".to_string();
+        app.apply_search();
+        assert_eq!(app.shown.iter().map(|&i| app.keys[i].as_str()).collect::<Vec<_>>(), vec!["Banana", "blueberry"]);
+        assert_eq!(app.selected(), Some("blueberry"));
+        tui::move_selection(&mut app, KeyCode::Down);
+        assert_eq!(app.selected(), Some("blueberry"));
+        tui::move_selection(&mut app, KeyCode::PageUp);
+        assert_eq!(app.selected(), Some("Banana"));
+        app.search = "CH".to_string();
+        app.apply_search();
+        assert_eq!(app.selected(), Some("cherry"));
+        app.search = "z".to_string();
+        app.apply_search();
+        assert_eq!(app.selected(), None);
+        app.search.clear();
+        app.apply_search();
+
+        tui::rename(&store, &mut app, "apple", "cherry").await;
+        assert_eq!(app.status, "cherry already exists");
+        tui::rename(&store, &mut app, "apple", "apricot").await;
+        assert_eq!(app.status, "renamed apple to apricot");
+        assert_eq!(app.selected(), Some("apricot"));
+        assert_eq!(store.get("apricot").await?, "apple");
+        tui::rename(&store, &mut app, "apple", "avocado").await;
+        assert_eq!(app.status, "apple no longer exists");
+
+        // an edit keeps the record's TTL and content type, unless the record changed meanwhile
+        store.set_with("cherry", b"red", &SetOptions { ttl: Some(Duration::from_secs(600)), content_type: Some("text/plain".to_string()), ..Default::default() }).await?;
+        let (value, meta) = store.get_with_meta("cherry").await?;
+        let preview = Preview { key: "cherry".to_string(), value, meta };
+        assert_eq!(tui::save(&store, &preview, b"dark red").await, "saved cherry");
+        let (value, meta) = store.get_with_meta("cherry").await?;
+        assert_eq!(value, b"dark red");
+        assert_eq!(meta.content_type.as_deref(), Some("text/plain"));
+        assert!(meta.expires_at.unwrap() > SystemTime::now() + Duration::from_secs(590));
+        assert_eq!(tui::save(&store, &preview, b"black").await, "cherry was changed while it was being edited and was not saved");
+        assert_eq!(store.get("cherry").await?, "dark red");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking() -> Result<(), DsError> {
+        use crate::KVLiteBlocking;
+
+        let store = KVLiteBlocking::open(KVLiteOptions::memory(), "blocking")?;
+        store.set("a", "1")?;
+        store.set_with_ttl("b", "2", Duration::from_secs(60))?;
+        assert_eq!(store.get("a")?, "1");
+        assert_eq!(store.incr("a", 2)?, 3);
+        assert!(store.ttl("b")?.is_some());
+        assert_eq!(store.count(&Filter::default())?, 2);
+        assert_eq!(store.keys(&ListOptions { sort: true, ..Default::default() })?.iter().map(|row| row.get("key")).collect::<Vec<String>>(), vec!["a", "b"]);
+
+        store.del("a")?;
+        assert!(!store.contains("a")?);
+        assert!(matches!(store.get("a"), Err(DsError::NotFound)));
+        assert_eq!(store.block_on(|store| store.stores())?, vec!["blocking"]);
+        Ok(())
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_ffi() {
+        use std::{ffi::{CStr, CString}, ptr};
+        use crate::ffi::*;
+
+        let (path, name) = (CString::new("sqlite::memory:").unwrap(), CString::new("ffi").unwrap());
+        let key = CString::new("greeting").unwrap();
+        unsafe {
+            let store = ds_open(path.as_ptr(), name.as_ptr());
+            assert!(!store.is_null());
+            assert_eq!(ds_set(store, key.as_ptr(), c"hello".as_ptr(), 5), DS_OK);
+
+            let (mut value, mut len) = (ptr::null_mut(), 0);
+            assert_eq!(ds_get(store, key.as_ptr(), &mut value, &mut len), DS_OK);
+            assert_eq!(len, 5);
+            assert_eq!(CStr::from_ptr(value).to_str(), Ok("hello"));
+            ds_free(value);
+
+            assert_eq!(ds_del(store, key.as_ptr()), DS_OK);
+            assert_eq!(ds_get(store, key.as_ptr(), &mut value, ptr::null_mut()), DS_NOT_FOUND);
+            assert_eq!(ds_get(store, ptr::null(), &mut value, ptr::null_mut()), DS_ERROR);
+            assert!(!ds_last_error().is_null());
+            ds_close(store);
+        }
+    }
+
+    #[cfg(feature = "icu")]
+    #[tokio::test]
+    async fn test_locale_collation() -> Result<(), DsError> {
+        let mut conn = setup_store("locale_collation").await?.pool.acquire().await?;
+        QueryBuilder::new("DROP TABLE IF EXISTS locale_collation")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        let collation: Collation = "icu:de".parse().unwrap();
+        let store = KVLite::connect_with(KVLite::options("sqlite://tmp.db", false, true)?, "locale_collation", collation.clone()).await?;
+        for key in ["zebra", "Äpfel", "apfel", "Birne"] {
+            store.set(key, "value").await?;
+        }
+
+        let reopened = KVLite::new("sqlite://tmp.db", "locale_collation", false, false).await?;
+        assert_eq!(reopened.collation, collation);
+        let keys: Vec<String> = reopened.keys(&ListOptions { sort: true, ..Default::default() }).await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+        assert_eq!(keys, vec!["apfel", "Äpfel", "Birne", "zebra"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reverse_limit() -> Result<(), DsError> {
+        let store = setup_store("reverse_limit").await?;
+        for i in 0..10 {
+            store.set(&format!("key{}", i), &format!("value{}", i)).await?;
+        }
+
+        let keys: Vec<String> = store.keys(&ListOptions { reverse: true, limit: Some(3), ..Default::default() }).await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+        assert_eq!(keys, vec!["key9", "key8", "key7"]);
+
+        let records = store.records(&ListOptions { sort: true, limit: Some(2), ..Default::default() }).await?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].get::<String, &str>("key"), "key0");
+        assert_eq!(records[1].get::<String, &str>("value"), "value1");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sort_by() -> Result<(), DsError> {
+        let store = setup_store("sort_by").await?;
+        for (key, value, updated_at) in [("a", "3", 20), ("b", "1", 30), ("c", "2", 10), ("d", "1", 10)] {
+            store.set(key, value).await?;
+            let mut conn = store.pool.acquire().await?;
+            QueryBuilder::new("UPDATE sort_by SET updated_at=")
+                .push_bind(updated_at)
+                .push(" WHERE key=")
+                .push_bind(key)
+                .build()
+                .execute(&mut conn)
+                .await?;
+        }
+
+        let keys = |by, reverse| {
+            let store = &store;
+            async move {
+                let options = ListOptions { sort: true, reverse, by, ..Default::default() };
+                Ok::<Vec<String>, DsError>(store.keys(&options).await?.iter().map(|row| row.get("key")).collect())
+            }
+        };
+        assert_eq!(keys(SortBy::Key, true).await?, vec!["d", "c", "b", "a"]);
+        assert_eq!(keys(SortBy::Value, false).await?, vec!["b", "d", "c", "a"]);
+        assert_eq!(keys(SortBy::Value, true).await?, vec!["a", "c", "d", "b"]);
+        assert_eq!(keys(SortBy::UpdatedAt, false).await?, vec!["c", "d", "a", "b"]);
+        assert_eq!(keys(SortBy::UpdatedAt, true).await?, vec!["b", "a", "d", "c"]);
+        assert_eq!("updated_at".parse::<SortBy>().unwrap(), SortBy::UpdatedAt);
+        assert!("size".parse::<SortBy>().is_err());
+
+        let mut random = keys(SortBy::Random, false).await?;
+        random.sort();
+        assert_eq!(random, vec!["a", "b", "c", "d"]);
+        let options = ListOptions { sort: true, by: SortBy::Random, limit: Some(2), ..Default::default() };
+        assert_eq!(store.records(&options).await?.len(), 2);
+        let key = store.random_key(&Filter { prefix: Some("b".to_string()), ..Default::default() }).await?;
+        assert_eq!(key.as_deref(), Some("b"));
+        assert_eq!(store.random_key(&Filter { prefix: Some("z".to_string()), ..Default::default() }).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cursor() -> Result<(), DsError> {
+        let store = setup_store("cursor").await?;
+        for i in 0..10 {
+            store.set(&format!("key{}", i), &format!("value{}", i)).await?;
+        }
+
+        for reverse in [false, true] {
+            let mut options = ListOptions { sort: true, reverse, limit: Some(3), ..Default::default() };
+            let mut keys = vec![];
+            loop {
+                let page = store.keys(&options).await?;
+                keys.extend(page.iter().map(|row| row.get::<String, &str>("key")));
+                match page.last() {
+                    Some(last) => {
+                        let cursor = Cursor { key: last.get("key"), reverse };
+                        let cursor: Cursor = cursor.to_string().parse().unwrap();
+                        options = cursor.list_options(Some(3));
+                    },
+                    None => break,
+                }
+            }
+
+            let mut expected: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+            if reverse {
+                expected.reverse();
+            }
+            assert_eq!(keys, expected);
+        }
+
+        assert!("x00".parse::<Cursor>().is_err());
+        assert!("f0".parse::<Cursor>().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_range() -> Result<(), DsError> {
+        let store = setup_store("range").await?;
+        for day in ["2024-01-01", "2024-01-02", "2024-01-03", "2024-02-01"] {
+            store.set(&format!("{}/log", day), day).await?;
+        }
+
+        let range_keys = |rows: Vec<SqliteRow>| rows.iter().map(|row| row.get::<String, &str>("key")).collect::<Vec<_>>();
+        assert_eq!(range_keys(store.range("2024-01-02".."2024-01-03").await?), vec!["2024-01-02/log"]);
+        assert_eq!(range_keys(store.range("2024-01-02"..="2024-01-03/log").await?), vec!["2024-01-02/log", "2024-01-03/log"]);
+        assert_eq!(range_keys(store.range("2024-02"..).await?), vec!["2024-02-01/log"]);
+        assert_eq!(range_keys(store.range(.."2024-01-02").await?), vec!["2024-01-01/log"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("app.*", "app.db.host"));
+        assert!(glob_match("app.*.host", "app.db.host"));
+        assert!(glob_match("key?", "key1"));
+        assert!(!glob_match("key?", "key10"));
+        assert!(!glob_match("app.*", "other.db"));
+        assert!(glob_match("*a*b", "xaxxab"));
+    }
+
+    #[test]
+    fn test_key_levels() {
+        assert_eq!(key_levels("app.db.host").collect::<Vec<_>>(), vec!["app", "db", "host"]);
+        assert_eq!(key_levels("/app//db").collect::<Vec<_>>(), vec!["app", "db"]);
+        assert_eq!(key_at_depth("app.db.host", "", 1), "app");
+        assert_eq!(key_at_depth("app.db.host", "", 2), "app.db");
+        assert_eq!(key_at_depth("app.db.host", "", 3), "app.db.host");
+        assert_eq!(key_at_depth("app.db.host", "app.", 1), "app.db");
+        assert_eq!(key_at_depth("app.db.host", "app", 1), "app.db");
+        assert_eq!(key_at_depth("app/db.host", "other", 1), "app");
+        assert_eq!(key_at_depth("app", "", 1), "app");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() -> Result<(), DsError> {
+        let store = setup_store("subscribe").await?;
+        let mut changes = Box::pin(store.subscribe("app.*"));
+
+        store.set("other", "value").await?;
+        store.set("app.name", "ds").await?;
+        store.del("app.name").await?;
+
+        assert_eq!(changes.next().await, Some(ChangeEvent { kind: ChangeKind::Set, key: "app.name".to_string(), value: Some("ds".to_string()) }));
+        assert_eq!(changes.next().await, Some(ChangeEvent { kind: ChangeKind::Delete, key: "app.name".to_string(), value: None }));
+
+        store.set_with_ttl("app.session", "token", Duration::ZERO).await?;
+        store.purge_expired().await?;
+        assert_eq!(changes.next().await, Some(ChangeEvent { kind: ChangeKind::Set, key: "app.session".to_string(), value: Some("token".to_string()) }));
+        assert_eq!(changes.next().await, Some(ChangeEvent { kind: ChangeKind::Expired, key: "app.session".to_string(), value: None }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_events() -> Result<(), DsError> {
+        let store = setup_store("expired_events").await?;
+        let mut changes = Box::pin(store.subscribe("*"));
+        let event = |kind, key: &str, value: Option<&str>| Some(ChangeEvent { kind, key: key.to_string(), value: value.map(str::to_string) });
+
+        // the writes that purge expired records tell subscribers they expired before their own change
+        store.set_with_ttl("lapsed1", "value", Duration::ZERO).await?;
+        assert_eq!(store.getset("a", "1").await?, None);
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "lapsed1", Some("value")));
+        assert_eq!(changes.next().await, event(ChangeKind::Expired, "lapsed1", None));
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "a", Some("1")));
+
+        store.set_with_ttl("lapsed2", "value", Duration::ZERO).await?;
+        assert!(store.rename("a", "b", false).await?);
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "lapsed2", Some("value")));
+        assert_eq!(changes.next().await, event(ChangeKind::Expired, "lapsed2", None));
+        assert_eq!(changes.next().await, event(ChangeKind::Delete, "a", None));
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "b", Some("1")));
+
+        store.set_with_ttl("lapsed3", "value", Duration::ZERO).await?;
+        assert!(store.copy("b", "c", false).await?);
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "lapsed3", Some("value")));
+        assert_eq!(changes.next().await, event(ChangeKind::Expired, "lapsed3", None));
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "c", Some("1")));
+
+        store.set_with_ttl("lapsed4", "value", Duration::ZERO).await?;
+        store.rename_prefix("c", "d").await?;
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "lapsed4", Some("value")));
+        assert_eq!(changes.next().await, event(ChangeKind::Expired, "lapsed4", None));
+        assert_eq!(changes.next().await, event(ChangeKind::Delete, "c", None));
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "d", Some("1")));
+
+        // a record deleted before it expired was deleted, not expired
+        store.set_with_ttl("e", "value", Duration::from_secs(60)).await?;
+        store.del("e").await?;
+        assert_eq!(store.purge_expired().await?, 0);
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "e", Some("value")));
+        assert_eq!(changes.next().await, event(ChangeKind::Delete, "e", None));
+        store.set("f", "value").await?;
+        assert_eq!(changes.next().await, event(ChangeKind::Set, "f", Some("value")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch() -> Result<(), DsError> {
+        let store = setup_store("watch").await?;
+        // a second handle stands in for another process, whose writes subscribe can't see
+        let other = KVLite::new("sqlite://tmp.db", "watch", false, false).await?;
+        let mut changes = Box::pin(store.watch("app.*", Duration::from_millis(10)).await?);
+
+        other.set("other", "value").await?;
+        other.set("app.name", "ds").await?;
+        other.del("app.name").await?;
+        other.set_with_ttl("app.session", "token", Duration::ZERO).await?;
+        other.purge_expired().await?;
+
+        // values are read when the log is polled, so only the order of changes is certain
+        let mut events = vec![];
+        for _ in 0..4 {
+            let event = changes.next().await.unwrap()?;
+            events.push((event.kind, event.key));
+        }
+        assert_eq!(events, vec![
+            (ChangeKind::Set, "app.name".to_string()),
+            (ChangeKind::Delete, "app.name".to_string()),
+            (ChangeKind::Set, "app.session".to_string()),
+            (ChangeKind::Expired, "app.session".to_string()),
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_filtered() -> Result<(), DsError> {
+        let filter: ChangeFilter = "prefix:app. *.json kind:set tag:json changed".parse().unwrap();
+        assert_eq!(filter, ChangeFilter {
+            prefixes: vec!["app.".to_string()],
+            globs: vec!["*.json".to_string()],
+            kinds: vec![ChangeKind::Set],
+            tags: vec![ValueTag::Json],
+            changed_only: true,
+        });
+        assert_eq!(filter.to_string().parse::<ChangeFilter>(), Ok(filter.clone()));
+        assert!("kind:renamed".parse::<ChangeFilter>().is_err());
+        assert_eq!("".parse::<ChangeFilter>(), Ok(ChangeFilter::default()));
+
+        let store = setup_store("watch_filtered").await?;
+        let other = KVLite::new("sqlite://tmp.db", "watch_filtered", false, false).await?;
+        let mut changes = Box::pin(store.watch_filtered(&filter, Duration::from_millis(10)).await?);
+        let json = SetOptions { tag: Some(ValueTag::Json), ..Default::default() };
+        other.set_with("app.a.json", b"1", &json).await?;
+        other.set_with("app.a.json", b"1", &json).await?;
+        other.set_with("app.b.json", b"2", &SetOptions::default()).await?;
+        other.set_with("other.c.json", b"3", &json).await?;
+        other.del("app.a.json").await?;
+        other.set_with("app.a.json", b"1", &json).await?;
+        // sets are seen with the record's value when the log is read, so the next value is only
+        // written once the last of these has been seen
+        for value in ["1", "1"] {
+            let event = tokio::time::timeout(Duration::from_secs(5), changes.next()).await.expect("no change seen").unwrap()?;
+            assert_eq!((event.key.as_str(), event.value.as_deref()), ("app.a.json", Some(value)));
+        }
+        other.set_with("app.a.json", b"4", &json).await?;
+        let event = tokio::time::timeout(Duration::from_secs(5), changes.next()).await.expect("no change seen").unwrap()?;
+        assert_eq!((event.key.as_str(), event.value.as_deref()), ("app.a.json", Some("4")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_getset() -> Result<(), DsError> {
+        let store = setup_store("getset").await?;
+        assert_eq!(store.getset("key", "one").await?, None);
+        assert_eq!(store.getset("key", "two").await?, Some("one".to_string()));
+        assert_eq!(store.get("key").await?, "two");
+
+        store.set_with_ttl("expired", "old", Duration::ZERO).await?;
+        assert_eq!(store.getset("expired", "new").await?, None);
+        assert_eq!(store.ttl("expired").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cas() -> Result<(), DsError> {
+        let store = setup_store("cas").await?;
+
+        assert!(store.cas("lock", None, "first").await?);
+        assert!(!store.cas("lock", None, "second").await?);
+        assert_eq!(store.get("lock").await?, "first");
+
+        assert!(!store.cas("lock", Some("second"), "third").await?);
+        assert!(store.cas("lock", Some("first"), "third").await?);
+        assert_eq!(store.get("lock").await?, "third");
+        assert!(!store.cas("missing", Some("third"), "value").await?);
+
+        store.set_bytes("bytes", b"\xff").await?;
+        assert!(!store.cas("bytes", Some("text"), "value").await?);
+
+        // expired records count as absent
+        store.set_with_ttl("session", "old", Duration::ZERO).await?;
+        assert!(!store.cas("session", Some("old"), "new").await?);
+        let options = SetOptions { ttl: Some(Duration::from_secs(60)), ..Default::default() };
+        assert!(store.cas_with("session", None, b"new", &options).await?);
+        assert_eq!(store.get("session").await?, "new");
+        assert!(store.ttl("session").await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history() -> Result<(), DsError> {
+        let store = setup_store("history").await?;
+        store.set_versioned(false).await?;
+        store.set("key", "before").await?;
+        store.set_versioned(true).await?;
+        assert!(store.is_versioned().await?);
+
+        store.set("key", "first").await?;
+        store.set("key", "second").await?;
+        store.del("key").await?;
+        let values: Vec<_> = store.history("key").await?.into_iter().map(|version| (version.version, version.value)).collect();
+        assert_eq!(values, vec![(1, Some(b"first".to_vec())), (2, Some(b"second".to_vec())), (3, None)]);
+        assert_eq!(store.get_version("key", 1).await?.value, Some(b"first".to_vec()));
+        assert!(matches!(store.get_version("key", 4).await, Err(DsError::NotFound)));
+
+        assert_eq!(store.rollback("key", None).await?, 2);
+        assert_eq!(store.get("key").await?, "second");
+        assert_eq!(store.rollback("key", Some(1)).await?, 1);
+        assert_eq!(store.get("key").await?, "first");
+        assert_eq!(store.history("key").await?.len(), 5);
+        assert!(matches!(store.rollback("missing", None).await, Err(DsError::NotFound)));
+
+        // the history table isn't a store of its own
+        assert!(!store.stores().await?.contains(&"history_history".to_string()));
+        store.set_versioned(false).await?;
+        assert!(!store.is_versioned().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_as_of() -> Result<(), DsError> {
+        let store = setup_store("as_of").await?;
+        store.set_versioned(false).await?;
+        store.set_versioned(true).await?;
+        store.set("a", "first").await?;
+        store.set("b", "first").await?;
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new(format!("UPDATE {} SET at = at - 100", store.kv_name.object("history"))).build().execute(&mut conn).await?;
+        drop(conn);
+        store.set("a", "second").await?;
+        store.del("b").await?;
+        store.set("c", "first").await?;
+
+        let before = std::time::SystemTime::now() - Duration::from_secs(50);
+        assert_eq!(store.get_as_of("a", before).await?.value, Some(b"first".to_vec()));
+        assert_eq!(store.get_as_of("a", std::time::SystemTime::now()).await?.value, Some(b"second".to_vec()));
+        assert_eq!(store.get_as_of("b", std::time::SystemTime::now()).await?.value, None);
+        assert!(matches!(store.get_as_of("c", before).await, Err(DsError::NotFound)));
+
+        let keys = |records: Vec<(String, Version)>| records.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys(store.records_as_of(before, &Filter::default()).await?), vec!["a", "b"]);
+        assert_eq!(keys(store.records_as_of(std::time::SystemTime::now(), &Filter::default()).await?), vec!["a", "c"]);
+        let filter = Filter { prefix: Some("b".to_string()), ..Default::default() };
+        assert_eq!(keys(store.records_as_of(before, &filter).await?), vec!["b"]);
+        assert!(store.records_as_of(UNIX_EPOCH, &Filter::default()).await?.is_empty());
+
+        store.set_versioned(false).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_provenance() -> Result<(), DsError> {
+        let mut store = setup_store("provenance").await?;
+        store.set_versioned(false).await?;
+        store.set_versioned(true).await?;
+        store.set("key", "unknown").await?;
+        store.set_source(Some("cli:alice".to_string()));
+        store.set("key", "first").await?;
+        store.set_with("key", b"second", &SetOptions { source: Some("token:app".to_string()), ..Default::default() }).await?;
+        store.incr("counter", 1).await?;
+        store.set_source(Some("import:records.csv".to_string()));
+        store.import_from("counter,5".as_bytes(), Format::Csv, false).await?;
+
+        assert_eq!(store.stat("key").await?.source.as_deref(), Some("token:app"));
+        assert_eq!(store.stat("counter").await?.source.as_deref(), Some("import:records.csv"));
+        let sources: Vec<_> = store.history("key").await?.into_iter().map(|version| version.source).collect();
+        assert_eq!(sources, vec![None, Some("cli:alice".to_string()), Some("token:app".to_string())]);
+
+        // a standby keeps the primary's sources
+        let standby = setup_store("provenance_standby").await?;
+        standby.apply_changes(&store.replication_snapshot().await?, true).await?;
+        assert_eq!(standby.stat("key").await?.source.as_deref(), Some("token:app"));
+
+        store.set_versioned(false).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn test_encrypted() -> Result<(), DsError> {
+        let _ = std::fs::remove_file("tmp-encrypted.db");
+        let options = |passphrase| Ok::<_, DsError>(KVLite::encrypted(KVLite::options("sqlite://tmp-encrypted.db", false, true)?, passphrase));
+
+        let store = KVLite::connect_with(options("it's secret")?, "store", Collation::Binary).await?;
+        store.set("token", "plaintext value").await?;
+        store.pool.close().await;
+
+        let file = std::fs::read("tmp-encrypted.db")?;
+        assert!(!file.windows(15).any(|window| window == b"plaintext value"));
+        assert!(KVLite::connect_with(options("wrong")?, "store", Collation::Binary).await.is_err());
+        assert!(KVLite::new("sqlite://tmp-encrypted.db", "store", false, false).await.is_err());
+
+        let store = KVLite::connect_with(options("it's secret")?, "store", Collation::Binary).await?;
+        assert_eq!(store.get("token").await?, "plaintext value");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_templates() -> Result<(), DsError> {
+        let mut sessions = setup_store("sessions").await?;
+        sessions.apply_template(Template::Sessions).await?;
+        sessions.set("a", "user").await?;
+        assert!(sessions.ttl("a").await?.unwrap() > Duration::from_secs(23 * 60 * 60));
+        sessions.set_with_ttl("b", "user", Duration::from_secs(60)).await?;
+        assert!(sessions.ttl("b").await?.unwrap() <= Duration::from_secs(60));
+        sessions.incr("visits", 1).await?;
+        assert!(sessions.ttl("visits").await?.is_some());
+        assert!(sessions.set("big", &"x".repeat(64 * 1024 + 1)).await.is_err());
+        assert!(sessions.apply_template(Template::Sessions).await.is_err());
+
+        // settings are kept with the store
+        let mut sessions = KVLite::new("sqlite://tmp.db", "sessions", false, false).await?;
+        assert_eq!(sessions.max_value_size().await?, Some(64 * 1024));
+        sessions.set("c", "user").await?;
+        assert!(sessions.ttl("c").await?.is_some());
+        sessions.set_default_ttl(None).await?;
+        sessions.set("d", "user").await?;
+        assert_eq!(sessions.ttl("d").await?, None);
+
+        let mut config = setup_store("config").await?;
+        config.apply_template(Template::Config).await?;
+        assert!(config.set("app", "not json").await.is_err());
+        config.set("app", r#"{"debug":false}"#).await?;
+        config.set("app", r#"{"debug":true}"#).await?;
+        config.rollback("app", None).await?;
+        assert_eq!(config.get("app").await?, r#"{"debug":false}"#);
+        assert_eq!(config.ttl("app").await?, None);
+
+        let mut secrets = setup_store("secrets").await?;
+        secrets.apply_template(Template::Secrets).await?;
+        assert_eq!(secrets.value_type().await?, ValueType::Text);
+        assert!(secrets.set_bytes("key", &[0xff, 0xfe]).await.is_err());
+        assert!(secrets.history("key").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_transforms() -> Result<(), DsError> {
+        let mut store = setup_store("canonical").await?;
+        store.set("  Hello World ", "1").await?;
+        store.set("Config/Feature Flags", "2").await?;
+        store.set("other", "3").await?;
+        let renamed = store.set_key_transforms(vec![KeyTransform::Trim, KeyTransform::Slugify]).await?;
+        assert_eq!(renamed, 2);
+        assert_eq!(store.get("hello-world").await?, "1");
+        assert_eq!(store.get("HELLO world!").await?, "1");
+        assert_eq!(store.get("config/feature-flags").await?, "2");
+
+        store.set(" New_Key ", "4").await?;
+        assert!(store.contains("new-key").await?);
+        store.incr("Visits!", 1).await?;
+        store.mset(&[("Multi One", "a")]).await?;
+        assert_eq!(store.mget(&["multi-one"]).await?, vec![Some("a".to_string())]);
+        assert!(store.rename("Multi One", "Multi Two", false).await?);
+        assert_eq!(store.get("multi-two").await?, "a");
+        store.del("NEW KEY").await?;
+        assert!(!store.contains("new-key").await?);
+        let mut tx = store.transaction().await?;
+        tx.set("In A Transaction", "5").await?;
+        tx.commit().await?;
+        let mut keys: Vec<String> = store.keys(&ListOptions::default()).await?.iter().map(|row| row.get("key")).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["config/feature-flags", "hello-world", "in-a-transaction", "multi-two", "other", "visits"]);
+
+        // transforms are kept with the store
+        let mut store = KVLite::new("sqlite://tmp.db", "canonical", false, false).await?;
+        assert_eq!(store.key_transforms(), &[KeyTransform::Trim, KeyTransform::Slugify]);
+        assert_eq!(store.set_key_transforms(vec![KeyTransform::StripPrefix("config/".to_string())]).await?, 1);
+        assert_eq!(store.get("config/feature-flags").await?, "2");
+        assert_eq!(store.get("feature-flags").await?, "2");
+
+        // nothing is renamed if two records would end up with one key
+        store.set_key_transforms(vec![]).await?;
+        store.set("Other", "6").await?;
+        assert!(store.set_key_transforms(vec![KeyTransform::Lowercase]).await.is_err());
+        assert!(store.key_transforms().is_empty());
+        assert_eq!(store.get("Other").await?, "6");
+
+        assert_eq!("strip-prefix:tenant/".parse(), Ok(KeyTransform::StripPrefix("tenant/".to_string())));
+        assert!("uppercase".parse::<KeyTransform>().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dedup() -> Result<(), DsError> {
+        let _ = std::fs::remove_file("tmp_dedup.db");
+        let mut store = KVLite::new("sqlite://tmp_dedup.db", "store", false, true).await?;
+        let big = "x".repeat(1000);
+        store.set("a", &big).await?;
+        store.set("small", "value").await?;
+        assert_eq!(store.set_dedup(Some(100)).await?, 1);
+        store.set("b", &big).await?;
+        store.set_bytes("c", &[0xff; 200]).await?;
+        // looks like a reference, so it's kept as a blob to be read back as written
+        store.set("fake", "dsr-blob:0000").await?;
+
+        assert_eq!(store.get("a").await?, big);
+        assert_eq!(store.get_with_meta("b").await?.0, big.as_bytes());
+        assert_eq!(store.get_bytes("c").await?, vec![0xff; 200]);
+        assert_eq!(store.get("small").await?, "value");
+        assert_eq!(store.get("fake").await?, "dsr-blob:0000");
+        let count = |store: &KVLite| {
+            let pool = store.pool.clone();
+            async move { sqlx::query("SELECT count(*) FROM store_blobs").fetch_one(&pool).await.map(|row| row.get::<i64, usize>(0)) }
+        };
+        assert_eq!(count(&store).await?, 3);
+        let stats = store.datastore_stats().await?;
+        assert_eq!(stats.stores[0].blob_bytes, 1000 + 200 + 13);
+        assert_eq!(stats.dedup_saved_bytes(), 1000);
+        assert!(matches!(store.incr("small", 1).await, Err(DsError::Sql(_))));
+
+        // reopened, the handle reads the setting
+        let store2 = KVLite::new("sqlite://tmp_dedup.db", "store", false, false).await?;
+        assert_eq!(store2.dedup_min_size(), Some(100));
+        assert_eq!(store2.get("b").await?, big);
+        store2.pool.close().await;
+
+        store.del("a").await?;
+        store.del("b").await?;
+        store.compact(false).await?;
+        assert_eq!(count(&store).await?, 2);
+
+        assert_eq!(store.set_dedup(None).await?, 2);
+        assert_eq!(store.get_bytes("c").await?, vec![0xff; 200]);
+        assert_eq!(store.get("fake").await?, "dsr-blob:0000");
+        assert_eq!(store.datastore_stats().await?.stores[0].blob_bytes, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dedup_constraints() -> Result<(), DsError> {
+        // types and sizes are checked by triggers, which would only see the references
+        let mut store = setup_store("dedup_typed").await?;
+        store.set_value_type(ValueType::Json).await?;
+        assert!(store.set_dedup(Some(16)).await.is_err());
+        assert_eq!(store.dedup_min_size(), None);
+        store.set_value_type(ValueType::Any).await?;
+        store.set_max_value_size(Some(1000)).await?;
+        assert!(store.set_dedup(Some(16)).await.is_err());
+        store.set_max_value_size(None).await?;
+
+        store.set_dedup(Some(16)).await?;
+        assert!(store.set_value_type(ValueType::Json).await.is_err());
+        assert!(store.set_max_value_size(Some(1000)).await.is_err());
+        let json = format!("{{\"value\": \"{}\"}}", "x".repeat(100));
+        store.set("json", &json).await?;
+        assert_eq!(store.get("json").await?, json);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dedup_reads() -> Result<(), DsError> {
+        let mut store = setup_store("dedup_reads").await?;
+        store.set_dedup(Some(4)).await?;
+        store.set("a", "hello world").await?;
+        store.set("b", "hi").await?;
+
+        let records: Vec<(String, String)> = store.records(&ListOptions::default()).await?
+            .iter()
+            .map(|row| (row.get("key"), row.get("value")))
+            .collect();
+        assert_eq!(records, vec![("a".to_string(), "hello world".to_string()), ("b".to_string(), "hi".to_string())]);
+        let streamed: Vec<String> = store.stream_records(&ListOptions::default()).await?
+            .map(|row| row.map(|row| row.get("value")))
+            .collect::<Result<Vec<String>, DsError>>()
+            .await?;
+        assert_eq!(streamed, vec!["hello world", "hi"]);
+
+        // value filters match values as written, not their references
+        let matching = |contains: &str| Filter { value_contains: Some(contains.to_string()), ..Default::default() };
+        assert_eq!(store.records(&ListOptions { filter: matching("world"), ..Default::default() }).await?.len(), 1);
+        assert_eq!(store.count(&matching("world")).await?, 1);
+        assert_eq!(store.count(&matching("h")).await?, 2);
+        assert_eq!(store.count(&matching("dsr-blob")).await?, 0);
+        assert_eq!(store.snapshot().await?.count(&matching("world")).await?, 1);
+        assert!(store.search("world", None).await.is_err());
+        store.set("doomed", "goodbye world").await?;
+        assert_eq!(store.del_matching(&matching("bye")).await?, 1);
+        assert_eq!(store.count(&Filter::default()).await?, 2);
+
+        let _ = std::fs::remove_file("tmp_dedup_export.db");
+        assert_eq!(store.export_to_sqlite("sqlite://tmp_dedup_export.db", &Filter::default()).await?, 2);
+        let exported = KVLite::new("sqlite://tmp_dedup_export.db", "dedup_reads", false, false).await?;
+        assert_eq!(exported.get("a").await?, "hello world");
+        exported.pool.close().await;
+
+        let plain = setup_store("dedup_reads_plain").await?;
+        plain.set("a", "hello world").await?;
+        assert!(store.diff("dedup_reads_plain").await?.iter().all(|row| row.get::<&str, &str>("change") != "~"));
+
+        let _ = std::fs::remove_file("tmp_dedup_sync.db");
+        store.sync("sqlite://tmp_dedup_sync.db", SyncStrategy::Push).await?;
+        let other = KVLite::new("sqlite://tmp_dedup_sync.db", "dedup_reads", false, false).await?;
+        assert_eq!(other.get("a").await?, "hello world");
+        other.set("c", "pulled back").await?;
+        store.sync("sqlite://tmp_dedup_sync.db", SyncStrategy::Pull).await?;
+        assert_eq!(store.get("c").await?, "pulled back");
+        other.pool.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_value_transforms() -> Result<(), DsError> {
+        let mut store = setup_store("transformed").await?;
+        store.set("before", "written before the transforms").await?;
+        assert_eq!(store.set_value_transforms(vec![ValueTransform::Gzip, ValueTransform::Base64]).await?, 1);
+        assert_eq!(store.get("before").await?, "written before the transforms");
+
+        let value = "a".repeat(1000);
+        store.set("after", &value).await?;
+        assert_eq!(store.get("after").await?, value);
+        assert_eq!(store.get_bytes("after").await?, value.as_bytes());
+        assert_eq!(store.mget(&["after", "missing"]).await?, vec![Some(value.clone()), None]);
+        // what's stored is the compressed value, encoded as text
+        let mut conn = store.pool.acquire().await?;
+        let stored: String = QueryBuilder::new("SELECT value FROM transformed WHERE key = 'after'")
+            .build()
+            .fetch_one(&mut conn)
+            .await?
+            .get("value");
+        drop(conn);
+        assert!(stored.len() < value.len());
+        assert_ne!(stored, value);
+
+        let mut tx = store.transaction().await?;
+        tx.set("in a transaction", "1").await?;
+        tx.commit().await?;
+        assert_eq!(store.get("in a transaction").await?, "1");
+        assert!(store.incr("in a transaction", 1).await.is_err());
+        assert!(store.append("in a transaction", "2").await.is_err());
+
+        // transforms are kept with the store
+        let mut store = KVLite::new("sqlite://tmp.db", "transformed", false, false).await?;
+        assert_eq!(store.value_transforms(), &[ValueTransform::Gzip, ValueTransform::Base64]);
+        assert_eq!(store.set_value_transforms(vec![]).await?, 3);
+        assert_eq!(store.get("after").await?, value);
+        assert_eq!(store.strlen("after").await?, 1000);
+
+        assert_eq!("compress".parse(), Ok(ValueTransform::Gzip));
+        assert!("rot13".parse::<ValueTransform>().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_value_transform_constraints() -> Result<(), DsError> {
+        // types and sizes are checked by triggers, which would only see values transformed
+        let mut store = setup_store("transformed_typed").await?;
+        store.set_value_type(ValueType::Json).await?;
+        assert!(store.set_value_transforms(vec![ValueTransform::Gzip]).await.is_err());
+        assert!(store.value_transforms().is_empty());
+        store.set("json", "{}").await?;
+        store.set_value_type(ValueType::Any).await?;
+        store.set_max_value_size(Some(100)).await?;
+        assert!(store.set_value_transforms(vec![ValueTransform::Base64]).await.is_err());
+        store.set_max_value_size(None).await?;
+
+        store.set_value_transforms(vec![ValueTransform::Base64]).await?;
+        assert!(store.set_value_type(ValueType::Integer).await.is_err());
+        assert!(store.set_max_value_size(Some(100)).await.is_err());
+        assert_eq!(store.value_type().await?, ValueType::Any);
+        store.set("n", "5").await?;
+        assert_eq!(store.get("n").await?, "5");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_value_transform_reads() -> Result<(), DsError> {
+        let mut store = setup_store("transformed_reads").await?;
+        store.set_value_transforms(vec![ValueTransform::Base64]).await?;
+        store.set("k", "v1").await?;
+
+        // listings hold what's stored, read as written with untransform_value
+        let records = store.records(&ListOptions::default()).await?;
+        assert_eq!(records[0].get::<String, &str>("value"), "djE=");
+        assert_eq!(store.untransform_value(records[0].get("value"))?, b"v1");
+
+        // what's stored can't be matched or ordered as written, so filtering and sorting by value are refused
+        let filter = Filter { value_contains: Some("v1".to_string()), ..Default::default() };
+        assert!(store.records(&ListOptions { filter: filter.clone(), ..Default::default() }).await.is_err());
+        assert!(store.count(&filter).await.is_err());
+        assert!(store.del_matching(&filter).await.is_err());
+        assert!(store.records(&ListOptions { sort: true, by: SortBy::Value, ..Default::default() }).await.is_err());
+        assert_eq!(store.records(&ListOptions { sort: true, ..Default::default() }).await?.len(), 1);
+        assert!(store.search("v1", None).await.is_err());
+
+        // changes carry values as written
+        let mut changes = Box::pin(store.watch_filtered(&ChangeFilter::default(), Duration::from_millis(10)).await?);
+        store.set("k", "v2").await?;
+        let event = tokio::time::timeout(Duration::from_secs(5), changes.next()).await.expect("no change seen").unwrap()?;
+        assert_eq!(event.value.as_deref(), Some("v2"));
+        assert_eq!(store.recent_changes(1).await?[0].value.as_deref(), Some("v2"));
+        let snapshot = store.replication_snapshot().await?;
+        assert_eq!(snapshot.changes[0].value.as_deref(), Some(b"v2".as_slice()));
+        assert_eq!(store.changes_since(0, 10).await?.unwrap().changes[0].value.as_deref(), Some(b"v2".as_slice()));
+
+        // a standby stores them with its own transforms
+        let mut standby = setup_store("transformed_reads_standby").await?;
+        standby.set_value_transforms(vec![ValueTransform::Base64]).await?;
+        standby.apply_changes(&snapshot, true).await?;
+        assert_eq!(standby.get("k").await?, "v2");
+        let plain = setup_store("transformed_reads_plain").await?;
+        plain.apply_changes(&snapshot, true).await?;
+        assert_eq!(plain.get("k").await?, "v2");
+        store.set("k", "v1").await?;
+
+        let _ = std::fs::remove_file("tmp_transformed_export.db");
+        assert_eq!(store.export_to_sqlite("sqlite://tmp_transformed_export.db", &Filter::default()).await?, 1);
+        let exported = KVLite::new("sqlite://tmp_transformed_export.db", "transformed_reads", false, false).await?;
+        assert_eq!(exported.get("k").await?, "v1");
+        exported.pool.close().await;
+
+        plain.set("k", "v1").await?;
+        assert!(store.diff("transformed_reads_plain").await?.is_empty());
+        plain.set("k", "v2").await?;
+        let rows = store.diff("transformed_reads_plain").await?;
+        assert_eq!(rows[0].get::<Option<Vec<u8>>, &str>("value"), Some(b"v1".to_vec()));
+
+        let _ = std::fs::remove_file("tmp_transformed_sync.db");
+        store.sync("sqlite://tmp_transformed_sync.db", SyncStrategy::Push).await?;
+        assert_eq!(store.sync("sqlite://tmp_transformed_sync.db", SyncStrategy::Push).await?, SyncReport::default());
+        let other = KVLite::new("sqlite://tmp_transformed_sync.db", "transformed_reads", false, false).await?;
+        assert_eq!(other.get("k").await?, "v1");
+        other.set("pulled", "back").await?;
+        store.sync("sqlite://tmp_transformed_sync.db", SyncStrategy::Pull).await?;
+        assert_eq!(store.get("pulled").await?, "back");
+        other.pool.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_value_compression() -> Result<(), DsError> {
+        let mut store = setup_store("compressed").await?;
+        let large = format!("{{\"items\": [{}]}}", vec!["{\"name\": \"item\", \"count\": 1}"; 100].join(", "));
+        store.set("large", &large).await?;
+        assert_eq!(store.set_value_transforms(vec![ValueTransform::Compress(Codec::Gzip, 1024)]).await?, 1);
+        store.set("small", "{\"count\": 1}").await?;
+        // looks compressed, so it's compressed anyway to be read back as written
+        store.set_bytes("magic", &[0x1f, 0x8b, 0]).await?;
+        assert_eq!(store.get("large").await?, large);
+        assert_eq!(store.get("small").await?, "{\"count\": 1}");
+        assert_eq!(store.get_bytes("magic").await?, [0x1f, 0x8b, 0]);
+
+        // only the large value is stored compressed
+        let mut conn = store.pool.acquire().await?;
+        let stored: Vec<(String, Vec<u8>)> = QueryBuilder::new("SELECT key, CAST(value AS BLOB) AS value FROM compressed ORDER BY key")
+            .build()
+            .fetch_all(&mut conn)
+            .await?
+            .iter()
+            .map(|row| (row.get("key"), row.get("value")))
+            .collect();
+        drop(conn);
+        assert!(stored[0].1.starts_with(&[0x1f, 0x8b]) && stored[0].1.len() < large.len());
+        assert_ne!(stored[1].1, [0x1f, 0x8b, 0]);
+        assert_eq!(stored[2].1, b"{\"count\": 1}");
+
+        // records compressed before keep reading after the size changes
+        assert_eq!(store.set_value_transforms(vec![ValueTransform::Compress(Codec::Gzip, 0)]).await?, 3);
+        assert_eq!(store.get("large").await?, large);
+        assert_eq!(store.get("small").await?, "{\"count\": 1}");
+
+        assert_eq!("gzip:1024".parse(), Ok(ValueTransform::Compress(Codec::Gzip, 1024)));
+        assert_eq!(ValueTransform::Compress(Codec::Gzip, 1024).to_string(), "gzip:1024");
+        assert!("gzip:lots".parse::<ValueTransform>().is_err());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hooks() -> Result<(), DsError> {
+        let out = std::env::temp_dir().join(format!("dsr-hook-{}", std::process::id()));
+        let _ = std::fs::remove_file(&out);
+        let mut store = setup_store("hooked").await?;
+        let filter = "prefix:nginx/ kind:set".parse().unwrap();
+        let command = format!(r#"printf '%s %s %s %s' "$DSR_STORE" "$DSR_KIND" "$DSR_KEY" "$DSR_VALUE" > {}"#, out.display());
+        let id = store.add_hook(&filter, &command).await?;
+        assert!(store.add_hook(&"changed".parse().unwrap(), "true").await.is_err());
+
+        // hooks are kept with the store
+        let store = KVLite::new("sqlite://tmp.db", "hooked", false, false).await?;
+        assert_eq!(store.hooks(), vec![Hook { id, filter, command }]);
+        store.set("other", "1").await?;
+        store.del("other").await?;
+        store.set("nginx/conf", "server {}").await?;
+        let mut written = String::new();
+        for _ in 0..100 {
+            written = std::fs::read_to_string(&out).unwrap_or_default();
+            if !written.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(written, "hooked set nginx/conf server {}");
+
+        let other = KVLite::new("sqlite://tmp.db", "hooked", false, false).await?;
+        let mut store = store;
+        assert!(store.remove_hook(id).await?);
+        assert!(!store.remove_hook(id).await?);
+        assert!(store.hooks().is_empty());
+        // other handles keep running it until they reload their hooks
+        assert_eq!(other.hooks().len(), 1);
+        other.reload_hooks().await?;
+        assert!(other.hooks().is_empty());
+        let _ = std::fs::remove_file(&out);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_saved_snapshots() -> Result<(), DsError> {
+        let store = setup_store("snapshotted").await?;
+        store.set("kept", "1").await?;
+        store.set("changed", "before").await?;
+        store.set_with_ttl("expired", "x", Duration::from_secs(0)).await?;
+        assert_eq!(store.save_snapshot("nightly").await?, 2);
+        assert!(store.save_snapshot("nightly").await.is_err());
+        assert!(!store.stores().await?.iter().any(|name| name.starts_with("snapshotted_")));
+
+        store.set("changed", "after").await?;
+        store.set("added", "2").await?;
+        store.del("kept").await?;
+        let diff: Vec<(String, String)> = store.diff_snapshot("nightly").await?.iter()
+            .map(|row| (row.get("change"), row.get("key")))
+            .collect();
+        assert_eq!(diff, vec![
+            ("+".to_string(), "added".to_string()),
+            ("~".to_string(), "changed".to_string()),
+            ("-".to_string(), "kept".to_string()),
+        ]);
+
+        let snapshots = store.saved_snapshots().await?;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!((snapshots[0].name.as_str(), snapshots[0].records), ("nightly", 2));
+
+        assert_eq!(store.restore_snapshot("nightly").await?, 2);
+        assert_eq!(store.get("changed").await?, "before");
+        assert_eq!(store.get("kept").await?, "1");
+        assert!(!store.contains("added").await?);
+        assert!(store.diff_snapshot("nightly").await?.is_empty());
+        assert!(matches!(store.restore_snapshot("weekly").await, Err(DsError::NotFound)));
+        assert_eq!(store.get("kept").await?, "1");
+
+        assert!(store.drop_snapshot("nightly").await?);
+        assert!(!store.drop_snapshot("nightly").await?);
+        assert!(store.saved_snapshots().await?.is_empty());
+
+        Ok(())
+    }
+
+    async fn check_backend(backend: &impl KvBackend) -> Result<(), DsError> {
+        backend.set_with("b", b"2", &SetOptions::default()).await?;
+        backend.set_with("a", b"1", &SetOptions::default()).await?;
+        backend.set_with("expired", b"3", &SetOptions { ttl: Some(Duration::from_secs(0)), ..Default::default() }).await?;
+        assert!(backend.set_with("int", b"one", &SetOptions { tag: Some(ValueTag::Int), ..Default::default() }).await.is_err());
+        assert_eq!(backend.get_bytes("a").await?, b"1");
+        assert!(matches!(backend.get_bytes("expired").await, Err(DsError::NotFound)));
+        assert!(backend.contains("b").await?);
+        assert_eq!(backend.list_keys(&Filter::default(), None).await?, vec!["a", "b"]);
+        assert_eq!(backend.list_keys(&Filter::default(), Some(1)).await?, vec!["a"]);
+        assert_eq!(backend.list_keys(&Filter { value_contains: Some("2".to_string()), ..Default::default() }, None).await?, vec!["b"]);
+        backend.del("a").await?;
+        backend.del("a").await?;
+        assert!(!backend.contains("a").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backends() -> Result<(), DsError> {
+        check_backend(&MemoryBackend::new()).await?;
+        check_backend(&setup_store("backend").await?).await?;
+        check_backend(&KVLiteOptions::memory().open("store").await?).await?;
+
+        // each in-memory datastore is its own, shared by the store's connections
+        let store = KVLiteOptions::new("memory:")?.open("store").await?;
+        assert!(store.keys(&ListOptions::default()).await?.is_empty());
+        store.set("key", "value").await?;
+        let mut tx = store.transaction().await?;
+        assert_eq!(tx.get("key").await?, "value");
+        tx.commit().await?;
+        assert_eq!(store.stores().await?, vec!["store"]);
+
+        Ok(())
+    }
+
+    /// Check a backend in a database other tests' runs may have left records in
+    #[cfg(any(feature = "postgres", feature = "mysql", feature = "libsql"))]
+    async fn check_remote_backend(backend: &impl KvBackend) -> Result<(), DsError> {
+        for key in ["a", "b", "expired", "app.name", "apple"] {
+            backend.del(key).await?;
+        }
+        check_backend(backend).await?;
+
+        backend.set_with("app.name", b"ds", &SetOptions::default()).await?;
+        backend.set_with("apple", b"\xff\x00", &SetOptions::default()).await?;
+        assert_eq!(backend.get_bytes("apple").await?, b"\xff\x00");
+        assert_eq!(backend.list_keys(&Filter { prefix: Some("app.".to_string()), ..Default::default() }, None).await?, vec!["app.name"]);
+        assert_eq!(backend.list_keys(&Filter { glob: Some("app*".to_string()), ..Default::default() }, None).await?, vec!["app.name", "apple"]);
+        for key in ["b", "app.name", "apple"] {
+            backend.del(key).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(any(feature = "postgres", feature = "mysql"))]
+    #[tokio::test]
+    async fn test_remote_backends() -> Result<(), DsError> {
+        // the databases are only checked when there's one to reach, given by the URL in
+        // DSR_TEST_POSTGRES_URL or DSR_TEST_MYSQL_URL
+        #[cfg(feature = "postgres")]
+        {
+            use crate::PostgresBackend;
+            assert!(matches!(PostgresBackend::connect("postgres://127.0.0.1:1/dsr", "bad-name").await, Err(DsError::InvalidStoreName(_))));
+            if let Ok(url) = std::env::var("DSR_TEST_POSTGRES_URL") {
+                check_remote_backend(&PostgresBackend::connect(&url, "dsr_test_backend").await?).await?;
+            }
+        }
+        #[cfg(feature = "mysql")]
+        {
+            use crate::MySqlBackend;
+            assert!(matches!(MySqlBackend::connect("mysql://127.0.0.1:1/dsr", "bad-name").await, Err(DsError::InvalidStoreName(_))));
+            if let Ok(url) = std::env::var("DSR_TEST_MYSQL_URL") {
+                check_remote_backend(&MySqlBackend::connect(&url, "dsr_test_backend").await?).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "libsql")]
+    #[tokio::test]
+    async fn test_libsql_backend() -> Result<(), DsError> {
+        use crate::LibsqlBackend;
+
+        assert!(matches!(LibsqlBackend::connect("http://127.0.0.1:1", "bad-name", None).await, Err(DsError::InvalidStoreName(_))));
+        assert!(LibsqlBackend::connect("http://127.0.0.1:1", "store", None).await.is_err());
+        // a hosted database is only checked when there's one to reach, given by the URL, with
+        // its authToken, in DSR_TEST_LIBSQL_URL
+        if let Ok(url) = std::env::var("DSR_TEST_LIBSQL_URL") {
+            check_remote_backend(&LibsqlBackend::connect(&url, "dsr_test_backend", None).await?).await?;
+        }
+
+        // a stand-in for a libsql server, running each pipeline's statement on a SQLite database
+        #[cfg(feature = "server")]
+        {
+            use axum::{Json, Router, extract::State, http::{HeaderMap, StatusCode, header}, routing::post};
+            use base64::{Engine, engine::general_purpose::{STANDARD, STANDARD_NO_PAD}};
+            use serde_json::{Value, json};
+            use sqlx::{SqlitePool, TypeInfo, ValueRef};
+
+            async fn pipeline(State(pool): State<SqlitePool>, headers: HeaderMap, Json(body): Json<Value>) -> (StatusCode, Json<Value>) {
+                if headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok()) != Some("Bearer secret") {
+                    return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Unauthorized" })));
+                }
+                let stmt = &body["requests"][0]["stmt"];
+                let mut query = sqlx::query(stmt["sql"].as_str().unwrap());
+                for arg in stmt["args"].as_array().unwrap() {
+                    query = match arg["type"].as_str().unwrap() {
+                        "text" => query.bind(arg["value"].as_str().unwrap().to_string()),
+                        "integer" => query.bind(arg["value"].as_str().unwrap().parse::<i64>().unwrap()),
+                        "blob" => query.bind(STANDARD.decode(arg["base64"].as_str().unwrap()).unwrap()),
+                        _ => query.bind(None::<String>),
+                    };
+                }
+                let result = match query.fetch_all(&pool).await {
+                    Ok(rows) => {
+                        let rows: Vec<Value> = rows.iter()
+                            .map(|row| (0..row.len())
+                                .map(|i| {
+                                    let raw = row.try_get_raw(i).unwrap();
+                                    match raw.type_info().name() {
+                                        "TEXT" => json!({ "type": "text", "value": row.get::<String, usize>(i) }),
+                                        "INTEGER" => json!({ "type": "integer", "value": row.get::<i64, usize>(i).to_string() }),
+                                        // blobs without padding, as some servers send them
+                                        "BLOB" => json!({ "type": "blob", "base64": STANDARD_NO_PAD.encode(row.get::<Vec<u8>, usize>(i)) }),
+                                        _ => json!({ "type": "null" }),
+                                    }
+                                })
+                                .collect())
+                            .collect();
+                        json!({ "type": "ok", "response": { "type": "execute", "result": { "rows": rows } } })
+                    },
+                    Err(e) => json!({ "type": "error", "error": { "message": e.to_string() } }),
+                };
+                (StatusCode::OK, Json(json!({ "results": [result, { "type": "ok", "response": { "type": "close" } }] })))
+            }
+
+            let pool = SqlitePool::connect("sqlite::memory:").await?;
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let url = format!("http://{}", listener.local_addr()?);
+            tokio::spawn(async move { axum::serve(listener, Router::new().route("/v2/pipeline", post(pipeline)).with_state(pool)).await });
+
+            check_remote_backend(&LibsqlBackend::connect(&url, "libsql", Some("secret".to_string())).await?).await?;
+            // the token can come with the URL instead
+            let backend = LibsqlBackend::connect(&format!("{}/?authToken=secret", url), "libsql", None).await?;
+            backend.set_with("created", b"1", &SetOptions { content_type: Some("text/plain".to_string()), ..Default::default() }).await?;
+            assert_eq!(backend.get_bytes("created").await?, b"1");
+            assert!(matches!(backend.get_bytes("missing").await, Err(DsError::NotFound)));
+            assert!(LibsqlBackend::connect(&url, "libsql", Some("wrong".to_string())).await.is_err());
+            assert!(LibsqlBackend::connect(&url, "libsql", None).await.is_err());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_swap_stores() -> Result<(), DsError> {
+        let blue = setup_store("blue").await?;
+        let green = setup_store("green").await?;
+        blue.set_versioned(false).await?;
+        green.set_versioned(false).await?;
+        blue.set("app", "1").await?;
+        blue.set_value_type(ValueType::Integer).await?;
+        assert_eq!(blue.search("1", None).await?.len(), 1);
+        green.set_versioned(true).await?;
+        green.set("app", "two").await?;
+        green.set("new", "three").await?;
+
+        blue.swap_stores("blue", "green").await?;
+        assert_eq!(blue.get("app").await?, "two");
+        assert_eq!(blue.get("new").await?, "three");
+        assert_eq!(green.get("app").await?, "1");
+        assert!(!green.contains("new").await?);
+
+        // settings, history and constraints go with the records
+        assert!(green.set("app", "one").await.is_err());
+        assert_eq!(green.value_type().await?, ValueType::Integer);
+        assert!(blue.is_versioned().await?);
+        assert!(!green.is_versioned().await?);
+        blue.set("app", "four").await?;
+        assert_eq!(blue.history("app").await?.len(), 2);
+        blue.set_value_type(ValueType::Text).await?;
+        green.set_value_type(ValueType::Any).await?;
+        green.set("app", "one").await?;
+        assert_eq!(green.search("one", None).await?.len(), 1);
+        assert!(blue.search("1", None).await?.is_empty());
+
+        blue.swap_stores("green", "blue").await?;
+        assert_eq!(blue.get("app").await?, "one");
+        assert!(matches!(blue.swap_stores("blue", "missing").await, Err(DsError::NotFound)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_locks() -> Result<(), DsError> {
+        let store = setup_store("locks").await?;
+        store.release_lock("job", None).await?;
+        assert!(store.acquire_lock("job", "a", Duration::from_secs(60)).await?);
+        assert!(!store.acquire_lock("job", "b", Duration::from_secs(60)).await?);
+        assert_eq!(store.lock_holder("job").await?.map(|(holder, _)| holder).as_deref(), Some("a"));
+        // locks don't touch the records
+        assert!(!store.contains("job").await?);
+
+        // the holder renews its lease, and only it can release the lock when a holder is given
+        assert!(store.acquire_lock("job", "a", Duration::from_secs(120)).await?);
+        assert!(!store.release_lock("job", Some("b")).await?);
+        assert!(store.release_lock("job", Some("a")).await?);
+        assert!(!store.release_lock("job", None).await?);
+        assert_eq!(store.lock_holder("job").await?, None);
+
+        // a lease that ran out frees the lock
+        assert!(store.acquire_lock("job", "crashed", Duration::ZERO).await?);
+        assert_eq!(store.lock_holder("job").await?, None);
+        assert!(store.acquire_lock("job", "b", Duration::from_secs(60)).await?);
+        assert!(store.release_lock("job", None).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_grace() -> Result<(), DsError> {
+        let mut store = setup_store("grace").await?;
+        store.set_grace(Duration::ZERO).await?;
+        store.set_with_ttl("lease", "holder", Duration::ZERO).await?;
+        store.set_with_ttl("later", "holder", Duration::from_secs(600)).await?;
+        store.set("forever", "value").await?;
+        assert!(store.get("lease").await.is_err());
+
+        store.set_grace(Duration::from_secs(60)).await?;
+        let (value, meta) = store.get_with_meta("lease").await?;
+        assert_eq!(value, b"holder");
+        assert!(meta.expires_at.unwrap() <= std::time::SystemTime::now());
+        assert!(!store.contains("lease").await?);
+        assert_eq!(store.purge_expired().await?, 0);
+
+        let expiring: Vec<_> = store.expiring(Duration::from_secs(60)).await?.into_iter().map(|meta| meta.key).collect();
+        assert_eq!(expiring, vec!["lease"]);
+        assert_eq!(store.expiring(Duration::from_secs(3600)).await?.len(), 2);
+
+        // the grace period is kept with the store
+        let store = KVLite::new("sqlite://tmp.db", "grace", false, false).await?;
+        assert_eq!(store.grace(), Duration::from_secs(60));
+        assert_eq!(store.get("lease").await?, "holder");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_names() -> Result<(), DsError> {
+        for name in ["my store", "quote\"d", "select", "x; DROP TABLE names --"] {
+            let store = KVLite::new("sqlite://tmp.db", name, false, false).await?;
+            store.set_with_ttl("key", "value", Duration::from_secs(60)).await?;
+            store.purge_expired().await?;
+            assert_eq!(store.get("key").await?, "value");
+            assert!(store.stores().await?.contains(&name.to_string()));
+            let mut changes = Box::pin(store.watch("*", Duration::from_millis(10)).await?);
+            store.del("key").await?;
+            assert_eq!(changes.next().await.unwrap()?.kind, ChangeKind::Delete);
+
+            store.clone_store(&format!("{} copy", name), None).await?;
+            store.drop_store(&format!("{} copy", name)).await?;
+            store.drop_store(name).await?;
+        }
+
+        assert!(KVLite::new("sqlite://tmp.db", "", false, false).await.is_err());
+        assert!(KVLite::new("sqlite://tmp.db", "sqlite_master", false, false).await.is_err());
+
+        // the tables of another store aren't stores, opening one would alter it
+        let owner = setup_store("names_owner").await?;
+        owner.set("key", "value").await?;
+        for name in ["names_owner_changes", "names_owner_settings", "names_owner_snapshot_records"] {
+            assert!(matches!(KVLite::new("sqlite://tmp.db", name, false, false).await, Err(DsError::InvalidStoreName(_))));
+            assert!(matches!(owner.transfer(name, None, &Filter::default(), false).await, Err(DsError::InvalidStoreName(_))));
+            assert!(matches!(owner.clone_store(name, None).await, Err(DsError::InvalidStoreName(_))));
+        }
+        owner.set("other", "value").await?;
+        assert_eq!(owner.get("other").await?, "value");
+        let _ = std::fs::remove_file("tmp_names.db");
+        KVLite::new("sqlite://tmp_names.db", "names_owner", false, true).await?;
+        assert!(matches!(owner.transfer("names_owner_changes", Some("sqlite://tmp_names.db"), &Filter::default(), false).await, Err(DsError::InvalidStoreName(_))));
+        // only tables of stores that exist are refused
+        KVLite::new("sqlite://tmp.db", "names_nobody_changes", false, false).await?.drop_store("names_nobody_changes").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_value_type() -> Result<(), DsError> {
+        let store = setup_store("value_type").await?;
+        store.set_value_type(ValueType::Any).await?;
+        store.set("count", "1").await?;
+        store.set("name", "ds").await?;
+        assert!(store.set_value_type(ValueType::Integer).await.is_err());
+        assert_eq!(store.value_type().await?, ValueType::Any);
+
+        store.del("name").await?;
+        store.set_value_type(ValueType::Integer).await?;
+        assert_eq!(store.value_type().await?, ValueType::Integer);
+        assert!(store.set("name", "ds").await.is_err());
+        assert!(store.set("count", "1.5").await.is_err());
+        assert!(store.cas("count", Some("1"), "two").await.is_err());
+        assert_eq!(store.incr("count", 2).await?, 3);
+        assert!(store.import(&[("other".to_string(), "value".to_string())], false).await.is_err());
+        assert_eq!(store.get("count").await?, "3");
+
+        store.set_value_type(ValueType::Json).await?;
+        store.set("config", r#"{"a": 1}"#).await?;
+        assert!(store.set("config", "{").await.is_err());
+        assert!(store.set_bytes("config", b"{}").await.is_err());
+
+        store.set_value_type(ValueType::Text).await?;
+        store.set("name", "ds").await?;
+        assert!(store.set_bytes("bytes", b"\xff").await.is_err());
+        store.set_value_type(ValueType::Any).await?;
+        store.set_bytes("bytes", b"\xff").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refs() -> Result<(), DsError> {
+        let users = setup_store("refs_users").await?;
+        let emails = setup_store("refs_emails").await?;
+        let aliases = setup_store("refs_aliases").await?;
+        emails.set_value_type(ValueType::Reference(Some("refs_users".to_string()))).await?;
+        aliases.set_value_type(ValueType::Reference(Some("refs_emails".to_string()))).await?;
+        assert!(users.set_value_type(ValueType::Reference(Some("refs_missing".to_string()))).await.is_err());
+
+        users.set("alice", "1").await?;
+        users.set("bob", "2").await?;
+        emails.set("alice@example.com", "alice").await?;
+        emails.set("bob@example.com", "bob").await?;
+        emails.set("carol@example.com", "carol").await?;
+        aliases.set("al", "alice@example.com").await?;
+        assert_eq!(emails.dangling().await?, vec![("carol@example.com".to_string(), "carol".to_string())]);
+        assert!(users.dangling().await?.is_empty());
+
+        assert!(users.del_with("alice", OnReferenced::Restrict).await.is_err());
+        assert!(users.contains("alice").await?);
+        users.del_with("dave", OnReferenced::Restrict).await?;
+
+        let deleted = users.del_with("alice", OnReferenced::Cascade).await?;
+        assert_eq!(deleted, vec![
+            ("refs_emails".to_string(), "alice@example.com".to_string()),
+            ("refs_aliases".to_string(), "al".to_string()),
+        ]);
+        assert!(!users.contains("alice").await?);
+        assert!(!aliases.contains("al").await?);
+        assert!(emails.contains("bob@example.com").await?);
+
+        users.del_with("bob", OnReferenced::Ignore).await?;
+        assert_eq!(emails.dangling().await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tags() -> Result<(), DsError> {
+        let store = setup_store("tags").await?;
+        let typed = |tag| SetOptions { tag: Some(tag), ..Default::default() };
+
+        store.set_with("count", b"1", &typed(ValueTag::Int)).await?;
+        assert!(store.set_with("count", b"1.5", &typed(ValueTag::Int)).await.is_err());
+        assert!(store.set_with("count", b"+1", &typed(ValueTag::Int)).await.is_err());
+        assert_eq!(store.incr("count", 1).await?, 2);
+        assert_eq!(store.stat("count").await?.tag, Some(ValueTag::Int));
+
+        store.set_with("ratio", b"2", &typed(ValueTag::Float)).await?;
+        assert!(store.incr("ratio", 1).await.is_err());
+        assert!(store.set_with("ratio", b"inf", &typed(ValueTag::Float)).await.is_err());
+        assert!(store.set_with("flag", b"yes", &typed(ValueTag::Bool)).await.is_err());
+        store.set_with("flag", b"true", &typed(ValueTag::Bool)).await?;
+        assert!(store.cas_with("flag", Some("true"), b"1", &typed(ValueTag::Bool)).await.is_err());
+
+        store.set_with("config", br#"{"a": 1}"#, &typed(ValueTag::Json)).await?;
+        assert!(store.set_with("config", b"{", &typed(ValueTag::Json)).await.is_err());
+        assert!(store.get_with_meta("config").await?.1.is_json());
+
+        // setting without a type clears it
+        store.set("ratio", "text").await?;
+        assert_eq!(store.stat("ratio").await?.tag, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pointers() -> Result<(), DsError> {
+        let store = setup_store("pointers").await?;
+        let pointer = SetOptions { tag: Some(ValueTag::Pointer), ..Default::default() };
+        let path = std::env::current_dir()?.join("tmp_pointer.bin");
+        std::fs::write(&path, b"large content")?;
+        let missing = std::env::current_dir()?.join("tmp_pointer_missing.bin");
+
+        store.set_with("blob", path.to_string_lossy().as_bytes(), &pointer).await?;
+        store.set_with("url", format!("file://{}", path.display()).as_bytes(), &pointer).await?;
+        store.set_with("gone", missing.to_string_lossy().as_bytes(), &pointer).await?;
+        assert!(store.set_with("relative", b"tmp_pointer.bin", &pointer).await.is_err());
+        store.set("plain", "value").await?;
+
+        let (content, meta) = store.get_resolved("blob").await?;
+        assert_eq!(content, b"large content");
+        assert_eq!((meta.size, meta.tag), (13, None));
+        assert_eq!(store.get_resolved("url").await?.0, b"large content");
+        assert_eq!(store.get_resolved("plain").await?.0, b"value");
+        assert_eq!(store.get("blob").await?, path.to_string_lossy());
+        assert!(matches!(store.get_resolved("gone").await, Err(DsError::Io(_))));
+
+        assert_eq!(store.dangling_pointers().await?, vec![("gone".to_string(), missing.to_string_lossy().into_owned())]);
+        std::fs::remove_file(&path)?;
+        assert_eq!(store.dangling_pointers().await?.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit() -> Result<(), DsError> {
+        let store = setup_store("audit").await?;
+        let later = SystemTime::now() + Duration::from_secs(60);
+        store.set("before", "a").await?;
+        store.set_audited(true).await?;
+        store.prune_audit(later).await?;
+        assert!(store.is_audited().await?);
+
+        store.set("key", "a").await?;
+        store.set("key", "b").await?;
+        store.del("key").await?;
+        let log = store.audit_log(None, None, None).await?;
+        assert_eq!(log.iter().map(|entry| entry.kind).collect::<Vec<_>>(), vec![ChangeKind::Set, ChangeKind::Set, ChangeKind::Delete]);
+        assert_eq!(log[0].old_hash, None);
+        // sha-256 of "a", then of "b"
+        assert_eq!(log[1].old_hash.as_deref(), Some("ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"));
+        assert_eq!(log[2].old_hash.as_deref(), Some("3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d"));
+        assert!(log.iter().all(|entry| entry.pid == Some(std::process::id())));
+        assert!(log.windows(2).all(|entries| entries[0].seq < entries[1].seq));
+
+        store.set("other", "c").await?;
+        assert_eq!(store.audit_log(Some("key"), None, None).await?.len(), 3);
+        let latest = store.audit_log(None, None, Some(2)).await?;
+        assert_eq!(latest.iter().map(|entry| &entry.key[..]).collect::<Vec<_>>(), vec!["key", "other"]);
+        assert!(store.audit_log(None, Some(later), None).await?.is_empty());
+
+        store.set_audited(false).await?;
+        store.set("other", "d").await?;
+        assert!(!store.is_audited().await?);
+        assert_eq!(store.audit_log(None, None, None).await?.len(), 4);
+        assert_eq!(store.prune_audit(later).await?, 4);
+        assert!(store.audit_log(None, None, None).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_change_feed() -> Result<(), DsError> {
+        let store = setup_store("feed").await?;
+        store.set("before", "a").await?;
+        assert!(store.feed_since(0, 10).await?.is_empty());
+        store.set_change_feed(true).await?;
+        assert!(store.has_change_feed().await?);
+
+        store.set("key", "a").await?;
+        store.set("key", "b").await?;
+        store.rename("key", "renamed", false).await?;
+        store.del("renamed").await?;
+        let events = store.feed_since(0, 10).await?;
+        assert_eq!(events.iter().map(|event| (event.kind, &event.key[..], event.value.as_deref())).collect::<Vec<_>>(), vec![
+            (ChangeKind::Set, "key", Some(&b"a"[..])),
+            (ChangeKind::Set, "key", Some(&b"b"[..])),
+            (ChangeKind::Delete, "key", None),
+            (ChangeKind::Set, "renamed", Some(&b"b"[..])),
+            (ChangeKind::Delete, "renamed", None),
+        ]);
+        assert!(events.windows(2).all(|events| events[0].seq < events[1].seq));
+        assert_eq!(store.feed_since(events[2].seq, 1).await?, vec![events[3].clone()]);
+
+        let mut tail = Box::pin(store.tail_changes(events[3].seq, Duration::from_millis(50)).await?);
+        assert_eq!(tail.next().await.unwrap()?, events[4]);
+        store.set("after", "c").await?;
+        let event = tail.next().await.unwrap()?;
+        assert_eq!((event.seq, &event.key[..], event.value.as_deref()), (events[4].seq + 1, "after", Some(&b"c"[..])));
+
+        assert_eq!(store.prune_changes(events[4].seq).await?, 5);
+        store.set_change_feed(false).await?;
+        store.set("after", "d").await?;
+        assert!(!store.has_change_feed().await?);
+        assert_eq!(store.feed_since(0, 10).await?, vec![event]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify() -> Result<(), DsError> {
+        let store = setup_store("verify").await?;
+        store.set("before", "a").await?;
+        assert_eq!(store.verify(true).await?, Default::default());
+        store.set_versioned(true).await?;
+        store.set_checksummed(true).await?;
+        assert!(store.is_checksummed().await?);
+        store.set("key", "one").await?;
+        store.set("key", "two").await?;
+        store.set("other", "three").await?;
+        assert_eq!(store.verify(false).await?, Default::default());
+
+        // stands in for the values changing on disk after they were written
+        let mut conn = store.pool.acquire().await?;
+        for (key, checksum) in [("before", "bad"), ("key", "7692c3ad3540bb803c020b3aee66cd8887123234ea0c6e7143c0add73ff431ed")] {
+            QueryBuilder::new(format!("UPDATE {} SET checksum = ", store.kv_name.object("checksums")))
+                .push_bind(checksum)
+                .push(" WHERE key = ")
+                .push_bind(key)
+                .build()
+                .execute(&mut conn)
+                .await?;
+        }
+        drop(conn);
+        let verification = store.verify(false).await?;
+        assert_eq!(verification.corrupted.iter().map(|corrupted| &corrupted.key[..]).collect::<Vec<_>>(), vec!["before", "key"]);
+        assert!(!verification.is_ok());
+
+        // key's first version, "one", matches its checksum, and before has no history to restore from
+        let verification = store.verify(true).await?;
+        assert_eq!(verification.corrupted, vec![
+            Corrupted { key: "before".to_string(), repair: Some(Repair::Dropped) },
+            Corrupted { key: "key".to_string(), repair: Some(Repair::Restored(1)) },
+        ]);
+        assert!(verification.is_ok());
+        assert_eq!(store.get("key").await?, "one");
+        assert!(!store.contains("before").await?);
+        assert_eq!(store.verify(false).await?, Default::default());
+
+        store.set_checksummed(false).await?;
+        assert!(!store.is_checksummed().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_file() -> Result<(), DsError> {
+        let store = setup_store("stream_file").await?;
+        let dir = std::env::temp_dir();
+        let (input, output) = (dir.join(format!("dsr-in-{}", std::process::id())), dir.join(format!("dsr-out-{}", std::process::id())));
+        // more than a few chunks, and not UTF-8
+        let value: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8 | 0x80).collect();
+        std::fs::write(&input, &value)?;
+
+        store.set_from_file("big", &input, &SetOptions { content_type: Some("application/octet-stream".to_string()), ..Default::default() }).await?;
+        assert_eq!(store.get_bytes("big").await?, value);
+        assert_eq!(store.stat("big").await?.size, value.len() as u64);
+        let meta = store.get_to_file("big", &output).await?;
+        assert_eq!(meta.content_type.as_deref(), Some("application/octet-stream"));
+        assert_eq!(std::fs::read(&output)?, value);
+        assert!(matches!(store.get_to_file("missing", &output).await, Err(DsError::NotFound)));
+
+        // a max value size still sees the value's length, and can't be set while big is over it
+        store.del("big").await?;
+        store.set_max_value_size(Some(1000)).await?;
+        assert!(store.set_from_file("bigger", &input, &SetOptions::default()).await.is_err());
+        assert!(!store.contains("bigger").await?);
+        store.set_max_value_size(None).await?;
+
+        // a versioned store keeps the whole value in its history
+        store.set_versioned(true).await?;
+        std::fs::write(&input, "small")?;
+        store.set_from_file("big", &input, &SetOptions::default()).await?;
+        assert_eq!(store.get("big").await?, "small");
+        assert_eq!(store.history("big").await?.last().and_then(|version| version.value.clone()).as_deref(), Some(&b"small"[..]));
+
+        std::fs::remove_file(&input)?;
+        std::fs::remove_file(&output)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_value() -> Result<(), DsError> {
+        let store = setup_store("stream_value").await?;
+        let value: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let chunks = tokio_stream::iter(value.chunks(10_000).map(Ok::<&[u8], DsError>));
+        store.set_from_stream("big", value.len() as u64, chunks, &SetOptions { content_type: Some("text/plain".to_string()), ..Default::default() }).await?;
+        assert_eq!(store.get_bytes("big").await?, value);
+
+        let (meta, chunks) = store.stream_value("big").await?;
+        assert_eq!((meta.size, meta.content_type.as_deref()), (value.len() as u64, Some("text/plain")));
+        let read: Vec<Vec<u8>> = chunks.collect::<Result<_, DsError>>().await?;
+        assert!(read.len() > 1);
+        assert_eq!(read.concat(), value);
+        assert!(matches!(store.stream_value("missing").await, Err(DsError::NotFound)));
+
+        // a value of one chunk is read up front, so its connection is back in the pool before it's taken
+        store.set("small", "abc").await?;
+        let (_, chunks) = store.stream_value("small").await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.pool.num_idle() as u32, store.pool.size());
+        assert_eq!(chunks.collect::<Result<Vec<_>, DsError>>().await?, vec![b"abc".to_vec()]);
+
+        // a reader that stalls has its stream end in an error, and the transaction let go of meanwhile
+        let (_, mut chunks) = store.stream_value_within("big", Duration::from_millis(50)).await?;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(store.pool.num_idle() as u32, store.pool.size());
+        assert_eq!(chunks.next().await.transpose()?.map(|chunk| chunk.len()), Some(64 * 1024));
+        assert!(matches!(chunks.next().await, Some(Err(DsError::Io(e))) if e.kind() == std::io::ErrorKind::TimedOut));
+        assert!(chunks.next().await.is_none());
+
+        // a stream that doesn't add up to its length writes nothing
+        let short = tokio_stream::iter([Ok::<&[u8], DsError>(b"abc")]);
+        assert!(store.set_from_stream("short", 4, short, &SetOptions::default()).await.is_err());
+        assert!(!store.contains("short").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_grep() -> Result<(), DsError> {
+        let mut store = setup_store("grep").await?;
+        store.set("a", "first line\nerror: disk full").await?;
+        store.set("b", "all fine").await?;
+        store.set("errors", "none").await?;
+        store.set_bytes("c", &[0xff, b'E', b'R', b'R', b'O', b'R']).await?;
+
+        let grep = |pattern: &'static str, options: GrepOptions| {
+            let store = &store;
+            async move {
+                let matches: Vec<_> = store.grep(pattern, &options).await?.collect().await;
+                matches.into_iter().map(|record| record.map(|(key, _)| key)).collect::<Result<Vec<String>, DsError>>()
+            }
+        };
+        assert_eq!(grep("^error: .* full$", GrepOptions::default()).await?, Vec::<String>::new());
+        assert_eq!(grep("(?m)^error: .* full$", GrepOptions::default()).await?, vec!["a"]);
+        assert_eq!(grep("error", GrepOptions { ignore_case: true, ..Default::default() }).await?, vec!["a", "c"]);
+        assert_eq!(grep("error", GrepOptions { keys: true, ..Default::default() }).await?, vec!["a", "errors"]);
+        assert_eq!(grep("error", GrepOptions { ignore_case: true, filter: Filter { prefix: Some("c".to_string()), ..Default::default() }, ..Default::default() }).await?, vec!["c"]);
+        assert!(store.grep("(", &GrepOptions::default()).await.is_err());
+
+        // values are matched as they're read, not as they're stored
+        store.set_value_transforms(vec![ValueTransform::Gzip]).await?;
+        let matches: Vec<_> = store.grep("fine", &GrepOptions::default()).await?.collect().await;
+        assert_eq!(matches.into_iter().collect::<Result<Vec<_>, _>>()?, vec![("b".to_string(), b"all fine".to_vec())]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queue() -> Result<(), DsError> {
+        let store = setup_store("queue").await?;
+        // setup_store only clears the store's own table, so a failed run can leave values behind
+        for queue in ["jobs", "other"] {
+            while store.pop(queue).await?.is_some() {}
+        }
+        assert_eq!(store.pop("jobs").await?, None);
+        assert_eq!(store.push("jobs", "first").await?, 1);
+        assert_eq!(store.push("jobs", "second").await?, 2);
+        assert_eq!(store.push("other", "elsewhere").await?, 1);
+        assert_eq!(store.queue_len("jobs").await?, 2);
+        // queues aren't records
+        assert!(!store.contains("jobs").await?);
+
+        assert_eq!(store.pop("jobs").await?.as_deref(), Some("first"));
+        assert_eq!(store.pop("jobs").await?.as_deref(), Some("second"));
+        assert_eq!(store.pop("jobs").await?, None);
+        assert_eq!(store.pop_wait("jobs", Duration::from_millis(200)).await?, None);
+
+        // a value pushed while waiting is popped
+        let other = KVLite::new("sqlite://tmp.db", "queue", false, false).await?;
+        let pushing = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            other.push("jobs", "late").await
+        });
+        assert_eq!(store.pop_wait("jobs", Duration::from_secs(5)).await?.as_deref(), Some("late"));
+        pushing.await.unwrap()?;
+        assert_eq!(store.queue_len("other").await?, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_tags() -> Result<(), DsError> {
+        let store = setup_store("key_tags").await?;
+        for key in ["app/db", "app/cache", "user/1"] {
+            store.set(key, "1").await?;
+        }
+        assert_eq!(store.tag_key("app/db", &["backup", "critical"]).await?, 2);
+        assert_eq!(store.tag_key("app/db", &["backup"]).await?, 0);
+        assert_eq!(store.tag_key("user/1", &["backup"]).await?, 1);
+        assert!(matches!(store.tag_key("missing", &["backup"]).await, Err(DsError::NotFound)));
+        assert_eq!(store.key_tags("app/db").await?, vec!["backup", "critical"]);
+        assert!(store.key_tags("app/cache").await?.is_empty());
+
+        let tagged = |tag: &str| ListOptions { sort: true, filter: Filter { key_tag: Some(tag.to_string()), ..Default::default() }, ..Default::default() };
+        let keys = |rows: Vec<SqliteRow>| rows.iter().map(|row| row.get("key")).collect::<Vec<String>>();
+        assert_eq!(keys(store.keys(&tagged("backup")).await?), vec!["app/db", "user/1"]);
+        assert_eq!(store.count(&Filter { prefix: Some("app/".to_string()), key_tag: Some("backup".to_string()), ..Default::default() }).await?, 1);
+        assert!(store.keys(&tagged("none")).await?.is_empty());
+
+        assert_eq!(store.untag_key("app/db", &["backup", "other"]).await?, 1);
+        assert_eq!(keys(store.keys(&tagged("backup")).await?), vec!["user/1"]);
+
+        // a key set again after it's deleted starts without tags
+        store.del("user/1").await?;
+        store.set("user/1", "2").await?;
+        assert!(store.key_tags("user/1").await?.is_empty());
+        assert!(store.keys(&tagged("backup")).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter_updated_since_and_type() -> Result<(), DsError> {
+        let store = setup_store("filter_updated").await?;
+        store.set_with("count", b"1", &SetOptions { tag: Some(ValueTag::Int), ..Default::default() }).await?;
+        store.set("name", "one").await?;
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("UPDATE filter_updated SET updated_at = 1000 WHERE key = 'name'")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        let listed = |filter: Filter| {
+            let store = &store;
+            async move {
+                Ok::<Vec<String>, DsError>(store.keys(&ListOptions { sort: true, filter, ..Default::default() }).await?
+                    .iter()
+                    .map(|row| row.get("key"))
+                    .collect())
+            }
+        };
+        assert_eq!(listed(Filter { updated_since: Some(UNIX_EPOCH + Duration::from_secs(2000)), ..Default::default() }).await?, vec!["count"]);
+        assert_eq!(listed(Filter { updated_since: Some(UNIX_EPOCH), ..Default::default() }).await?, vec!["count", "name"]);
+        assert_eq!(listed(Filter { value_tag: Some(ValueTag::Int), ..Default::default() }).await?, vec!["count"]);
+        assert!(listed(Filter { value_tag: Some(ValueTag::Json), ..Default::default() }).await?.is_empty());
+        assert!(store.records_as_of(SystemTime::now(), &Filter { value_tag: Some(ValueTag::Int), ..Default::default() }).await.is_err());
+
+        assert_eq!(store.del_matching(&Filter { updated_before: Some(UNIX_EPOCH + Duration::from_secs(2000)), ..Default::default() }).await?, 1);
+        assert!(!store.contains("name").await?);
+        assert!(store.contains("count").await?);
+
+        // records from before times were kept go by when they were created, if that's known
+        store.set("created", "old").await?;
+        store.set("unknown", "old").await?;
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("UPDATE filter_updated SET updated_at = NULL, created_at = CASE key WHEN 'created' THEN 1000 WHEN 'count' THEN created_at END")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+        assert_eq!(store.del_matching(&Filter { updated_before: Some(UNIX_EPOCH + Duration::from_secs(2000)), ..Default::default() }).await?, 2);
+        assert_eq!(listed(Filter::default()).await?, vec!["count"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bench() -> Result<(), DsError> {
+        let store = setup_store("bench").await?;
+        store.set("kept", "1").await?;
+        let results = store.bench(&BenchOptions { ops: 50, concurrency: 4, value_size: 10 }).await?;
+        assert_eq!(results.iter().map(|result| result.op).collect::<Vec<_>>(), vec!["set", "get", "delete"]);
+        for result in &results {
+            assert_eq!(result.ops, 50);
+            assert!(result.p50 <= result.p90 && result.p90 <= result.p99 && result.p99 <= result.max);
+            assert!(result.ops_per_sec() > 0.0);
+        }
+        // only the records it wrote are gone
+        assert_eq!(store.count(&Filter::default()).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pin() -> Result<(), DsError> {
+        let store = setup_store("pin").await?;
+        store.set_with_ttl("lease", "a", Duration::from_secs(1)).await?;
+        store.pin("lease").await?;
+        assert!(store.stat("lease").await?.pinned);
+        assert_eq!(store.ttl("lease").await?, None);
+
+        store.set_with_ttl("lease", "b", Duration::from_secs(1)).await?;
+        assert_eq!(store.ttl("lease").await?, None);
+        assert!(!store.cas_with("lease", Some("a"), b"c", &SetOptions { ttl: Some(Duration::from_secs(1)), ..Default::default() }).await?);
+        assert!(store.cas_with("lease", Some("b"), b"c", &SetOptions { ttl: Some(Duration::from_secs(1)), ..Default::default() }).await?);
+        assert_eq!(store.ttl("lease").await?, None);
+
+        store.unpin("lease").await?;
+        assert!(!store.stat("lease").await?.pinned);
+        store.set_with_ttl("lease", "d", Duration::from_secs(60)).await?;
+        assert!(store.ttl("lease").await?.is_some());
+
+        assert!(matches!(store.pin("missing").await, Err(DsError::NotFound)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search() -> Result<(), DsError> {
+        let store = setup_store("search").await?;
+        QueryBuilder::new("DROP TABLE IF EXISTS search_search").build().execute(&store.pool).await?;
+        store.set("fox", "the quick brown fox").await?;
+        store.set("dog", "the lazy dog").await?;
+        let keys = |rows: Vec<SqliteRow>| rows.iter().map(|row| row.get::<String, &str>("key")).collect::<Vec<_>>();
+
+        assert_eq!(keys(store.search("quick", None).await?), vec!["fox"]);
+        assert_eq!(keys(store.search("the", Some(1)).await?).len(), 1);
+
+        // the index follows writes made after it was built
+        store.set("cat", "a quick cat").await?;
+        store.set("fox", "the slow brown fox").await?;
+        store.del("dog").await?;
+        assert_eq!(keys(store.search("quick", None).await?), vec!["cat"]);
+        assert!(store.search("lazy", None).await?.is_empty());
+        store.rename_prefix("c", "k").await?;
+        assert_eq!(keys(store.search("cat", None).await?), vec!["kat"]);
+
+        store.set_with_ttl("gone", "quick", Duration::from_secs(0)).await?;
+        assert_eq!(keys(store.search("quick", None).await?), vec!["kat"]);
+
+        assert!(store.search("\"unbalanced", None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream() -> Result<(), DsError> {
+        let store = setup_store("stream").await?;
+        let records: Vec<(String, String)> = (0..1000).map(|i| (format!("key{:04}", i), format!("value{}", i))).collect();
+        store.import(&records, false).await?;
+
+        let options = ListOptions { sort: true, ..Default::default() };
+        let streamed: Vec<(String, String)> = store.stream_records(&options).await?
+            .map(|row| row.map(|row| (row.get("key"), row.get("value"))))
+            .collect::<Result<_, _>>()
+            .await?;
+        assert_eq!(streamed, records);
+
+        let options = ListOptions { reverse: true, limit: Some(2), filter: Filter { prefix: Some("key00".to_string()), ..Default::default() }, ..Default::default() };
+        let keys: Vec<String> = store.stream_keys(&options).await?
+            .map(|row| row.map(|row| row.get("key")))
+            .collect::<Result<_, _>>()
+            .await?;
+        assert_eq!(keys, vec!["key0099", "key0098"]);
+
+        // dropping a stream part way through frees its connection
+        let mut values = store.stream_values(&ListOptions::default()).await?;
+        assert!(values.next().await.is_some());
+        drop(values);
+        assert_eq!(store.keys(&ListOptions::default()).await?.len(), 1000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_as() -> Result<(), DsError> {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Config {
+            name: String,
+            retries: u32,
+            tags: Vec<String>,
+        }
+
+        let store = setup_store("set_as").await?;
+        store.set_encoding(Encoding::Json).await?;
+        let config = Config { name: "ds".to_string(), retries: 3, tags: vec!["a".to_string()] };
+        store.set_as("config", &config).await?;
+        assert_eq!(store.get("config").await?, r#"{"name":"ds","retries":3,"tags":["a"]}"#);
+        assert_eq!(store.get_as::<Config>("config").await?, config);
+        assert!(store.get_as::<u32>("config").await.is_err());
+
+        let encodings: &[Encoding] = &[
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack,
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor,
+        ];
+        for &encoding in encodings {
+            store.set_as_with("binary", &config, encoding).await?;
+            assert_eq!(store.stat("binary").await?.content_type.as_deref(), Some(encoding.content_type()));
+            assert_eq!(store.get_as::<Config>("binary").await?, config, "{}", encoding);
+
+            // records without a content type are read in the store's encoding
+            store.set_encoding(encoding).await?;
+            assert_eq!(store.encoding().await?, encoding);
+            store.set_bytes("plain", &store.get_bytes("binary").await?).await?;
+            assert_eq!(store.get_as::<Config>("plain").await?, config, "{}", encoding);
+            store.set_encoding(Encoding::Json).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stat() -> Result<(), DsError> {
+        let store = setup_store("stat").await?;
+        store.set("key", "välue").await?;
+
+        let meta = store.stat("key").await?;
+        assert_eq!(meta, RecordMeta { key: "key".to_string(), size: 6, expires_at: None, content_type: None, created_at: meta.created_at, updated_at: meta.updated_at, tag: None, pinned: false, source: None });
+        assert!(matches!(store.stat("not in store").await, Err(DsError::NotFound)));
+
+        let options = SetOptions { content_type: Some("application/json; charset=utf-8".to_string()), ..Default::default() };
+        store.set_with("json", br#"{"a": 1}"#, &options).await?;
+        let (value, meta) = store.get_with_meta("json").await?;
+        assert_eq!(value, br#"{"a": 1}"#);
+        assert!(meta.is_json() && !meta.is_binary());
+        store.set("json", "plain").await?;
+        assert_eq!(store.stat("json").await?.content_type, None);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_content_type() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::{Body, to_bytes}, http::{Request, StatusCode, header}};
+        use tower::ServiceExt;
+
+        let store = Arc::new(setup_store("server_content_type").await?);
+        let send = |request: Request<Body>| {
+            let router = crate::server::router(store.clone(), Default::default());
+            async move { router.oneshot(request).await.unwrap() }
+        };
+        let put = |key: &str, content_type: Option<&str>, body: &'static str| {
+            let mut request = Request::builder().method("PUT").uri(format!("/keys/{}", key));
+            if let Some(content_type) = content_type {
+                request = request.header(header::CONTENT_TYPE, content_type);
+            }
+            request.body(Body::from(body)).unwrap()
+        };
+        let get = |key: &str| Request::builder().uri(format!("/keys/{}", key)).body(Body::empty()).unwrap();
+
+        assert_eq!(send(put("doc", Some("application/json"), r#"{"a":1}"#)).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(store.stat("doc").await?.content_type.as_deref(), Some("application/json"));
+        let res = send(get("doc")).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await.unwrap(), r#"{"a":1}"#);
+
+        // a value put again without a type loses the one it had
+        assert_eq!(send(put("doc", None, "plain")).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(store.stat("doc").await?.content_type, None);
+        assert!(send(get("doc")).await.headers().get(header::CONTENT_TYPE).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lazy_values() -> Result<(), DsError> {
+        let store = setup_store("lazy").await?;
+        store.set("small", "a").await?;
+        store.set_bytes("large", &[7; 4096]).await?;
+
+        let record = store.get_meta("large").await?;
+        assert_eq!((record.meta.key.as_str(), record.meta.size), ("large", 4096));
+        assert_eq!(record.load_value().await?, vec![7; 4096]);
+        assert!(matches!(store.get_meta("missing").await, Err(DsError::NotFound)));
+
+        let records = store.list_meta(&ListOptions { sort: true, ..Default::default() }).await?;
+        let sizes: Vec<(&str, u64)> = records.iter().map(|record| (record.meta.key.as_str(), record.meta.size)).collect();
+        assert_eq!(sizes, vec![("large", 4096), ("small", 1)]);
+        // values are read when they're loaded, not when they're listed
+        store.set("small", "bc").await?;
+        assert_eq!(records[1].load_value().await?, b"bc");
+        store.del("small").await?;
+        assert!(matches!(records[1].load_value().await, Err(DsError::NotFound)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timestamps() -> Result<(), DsError> {
+        let store = setup_store("timestamps").await?;
+        store.set("key", "value").await?;
+        let meta = store.stat("key").await?;
+        assert!(meta.created_at.is_some() && meta.created_at == meta.updated_at);
+
+        // backdate the record so a later write is distinguishable
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("UPDATE timestamps SET created_at = 1, updated_at = 1").build().execute(&mut conn).await?;
+        drop(conn);
+        let created = Some(UNIX_EPOCH + Duration::from_secs(1));
+
+        store.set("key", "new").await?;
+        store.incr("count", 1).await?;
+        store.incr("count", 1).await?;
+        store.import(&[("key".to_string(), "imported".to_string())], false).await?;
+        let meta = store.stat("key").await?;
+        assert_eq!(meta.created_at, created);
+        assert!(meta.updated_at > created);
+        assert!(store.stat("count").await?.created_at.is_some());
+
+        // an expired record is created anew
+        store.set_with_ttl("key", "value", Duration::ZERO).await?;
+        store.set("key", "value").await?;
+        assert!(store.stat("key").await?.created_at > created);
+
+        let records = store.records(&ListOptions::default()).await?;
+        assert!(records.iter().all(|record| record.get::<Option<i64>, &str>("updated_at").is_some()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter() -> Result<(), DsError> {
+        let store = setup_store("filter").await?;
+        store.set("app.host", "localhost").await?;
+        store.set("app.port", "8080").await?;
+        store.set("app_name", "ds").await?;
+        store.set("db.host", "db.local").await?;
+
+        let keys = |rows: Vec<SqliteRow>| rows.iter().map(|row| row.get::<String, &str>("key")).collect::<Vec<_>>();
+        let filter = Filter { prefix: Some("app.".to_string()), ..Default::default() };
+        assert_eq!(keys(store.keys(&ListOptions { sort: true, filter, ..Default::default() }).await?), vec!["app.host", "app.port"]);
+
+        let filter = Filter { value_contains: Some("local".to_string()), ..Default::default() };
+        assert_eq!(keys(store.records(&ListOptions { sort: true, filter, ..Default::default() }).await?), vec!["app.host", "db.host"]);
+
+        let filter = Filter { prefix: Some("app".to_string()), value_contains: Some("80".to_string()), ..Default::default() };
+        assert_eq!(keys(store.records(&ListOptions { filter, ..Default::default() }).await?), vec!["app.port"]);
+
+        let filter = Filter { glob: Some("*.host".to_string()), ..Default::default() };
+        assert_eq!(keys(store.keys(&ListOptions { sort: true, filter, ..Default::default() }).await?), vec!["app.host", "db.host"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_del_matching() -> Result<(), DsError> {
+        let store = setup_store("del_matching").await?;
+        for key in ["tmp:1", "tmp:2", "tmp[3]", "keep", "tmp"] {
+            store.set(key, "value").await?;
+        }
+
+        assert_eq!(store.del_matching(&Filter { glob: Some("tmp[3]".to_string()), ..Default::default() }).await?, 1);
+        assert_eq!(store.del_matching(&Filter { glob: Some("tmp:*".to_string()), ..Default::default() }).await?, 2);
+        assert_eq!(store.del_matching(&Filter { prefix: Some("tm".to_string()), ..Default::default() }).await?, 1);
+        assert!(store.contains("keep").await?);
+
+        assert_eq!(store.clear().await?, 1);
+        assert!(store.keys(&ListOptions::default()).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_prefix() -> Result<(), DsError> {
+        let store = setup_store("rename_prefix").await?;
+        store.set("a/1", "one").await?;
+        store.set("a/2", "two").await?;
+        store.set("a/a/3", "three").await?;
+        store.set("b/2", "other").await?;
+
+        assert_eq!(store.rename_prefix("a/", "b/").await?, PrefixRename { renamed: 0, conflicts: vec!["b/2".to_string()] });
+        assert_eq!(store.get("a/2").await?, "two");
+
+        assert_eq!(store.rename_prefix("a/", "a/a/").await?.renamed, 3);
+        let keys: Vec<String> = store.keys(&ListOptions { sort: true, ..Default::default() }).await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+        assert_eq!(keys, vec!["a/a/1", "a/a/2", "a/a/a/3", "b/2"]);
+        assert_eq!(store.get("a/a/a/3").await?, "three");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_copy() -> Result<(), DsError> {
+        let store = setup_store("rename_copy").await?;
+        store.set_with("a", b"one", &SetOptions { ttl: Some(Duration::from_secs(3600)), content_type: Some("text/plain".to_string()), ..Default::default() }).await?;
+        store.set("b", "two").await?;
+        store.set_with_ttl("gone", "expired", Duration::ZERO).await?;
+
+        assert!(!store.rename("a", "b", false).await?);
+        assert!(!store.copy("a", "b", false).await?);
+        assert_eq!(store.get("b").await?, "two");
+        assert!(matches!(store.rename("missing", "c", true).await, Err(DsError::NotFound)));
+
+        assert!(store.copy("a", "gone", false).await?);
+        assert_eq!(store.get("gone").await?, "one");
+        assert!(store.rename("a", "b", true).await?);
+        assert!(!store.contains("a").await?);
+        let meta = store.stat("b").await?;
+        assert_eq!(meta.content_type.as_deref(), Some("text/plain"));
+        assert!(meta.expires_at.is_some());
+
+        assert!(store.copy("b", "c", false).await?);
+        assert!(store.copy("c", "c", false).await?);
+        assert!(store.rename("c", "c", false).await?);
+        assert_eq!(store.get("b").await?, "one");
+        assert_eq!(store.get("c").await?, "one");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stores() -> Result<(), DsError> {
+        let _ = std::fs::remove_file("tmp_stores.db");
+        let store = KVLite::new("sqlite://tmp_stores.db", "store", false, true).await?;
+        KVLite::new("sqlite://tmp_stores.db", "other", false, true).await?;
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("CREATE TABLE not_a_store (id INTEGER)")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        assert_eq!(store.stores().await?, vec!["other", "store"]);
+
+        store.drop_store("other").await?;
+        assert_eq!(store.stores().await?, vec!["store"]);
+        assert!(store.drop_store("not_a_store").await.is_err());
+
+        store.set("user:1", "ab").await?;
+        store.set("user:2", "cde").await?;
+        store.set("job", "f").await?;
+        store.set_with_ttl("user:3", "expired", Duration::ZERO).await?;
+        assert_eq!(store.count(&Filter::default()).await?, 3);
+        assert_eq!(store.count(&Filter { prefix: Some("user:".to_string()), ..Default::default() }).await?, 2);
+
+        KVLite::new("sqlite://tmp_stores.db", "empty", false, true).await?;
+        let stats = store.datastore_stats().await?;
+        assert!(stats.file_size > 0);
+        assert_eq!(stats.stores, vec![
+            StoreStats { store: "empty".to_string(), records: 0, key_bytes: 0, value_bytes: 0, blob_bytes: 0, dedup_saved_bytes: 0 },
+            StoreStats { store: "store".to_string(), records: 3, key_bytes: 15, value_bytes: 6, blob_bytes: 0, dedup_saved_bytes: 0 },
+        ]);
+        assert_eq!((stats.records(), stats.key_bytes(), stats.value_bytes()), (3, 15, 6));
+        // read without opening a store, so none is created
+        let options = KVLiteOptions::from(KVLite::options("sqlite://tmp_stores.db", true, false)?);
+        assert_eq!(options.datastore_stats().await?, stats);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ttl() -> Result<(), DsError> {
+        let store = setup_store("ttl").await?;
+        store.set("forever", "value").await?;
+        store.set_with_ttl("later", "value", Duration::from_secs(3600)).await?;
+        store.set_with_ttl("expired", "value", Duration::ZERO).await?;
+
+        assert_eq!(store.ttl("forever").await?, None);
+        assert!(store.ttl("later").await?.unwrap() > Duration::from_secs(3500));
+        assert!(store.get("expired").await.is_err());
+        assert!(!store.contains("expired").await?);
+        assert!(store.ttl("expired").await.is_err());
+        assert_eq!(store.keys(&ListOptions::default()).await?.len(), 2);
+
+        assert_eq!(store.purge_expired().await?, 1);
+        assert_eq!(store.purge_expired().await?, 0);
+
+        let mut changes = Box::pin(store.subscribe("gone"));
+        store.set_with_ttl("gone", "value", Duration::ZERO).await?;
+        assert_eq!(store.purge_expired_keys().await?, vec!["gone"]);
+        assert_eq!(changes.next().await.map(|change| change.kind), Some(ChangeKind::Set));
+        assert_eq!(changes.next().await, Some(ChangeEvent { kind: ChangeKind::Expired, key: "gone".to_string(), value: None }));
+        assert!(store.purge_expired_keys().await?.is_empty());
+
+        store.set("later", "value").await?;
+        assert_eq!(store.ttl("later").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_store_table() -> Result<(), DsError> {
+        let mut conn = setup_store("upgrade").await?.pool.acquire().await?;
+        // as a store made before versions were recorded
+        QueryBuilder::new("DROP TABLE upgrade; CREATE TABLE upgrade (key TEXT PRIMARY KEY, value TEXT); INSERT INTO upgrade VALUES ('key', 'value'); DELETE FROM _ds_meta WHERE store='upgrade'")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+
+        let store = KVLite::new("sqlite://tmp.db", "upgrade", false, false).await?;
+        assert_eq!(store.get("key").await?, "value");
+        store.set_with_ttl("key", "value", Duration::from_secs(60)).await?;
+        assert!(store.ttl("key").await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrate() -> Result<(), DsError> {
+        let store = setup_store("migrate").await?;
+        assert!(store.schema_versions().await?.contains(&SchemaVersion { store: "migrate".to_string(), version: SCHEMA_VERSION }));
+
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("DROP TABLE migrate_key_tags; DELETE FROM _ds_meta WHERE store='migrate'")
+            .build()
+            .execute(&mut conn)
+            .await?;
+        drop(conn);
+        assert!(store.schema_versions().await?.contains(&SchemaVersion { store: "migrate".to_string(), version: 0 }));
+
+        let store = KVLite::new("sqlite://tmp.db", "migrate", false, false).await?;
+        assert!(store.schema_versions().await?.contains(&SchemaVersion { store: "migrate".to_string(), version: SCHEMA_VERSION }));
+        store.set("key", "value").await?;
+        assert_eq!(store.tag_key("key", &["tag"]).await?, 1);
+
+        let mut conn = store.pool.acquire().await?;
+        store.set_schema_version(&mut conn, &Table::new("migrate"), SCHEMA_VERSION + 1).await?;
+        drop(conn);
+        assert!(matches!(KVLite::new("sqlite://tmp.db", "migrate", false, false).await,
+            Err(DsError::SchemaTooNew { store, version }) if store == "migrate" && version == SCHEMA_VERSION + 1));
+
+        store.drop_store("migrate").await?;
+        assert!(!store.schema_versions().await?.iter().any(|v| v.store == "migrate"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import() -> Result<(), DsError> {
+        let store = setup_store("import").await?;
+        store.set("kept", "value").await?;
+        store.set("key", "old").await?;
+
+        let records = vec![("key".to_string(), "new".to_string()), ("other".to_string(), "value".to_string())];
+        assert_eq!(store.import(&records, false).await?, 2);
+        assert_eq!(store.get("key").await?, "new");
+        assert!(store.contains("kept").await?);
+
+        store.import(&records[1..], true).await?;
+        assert_eq!(store.records(&ListOptions { sort: true, ..Default::default() }).await?.len(), 1);
+        assert!(!store.contains("kept").await?);
+
+        let csv: String = (0..2500).map(|i| format!("load{},{}\n", i, i)).collect();
+        assert_eq!(store.load_from(std::io::Cursor::new(csv), Format::Csv, false).await?, 2500);
+        assert_eq!(store.get("load2499").await?, "2499");
+        assert!(store.contains("other").await?);
+        // the connection is back to the default of FULL for whatever uses it next
+        let mut conn = store.pool.acquire().await?;
+        assert_eq!(sqlx::query("PRAGMA synchronous").fetch_one(&mut conn).await?.get::<i64, usize>(0), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_formats() {
+        let records = vec![
+            ("a".to_string(), "plain".to_string()),
+            ("b".to_string(), "with, \"quotes\"\nand $lines".to_string()),
+        ];
+        for format in [Format::Json, Format::Csv, Format::Env] {
+            let exported = format::export(&records, format).unwrap();
+            assert_eq!(format::import(&exported, format).unwrap(), records, "{}", format);
+        }
+
+        assert_eq!(format::import("# comment\nexport A=1\nB='two'\n", Format::Env).unwrap(),
+            vec![("A".to_string(), "1".to_string()), ("B".to_string(), "two".to_string())]);
+        assert!(format::export(&[("a b".to_string(), "c".to_string())], Format::Env).is_err());
+        assert_eq!(format::env_name("app.db-host"), "APP_DB_HOST");
+        assert_eq!(format::env_name("2fa/secret"), "_2FA_SECRET");
+        assert_eq!(format::env_name("ключ"), "____");
+        assert!(format::import("a,b,c", Format::Csv).is_err());
+        assert_eq!(format::export(&[], Format::Json).unwrap(), "{}\n");
+
+        #[cfg(feature = "msgpack")]
+        {
+            let mut writer = format::RecordWriter::new(vec![], Format::MessagePack).unwrap();
+            for (key, value) in &records {
+                writer.write(key, value).unwrap();
+            }
+            let exported = writer.finish().unwrap();
+            let mut imported = vec![];
+            format::read_records(exported.as_slice(), Format::MessagePack, |key, value| {
+                imported.push((key, value));
+                Ok(())
+            }).unwrap();
+            assert_eq!(imported, records);
+            assert!(format::read_records(&exported[..exported.len() - 1], Format::MessagePack, |_, _| Ok(())).is_err());
+        }
+
+        #[cfg(feature = "parquet")]
+        {
+            use parquet::file::reader::{FileReader, SerializedFileReader};
+
+            let mut writer = format::RecordWriter::new(vec![], Format::Parquet).unwrap();
+            writer.write_with_times("a", "plain", Some(10), None).unwrap();
+            writer.write("b", "two").unwrap();
+            std::fs::write("tmp_formats.parquet", writer.finish().unwrap()).unwrap();
+            let reader = SerializedFileReader::new(std::fs::File::open("tmp_formats.parquet").unwrap()).unwrap();
+            let rows: Vec<String> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap().to_string()).collect();
+            assert_eq!(rows, vec![
+                r#"{key: "a", value: "plain", created_at: 1970-01-01 00:00:10.000 +00:00, updated_at: null}"#,
+                r#"{key: "b", value: "two", created_at: null, updated_at: null}"#,
+            ]);
+            let _ = std::fs::remove_file("tmp_formats.parquet");
+            assert!(format::import("", Format::Parquet).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_append() -> Result<(), DsError> {
+        let store = setup_store("append").await?;
+        assert_eq!(store.strlen("log").await?, 0);
+        assert_eq!(store.append("log", "b").await?, 1);
+        assert_eq!(store.append("log", "c'\"").await?, 4);
+        assert_eq!(store.prepend("log", "a").await?, 5);
+        assert_eq!(store.get("log").await?, "abc'\"");
+        assert_eq!(store.strlen("log").await?, 5);
+
+        // bytes stay bytes, and a TTL is kept
+        store.set_bytes_with_ttl("bytes", &[0xff], Duration::from_secs(60)).await?;
+        assert_eq!(store.append("bytes", "a").await?, 2);
+        assert_eq!(store.get_bytes("bytes").await?, vec![0xff, b'a']);
+        assert!(store.ttl("bytes").await?.is_some());
+
+        // expired records start again, and typed ones can't be added to
+        store.set_with_ttl("expired", "old", Duration::ZERO).await?;
+        assert_eq!(store.append("expired", "new").await?, 3);
+        store.set_with("typed", b"1", &SetOptions { tag: Some(ValueTag::Int), ..Default::default() }).await?;
+        assert!(store.append("typed", "2").await.is_err());
+        assert_eq!(store.get("typed").await?, "1");
+
+        let mut changes = Box::pin(store.subscribe("log"));
+        store.append("log", "d").await?;
+        assert_eq!(changes.next().await.unwrap().value.as_deref(), Some("abc'\"d"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lists_and_sets() -> Result<(), DsError> {
+        let store = setup_store("collections").await?;
+        assert_eq!(store.rpush("queue", &["b", "c"]).await?, 2);
+        assert_eq!(store.lpush("queue", &["a", "z"]).await?, 4);
+        assert_eq!(store.lrange("queue", 0, -1).await?, vec!["z", "a", "b", "c"]);
+        assert_eq!(store.lrange("queue", 1, -2).await?, vec!["a", "b"]);
+        assert_eq!(store.lrange("queue", -100, 100).await?.len(), 4);
+        assert!(store.lrange("queue", 3, 1).await?.is_empty());
+        assert!(store.lrange("missing", 0, -1).await?.is_empty());
+        assert_eq!(store.stat("queue").await?.tag, Some(ValueTag::List));
+        assert_eq!(store.get("queue").await?, r#"["z","a","b","c"]"#);
+
+        // popping keeps the TTL, and the last pop deletes the list
+        store.set_with("queue", br#"["a","b"]"#, &SetOptions { ttl: Some(Duration::from_secs(60)), tag: Some(ValueTag::List), ..Default::default() }).await?;
+        assert_eq!(store.lpop("queue").await?.as_deref(), Some("a"));
+        assert!(store.ttl("queue").await?.is_some());
+        assert_eq!(store.lpop("queue").await?.as_deref(), Some("b"));
+        assert!(!store.contains("queue").await?);
+        assert_eq!(store.lpop("queue").await?, None);
+
+        assert_eq!(store.sadd("tags", &["b", "a", "b"]).await?, 2);
+        assert_eq!(store.sadd("tags", &["a", "c"]).await?, 1);
+        assert_eq!(store.smembers("tags").await?, vec!["a", "b", "c"]);
+        assert_eq!(store.srem("tags", &["a", "z"]).await?, 1);
+        assert_eq!(store.srem("tags", &["b", "c"]).await?, 2);
+        assert!(store.smembers("tags").await?.is_empty());
+        assert!(!store.contains("tags").await?);
+
+        // other records can't be used as lists or sets
+        store.set("plain", "text").await?;
+        assert!(store.rpush("plain", &["a"]).await.is_err());
+        assert!(store.sadd("plain", &["a"]).await.is_err());
+        store.sadd("set", &["a"]).await?;
+        assert!(store.lrange("set", 0, -1).await.is_err());
+        assert_eq!(store.get("plain").await?, "text");
+        assert!(store.set_with("dupes", br#"["a","a"]"#, &SetOptions { tag: Some(ValueTag::Set), ..Default::default() }).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incr() -> Result<(), DsError> {
+        let store = setup_store("incr").await?;
+        assert_eq!(store.incr("count", 1).await?, 1);
+        assert_eq!(store.incr("count", 5).await?, 6);
+        assert_eq!(store.incr("count", -8).await?, -2);
+        assert_eq!(store.get("count").await?, "-2");
+
+        store.set_with_ttl("expiring", "5", Duration::from_secs(3600)).await?;
+        assert_eq!(store.incr("expiring", 1).await?, 6);
+        assert!(store.ttl("expiring").await?.is_some());
+        store.set_with_ttl("expired", "5", Duration::ZERO).await?;
+        assert_eq!(store.incr("expired", 1).await?, 1);
+        assert_eq!(store.ttl("expired").await?, None);
+
+        store.set("text", "abc").await?;
+        assert!(store.incr("text", 1).await.is_err());
+        assert_eq!(store.get("text").await?, "abc");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multi() -> Result<(), DsError> {
+        let store = setup_store("multi").await?;
+        store.mset(&[("a", "1"), ("b", "2"), ("c", "3")]).await?;
+        assert_eq!(store.mget(&["c", "missing", "a"]).await?, vec![Some("3".to_string()), None, Some("1".to_string())]);
+
+        store.mdel(&["a", "c"]).await?;
+        assert_eq!(store.mget(&["a", "b", "c"]).await?, vec![None, Some("2".to_string()), None]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bytes() -> Result<(), DsError> {
+        let store = setup_store("bytes").await?;
+        let bytes = vec![0, 159, 146, 150, b'\n', 255];
+        store.set_bytes("binary", &bytes).await?;
+        store.set("text", "value").await?;
+
+        assert_eq!(store.get_bytes("binary").await?, bytes);
+        assert_eq!(store.get_bytes("text").await?, b"value");
+        assert!(store.get("binary").await.is_err());
+        assert_eq!(store.stat("binary").await?.size, 6);
+
+        store.set_bytes("utf8", "text".as_bytes()).await?;
+        assert_eq!(store.get("utf8").await?, "text");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_errors() -> Result<(), DsError> {
+        let store = setup_store("errors").await?;
+        assert!(matches!(store.get("missing").await, Err(DsError::NotFound)));
+        assert!(matches!(KVLite::new("sqlite://tmp.db", "sqlite_store", false, true).await, Err(DsError::InvalidStoreName(name)) if name == "sqlite_store"));
+
+        // a file of its own, so other tests' writes don't hold the lock
+        let store = KVLite::new("sqlite://tmp_errors.db", "errors", false, true).await?;
+        let options = KVLite::options("sqlite://tmp_errors.db", false, false)?.busy_timeout(Duration::from_millis(100));
+        let other = KVLite::connect_with(options, "errors", Collation::Binary).await?;
+        let mut conn = store.pool.acquire().await?;
+        QueryBuilder::new("BEGIN EXCLUSIVE").build().execute(&mut conn).await?;
+        assert!(matches!(other.set("key", "value").await, Err(DsError::StoreLocked)));
+        QueryBuilder::new("ROLLBACK").build().execute(&mut conn).await?;
+        other.set("key", "value").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_options() -> Result<(), DsError> {
+        use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+
+        let options = KVLiteOptions::new("sqlite://tmp_options.db")?
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(Duration::from_secs(10))
+            .cache_size(-4096)
+            .pool_size(4)
+            .collation(Collation::Nocase);
+        let store = options.clone().open("options").await?;
+        store.clear().await?;
+        let mut conn = store.pool.acquire().await?;
+        let pragmas = QueryBuilder::new("SELECT journal_mode, synchronous, cache_size FROM pragma_journal_mode(), pragma_synchronous(), pragma_cache_size()")
+            .build()
+            .fetch_one(&mut conn)
+            .await?;
+        assert_eq!(pragmas.get::<String, &str>("journal_mode"), "wal");
+        assert_eq!(pragmas.get::<i64, &str>("synchronous"), 1);
+        assert_eq!(pragmas.get::<i64, &str>("cache_size"), -4096);
+        drop(conn);
+
+        // writers from two handles wait for each other's locks instead of failing
+        let (store, other) = (std::sync::Arc::new(store), std::sync::Arc::new(options.open("options").await?));
+        let writes: Vec<_> = (0..20)
+            .map(|i| {
+                let store = if i % 2 == 0 { store.clone() } else { other.clone() };
+                tokio::spawn(async move { store.set(&i.to_string(), "value").await })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap()?;
+        }
+        assert_eq!(store.count(&Filter::default()).await?, 20);
+        assert!(store.set("A", "1").await.is_ok() && store.get("a").await? == "1");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backup() -> Result<(), DsError> {
+        let store = KVLite::new("sqlite://tmp_backup.db", "backup", false, true).await?;
+        store.clear().await?;
+        store.set("kept", "before").await?;
+        let other = KVLite::new("sqlite://tmp_backup.db", "other", false, true).await?;
+        other.set("other", "value").await?;
+
+        // a writer carrying on during the backup doesn't stop it
+        let writer = KVLite::new("sqlite://tmp_backup.db", "backup", false, false).await?;
+        let writes = tokio::spawn(async move {
+            for i in 0..50 {
+                writer.set(&format!("write{}", i), "value").await?;
+            }
+            Ok::<_, DsError>(())
+        });
+        store.backup("tmp_backup_copy.db", false).await?;
+        store.backup("tmp_backup_copy.db.gz", true).await?;
+        writes.await.unwrap()?;
+        assert_eq!(std::fs::read("tmp_backup_copy.db.gz")?[..2], [0x1f, 0x8b]);
+        assert!(!std::path::Path::new("tmp_backup_copy.db.partial").exists());
+
+        let copy = KVLite::new("sqlite://tmp_backup_copy.db", "backup", false, false).await?;
+        assert_eq!(copy.get("kept").await?, "before");
+        assert!(copy.integrity_check().await?.is_empty());
+
+        for backup in ["tmp_backup_copy.db", "tmp_backup_copy.db.gz"] {
+            store.set("kept", "after").await?;
+            store.set("extra", "value").await?;
+            other.del("other").await?;
+            store.restore(backup).await?;
+            assert_eq!(store.get("kept").await?, "before");
+            assert!(matches!(store.get("extra").await, Err(DsError::NotFound)));
+            assert_eq!(other.get("other").await?, "value");
+        }
+
+        assert!(store.restore("Cargo.toml").await.is_err());
+        assert_eq!(store.get("kept").await?, "before");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writers() -> Result<(), DsError> {
+        use std::sync::Arc;
+
+        // handles with pools of their own, as separate processes would have
+        let options = KVLiteOptions::new("sqlite://tmp_concurrent.db")?.busy_timeout(Duration::from_secs(30));
+        let first = Arc::new(options.clone().open("concurrent").await?);
+        first.clear().await?;
+        let handles = [first.clone(), Arc::new(options.clone().open("concurrent").await?), Arc::new(options.open("concurrent").await?)];
+
+        let writers: Vec<_> = (0..12)
+            .map(|i| {
+                let store = handles[i % handles.len()].clone();
+                tokio::spawn(async move {
+                    for j in 0..25 {
+                        store.incr("counter", 1).await?;
+                        store.mset(&[(&format!("{}/{}", i, j), "a"), (&format!("{}/{}/copy", i, j), "a")]).await?;
+                    }
+                    Ok::<_, DsError>(())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap()?;
+        }
+
+        // every increment is counted once, and both records of every mset are there
+        assert_eq!(first.get("counter").await?, "300");
+        assert_eq!(first.count(&Filter::default()).await?, 601);
+        assert!(first.integrity_check().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crash_recovery() -> Result<(), DsError> {
+        let options = KVLiteOptions::new("sqlite://tmp_crash.db")?.cache_size(1);
+        // run again as a child process, which is killed part way through a transaction
+        if std::env::var_os("DSR_CRASH_CHILD").is_some() {
+            let store = options.open("crash").await?;
+            let mut tx = store.transaction().await?;
+            for i in 0..2000 {
+                tx.set(&format!("uncommitted/{}", i), &"x".repeat(1024)).await?;
+            }
+            // exiting without dropping anything leaves the datastore as a killed process would
+            std::process::exit(1);
+        }
+
+        let store = options.clone().open("crash").await?;
+        store.clear().await?;
+        store.set("committed", "value").await?;
+        let status = std::process::Command::new(std::env::current_exe()?)
+            .args(["--exact", "tests::test_crash_recovery", "--test-threads=1"])
+            .env("DSR_CRASH_CHILD", "1")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()?;
+        assert!(!status.success());
+        // the crash left a hot journal, which the next connection rolls back
+        assert!(std::path::Path::new("tmp_crash.db-journal").exists());
+        drop(store);
+
+        let store = options.open("crash").await?;
+        assert!(store.integrity_check().await?.is_empty());
+        assert_eq!(store.get("committed").await?, "value");
+        assert_eq!(store.count(&Filter { prefix: Some("uncommitted/".to_string()), ..Default::default() }).await?, 0);
+        store.set("after", "value").await?;
+
+        Ok(())
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(32))]
+
+        /// Any key and value read back as they were written, through every way of writing them
+        #[test]
+        fn test_round_trip(records in proptest::collection::btree_map(".{1,40}", proptest::collection::vec(proptest::prelude::any::<u8>(), 0..200), 1..20)) {
+            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+                let store = KVLite::new("sqlite://tmp_round_trip.db", "round_trip", false, true).await.unwrap();
+                store.clear().await.unwrap();
+                for (key, value) in &records {
+                    store.set_with(key, value, &SetOptions::default()).await.unwrap();
+                }
+                for (key, value) in &records {
+                    assert_eq!(&store.get_bytes(key).await.unwrap(), value);
+                }
+                let keys: Vec<String> = store.keys(&ListOptions { sort: true, ..Default::default() }).await.unwrap()
+                    .iter()
+                    .map(|row| row.get("key"))
+                    .collect();
+                assert_eq!(keys, records.keys().cloned().collect::<Vec<_>>());
+
+                // and again in one transaction, after deleting them
+                let mut tx = store.transaction().await.unwrap();
+                for (key, value) in &records {
+                    tx.del(key).await.unwrap();
+                    assert!(!tx.contains(key).await.unwrap());
+                    tx.set_with(key, value, &SetOptions::default()).await.unwrap();
+                }
+                tx.commit().await.unwrap();
+                for (key, value) in &records {
+                    assert_eq!(&store.get_bytes(key).await.unwrap(), value);
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_only() -> Result<(), DsError> {
+        let store = KVLite::new("sqlite://tmp_read_only.db", "read_only", false, true).await?;
+        store.set("key", "value").await?;
+        assert!(matches!(KVLite::new("sqlite://tmp_read_only_missing.db", "read_only", false, false).await, Err(DsError::Sql(_))));
+
+        let read_only = KVLite::new("sqlite://tmp_read_only.db", "read_only", true, false).await?;
+        assert_eq!(read_only.get("key").await?, "value");
+        assert!(matches!(read_only.set("key", "other").await, Err(DsError::ReadOnly)));
+        assert!(matches!(read_only.del("key").await, Err(DsError::ReadOnly)));
+        assert!(matches!(KVLite::new("sqlite://tmp_read_only.db", "missing", true, false).await, Err(DsError::NotFound)));
+        assert_eq!(store.get("key").await?, "value");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replication() -> Result<(), DsError> {
+        let primary = setup_store("replica_primary").await?;
+        let standby = setup_store("replica_standby").await?;
+        primary.set("a", "1").await?;
+        primary.set_bytes("b", &[0, 255]).await?;
+        standby.set("stale", "x").await?;
+
+        standby.follow("http://primary").await?;
+        assert_eq!(standby.primary().await?.as_deref(), Some("http://primary"));
+        assert_eq!(standby.replica_seq().await?, None);
+        let snapshot = primary.replication_snapshot().await?;
+        standby.apply_changes(&snapshot, true).await?;
+        assert_eq!(standby.get("a").await?, "1");
+        assert_eq!(standby.get_bytes("b").await?, vec![0, 255]);
+        assert!(!standby.contains("stale").await?);
+        assert_eq!(standby.replica_seq().await?, Some(snapshot.seq));
+
+        primary.set_with_ttl("c", "3", Duration::from_secs(60)).await?;
+        primary.del("a").await?;
+        let changes = primary.changes_since(snapshot.seq, 10).await?.unwrap();
+        assert_eq!(changes.changes.len(), 2);
+        standby.apply_changes(&changes, false).await?;
+        assert!(!standby.contains("a").await?);
+        assert_eq!(standby.get("c").await?, "3");
+        assert!(standby.ttl("c").await?.is_some());
+        assert_eq!(primary.changes_since(changes.seq, 10).await?.unwrap().changes, vec![]);
+        assert_eq!(primary.changes_since(changes.seq + 1, 10).await?, None);
+
+        // sessions are of the primary's change log on both
+        let session = primary.session().await?;
+        assert_eq!(session, Session { generation: 0, seq: changes.seq });
+        assert_eq!(session.to_string().parse::<Session>(), Ok(session));
+        assert!("1".parse::<Session>().is_err());
+        assert_eq!(standby.session().await?, session);
+        primary.set("d", "4").await?;
+        assert!(!standby.caught_up(&primary.session().await?).await?);
+        assert!(standby.caught_up(&session).await?);
+
+        // a promoted standby is fenced off from its old primary
+        assert_eq!(standby.promote().await?, 1);
+        assert_eq!(standby.primary().await?, None);
+        assert!(standby.apply_changes(&primary.replication_snapshot().await?, true).await.is_err());
+        assert_eq!(standby.get("c").await?, "3");
+        // and what was seen of that primary is superseded
+        assert!(standby.caught_up(&primary.session().await?).await?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::{Body, to_bytes}, http::{Request, StatusCode}};
+        use tower::ServiceExt;
+
+        let store = Arc::new(setup_store("server").await?);
+        let request = |method: &str, uri: &str, body: &'static str| Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body))
+            .unwrap();
+        let send = |request: Request<Body>| {
+            let router = crate::server::router(store.clone(), Default::default());
+            async move {
+                let res = router.oneshot(request).await.unwrap();
+                (res.status(), to_bytes(res.into_body(), usize::MAX).await.unwrap())
+            }
+        };
+
+        assert_eq!(send(request("PUT", "/keys/app/name", "ds")).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(request("PUT", "/keys/other", "value")).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(request("GET", "/keys/app/name", "")).await, (StatusCode::OK, "ds".into()));
+        assert_eq!(send(request("GET", "/keys?prefix=app", "")).await, (StatusCode::OK, r#"["app/name"]"#.into()));
+        assert_eq!(send(request("DELETE", "/keys/app/name", "")).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(request("GET", "/keys/app/name", "")).await.0, StatusCode::NOT_FOUND);
+
+        // a large body of a known length is streamed in, and back out
+        let large = "x".repeat(100_000);
+        let put = Request::builder()
+            .method("PUT")
+            .uri("/keys/large")
+            .header("Content-Length", large.len())
+            .body(Body::from(large.clone()))
+            .unwrap();
+        assert_eq!(send(put).await.0, StatusCode::NO_CONTENT);
+        // written into a zeroblob rather than bound as text
+        let stored: String = sqlx::query("SELECT typeof(value) FROM server WHERE key = 'large'").fetch_one(&store.pool).await?.get(0);
+        assert_eq!(stored, "blob");
+        assert_eq!(send(request("GET", "/keys/large", "")).await, (StatusCode::OK, large.into()));
+
+        // a batch runs in one transaction, answering with each operation's result
+        let batch = |body: &'static str| Request::builder()
+            .method("POST")
+            .uri("/batch")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let (status, body) = send(batch(r#"[
+            {"op": "set", "key": "a", "value": "1"},
+            {"op": "get", "key": "a"},
+            {"op": "contains", "key": "missing"},
+            {"op": "delete", "key": "other"},
+            {"op": "get", "key": "other"}
+        ]"#)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!([
+            {}, {"value": "1"}, {"contains": false}, {}, {"value": null},
+        ]));
+        assert_eq!(store.get("a").await?, "1");
+        assert!(!store.contains("other").await?);
+        // one that fails writes nothing
+        store.del("large").await?;
+        store.set_value_type(ValueType::Integer).await?;
+        let (status, body) = send(batch(r#"[{"op": "set", "key": "b", "value": "2"}, {"op": "set", "key": "a", "value": "x"}]"#)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(String::from_utf8_lossy(&body).starts_with("operation 1:"));
+        assert!(!store.contains("b").await?);
+        store.set_value_type(ValueType::Any).await?;
+        assert_eq!(send(batch(r#"[{"op": "rename", "key": "a"}]"#)).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+
+        // a retried write with an idempotency key is answered again without being applied again,
+        // by the same server
+        let router = crate::server::router(store.clone(), Default::default());
+        let send = |request: Request<Body>| {
+            let router = router.clone();
+            async move {
+                let res = router.oneshot(request).await.unwrap();
+                (res.status(), to_bytes(res.into_body(), usize::MAX).await.unwrap())
+            }
+        };
+        let idempotent = |method: &str, uri: &str, key: &str, body: &'static str| Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Idempotency-Key", key)
+            .body(Body::from(body))
+            .unwrap();
+        assert_eq!(send(idempotent("PUT", "/keys/counter", "first", "1")).await.0, StatusCode::NO_CONTENT);
+        store.set("counter", "2").await?;
+        assert_eq!(send(idempotent("PUT", "/keys/counter", "first", "1")).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(store.get("counter").await?, "2");
+        assert_eq!(send(idempotent("DELETE", "/keys/counter", "first", "")).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(send(idempotent("DELETE", "/keys/counter", "second", "")).await.0, StatusCode::NO_CONTENT);
+        store.set("counter", "3").await?;
+        assert_eq!(send(idempotent("DELETE", "/keys/counter", "second", "")).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(store.get("counter").await?, "3");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use crate::grpc::Client;
+
+        let store = Arc::new(setup_store("grpc").await?);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(crate::grpc::serve_on(store.clone(), listener, Default::default()));
+        let client = Client::connect(&url, None).await?;
+
+        let mut changes = Box::pin(client.watch(&ChangeFilter::glob("app/*"), Duration::from_millis(10)).await?);
+        KvBackend::set_with(&client, "app/name", b"ds", &Default::default()).await?;
+        KvBackend::set_with(&client, "other", b"value", &Default::default()).await?;
+        assert_eq!(KvBackend::get_bytes(&client, "app/name").await?, b"ds");
+        assert_eq!(store.get("other").await?, "value");
+        assert!(matches!(KvBackend::get_bytes(&client, "missing").await, Err(DsError::NotFound)));
+        let options = SetOptions { tag: Some(ValueTag::Int), ..Default::default() };
+        assert!(KvBackend::set_with(&client, "count", b"many", &options).await.is_err());
+
+        let filter = Filter { prefix: Some("app/".into()), ..Default::default() };
+        assert_eq!(client.scan(&filter, None, true).await?, vec![("app/name".to_string(), b"ds".to_vec())]);
+        assert_eq!(KvBackend::list_keys(&client, &Default::default(), Some(1)).await?, vec!["app/name"]);
+        KvBackend::del(&client, "app/name").await?;
+        assert!(!KvBackend::contains(&client, "app/name").await?);
+
+        let change = changes.next().await.unwrap()?;
+        assert_eq!((change.kind, change.key.as_str()), (ChangeKind::Set, "app/name"));
+        assert_eq!(changes.next().await.unwrap()?.kind, ChangeKind::Delete);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_cache() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use crate::grpc::Client;
+
+        let store = Arc::new(setup_store("grpc_cache").await?);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(crate::grpc::serve_on(store.clone(), listener, Default::default()));
+        let path = std::env::temp_dir().join(format!("dsr-client-cache-{}.db", std::process::id()));
+        let client = Client::connect(&url, None).await?.cache_in(&path).await?;
+
+        store.set("app/name", "ds").await?;
+        assert_eq!(KvBackend::get_bytes(&client, "app/name").await?, b"ds");
+        // revalidated against the cache, which is what's answered while the store isn't written
+        assert_eq!(KvBackend::get_bytes(&client, "app/name").await?, b"ds");
+        store.set("app/name", "dsr").await?;
+        assert_eq!(KvBackend::get_bytes(&client, "app/name").await?, b"dsr");
+        store.del("app/name").await?;
+        assert!(matches!(KvBackend::get_bytes(&client, "app/name").await, Err(DsError::NotFound)));
+        store.set("app/name", "ds").await?;
+        assert_eq!(KvBackend::get_bytes(&client, "app/name").await?, b"ds");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc_queue() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use crate::grpc::Client;
+
+        let store = Arc::new(setup_store("grpc_queue").await?);
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let url = format!("http://{}", addr);
+        let queue = std::env::temp_dir().join(format!("dsr-client-queue-{}.db", std::process::id()));
+        let cache = std::env::temp_dir().join(format!("dsr-client-queue-cache-{}.db", std::process::id()));
+        let client = Client::connect_queued(&url, None, &queue).await?.cache_in(&cache).await?;
+
+        // nothing is listening, so writes are queued
+        KvBackend::set_with(&client, "app/a", b"1", &Default::default()).await?;
+        assert_eq!(client.queued().await?.len(), 1);
+        assert!(client.replay().await.is_err());
+
+        store.set("app/b", "old").await?;
+        store.set("app/c", "old").await?;
+        tokio::spawn(crate::grpc::serve_on(store.clone(), tokio::net::TcpListener::bind(addr).await?, Default::default()));
+        assert_eq!(KvBackend::get_bytes(&client, "app/c").await?, b"old");
+        // queued behind the first write, with what the client read as their base
+        KvBackend::set_with(&client, "app/c", b"ours", &Default::default()).await?;
+        KvBackend::del(&client, "app/b").await?;
+        store.set("app/c", "theirs").await?;
+        assert_eq!(client.queued().await?.len(), 3);
+
+        let replayed = client.replay().await?;
+        assert_eq!(replayed.applied, 2);
+        assert_eq!(replayed.conflicts.iter().map(|write| (write.key.as_str(), write.value.as_deref())).collect::<Vec<_>>(), vec![("app/c", Some(&b"ours"[..]))]);
+        assert!(replayed.rejected.is_empty());
+        assert_eq!(store.get("app/a").await?, "1");
+        assert!(!store.contains("app/b").await?);
+        assert_eq!(store.get("app/c").await?, "theirs");
+        assert!(client.queued().await?.is_empty());
+
+        // with nothing queued, writes go straight to the server
+        KvBackend::set_with(&client, "app/d", b"1", &Default::default()).await?;
+        assert_eq!(store.get("app/d").await?, "1");
+
+        std::fs::remove_file(&queue)?;
+        std::fs::remove_file(&cache)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_ui() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::{Body, to_bytes}, http::{Request, StatusCode}};
+        use tower::ServiceExt;
+        use crate::server::Options;
+
+        let store = Arc::new(setup_store("server_ui").await?);
+        store.set("fruit", "apple pie").await?;
+        store.set("veg", "carrot cake").await?;
+        store.del("veg").await?;
+        let send = |ui: bool, uri: &str| {
+            let router = crate::server::router(store.clone(), Options { ui, ..Default::default() });
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            async move {
+                let res = router.oneshot(request).await.unwrap();
+                (res.status(), serde_json::from_slice::<serde_json::Value>(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).ok())
+            }
+        };
+
+        assert_eq!(send(false, "/ui").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(true, "/ui").await.0, StatusCode::OK);
+        assert_eq!(send(true, "/search?q=apple").await, (StatusCode::OK, Some(serde_json::json!([{ "key": "fruit", "value": "apple pie" }]))));
+        assert_eq!(send(true, "/changes?limit=2").await, (StatusCode::OK, Some(serde_json::json!([
+            { "kind": "delete", "key": "veg", "value": null },
+            { "kind": "set", "key": "veg", "value": null },
+        ]))));
+        let (status, stats) = send(true, "/stats").await;
+        assert_eq!(status, StatusCode::OK);
+        // only the store served, not the others in the datastore
+        assert_eq!(stats.unwrap()["stores"], serde_json::json!([{ "store": "server_ui", "records": 1, "key_bytes": 5, "value_bytes": 9 }]));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_limits() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::{Body, to_bytes}, http::{Request, StatusCode}};
+        use tower::ServiceExt;
+        use crate::server::{Auth, Limits, Options, Token, Utf8Policy};
+
+        assert_eq!("replace".parse::<Utf8Policy>(), Ok(Utf8Policy::Replace));
+        assert!("strict".parse::<Utf8Policy>().is_err());
+
+        let store = Arc::new(setup_store("server_limits").await?);
+        let limits = Limits { max_body: 4, utf8: Utf8Policy::Reject, max_key: Some(8), key_patterns: vec!["app/*".to_string()] };
+        let auth = Auth { tokens: vec!["app:secret".parse::<Token>().unwrap()], ..Default::default() };
+        let router = crate::server::router(store.clone(), Options { auth, limits: limits.clone(), ..Default::default() });
+        let send = |uri: &str, body: &'static [u8]| {
+            let router = router.clone();
+            let request = Request::builder().method("PUT").uri(uri).header("Authorization", "Bearer secret").body(Body::from(body)).unwrap();
+            async move {
+                let res = router.oneshot(request).await.unwrap();
+                (res.status(), String::from_utf8(to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap())
+            }
+        };
+
+        assert_eq!(send("/keys/app/a", b"1234").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send("/keys/app/a", b"12345").await, (StatusCode::PAYLOAD_TOO_LARGE, "the request body is over the limit of 4 bytes".to_string()));
+        assert_eq!(send("/keys/app/longer", b"1").await, (StatusCode::BAD_REQUEST, "the key is 10 bytes, over the limit of 8".to_string()));
+        assert_eq!(send("/keys/other", b"1").await, (StatusCode::BAD_REQUEST, "the key doesn't match any of the allowed patterns app/*".to_string()));
+        assert_eq!(send("/keys/app/b", &[255]).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(store.get("app/a").await?, "1234");
+        assert!(!store.contains("app/longer").await? && !store.contains("app/b").await?);
+
+        // bodies over the limit are refused without a token in the way too
+        let router = crate::server::router(store.clone(), Options { limits: Limits { utf8: Utf8Policy::Replace, ..limits }, ..Default::default() });
+        let request = Request::builder().method("PUT").uri("/keys/app/b").body(Body::from(vec![b'a', 255])).unwrap();
+        assert_eq!(router.clone().oneshot(request).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(store.get("app/b").await?, "a\u{fffd}");
+        let request = Request::builder().method("PUT").uri("/keys/app/b").body(Body::from("12345")).unwrap();
+        assert_eq!(router.oneshot(request).await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_cors() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::Body, http::{Request, StatusCode, header}};
+        use tower::ServiceExt;
+        use crate::server::{Auth, Cors, Options};
+
+        let store = Arc::new(setup_store("server_cors").await?);
+        let cors = Cors {
+            origins: vec!["https://dashboard.example.com".parse().unwrap()],
+            methods: vec!["GET".parse().unwrap()],
+            headers: vec![header::AUTHORIZATION],
+        };
+        let auth = Auth { tokens: vec!["app:secret".parse().unwrap()], ..Default::default() };
+        let router = crate::server::router(store, Options { auth, cors: Some(cors), ..Default::default() });
+        let send = |request: Request<Body>| {
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap() }
+        };
+
+        // preflights are answered without a token
+        let res = send(Request::builder().method("OPTIONS").uri("/keys")
+            .header(header::ORIGIN, "https://dashboard.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://dashboard.example.com");
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "GET");
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS], "authorization");
+
+        let res = send(Request::builder().uri("/keys").header(header::ORIGIN, "https://dashboard.example.com").header(header::AUTHORIZATION, "Bearer secret").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://dashboard.example.com");
+        let res = send(Request::builder().uri("/keys").header(header::ORIGIN, "https://other.example.com").header(header::AUTHORIZATION, "Bearer secret").body(Body::empty()).unwrap()).await;
+        assert!(!res.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_tokens() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::{Body, to_bytes}, http::{Request, StatusCode}};
+        use tower::ServiceExt;
+        use crate::server::{Auth, Options, Token};
+
+        assert!("app".parse::<Token>().is_err());
+        assert!("app:secret:many".parse::<Token>().is_err());
+        assert_eq!("app:secret::5".parse::<Token>(), Ok(Token { name: "app".into(), secret: "secret".into(), max_requests: None, max_bytes: Some(5) }));
+
+        let store = Arc::new(setup_store("server_tokens").await?);
+        let auth = Auth {
+            tokens: vec!["app:secret:3".parse().unwrap(), "writer:other::4".parse().unwrap()],
+            admin_secret: Some("admin".to_string()),
+            ..Default::default()
+        };
+        let router = crate::server::router(store.clone(), Options { auth, ..Default::default() });
+        let send = |method: &str, uri: &str, token: &str, body: &'static str| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if !token.is_empty() {
+                request = request.header("Authorization", format!("Bearer {}", token));
+            }
+            let router = router.clone();
+            let request = request.body(Body::from(body)).unwrap();
+            async move {
+                let res = router.oneshot(request).await.unwrap();
+                (res.status(), to_bytes(res.into_body(), usize::MAX).await.unwrap())
+            }
+        };
+
+        assert_eq!(send("GET", "/keys", "", "").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send("GET", "/keys", "wrong", "").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send("PUT", "/keys/name", "app", "ds").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send("PUT", "/keys/name", "secret", "ds").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send("GET", "/keys/name", "secret", "").await, (StatusCode::OK, "ds".into()));
+        assert_eq!(send("GET", "/keys/name", "secret", "").await.0, StatusCode::OK);
+        assert_eq!(send("GET", "/keys/name", "secret", "").await.0, StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(send("PUT", "/keys/a", "other", "abc").await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send("PUT", "/keys/b", "other", "de").await.0, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send("PUT", "/keys/b", "other", "d").await.0, StatusCode::NO_CONTENT);
+
+        assert_eq!(send("GET", "/admin/usage", "secret", "").await.0, StatusCode::UNAUTHORIZED);
+        let (status, usage) = send("GET", "/admin/usage", "admin", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&usage).unwrap(), serde_json::json!({
+            "app": {"requests": 3, "bytes_written": 2, "max_requests": 3, "max_bytes": null},
+            "writer": {"requests": 2, "bytes_written": 4, "max_requests": null, "max_bytes": 4},
+        }));
+        // writes are recorded as coming from the token that made them
+        assert_eq!(store.stat("a").await?.source.as_deref(), Some("token:writer"));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_reload() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::Body, http::{Request, StatusCode}};
+        use tower::ServiceExt;
+        use crate::{Role, server::{Auth, Options}};
+
+        let tokens = std::env::temp_dir().join(format!("dsr-tokens-{}", std::process::id()));
+        std::fs::write(&tokens, "# clients\napp:secret\n").unwrap();
+        let store = Arc::new(setup_store("server_reload").await?);
+        let mut auth = Auth {
+            tokens: vec!["cli:fixed".parse().unwrap()],
+            token_file: Some(tokens.display().to_string()),
+            admin_secret: Some("admin".to_string()),
+            ..Default::default()
+        };
+        auth.load_files().unwrap();
+        let router = crate::server::router(store.clone(), Options { auth, ..Default::default() });
+        let send = |method: &str, uri: &str, token: &str| {
+            let request = Request::builder().method(method).uri(uri).header("Authorization", format!("Bearer {}", token)).body(Body::from("ds")).unwrap();
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(send("PUT", "/keys/name", "secret").await, StatusCode::NO_CONTENT);
+        assert_eq!(send("GET", "/keys/name", "new").await, StatusCode::UNAUTHORIZED);
+
+        // tokens, roles and hooks change once the server reloads, not before
+        std::fs::write(&tokens, "app:new\n").unwrap();
+        store.grant("token:app", Role::ReadOnly).await?;
+        store.grant("token:cli", Role::ReadWrite).await?;
+        KVLite::new("sqlite://tmp.db", "server_reload", false, false).await?.add_hook(&"kind:set".parse().unwrap(), "true").await?;
+        assert_eq!(send("GET", "/keys/name", "new").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(send("POST", "/admin/reload", "secret").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(send("POST", "/admin/reload", "admin").await, StatusCode::NO_CONTENT);
+        assert_eq!(send("GET", "/keys/name", "secret").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(send("GET", "/keys/name", "new").await, StatusCode::OK);
+        assert_eq!(send("PUT", "/keys/name", "new").await, StatusCode::FORBIDDEN);
+        assert_eq!(send("PUT", "/keys/name", "fixed").await, StatusCode::NO_CONTENT);
+        assert_eq!(store.hooks().len(), 1);
+
+        // a file that can't be read leaves the configuration as it was
+        std::fs::write(&tokens, "app\n").unwrap();
+        assert_eq!(send("POST", "/admin/reload", "admin").await, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(send("GET", "/keys/name", "new").await, StatusCode::OK);
+        let _ = std::fs::remove_file(&tokens);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_roles() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::Body, http::{Request, StatusCode}};
+        use base64::{Engine, engine::general_purpose::STANDARD};
+        use tower::ServiceExt;
+        use crate::{Role, server::{Auth, Options}};
+
+        let store = Arc::new(setup_store("server_roles").await?);
+        assert_eq!(store.acl().await?, vec![]);
+        store.grant("token:reader", Role::ReadWrite).await?;
+        store.grant("token:reader", "ro".parse().unwrap()).await?;
+        store.grant("token:writer", Role::ReadWrite).await?;
+        store.grant("user:writer", Role::ReadWrite).await?;
+        assert!(store.revoke("user:writer").await?);
+        assert!(!store.revoke("user:writer").await?);
+        assert!(store.grant("writer", Role::ReadWrite).await.is_err());
+        assert!(store.grant("token:", Role::ReadWrite).await.is_err());
+        assert_eq!(store.acl().await?, vec![("token:reader".to_string(), Role::ReadOnly), ("token:writer".to_string(), Role::ReadWrite)]);
+
+        let htpasswd = format!("writer:{}\n", bcrypt::hash("w", 4).unwrap()).parse().unwrap();
+        let auth = Auth {
+            tokens: vec!["reader:r".parse().unwrap(), "writer:w".parse().unwrap(), "other:o".parse().unwrap()],
+            htpasswd: Some(htpasswd),
+            roles: crate::server::acl_roles(&store).await?,
+            ..Default::default()
+        };
+        let router = crate::server::router(store.clone(), Options { auth, ..Default::default() });
+        let send = |method: &str, uri: &str, token: &str| {
+            let request = Request::builder().method(method).uri(uri).header("Authorization", format!("Bearer {}", token)).body(Body::from("ds")).unwrap();
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+
+        assert_eq!(send("PUT", "/keys/name", "w").await, StatusCode::NO_CONTENT);
+        assert_eq!(send("GET", "/keys/name", "r").await, StatusCode::OK);
+        assert_eq!(send("PUT", "/keys/name", "r").await, StatusCode::FORBIDDEN);
+        assert_eq!(send("DELETE", "/keys/name", "r").await, StatusCode::FORBIDDEN);
+        // a client the acl doesn't name can't do anything
+        assert_eq!(send("GET", "/keys/name", "o").await, StatusCode::FORBIDDEN);
+        assert_eq!(store.get("name").await?, "ds");
+        // nor can a user sharing a token's name
+        let request = Request::builder().uri("/keys/name").header("Authorization", format!("Basic {}", STANDARD.encode("writer:w"))).body(Body::empty()).unwrap();
+        assert_eq!(router.clone().oneshot(request).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        // a name granted before names said how the client is authenticated isn't served
+        sqlx::query("INSERT INTO server_roles_acl (name, role) VALUES ('legacy', 'read-write')").execute(&store.pool).await?;
+        assert!(crate::server::acl_roles(&store).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_auth_backends() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{Json, routing::get, body::Body, http::{Request, StatusCode, header}};
+        use base64::{Engine, engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}};
+        use tower::ServiceExt;
+        use crate::server::{Auth, Htpasswd, Jwt, Options};
+
+        assert!("carol:plain".parse::<Htpasswd>().is_err());
+        let htpasswd: Htpasswd = format!("# users\nalice:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\nbob:{}\n", bcrypt::hash("builder", 4).unwrap()).parse().unwrap();
+        assert!(htpasswd.verify("alice", "password"));
+        assert!(htpasswd.verify("bob", "builder"));
+        assert!(!htpasswd.verify("bob", "password"));
+        assert!(!htpasswd.verify("carol", "password"));
+
+        // an issuer serving an HMAC key, so tokens can be signed here
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let issuer = format!("http://{}", listener.local_addr()?);
+        let discovery = serde_json::json!({ "issuer": issuer, "jwks_uri": format!("{}/jwks", issuer) });
+        let keys = serde_json::json!({ "keys": [{ "kty": "oct", "kid": "key", "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(b"signing secret") }] });
+        let served = axum::Router::new()
+            .route("/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+            .route("/jwks", get(move || async move { Json(keys) }));
+        tokio::spawn(async move { axum::serve(listener, served).await });
+        let sign = |audience: &str| {
+            let header = jsonwebtoken::Header { kid: Some("key".to_string()), ..Default::default() };
+            let claims = serde_json::json!({ "sub": "erin", "iss": issuer, "aud": audience, "exp": super::now() + 60 });
+            jsonwebtoken::encode(&header, &claims, &jsonwebtoken::EncodingKey::from_secret(b"signing secret")).unwrap()
+        };
+
+        let store = Arc::new(setup_store("server_auth_backends").await?);
+        let auth = Auth { htpasswd: Some(htpasswd), jwt: Some(Jwt::new(&issuer, "dsr")), admin_secret: Some("admin".to_string()), ..Default::default() };
+        let router = crate::server::router(store, Options { auth, ..Default::default() });
+        let send = |authorization: String| {
+            let router = router.clone();
+            let request = Request::builder().uri("/keys").header(header::AUTHORIZATION, authorization).body(Body::empty()).unwrap();
+            async move { router.oneshot(request).await.unwrap() }
+        };
+
+        assert_eq!(send(format!("Basic {}", STANDARD.encode("alice:password"))).await.status(), StatusCode::OK);
+        assert_eq!(send(format!("Basic {}", STANDARD.encode("bob:builder"))).await.status(), StatusCode::OK);
+        let res = send(format!("Basic {}", STANDARD.encode("alice:wrong"))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], r#"Basic realm="dsr""#);
+
+        assert_eq!(send(format!("Bearer {}", sign("dsr"))).await.status(), StatusCode::OK);
+        assert_eq!(send(format!("Bearer {}", sign("other"))).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(send("Bearer garbage".to_string()).await.status(), StatusCode::UNAUTHORIZED);
+
+        let res = send("Bearer admin".to_string()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let request = Request::builder().uri("/admin/usage").header(header::AUTHORIZATION, "Bearer admin").body(Body::empty()).unwrap();
+        let usage = axum::body::to_bytes(router.oneshot(request).await.unwrap().into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&usage).unwrap(), serde_json::json!({
+            "alice": {"requests": 1, "bytes_written": 0, "max_requests": null, "max_bytes": null},
+            "bob": {"requests": 1, "bytes_written": 0, "max_requests": null, "max_bytes": null},
+            "erin": {"requests": 1, "bytes_written": 0, "max_requests": null, "max_bytes": null},
+        }));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_coalesce() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::Body, http::{Request, StatusCode}};
+        use tower::ServiceExt;
+        use crate::{server::{Coalesce, Options}, RecordWrite};
+
+        let store = Arc::new(setup_store("server_coalesce").await?);
+        let bad = [
+            RecordWrite::Set { key: "a".into(), value: b"1".to_vec(), options: Default::default() },
+            RecordWrite::Set { key: "b".into(), value: b"x".to_vec(), options: SetOptions { tag: Some(ValueTag::Int), ..Default::default() } },
+        ];
+        assert!(store.write_all(&bad).await.is_err());
+        assert!(!store.contains("a").await?);
+
+        let coalescing = Coalesce { max_delay: Duration::from_millis(50), max_writes: 4 };
+        let router = crate::server::router(store.clone(), Options { coalescing: Some(coalescing), ..Default::default() });
+        let requests: Vec<_> = (0..10)
+            .map(|i| {
+                let request = Request::builder()
+                    .method("PUT")
+                    .uri(format!("/keys/key{}", i))
+                    .body(Body::from(i.to_string()))
+                    .unwrap();
+                tokio::spawn(router.clone().oneshot(request))
+            })
+            .collect();
+        for request in requests {
+            assert_eq!(request.await.unwrap().unwrap().status(), StatusCode::NO_CONTENT);
+        }
+        for i in 0..10 {
+            assert_eq!(store.get(&format!("key{}", i)).await?, i.to_string());
+        }
+
+        let request = Request::builder().method("DELETE").uri("/keys/key3").body(Body::empty()).unwrap();
+        assert_eq!(router.oneshot(request).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert!(!store.contains("key3").await?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_server_logging() {
+        use axum::http::{Method, StatusCode, Uri};
+        use crate::server::Logging;
+
+        let uri: Uri = "/keys/app/name?ttl=5".parse().unwrap();
+        let slow_only = Logging { access: false, slow: Some(Duration::from_millis(100)) };
+        assert_eq!(slow_only.entry(&Method::GET, &uri, StatusCode::OK, None, Duration::from_millis(50)), None);
+        let entry = slow_only.entry(&Method::PUT, &uri, StatusCode::NO_CONTENT, Some("token:app"), Duration::from_millis(150)).unwrap();
+        assert_eq!(entry["method"], "PUT");
+        assert_eq!(entry["path"], "/keys/app/name");
+        assert_eq!(entry["key"], "app/name");
+        assert_eq!(entry["status"], 204);
+        assert_eq!(entry["ms"], 150.0);
+        assert_eq!(entry["client"], "token:app");
+        assert_eq!(entry["slow"], true);
+
+        let access = Logging { access: true, slow: None };
+        let entry = access.entry(&Method::GET, &"/stats".parse().unwrap(), StatusCode::OK, None, Duration::from_millis(50)).unwrap();
+        assert_eq!(entry["key"], serde_json::Value::Null);
+        assert_eq!(entry["client"], serde_json::Value::Null);
+        assert!(entry.get("slow").is_none());
+        assert_eq!(Logging::default().entry(&Method::GET, &uri, StatusCode::OK, None, Duration::from_secs(60)), None);
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_shutdown() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use crate::server::{Coalesce, Options};
+
+        for file in ["tmp_shutdown.db", "tmp_shutdown.db-wal", "tmp_shutdown.db-shm"] {
+            let _ = std::fs::remove_file(file);
+        }
+        let store = Arc::new(KVLiteOptions::new("sqlite://tmp_shutdown.db")?
+            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+            .open("shutdown")
+            .await?);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/keys/a", listener.local_addr()?);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        // a write waits long enough to still be coalescing when the server is stopped
+        let coalescing = Coalesce { max_delay: Duration::from_millis(500), max_writes: 100 };
+        let server = tokio::spawn(crate::server::serve_on(store.clone(), listener, Options { coalescing: Some(coalescing), ..Default::default() }, async move {
+            let _ = stopped.await;
+        }));
+
+        let put = tokio::spawn(reqwest::Client::new().put(url).body("1").send());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await.expect("the server didn't stop").unwrap()?;
+        assert_eq!(put.await.unwrap().unwrap().status(), reqwest::StatusCode::NO_CONTENT);
+
+        // the write was finished, and the log checkpointed into the file
+        assert_eq!(std::fs::metadata("tmp_shutdown.db-wal").map_or(0, |wal| wal.len()), 0);
+        let reopened = KVLite::new("sqlite://tmp_shutdown.db", "shutdown", false, false).await?;
+        assert_eq!(reopened.get("a").await?, "1");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_server_standby() -> Result<(), DsError> {
+        use std::sync::Arc;
+        use axum::{body::Body, http::{Request, StatusCode, header}};
+        use tower::ServiceExt;
+        use crate::server::{Options, Standby};
+
+        let primary = Arc::new(setup_store("standby_primary").await?);
+        primary.set("a", "1").await?;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let served = crate::server::router(primary.clone(), Default::default());
+        let primary_router = served.clone();
+        tokio::spawn(async move { axum::serve(listener, served).await });
+
+        let store = Arc::new(setup_store("standby").await?);
+        store.follow(&url).await?;
+        let router = crate::server::router(store.clone(), Options { standby: Some(Standby { primary: url.clone(), token: None }), ..Default::default() });
+        primary.set("b", "2").await?;
+        let mut followed = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if store.contains("a").await? && store.contains("b").await? {
+                followed = true;
+                break;
+            }
+        }
+        assert!(followed);
+
+        let request = Request::builder().method("PUT").uri("/keys/c?ttl=5").body(Body::from("3")).unwrap();
+        let res = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(res.headers()[header::LOCATION], format!("{}/keys/c?ttl=5", url));
+
+        // a client that wrote to the primary reads its write from the standby once it has caught up
+        let request = Request::builder().method("PUT").uri("/keys/d").body(Body::from("4")).unwrap();
+        let res = primary_router.oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let session = res.headers()["session-token"].clone();
+        assert_eq!(session.to_str().unwrap().parse::<Session>().unwrap(), primary.session().await?);
+        let request = Request::builder().uri("/keys/d").header("Session-Token", session).body(Body::empty()).unwrap();
+        let res = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["session-token"].to_str().unwrap().parse::<Session>().unwrap(), store.session().await?);
+        // and is sent to the primary for one the standby doesn't catch up with in time
+        let ahead = Session { seq: primary.session().await?.seq + 100, ..primary.session().await? };
+        let request = Request::builder().uri("/keys/d").header("Session-Token", ahead.to_string()).body(Body::empty()).unwrap();
+        let res = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(res.headers()[header::LOCATION], format!("{}/keys/d", url));
+        let request = Request::builder().uri("/keys/d").header("Session-Token", "latest").body(Body::empty()).unwrap();
+        assert_eq!(router.oneshot(request).await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_resp() -> Result<(), DsError> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let store = setup_store("resp").await?;
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nname\r\n$2\r\nds\r\nGET name\r\nEXISTS name missing\r\nTTL name\r\n").await?;
+        client.write_all(b"KEYS n*\r\nDEL name\r\nGET name\r\nTTL name\r\nINCR name\r\n").await?;
+        client.write_all(b"RPUSH queue a b\r\nLPOP queue\r\nLRANGE queue 0 -1\r\nSADD tags x\r\nSMEMBERS tags\r\n").await?;
+        client.shutdown().await?;
+        crate::resp::handle(&store, server).await?;
+
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await?;
+        assert_eq!(replies, "+OK\r\n$2\r\nds\r\n:1\r\n:-1\r\n*1\r\n$4\r\nname\r\n:1\r\n$-1\r\n:-2\r\n-ERR unknown command 'incr'\r\n:2\r\n$1\r\na\r\n*1\r\n$1\r\nb\r\n:1\r\n*1\r\n$1\r\nx\r\n");
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "daemon", unix))]
+    #[tokio::test]
+    async fn test_daemon() -> Result<(), DsError> {
+        use crate::daemon::{self, Client};
+
+        let store = setup_store("daemon").await?;
+        store.set("local", "1").await?;
+        let socket = std::path::Path::new("tmp_daemon.db.sock");
+        assert!(Client::connect(socket).await?.is_none());
+
+        let options = KVLiteOptions::new("sqlite://tmp.db")?;
+        tokio::spawn(async move { daemon::serve(options, socket, None, None).await });
+        let mut client = loop {
+            if let Some(client) = Client::connect(socket).await? {
+                break client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let watch = Client::connect(socket).await?.unwrap();
+        let mut changes = Box::pin(watch.watch("daemon", &ChangeFilter::glob("app/*"), Duration::from_millis(10)).await?);
+        // the watch only sees changes after it starts
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        client.set("daemon", "app/a", b"one", Some(Duration::from_secs(60)), Some("cli:tester")).await?;
+        let (value, meta) = client.get_with_meta("daemon", "app/a").await?;
+        assert_eq!(value, b"one");
+        assert!(meta.expires_at.is_some());
+        assert!(client.ttl("daemon", "app/a").await?.unwrap() <= Duration::from_secs(60));
+        assert_eq!(store.stat("app/a").await?.source.as_deref(), Some("cli:tester"));
+        assert!(client.contains("daemon", "local").await?);
+        assert_eq!(client.keys("daemon", Some("app/")).await?, vec!["app/a"]);
+        client.del("daemon", "app/a").await?;
+        assert!(matches!(client.get_with_meta("daemon", "app/a").await, Err(DsError::NotFound)));
+
+        let change = changes.next().await.unwrap()?;
+        assert_eq!((change.kind, change.key.as_str()), (ChangeKind::Set, "app/a"));
+        assert_eq!(changes.next().await.unwrap()?.kind, ChangeKind::Delete);
+
+        // only one daemon serves a socket
+        assert!(daemon::serve(KVLiteOptions::new("sqlite://tmp.db")?, socket, None, None).await.is_err());
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "daemon", unix))]
+    #[tokio::test]
+    async fn test_daemon_idle_close() -> Result<(), DsError> {
+        use crate::daemon::{self, Client};
+
+        let _ = std::fs::remove_file("tmp_idle.db");
+        let socket = std::path::Path::new("tmp_idle.db.sock");
+        let options = KVLiteOptions::new("sqlite://tmp_idle.db")?.journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
+        tokio::spawn(async move { daemon::serve(options, socket, Some(Duration::from_millis(100)), None).await });
+        let mut client = loop {
+            if let Some(client) = Client::connect(socket).await? {
+                break client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        client.set("store", "a", b"1", None, None).await?;
+        assert!(std::path::Path::new("tmp_idle.db-wal").exists());
+        // closing the last connection checkpoints the WAL and removes it
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(!std::path::Path::new("tmp_idle.db-wal").exists());
+
+        // the next command opens the datastore again
+        assert_eq!(client.get_with_meta("store", "a").await?.0, b"1");
+        assert!(std::path::Path::new("tmp_idle.db-wal").exists());
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "daemon", unix))]
+    #[tokio::test]
+    async fn test_daemon_stats_history() -> Result<(), DsError> {
+        use crate::daemon;
+
+        let _ = std::fs::remove_file("tmp_sampled.db");
+        let store = KVLiteOptions::new("sqlite://tmp_sampled.db")?.open("store").await?;
+        assert_eq!(store.stats_history().await?, vec![]);
+        store.set("a", "1").await?;
+        let socket = std::path::Path::new("tmp_sampled.db.sock");
+        let options = KVLiteOptions::new("sqlite://tmp_sampled.db")?;
+        tokio::spawn(async move { daemon::serve(options, socket, None, Some(Duration::from_millis(50))).await });
+
+        let mut history = vec![];
+        for _ in 0..100 {
+            history = store.stats_history().await?;
+            if !history.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(history[0].records, 1);
+        assert!(history[0].file_size > 0);
+        store.set("b", "2").await?;
+        for _ in 0..100 {
+            history = store.stats_history().await?;
+            if history.last().unwrap().records == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(history.last().unwrap().records, 2);
+        assert!(history.windows(2).all(|pair| pair[0].at <= pair[1].at));
+        // the samples aren't a store of their own
+        assert_eq!(store.stores().await?, vec!["store"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_strategies() -> Result<(), DsError> {
+        let store = setup_store("import_strategies").await?;
+        store.set("a", "old").await?;
+        store.set("b", "old").await?;
+        let csv = || std::io::Cursor::new("a,new\nb,new\nc,new\n");
+
+        assert!(store.import_with(csv(), Format::Csv, ImportStrategy::Fail).await.is_err());
+        assert!(!store.contains("c").await?);
+        assert_eq!(store.import_with(csv(), Format::Csv, ImportStrategy::Skip).await?, ImportReport { added: 1, overwritten: 0, skipped: 2 });
+        assert_eq!(store.get("a").await?, "old");
+        assert_eq!(store.get("c").await?, "new");
+
+        // records written after the imported ones are kept
+        let written_at = SystemTime::now() - Duration::from_secs(60);
+        store.set("a", "newer").await?;
+        store.set("b", "older").await?;
+        sqlx::query("UPDATE import_strategies SET updated_at = ? WHERE key = 'b'")
+            .bind(crate::history::epoch_secs(written_at) - 60)
+            .execute(&store.pool)
+            .await?;
+        assert_eq!(store.import_with(csv(), Format::Csv, ImportStrategy::Newest { written_at }).await?, ImportReport { added: 0, overwritten: 1, skipped: 2 });
+        assert_eq!(store.get("a").await?, "newer");
+        assert_eq!(store.get("b").await?, "new");
+
+        assert_eq!(store.import_with(csv(), Format::Csv, ImportStrategy::Overwrite).await?, ImportReport { added: 0, overwritten: 3, skipped: 0 });
+        assert_eq!(store.get("a").await?, "new");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_from() -> Result<(), DsError> {
+        use crate::{MergeChoice, MergeConflict};
+
+        let store = setup_store("merge_into").await?;
+        let other = setup_store("merge_from").await?;
+        store.set("same", "1").await?;
+        store.set("ours", "1").await?;
+        store.set("kept", "ours").await?;
+        store.set("taken", "ours").await?;
+        store.set("edited", "ours").await?;
+        other.set("same", "1").await?;
+        other.set("theirs", "1").await?;
+        other.set("kept", "theirs").await?;
+        other.set("taken", "theirs").await?;
+        other.set_with("edited", b"theirs", &SetOptions { content_type: Some("text/plain".into()), ..Default::default() }).await?;
+
+        // nothing is merged when resolving a conflict fails
+        assert!(store.merge_from(&other, |_| Err(DsError::NotFound)).await.is_err());
+        assert!(!store.contains("theirs").await?);
+
+        let mut conflicts = vec![];
+        let report = store.merge_from(&other, |conflict: &MergeConflict| {
+            conflicts.push(conflict.key.clone());
+            Ok(match conflict.key.as_str() {
+                "kept" => MergeChoice::Ours,
+                "taken" => MergeChoice::Theirs,
+                _ => MergeChoice::Value([conflict.ours.0.as_slice(), b"+", conflict.theirs.0.as_slice()].concat()),
+            })
+        }).await?;
+        assert_eq!(conflicts, vec!["edited", "kept", "taken"]);
+        assert_eq!(report, ImportReport { added: 1, overwritten: 2, skipped: 1 });
+        assert_eq!(store.get("ours").await?, "1");
+        assert_eq!(store.get("theirs").await?, "1");
+        assert_eq!(store.get("kept").await?, "ours");
+        assert_eq!(store.get("taken").await?, "theirs");
+        assert_eq!(store.get("edited").await?, "ours+theirs");
+        assert_eq!(store.stat("edited").await?.content_type.as_deref(), Some("text/plain"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_from() -> Result<(), DsError> {
+        let store = setup_store("import_from").await?;
+        let csv: String = (0..2500).map(|i| format!("key{},value{}\n", i, i)).collect();
+        assert_eq!(store.import_from(std::io::Cursor::new(csv.clone()), Format::Csv, false).await?, 2500);
+        assert_eq!(store.get("key2499").await?, "value2499");
+
+        // a parse error after the first batches leaves the store untouched
+        let broken = csv.replace("key0,", "new0,") + "missing value\n";
+        assert!(store.import_from(std::io::Cursor::new(broken), Format::Csv, true).await.is_err());
+        assert!(!store.contains("new0").await?);
+        assert!(store.contains("key0").await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch() -> Result<(), DsError> {
+        let store = setup_store("batch").await?;
+        let ops = vec![
+            BatchOp::Set("a".to_string(), "1".to_string()),
+            BatchOp::Get("a".to_string()),
+            BatchOp::Delete("a".to_string()),
+            BatchOp::Contains("a".to_string()),
+            BatchOp::Get("a".to_string()),
+        ];
+        assert_eq!(store.batch(&ops, true).await?, vec![
+            BatchResult::Done,
+            BatchResult::Value(Some("1".to_string())),
+            BatchResult::Done,
+            BatchResult::Contains(false),
+            BatchResult::Value(None),
+        ]);
+
+        // reading a value that isn't UTF-8 fails part way through
+        store.set_bytes("binary", &[255]).await?;
+        let ops = vec![BatchOp::Set("b".to_string(), "2".to_string()), BatchOp::Get("binary".to_string())];
+        assert!(store.batch(&ops, true).await.is_err());
+        assert!(!store.contains("b").await?);
+        assert!(store.batch(&ops, false).await.is_err());
+        assert!(store.contains("b").await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction() -> Result<(), DsError> {
+        let store = setup_store("transactions").await?;
+        store.set("a", "1").await?;
+
+        let mut tx = store.transaction().await?;
+        tx.set("b", "2").await?;
+        tx.del("a").await?;
+        assert_eq!(tx.get("b").await?, "2");
+        assert!(!tx.contains("a").await?);
+        // other connections don't see it until it's committed
+        assert!(store.contains("a").await?);
+        assert!(!store.contains("b").await?);
+        tx.rollback().await?;
+        assert!(store.contains("a").await?);
+        assert!(!store.contains("b").await?);
+
+        let mut tx = store.transaction().await?;
+        tx.set("b", "2").await?;
+        drop(tx);
+        assert!(!store.contains("b").await?);
+
+        let mut changes = Box::pin(store.subscribe("*"));
+        let mut tx = store.transaction().await?;
+        tx.set_with("b", &[255], &SetOptions::default()).await?;
+        tx.del("a").await?;
+        assert!(matches!(tx.get("b").await, Err(DsError::Sql(_))));
+        assert_eq!(tx.get_bytes("b").await?, vec![255]);
+        tx.commit().await?;
+        assert_eq!(store.get_bytes("b").await?, vec![255]);
+        assert!(!store.contains("a").await?);
+        assert_eq!(changes.next().await, Some(ChangeEvent { kind: ChangeKind::Set, key: "b".to_string(), value: Some("\u{fffd}".to_string()) }));
+        assert_eq!(changes.next().await, Some(ChangeEvent { kind: ChangeKind::Delete, key: "a".to_string(), value: None }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() -> Result<(), DsError> {
+        let _ = std::fs::remove_file("tmp_snapshot.db");
+        let store = KVLiteOptions::new("sqlite://tmp_snapshot.db")?
+            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+            .open("snapshots")
+            .await?;
+        store.set("a", "1").await?;
+        store.set("b", "2").await?;
+
+        let mut snapshot = store.snapshot().await?;
+        store.set("a", "3").await?;
+        store.del("b").await?;
+        store.set("c", "4").await?;
+        assert_eq!(snapshot.get("a").await?, "1");
+        assert!(snapshot.contains("b").await?);
+        assert!(!snapshot.contains("c").await?);
+        let options = ListOptions { sort: true, ..Default::default() };
+        let keys: Vec<String> = snapshot.keys(&options).await?.iter().map(|row| row.get("key")).collect();
+        assert_eq!(keys, vec!["a", "b"]);
+        assert_eq!(snapshot.count(&Filter::default()).await?, 2);
+        drop(snapshot);
+
+        assert_eq!(store.get("a").await?, "3");
+        assert_eq!(store.snapshot().await?.count(&Filter::default()).await?, 2);
+
+        Ok(())
+    }
+}
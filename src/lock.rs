@@ -0,0 +1,77 @@
+use std::time::{Duration, SystemTime};
+use sqlx::{sqlite::SqliteConnection, QueryBuilder, Row};
+use crate::{KVLite, DsError, Table, now, to_time};
+
+impl KVLite {
+    fn lock_table(&self) -> Table {
+        self.kv_name.object("locks")
+    }
+
+    async fn create_lock_table(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, holder TEXT NOT NULL, expires_at INTEGER NOT NULL)
+            "#, self.lock_table()))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Take the lock on a key for a holder, for a lease of ttl, returning whether it was taken.
+    /// Locks are separate from records, so a key can be locked whether or not it's set. A lock
+    /// whose lease ran out is free to take, so a holder that crashed doesn't keep it, and taking
+    /// a lock the holder already has renews its lease.
+    pub async fn acquire_lock(&self, key: &str, holder: &str, ttl: Duration) -> Result<bool, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.create_lock_table(&mut conn).await?;
+        let now = now();
+        let res = self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (key, holder, expires_at) VALUES (
+            "#, self.lock_table()))
+            .push_bind(key)
+            .push(",")
+            .push_bind(holder)
+            .push(",")
+            .push_bind(now + ttl.as_secs() as i64)
+            .push(r#") ON CONFLICT (key) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+                WHERE holder = excluded.holder OR expires_at <= "#)
+            .push_bind(now)
+            .build())
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Release the lock on a key, only if the holder has it when one is given. Returns whether a
+    /// lock was released.
+    pub async fn release_lock(&self, key: &str, holder: Option<&str>) -> Result<bool, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.create_lock_table(&mut conn).await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE key =
+            "#, self.lock_table()));
+        query.push_bind(key)
+            .push(" AND expires_at > ")
+            .push_bind(now());
+        if let Some(holder) = holder {
+            query.push(" AND holder = ").push_bind(holder);
+        }
+        Ok(self.execute(&mut conn, query.build()).await?.rows_affected() > 0)
+    }
+
+    /// Who holds the lock on a key and when their lease runs out, None if it isn't locked
+    pub async fn lock_holder(&self, key: &str) -> Result<Option<(String, SystemTime)>, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.create_lock_table(&mut conn).await?;
+        let row = self.fetch_optional(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT holder, expires_at FROM {} WHERE key =
+            "#, self.lock_table()))
+            .push_bind(key)
+            .push(" AND expires_at > ")
+            .push_bind(now())
+            .build())
+            .await?;
+        Ok(row.map(|row| (row.get("holder"), to_time(row.get("expires_at")))))
+    }
+}
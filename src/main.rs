@@ -1,15 +1,107 @@
-use std::{str::FromStr, fs};
-use sqlx::{sqlite::{SqlitePool, SqliteConnectOptions, SqliteRow}, QueryBuilder, Row, pool::PoolConnection, Sqlite};
-use clap::{Parser, Subcommand};
+use std::{fs, cmp::Ordering, str::FromStr, collections::{HashMap, HashSet}, io::{self, IsTerminal, Read, Write}, path::{Path, PathBuf}, time::{Instant, Duration, SystemTime, UNIX_EPOCH}};
+#[cfg(feature = "server")]
+use std::net::SocketAddr;
+use sqlx::{Row, sqlite::{SqliteRow, SqliteJournalMode, SqliteSynchronous}};
+use clap::{CommandFactory, Parser, Subcommand, Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use tokio_stream::{Stream, StreamExt};
+use dsr::{KVLite, KVLiteOptions, BenchOptions, ChangeFilter, DsError, FeedEvent, GrepOptions, RecordMeta, Version, ValueType, ValueTag, Repair, Role, Template, KeyTransform, ValueTransform, OnReferenced, SetOptions, SyncStrategy, ImportStrategy, MergeChoice, MergeConflict, Explain, Collation, ListOptions, SortBy, Cursor, Filter, BatchOp, BatchResult, SCHEMA_VERSION, format::{self, Format}};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 #[clap(propagate_version = true)]
 struct Cli {
-    #[clap(long)]
-    /// Specify datastore location
+    #[clap(long, env = "DS_RUST_DB")]
+    /// Specify datastore location, or memory: for a datastore in memory that lasts as long as the
+    /// command, or as the daemon serving it
     ds: Option<String>,
 
+    #[clap(long, global = true, env = "DS_RUST_STORE")]
+    /// Name of the store to use within the datastore, store by default
+    store: Option<String>,
+
+    #[clap(long, global = true, value_enum)]
+    /// Format of command output, text by default
+    output: Option<Output>,
+
+    #[clap(long, global = true)]
+    /// Profile in ~/.config/ds-rust/config.toml to take the datastore, store and output format from,
+    /// the default profile if not given
+    profile: Option<String>,
+
+    #[clap(long)]
+    /// Open the datastore read-only, so commands that write fail instead
+    read_only: bool,
+
+    #[clap(long)]
+    /// Fail instead of creating the datastore if it doesn't exist
+    no_create: bool,
+
+    #[clap(long, value_name = "MODE", value_parser)]
+    /// Journal mode of the datastore: delete, truncate, persist, memory, wal or off.
+    /// wal lets other processes read during a write, and stays set on the file
+    journal_mode: Option<SqliteJournalMode>,
+
+    #[clap(long, value_name = "MODE", value_parser)]
+    /// How often to wait for writes to reach the disk: off, normal, full or extra.
+    /// normal is safe with wal and much faster than the default of full
+    synchronous: Option<SqliteSynchronous>,
+
+    #[clap(long, value_name = "MS")]
+    /// How long to wait for another process's lock before failing with status 3 [default: 5000]
+    busy_timeout: Option<u64>,
+
+    #[clap(long, value_name = "PAGES", allow_hyphen_values = true)]
+    /// Pages of the datastore cached by each connection, or KiB of cache if negative
+    cache_size: Option<i64>,
+
+    #[clap(long, value_name = "CONNECTIONS")]
+    /// Most connections kept open to the datastore, for the server [default: 10]
+    pool_size: Option<u32>,
+
+    #[clap(long)]
+    /// Who writes are recorded as coming from, in each record and its history: cli:$USER by default,
+    /// import:<file> for import and http, resp, grpc or peer:<primary> for serve
+    source: Option<String>,
+
+    #[clap(long)]
+    /// Print elapsed time, statements run and rows affected
+    timing: bool,
+
+    #[clap(long)]
+    /// Print the SQL each command runs without executing writes
+    explain: bool,
+
+    #[clap(long, requires = "explain")]
+    /// Also print the query plan of each read
+    query_plan: bool,
+
+    #[clap(long, default_value = "binary", value_parser)]
+    /// Key collation used when creating a new store: binary, nocase, natural or icu:<locale>
+    collation: Collation,
+
+    #[cfg(feature = "extensions")]
+    #[clap(long, multiple_occurrences = true)]
+    /// Load a SQLite extension into each connection
+    extension: Vec<String>,
+
+    #[cfg(feature = "encryption")]
+    #[clap(long)]
+    /// Encrypt the datastore with a passphrase, read from DSR_PASSPHRASE or prompted for
+    encrypt: bool,
+
+    #[cfg(all(feature = "daemon", unix))]
+    #[clap(long, value_name = "PATH")]
+    /// Unix socket of the daemon, <datastore>.sock by default
+    socket: Option<PathBuf>,
+
+    #[cfg(all(feature = "daemon", unix))]
+    #[clap(long)]
+    /// Open the datastore directly even when a daemon is running
+    no_daemon: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -17,316 +109,4421 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Set the value of a record
-    Set { key: String, value: String },
-    /// Check if a record exists
-    Contains { key: String },
+    Set {
+        key: String,
+        #[clap(required_unless_present_any = &["stdin", "file", "edit", "prompt", "value-from"])]
+        /// Value to set, or - to read it from stdin
+        value: Option<String>,
+        #[clap(long, conflicts_with = "value")]
+        /// Read the value from stdin, keeping newlines and bytes
+        stdin: bool,
+        #[clap(long, conflicts_with_all = &["value", "stdin"])]
+        /// Read the value from a file, streamed into the store without reading it all into memory
+        file: Option<String>,
+        #[clap(long, conflicts_with_all = &["value", "stdin", "file"])]
+        /// Write the value in $EDITOR, leaving the record unchanged if it's left empty
+        edit: bool,
+        #[clap(long, conflicts_with_all = &["value", "stdin", "file", "edit"])]
+        /// Read the value from the terminal without echoing it, for secrets
+        prompt: bool,
+        #[clap(long, value_parser, conflicts_with_all = &["value", "stdin", "file", "edit", "prompt"])]
+        /// Where to read the value from, keeping it out of argv: stdin, prompt, file:<path> or env:<VAR>
+        value_from: Option<ValueSource>,
+        #[clap(long)]
+        /// Mark the value as a secret, warning if it was given on the command line
+        secret: bool,
+        #[clap(long)]
+        /// MIME type of the value, used to render it on get
+        content_type: Option<String>,
+        #[clap(long = "type")]
+        /// Type the value must have, int, float, bool, json, list, set or pointer, kept with the record
+        tag: Option<ValueTag>,
+        #[clap(long)]
+        /// Expire the record after this many seconds
+        ttl: Option<u64>,
+        #[clap(long)]
+        /// Only set the record if it doesn't exist, exiting with 1 otherwise
+        if_absent: bool,
+        #[clap(long, conflicts_with = "if-absent")]
+        /// Only set the record if it has this value, exiting with 1 otherwise
+        if_equals: Option<String>,
+    },
+    /// Edit the value of a record in $EDITOR, writing it back unless it was changed meanwhile
+    Edit {
+        key: String,
+        #[clap(long)]
+        /// Start from an empty value if the record doesn't exist
+        create: bool,
+    },
+    /// Run newline-delimited commands from stdin over one connection:
+    /// set <key> <value>, get <key>, contains <key> and delete <key>
+    Batch {
+        #[clap(long)]
+        /// Run every command in one transaction, writing nothing if any fails
+        atomic: bool,
+    },
+    /// Set the values of several records at once
+    Mset {
+        #[clap(required = true, value_names = &["KEY", "VALUE"])]
+        /// Alternating keys and values
+        pairs: Vec<String>,
+    },
+    /// Get the values of several records, printing an empty line for missing records
+    Mget {
+        #[clap(required = true)]
+        keys: Vec<String>,
+    },
+    /// Delete several records at once
+    Mdel {
+        #[clap(required = true)]
+        keys: Vec<String>,
+    },
+    /// Add to the integer value of a record, starting from 0
+    Incr {
+        key: String,
+        #[clap(default_value = "1", allow_hyphen_values = true)]
+        amount: i64,
+    },
+    /// Subtract from the integer value of a record, starting from 0
+    Decr {
+        key: String,
+        #[clap(default_value = "1", allow_hyphen_values = true)]
+        amount: i64,
+    },
+    /// Add text to the end of a value in one statement, or read it from stdin if it's -, and print
+    /// the value's new length in bytes. A missing record is created with just the text
+    Append {
+        key: String,
+        #[clap(allow_hyphen_values = true)]
+        suffix: String,
+    },
+    /// Add text to the start of a value in one statement, like append
+    Prepend {
+        key: String,
+        #[clap(allow_hyphen_values = true)]
+        prefix: String,
+    },
+    /// Get the length of a value in bytes, 0 if the record doesn't exist
+    Strlen { key: String },
+    /// Add values to the start of a list, the last one given first, and print its new length
+    Lpush {
+        key: String,
+        #[clap(required = true)]
+        values: Vec<String>,
+    },
+    /// Add values to the end of a list and print its new length
+    Rpush {
+        key: String,
+        #[clap(required = true)]
+        values: Vec<String>,
+    },
+    /// Remove and print the first value of a list, exiting with status 1 if it's empty
+    Lpop { key: String },
+    /// Add a value to the end of a durable FIFO queue kept alongside the store and print its new length
+    Push { queue: String, value: String },
+    /// Remove and print the value at the front of a queue, exiting with status 1 if it's empty
+    Pop {
+        queue: String,
+        #[clap(long, value_name = "SECS")]
+        /// Wait up to this long for a value to be pushed if the queue is empty
+        wait: Option<u64>,
+    },
+    /// Get the values of a list from start to stop, both included, counting back from the end if
+    /// negative
+    Lrange {
+        key: String,
+        #[clap(default_value = "0", allow_hyphen_values = true)]
+        start: i64,
+        #[clap(default_value = "-1", allow_hyphen_values = true)]
+        stop: i64,
+    },
+    /// Add members to a set and print how many weren't already in it
+    Sadd {
+        key: String,
+        #[clap(required = true)]
+        members: Vec<String>,
+    },
+    /// Remove members from a set and print how many were in it
+    Srem {
+        key: String,
+        #[clap(required = true)]
+        members: Vec<String>,
+    },
+    /// Get the members of a set in order
+    Smembers { key: String },
+    /// Get the number of seconds before a record expires, or -1 if it never expires
+    Ttl { key: String },
+    /// Keep a record from ever expiring, clearing its TTL and ignoring any it's set with later
+    Pin { key: String },
+    /// Let a pinned record be given a TTL again
+    Unpin { key: String },
+    /// Take the lock on a key and print the token that holds it, exiting with status 1 if another
+    /// holder has it. The lock is released by unlock or when its lease runs out
+    Lock {
+        key: String,
+        #[clap(long, default_value = "60")]
+        /// Seconds the lease lasts, so a holder that crashes doesn't keep the lock
+        ttl: u64,
+        #[clap(long, value_name = "SECS")]
+        /// Keep trying to take the lock for this many seconds
+        wait: Option<u64>,
+        #[clap(long)]
+        /// Token to hold the lock with, renewing its lease if it already holds it
+        token: Option<String>,
+    },
+    /// Release the lock on a key, exiting with status 1 if it wasn't locked
+    Unlock {
+        key: String,
+        #[clap(long)]
+        /// Only release the lock if this token holds it
+        token: Option<String>,
+    },
+    /// Show the size, TTL, content type, type, pinning and creation and modification times of a record
+    Stat {
+        key: String,
+        #[clap(long)]
+        /// Also show who last wrote the record
+        provenance: bool,
+    },
+    /// Get the type a record was set with, or untyped
+    Type { key: String },
+    /// Check if a record exists, exiting with status 1 if it doesn't
+    Contains {
+        key: String,
+        #[clap(long, short)]
+        /// Print nothing, only setting the exit status
+        quiet: bool,
+    },
+    /// Count the records in the store
+    Count {
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Show the size of the datastore file, and the records and key and value bytes of each store
+    Stats {
+        #[clap(long)]
+        /// Show how the datastore's size and records have grown instead, from the samples the daemon takes
+        history: bool,
+    },
+    /// List the stores in the datastore with how many records each has, without creating any
+    Ls {
+        #[clap(long)]
+        /// List the stores of every datastore known: this one, the default one and those of the
+        /// profiles in the config file
+        all: bool,
+    },
+    /// List the multi-step commands, import, load, restore, rename-prefix, sync and transfer, that
+    /// were interrupted before they finished, so they can be run again or dropped
+    Recover {
+        #[clap(long)]
+        /// Run the interrupted commands again, from the directories they were run in
+        resume: bool,
+        #[clap(long, conflicts_with = "resume")]
+        /// Drop the interrupted commands, each of which wrote in one transaction SQLite has rolled back
+        rollback: bool,
+    },
+    /// Print a completion script for a shell, which completes the keys of get, delete, contains and
+    /// the other commands taking a key from the store as they're typed. Load it with
+    /// source <(dsr completions bash), or save it as _dsr in a zsh $fpath directory or as
+    /// ~/.config/fish/completions/dsr.fish
+    Completions {
+        #[clap(value_enum)]
+        shell: Shell,
+    },
     /// Get the value of a record
-    Get { key: String },
-    /// Delete a record
-    Delete { key: String },
+    Get {
+        key: String,
+        #[clap(long, conflicts_with = "format")]
+        /// Write the value's bytes to stdout as is, without pretty-printing or a trailing newline
+        raw: bool,
+        #[clap(long, conflicts_with_all = &["raw", "format"])]
+        /// Write the value's bytes to a file, streamed out of the store without reading it all into memory
+        out: Option<String>,
+        #[clap(long)]
+        /// Get a past version from the store's history instead
+        version: Option<i64>,
+        #[clap(long, value_parser = parse_time, value_name = "TIME", conflicts_with = "version")]
+        /// Get the value the record had at a past time, like 2024-06-01T12:00Z, from the store's history
+        as_of: Option<SystemTime>,
+        #[clap(long, conflicts_with_all = &["version", "as-of"])]
+        /// Value to print if the record doesn't exist, instead of failing
+        default: Option<String>,
+        #[clap(long, short, conflicts_with = "default")]
+        /// Don't report a record that doesn't exist, only exiting with status 1
+        quiet: bool,
+        #[clap(long, conflicts_with_all = &["version", "as-of"])]
+        /// Print the content a pointer record points at rather than the pointer
+        resolve: bool,
+        #[clap(flatten)]
+        format: FormatArgs,
+    },
+    /// Set the value of a record and print the value it had before, or nothing if it didn't exist,
+    /// in one transaction
+    Getset { key: String, value: String },
+    /// Delete a record, or every record matching --prefix, --glob or --older-than, printing how many were deleted
+    Delete {
+        #[clap(required_unless_present_any = &["prefix", "glob", "older-than"])]
+        key: Option<String>,
+        #[clap(long)]
+        /// Also delete the records in reference stores naming it, and the records naming those
+        cascade: bool,
+        #[clap(long, conflicts_with = "cascade")]
+        /// Refuse to delete the record if a record in a reference store names it
+        restrict: bool,
+        #[clap(long, conflicts_with_all = &["key", "cascade", "restrict"])]
+        /// Delete every record whose key starts with this prefix
+        prefix: Option<String>,
+        #[clap(long, conflicts_with_all = &["key", "cascade", "restrict"])]
+        /// Delete every record whose key matches a glob pattern using * and ?
+        glob: Option<String>,
+        #[clap(long, value_parser = parse_age, value_name = "TIME", conflicts_with_all = &["key", "cascade", "restrict"])]
+        /// Delete every record last written before a time, like 2024-06-01T12:00Z, or longer ago than
+        /// an age, like 30d, 12h, 15m or 45s
+        older_than: Option<SystemTime>,
+        #[clap(long)]
+        /// Overwrite the deleted values in the datastore file and erase them from the history, so
+        /// they can't be recovered
+        secure: bool,
+    },
+    /// Delete every record in the store
+    Clear {
+        #[clap(long)]
+        /// Don't ask for confirmation, which is otherwise needed on a terminal and refused elsewhere
+        yes: bool,
+    },
+    /// Check the datastore file for corruption, printing each problem found
+    Check {
+        #[clap(long)]
+        /// Also print the records of a reference store naming missing keys, as "<key> <value>"
+        refs: bool,
+        #[clap(long)]
+        /// Also print the pointer records whose file or URL doesn't exist, as "<key> <pointer>"
+        refs_external: bool,
+    },
+    /// Run SQLite's full integrity check and check the store's records against the checksums
+    /// stored when they were written (see store checksums), printing each problem and corrupted key
+    Verify {
+        #[clap(long)]
+        /// Put corrupted records back from their history, or delete those that can't be
+        repair: bool,
+    },
+    /// Vacuum the datastore file, giving its free space back to the filesystem, and print how many
+    /// bytes were reclaimed
+    Compact {
+        #[clap(long)]
+        /// Only truncate free pages off the end of the file instead of rebuilding it. The first time
+        /// switches the datastore to incremental auto vacuum, which takes a full vacuum
+        incremental: bool,
+    },
+    /// Copy the whole datastore to a file, safely while other processes write to it
+    Backup {
+        path: String,
+        #[clap(long)]
+        /// Compress the backup with gzip
+        gzip: bool,
+    },
+    /// Replace the whole datastore with a backup, gzip compressed or not
+    Restore {
+        path: String,
+        #[clap(long)]
+        /// Don't ask for confirmation, which is otherwise needed on a terminal and refused elsewhere
+        yes: bool,
+    },
+    /// List the past versions of a record in a versioned store
+    History {
+        key: String,
+        #[clap(long)]
+        /// Also show who wrote each version, as "<version> <time> <source> <value>"
+        provenance: bool,
+    },
+    /// Restore a record in a versioned store to the version before its latest
+    Rollback {
+        key: String,
+        #[clap(long)]
+        /// Version to restore instead
+        to: Option<i64>,
+    },
+    /// List records expiring soon as "<key> <seconds left>", or run a command for each so leases can be renewed
+    Expiring {
+        #[clap(long, default_value = "60")]
+        /// Seconds ahead to look for expiring records
+        within: u64,
+        #[clap(long)]
+        /// Shell command to run for each record, given DSR_KEY and DSR_EXPIRES_AT (seconds since the epoch),
+        /// e.g. curl to call a webhook
+        exec: Option<String>,
+        #[clap(long)]
+        /// Keep checking, handling each record once per expiry
+        follow: bool,
+        #[clap(long, default_value = "1000")]
+        /// Milliseconds between checks with --follow
+        interval: u64,
+    },
+    /// Keep deleting expired records as they expire rather than when the store is next written,
+    /// printing "expired <key>" for each or running a command for each, so listings read by other
+    /// tools don't include dead records. The store's hooks run for each expiry too
+    ExpireDaemon {
+        #[clap(long, default_value = "60")]
+        /// Seconds between sweeps
+        interval: u64,
+        #[clap(long)]
+        /// Shell command to run for each expired record, given DSR_KEY, instead of printing it
+        exec: Option<String>,
+    },
+    /// Print changes made by any process as they happen, one "<set|delete|expired> <key>" line each
+    Watch {
+        #[clap(required_unless_present_any = &["prefix", "filter"], conflicts_with = "prefix")]
+        /// Key to watch, or a glob pattern using * and ?
+        key: Option<String>,
+        #[clap(long)]
+        /// Watch every key with this prefix
+        prefix: Option<String>,
+        #[clap(long, value_parser, value_name = "EXPR")]
+        /// Only print changes passing every space-separated term of a filter: prefix:<prefix>,
+        /// glob:<pattern>, kind:set|delete|expired or tag:<tag> (any one of the kinds and tags
+        /// given), and changed to skip sets that leave the value as it was
+        filter: Option<ChangeFilter>,
+        #[clap(long, default_value = "500")]
+        /// Milliseconds between polls of the change log
+        interval: u64,
+    },
     /// Get a list of all keys in the datastore
-    Keys,
+    Keys {
+        #[clap(long, value_parser, value_name = "BY")]
+        /// Sort keys by key (the default, using the store's collation), value, updated_at or random
+        sort: Option<Option<SortBy>>,
+        #[clap(long)]
+        /// List keys in reverse order
+        reverse: bool,
+        #[clap(long)]
+        /// List at most this many keys
+        limit: Option<u64>,
+        #[clap(long, value_parser, conflicts_with_all = &["sort", "reverse"])]
+        /// Continue a listing from the cursor printed by a previous page
+        cursor: Option<Cursor>,
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "cursor")]
+        /// List each key cut off after this many of its .- or /-separated levels below --prefix,
+        /// once for all the keys sharing them
+        depth: Option<u64>,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Print the keys, or those starting with a prefix, as an indented tree of their .- or
+    /// /-separated levels
+    Tree { prefix: Option<String> },
     /// Get a list of all values in the datastore
-    Values,
+    Values {
+        #[clap(long, value_parser, value_name = "BY")]
+        /// Sort values by key (the default, using the store's collation), value, updated_at or random
+        sort: Option<Option<SortBy>>,
+        #[clap(long)]
+        /// List values in reverse order
+        reverse: bool,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Find the records whose values match a full-text query, best match first.
+    /// The first search of a store builds its index, which every write keeps up to date after that
+    Search {
+        /// An SQLite FTS5 query, like words, "a phrase", prefix* or a AND NOT b
+        query: String,
+        #[clap(long)]
+        /// List at most this many records
+        limit: Option<u64>,
+    },
+    /// Print the records whose values match a regular expression as "key,value" lines, exiting with
+    /// status 1 if none do. Values are matched as get returns them
+    Grep {
+        pattern: String,
+        #[clap(long)]
+        /// Print only the keys of matching records
+        keys_only: bool,
+        #[clap(long, short)]
+        /// Match letters of either case
+        ignore_case: bool,
+        #[clap(long)]
+        /// Also match the pattern against keys
+        match_keys: bool,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
     /// Get a list of all records in the datastore
-    Records,
-}
-
-struct KVLite {
-    pool: SqlitePool,
-    kv_name: String,
-}
-
-impl KVLite {
-    async fn create_store_table(conn: &mut PoolConnection<Sqlite>, kv_name: &str) -> Result<(), sqlx::Error>{
-        QueryBuilder::new(format!(r#"
-                CREATE TABLE {} (key TEXT PRIMARY KEY, value TEXT);
-            "#, kv_name))
-            .build()
-            .execute(conn)
-            .await?;
-        Ok(())
-    }
-
-    pub async fn new(filename: &str, kv_name: &str, read_only: bool, create_new: bool) -> Result<KVLite, sqlx::Error> {
-        let options = SqliteConnectOptions::from_str(filename)?
-            .create_if_missing(create_new)
-            .read_only(read_only);
-
-        let pool = SqlitePool::connect_with(options).await?;
-
-        let mut conn = pool.acquire().await?;
-        match QueryBuilder::new(r#"
-                SELECT name FROM sqlite_master WHERE type="table" AND name=
-            "#)
-            .push_bind(kv_name)
-            .build()
-            .fetch_optional(&mut conn)
-            .await? 
-        {
-            Some(_) => (),
-            None => {
-                KVLite::create_store_table(&mut conn, kv_name).await?;
+    Records {
+        #[clap(long, value_parser, value_name = "BY")]
+        /// Sort records by key (the default, using the store's collation), value, updated_at or random
+        sort: Option<Option<SortBy>>,
+        #[clap(long)]
+        /// List records in reverse order
+        reverse: bool,
+        #[clap(long)]
+        /// List at most this many records
+        limit: Option<u64>,
+        #[clap(long, conflicts_with_all = &["reverse", "limit", "cursor"])]
+        /// List only the last N records in sort order
+        tail: Option<u64>,
+        #[clap(long, conflicts_with = "format")]
+        /// Also show when each record was created and last updated
+        long: bool,
+        #[clap(long, value_parser, conflicts_with_all = &["sort", "reverse"])]
+        /// Continue a listing from the cursor printed by a previous page
+        cursor: Option<Cursor>,
+        #[clap(long, value_parser = parse_time, value_name = "TIME", conflicts_with_all = &["sort", "reverse", "tail", "long", "cursor", "updated-since", "value-tag"])]
+        /// List the records as they were at a past time, like 2024-06-01T12:00Z, from the store's history
+        as_of: Option<SystemTime>,
+        #[clap(flatten)]
+        filter: FilterArgs,
+        #[clap(flatten)]
+        format: FormatArgs,
+    },
+    /// Get the records with keys from start up to end
+    Range {
+        start: String,
+        end: String,
+        #[clap(long)]
+        /// Include records with the end key
+        inclusive: bool,
+    },
+    /// Print the key of a record chosen at random, or nothing and exit with status 1 if there are none
+    Randomkey {
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Print up to n records chosen at random as "key,value" lines, for spot checks
+    Sample {
+        n: u64,
+        #[clap(long)]
+        /// Print only their keys
+        keys: bool,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Rename a record in one transaction, keeping its value, TTL and metadata
+    Rename {
+        old: String,
+        new: String,
+        #[clap(flatten)]
+        overwrite: OverwriteArgs,
+    },
+    /// Copy a record to a new key in one transaction
+    Copy {
+        src: String,
+        dst: String,
+        #[clap(flatten)]
+        overwrite: OverwriteArgs,
+    },
+    /// Replace the prefix of every key starting with old by new
+    RenamePrefix { old: String, new: String },
+    /// Copy the records of a store to another, in this datastore or another file, creating it if
+    /// needed, a batch per transaction, and print how many were copied
+    Transfer {
+        #[clap(long, value_name = "STORE")]
+        /// Store to copy records from, instead of --store
+        from_store: Option<String>,
+        #[clap(long, value_name = "STORE")]
+        /// Store to copy records to
+        to_store: String,
+        #[clap(long, value_name = "DATASTORE")]
+        /// Datastore file to copy records to, this one if not given
+        to_ds: Option<String>,
+        #[clap(long = "move")]
+        /// Delete the records from the store they're copied from
+        move_records: bool,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Compare the store with another, printing + for keys only in this store, - for keys only in
+    /// the other and ~ for keys whose values differ
+    Diff {
+        #[clap(required_unless_present = "against", conflicts_with = "against")]
+        /// Datastore file holding a store of the same name, or a file records were exported to
+        other: Option<String>,
+        #[clap(long)]
+        /// Store in the datastore to compare against
+        against: Option<String>,
+        #[clap(long, value_parser, requires = "other")]
+        /// Format of the exported records compared against: json, csv, env or msgpack (with the
+        /// msgpack feature). Guessed from the file when not given.
+        format: Option<Format>,
+        #[clap(long)]
+        /// Also print the values that differ, the other's after - and this store's after +
+        values: bool,
+    },
+    /// Copy the records that differ between the store and the store of the same name in another
+    /// datastore, which is created if it doesn't exist. Deletes aren't synced.
+    Sync {
+        /// Datastore to sync with
+        other: String,
+        #[clap(long, required_unless_present_any = &["pull", "two-way"], conflicts_with_all = &["pull", "two-way"])]
+        /// Copy records the other datastore is missing or has different to it
+        push: bool,
+        #[clap(long, conflicts_with = "two-way")]
+        /// Copy records this datastore is missing or has different from the other
+        pull: bool,
+        #[clap(long)]
+        /// Copy records each datastore is missing to it, reporting records that differ as conflicts
+        two_way: bool,
+        #[clap(long, requires = "two-way")]
+        /// Resolve conflicts by keeping the record updated last
+        newest_wins: bool,
+    },
+    /// Copy the records of the store of the same name in another datastore, or of another store in
+    /// this one, into the store in one transaction, keeping those only it has, then print how many
+    /// were added, overwritten and skipped
+    Merge {
+        #[clap(required_unless_present = "from", conflicts_with = "from")]
+        /// Datastore holding the store to merge from
+        other: Option<String>,
+        #[clap(long)]
+        /// Store in the datastore to merge from
+        from: Option<String>,
+        #[clap(long, value_enum, conflicts_with = "interactive")]
+        /// What to do with records both stores have with different values, as import --strategy
+        /// does, taking the other's to be written when it last updated them. Overwritten by default
+        strategy: Option<ImportConflicts>,
+        #[clap(long)]
+        /// Show both values of each record the stores have different, diffed, and ask whether to keep
+        /// ours, take theirs or edit them together in $EDITOR
+        interactive: bool,
+    },
+    /// Write every record in the store to a file or stdout
+    Export {
+        #[clap(long, default_value = "json", value_parser)]
+        /// Format to write: json, csv, env, msgpack or parquet (with the feature of the same name)
+        format: Format,
+        #[clap(long)]
+        /// File to write to instead of stdout
+        file: Option<String>,
+        #[clap(long, conflicts_with_all = &["format", "file"])]
+        /// Create a new, vacuumed datastore holding only the exported records instead
+        to_sqlite: Option<String>,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Load records from a file or stdin into the store
+    Import {
+        /// File to read from, stdin if not given
+        file: Option<String>,
+        #[clap(long, default_value = "json", value_parser)]
+        /// Format to read: json, csv, env or msgpack (with the msgpack feature)
+        format: Format,
+        #[clap(long)]
+        /// Keep existing records, overwriting those that are imported (the default)
+        merge: bool,
+        #[clap(long, conflicts_with = "merge")]
+        /// Delete existing records that aren't imported
+        replace: bool,
+        #[clap(long, value_enum, conflicts_with = "replace")]
+        /// What to do with records whose keys the store already has: overwrite them, skip the imported
+        /// ones, fail importing anything, or keep the newest, taking the imported records to have been
+        /// written when the file was last modified, then print how many were added, overwritten and skipped
+        strategy: Option<ImportConflicts>,
+    },
+    /// Bulk load a CSV or JSON file of records, or stdin given as -, with synchronous off until
+    /// it's committed, and print how fast it went. Much faster than import for millions of records,
+    /// but the datastore can be corrupted if the machine loses power meanwhile
+    Load {
+        #[clap(long, required_unless_present = "json", conflicts_with = "json")]
+        /// key,value CSV file to load
+        csv: Option<String>,
+        #[clap(long)]
+        /// JSON file to load, as export writes it
+        json: Option<String>,
+        #[clap(long)]
+        /// Delete existing records that aren't loaded
+        replace: bool,
+    },
+    /// Move records in and out of process environments
+    Env {
+        #[clap(subcommand)]
+        command: EnvCommands,
+    },
+    /// Tag records, so those sharing a tag can be listed with --tag whatever their keys
+    Tag {
+        #[clap(subcommand)]
+        command: TagCommands,
+    },
+    /// Manage the stores in the datastore
+    Store {
+        #[clap(subcommand)]
+        command: StoreCommands,
+    },
+    /// Read or prune the audit log of an audited store
+    Audit {
+        #[clap(subcommand)]
+        command: AuditCommands,
+    },
+    /// Read or prune the change feed of a store with one
+    Changes {
+        #[clap(subcommand)]
+        command: ChangesCommands,
+    },
+    /// Keep named copies of the store inside the datastore and bring them back
+    Snapshot {
+        #[clap(subcommand)]
+        command: SnapshotCommands,
+    },
+    /// Run shell commands when records in the store are set or deleted
+    Hook {
+        #[clap(subcommand)]
+        command: HookCommands,
+    },
+    /// Give clients of serve roles on the store, as token:<name>, user:<name> or jwt:<subject>.
+    /// Once any client has one, clients without one are refused
+    Acl {
+        #[clap(subcommand)]
+        command: AclCommands,
+    },
+    /// Time setting, getting and deleting records under dsr-bench/ with the store opened as given,
+    /// printing each operation's throughput and latency percentiles, to compare journal modes,
+    /// pragmas and pool sizes
+    Bench {
+        #[clap(long, default_value = "10000")]
+        /// Records set, read and deleted
+        ops: u64,
+        #[clap(long, default_value = "1")]
+        /// Operations in flight at once, each needing a connection from the pool
+        concurrency: usize,
+        #[clap(long, default_value = "100", value_name = "BYTES")]
+        /// Size of each value
+        value_size: usize,
+    },
+    /// Bring every store in the datastore up to the schema this version of dsr uses, printing the
+    /// version each migrated store was at. Opening a store migrates it too, so this is for
+    /// upgrading them all at once, before they're used by anything older
+    Migrate {
+        #[clap(long)]
+        /// Print the schema version of every store instead, without migrating any
+        status: bool,
+    },
+    /// Look after the datastore file
+    Maintain {
+        #[clap(subcommand)]
+        command: MaintainCommands,
+    },
+    #[cfg(feature = "scripting")]
+    /// Run a Rhai script against the store in a single transaction
+    Eval {
+        /// Path to the script
+        script: String,
+    },
+    #[cfg(feature = "tui")]
+    /// Browse the store in the terminal, with a searchable list of keys beside the selected
+    /// record's value, which can be edited in $EDITOR, renamed or deleted
+    Tui,
+    #[cfg(feature = "server")]
+    /// Serve the store over HTTP
+    Serve {
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        /// Address to listen on
+        addr: SocketAddr,
+        #[clap(long)]
+        /// Speak the Redis protocol (GET, SET, DEL, EXISTS, KEYS and TTL) instead of HTTP
+        resp: bool,
+        #[cfg(feature = "grpc")]
+        #[clap(long, conflicts_with_all = &["resp", "admin-token", "max-body", "utf8", "max-key", "key-pattern", "cors-origin",
+            "coalesce-delay", "follow", "promote", "ui", "access-log", "slow-ms"])]
+        /// Serve the Store gRPC service in proto/dsr.proto (Get, Set, Delete, Scan and Watch) instead of HTTP,
+        /// with the same authentication
+        grpc: bool,
+        #[clap(long, value_parser, multiple_occurrences = true, conflicts_with = "resp")]
+        /// Only serve HTTP clients that authenticate, here sending one of these bearer tokens given as
+        /// name:secret[:max_requests[:max_bytes]], answering 429 once a token's quota is used up
+        token: Vec<dsr::server::Token>,
+        #[clap(long, value_name = "FILE", conflicts_with = "resp")]
+        /// Also serve HTTP clients sending one of the tokens in this file, one a line like --token,
+        /// read again on SIGHUP or POST /admin/reload
+        token_file: Option<String>,
+        #[clap(long, value_name = "FILE", conflicts_with = "resp")]
+        /// Also serve HTTP clients sending basic auth for a user in this htpasswd file,
+        /// with passwords hashed by bcrypt or SHA-1, read again on SIGHUP or POST /admin/reload
+        htpasswd: Option<String>,
+        #[clap(long, value_name = "URL", requires = "jwt-audience", conflicts_with = "resp")]
+        /// Also serve HTTP clients sending a bearer JWT signed by this OpenID Connect issuer
+        jwt_issuer: Option<String>,
+        #[clap(long, value_name = "AUDIENCE", requires = "jwt-issuer")]
+        /// Audience JWTs from --jwt-issuer must be meant for
+        jwt_audience: Option<String>,
+        #[clap(long, conflicts_with = "resp")]
+        /// Bearer token that can read each token's usage from GET /admin/usage
+        admin_token: Option<String>,
+        #[clap(long, value_name = "BYTES", default_value_t = dsr::server::DEFAULT_MAX_BODY, conflicts_with = "resp")]
+        /// Refuse HTTP requests with bodies bigger than this
+        max_body: usize,
+        #[clap(long, value_name = "POLICY", default_value = "allow", value_parser, conflicts_with = "resp")]
+        /// What to do with values that aren't valid UTF-8: allow them as BLOBs, reject them,
+        /// or replace invalid sequences with U+FFFD
+        utf8: dsr::server::Utf8Policy,
+        #[clap(long, value_name = "BYTES", conflicts_with = "resp")]
+        /// Refuse writes to keys longer than this
+        max_key: Option<usize>,
+        #[clap(long, value_name = "GLOB", multiple_occurrences = true, conflicts_with = "resp")]
+        /// Refuse writes to keys that don't match one of these globs
+        key_pattern: Vec<String>,
+        #[clap(long, value_name = "ORIGIN", value_parser, multiple_occurrences = true, conflicts_with = "resp")]
+        /// Let browser pages from this origin call the HTTP API, or from any with *
+        cors_origin: Vec<axum::http::HeaderValue>,
+        #[clap(long, value_name = "METHOD", value_parser, multiple_occurrences = true, default_values = &["GET", "PUT", "DELETE"], requires = "cors-origin")]
+        /// Methods allowed from --cors-origin
+        cors_method: Vec<axum::http::Method>,
+        #[clap(long, value_name = "HEADER", value_parser, multiple_occurrences = true, default_values = &["authorization", "content-type"], requires = "cors-origin")]
+        /// Request headers allowed from --cors-origin
+        cors_header: Vec<axum::http::HeaderName>,
+        #[clap(long, value_name = "MS", conflicts_with = "resp")]
+        /// Group writes arriving within this many milliseconds of each other into one transaction
+        coalesce_delay: Option<u64>,
+        #[clap(long, value_name = "WRITES", default_value = "128", requires = "coalesce-delay")]
+        /// Most writes grouped into one transaction
+        coalesce_max: usize,
+        #[clap(long, value_name = "URL", conflicts_with_all = &["resp", "promote"])]
+        /// Run as a standby of the primary served at this URL, following its changes and redirecting
+        /// writes to it. A standby keeps following its primary when served again without --follow
+        follow: Option<String>,
+        #[clap(long, conflicts_with = "resp")]
+        /// Stop following the primary and take writes, starting a new generation so standbys
+        /// refuse to follow the old primary
+        promote: bool,
+        #[clap(long, value_name = "SECRET", conflicts_with = "resp")]
+        /// Bearer token sent to the primary when following it
+        primary_token: Option<String>,
+        #[clap(long, conflicts_with = "resp")]
+        /// Serve a dashboard at /ui for browsing, searching and editing records, with the
+        /// datastore's stats and latest changes
+        ui: bool,
+        #[clap(long, conflicts_with = "resp")]
+        /// Log every HTTP request to stderr as a line of JSON, with its method, key, status,
+        /// duration and client
+        access_log: bool,
+        #[clap(long, value_name = "MS", conflicts_with = "resp")]
+        /// Log HTTP requests taking at least this many milliseconds, marked as slow, even without --access-log
+        slow_ms: Option<u64>,
+    },
+    #[cfg(feature = "grpc")]
+    /// Run get, set, delete, contains, keys or watch against a store served by another dsr with
+    /// serve --grpc
+    Client {
+        /// URL of the server, like http://127.0.0.1:8080
+        url: String,
+        #[clap(long, env = "DSR_TOKEN", hide_env_values = true)]
+        /// Bearer token to send, for servers run with --token or --jwt-issuer
+        token: Option<String>,
+        #[clap(long)]
+        /// Keep values read with get in ~/.cache/ds-rust/client-cache.db, and only have the server
+        /// send one again once its store has been written since
+        cache: bool,
+        #[clap(long)]
+        /// Keep sets and deletes in ~/.local/share/ds-rust/client-queue.db while the server can't
+        /// be reached, and send them, checking for conflicts, the next time it can be
+        queue: bool,
+        #[clap(subcommand)]
+        command: Box<Commands>,
+    },
+    #[cfg(all(feature = "daemon", unix))]
+    /// Keep the datastore open and serve it on a Unix socket. While it runs, get, set, contains,
+    /// delete, ttl, keys and watch without options other than --ttl, --default and --quiet are
+    /// sent to it instead of opening the datastore
+    Daemon {
+        #[clap(long, value_name = "SECS")]
+        /// Close the datastore after this many seconds without a command, so backup tools can
+        /// copy the file, and open it again for the next command
+        idle_close: Option<u64>,
+        #[clap(long, value_name = "SECS", default_value = "3600")]
+        /// Record the datastore's size and records this often for stats --history, 0 not to
+        stats_interval: u64,
+    },
+    /// Copy a store and all of its records into a new store
+    CloneStore {
+        src: String,
+        dst: String,
+        #[clap(long)]
+        /// Datastore to create the new store in
+        to_ds: Option<String>,
+    },
+}
+
+/// Where set reads a value from other than its arguments
+#[derive(Clone, Debug)]
+enum ValueSource {
+    Stdin,
+    /// The terminal, without echoing
+    Prompt,
+    File(String),
+    /// An environment variable
+    Env(String),
+}
+
+impl FromStr for ValueSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "stdin" => Ok(ValueSource::Stdin),
+            _ if s == "prompt" => Ok(ValueSource::Prompt),
+            Some(("file", path)) if !path.is_empty() => Ok(ValueSource::File(path.to_string())),
+            Some(("env", var)) if !var.is_empty() => Ok(ValueSource::Env(var.to_string())),
+            _ => Err(format!("unknown value source {}, expected stdin, prompt, file:<path> or env:<VAR>", s)),
+        }
+    }
+}
+
+impl ValueSource {
+    fn read(self, key: &str) -> io::Result<Vec<u8>> {
+        match self {
+            ValueSource::Stdin => {
+                let mut value = vec![];
+                io::stdin().read_to_end(&mut value)?;
+                Ok(value)
             },
+            ValueSource::Prompt => Ok(rpassword::prompt_password(format!("value of {}: ", key))?.into_bytes()),
+            ValueSource::File(path) => fs::read(path),
+            ValueSource::Env(var) => std::env::var(&var)
+                .map(String::into_bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("{}: {}", var, e))),
         }
+    }
+}
 
-        Ok(KVLite { pool, kv_name: kv_name.to_string() })
-    }
-
-    pub async fn get(&self, key: &str) -> Result<String, sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-        
-        let row = QueryBuilder::new(format!(r#"
-                SELECT value FROM {} WHERE key=
-            "#, self.kv_name))
-            .push_bind(key)
-            .build()
-            .fetch_one(&mut conn)
-            .await?;
-        
-        Ok(row.get("value"))
+/// The text given as an argument, or stdin if it's -
+fn text_or_stdin(text: String, key: &str) -> io::Result<String> {
+    match text.as_str() {
+        "-" => Ok(String::from_utf8_lossy(&ValueSource::Stdin.read(key)?).into_owned()),
+        _ => Ok(text),
     }
+}
 
-    pub async fn set(&self, key: &str, value: &str) -> Result<(), sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-        
-        QueryBuilder::new(format!(r#"
-                INSERT OR REPLACE INTO {} VALUES (
-            "#, self.kv_name))
-            .push_bind(key)
-            .push(",")
-            .push_bind(value)
-            .push(")")
-            .build()
-            .execute(&mut conn)
-            .await?;
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Output {
+    /// One result per line, with records as key,value
+    Text,
+    /// A single JSON value per command
+    Json,
+}
 
-        Ok(())
-    }
+#[derive(Subcommand)]
+enum StoreCommands {
+    /// List the stores in the datastore
+    List,
+    /// Create a store, set up for sessions, secrets or config if given a template
+    Create {
+        name: String,
+        #[clap(long)]
+        /// sessions: a day's TTL by default and values up to 64 KiB. secrets: text up to 16 KiB,
+        /// deleted securely and never versioned, in an encrypted datastore. config: JSON up to 1
+        /// MiB, versioned
+        template: Option<Template>,
+    },
+    /// Delete a store and all of its records
+    Drop { name: String },
+    /// Swap the names of two stores in one transaction, so a store built offline replaces one in
+    /// use at once
+    Swap { a: String, b: String },
+    /// Keep expired records readable, with a warning, for this many seconds before they're purged
+    Grace { seconds: u64 },
+    /// Give records set without a TTL this many seconds to live, or none if 0
+    DefaultTtl { seconds: u64 },
+    /// Refuse values larger than this many bytes, or lift the limit if 0
+    MaxValueSize { bytes: u64 },
+    /// Overwrite every deleted value in the datastore file and erase it from the history, as
+    /// delete --secure does
+    SecureDelete {
+        #[clap(long)]
+        /// Let deleted values stay in the file until their pages are reused
+        off: bool,
+    },
+    /// Keep every write to the store in its history, for history, get --version and rollback
+    Versioning {
+        #[clap(long)]
+        /// Stop versioning the store, deleting its history
+        off: bool,
+    },
+    /// Record every write to the store in its audit log, with its time, user, process and a hash
+    /// of the value it replaced
+    Audit {
+        #[clap(long)]
+        /// Stop auditing the store, keeping the log recorded so far
+        off: bool,
+    },
+    /// Append every write to the store to its change feed, numbered in order with the value set,
+    /// for changes tail to read from a position
+    ChangeFeed {
+        #[clap(long)]
+        /// Stop appending to the change feed, keeping the events appended so far
+        off: bool,
+    },
+    /// Store a SHA-256 checksum of every value as it's written, for verify to check records against
+    Checksums {
+        #[clap(long)]
+        /// Stop storing checksums, deleting them
+        off: bool,
+    },
+    /// Require every value written to the store to be any, text, integer, json or reference[:<store>], or print the current type
+    Type { value_type: Option<ValueType> },
+    /// Apply lowercase, trim, slugify or strip-prefix:<prefix> in order to every key given to the
+    /// store, renaming the records already in it and printing how many were renamed, or print the
+    /// current transforms
+    KeyTransform {
+        transforms: Vec<KeyTransform>,
+        #[clap(long, conflicts_with = "transforms")]
+        /// Use keys as they're given again
+        off: bool,
+    },
+    /// Pass every value written to the store through gzip, encrypt or base64 in order, undoing them
+    /// when values are read, rewriting the records already in it and printing how many were
+    /// rewritten, or print the current transforms. gzip:<bytes> and zstd:<bytes> only compress values
+    /// of at least that many bytes. Encrypted values are keyed by a passphrase read from
+    /// DSR_VALUE_PASSPHRASE or prompted for
+    ValueTransform {
+        transforms: Vec<ValueTransform>,
+        #[clap(long, conflicts_with = "transforms")]
+        /// Store values as they're written again
+        off: bool,
+    },
+    /// Keep each distinct value of at least this many bytes once, in a table of blobs keyed by its
+    /// SHA-256 that records refer to, rewriting the records already in the store and printing how
+    /// many were rewritten, or print the current size
+    Dedup {
+        #[clap(value_name = "BYTES")]
+        min_size: Option<u64>,
+        #[clap(long, conflicts_with = "min-size")]
+        /// Store every value in its record again
+        off: bool,
+    },
+}
 
-    pub async fn del(&self, key: &str) -> Result<(), sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-        
-        QueryBuilder::new(format!(r#"
-                DELETE FROM {} WHERE key=
-            "#, self.kv_name))
-            .push_bind(key)
-            .build()
-            .execute(&mut conn)
-            .await?;
+#[derive(Subcommand)]
+enum AuditCommands {
+    /// List the audit log oldest first, as "<time> <kind> <key>" followed by what's known of the
+    /// source, user, pid and hash of the value replaced
+    List {
+        #[clap(long)]
+        /// Only entries for this key
+        key: Option<String>,
+        #[clap(long, value_parser = parse_time, value_name = "TIME")]
+        /// Only entries made at or after this time
+        since: Option<SystemTime>,
+        #[clap(long)]
+        /// Only the latest entries, this many of them
+        limit: Option<u64>,
+    },
+    /// Delete the entries of the audit log made before a time, printing how many were deleted
+    Prune {
+        #[clap(long, value_parser = parse_time, value_name = "TIME")]
+        before: SystemTime,
+    },
+}
 
-        Ok(())
-    }
+#[derive(Subcommand)]
+enum ChangesCommands {
+    /// Print the events of the change feed after a position oldest first, as
+    /// "<seq> <set|delete|expired> <key>", and with --follow each one appended after them
+    Tail {
+        #[clap(long, default_value = "0")]
+        /// Position to read after, the last one already read or 0 for the whole feed
+        since: i64,
+        #[clap(long)]
+        /// Keep printing events as they're appended until interrupted
+        follow: bool,
+        #[clap(long, conflicts_with = "follow")]
+        /// Only the first events after the position, this many of them
+        limit: Option<u64>,
+        #[clap(long, default_value = "500")]
+        /// Milliseconds between polls of the change feed with --follow
+        interval: u64,
+    },
+    /// Delete the events of the change feed up to and including a position, printing how many
+    /// were deleted
+    Prune {
+        #[clap(long)]
+        through: i64,
+    },
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommands {
+    /// Copy the store's live records to a snapshot with a name, printing how many were copied
+    Create { name: String },
+    /// List the store's snapshots oldest first, as "<name> <time> <records>"
+    List,
+    /// Replace every record in the store with a snapshot's, printing how many were restored
+    Restore { name: String },
+    /// Compare the store with a snapshot, printing + for keys only in the store, - for keys only
+    /// in the snapshot and ~ for keys whose values differ
+    Diff {
+        name: String,
+        #[clap(long)]
+        /// Also print the values that differ, the snapshot's after - and the store's after +
+        values: bool,
+    },
+    /// Delete a snapshot
+    Drop { name: String },
+}
+
+#[derive(Subcommand)]
+enum HookCommands {
+    /// Run a command with sh -c whenever a write passes a filter expression, as watch --filter
+    /// takes without tag: or changed, printing the hook's id. The command gets DSR_STORE, DSR_KEY,
+    /// DSR_KIND and, for sets, DSR_VALUE in its environment
+    Add {
+        filter: ChangeFilter,
+        command: String,
+    },
+    /// List the store's hooks as "<id> <filter>: <command>"
+    List,
+    /// Stop running a hook
+    Remove { id: i64 },
+}
+
+#[derive(Subcommand)]
+enum EnvCommands {
+    /// Print "export NAME='value'" lines for eval or source, named after each key uppercased with
+    /// anything that can't be in a variable name replaced by _, so app.db-host becomes APP_DB_HOST
+    Export {
+        #[clap(long)]
+        /// Only records whose key starts with this prefix
+        prefix: Option<String>,
+        #[clap(long, requires = "prefix")]
+        /// Leave the prefix out of the names, so --prefix app. exports app.port as PORT
+        strip_prefix: bool,
+    },
+    /// Set a record for each KEY=value line of a dotenv file, skipping comments and blank lines
+    Import {
+        file: String,
+        #[clap(long)]
+        /// Put this in front of every key
+        prefix: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TagCommands {
+    /// Give a record some tags and print how many it didn't already have
+    Add {
+        key: String,
+        #[clap(required = true)]
+        tags: Vec<String>,
+    },
+    /// Take some tags off a record and print how many it had
+    Rm {
+        key: String,
+        #[clap(required = true)]
+        tags: Vec<String>,
+    },
+    /// List a record's tags
+    List { key: String },
+}
+
+#[derive(Subcommand)]
+enum AclCommands {
+    /// Let a client read the store, or read and write it, replacing any role it had
+    Grant {
+        /// The client, as token:<name>, user:<name> or jwt:<subject>
+        name: String,
+        #[clap(value_parser)]
+        /// read-only or read-write
+        role: Role,
+    },
+    /// Take away a client's role
+    Revoke { name: String },
+    /// List the clients given roles as "<name> <role>"
+    List,
+}
 
+#[derive(Subcommand)]
+enum MaintainCommands {
+    /// Report free space, fragmentation, oversized values, expired records and missing indexes,
+    /// with the maintenance recommended
+    AnalyzeReport {
+        #[clap(long)]
+        /// Carry out the recommended maintenance
+        fix: bool,
+    },
+}
+
+#[derive(Args)]
+struct FilterArgs {
+    #[clap(long)]
+    /// Only records whose key starts with this prefix
+    prefix: Option<String>,
+    #[clap(long)]
+    /// Only records whose value contains this text
+    contains: Option<String>,
+    #[clap(long)]
+    /// Only records whose key matches a glob pattern using * and ?
+    glob: Option<String>,
+    #[clap(long)]
+    /// Only records given this tag with tag add
+    tag: Option<String>,
+    #[clap(long, value_parser = parse_time, value_name = "TIME")]
+    /// Only records last written at or after a time, like 2024-06-01T12:00Z
+    updated_since: Option<SystemTime>,
+    #[clap(long = "type")]
+    /// Only records set with this type, int, float, bool, json, list, set or pointer
+    value_tag: Option<ValueTag>,
+}
 
-    pub async fn keys(&self) -> Result<Vec<SqliteRow>, sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-        
-        let rows = QueryBuilder::new(format!(r#"
-                SELECT key FROM {}
-            "#, self.kv_name))
-            .build()
-            .fetch_all(&mut conn)
-            .await?;
+#[derive(Args)]
+struct OverwriteArgs {
+    #[clap(long)]
+    /// Replace a record already at the new key
+    overwrite: bool,
+    #[clap(long, conflicts_with = "overwrite")]
+    /// Fail if a record is already at the new key (the default)
+    no_overwrite: bool,
+}
+
+#[derive(Args)]
+struct FormatArgs {
+    #[clap(long, value_parser, value_name = "TEMPLATE")]
+    /// Print each record with a template instead, like '{key}={value}'. {key}, {value},
+    /// {created_at} and {updated_at} are filled in, and {{ and }} print braces
+    format: Option<RecordFormat>,
+    #[clap(long, requires = "format")]
+    /// Quote what's filled into the template for a POSIX shell, so lines can be eval'd
+    shell_quote: bool,
+    #[clap(long, requires = "format")]
+    /// End each record with a NUL byte instead of a newline, for xargs -0 or read -d ''
+    null_delimited: bool,
+}
 
-        Ok(rows)
+impl FormatArgs {
+    /// What ends each record
+    fn end(&self) -> char {
+        if self.null_delimited { '\0' } else { '\n' }
+    }
+
+    /// Print a record with the template, returning false without printing if there isn't one
+    fn print(&self, key: &str, value: &[u8], created_at: Option<SystemTime>, updated_at: Option<SystemTime>) -> bool {
+        match &self.format {
+            Some(template) => {
+                print!("{}{}", template.render(key, &String::from_utf8_lossy(value), created_at, updated_at, self.shell_quote), self.end());
+                true
+            },
+            None => false,
+        }
     }
+}
+
+/// A template records are printed with by --format
+#[derive(Debug, Clone)]
+struct RecordFormat(Vec<FormatPart>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FormatPart {
+    Text(String),
+    Key,
+    Value,
+    CreatedAt,
+    UpdatedAt,
+}
 
-    pub async fn values(&self) -> Result<Vec<SqliteRow>, sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-        
-        let rows = QueryBuilder::new(format!(r#"
-                SELECT value FROM {}
-            "#, self.kv_name))
-            .build()
-            .fetch_all(&mut conn)
-            .await?;
+impl FromStr for RecordFormat {
+    type Err = String;
 
-        Ok(rows)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("{{{} isn't closed with a }}", name)),
+                        }
+                    }
+                    let field = match name.as_str() {
+                        "key" => FormatPart::Key,
+                        "value" => FormatPart::Value,
+                        "created_at" => FormatPart::CreatedAt,
+                        "updated_at" => FormatPart::UpdatedAt,
+                        _ => return Err(format!("unknown field {{{}}}, expected {{key}}, {{value}}, {{created_at}} or {{updated_at}}", name)),
+                    };
+                    if !text.is_empty() {
+                        parts.push(FormatPart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(field);
+                },
+                '}' => return Err("unmatched }, use }} to print one".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(FormatPart::Text(text));
+        }
+        Ok(RecordFormat(parts))
     }
+}
 
-    pub async fn records(&self) -> Result<Vec<SqliteRow>, sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-        
-        let rows = QueryBuilder::new(format!(r#"
-                SELECT key,value FROM {}
-            "#, self.kv_name))
-            .build()
-            .fetch_all(&mut conn)
-            .await?;
-        Ok(rows)
+impl RecordFormat {
+    /// Fill in the template for a record, leaving times it doesn't have empty
+    fn render(&self, key: &str, value: &str, created_at: Option<SystemTime>, updated_at: Option<SystemTime>, shell_quote: bool) -> String {
+        let mut line = String::new();
+        for part in &self.0 {
+            let field = match part {
+                FormatPart::Text(text) => {
+                    line.push_str(text);
+                    continue;
+                },
+                FormatPart::Key => key.to_string(),
+                FormatPart::Value => value.to_string(),
+                FormatPart::CreatedAt => created_at.map(format_time).unwrap_or_default(),
+                FormatPart::UpdatedAt => updated_at.map(format_time).unwrap_or_default(),
+            };
+            match shell_quote {
+                true => line.push_str(&shell_quote_text(&field)),
+                false => line.push_str(&field),
+            }
+        }
+        line
     }
+}
 
-    pub async fn contains(&self, key: &str) -> Result<bool, sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
+/// Text quoted for a POSIX shell, left as is if no character in it is special
+fn shell_quote_text(text: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-.,/:@%+=".contains(c);
+    match !text.is_empty() && text.chars().all(safe) {
+        true => text.to_string(),
+        false => format!("'{}'", text.replace('\'', r"'\''")),
+    }
+}
 
-        match QueryBuilder::new(format!(r#"
-                SELECT value FROM {} WHERE key=
-            "#, self.kv_name))
-            .push_bind(key)
-            .build()
-            .fetch_optional(&mut conn)
-            .await? 
-        {
-            Some(_) => Ok(true),
-            None => Ok(false),
+impl From<FilterArgs> for Filter {
+    fn from(args: FilterArgs) -> Self {
+        Filter {
+            prefix: args.prefix,
+            value_contains: args.contains,
+            glob: args.glob,
+            key_tag: args.tag,
+            updated_since: args.updated_since,
+            updated_before: None,
+            value_tag: args.value_tag,
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use sqlx::{QueryBuilder, Row};
+/// The changes watch prints, to the key or prefix given and passing --filter
+fn watch_filter(key: Option<String>, prefix: Option<String>, filter: Option<ChangeFilter>) -> ChangeFilter {
+    let mut filter = filter.unwrap_or_default();
+    filter.globs.extend(key);
+    filter.prefixes.extend(prefix);
+    filter
+}
+
+/// Print an event of a change feed, as a line of JSON for each so a tailed feed can be read as it's printed
+fn print_feed_event(event: &FeedEvent, output: Output) {
+    match output {
+        Output::Json => println!("{}", json!({
+            "seq": event.seq,
+            "at": epoch_secs(event.at),
+            "kind": event.kind.to_string(),
+            "key": event.key,
+            "value": event.value.as_ref().map(|value| String::from_utf8_lossy(value).into_owned()),
+            "source": event.source,
+        })),
+        Output::Text => println!("{} {} {}", event.seq, event.kind, event.key),
+    }
+}
 
-    use crate::KVLite;
+/// Listing options ordered as the --sort and --reverse options ask
+fn sorted(sort: Option<Option<SortBy>>, reverse: bool) -> ListOptions {
+    ListOptions { sort: sort.is_some(), reverse, by: sort.flatten().unwrap_or_default(), ..Default::default() }
+}
 
-    async fn setup_store() -> Result<KVLite, sqlx::Error> {
-        let kv_name = "store";
-        let store = KVLite::new("sqlite://tmp.db", kv_name, false, true).await?;
-        
-        
-        let mut conn = store.pool.acquire().await?;
-        QueryBuilder::new(format!(r#"
-                DROP TABLE IF EXISTS {};
-            "#, kv_name))
-            .build()
-            .execute(&mut conn)
-            .await?;
+/// Print a cursor for the next page to stderr when a limited, key ordered listing may have more results
+fn print_next_cursor(count: u64, last: Option<String>, options: &ListOptions) {
+    if !(options.sort || options.reverse) || options.by != SortBy::Key || options.limit != Some(count) {
+        return;
+    }
+    if let Some(key) = last {
+        eprintln!("cursor: {}", Cursor { key, reverse: options.reverse });
+    }
+}
 
-        KVLite::create_store_table(&mut conn, kv_name).await?;
+/// Print rows as they're streamed, one each ended by end or as one JSON array, so a listing is never all in memory.
+/// Returns how many rows there were and the last one's key.
+async fn print_stream(
+    mut rows: impl Stream<Item = Result<SqliteRow, DsError>> + Unpin,
+    output: Output,
+    text: impl Fn(&SqliteRow) -> String,
+    item: impl Fn(&SqliteRow) -> Value,
+    end: char,
+) -> Result<(u64, Option<String>), DsError> {
+    let mut count = 0;
+    let mut last = None;
+    if output == Output::Json {
+        print!("[");
+    }
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        match output {
+            Output::Json => print!("{}{}", if count == 0 { "" } else { "," }, item(&row)),
+            Output::Text => print!("{}{}", text(&row), end),
+        }
+        count += 1;
+        last = row.try_get("key").ok();
+    }
+    if output == Output::Json {
+        println!("]");
+    }
+    Ok((count, last))
+}
 
-        Ok(store)
+/// Stream every record to a writer in a format, so large stores are never held in memory
+async fn export(store: &KVLite, writer: impl Write + Send, format: Format, filter: Filter) -> Result<(), String> {
+    let mut rows = store.stream_records(&ListOptions { sort: true, filter, ..Default::default() }).await.map_err(|e| e.to_string())?;
+    let mut writer = format::RecordWriter::new(writer, format)?;
+    while let Some(row) = rows.next().await {
+        let row = row.map_err(|e| e.to_string())?;
+        let value = store.untransform_value(row.get("value")).map_err(|e| e.to_string())?;
+        writer.write_with_times(row.get("key"), &String::from_utf8_lossy(&value), row.get("created_at"), row.get("updated_at"))?;
     }
+    writer.finish()?;
+    Ok(())
+}
 
-    #[tokio::test]
-    async fn test_kv_lite() -> Result<(), sqlx::Error> {
-        let store = setup_store().await?;
+/// An error the cli can exit with, by default with status 2
+trait Failure: std::fmt::Display {
+    fn status(&self) -> i32 {
+        2
+    }
+}
 
-        for i in 0..100 {
-            assert!(matches!(store.set(&format!("key{}", i), &format!("value{}", i)).await, Ok(())))
+impl Failure for DsError {
+    /// 1 for a record that doesn't exist, 3 for a datastore locked by another process
+    /// so scripts can retry, and 2 for anything else
+    fn status(&self) -> i32 {
+        match self {
+            DsError::NotFound => 1,
+            DsError::StoreLocked => 3,
+            _ => 2,
         }
+    }
+}
 
-        assert!(store.get("not in store").await.is_err());
-        for i in 0..100 {
-            assert_eq!(store.get(&format!("key{}", i)).await.unwrap(), format!("value{}", i))
+impl Failure for String {}
+impl Failure for io::Error {}
+#[cfg(feature = "scripting")]
+impl Failure for dsr::EvalError {
+    fn status(&self) -> i32 {
+        match self {
+            dsr::EvalError::Database(e) => e.status(),
+            dsr::EvalError::Script(_) => 2,
         }
+    }
+}
+
+/// Print an error to stderr and exit with its status
+fn fail(e: impl Failure) -> ! {
+    eprintln!("error: {}", e);
+    std::process::exit(e.status())
+}
+
+/// Where the daemon of a datastore listens, next to its file
+#[cfg(all(feature = "daemon", unix))]
+fn default_socket(db_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.sock", datastore_file(db_path)))
+}
+
+/// The file of a datastore, without the sqlite: scheme its location may have
+fn datastore_file(db_path: &str) -> &str {
+    db_path.strip_prefix("sqlite://")
+        .or_else(|| db_path.strip_prefix("sqlite:"))
+        .unwrap_or(db_path)
+}
+
+/// Whether two datastore locations are the same file, however each was written
+fn same_datastore(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(datastore_file(a)), fs::canonicalize(datastore_file(b))) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => datastore_file(a) == datastore_file(b),
+    }
+}
+
+/// What a multi-step command was run to do, journaled next to the datastore before it starts
+/// writing and removed once it's finished, so recover can find a run that was interrupted
+#[derive(Serialize, Deserialize)]
+struct Intent {
+    /// The command's arguments, without the program name
+    args: Vec<String>,
+    /// Where it was run from, for its relative paths
+    dir: PathBuf,
+    /// Seconds since the Unix epoch
+    started_at: u64,
+    /// Whether it can be run again, which it can't once the stdin it read is gone
+    resumable: bool,
+}
+
+/// The journaled intent of the command being run, removed when it's dropped. Dropping it before
+/// fail, which exits without dropping anything, keeps a command that failed from looking interrupted.
+struct Journaled(PathBuf);
 
-        let mut keys = store.keys().await.unwrap();
-        keys.sort_by(|a, b| 
-                a.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
-            .cmp(
-                &b.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
-            ));
-        for (i, key) in keys.iter().enumerate() {
-            assert_eq!(key.get::<String, &str>("key"), format!("key{}", i))
+impl Drop for Journaled {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+        if let Some(dir) = self.0.parent() {
+            // only removed once it's empty
+            let _ = fs::remove_dir(dir);
         }
+    }
+}
+
+impl Intent {
+    fn dir(db_path: &str) -> PathBuf {
+        PathBuf::from(format!("{}.intents", datastore_file(db_path)))
+    }
+
+    /// Journal the intent of the command being run on a datastore
+    fn begin(db_path: &str, resumable: bool) -> io::Result<Journaled> {
+        let intent = Intent {
+            args: std::env::args_os().skip(1).map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            dir: std::env::current_dir()?,
+            started_at: epoch_secs(SystemTime::now()),
+            resumable,
+        };
+        let dir = Intent::dir(db_path);
+        fs::create_dir_all(&dir)?;
+        let name = format!("{}-{}", std::process::id(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+        // renamed into place, so recover never reads a half written intent
+        let partial = dir.join(format!("{}.partial", name));
+        fs::write(&partial, serde_json::to_vec(&intent)?)?;
+        let path = dir.join(format!("{}.json", name));
+        fs::rename(partial, &path)?;
+        Ok(Journaled(path))
+    }
 
-        let mut values = store.values().await.unwrap();
-        values.sort_by(|a, b| 
-            a.get::<String,&str>("value")[5..].parse::<i32>().unwrap()
-        .cmp(
-            &b.get::<String,&str>("value")[5..].parse::<i32>().unwrap()
-        ));
-        for (i, value) in values.iter().enumerate() {
-            assert_eq!(value.get::<String, &str>("value"), format!("value{}", i))
+    /// The intents journaled for a datastore, oldest first, with their files. Commands still
+    /// running in other processes are included.
+    fn pending(db_path: &str) -> io::Result<Vec<(PathBuf, Intent)>> {
+        let entries = match fs::read_dir(Intent::dir(db_path)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut intents = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "json") {
+                let intent: Intent = serde_json::from_slice(&fs::read(&path)?)?;
+                intents.push((path, intent));
+            }
         }
+        intents.sort_by_key(|(_, intent)| intent.started_at);
+        Ok(intents)
+    }
+
+    /// The command as it could be typed into a shell
+    fn command(&self) -> String {
+        std::iter::once("dsr".to_string())
+            .chain(self.args.iter().map(|arg| shell_quote_text(arg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
 
-        let mut records = store.records().await.unwrap();
-        records.sort_by(|a, b| 
-            a.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
-        .cmp(
-            &b.get::<String,&str>("key")[3..].parse::<i32>().unwrap()
-        ));
-        for (i, record) in records.iter().enumerate() {
-            assert_eq!(record.get::<String, &str>("key"), format!("key{}", i));
-            assert_eq!(record.get::<String, &str>("value"), format!("value{}", i));
+/// List the commands journaled for a datastore that didn't finish, or run them again or drop them
+fn recover(db_path: &str, resume: bool, rollback: bool, output: Output) -> io::Result<()> {
+    let intents = Intent::pending(db_path)?;
+    if output == Output::Json && !(resume || rollback) {
+        println!("{}", intents.iter()
+            .map(|(_, intent)| json!({ "args": intent.args, "dir": intent.dir, "started_at": intent.started_at, "resumable": intent.resumable }))
+            .collect::<Value>());
+        return Ok(());
+    }
+    for (path, intent) in intents {
+        match () {
+            _ if rollback => {
+                fs::remove_file(&path)?;
+                println!("dropped: {}", intent.command());
+            },
+            _ if resume && !intent.resumable => eprintln!("can't resume {}, it read from stdin", intent.command()),
+            _ if resume => {
+                println!("resuming: {}", intent.command());
+                let status = std::process::Command::new(std::env::current_exe()?)
+                    .args(&intent.args)
+                    .current_dir(&intent.dir)
+                    .status()?;
+                // the command journals itself again while it runs
+                fs::remove_file(&path)?;
+                if !status.success() {
+                    eprintln!("{} failed", intent.command());
+                    std::process::exit(status.code().unwrap_or(2));
+                }
+            },
+            _ => println!("{} {}", format_time(UNIX_EPOCH + Duration::from_secs(intent.started_at)), intent.command()),
         }
+    }
+    let _ = fs::remove_dir(Intent::dir(db_path));
+    Ok(())
+}
 
-        assert!(matches!(store.contains("not in store").await, Ok(false)));
-        for i in 0..100 {
-            assert!(matches!(store.contains(&format!("key{}", i)).await, Ok(true)))
+/// Print the stores of the datastores and how many records each has, and why any couldn't be
+/// opened. Datastores are named with the profiles using them when there's more than one.
+async fn list_stores(datastores: Vec<(String, Vec<String>, KVLiteOptions)>, all: bool, output: Output) {
+    let mut listed = vec![];
+    for (db_path, profiles, options) in datastores {
+        let stats = options.datastore_stats().await;
+        if !all {
+            match stats {
+                Ok(stats) if output == Output::Json => println!("{}", json!(stats.stores.iter()
+                    .map(|store| json!({ "store": store.store, "records": store.records }))
+                    .collect::<Value>())),
+                Ok(stats) => for store in &stats.stores {
+                    println!("{}: {} records", store.store, store.records)
+                },
+                Err(e) => fail(e),
+            }
+            return;
         }
 
-        assert!(matches!(store.del("key1").await, Ok(())));
-        assert!(matches!(store.contains("key1").await, Ok(false)));
+        let file = datastore_file(&db_path);
+        match output {
+            Output::Json => listed.push(match stats {
+                Ok(stats) => json!({
+                    "ds": file,
+                    "profiles": profiles,
+                    "stores": stats.stores.iter().map(|store| json!({ "store": store.store, "records": store.records })).collect::<Value>(),
+                }),
+                Err(e) => json!({ "ds": file, "profiles": profiles, "error": e.to_string() }),
+            }),
+            Output::Text => {
+                match profiles.is_empty() {
+                    true => println!("{}", file),
+                    false => println!("{} ({})", file, profiles.join(", ")),
+                }
+                match stats {
+                    Ok(stats) => for store in &stats.stores {
+                        println!("  {}: {} records", store.store, store.records)
+                    },
+                    Err(e) => println!("  couldn't open: {}", e),
+                }
+            },
+        }
+    }
+    if output == Output::Json {
+        println!("{}", Value::Array(listed));
+    }
+}
 
-        Ok(())
+/// Print the rows of a diff, with + for keys only in the store, - for keys only in what it was
+/// compared with and ~ for keys whose values differ
+fn print_diff(rows: &[SqliteRow], values: bool, output: Output) {
+    let value = |row: &SqliteRow, column: &str| row.get::<Option<Vec<u8>>, &str>(column).map(|value| String::from_utf8_lossy(&value).into_owned());
+    match output {
+        Output::Json => println!("{}", rows.iter()
+            .map(|row| match values {
+                true => json!({ "change": row.get::<String, &str>("change"), "key": row.get::<String, &str>("key"), "value": value(row, "value"), "other_value": value(row, "other_value") }),
+                false => json!({ "change": row.get::<String, &str>("change"), "key": row.get::<String, &str>("key") }),
+            })
+            .collect::<Value>()),
+        Output::Text => for change in rows {
+            print!("{} ", change.get::<String, &str>("change"));
+            println!("{}", change.get::<String, &str>("key"));
+            if values {
+                for (sign, column) in [("-", "other_value"), ("+", "value")] {
+                    for line in value(change, column).iter().flat_map(|value| value.lines()) {
+                        println!("  {} {}", sign, line);
+                    }
+                }
+            }
+        },
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), sqlx::Error> {
-    let default_db_dir = "ds-rust/";
-    let default_db_name = "ds.db";
-    let default_db_prefix = "sqlite://";
+/// Run a command on a store in a server database, which only has the record operations of
+/// KvBackend, failing for any other command
+#[cfg(any(feature = "postgres", feature = "mysql", feature = "libsql", feature = "grpc"))]
+async fn run_on_backend(backend: &impl dsr::KvBackend, command: Commands, database: &str, output: Output) {
+    match command {
+        Commands::Get { key, default, quiet, raw: false, out: None, version: None, as_of: None, resolve: false, format: FormatArgs { format: None, .. }, .. } => {
+            match (backend.get_bytes(&key).await, default) {
+                (Ok(value), _) if output == Output::Json => println!("{}", json!(String::from_utf8_lossy(&value))),
+                (Ok(value), _) => println!("{}", String::from_utf8_lossy(&value)),
+                (Err(DsError::NotFound), Some(default)) if output == Output::Json => println!("{}", json!(default)),
+                (Err(DsError::NotFound), Some(default)) => println!("{}", default),
+                (Err(DsError::NotFound), None) => not_found(&key, quiet),
+                (Err(e), _) => fail(e),
+            }
+        },
+        Commands::Set { key, value: Some(value), stdin: false, file: None, edit: false, prompt: false, value_from: None, content_type: None, if_absent: false, if_equals: None, ttl, tag, .. } if value != "-" => {
+            let options = SetOptions { ttl: ttl.map(Duration::from_secs), tag, ..Default::default() };
+            if let Err(e) = backend.set_with(&key, value.as_bytes(), &options).await {
+                fail(e)
+            }
+        },
+        Commands::Contains { key, quiet } => {
+            let res = match backend.contains(&key).await {
+                Ok(res) => res,
+                Err(e) => fail(e),
+            };
+            match output {
+                _ if quiet => (),
+                Output::Json => println!("{}", json!(res)),
+                Output::Text => println!("{}", res),
+            }
+            if !res {
+                std::process::exit(1);
+            }
+        },
+        Commands::Delete { key: Some(key), cascade: false, restrict: false, secure: false, .. } => {
+            if let Err(e) = backend.del(&key).await {
+                fail(e)
+            }
+        },
+        Commands::Keys { sort: None, reverse: false, limit, cursor: None, depth: None, filter: filter @ FilterArgs { tag: None, updated_since: None, value_tag: None, .. } } => {
+            match backend.list_keys(&filter.into(), limit).await {
+                Ok(keys) if output == Output::Json => println!("{}", json!(keys)),
+                Ok(keys) => for key in keys {
+                    println!("{}", key);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        _ => fail(format!("only get, set, delete, contains and keys, without options beyond a TTL, tag, default or filter, work with a {} datastore", database)),
+    }
+}
 
-    let args = Cli::parse();
+/// Send the writes queued for a gRPC server, saying on stderr which conflicted or were refused.
+/// They stay queued if it still can't be reached.
+#[cfg(feature = "grpc")]
+async fn replay_queued(client: &dsr::grpc::Client) {
+    let replayed = match client.replay().await {
+        Ok(replayed) => replayed,
+        Err(DsError::Io(e)) if e.kind() == io::ErrorKind::ConnectionRefused => return,
+        Err(e) => fail(e),
+    };
+    let describe = |write: &dsr::grpc::QueuedWrite| match write.value {
+        Some(_) => format!("set of {} queued at {}", write.key, format_time(write.queued_at)),
+        None => format!("delete of {} queued at {}", write.key, format_time(write.queued_at)),
+    };
+    for write in &replayed.conflicts {
+        eprintln!("conflict: the {} wasn't sent, as the record was changed on the server since", describe(write));
+        if let Some(value) = &write.value {
+            eprintln!("  it set: {}", String::from_utf8_lossy(value));
+        }
+    }
+    for (write, e) in &replayed.rejected {
+        eprintln!("the {} was refused: {}", describe(write), e);
+    }
+    if replayed.applied > 0 {
+        eprintln!("sent {} queued writes", replayed.applied);
+    }
+}
 
-    let db_path = match args.ds {
-        Some(db_path) => db_path,
-        None => {
-            let mut db_path = dirs::config_dir().expect("couldn't find a default db location");
-            db_path.push(default_db_dir);
-            fs::create_dir_all(&db_path)?;
-            db_path.push(default_db_name);
-            let db_path = db_path.to_str().expect("couldn't find a default db location").to_string();
-            format!("{}{}", default_db_prefix, db_path)
+/// Run a command on a store served over gRPC, which has the record operations of KvBackend and watch
+#[cfg(feature = "grpc")]
+async fn run_on_grpc(client: &dsr::grpc::Client, command: Commands, output: Output) {
+    match command {
+        Commands::Watch { key, prefix, filter, interval } => {
+            let filter = watch_filter(key, prefix, filter);
+            let mut changes = match client.watch(&filter, Duration::from_millis(interval)).await {
+                Ok(changes) => Box::pin(changes),
+                Err(e) => fail(e),
+            };
+            while let Some(change) = changes.next().await {
+                match change {
+                    Ok(change) if output == Output::Json => println!("{}", json!({ "kind": change.kind.to_string(), "key": change.key, "value": change.value })),
+                    Ok(change) => println!("{} {}", change.kind, change.key),
+                    Err(e) => fail(e),
+                }
+            }
         },
+        command => run_on_backend(client, command, "gRPC", output).await,
+    }
+}
+
+/// Run a command through the daemon listening on a socket, printing what running it directly would.
+/// Returns false without doing anything if no daemon is listening or it can't run the command.
+#[cfg(all(feature = "daemon", unix))]
+async fn run_through_daemon(socket: &Path, command: &Commands, store: &str, source: &str, output: Output) -> Result<bool, DsError> {
+    let supported = match command {
+        Commands::Get { raw: false, out: None, version: None, as_of: None, resolve: false, format: FormatArgs { format: None, .. }, .. } => true,
+        Commands::Set { value: Some(value), stdin: false, file: None, edit: false, prompt: false, value_from: None, secret: false, content_type: None, tag: None, if_absent: false, if_equals: None, .. } => value != "-",
+        Commands::Contains { .. } | Commands::Ttl { .. } | Commands::Watch { .. } => true,
+        Commands::Delete { key: Some(_), cascade: false, restrict: false, secure: false, .. } => true,
+        Commands::Keys { sort: None, reverse: false, limit: None, cursor: None, depth: None, filter: FilterArgs { contains: None, glob: None, tag: None, updated_since: None, value_tag: None, .. } } => true,
+        _ => false,
+    };
+    if !supported {
+        return Ok(false);
+    }
+    let mut client = match dsr::daemon::Client::connect(socket).await? {
+        Some(client) => client,
+        None => return Ok(false),
     };
 
-    let store = KVLite::new(&db_path, "store", false, true).await?;
-    
-    
-    match args.command {
-        Commands::Set { key, value } => {
-            match store.set(&key, &value).await {
-                Ok(_) => (),
-                Err(e) => println!("{:?}", e),
-            } 
+    match command {
+        Commands::Get { key, default, quiet, .. } => {
+            match (client.get_with_meta(store, key).await, default) {
+                (Ok((value, meta)), _) => {
+                    warn_expired(&meta);
+                    match output {
+                        Output::Json => println!("{}", json!(render_value(value, &meta, false))),
+                        Output::Text => println!("{}", render_value(value, &meta, io::stdout().is_terminal())),
+                    }
+                },
+                (Err(DsError::NotFound), Some(default)) if output == Output::Json => println!("{}", json!(default)),
+                (Err(DsError::NotFound), Some(default)) => println!("{}", default),
+                (Err(DsError::NotFound), None) => not_found(key, *quiet),
+                (Err(e), _) => fail(e),
+            }
         },
-        Commands::Contains { key } => {
-            match store.contains(&key).await {
-                Ok(res) => println!("{}", res),
-                Err(e) => println!("{:?}", e),
-            } 
+        Commands::Set { key, value, ttl, .. } => {
+            let value = value.as_deref().unwrap_or_default().as_bytes();
+            if let Err(e) = client.set(store, key, value, ttl.map(Duration::from_secs), Some(source)).await {
+                fail(e)
+            }
         },
-        Commands::Get { key } => {
-            match store.get(&key).await {
-                Ok(res) => println!("{}", res),
-                Err(e) => println!("{:?}", e),
-            } 
+        Commands::Contains { key, quiet } => {
+            let res = match client.contains(store, key).await {
+                Ok(res) => res,
+                Err(e) => fail(e),
+            };
+            match output {
+                _ if *quiet => (),
+                Output::Json => println!("{}", json!(res)),
+                Output::Text => println!("{}", res),
+            }
+            if !res {
+                std::process::exit(1);
+            }
         },
-        Commands::Delete { key } => {
-            match store.del(&key).await {
-                Ok(_) => (),
-                Err(e) => println!("{:?}", e),
-            } 
+        Commands::Delete { key: Some(key), .. } => {
+            if let Err(e) = client.del(store, key).await {
+                fail(e)
+            }
         },
-        Commands::Keys =>  {
-            match store.keys().await {
-                Ok(res) => for key in res {
-                    println!("{}", key.get::<String, &str>("key"))
-                },
-                Err(e) => println!("{:?}", e),
-            } 
+        Commands::Ttl { key } => {
+            match client.ttl(store, key).await {
+                Ok(ttl) if output == Output::Json => println!("{}", json!(ttl.map(|ttl| ttl.as_secs()))),
+                Ok(Some(ttl)) => println!("{}", ttl.as_secs()),
+                Ok(None) => println!("-1"),
+                Err(e) => fail(e),
+            }
         },
-        Commands::Values =>  {
-            match store.values().await {
-                Ok(res) => for value in res {
-                    println!("{}", value.get::<String, &str>("value"))
+        Commands::Keys { filter, .. } => {
+            match client.keys(store, filter.prefix.as_deref()).await {
+                Ok(keys) if output == Output::Json => println!("{}", json!(keys)),
+                Ok(keys) => for key in keys {
+                    println!("{}", key);
                 },
-                Err(e) => println!("{:?}", e),
-            } 
+                Err(e) => fail(e),
+            }
         },
-        Commands::Records =>  {
-            match store.records().await {
-                Ok(res) => for record in res {
-                    print!("{},", record.get::<String, &str>("key"));
-                    println!("{}", record.get::<String, &str>("value"))
-                },
-                Err(e) => println!("{:?}", e),
-            } 
+        Commands::Watch { key, prefix, filter, interval } => {
+            let filter = watch_filter(key.clone(), prefix.clone(), filter.clone());
+            let mut changes = Box::pin(client.watch(store, &filter, Duration::from_millis(*interval)).await?);
+            while let Some(change) = changes.next().await {
+                match change {
+                    Ok(change) if output == Output::Json => println!("{}", json!({ "kind": change.kind.to_string(), "key": change.key, "value": change.value })),
+                    Ok(change) => println!("{} {}", change.kind, change.key),
+                    Err(e) => fail(e),
+                }
+            }
+        },
+        _ => unreachable!("only supported commands are run through the daemon"),
+    }
+    Ok(true)
+}
+
+/// What import does with records whose keys the store already has
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ImportConflicts {
+    Skip,
+    Overwrite,
+    Fail,
+    Newest,
+}
+
+/// A shell completions are printed for
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// What completion scripts need to know about a command
+struct CompletedCommand {
+    name: String,
+    about: String,
+    flags: Vec<(String, String)>,
+    /// Names of its own subcommands, like those of store
+    subcommands: Vec<String>,
+    /// Whether its first argument is a key, completed from the store
+    takes_key: bool,
+}
+
+/// The first line of a command or argument's help
+fn summary(help: Option<&str>) -> String {
+    help.and_then(|help| help.lines().next()).unwrap_or_default().to_string()
+}
+
+fn long_flags(command: &clap::Command) -> Vec<(String, String)> {
+    let mut flags: Vec<(String, String)> = vec![];
+    for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        // a command's own --version replaces the one clap adds
+        match arg.get_long().map(|long| format!("--{}", long)) {
+            Some(flag) if !flags.iter().any(|(known, _)| *known == flag) => flags.push((flag, summary(arg.get_help()))),
+            _ => (),
+        }
+    }
+    flags
+}
+
+fn completed_commands(cli: &clap::Command) -> Vec<CompletedCommand> {
+    cli.get_subcommands()
+        .filter(|command| !command.is_hide_set())
+        .map(|command| CompletedCommand {
+            name: command.get_name().to_string(),
+            about: summary(command.get_about()),
+            flags: long_flags(command),
+            subcommands: command.get_subcommands().map(|command| command.get_name().to_string()).collect(),
+            takes_key: command.get_positionals().next().is_some_and(|arg| arg.get_id() == "key"),
+        })
+        .collect()
+}
+
+/// A completion script for a shell, generated from the CLI's commands and flags. Keys are completed
+/// by running dsr keys with the --ds, --store and --profile already typed, so they're always the
+/// store's current keys.
+fn completions(shell: Shell) -> String {
+    let cli = Cli::command();
+    let commands = completed_commands(&cli);
+    let globals = long_flags(&cli);
+    // values of the flags given before the command mustn't be taken for it
+    let valued: Vec<String> = cli.get_arguments()
+        .filter(|arg| arg.is_takes_value_set())
+        .filter_map(|arg| arg.get_long())
+        .filter(|long| !["ds", "store", "profile"].contains(long))
+        .map(|long| format!("--{}", long))
+        .collect();
+    let key_commands: Vec<&str> = commands.iter().filter(|command| command.takes_key).map(|command| command.name.as_str()).collect();
+    let join = |words: &mut dyn Iterator<Item = &str>| words.collect::<Vec<_>>().join(" ");
+
+    match shell {
+        Shell::Bash => {
+            let mut script = format!(r#"_dsr() {{
+    # COMP_WORDS splits keys and paths at colons, so the line is split at spaces instead
+    local line=${{COMP_LINE:0:COMP_POINT}}
+    local cur=${{line##*[[:space:]]}} cmd="" i
+    local -a words opts=()
+    read -ra words <<< "${{line%"$cur"}}"
+    for ((i = 1; i < ${{#words[@]}}; i++)); do
+        case ${{words[i]}} in
+            --ds|--store|--profile) opts+=("${{words[i]}}" "${{words[i+1]}}"); ((i++)) ;;
+            {valued}) ((i++)) ;;
+            -*) ;;
+            *) [[ -z $cmd ]] && cmd=${{words[i]}} ;;
+        esac
+    done
+    local IFS=$'\n'
+    if [[ -z $cmd ]]; then
+        COMPREPLY=($(compgen -W "$(printf '%s\n' {commands} {globals})" -- "$cur"))
+    elif [[ $cur == -* ]]; then
+        case $cmd in
+"#,
+                valued = valued.join("|"),
+                commands = join(&mut commands.iter().map(|command| command.name.as_str())),
+                globals = join(&mut globals.iter().map(|(flag, _)| flag.as_str())));
+            for command in commands.iter().filter(|command| !command.flags.is_empty()) {
+                script += &format!("            {}) COMPREPLY=($(compgen -W \"$(printf '%s\\n' {})\" -- \"$cur\")) ;;\n",
+                    command.name, join(&mut command.flags.iter().map(|(flag, _)| flag.as_str())));
+            }
+            script += "        esac\n    else\n        case $cmd in\n";
+            for command in commands.iter().filter(|command| !command.subcommands.is_empty()) {
+                script += &format!("            {}) COMPREPLY=($(compgen -W \"$(printf '%s\\n' {})\" -- \"$cur\")) ;;\n",
+                    command.name, command.subcommands.join(" "));
+            }
+            script += &format!(r#"            {}) COMPREPLY=($(dsr "${{opts[@]}}" --read-only keys --sort --prefix "$cur" --limit 200 2>/dev/null)) ;;
+        esac
+    fi
+    # bash splits words at colons, so only what follows the last one is replaced
+    if [[ $COMP_WORDBREAKS == *:* && $cur == *:* ]]; then
+        local colon_word=${{cur%"${{cur##*:}}"}}
+        COMPREPLY=("${{COMPREPLY[@]#"$colon_word"}}")
+    fi
+}}
+complete -o default -o nosort -F _dsr dsr 2>/dev/null || complete -o default -F _dsr dsr
+"#, key_commands.join("|"));
+            script
+        },
+        Shell::Zsh => {
+            let quote = |text: &str| text.replace('\'', r"'\''");
+            let mut script = format!(r#"#compdef dsr
+
+_dsr() {{
+    local -a opts
+    local i cmd
+    for ((i = 2; i < CURRENT; i++)); do
+        case ${{words[i]}} in
+            --ds|--store|--profile) opts+=("${{words[i]}}" "${{words[i+1]}}"); ((i++)) ;;
+            {valued}) ((i++)) ;;
+            -*) ;;
+            *) [[ -z $cmd ]] && cmd=${{words[i]}} ;;
+        esac
+    done
+    if [[ -z $cmd && $PREFIX == -* ]]; then
+        local -a flags=(
+"#, valued = valued.join("|"));
+            for (flag, help) in &globals {
+                script += &format!("            '{}:{}'\n", flag, quote(&help.replace(':', r"\:")));
+            }
+            script += "        )\n        _describe flag flags\n    elif [[ -z $cmd ]]; then\n        local -a commands=(\n";
+            for command in &commands {
+                script += &format!("            '{}:{}'\n", command.name, quote(&command.about.replace(':', r"\:")));
+            }
+            script += "        )\n        _describe command commands\n    elif [[ $PREFIX == -* ]]; then\n        case $cmd in\n";
+            for command in commands.iter().filter(|command| !command.flags.is_empty()) {
+                script += &format!("            ({}) compadd -- {} ;;\n", command.name, join(&mut command.flags.iter().map(|(flag, _)| flag.as_str())));
+            }
+            script += "        esac\n    else\n        case $cmd in\n";
+            for command in commands.iter().filter(|command| !command.subcommands.is_empty()) {
+                script += &format!("            ({}) compadd -- {} ;;\n", command.name, command.subcommands.join(" "));
+            }
+            script += &format!(r#"            ({}) compadd -- ${{(f)"$(dsr $opts --read-only keys --sort --prefix "$PREFIX" --limit 200 2>/dev/null)"}} ;;
+        esac
+    fi
+}}
+
+if [[ $zsh_eval_context[-1] == loadautofunc ]]; then
+    _dsr "$@"
+else
+    compdef _dsr dsr
+fi
+"#, key_commands.join("|"));
+            script
+        },
+        Shell::Fish => {
+            let quote = |text: &str| text.replace('\\', r"\\").replace('\'', r"\'");
+            let mut script = String::from(r#"function __dsr_keys
+    set -l tokens (commandline -opc)
+    set -l opts
+    for i in (seq (count $tokens))
+        switch $tokens[$i]
+            case --ds --store --profile
+                set -a opts $tokens[$i] $tokens[(math $i + 1)]
+        end
+    end
+    dsr $opts --read-only keys --sort --prefix (commandline -ct) --limit 200 2>/dev/null
+end
+
+"#);
+            for (flag, help) in &globals {
+                script += &format!("complete -c dsr -n __fish_use_subcommand -l {} -d '{}'\n", &flag[2..], quote(help));
+            }
+            for command in &commands {
+                script += &format!("complete -c dsr -n __fish_use_subcommand -f -a {} -d '{}'\n", command.name, quote(&command.about));
+                for (flag, help) in &command.flags {
+                    script += &format!("complete -c dsr -n '__fish_seen_subcommand_from {}' -l {} -d '{}'\n", command.name, &flag[2..], quote(help));
+                }
+                if !command.subcommands.is_empty() {
+                    script += &format!("complete -c dsr -n '__fish_seen_subcommand_from {}' -f -a '{}'\n", command.name, command.subcommands.join(" "));
+                }
+            }
+            script += &format!("complete -c dsr -n '__fish_seen_subcommand_from {}' -f -a '(__dsr_keys)'\n", key_commands.join(" "));
+            script
+        },
+    }
+}
+
+/// Keys arranged by their levels, each level's names in the order they were first listed
+#[derive(Default)]
+struct KeyTree(Vec<(String, KeyTree)>);
+
+impl KeyTree {
+    fn insert<'a>(&mut self, mut levels: impl Iterator<Item = &'a str>) {
+        let level = match levels.next() {
+            Some(level) => level,
+            None => return,
+        };
+        // keys are listed in order, so a level is nearly always the last one added
+        let i = match self.0.iter().rposition(|(name, _)| name == level) {
+            Some(i) => i,
+            None => {
+                self.0.push((level.to_string(), KeyTree::default()));
+                self.0.len() - 1
+            },
+        };
+        self.0[i].1.insert(levels);
+    }
+
+    fn print(&self, indent: usize) {
+        for (name, children) in &self.0 {
+            println!("{}{}", "  ".repeat(indent), name);
+            children.print(indent + 1);
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        Value::Object(self.0.iter().map(|(name, children)| (name.clone(), children.to_json())).collect())
+    }
+}
+
+/// Report a missing record on stderr unless quiet, and exit with status 1
+fn not_found(key: &str, quiet: bool) -> ! {
+    if !quiet {
+        eprintln!("{} not found", key);
+    }
+    std::process::exit(1)
+}
+
+/// Ask a yes or no question on the terminal, answering no when stdin isn't one
+fn confirm(question: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Parse a batch command, where a set's value is the rest of the line
+fn parse_batch_op(line: &str) -> Result<BatchOp, String> {
+    let line = line.trim_start();
+    let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim_start();
+    let (key, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    match (command, key, value.trim_start()) {
+        ("set" | "get" | "contains" | "delete" | "del", "", _) => Err(format!("expected a key after {}", command)),
+        ("set", key, value) => Ok(BatchOp::Set(key.to_string(), value.to_string())),
+        ("get", key, "") => Ok(BatchOp::Get(key.to_string())),
+        ("contains", key, "") => Ok(BatchOp::Contains(key.to_string())),
+        ("delete" | "del", key, "") => Ok(BatchOp::Delete(key.to_string())),
+        ("get" | "contains" | "delete" | "del", _, _) => Err(format!("unexpected arguments to {}", command)),
+        _ => Err(format!("unknown command {}", command)),
+    }
+}
+
+/// Render a value for reading by its content type: JSON is pretty-printed
+/// and binary values are base64 encoded. On a terminal untagged JSON objects and arrays
+/// are pretty-printed too, and highlighted unless NO_COLOR is set.
+fn render_value(value: Vec<u8>, meta: &RecordMeta, terminal: bool) -> String {
+    if let Ok(json) = serde_json::from_slice::<Value>(&value) {
+        let structured = matches!(json, Value::Object(_) | Value::Array(_));
+        if terminal && structured && std::env::var_os("NO_COLOR").is_none() {
+            let mut out = String::new();
+            highlight_json(&json, 0, &mut out);
+            return out;
+        }
+        if meta.is_json() || (terminal && structured) {
+            return serde_json::to_string_pretty(&json).unwrap_or_default();
+        }
+    }
+    match String::from_utf8(value) {
+        Ok(text) if !meta.is_binary() => text,
+        Ok(text) => STANDARD.encode(text),
+        Err(e) => STANDARD.encode(e.as_bytes()),
+    }
+}
+
+/// Pretty-print JSON like serde_json does, colouring keys, strings and other values
+fn highlight_json(json: &Value, indent: usize, out: &mut String) {
+    const KEY: &str = "\x1b[34m";
+    const STRING: &str = "\x1b[32m";
+    const LITERAL: &str = "\x1b[33m";
+    const RESET: &str = "\x1b[0m";
+
+    let pad = |indent: usize| "  ".repeat(indent);
+    match json {
+        Value::Object(map) if !map.is_empty() => {
+            out.push_str("{\n");
+            for (i, (key, value)) in map.iter().enumerate() {
+                out.push_str(&format!("{}{}{}{}: ", pad(indent + 1), KEY, Value::from(key.as_str()), RESET));
+                highlight_json(value, indent + 1, out);
+                out.push_str(if i + 1 < map.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&format!("{}}}", pad(indent)));
         },
+        Value::Array(values) if !values.is_empty() => {
+            out.push_str("[\n");
+            for (i, value) in values.iter().enumerate() {
+                out.push_str(&pad(indent + 1));
+                highlight_json(value, indent + 1, out);
+                out.push_str(if i + 1 < values.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&format!("{}]", pad(indent)));
+        },
+        Value::Object(_) | Value::Array(_) => out.push_str(&json.to_string()),
+        Value::String(_) => out.push_str(&format!("{}{}{}", STRING, json, RESET)),
+        _ => out.push_str(&format!("{}{}{}", LITERAL, json, RESET)),
+    }
+}
+
+/// Open $EDITOR (or vi) on a file holding value and read back what was written,
+/// without the newline editors add at the end. None if it was left empty.
+fn edit_value(value: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let path = std::env::temp_dir().join(format!("dsr-edit-{}", std::process::id()));
+    fs::write(&path, value)?;
+
+    let editor = std::env::var("VISUAL").or_else(|_| std::env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    // editors are often given with arguments, like "code --wait"
+    let mut words = editor.split_whitespace();
+    let status = std::process::Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(&path)
+        .status();
+    let value = fs::read(&path);
+    let _ = fs::remove_file(&path);
+
+    if !status?.success() {
+        return Err(io::Error::other("the editor exited with an error"));
+    }
+    let mut value = value?;
+    if value.ends_with(b"\n") {
+        value.pop();
+        if value.ends_with(b"\r") {
+            value.pop();
+        }
+    }
+    Ok(if value.is_empty() { None } else { Some(value) })
+}
+
+/// Ask on the terminal how to resolve a record two stores being merged have different, showing
+/// the lines of its values that differ, ours after - and theirs after +
+fn resolve_conflict(conflict: &MergeConflict) -> Result<MergeChoice, DsError> {
+    let (ours, theirs) = (String::from_utf8_lossy(&conflict.ours.0), String::from_utf8_lossy(&conflict.theirs.0));
+    let (ours_lines, theirs_lines): (Vec<&str>, Vec<&str>) = (ours.lines().collect(), theirs.lines().collect());
+    // the lines both start and end with are shown once
+    let start = ours_lines.iter().zip(&theirs_lines).take_while(|(a, b)| a == b).count();
+    let end = ours_lines[start..].iter().rev().zip(theirs_lines[start..].iter().rev()).take_while(|(a, b)| a == b).count();
+    eprintln!("{} differs:", conflict.key);
+    for line in &ours_lines[..start] {
+        eprintln!("    {}", line);
+    }
+    for line in &ours_lines[start..ours_lines.len() - end] {
+        eprintln!("  - {}", line);
+    }
+    for line in &theirs_lines[start..theirs_lines.len() - end] {
+        eprintln!("  + {}", line);
+    }
+    for line in &ours_lines[ours_lines.len() - end..] {
+        eprintln!("    {}", line);
+    }
+
+    loop {
+        eprint!("keep [o]urs, take [t]heirs, [e]dit or [q]uit? ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        // nothing's been written yet, so stopping leaves the store as it was
+        if io::stdin().read_line(&mut answer)? == 0 {
+            fail("the merge was stopped, nothing was merged".to_string());
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "o" | "ours" => return Ok(MergeChoice::Ours),
+            "t" | "theirs" => return Ok(MergeChoice::Theirs),
+            "e" | "edit" => {
+                let both = format!("<<<<<<< ours\n{}\n=======\n{}\n>>>>>>> theirs\n", ours, theirs);
+                // a value left empty asks again
+                if let Some(value) = edit_value(both.as_bytes())? {
+                    return Ok(MergeChoice::Value(value));
+                }
+            },
+            "q" | "quit" => fail("the merge was stopped, nothing was merged".to_string()),
+            _ => (),
+        }
+    }
+}
+
+/// The format of a file records were exported to, None if it's a datastore, from its first
+/// bytes or its extension and JSON by default
+fn guess_format(path: &str) -> io::Result<Option<Format>> {
+    let mut magic = [0; 16];
+    let read = fs::File::open(datastore_file(path))?.read(&mut magic)?;
+    if &magic[..read] == b"SQLite format 3\0" {
+        return Ok(None);
+    }
+    Ok(match std::path::Path::new(path).extension().and_then(|extension| extension.to_str()) {
+        Some("db" | "sqlite" | "sqlite3") => None,
+        Some(extension) => Some(extension.parse().unwrap_or(Format::Json)),
+        None => Some(Format::Json),
+    })
+}
+
+/// A record's value and metadata, or with resolve the content a pointer record points at
+async fn get_record(store: &KVLite, key: &str, resolve: bool) -> Result<(Vec<u8>, RecordMeta), DsError> {
+    match resolve {
+        true => store.get_resolved(key).await,
+        false => store.get_with_meta(key).await,
+    }
+}
+
+/// Warn on stderr when a record is only readable because of the store's grace period
+fn warn_expired(meta: &RecordMeta) {
+    if meta.expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now()) {
+        eprintln!("warning: {} has expired and is only readable during the store's grace period", meta.key);
+    }
+}
+
+/// Seconds since the Unix epoch
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Format a time as an ISO 8601 date and time in UTC
+fn format_time(time: SystemTime) -> String {
+    let secs = epoch_secs(time) as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // days since the epoch to a civil date, using Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Parse a time as parse_time does, or an age like 30d, 12h, 15m or 45s as that long before now
+fn parse_age(s: &str) -> Result<SystemTime, String> {
+    let unit = match s.chars().last() {
+        Some('d') => 86400,
+        Some('h') => 3600,
+        Some('m') => 60,
+        Some('s') => 1,
+        _ => return parse_time(s),
+    };
+    let age = s[..s.len() - 1].parse::<u64>().map_err(|_| format!("invalid age {}, expected one like 30d", s))?;
+    SystemTime::now().checked_sub(Duration::from_secs(age.saturating_mul(unit)))
+        .filter(|at| *at >= UNIX_EPOCH)
+        .ok_or_else(|| format!("{} is before the epoch", s))
+}
+
+/// Parse an ISO 8601 date, or date and time with minutes or seconds and an optional Z or
+/// UTC offset, taking times without one as UTC. Seconds since the epoch are taken too.
+fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("invalid time {}, expected one like 2024-06-01T12:00Z", s);
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, "00:00"));
+    let number = |part: Option<&str>, len: usize| part
+        .filter(|part| part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|part| part.parse::<i64>().ok())
+        .ok_or_else(invalid);
+
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (number(parts.next(), 4)?, number(parts.next(), 2)?, number(parts.next(), 2)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    let (time, offset) = match time.strip_suffix('Z') {
+        Some(time) => (time, 0),
+        None => match time.find(['+', '-']) {
+            Some(at) => {
+                let (time, zone) = time.split_at(at);
+                let digits = zone[1..].replace(':', "");
+                let offset = number(digits.get(..2), 2)? * 3600 + number(Some(digits.get(2..).filter(|m| !m.is_empty()).unwrap_or("00")), 2)? * 60;
+                (time, if zone.starts_with('-') { -offset } else { offset })
+            },
+            None => (time, 0),
+        },
+    };
+    let mut parts = time.splitn(3, ':');
+    let (hours, minutes) = (number(parts.next(), 2)?, number(parts.next(), 2)?);
+    let seconds = match parts.next() {
+        Some(seconds) => number(seconds.split('.').next(), 2)?,
+        None => 0,
+    };
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return Err(invalid());
+    }
+
+    // a civil date to days since the epoch, using Howard Hinnant's days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + hours * 3600 + minutes * 60 + seconds - offset;
+    u64::try_from(secs).map(|secs| UNIX_EPOCH + Duration::from_secs(secs)).map_err(|_| invalid())
+}
+
+/// A record's value as it was written, undoing the store's value transforms, as text, replacing
+/// bytes that aren't UTF-8
+fn value_text(store: &KVLite, row: &SqliteRow) -> String {
+    match store.untransform_value(row.get("value")) {
+        Ok(value) => String::from_utf8_lossy(&value).into_owned(),
+        Err(e) => fail(e),
+    }
+}
+
+fn records_json(store: &KVLite, rows: &[SqliteRow]) -> Value {
+    rows.iter()
+        .map(|row| json!({ "key": row.get::<String, &str>("key"), "value": value_text(store, row) }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use serde_json::json;
+    use dsr::RecordMeta;
+
+    use crate::{FormatPart, RecordFormat, highlight_json, parse_age, render_value, shell_quote_text};
+
+    fn meta(content_type: Option<&str>) -> RecordMeta {
+        RecordMeta { key: "key".to_string(), size: 0, expires_at: None, content_type: content_type.map(str::to_string), created_at: None, updated_at: None, tag: None, pinned: false, source: None }
+    }
+
+    #[test]
+    fn test_render_value() {
+        let doc = br#"{"a":[1,"two"],"b":{}}"#.to_vec();
+        let pretty = "{\n  \"a\": [\n    1,\n    \"two\"\n  ],\n  \"b\": {}\n}";
+
+        // JSON is left as it is unless it's typed as JSON or printed to a terminal
+        assert_eq!(render_value(doc.clone(), &meta(None), false), r#"{"a":[1,"two"],"b":{}}"#);
+        assert_eq!(render_value(doc.clone(), &meta(Some("application/json")), false), pretty);
+        assert_eq!(render_value(b"5".to_vec(), &meta(None), true), "5");
+        assert_eq!(render_value(b"not json".to_vec(), &meta(None), true), "not json");
+        assert_eq!(render_value(b"hi".to_vec(), &meta(Some("application/octet-stream")), false), "aGk=");
+        assert_eq!(render_value(vec![0, 255], &meta(None), false), "AP8=");
+
+        // highlighting lays JSON out as pretty-printing does, colouring keys, strings and literals
+        let mut highlighted = String::new();
+        highlight_json(&json!({"a": [1, "two"], "b": {}}), 0, &mut highlighted);
+        assert!(highlighted.contains("\x1b[34m\"a\"\x1b[0m: ") && highlighted.contains("\x1b[32m\"two\"\x1b[0m") && highlighted.contains("\x1b[33m1\x1b[0m"));
+        let plain = highlighted.replace("\x1b[34m", "").replace("\x1b[32m", "").replace("\x1b[33m", "").replace("\x1b[0m", "");
+        assert_eq!(plain, pretty);
+    }
+
+    #[test]
+    fn test_record_format() {
+        let format: RecordFormat = "{{{key}}}={value} at {created_at}".parse().unwrap();
+        assert_eq!(format.0, vec![FormatPart::Text("{".to_string()), FormatPart::Key, FormatPart::Text("}=".to_string()), FormatPart::Value, FormatPart::Text(" at ".to_string()), FormatPart::CreatedAt]);
+        assert_eq!(format.render("a b", "it's", None, None, false), "{a b}=it's at ");
+        assert_eq!(format.render("a b", "it's", Some(UNIX_EPOCH), None, true), r"{'a b'}='it'\''s' at 1970-01-01T00:00:00Z");
+
+        assert_eq!("{key".parse::<RecordFormat>().unwrap_err(), "{key isn't closed with a }");
+        assert!("{size}".parse::<RecordFormat>().unwrap_err().starts_with("unknown field {size}"));
+        assert!("key}".parse::<RecordFormat>().is_err());
+
+        assert_eq!(shell_quote_text("app.db-host:5432/x"), "app.db-host:5432/x");
+        assert_eq!(shell_quote_text(""), "''");
+        assert_eq!(shell_quote_text("$HOME `x` \n"), "'$HOME `x` \n'");
+    }
+
+    #[test]
+    fn test_parse_age() {
+        let ago = |age: &str| {
+            let at = parse_age(age).unwrap();
+            SystemTime::now().duration_since(at).unwrap()
+        };
+        let about = |age: Duration, expected: u64| age >= Duration::from_secs(expected) && age < Duration::from_secs(expected + 5);
+        assert!(about(ago("30d"), 30 * 86400));
+        assert!(about(ago("12h"), 12 * 3600));
+        assert!(about(ago("15m"), 15 * 60));
+        assert!(about(ago("45s"), 45));
+        assert!(about(ago("0s"), 0));
+
+        // anything else is taken for a time
+        assert_eq!(parse_age("2024-06-01T12:00Z"), Ok(UNIX_EPOCH + Duration::from_secs(1717243200)));
+        assert_eq!(parse_age("1970-01-01T00:00:10Z"), Ok(UNIX_EPOCH + Duration::from_secs(10)));
+
+        for invalid in ["d", "1.5h", "-5d", "30 d", "30w", "", "yesterday"] {
+            assert!(parse_age(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(parse_age("x30d"), Err("invalid age x30d, expected one like 30d".to_string()));
+        assert_eq!(parse_age("99999999999999d"), Err("99999999999999d is before the epoch".to_string()));
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        fail(e)
+    }
+}
+
+/// The datastore used when neither --ds nor the profile names one
+fn default_db() -> PathBuf {
+    let mut db_path = dirs::config_dir().expect("couldn't find a default db location");
+    db_path.push("ds-rust");
+    db_path.push("ds.db");
+    db_path
+}
+
+fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("ds-rust").join("config.toml"))
+}
+
+/// Settings for a datastore, chosen with --profile from ~/.config/ds-rust/config.toml:
+///
+/// [profiles.work]
+/// ds = "/home/me/work.db"
+/// store = "tasks"
+/// output = "json"
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    ds: Option<String>,
+    store: Option<String>,
+    output: Option<Output>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+impl Config {
+    /// Read the config file, which has no profiles if it doesn't exist
+    fn load() -> Result<Config, String> {
+        let path = match config_path() {
+            Some(path) => path,
+            None => return Ok(Config { profiles: HashMap::new() }),
+        };
+        match fs::read_to_string(&path) {
+            Ok(config) => toml::from_str(&config).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config { profiles: HashMap::new() }),
+            Err(e) => Err(format!("couldn't read {}: {}", path.display(), e)),
+        }
+    }
+}
+
+impl Profile {
+    /// Read a profile from the config file, or the default profile if it has one when name is None
+    fn load(name: Option<&str>) -> Result<Profile, String> {
+        let path = match config_path() {
+            Some(path) => path,
+            None if name.is_none() => return Ok(Profile::default()),
+            None => return Err("couldn't find the config directory".to_string()),
+        };
+        let config = match fs::read_to_string(&path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && name.is_none() => return Ok(Profile::default()),
+            Err(e) => return Err(format!("couldn't read {}: {}", path.display(), e)),
+        };
+        let mut config: Config = toml::from_str(&config).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match config.profiles.remove(name.unwrap_or("default")) {
+            Some(profile) => Ok(profile),
+            None if name.is_none() => Ok(Profile::default()),
+            None => Err(format!("there's no profile {} in {}", name.unwrap_or_default(), path.display())),
+        }
+    }
+}
+
+async fn run() -> Result<(), DsError> {
+    let args = Cli::parse();
+    if let Commands::Completions { shell } = args.command {
+        print!("{}", completions(shell));
+        return Ok(());
+    }
+    let profile = match Profile::load(args.profile.as_deref()) {
+        Ok(profile) => profile,
+        Err(e) => fail(e),
+    };
+    let store_name = args.store.or(profile.store).unwrap_or_else(|| "store".to_string());
+    let output = args.output.or(profile.output).unwrap_or(Output::Text);
+    #[cfg(feature = "grpc")]
+    if let Commands::Client { url, token, cache, queue, command } = args.command {
+        let client = match queue {
+            true => {
+                let path = dirs::data_dir().expect("couldn't find a data location").join("ds-rust").join("client-queue.db");
+                dsr::grpc::Client::connect_queued(&url, token.as_deref(), &path).await
+            },
+            false => dsr::grpc::Client::connect(&url, token.as_deref()).await,
+        };
+        let mut client = match client {
+            Ok(client) => client,
+            Err(e) => fail(e),
+        };
+        if cache {
+            let path = dirs::cache_dir().expect("couldn't find a cache location").join("ds-rust").join("client-cache.db");
+            client = match client.cache_in(&path).await {
+                Ok(client) => client,
+                Err(e) => fail(e),
+            };
+        }
+        if queue {
+            replay_queued(&client).await;
+        }
+        run_on_grpc(&client, *command, output).await;
+        if queue {
+            match client.queued().await {
+                Ok(queued) if !queued.is_empty() => eprintln!("{} writes are queued until {} can be reached", queued.len(), url),
+                Ok(_) => (),
+                Err(e) => fail(e),
+            }
+        }
+        return Ok(());
+    }
+
+    let db_path = match args.ds.or(profile.ds) {
+        Some(db_path) => db_path,
+        None => {
+            let db_path = default_db();
+            if !(args.read_only || args.no_create || matches!(args.command, Commands::Ls { .. })) {
+                fs::create_dir_all(db_path.parent().expect("couldn't find a default db location"))?;
+            }
+            format!("sqlite://{}", db_path.to_str().expect("couldn't find a default db location"))
+        },
+    };
+
+    let kv_name = match &args.command {
+        Commands::CloneStore { src, .. } => src.as_str(),
+        Commands::Transfer { from_store: Some(from_store), .. } => from_store.as_str(),
+        Commands::Store { command: StoreCommands::Create { name, .. } } => name.as_str(),
+        _ => store_name.as_str(),
+    };
+    #[cfg(feature = "postgres")]
+    if db_path.starts_with("postgres://") || db_path.starts_with("postgresql://") {
+        match dsr::PostgresBackend::connect(&db_path, kv_name).await {
+            Ok(backend) => run_on_backend(&backend, args.command, "PostgreSQL", output).await,
+            Err(e) => fail(e),
+        }
+        return Ok(());
+    }
+    #[cfg(feature = "mysql")]
+    if db_path.starts_with("mysql://") {
+        match dsr::MySqlBackend::connect(&db_path, kv_name).await {
+            Ok(backend) => run_on_backend(&backend, args.command, "MySQL", output).await,
+            Err(e) => fail(e),
+        }
+        return Ok(());
+    }
+    #[cfg(feature = "libsql")]
+    if db_path.starts_with("libsql://") {
+        match dsr::LibsqlBackend::connect(&db_path, kv_name, std::env::var("DSR_LIBSQL_TOKEN").ok()).await {
+            Ok(backend) => run_on_backend(&backend, args.command, "libsql", output).await,
+            Err(e) => fail(e),
+        }
+        return Ok(());
+    }
+    let start = Instant::now();
+    let explain = match (args.explain, args.query_plan) {
+        (false, _) => Explain::Off,
+        (true, false) => Explain::Sql,
+        (true, true) => Explain::QueryPlan,
+    };
+    let read_only = args.explain || args.read_only;
+    let mut options = KVLiteOptions::from(KVLite::options(&db_path, read_only, !(read_only || args.no_create))?)
+        .collation(args.collation);
+    if let Some(mode) = args.journal_mode {
+        options = options.journal_mode(mode);
+    }
+    if let Some(synchronous) = args.synchronous {
+        options = options.synchronous(synchronous);
+    }
+    if let Some(timeout) = args.busy_timeout {
+        options = options.busy_timeout(Duration::from_millis(timeout));
+    }
+    if let Some(size) = args.cache_size {
+        options = options.cache_size(size);
+    }
+    if let Some(size) = args.pool_size {
+        options = options.pool_size(size);
+    }
+    #[cfg(feature = "extensions")]
+    for extension in &args.extension {
+        options = options.sqlite(|options| options.extension(extension.clone()));
+    }
+    #[cfg(feature = "encryption")]
+    if args.encrypt {
+        let passphrase = match std::env::var("DSR_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => rpassword::prompt_password("passphrase: ")?,
+        };
+        options = options.sqlite(|options| KVLite::encrypted(options, &passphrase));
+    }
+    let user = std::env::var("USER").or_else(|_| std::env::var("USERNAME"));
+    let source = args.source.clone().unwrap_or_else(|| match user {
+        Ok(user) => format!("cli:{}", user),
+        Err(_) => "cli".to_string(),
+    });
+
+    // opening a store to list them would create it
+    if let Commands::Ls { all } = args.command {
+        let mut datastores = vec![(db_path.clone(), vec![], options.create_if_missing(false))];
+        if all {
+            let config = match Config::load() {
+                Ok(config) => config,
+                Err(e) => fail(e),
+            };
+            let mut profiles: Vec<_> = config.profiles.into_iter().collect();
+            profiles.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let default = default_db().to_string_lossy().into_owned();
+            for (profile, db_path) in profiles.into_iter().filter_map(|(name, profile)| Some((Some(name), profile.ds?))).chain([(None, default)]) {
+                let i = match datastores.iter().position(|(listed, _, _)| same_datastore(listed, &db_path)) {
+                    Some(i) => i,
+                    None => {
+                        datastores.push((db_path.clone(), vec![], KVLiteOptions::from(KVLite::options(&db_path, true, false)?)));
+                        datastores.len() - 1
+                    },
+                };
+                datastores[i].1.extend(profile);
+            }
+        }
+        list_stores(datastores, all, output).await;
+        return Ok(());
+    }
+
+    if let Commands::Recover { resume, rollback } = args.command {
+        return recover(&db_path, resume, rollback, output).map_err(DsError::Io);
+    }
+
+    #[cfg(all(feature = "daemon", unix))]
+    {
+        let socket = args.socket.clone().unwrap_or_else(|| default_socket(&db_path));
+        if let Commands::Daemon { idle_close, stats_interval } = args.command {
+            let sampling = (stats_interval > 0).then(|| Duration::from_secs(stats_interval));
+            return dsr::daemon::serve(options, &socket, idle_close.map(Duration::from_secs), sampling).await.map_err(DsError::Io);
+        }
+        #[cfg(feature = "encryption")]
+        let encrypted = args.encrypt;
+        #[cfg(not(feature = "encryption"))]
+        let encrypted = false;
+        // only some commands can be run by the daemon, and it's opened the datastore its own way
+        if !(args.no_daemon || args.explain || args.timing || read_only || encrypted)
+            && run_through_daemon(&socket, &args.command, kv_name, &source, output).await? {
+            return Ok(());
+        }
+    }
+
+    let mut store = match options.clone().open(kv_name).await {
+        Ok(store) => store,
+        Err(DsError::NotFound) => fail(format!("there's no store {} in the datastore", kv_name)),
+        Err(e) => fail(e),
+    };
+    store.set_explain(explain);
+    store.set_source(Some(source));
+    
+    
+    // the server needs to own the store, so it runs instead of the other commands
+    #[cfg(feature = "server")]
+    if let Commands::Serve { addr, resp, #[cfg(feature = "grpc")] grpc, token, token_file, htpasswd, jwt_issuer, jwt_audience, admin_token, max_body, utf8, max_key, key_pattern, cors_origin, cors_method, cors_header, coalesce_delay, coalesce_max, follow, promote, primary_token, ui, access_log, slow_ms } = args.command {
+        let mut auth = dsr::server::Auth {
+            tokens: token,
+            token_file,
+            file_tokens: vec![],
+            htpasswd: None,
+            htpasswd_file: htpasswd,
+            jwt: jwt_issuer.zip(jwt_audience).map(|(issuer, audience)| dsr::server::Jwt::new(&issuer, &audience)),
+            admin_secret: admin_token,
+            roles: match dsr::server::acl_roles(&store).await {
+                Ok(roles) => roles,
+                Err(e) => fail(e),
+            },
+        };
+        if let Err(e) = auth.load_files() {
+            fail(e);
+        }
+        if !auth.roles.is_empty() && auth.is_open() {
+            fail("the store's acl gives clients roles, so it can only be served with --token, --token-file, --htpasswd or --jwt-issuer".to_string());
+        }
+        #[cfg(feature = "grpc")]
+        if grpc {
+            if args.source.is_none() {
+                store.set_source(Some("grpc".to_string()));
+            }
+            return dsr::grpc::serve(store, addr, auth).await.map_err(DsError::Io);
+        }
+        let coalescing = coalesce_delay.map(|delay| dsr::server::Coalesce { max_delay: Duration::from_millis(delay), max_writes: coalesce_max.max(1) });
+        if let Some(primary) = follow {
+            store.follow(&primary).await?;
+        }
+        if promote {
+            eprintln!("promoted to generation {}", store.promote().await?);
+        }
+        let cors = (!cors_origin.is_empty()).then(|| dsr::server::Cors { origins: cors_origin, methods: cors_method, headers: cors_header });
+        let standby = store.primary().await?.map(|primary| dsr::server::Standby { primary, token: primary_token });
+        if args.source.is_none() {
+            store.set_source(Some(match (&standby, resp) {
+                (Some(standby), _) => format!("peer:{}", standby.primary),
+                (None, true) => "resp".to_string(),
+                (None, false) => "http".to_string(),
+            }));
+        }
+        let res = match resp {
+            true => dsr::resp::serve(store, addr).await,
+            false => dsr::server::serve(store, addr, dsr::server::Options {
+                auth,
+                limits: dsr::server::Limits { max_body, utf8, max_key, key_patterns: key_pattern },
+                cors,
+                coalescing,
+                standby,
+                ui,
+                logging: dsr::server::Logging { access: access_log, slow: slow_ms.map(Duration::from_millis) },
+            }).await,
+        };
+        return res.map_err(DsError::Io);
+    }
+
+    if let Commands::Delete { secure: true, .. } = args.command {
+        store.delete_securely();
+    }
+    #[cfg(feature = "value-encryption")]
+    {
+        let encrypting = match &args.command {
+            Commands::Store { command: StoreCommands::ValueTransform { transforms, .. } } => transforms.contains(&ValueTransform::Encrypt),
+            _ => false,
+        };
+        if encrypting || store.value_transforms().contains(&ValueTransform::Encrypt) {
+            let passphrase = match std::env::var("DSR_VALUE_PASSPHRASE") {
+                Ok(passphrase) => passphrase,
+                Err(_) => rpassword::prompt_password("value passphrase: ")?,
+            };
+            store.set_value_passphrase(&passphrase);
+        }
+    }
+    match args.command {
+        Commands::Set { key, value, stdin, file, edit, prompt, value_from, secret, ttl, content_type, tag, if_absent, if_equals } => {
+            let source = match (value_from, file) {
+                (Some(source), _) => Some(source),
+                (None, Some(file)) => Some(ValueSource::File(file)),
+                _ if prompt => Some(ValueSource::Prompt),
+                _ if stdin || value.as_deref() == Some("-") => Some(ValueSource::Stdin),
+                _ => None,
+            };
+            let options = SetOptions { ttl: ttl.map(Duration::from_secs), content_type, tag, ..Default::default() };
+            let res = match (source, edit, if_absent, if_equals) {
+                // a file is streamed into the value rather than read into memory
+                (Some(ValueSource::File(path)), false, false, None) => store.set_from_file(&key, Path::new(&path), &options).await.map(|_| true),
+                (source, _, if_absent, if_equals) => {
+                    let value = match source {
+                        _ if edit => match edit_value(b"")? {
+                            Some(value) => value,
+                            None => {
+                                println!("{} was not set, the value was empty", key);
+                                return Ok(())
+                            },
+                        },
+                        Some(source) => source.read(&key)?,
+                        None => {
+                            if secret {
+                                eprintln!("warning: the secret value of {} was given on the command line and may be kept in shell history, use --value-from prompt or env:<VAR>", key);
+                            }
+                            value.unwrap_or_default().into_bytes()
+                        },
+                    };
+                    match (if_absent, if_equals) {
+                        (false, None) => store.set_with(&key, &value, &options).await.map(|_| true),
+                        (_, expected) => store.cas_with(&key, expected.as_deref(), &value, &options).await,
+                    }
+                },
+            };
+            match res {
+                Ok(true) => if let Err(e) = store.purge_expired().await {
+                    fail(e)
+                },
+                Ok(false) => {
+                    eprintln!("{} was not set", key);
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Edit { key, create } => {
+            let (old, options) = match store.get_with_meta(&key).await {
+                Ok((value, meta)) => {
+                    let value = String::from_utf8(value).unwrap_or_else(|_| fail(format!("{} isn't text and can't be edited", key)));
+                    // keep what's stored alongside the value, with the TTL it has left
+                    let ttl = meta.expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now()).unwrap_or_default().max(Duration::from_secs(1)));
+                    (Some(value), SetOptions { ttl, content_type: meta.content_type, tag: meta.tag, ..Default::default() })
+                },
+                Err(DsError::NotFound) if create => (None, SetOptions::default()),
+                Err(DsError::NotFound) => not_found(&key, false),
+                Err(e) => fail(e),
+            };
+            let value = match edit_value(old.as_deref().unwrap_or_default().as_bytes())? {
+                Some(value) if Some(&value[..]) != old.as_deref().map(str::as_bytes) => value,
+                Some(_) => {
+                    println!("{} was not changed", key);
+                    return Ok(())
+                },
+                None => {
+                    println!("{} was not changed, the value was empty", key);
+                    return Ok(())
+                },
+            };
+            match store.cas_with(&key, old.as_deref(), &value, &options).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("{} was changed while it was being edited and was not set", key);
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Stat { key, provenance } => {
+            match store.stat(&key).await {
+                Ok(meta) => {
+                    let ttl = meta.expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now()).unwrap_or_default().as_secs());
+                    match output {
+                        Output::Json => println!("{}", json!({
+                            "key": meta.key,
+                            "size": meta.size,
+                            "ttl": ttl,
+                            "content_type": meta.content_type,
+                            "type": meta.tag.map(|tag| tag.to_string()),
+                            "pinned": meta.pinned,
+                            "created_at": meta.created_at.map(epoch_secs),
+                            "updated_at": meta.updated_at.map(epoch_secs),
+                            "source": meta.source,
+                        })),
+                        Output::Text => {
+                            println!("size: {}", meta.size);
+                            println!("ttl: {}", ttl.map_or("-1".to_string(), |ttl| ttl.to_string()));
+                            println!("content-type: {}", meta.content_type.as_deref().unwrap_or("none"));
+                            println!("type: {}", meta.tag.map_or("untyped".to_string(), |tag| tag.to_string()));
+                            println!("pinned: {}", meta.pinned);
+                            println!("created: {}", meta.created_at.map_or("unknown".to_string(), format_time));
+                            println!("updated: {}", meta.updated_at.map_or("unknown".to_string(), format_time));
+                            if provenance {
+                                println!("source: {}", meta.source.as_deref().unwrap_or("unknown"));
+                            }
+                        },
+                    }
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Type { key } => {
+            match store.stat(&key).await {
+                Ok(meta) if output == Output::Json => println!("{}", json!(meta.tag.map(|tag| tag.to_string()))),
+                Ok(meta) => println!("{}", meta.tag.map_or("untyped".to_string(), |tag| tag.to_string())),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Batch { atomic } => {
+            let mut ops = vec![];
+            for (i, line) in io::stdin().lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_batch_op(&line) {
+                    Ok(op) => ops.push(op),
+                    Err(e) => fail(format!("line {}: {}", i + 1, e)),
+                }
+            }
+            let res = match store.batch(&ops, atomic).await {
+                Ok(res) => store.purge_expired().await.map(|_| res),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(res) => for result in res {
+                    match result {
+                        BatchResult::Done => (),
+                        BatchResult::Value(value) => println!("{}", value.unwrap_or_default()),
+                        BatchResult::Contains(contains) => println!("{}", contains),
+                    }
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Mset { pairs } => {
+            let records: Vec<(&str, &str)> = pairs.chunks(2).map(|pair| (pair[0].as_str(), pair[1].as_str())).collect();
+            match store.mset(&records).await.and(store.purge_expired().await.map(|_| ())) {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Mget { keys } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            match store.mget(&keys).await {
+                Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                Ok(res) => for value in res {
+                    println!("{}", value.unwrap_or_default())
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Mdel { keys } => {
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            match store.mdel(&keys).await.and(store.purge_expired().await.map(|_| ())) {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Incr { key, amount } => {
+            match store.incr(&key, amount).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Decr { key, amount } => {
+            match store.incr(&key, -amount).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Append { key, suffix } => {
+            match store.append(&key, &text_or_stdin(suffix, &key)?).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Prepend { key, prefix } => {
+            match store.prepend(&key, &text_or_stdin(prefix, &key)?).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Strlen { key } => {
+            match store.strlen(&key).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Lpush { key, values } => {
+            let values: Vec<&str> = values.iter().map(String::as_str).collect();
+            match store.lpush(&key, &values).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Rpush { key, values } => {
+            let values: Vec<&str> = values.iter().map(String::as_str).collect();
+            match store.rpush(&key, &values).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Lpop { key } => {
+            match store.lpop(&key).await {
+                Ok(Some(value)) if output == Output::Json => println!("{}", json!(value)),
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => std::process::exit(1),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Push { queue, value } => {
+            match store.push(&queue, &value).await {
+                Ok(len) if output == Output::Json => println!("{}", json!(len)),
+                Ok(len) => println!("{}", len),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Pop { queue, wait } => {
+            let res = match wait {
+                Some(wait) => store.pop_wait(&queue, Duration::from_secs(wait)).await,
+                None => store.pop(&queue).await,
+            };
+            match res {
+                Ok(Some(value)) if output == Output::Json => println!("{}", json!(value)),
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => std::process::exit(1),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Lrange { key, start, stop } => {
+            match store.lrange(&key, start, stop).await {
+                Ok(values) if output == Output::Json => println!("{}", json!(values)),
+                Ok(values) => for value in values {
+                    println!("{}", value)
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Sadd { key, members } => {
+            let members: Vec<&str> = members.iter().map(String::as_str).collect();
+            match store.sadd(&key, &members).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Srem { key, members } => {
+            let members: Vec<&str> = members.iter().map(String::as_str).collect();
+            match store.srem(&key, &members).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Smembers { key } => {
+            match store.smembers(&key).await {
+                Ok(members) if output == Output::Json => println!("{}", json!(members)),
+                Ok(members) => for member in members {
+                    println!("{}", member)
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Ttl { key } => {
+            match store.ttl(&key).await {
+                Ok(ttl) if output == Output::Json => println!("{}", json!(ttl.map(|ttl| ttl.as_secs()))),
+                Ok(Some(ttl)) => println!("{}", ttl.as_secs()),
+                Ok(None) => println!("-1"),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Pin { key } => {
+            match store.pin(&key).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Unpin { key } => {
+            match store.unpin(&key).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Lock { key, ttl, wait, token } => {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+            let token = token.unwrap_or_else(|| format!("{}-{}", std::process::id(), nanos));
+            let deadline = Instant::now() + Duration::from_secs(wait.unwrap_or(0));
+            loop {
+                match store.acquire_lock(&key, &token, Duration::from_secs(ttl)).await {
+                    Ok(true) if output == Output::Json => break println!("{}", json!(token)),
+                    Ok(true) => break println!("{}", token),
+                    Ok(false) if Instant::now() < deadline => tokio::time::sleep(Duration::from_millis(100)).await,
+                    Ok(false) => {
+                        eprintln!("{} is locked", key);
+                        std::process::exit(1);
+                    },
+                    Err(e) => fail(e),
+                }
+            }
+        },
+        Commands::Unlock { key, token } => {
+            match store.release_lock(&key, token.as_deref()).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("{} isn't locked{}", key, if token.is_some() { " with that token" } else { "" });
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Contains { key, quiet } => {
+            let res = match store.contains(&key).await {
+                Ok(res) => res,
+                Err(e) => fail(e),
+            };
+            match output {
+                _ if quiet => (),
+                Output::Json => println!("{}", json!(res)),
+                Output::Text => println!("{}", res),
+            }
+            if !res {
+                std::process::exit(1);
+            }
+        },
+        Commands::Count { filter } => {
+            match store.count(&filter.into()).await {
+                Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Bench { ops, concurrency, value_size } => {
+            let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+            match store.bench(&BenchOptions { ops, concurrency, value_size }).await {
+                Ok(results) if output == Output::Json => println!("{}", results.iter()
+                    .map(|result| json!({
+                        "op": result.op,
+                        "ops": result.ops,
+                        "ops_per_sec": result.ops_per_sec(),
+                        "p50_ms": ms(result.p50),
+                        "p90_ms": ms(result.p90),
+                        "p99_ms": ms(result.p99),
+                        "max_ms": ms(result.max),
+                    }))
+                    .collect::<Value>()),
+                Ok(results) => {
+                    println!("{:<8}{:>12}{:>10}{:>10}{:>10}{:>10}", "op", "ops/s", "p50 ms", "p90 ms", "p99 ms", "max ms");
+                    for result in results {
+                        println!("{:<8}{:>12.0}{:>10.3}{:>10.3}{:>10.3}{:>10.3}", result.op, result.ops_per_sec(), ms(result.p50), ms(result.p90), ms(result.p99), ms(result.max));
+                    }
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Stats { history: true } => {
+            match store.stats_history().await {
+                Ok(samples) if output == Output::Json => println!("{}", samples.iter()
+                    .map(|sample| json!({ "at": epoch_secs(sample.at), "file_size": sample.file_size, "records": sample.records }))
+                    .collect::<Value>()),
+                Ok(samples) if samples.is_empty() => eprintln!("the datastore hasn't been sampled yet, dsr daemon samples it every --stats-interval"),
+                Ok(samples) => {
+                    // a bar of each sample's share of the most records there have been
+                    let most = samples.iter().map(|sample| sample.records).max().unwrap_or(0).max(1);
+                    for sample in &samples {
+                        println!("{} {:>10} records {:>12} bytes {}", format_time(sample.at), sample.records, sample.file_size,
+                            "#".repeat((sample.records * 40 / most) as usize));
+                    }
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Stats { .. } => {
+            match store.datastore_stats().await {
+                Ok(stats) if output == Output::Json => println!("{}", json!({
+                    "file_size": stats.file_size,
+                    "records": stats.records(),
+                    "key_bytes": stats.key_bytes(),
+                    "value_bytes": stats.value_bytes(),
+                    "dedup_saved_bytes": stats.dedup_saved_bytes(),
+                    "stores": stats.stores.iter().map(|store| json!({
+                        "store": store.store,
+                        "records": store.records,
+                        "key_bytes": store.key_bytes,
+                        "value_bytes": store.value_bytes,
+                        "blob_bytes": store.blob_bytes,
+                        "dedup_saved_bytes": store.dedup_saved_bytes,
+                    })).collect::<Value>(),
+                })),
+                Ok(stats) => {
+                    println!("file size: {}", stats.file_size);
+                    println!("records: {}", stats.records());
+                    println!("key bytes: {}", stats.key_bytes());
+                    println!("value bytes: {}", stats.value_bytes());
+                    if stats.dedup_saved_bytes() > 0 {
+                        println!("saved by dedup: {}", stats.dedup_saved_bytes());
+                    }
+                    for store in &stats.stores {
+                        match store.blob_bytes {
+                            0 => println!("{}: {} records, {} key bytes, {} value bytes", store.store, store.records, store.key_bytes, store.value_bytes),
+                            blob_bytes => println!("{}: {} records, {} key bytes, {} value bytes, {} blob bytes, {} bytes saved by dedup",
+                                store.store, store.records, store.key_bytes, store.value_bytes, blob_bytes, store.dedup_saved_bytes),
+                        }
+                    }
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Get { key, raw, out, quiet, as_of: Some(as_of), format, .. } => {
+            match store.get_as_of(&key, as_of).await {
+                Ok(Version { value: None, .. }) | Err(DsError::NotFound) => not_found(&key, quiet),
+                Ok(Version { value: Some(value), .. }) if raw || out.is_some() => match out {
+                    Some(out) => fs::write(out, value)?,
+                    None => io::stdout().write_all(&value)?,
+                },
+                Ok(Version { value: Some(value), .. }) if format.print(&key, &value, None, None) => (),
+                Ok(Version { value: Some(value), content_type, expires_at, .. }) => {
+                    let meta = RecordMeta { key, size: value.len() as u64, expires_at, content_type, created_at: None, updated_at: None, tag: None, pinned: false, source: None };
+                    match output {
+                        Output::Json => println!("{}", json!(render_value(value, &meta, false))),
+                        Output::Text => println!("{}", render_value(value, &meta, io::stdout().is_terminal())),
+                    }
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Get { key, raw, out, version: Some(version), format, .. } => {
+            match store.get_version(&key, version).await {
+                Ok(Version { value: None, .. }) => println!("{} was deleted in version {}", key, version),
+                Ok(Version { value: Some(value), .. }) if raw || out.is_some() => match out {
+                    Some(out) => fs::write(out, value)?,
+                    None => io::stdout().write_all(&value)?,
+                },
+                Ok(Version { value: Some(value), .. }) if format.print(&key, &value, None, None) => (),
+                Ok(Version { value: Some(value), content_type, expires_at, .. }) => {
+                    let meta = RecordMeta { key, size: value.len() as u64, expires_at, content_type, created_at: None, updated_at: None, tag: None, pinned: false, source: None };
+                    match output {
+                        Output::Json => println!("{}", json!(render_value(value, &meta, false))),
+                        Output::Text => println!("{}", render_value(value, &meta, io::stdout().is_terminal())),
+                    }
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Get { key, out: Some(out), default: None, quiet, resolve: false, .. } => {
+            // streamed out of the value rather than read into memory
+            match store.get_to_file(&key, Path::new(&out)).await {
+                Ok(meta) => warn_expired(&meta),
+                Err(DsError::NotFound) => not_found(&key, quiet),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Get { key, raw, out, default, quiet, resolve, .. } if raw || out.is_some() => {
+            let res = match (get_record(&store, &key, resolve).await, default) {
+                (Ok((res, meta)), _) => {
+                    warn_expired(&meta);
+                    Ok(res)
+                },
+                (Err(DsError::NotFound), Some(default)) => Ok(default.into_bytes()),
+                (Err(e), _) => Err(e),
+            };
+            match (res, out) {
+                (Ok(res), Some(out)) => fs::write(out, res)?,
+                (Ok(res), None) => io::stdout().write_all(&res)?,
+                (Err(DsError::NotFound), _) => not_found(&key, quiet),
+                (Err(e), _) => fail(e),
+            } 
+        },
+        Commands::Get { key, default, quiet, resolve, format, .. } => {
+            match (get_record(&store, &key, resolve).await, default) {
+                (Ok((value, meta)), _) => {
+                    warn_expired(&meta);
+                    match output {
+                        _ if format.print(&meta.key, &value, meta.created_at, meta.updated_at) => (),
+                        Output::Json => println!("{}", json!(render_value(value, &meta, false))),
+                        Output::Text => println!("{}", render_value(value, &meta, io::stdout().is_terminal())),
+                    }
+                },
+                (Err(DsError::NotFound), Some(default)) if format.print(&key, default.as_bytes(), None, None) => (),
+                (Err(DsError::NotFound), Some(default)) if output == Output::Json => println!("{}", json!(default)),
+                (Err(DsError::NotFound), Some(default)) => println!("{}", default),
+                (Err(DsError::NotFound), None) => not_found(&key, quiet),
+                (Err(e), _) => fail(e),
+            } 
+        },
+        Commands::Getset { key, value } => {
+            match store.getset(&key, &value).await {
+                Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                Ok(Some(res)) => println!("{}", res),
+                Ok(None) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Delete { key: None, prefix, glob, older_than, .. } => {
+            match store.del_matching(&Filter { prefix, glob, updated_before: older_than, ..Default::default() }).await {
+                Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Delete { key: Some(key), cascade, restrict, .. } => {
+            let on_referenced = match (cascade, restrict) {
+                (true, _) => OnReferenced::Cascade,
+                (_, true) => OnReferenced::Restrict,
+                _ => OnReferenced::Ignore,
+            };
+            match store.del_with(&key, on_referenced).await {
+                Ok(deleted) => {
+                    for (store, key) in deleted {
+                        eprintln!("deleted {} from {}", key, store);
+                    }
+                    if let Err(e) = store.purge_expired().await {
+                        fail(e)
+                    }
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Clear { yes } => {
+            if !yes && !confirm(&format!("Delete every record in {}?", store_name))? {
+                eprintln!("not cleared, pass --yes to clear without confirmation");
+                std::process::exit(1);
+            }
+            match store.clear().await {
+                Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Check { refs, refs_external } => {
+            let mut problems = store.integrity_check().await?;
+            if refs {
+                problems.extend(store.dangling().await?
+                    .into_iter()
+                    .map(|(key, value)| format!("{} {}", key, value)));
+            }
+            if refs_external {
+                problems.extend(store.dangling_pointers().await?
+                    .into_iter()
+                    .map(|(key, pointer)| format!("{} {}", key, pointer)));
+            }
+            match output {
+                Output::Json => println!("{}", json!(problems)),
+                Output::Text => for problem in &problems {
+                    println!("{}", problem);
+                },
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+        },
+        Commands::Verify { repair } => {
+            let verification = match store.verify(repair).await {
+                Ok(verification) => verification,
+                Err(e) => fail(e),
+            };
+            let repaired = |repair: Option<Repair>| match repair {
+                Some(Repair::Restored(version)) => format!("restored from version {}", version),
+                Some(Repair::Dropped) => "dropped".to_string(),
+                None => "corrupted".to_string(),
+            };
+            match output {
+                Output::Json => println!("{}", json!({
+                    "problems": verification.problems,
+                    "corrupted": verification.corrupted.iter()
+                        .map(|corrupted| json!({
+                            "key": corrupted.key,
+                            "repair": corrupted.repair.map(|repair| match repair {
+                                Repair::Restored(_) => "restored",
+                                Repair::Dropped => "dropped",
+                            }),
+                            "version": match corrupted.repair {
+                                Some(Repair::Restored(version)) => Some(version),
+                                _ => None,
+                            },
+                        }))
+                        .collect::<Value>(),
+                })),
+                Output::Text => {
+                    for problem in &verification.problems {
+                        println!("{}", problem);
+                    }
+                    for corrupted in &verification.corrupted {
+                        println!("{} {}", corrupted.key, repaired(corrupted.repair));
+                    }
+                },
+            }
+            if !verification.is_ok() {
+                std::process::exit(1);
+            }
+        },
+        Commands::Compact { incremental } => {
+            match store.compact(incremental).await {
+                Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Backup { path, gzip } => {
+            if let Err(e) = store.backup(&path, gzip).await {
+                fail(e)
+            }
+        },
+        Commands::Restore { path, yes } => {
+            if !yes && !confirm(&format!("Replace every store in the datastore with {}?", path))? {
+                eprintln!("not restored, pass --yes to restore without confirmation");
+                std::process::exit(1);
+            }
+            let res = {
+                let _journaled = Intent::begin(&db_path, true)?;
+                store.restore(&path).await
+            };
+            if let Err(e) = res {
+                fail(e)
+            }
+        },
+        Commands::History { key, provenance } => {
+            match store.history(&key).await {
+                Ok(res) if output == Output::Json => println!("{}", res.iter()
+                    .map(|version| json!({
+                        "version": version.version,
+                        "at": epoch_secs(version.at),
+                        "value": version.value.as_ref().map(|value| String::from_utf8_lossy(value)),
+                        "content_type": version.content_type,
+                        "source": version.source,
+                    }))
+                    .collect::<Value>()),
+                Ok(res) => for version in res {
+                    let at = match provenance {
+                        true => format!("{} {}", format_time(version.at), version.source.as_deref().unwrap_or("unknown")),
+                        false => format_time(version.at),
+                    };
+                    match version.value {
+                        Some(value) => println!("{} {} {}", version.version, at, String::from_utf8_lossy(&value)),
+                        None => println!("{} {} (deleted)", version.version, at),
+                    }
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Rollback { key, to } => {
+            match store.rollback(&key, to).await.and(store.purge_expired().await.map(|_| ())) {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Expiring { within, exec, follow, interval } => {
+            let mut handled = HashSet::new();
+            loop {
+                let records = match store.expiring(Duration::from_secs(within)).await {
+                    Ok(records) => records,
+                    Err(e) => fail(e),
+                };
+                for meta in records {
+                    let expires_at = meta.expires_at.map_or(0, epoch_secs);
+                    // a renewed record has a new expiry, so it's handled again
+                    if !handled.insert((meta.key.clone(), expires_at)) {
+                        continue;
+                    }
+                    let left = expires_at.saturating_sub(epoch_secs(SystemTime::now()));
+                    match &exec {
+                        Some(command) => {
+                            let status = std::process::Command::new("sh")
+                                .arg("-c")
+                                .arg(command)
+                                .env("DSR_KEY", &meta.key)
+                                .env("DSR_EXPIRES_AT", expires_at.to_string())
+                                .status()?;
+                            if !status.success() {
+                                eprintln!("{} for {} exited with {}", command, meta.key, status);
+                            }
+                        },
+                        None if output == Output::Json => println!("{}", json!({ "key": meta.key, "ttl": left })),
+                        None => println!("{} {}", meta.key, left),
+                    }
+                }
+                if !follow {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(interval)).await;
+            }
+        },
+        Commands::ExpireDaemon { interval, exec } => {
+            let mut sweeps = tokio::time::interval(Duration::from_secs(interval.max(1)));
+            loop {
+                sweeps.tick().await;
+                let expired = match store.purge_expired_keys().await {
+                    Ok(expired) => expired,
+                    // another process is writing, the next sweep gets them
+                    Err(DsError::StoreLocked) => continue,
+                    Err(e) => fail(e),
+                };
+                for key in expired {
+                    match &exec {
+                        Some(command) => {
+                            let status = std::process::Command::new("sh")
+                                .arg("-c")
+                                .arg(command)
+                                .env("DSR_KEY", &key)
+                                .status()?;
+                            if !status.success() {
+                                eprintln!("{} for {} exited with {}", command, key, status);
+                            }
+                        },
+                        None if output == Output::Json => println!("{}", json!({ "kind": "expired", "key": key })),
+                        None => println!("expired {}", key),
+                    }
+                }
+            }
+        },
+        Commands::Watch { key, prefix, filter, interval } => {
+            let filter = watch_filter(key, prefix, filter);
+            let mut changes = Box::pin(store.watch_filtered(&filter, Duration::from_millis(interval)).await?);
+            while let Some(change) = changes.next().await {
+                match change {
+                    Ok(change) if output == Output::Json => println!("{}", json!({ "kind": change.kind.to_string(), "key": change.key, "value": change.value })),
+                    Ok(change) => println!("{} {}", change.kind, change.key),
+                    Err(e) => fail(e),
+                }
+            }
+        },
+        Commands::Keys { sort, reverse, limit, depth: Some(depth), filter, .. } =>  {
+            let prefix = filter.prefix.clone().unwrap_or_default();
+            let mut seen = HashSet::new();
+            let keys: Vec<String> = match store.keys(&ListOptions { filter: filter.into(), limit, ..sorted(sort, reverse) }).await {
+                Ok(rows) => rows.iter()
+                    .map(|row| dsr::key_at_depth(row.get("key"), &prefix, depth as usize).to_string())
+                    .filter(|key| seen.insert(key.clone()))
+                    .collect(),
+                Err(e) => fail(e),
+            };
+            match output {
+                Output::Json => println!("{}", json!(keys)),
+                Output::Text => for key in keys {
+                    println!("{}", key)
+                },
+            }
+        },
+        Commands::Tree { prefix } => {
+            let options = ListOptions { filter: Filter { prefix: prefix.clone(), ..Default::default() }, sort: true, ..Default::default() };
+            let mut tree = KeyTree::default();
+            match store.keys(&options).await {
+                Ok(rows) => for row in rows {
+                    let key: &str = row.get("key");
+                    tree.insert(dsr::key_levels(&key[prefix.as_deref().unwrap_or_default().len()..]));
+                },
+                Err(e) => fail(e),
+            }
+            match output {
+                Output::Json => println!("{}", tree.to_json()),
+                Output::Text => tree.print(0),
+            }
+        },
+        Commands::Keys { sort, reverse, limit, cursor, filter, .. } =>  {
+            let options = ListOptions {
+                filter: filter.into(),
+                ..match cursor {
+                    Some(cursor) => cursor.list_options(limit),
+                    None => ListOptions { limit, ..sorted(sort, reverse) },
+                }
+            };
+            let key = |row: &SqliteRow| row.get::<String, &str>("key");
+            let res = match store.stream_keys(&options).await {
+                Ok(rows) => print_stream(rows, output, key, |row| Value::from(key(row)), '\n').await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok((count, last)) => print_next_cursor(count, last, &options),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Values { sort, reverse, filter } =>  {
+            let res = match store.stream_values(&ListOptions { filter: filter.into(), ..sorted(sort, reverse) }).await {
+                Ok(rows) => print_stream(rows, output, |row| value_text(&store, row), |row| Value::from(value_text(&store, row)), '\n').await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                fail(e)
+            } 
+        },
+        Commands::Search { query, limit } => {
+            match store.search(&query, limit).await {
+                Ok(res) => match output {
+                    Output::Json => println!("{}", records_json(&store, &res)),
+                    Output::Text => for record in &res {
+                        println!("{},{}", record.get::<String, &str>("key"), value_text(&store, record));
+                    },
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Records { limit, as_of: Some(as_of), filter, format, .. } => {
+            let records = match store.records_as_of(as_of, &filter.into()).await {
+                Ok(records) => records,
+                Err(e) => fail(e),
+            };
+            let records = records.into_iter()
+                .take(limit.map_or(usize::MAX, |limit| limit as usize))
+                .map(|(key, version)| (key, String::from_utf8_lossy(&version.value.unwrap_or_default()).into_owned()));
+            match output {
+                _ if format.format.is_some() => for (key, value) in records {
+                    format.print(&key, value.as_bytes(), None, None);
+                },
+                Output::Json => println!("{}", records.map(|(key, value)| json!({ "key": key, "value": value })).collect::<Value>()),
+                Output::Text => for (key, value) in records {
+                    println!("{},{}", key, value);
+                },
+            }
+        },
+        Commands::Records { sort, reverse, limit, tail, long, cursor, filter, format, .. } =>  {
+            let options = ListOptions {
+                filter: filter.into(),
+                ..match (tail, cursor) {
+                    (Some(tail), _) => ListOptions { limit: Some(tail), ..sorted(sort, true) },
+                    (None, Some(cursor)) => cursor.list_options(limit),
+                    (None, None) => ListOptions { limit, ..sorted(sort, reverse) },
+                }
+            };
+            // a template replaces both the text and JSON output
+            let output = if format.format.is_some() { Output::Text } else { output };
+            let time = |record: &SqliteRow, column| record.get::<Option<i64>, &str>(column)
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64));
+            let text = |record: &SqliteRow| {
+                let key = record.get::<String, &str>("key");
+                if let Some(template) = &format.format {
+                    return template.render(&key, &value_text(&store, record), time(record, "created_at"), time(record, "updated_at"), format.shell_quote);
+                }
+                let line = format!("{},{}", key, value_text(&store, record));
+                match long {
+                    true => {
+                        let time = |column| time(record, column).map_or("-".to_string(), format_time);
+                        format!("{} {} {}", time("created_at"), time("updated_at"), line)
+                    },
+                    false => line,
+                }
+            };
+            let item = |record: &SqliteRow| match long {
+                true => json!({
+                    "key": record.get::<String, &str>("key"),
+                    "value": value_text(&store, record),
+                    "created_at": record.get::<Option<i64>, &str>("created_at"),
+                    "updated_at": record.get::<Option<i64>, &str>("updated_at"),
+                }),
+                false => json!({ "key": record.get::<String, &str>("key"), "value": value_text(&store, record) }),
+            };
+
+            // the last records are read in reverse, so they're collected to be printed in order
+            let res = match tail {
+                Some(_) => store.records(&options).await.map(|mut res| {
+                    res.reverse();
+                    match output {
+                        Output::Json => println!("{}", res.iter().map(item).collect::<Value>()),
+                        Output::Text => for record in &res {
+                            print!("{}{}", text(record), format.end())
+                        },
+                    }
+                }),
+                None => match store.stream_records(&options).await {
+                    Ok(rows) => print_stream(rows, output, text, item, format.end()).await
+                        .map(|(count, last)| print_next_cursor(count, last, &options)),
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(e) = res {
+                fail(e)
+            } 
+        },
+        Commands::Grep { pattern, keys_only, ignore_case, match_keys, filter } => {
+            let options = GrepOptions { ignore_case, keys: match_keys, filter: filter.into() };
+            let mut matches = Box::pin(store.grep(&pattern, &options).await?);
+            // printed as they're found, or collected into one array for JSON
+            let (mut count, mut found) = (0, vec![]);
+            while let Some(record) = matches.next().await {
+                let (key, value) = match record {
+                    Ok(record) => record,
+                    Err(e) => fail(e),
+                };
+                count += 1;
+                match output {
+                    Output::Json if keys_only => found.push(json!(key)),
+                    Output::Json => found.push(json!({ "key": key, "value": String::from_utf8_lossy(&value) })),
+                    Output::Text if keys_only => println!("{}", key),
+                    Output::Text => println!("{},{}", key, String::from_utf8_lossy(&value)),
+                }
+            }
+            if output == Output::Json {
+                println!("{}", Value::from(found));
+            }
+            if count == 0 {
+                std::process::exit(1);
+            }
+        },
+        Commands::Range { start, end, inclusive } => {
+            let res = if inclusive {
+                store.range(start.as_str()..=end.as_str()).await
+            } else {
+                store.range(start.as_str()..end.as_str()).await
+            };
+            match res {
+                Ok(res) if output == Output::Json => println!("{}", records_json(&store, &res)),
+                Ok(res) => for record in res {
+                    print!("{},", record.get::<String, &str>("key"));
+                    println!("{}", value_text(&store, &record))
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Randomkey { filter } => {
+            match store.random_key(&filter.into()).await {
+                Ok(Some(key)) if output == Output::Json => println!("{}", json!(key)),
+                Ok(Some(key)) => println!("{}", key),
+                Ok(None) => std::process::exit(1),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Sample { n, keys, filter } => {
+            let options = ListOptions { sort: true, by: SortBy::Random, limit: Some(n), filter: filter.into(), ..Default::default() };
+            match keys {
+                true => match store.keys(&options).await {
+                    Ok(res) if output == Output::Json => println!("{}", res.iter().map(|row| row.get::<String, &str>("key")).collect::<Value>()),
+                    Ok(res) => for row in res {
+                        println!("{}", row.get::<String, &str>("key"))
+                    },
+                    Err(e) => fail(e),
+                },
+                false => match store.records(&options).await {
+                    Ok(res) if output == Output::Json => println!("{}", records_json(&store, &res)),
+                    Ok(res) => for record in res {
+                        println!("{},{}", record.get::<String, &str>("key"), value_text(&store, &record))
+                    },
+                    Err(e) => fail(e),
+                },
+            }
+        },
+        Commands::Rename { old, new, overwrite } => {
+            match store.rename(&old, &new, overwrite.overwrite).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("{} already exists", new);
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Copy { src, dst, overwrite } => {
+            match store.copy(&src, &dst, overwrite.overwrite).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("{} already exists", dst);
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Transfer { to_store, to_ds, move_records, filter, .. } => {
+            let res = {
+                let _journaled = Intent::begin(&db_path, true)?;
+                store.transfer(&to_store, to_ds.as_deref(), &filter.into(), move_records).await
+            };
+            match res {
+                Ok(records) if output == Output::Json => println!("{}", json!({ "records": records })),
+                Ok(records) if move_records => println!("moved {} records to {}", records, to_store),
+                Ok(records) => println!("copied {} records to {}", records, to_store),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::RenamePrefix { old, new } => {
+            let res = {
+                let _journaled = Intent::begin(&db_path, true)?;
+                store.rename_prefix(&old, &new).await
+            };
+            match res {
+                Ok(res) if output == Output::Json => println!("{}", json!({ "renamed": res.renamed, "conflicts": res.conflicts })),
+                Ok(res) => for conflict in res.conflicts {
+                    println!("conflict: {} already exists", conflict)
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Diff { other, against, format, values } => {
+            let res = match (&against, &other) {
+                (Some(against), _) => store.diff(against).await,
+                (None, Some(other)) => match format.map_or_else(|| guess_format(other), |format| Ok(Some(format))) {
+                    Ok(Some(format)) => match fs::File::open(other) {
+                        Ok(file) => store.diff_records(file, format).await,
+                        Err(e) => Err(e.into()),
+                    },
+                    Ok(None) => store.diff_datastore(other).await,
+                    Err(e) => Err(e.into()),
+                },
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            match res {
+                Ok(res) => print_diff(&res, values, output),
+                Err(DsError::NotFound) => match (against, other) {
+                    (Some(against), _) => fail(format!("there's no store {} in the datastore", against)),
+                    (None, other) => fail(format!("there's no store {} in {}", store_name, other.unwrap_or_default())),
+                },
+                Err(DsError::Io(e)) => fail(format!("couldn't read {}: {}", other.unwrap_or_default(), e)),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Sync { other, push, pull, newest_wins, .. } => {
+            let strategy = match (push, pull) {
+                (true, _) => SyncStrategy::Push,
+                (_, true) => SyncStrategy::Pull,
+                _ => SyncStrategy::TwoWay { newest_wins },
+            };
+            let res = {
+                let _journaled = Intent::begin(&db_path, true)?;
+                store.sync(&other, strategy).await
+            };
+            match res {
+                Ok(res) if output == Output::Json => println!("{}", json!({ "pushed": res.pushed, "pulled": res.pulled, "conflicts": res.conflicts })),
+                Ok(res) => {
+                    for key in &res.pushed {
+                        println!("> {}", key);
+                    }
+                    for key in &res.pulled {
+                        println!("< {}", key);
+                    }
+                    for key in &res.conflicts {
+                        println!("conflict: {} differs in both datastores", key);
+                    }
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Merge { other, from, strategy, interactive } => {
+            let res = async {
+                let merged = match (&from, &other) {
+                    (Some(from), _) if !store.stores().await?.contains(from) => return Err(DsError::NotFound),
+                    (Some(from), _) => options.clone().open(from).await?,
+                    (None, Some(other)) => {
+                        // opening a datastore or a store that isn't there would create it
+                        fs::metadata(datastore_file(other))?;
+                        let options = KVLiteOptions::from(KVLite::options(other, false, false)?);
+                        if !options.clone().datastore_stats().await?.stores.iter().any(|stats| stats.store == store_name) {
+                            return Err(DsError::NotFound);
+                        }
+                        options.open(&store_name).await?
+                    },
+                    (None, None) => unreachable!("clap requires one of them"),
+                };
+                store.merge_from(&merged, |conflict| match (interactive, strategy.unwrap_or(ImportConflicts::Overwrite)) {
+                    (true, _) => resolve_conflict(conflict),
+                    (false, ImportConflicts::Overwrite) => Ok(MergeChoice::Theirs),
+                    (false, ImportConflicts::Skip) => Ok(MergeChoice::Ours),
+                    (false, ImportConflicts::Fail) => Err(DsError::Sql(sqlx::Error::Configuration(format!("{} differs in both stores, so nothing was merged", conflict.key).into()))),
+                    (false, ImportConflicts::Newest) => match conflict.theirs.1.updated_at > conflict.ours.1.updated_at {
+                        true => Ok(MergeChoice::Theirs),
+                        false => Ok(MergeChoice::Ours),
+                    },
+                }).await
+            }.await;
+            match res {
+                Ok(report) if output == Output::Json => println!("{}", json!({
+                    "added": report.added,
+                    "overwritten": report.overwritten,
+                    "skipped": report.skipped,
+                })),
+                Ok(report) => println!("{} added, {} overwritten, {} skipped", report.added, report.overwritten, report.skipped),
+                Err(DsError::NotFound) => match (from, other) {
+                    (Some(from), _) => fail(format!("there's no store {} in the datastore", from)),
+                    (None, other) => fail(format!("there's no store {} in {}", store_name, other.unwrap_or_default())),
+                },
+                Err(DsError::Io(e)) if other.is_some() => fail(format!("couldn't read {}: {}", other.unwrap_or_default(), e)),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Export { to_sqlite: Some(to_sqlite), filter, .. } => {
+            match store.export_to_sqlite(&to_sqlite, &filter.into()).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Export { format, file, filter, .. } => {
+            let writer: Box<dyn Write + Send> = match file {
+                Some(file) => Box::new(io::BufWriter::new(fs::File::create(file)?)),
+                None => Box::new(io::BufWriter::new(io::stdout())),
+            };
+            match export(&store, writer, format, filter.into()).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Import { file, format, strategy: Some(strategy), .. } => {
+            if args.source.is_none() {
+                store.set_source(Some(format!("import:{}", file.as_deref().unwrap_or("stdin"))));
+            }
+            // records read from stdin are as new as the import
+            let written_at = match &file {
+                Some(file) => fs::metadata(file)?.modified()?,
+                None => SystemTime::now(),
+            };
+            let strategy = match strategy {
+                ImportConflicts::Skip => ImportStrategy::Skip,
+                ImportConflicts::Overwrite => ImportStrategy::Overwrite,
+                ImportConflicts::Fail => ImportStrategy::Fail,
+                ImportConflicts::Newest => ImportStrategy::Newest { written_at },
+            };
+            let res = {
+                let _journaled = Intent::begin(&db_path, file.is_some())?;
+                match file {
+                    Some(file) => store.import_with(fs::File::open(file)?, format, strategy).await,
+                    None => store.import_with(io::stdin(), format, strategy).await,
+                }
+            };
+            match res {
+                Ok(report) if output == Output::Json => println!("{}", json!({
+                    "added": report.added,
+                    "overwritten": report.overwritten,
+                    "skipped": report.skipped,
+                })),
+                Ok(report) => println!("{} added, {} overwritten, {} skipped", report.added, report.overwritten, report.skipped),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Import { file, format, replace, .. } => {
+            if args.source.is_none() {
+                store.set_source(Some(format!("import:{}", file.as_deref().unwrap_or("stdin"))));
+            }
+            let res = {
+                let _journaled = Intent::begin(&db_path, file.is_some())?;
+                match file {
+                    Some(file) => store.import_from(fs::File::open(file)?, format, replace).await,
+                    None => store.import_from(io::stdin(), format, replace).await,
+                }
+            };
+            match res {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Load { csv, json, replace } => {
+            let (file, format) = match (csv, json) {
+                (Some(file), _) => (file, Format::Csv),
+                (None, file) => (file.unwrap_or_default(), Format::Json),
+            };
+            if args.source.is_none() {
+                store.set_source(Some(format!("load:{}", file)));
+            }
+            let start = Instant::now();
+            let res = {
+                let _journaled = Intent::begin(&db_path, file != "-")?;
+                match &file[..] {
+                    "-" => store.load_from(io::stdin(), format, replace).await,
+                    file => store.load_from(fs::File::open(file)?, format, replace).await,
+                }
+            };
+            let elapsed = start.elapsed().as_secs_f64();
+            match res {
+                Ok(records) if output == Output::Json => println!("{}", json!({
+                    "records": records,
+                    "seconds": elapsed,
+                    "rows_per_sec": records as f64 / elapsed.max(f64::EPSILON),
+                })),
+                Ok(records) => println!("loaded {} records in {:.2}s, {:.0} rows/s", records, elapsed, records as f64 / elapsed.max(f64::EPSILON)),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Migrate { status } => {
+            let versions = match store.schema_versions().await {
+                Ok(versions) => versions,
+                Err(e) => fail(e),
+            };
+            if status {
+                match output {
+                    Output::Json => println!("{}", json!(versions.iter().map(|v| json!({"store": v.store, "version": v.version})).collect::<Vec<_>>())),
+                    Output::Text => for v in &versions {
+                        let state = match v.version.cmp(&SCHEMA_VERSION) {
+                            Ordering::Less => "needs migrating",
+                            Ordering::Equal => "current",
+                            Ordering::Greater => "newer than this dsr",
+                        };
+                        println!("{}: {} ({})", v.store, v.version, state);
+                    },
+                }
+                return Ok(());
+            }
+            let mut migrated = vec![];
+            for v in versions.iter().filter(|v| v.version < SCHEMA_VERSION) {
+                if let Err(e) = options.clone().open(&v.store).await {
+                    fail(format!("{}: {}", v.store, e));
+                }
+                migrated.push((&v.store, v.version));
+            }
+            match output {
+                Output::Json => println!("{}", json!(migrated.iter().map(|(store, from)| json!({"store": store, "from": from, "to": SCHEMA_VERSION})).collect::<Vec<_>>())),
+                Output::Text => for (store, from) in &migrated {
+                    println!("{}: {} -> {}", store, from, SCHEMA_VERSION);
+                },
+            }
+            if let Some(v) = versions.iter().find(|v| v.version > SCHEMA_VERSION) {
+                fail(DsError::SchemaTooNew { store: v.store.clone(), version: v.version });
+            }
+        },
+        Commands::Env { command: EnvCommands::Export { prefix, strip_prefix } } => {
+            let options = ListOptions { sort: true, filter: Filter { prefix: prefix.clone(), ..Default::default() }, ..Default::default() };
+            let records = match store.records(&options).await {
+                Ok(records) => records,
+                Err(e) => fail(e),
+            };
+            let mut names: HashMap<String, String> = HashMap::new();
+            let mut exported = vec![];
+            for record in &records {
+                let key: String = record.get("key");
+                let name = match strip_prefix {
+                    true => format::env_name(&key[prefix.as_deref().unwrap_or_default().len()..]),
+                    false => format::env_name(&key),
+                };
+                if let Some(other) = names.insert(name.clone(), key.clone()) {
+                    fail(format!("{} and {} would both be exported as {}", other, key, name));
+                }
+                exported.push((name, value_text(&store, record)));
+            }
+            match output {
+                Output::Json => println!("{}", Value::Object(exported.into_iter().map(|(name, value)| (name, Value::from(value))).collect())),
+                Output::Text => for (name, value) in exported {
+                    println!("export {}={}", name, shell_quote_text(&value));
+                },
+            }
+        },
+        Commands::Env { command: EnvCommands::Import { file, prefix } } => {
+            if args.source.is_none() {
+                store.set_source(Some(format!("import:{}", file)));
+            }
+            let records: Vec<(String, String)> = match format::import(&fs::read_to_string(&file)?, Format::Env) {
+                Ok(records) => records.into_iter()
+                    .map(|(key, value)| (format!("{}{}", prefix.as_deref().unwrap_or_default(), key), value))
+                    .collect(),
+                Err(e) => fail(format!("{} isn't a dotenv file: {}", file, e)),
+            };
+            if let Err(e) = store.import(&records, false).await {
+                fail(e)
+            }
+        },
+        Commands::Tag { command: TagCommands::Add { key, tags } } => {
+            let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+            match store.tag_key(&key, &tags).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Tag { command: TagCommands::Rm { key, tags } } => {
+            let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+            match store.untag_key(&key, &tags).await {
+                Ok(res) => println!("{}", res),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Tag { command: TagCommands::List { key } } => {
+            match store.key_tags(&key).await {
+                Ok(tags) if output == Output::Json => println!("{}", json!(tags)),
+                Ok(tags) => for tag in tags {
+                    println!("{}", tag)
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Audit { command: AuditCommands::List { key, since, limit } } => {
+            match store.audit_log(key.as_deref(), since, limit).await {
+                Ok(entries) if output == Output::Json => println!("{}", entries.iter()
+                    .map(|entry| json!({
+                        "seq": entry.seq,
+                        "at": epoch_secs(entry.at),
+                        "kind": entry.kind.to_string(),
+                        "key": entry.key,
+                        "old_hash": entry.old_hash,
+                        "source": entry.source,
+                        "user": entry.user,
+                        "pid": entry.pid,
+                    }))
+                    .collect::<Value>()),
+                Ok(entries) => for entry in entries {
+                    let mut line = format!("{} {} {}", format_time(entry.at), entry.kind, entry.key);
+                    let fields = [
+                        ("source", entry.source),
+                        ("user", entry.user),
+                        ("pid", entry.pid.map(|pid| pid.to_string())),
+                        ("old", entry.old_hash),
+                    ];
+                    for (name, value) in fields {
+                        if let Some(value) = value {
+                            line.push_str(&format!(" {}={}", name, value));
+                        }
+                    }
+                    println!("{}", line);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Audit { command: AuditCommands::Prune { before } } => {
+            match store.prune_audit(before).await {
+                Ok(pruned) if output == Output::Json => println!("{}", json!({ "pruned": pruned })),
+                Ok(pruned) => println!("{}", pruned),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Changes { command: ChangesCommands::Tail { since, follow: true, interval, .. } } => {
+            let mut events = match store.tail_changes(since, Duration::from_millis(interval)).await {
+                Ok(events) => Box::pin(events),
+                Err(e) => fail(e),
+            };
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(event) => print_feed_event(&event, output),
+                    Err(e) => fail(e),
+                }
+            }
+        },
+        Commands::Changes { command: ChangesCommands::Tail { since, limit, .. } } => {
+            match store.feed_since(since, limit.unwrap_or(u64::MAX)).await {
+                Ok(events) => for event in events {
+                    print_feed_event(&event, output)
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Changes { command: ChangesCommands::Prune { through } } => {
+            match store.prune_changes(through).await {
+                Ok(pruned) if output == Output::Json => println!("{}", json!({ "pruned": pruned })),
+                Ok(pruned) => println!("{}", pruned),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Snapshot { command: SnapshotCommands::Create { name } } => {
+            match store.save_snapshot(&name).await {
+                Ok(records) if output == Output::Json => println!("{}", json!({ "records": records })),
+                Ok(records) => println!("{}", records),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Snapshot { command: SnapshotCommands::List } => {
+            match store.saved_snapshots().await {
+                Ok(snapshots) if output == Output::Json => println!("{}", snapshots.iter()
+                    .map(|snapshot| json!({ "name": snapshot.name, "taken_at": epoch_secs(snapshot.taken_at), "records": snapshot.records }))
+                    .collect::<Value>()),
+                Ok(snapshots) => for snapshot in snapshots {
+                    println!("{} {} {}", snapshot.name, format_time(snapshot.taken_at), snapshot.records);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Snapshot { command: SnapshotCommands::Restore { name } } => {
+            match store.restore_snapshot(&name).await {
+                Ok(records) if output == Output::Json => println!("{}", json!({ "records": records })),
+                Ok(records) => println!("{}", records),
+                Err(DsError::NotFound) => fail(format!("there's no snapshot {}", name)),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Snapshot { command: SnapshotCommands::Diff { name, values } } => {
+            match store.diff_snapshot(&name).await {
+                Ok(res) => print_diff(&res, values, output),
+                Err(DsError::NotFound) => fail(format!("there's no snapshot {}", name)),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Snapshot { command: SnapshotCommands::Drop { name } } => {
+            match store.drop_snapshot(&name).await {
+                Ok(true) => (),
+                Ok(false) => fail(format!("there's no snapshot {}", name)),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Hook { command: HookCommands::Add { filter, command } } => {
+            match store.add_hook(&filter, &command).await {
+                Ok(id) if output == Output::Json => println!("{}", json!({ "id": id })),
+                Ok(id) => println!("{}", id),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Hook { command: HookCommands::List } => {
+            match output {
+                Output::Json => println!("{}", store.hooks().iter()
+                    .map(|hook| json!({ "id": hook.id, "filter": hook.filter.to_string(), "command": hook.command }))
+                    .collect::<Value>()),
+                Output::Text => for hook in store.hooks() {
+                    println!("{} {}: {}", hook.id, hook.filter, hook.command);
+                },
+            }
+        },
+        Commands::Hook { command: HookCommands::Remove { id } } => {
+            match store.remove_hook(id).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("there's no hook {}", id);
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Acl { command: AclCommands::Grant { name, role } } => {
+            if let Err(e) = store.grant(&name, role).await {
+                fail(e)
+            }
+        },
+        Commands::Acl { command: AclCommands::Revoke { name } } => {
+            match store.revoke(&name).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    eprintln!("{} has no role", name);
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Acl { command: AclCommands::List } => {
+            match store.acl().await {
+                Ok(acl) if output == Output::Json => println!("{}", acl.iter()
+                    .map(|(name, role)| json!({ "name": name, "role": role.to_string() }))
+                    .collect::<Value>()),
+                Ok(acl) => for (name, role) in acl {
+                    println!("{} {}", name, role);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Maintain { command: MaintainCommands::AnalyzeReport { fix } } => {
+            let report = match store.analyze_report().await {
+                Ok(report) => report,
+                Err(e) => fail(e),
+            };
+            match output {
+                Output::Json => println!("{}", json!({
+                    "file_size": report.file_size,
+                    "free_bytes": report.free_bytes,
+                    "unused_bytes": report.unused_bytes,
+                    "fragmentation": report.fragmentation,
+                    "oversized": report.oversized.iter()
+                        .map(|(store, key, size)| json!({ "store": store, "key": key, "size": size }))
+                        .collect::<Value>(),
+                    "expired": report.expired.iter()
+                        .map(|(store, records)| json!({ "store": store, "records": records }))
+                        .collect::<Value>(),
+                    "recommendations": report.recommendations.iter().map(|recommendation| recommendation.to_string()).collect::<Value>(),
+                    "fixed": fix,
+                })),
+                Output::Text => {
+                    println!("file size: {}", report.file_size);
+                    println!("free bytes: {}", report.free_bytes);
+                    println!("unused bytes: {}", report.unused_bytes);
+                    println!("fragmentation: {:.1}%", report.fragmentation * 100.0);
+                    for (store, key, size) in &report.oversized {
+                        println!("oversized: {} in {} is {} bytes", key, store, size);
+                    }
+                    for (store, records) in &report.expired {
+                        println!("expired: {} records in {}", records, store);
+                    }
+                    for recommendation in &report.recommendations {
+                        println!("{}: {}", if fix { "fixing" } else { "recommended" }, recommendation);
+                    }
+                },
+            }
+            if fix {
+                if let Err(e) = store.fix(&report.recommendations).await {
+                    fail(e)
+                }
+            }
+        },
+        Commands::Store { command: StoreCommands::List } => {
+            match store.stores().await {
+                Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                Ok(res) => for name in res {
+                    println!("{}", name)
+                },
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::Versioning { off } } => {
+            match store.set_versioned(!off).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::Audit { off } } => {
+            match store.set_audited(!off).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::ChangeFeed { off } } => {
+            if let Err(e) = store.set_change_feed(!off).await {
+                fail(e)
+            }
+        },
+        Commands::Store { command: StoreCommands::Checksums { off } } => {
+            if let Err(e) = store.set_checksummed(!off).await {
+                fail(e)
+            }
+        },
+        Commands::Store { command: StoreCommands::SecureDelete { off } } => {
+            match store.set_secure_delete(!off).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::Create { template, .. } } => {
+            #[cfg(feature = "encryption")]
+            let encrypted = args.encrypt;
+            #[cfg(not(feature = "encryption"))]
+            let encrypted = false;
+            if template == Some(Template::Secrets) && !encrypted {
+                eprintln!("warning: the datastore isn't encrypted, open it with --encrypt to keep secrets unreadable in the file");
+            }
+            if let Some(template) = template {
+                if let Err(e) = store.apply_template(template).await {
+                    fail(e)
+                }
+            }
+        },
+        Commands::Store { command: StoreCommands::DefaultTtl { seconds } } => {
+            match store.set_default_ttl(Some(Duration::from_secs(seconds)).filter(|ttl| !ttl.is_zero())).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::MaxValueSize { bytes } } => {
+            match store.set_max_value_size(Some(bytes).filter(|bytes| *bytes > 0)).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::Grace { seconds } } => {
+            match store.set_grace(Duration::from_secs(seconds)).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::Type { value_type: Some(value_type) } } => {
+            match store.set_value_type(value_type).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::Type { value_type: None } } => {
+            match store.value_type().await {
+                Ok(value_type) => println!("{}", value_type),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::KeyTransform { transforms, off } } if off || !transforms.is_empty() => {
+            match store.set_key_transforms(transforms).await {
+                Ok(renamed) => println!("{}", renamed),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Store { command: StoreCommands::KeyTransform { .. } } => {
+            for transform in store.key_transforms() {
+                println!("{}", transform);
+            }
+        },
+        Commands::Store { command: StoreCommands::ValueTransform { transforms, off } } if off || !transforms.is_empty() => {
+            match store.set_value_transforms(transforms).await {
+                Ok(rewritten) => println!("{}", rewritten),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Store { command: StoreCommands::ValueTransform { .. } } => {
+            for transform in store.value_transforms() {
+                println!("{}", transform);
+            }
+        },
+        Commands::Store { command: StoreCommands::Dedup { min_size, off } } if off || min_size.is_some() => {
+            match store.set_dedup(min_size.filter(|_| !off)).await {
+                Ok(rewritten) => println!("{}", rewritten),
+                Err(e) => fail(e),
+            }
+        },
+        Commands::Store { command: StoreCommands::Dedup { .. } } => {
+            if let Some(min_size) = store.dedup_min_size() {
+                println!("{}", min_size);
+            }
+        },
+        Commands::Store { command: StoreCommands::Drop { name } } => {
+            match store.drop_store(&name).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+        Commands::Store { command: StoreCommands::Swap { a, b } } => {
+            match store.swap_stores(&a, &b).await {
+                Ok(_) => (),
+                Err(DsError::NotFound) => {
+                    eprintln!("both {} and {} must be stores", a, b);
+                    std::process::exit(1);
+                },
+                Err(e) => fail(e),
+            }
+        },
+        #[cfg(feature = "scripting")]
+        Commands::Eval { script } => {
+            match fs::read_to_string(&script) {
+                Ok(script) => match store.eval(&script).await {
+                    Ok(res) if output == Output::Json => println!("{}", json!(res)),
+                    Ok(res) if res.is_empty() => (),
+                    Ok(res) => println!("{}", res),
+                    Err(e) => fail(e),
+                },
+                Err(e) => fail(e),
+            }
+        },
+        #[cfg(feature = "tui")]
+        Commands::Tui => {
+            if let Err(e) = dsr::tui::run(&store, edit_value).await {
+                fail(e)
+            }
+        },
+        #[cfg(feature = "server")]
+        Commands::Serve { .. } => unreachable!(),
+        #[cfg(feature = "grpc")]
+        Commands::Client { .. } => unreachable!(),
+        #[cfg(all(feature = "daemon", unix))]
+        Commands::Daemon { .. } => unreachable!(),
+        Commands::Ls { .. } | Commands::Recover { .. } | Commands::Completions { .. } => unreachable!(),
+        Commands::CloneStore { dst, to_ds, .. } => {
+            match store.clone_store(&dst, to_ds.as_deref()).await {
+                Ok(_) => (),
+                Err(e) => fail(e),
+            } 
+        },
+    }
+
+    if args.timing {
+        let stats = store.stats();
+        eprintln!("time: {:?}, statements: {}, rows: {}", start.elapsed(), stats.statements, stats.rows);
     }
 
     Ok(())
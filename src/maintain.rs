@@ -0,0 +1,230 @@
+use std::fmt;
+use sqlx::{sqlite::SqliteConnection, QueryBuilder, Row};
+use crate::{KVLite, DsError, Table, now};
+
+/// Values bigger than this are reported as oversized
+const OVERSIZED_VALUE: i64 = 1024 * 1024;
+/// Oversized values reported at most
+const OVERSIZED_LIMIT: i64 = 10;
+/// Share of the file on the freelist above which a vacuum is recommended
+const MAX_FREE: f64 = 0.1;
+/// Share of out of order pages above which a vacuum is recommended
+const MAX_FRAGMENTATION: f64 = 0.3;
+
+/// How a datastore's file is used, from analyze_report, with the maintenance worth doing
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceReport {
+    /// Size of the datastore file in bytes
+    pub file_size: u64,
+    /// Bytes of pages on the freelist, only given back by a vacuum
+    pub free_bytes: u64,
+    /// Bytes left unused inside the pages holding tables and indexes
+    pub unused_bytes: u64,
+    /// Share of pages not stored right after the page before them in their table or index, from 0 to 1
+    pub fragmentation: f64,
+    /// The biggest values over a MiB, as (store, key, bytes)
+    pub oversized: Vec<(String, String, u64)>,
+    /// Expired records waiting to be purged, as (store, records)
+    pub expired: Vec<(String, u64)>,
+    pub recommendations: Vec<Recommendation>,
+}
+
+/// Maintenance analyze_report suggests, which fix applies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recommendation {
+    /// Create the index on expiry times a store is missing
+    CreateIndex(String),
+    /// Delete a store's expired records
+    Purge(String),
+    /// Gather statistics for the query planner
+    Analyze,
+    /// Rebuild the file to give back free pages and put pages in order
+    Vacuum,
+}
+
+impl fmt::Display for Recommendation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recommendation::CreateIndex(store) => write!(f, "create the expiry index of {}, so expired records are found without a scan", store),
+            Recommendation::Purge(store) => write!(f, "purge the expired records of {}", store),
+            Recommendation::Analyze => write!(f, "analyze the datastore, so queries are planned with statistics"),
+            Recommendation::Vacuum => write!(f, "vacuum the datastore, giving back free space and defragmenting it"),
+        }
+    }
+}
+
+impl KVLite {
+    /// Report on the space, fragmentation, oversized values, expired records and missing indexes
+    /// of the datastore, recommending the maintenance worth doing
+    pub async fn analyze_report(&self) -> Result<MaintenanceReport, DsError> {
+        let stores = self.stores().await?;
+        let mut conn = self.pool.acquire().await?;
+
+        let pages = self.fetch_one(&mut conn, QueryBuilder::new(r#"
+                SELECT page_count, page_size, freelist_count FROM pragma_page_count(), pragma_page_size(), pragma_freelist_count()
+            "#)
+            .build())
+            .await?;
+        let page_size: i64 = pages.get("page_size");
+        let file_size = (pages.get::<i64, &str>("page_count") * page_size) as u64;
+        let free_bytes = (pages.get::<i64, &str>("freelist_count") * page_size) as u64;
+
+        // dbstat lists the pages of each table and index in the order they're read
+        let usage = self.fetch_one(&mut conn, QueryBuilder::new(r#"
+                SELECT coalesce(sum(unused), 0) AS unused, count(prev) AS follows, count(prev) FILTER (WHERE pageno != prev + 1) AS jumps
+                FROM (SELECT unused, pageno, lag(pageno) OVER (PARTITION BY name ORDER BY path) AS prev FROM dbstat WHERE aggregate = FALSE)
+            "#)
+            .build())
+            .await?;
+        let unused_bytes = usage.get::<i64, &str>("unused") as u64;
+        let fragmentation = match usage.get::<i64, &str>("follows") {
+            0 => 0.0,
+            follows => usage.get::<i64, &str>("jumps") as f64 / follows as f64,
+        };
+
+        let mut report = MaintenanceReport {
+            file_size,
+            free_bytes,
+            unused_bytes,
+            fragmentation,
+            oversized: vec![],
+            expired: vec![],
+            recommendations: vec![],
+        };
+        for store in stores {
+            let table = Table::new(&store);
+            let rows = self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                    SELECT key, length(CAST(value AS BLOB)) AS size FROM {} WHERE length(CAST(value AS BLOB)) >
+                "#, table))
+                .push_bind(OVERSIZED_VALUE)
+                .push(" ORDER BY size DESC LIMIT ")
+                .push_bind(OVERSIZED_LIMIT)
+                .build())
+                .await?;
+            report.oversized.extend(rows.iter().map(|row| (store.clone(), row.get("key"), row.get::<i64, &str>("size") as u64)));
+
+            let before = self.purge_before(&mut conn, &table).await?;
+            let expired = self.fetch_one(&mut conn, QueryBuilder::new(format!(r#"
+                    SELECT count(*) AS count FROM {} WHERE expires_at <=
+                "#, table))
+                .push_bind(before)
+                .build())
+                .await?
+                .get::<i64, &str>("count") as u64;
+            if expired > 0 {
+                report.expired.push((store.clone(), expired));
+                report.recommendations.push(Recommendation::Purge(store.clone()));
+            }
+
+            let indexed = self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                    SELECT name FROM sqlite_master WHERE type="index" AND name=
+                "#)
+                .push_bind(table.object("expires_at").name)
+                .build())
+                .await?
+                .is_some();
+            if !indexed {
+                report.recommendations.push(Recommendation::CreateIndex(store));
+            }
+        }
+        report.oversized.sort_by_key(|(_, _, size)| std::cmp::Reverse(*size));
+        report.oversized.truncate(OVERSIZED_LIMIT as usize);
+
+        let analyzed = self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name="sqlite_stat1"
+            "#)
+            .build())
+            .await?
+            .is_some();
+        if !analyzed {
+            report.recommendations.push(Recommendation::Analyze);
+        }
+        if free_bytes as f64 > file_size as f64 * MAX_FREE || fragmentation > MAX_FRAGMENTATION {
+            report.recommendations.push(Recommendation::Vacuum);
+        }
+        Ok(report)
+    }
+
+    /// Carry out recommended maintenance, vacuuming last so it also gives back the space the rest freed
+    pub async fn fix(&self, recommendations: &[Recommendation]) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        for recommendation in recommendations {
+            match recommendation {
+                Recommendation::CreateIndex(store) => self.create_expiry_index(&mut conn, &Table::new(store)).await?,
+                Recommendation::Purge(store) => {
+                    let table = Table::new(store);
+                    let before = self.purge_before(&mut conn, &table).await?;
+                    self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                            DELETE FROM {} WHERE expires_at <=
+                        "#, table))
+                        .push_bind(before)
+                        .build())
+                        .await?;
+                },
+                Recommendation::Analyze => {
+                    self.execute(&mut conn, QueryBuilder::new("ANALYZE").build()).await?;
+                },
+                Recommendation::Vacuum => (),
+            }
+        }
+        if recommendations.contains(&Recommendation::Vacuum) {
+            self.execute(&mut conn, QueryBuilder::new("VACUUM").build()).await?;
+        }
+        Ok(())
+    }
+
+    /// Give the datastore's free pages back to the filesystem, returning how many bytes the file
+    /// shrank by. A full compaction rebuilds the file with VACUUM, which also defragments it, while
+    /// an incremental one only truncates free pages off its end. The first incremental compaction
+    /// switches the datastore to incremental auto vacuum, which takes a full vacuum. Blobs of
+    /// deduplicated values nothing refers to any more are deleted first.
+    pub async fn compact(&self, incremental: bool) -> Result<u64, DsError> {
+        let stores = self.stores().await?;
+        let mut conn = self.pool.acquire().await?;
+        let before = self.file_size(&mut conn).await?;
+        for store in &stores {
+            self.sweep_blobs(&mut conn, &Table::new(store)).await?;
+        }
+
+        let auto_vacuum = self.fetch_one(&mut conn, QueryBuilder::new("PRAGMA auto_vacuum").build())
+            .await?
+            .get::<i64, usize>(0);
+        let sql = match incremental {
+            // 2 is INCREMENTAL
+            true if auto_vacuum == 2 => "PRAGMA incremental_vacuum",
+            true => "PRAGMA auto_vacuum = INCREMENTAL; VACUUM",
+            false => "VACUUM",
+        };
+        self.execute(&mut conn, QueryBuilder::new(sql).build()).await?;
+        // in WAL mode the file only shrinks once the vacuum is checkpointed
+        self.execute(&mut conn, QueryBuilder::new("PRAGMA wal_checkpoint(TRUNCATE)").build()).await?;
+
+        Ok(before.saturating_sub(self.file_size(&mut conn).await?))
+    }
+
+    /// Copy every write in the write-ahead log into the datastore file and empty the log, so the
+    /// file holds the whole datastore, as before a server stops
+    pub async fn checkpoint(&self) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.execute(&mut conn, QueryBuilder::new("PRAGMA wal_checkpoint(TRUNCATE)").build()).await?;
+        Ok(())
+    }
+
+    async fn file_size(&self, conn: &mut SqliteConnection) -> Result<u64, DsError> {
+        let pages = self.fetch_one(conn, QueryBuilder::new(r#"
+                SELECT page_count, page_size FROM pragma_page_count(), pragma_page_size()
+            "#)
+            .build())
+            .await?;
+        Ok((pages.get::<i64, &str>("page_count") * pages.get::<i64, &str>("page_size")) as u64)
+    }
+
+    /// Records of a store that expired at or before this time are past its grace period
+    async fn purge_before(&self, conn: &mut SqliteConnection, store: &Table) -> Result<i64, DsError> {
+        let grace = match store.name == self.kv_name.name {
+            true => self.grace,
+            false => self.load_setting(conn, store, "grace").await?.and_then(|grace| grace.parse().ok()).unwrap_or(0),
+        };
+        Ok(now() - grace)
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::SystemTime;
+use sqlx::Row;
+use crate::{KVLite, DsError, ImportReport, ListOptions, RecordMeta, RecordWrite, SetOptions};
+
+/// A record two stores being merged both have, with different values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub key: String,
+    /// The value and metadata of the record in the store merged into
+    pub ours: (Vec<u8>, RecordMeta),
+    /// The value and metadata of the record in the store merged from
+    pub theirs: (Vec<u8>, RecordMeta),
+}
+
+/// How a merge resolves a conflict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeChoice {
+    /// Keep the record as it is in the store merged into
+    Ours,
+    /// Overwrite it with the record of the store merged from
+    Theirs,
+    /// Overwrite it with the record of the store merged from, but with this value
+    Value(Vec<u8>),
+}
+
+impl KVLite {
+    /// Copy another store's records into this one, in one transaction, with their TTLs, content
+    /// types and tags. Records this store doesn't have are added, and each it has with a different
+    /// value is handed to resolve, nothing being written if it fails. Records only this store has
+    /// are kept.
+    pub async fn merge_from(&self, other: &KVLite, mut resolve: impl FnMut(&MergeConflict) -> Result<MergeChoice, DsError>) -> Result<ImportReport, DsError> {
+        let mut report = ImportReport::default();
+        let mut writes = vec![];
+        for row in other.keys(&ListOptions { sort: true, ..Default::default() }).await? {
+            let key: String = row.get("key");
+            // a record that's expired since it was listed is left out
+            let (mut value, meta) = match other.get_with_meta(&key).await {
+                Ok(record) => record,
+                Err(DsError::NotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            match self.get_with_meta(&key).await {
+                Ok((ours, _)) if ours == value => continue,
+                Ok(ours) => {
+                    let conflict = MergeConflict { key: key.clone(), ours, theirs: (value, meta.clone()) };
+                    value = match resolve(&conflict)? {
+                        MergeChoice::Ours => {
+                            report.skipped += 1;
+                            continue;
+                        },
+                        MergeChoice::Theirs => conflict.theirs.0,
+                        MergeChoice::Value(value) => value,
+                    };
+                    report.overwritten += 1;
+                },
+                Err(DsError::NotFound) => report.added += 1,
+                Err(e) => return Err(e),
+            }
+            let ttl = meta.expires_at.map(|at| at.duration_since(SystemTime::now()).unwrap_or_default());
+            writes.push(RecordWrite::Set { key, value, options: SetOptions { ttl, content_type: meta.content_type, tag: meta.tag, ..Default::default() } });
+        }
+        self.write_all(&writes).await?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,165 @@
+use sqlx::{sqlite::SqliteConnection, QueryBuilder, Row};
+use crate::{KVLite, DsError, Table, now};
+
+/// The schema version stores are created at, and the newest this version of dsr can use
+pub const SCHEMA_VERSION: i64 = 3;
+
+/// A step bringing stores from the version before it up to its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+}
+
+/// Every step, in order. A store with no recorded version predates them and gets them all, each
+/// checking for what it adds first, so stores made by any older version are brought up to date.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "expiry, content type, timestamps, type, pinning and source of records, and the change log" },
+    Migration { version: 2, description: "the source of each version in the history" },
+    Migration { version: 3, description: "the table of key tags" },
+];
+
+/// A store's schema version, from schema_versions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaVersion {
+    pub store: String,
+    /// 0 if the store was made before versions were recorded
+    pub version: i64,
+}
+
+impl KVLite {
+    /// The table recording the schema version of each store in a datastore, in the schema kv_name is in
+    fn meta_table(kv_name: &Table) -> Table {
+        match &kv_name.schema {
+            Some(schema) => Table::in_schema(schema, "_ds_meta"),
+            None => Table::new("_ds_meta"),
+        }
+    }
+
+    async fn create_meta_table(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (store TEXT PRIMARY KEY, schema_version INTEGER NOT NULL, migrated_at INTEGER)
+            "#, Self::meta_table(kv_name)))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Record the version a store's schema is at
+    pub(crate) async fn set_schema_version(&self, conn: &mut SqliteConnection, kv_name: &Table, version: i64) -> Result<(), DsError> {
+        self.create_meta_table(conn, kv_name).await?;
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (store, schema_version, migrated_at) VALUES (
+            "#, Self::meta_table(kv_name)))
+            .push_bind(kv_name.name.as_str())
+            .push(",")
+            .push_bind(version)
+            .push(",")
+            .push_bind(now())
+            .push(") ON CONFLICT (store) DO UPDATE SET schema_version = excluded.schema_version, migrated_at = excluded.migrated_at")
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    async fn has_meta_table(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<bool, DsError> {
+        let master = match &kv_name.schema {
+            Some(schema) => Table::in_schema(schema, "sqlite_master"),
+            None => Table::new("sqlite_master"),
+        };
+        Ok(self.fetch_optional(conn, QueryBuilder::new(format!(r#"
+                SELECT name FROM {} WHERE type="table" AND name="_ds_meta"
+            "#, master))
+            .build())
+            .await?
+            .is_some())
+    }
+
+    async fn schema_version_in(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<i64, DsError> {
+        if !self.has_meta_table(conn, kv_name).await? {
+            return Ok(0);
+        }
+        Ok(self.fetch_optional(conn, QueryBuilder::new(format!(r#"
+                SELECT schema_version FROM {} WHERE store=
+            "#, Self::meta_table(kv_name)))
+            .push_bind(kv_name.name.as_str())
+            .build())
+            .await?
+            .map_or(0, |row| row.get("schema_version")))
+    }
+
+    /// Forget the version of a store that's been dropped
+    pub(crate) async fn remove_schema_version(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<(), DsError> {
+        if self.has_meta_table(conn, kv_name).await? {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    DELETE FROM {} WHERE store=
+                "#, Self::meta_table(kv_name)))
+                .push_bind(kv_name.name.as_str())
+                .build())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Swap the versions of two stores whose names have been swapped, through a third name as the
+    /// store names are unique
+    pub(crate) async fn swap_schema_versions(&self, conn: &mut SqliteConnection, a: &Table, b: &Table) -> Result<(), DsError> {
+        if !self.has_meta_table(conn, a).await? {
+            return Ok(());
+        }
+        for (from, to) in [(a.name.as_str(), "dsr_swap"), (b.name.as_str(), a.name.as_str()), ("dsr_swap", b.name.as_str())] {
+            self.execute(conn, QueryBuilder::new(format!(r#"
+                    UPDATE {} SET store=
+                "#, Self::meta_table(a)))
+                .push_bind(to)
+                .push(" WHERE store=")
+                .push_bind(from)
+                .build())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Bring the store opened up to SCHEMA_VERSION, running the steps it hasn't had in order, and
+    /// return the version it was at. Opening a store does this already. A store at a newer version
+    /// than this one of dsr knows is refused with SchemaTooNew rather than misread.
+    pub(crate) async fn migrate_in(&self, conn: &mut SqliteConnection) -> Result<i64, DsError> {
+        let kv_name = &self.kv_name;
+        let from = self.schema_version_in(conn, kv_name).await?;
+        if from > SCHEMA_VERSION {
+            return Err(DsError::SchemaTooNew { store: kv_name.name.clone(), version: from });
+        }
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version > from) {
+            match migration.version {
+                1 => self.upgrade_store_table(conn, kv_name).await?,
+                2 => self.upgrade_history(conn).await?,
+                3 => match self.create_key_tags(conn, kv_name).await {
+                    // a read-only datastore can't add it, but then nothing can be tagged in it either
+                    Err(DsError::ReadOnly) => (),
+                    res => res?,
+                },
+                version => unreachable!("no migration to version {}", version),
+            }
+        }
+        if from < SCHEMA_VERSION {
+            match self.set_schema_version(conn, kv_name, SCHEMA_VERSION).await {
+                // it's migrated again, finding nothing to do, once it's opened for writing
+                Err(DsError::ReadOnly) => (),
+                res => res?,
+            }
+        }
+        Ok(from)
+    }
+
+    /// The schema version of every store in the datastore, in order of name
+    pub async fn schema_versions(&self) -> Result<Vec<SchemaVersion>, DsError> {
+        let stores = self.stores().await?;
+        let mut conn = self.pool.acquire().await?;
+        let mut versions = vec![];
+        for store in stores {
+            let version = self.schema_version_in(&mut conn, &Table::new(&store)).await?;
+            versions.push(SchemaVersion { store, version });
+        }
+        Ok(versions)
+    }
+}
@@ -0,0 +1,110 @@
+use std::{str::FromStr, time::Duration};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use crate::{KVLite, DsError, Collation, DatastoreStats, StatsSample};
+
+/// Connections SQLx keeps open to a datastore at most, when no pool size is set
+pub const DEFAULT_POOL_SIZE: u32 = 10;
+/// The filename of a datastore kept in memory rather than a file
+pub const MEMORY: &str = "memory:";
+
+/// How a store is opened: the datastore file, SQLite pragmas set on each connection and the size of
+/// the connection pool. WAL with a busy timeout lets writers from several processes wait their turn
+/// instead of failing with StoreLocked.
+#[derive(Debug, Clone)]
+pub struct KVLiteOptions {
+    sqlite: SqliteConnectOptions,
+    pool_size: u32,
+    collation: Collation,
+}
+
+impl KVLiteOptions {
+    /// Options for a datastore file, which is created if it doesn't exist, or a new datastore in
+    /// memory if filename is memory:
+    pub fn new(filename: &str) -> Result<KVLiteOptions, DsError> {
+        match filename {
+            MEMORY => Ok(KVLiteOptions::memory()),
+            filename => Ok(KVLite::options(filename, false, true)?.into()),
+        }
+    }
+
+    /// Options for a new datastore kept in memory, gone once the store opened with them is dropped,
+    /// for tests that want a store without touching disk. Each call is a different datastore.
+    pub fn memory() -> KVLiteOptions {
+        SqliteConnectOptions::from_str("sqlite::memory:").expect("sqlite::memory: is a valid URL").into()
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.sqlite = self.sqlite.read_only(read_only);
+        self
+    }
+
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.sqlite = self.sqlite.create_if_missing(create);
+        self
+    }
+
+    /// How the datastore keeps its journal. WAL lets reads carry on during a write, and stays set
+    /// on the file for every connection after.
+    pub fn journal_mode(mut self, mode: SqliteJournalMode) -> Self {
+        self.sqlite = self.sqlite.journal_mode(mode);
+        self
+    }
+
+    /// How often SQLite waits for writes to reach the disk. NORMAL is safe from corruption in WAL mode,
+    /// and much faster than the default FULL.
+    pub fn synchronous(mut self, synchronous: SqliteSynchronous) -> Self {
+        self.sqlite = self.sqlite.synchronous(synchronous);
+        self
+    }
+
+    /// How long a connection waits for another's lock before failing with StoreLocked, 5 seconds by default
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.sqlite = self.sqlite.busy_timeout(timeout);
+        self
+    }
+
+    /// Pages cached by each connection, or KiB of cache if negative, as in PRAGMA cache_size
+    pub fn cache_size(mut self, size: i64) -> Self {
+        self.sqlite = self.sqlite.pragma("cache_size", size.to_string());
+        self
+    }
+
+    /// Most connections kept open to the datastore at once
+    pub fn pool_size(mut self, size: u32) -> Self {
+        self.pool_size = size.max(1);
+        self
+    }
+
+    /// Key collation the store is created with, if it doesn't exist yet
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.collation = collation;
+        self
+    }
+
+    /// Change the SQLite connection options directly, for settings without a method here
+    pub fn sqlite(mut self, f: impl FnOnce(SqliteConnectOptions) -> SqliteConnectOptions) -> Self {
+        self.sqlite = f(self.sqlite);
+        self
+    }
+
+    /// Open a store in the datastore, creating it if it doesn't exist yet
+    pub async fn open(self, kv_name: &str) -> Result<KVLite, DsError> {
+        KVLite::connect_pool(self.sqlite, self.pool_size, kv_name, self.collation).await
+    }
+
+    /// Size of the datastore and of every store in it, without opening a store, so none is created
+    pub async fn datastore_stats(self) -> Result<DatastoreStats, DsError> {
+        KVLite::stats_at(self.sqlite).await
+    }
+
+    /// Record the datastore's size in its stats history, without opening a store
+    pub async fn sample_stats(self) -> Result<StatsSample, DsError> {
+        KVLite::sample_stats_at(self.sqlite).await
+    }
+}
+
+impl From<SqliteConnectOptions> for KVLiteOptions {
+    fn from(sqlite: SqliteConnectOptions) -> Self {
+        KVLiteOptions { sqlite, pool_size: DEFAULT_POOL_SIZE, collation: Collation::Binary }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{io, path::Path};
+use sqlx::{QueryBuilder, Row};
+use crate::{KVLite, DsError, RecordMeta, ValueTag, push_live};
+
+/// Where a pointer record's content is kept
+pub(crate) enum Target<'a> {
+    File(&'a Path),
+    Url(&'a str),
+}
+
+impl<'a> Target<'a> {
+    /// The target a pointer names, None if it's neither an absolute path nor a file:, http: or https: URL
+    pub(crate) fn parse(pointer: &'a str) -> Option<Target<'a>> {
+        if pointer.starts_with("http://") || pointer.starts_with("https://") {
+            return Some(Target::Url(pointer));
+        }
+        let path = Path::new(pointer.strip_prefix("file://").unwrap_or(pointer));
+        path.is_absolute().then_some(Target::File(path))
+    }
+
+    async fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Target::File(path) => tokio::task::spawn_blocking({
+                    let path = path.to_path_buf();
+                    move || std::fs::read(path)
+                })
+                .await?,
+            Target::Url(url) => fetch(url).await,
+        }
+    }
+
+    async fn exists(&self) -> io::Result<bool> {
+        match self {
+            Target::File(path) => Ok(path.is_file()),
+            Target::Url(url) => head(url).await,
+        }
+    }
+}
+
+#[cfg(feature = "http-pointers")]
+async fn fetch(url: &str) -> io::Result<Vec<u8>> {
+    let res = reqwest::get(url).await.and_then(|res| res.error_for_status()).map_err(io::Error::other)?;
+    Ok(res.bytes().await.map_err(io::Error::other)?.to_vec())
+}
+
+#[cfg(feature = "http-pointers")]
+async fn head(url: &str) -> io::Result<bool> {
+    match reqwest::Client::new().head(url).send().await {
+        Ok(res) => Ok(res.status().is_success()),
+        // a server that can't be reached doesn't have the target either
+        Err(e) if e.is_connect() || e.is_timeout() => Ok(false),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+#[cfg(not(feature = "http-pointers"))]
+async fn fetch(_: &str) -> io::Result<Vec<u8>> {
+    Err(io::Error::other("pointers to URLs need the http-pointers feature"))
+}
+
+#[cfg(not(feature = "http-pointers"))]
+async fn head(url: &str) -> io::Result<bool> {
+    fetch(url).await.map(|_| false)
+}
+
+impl KVLite {
+    /// A record's value and metadata, with a pointer record's value replaced by the content it
+    /// points at, read from its file or fetched from its URL. The metadata is then that of the
+    /// content, which has no type.
+    pub async fn get_resolved(&self, key: &str) -> Result<(Vec<u8>, RecordMeta), DsError> {
+        let (value, mut meta) = self.get_with_meta(key).await?;
+        if meta.tag != Some(ValueTag::Pointer) {
+            return Ok((value, meta));
+        }
+        let pointer = String::from_utf8_lossy(&value);
+        let content = match Target::parse(&pointer) {
+            Some(target) => target.read().await.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", pointer, e)))?,
+            None => return Err(DsError::Sql(sqlx::Error::Decode(format!("{} is not a valid pointer", pointer).into()))),
+        };
+        meta.size = content.len() as u64;
+        meta.tag = None;
+        Ok((content, meta))
+    }
+
+    /// Pointer records whose target doesn't exist, a file that's missing or a URL that doesn't
+    /// answer a HEAD request with success, as (key, pointer)
+    pub async fn dangling_pointers(&self) -> Result<Vec<(String, String)>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key, value FROM {} WHERE tag = 'pointer'
+            "#, self.readable_records()));
+        push_live(&mut query, &mut true);
+        query.push(" ORDER BY key");
+        let pointers: Vec<(String, String)> = self.fetch_all(&mut conn, query.build()).await?
+            .iter()
+            .map(|row| (row.get("key"), row.get("value")))
+            .collect();
+        drop(conn);
+
+        let mut dangling = vec![];
+        for (key, pointer) in pointers {
+            let exists = match Target::parse(&pointer) {
+                Some(target) => target.exists().await?,
+                None => false,
+            };
+            if !exists {
+                dangling.push((key, pointer));
+            }
+        }
+        Ok(dangling)
+    }
+}
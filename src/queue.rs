@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+use sqlx::{sqlite::SqliteConnection, QueryBuilder, Row};
+use crate::{KVLite, DsError, Explain, Table, now};
+
+/// How often pop_wait looks for a value while a queue is empty
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl KVLite {
+    fn queue_table(&self) -> Table {
+        self.kv_name.object("queue")
+    }
+
+    async fn create_queue_table(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {table} (seq INTEGER PRIMARY KEY AUTOINCREMENT, queue TEXT NOT NULL, value TEXT NOT NULL, pushed_at INTEGER NOT NULL);
+                CREATE INDEX IF NOT EXISTS {index} ON {unqualified} (queue, seq);
+            "#, table = self.queue_table(), index = self.kv_name.object("queue_order"), unqualified = self.queue_table().unqualified()))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Add a value to the end of a named FIFO queue kept alongside the store, rather than in one
+    /// of its records, so pushes and pops don't rewrite the whole queue. Returns the queue's new length.
+    pub async fn push(&self, queue: &str, value: &str) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_queue_table(&mut conn).await?;
+        self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                INSERT INTO {} (queue, value, pushed_at) VALUES (
+            "#, self.queue_table()))
+            .push_bind(queue)
+            .push(",")
+            .push_bind(value)
+            .push(",")
+            .push_bind(now())
+            .push(")")
+            .build())
+            .await?;
+        self.queue_len_in(&mut conn, queue).await
+    }
+
+    /// Remove and return the value at the front of a queue, None if it's empty. It's one statement,
+    /// so two processes popping at once never get the same value.
+    pub async fn pop(&self, queue: &str) -> Result<Option<String>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_queue_table(&mut conn).await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                DELETE FROM {table} WHERE seq = (SELECT min(seq) FROM {table} WHERE queue =
+            "#, table = self.queue_table()));
+        query.push_bind(queue).push(") RETURNING value");
+
+        // the statement writes, so in explain mode it's only printed
+        if self.explain != Explain::Off {
+            self.execute(&mut conn, query.build()).await?;
+            return Ok(None);
+        }
+        Ok(self.fetch_optional(&mut conn, query.build()).await?.map(|row| row.get("value")))
+    }
+
+    /// Pop a value, waiting up to timeout for one to be pushed, by any process, if the queue is
+    /// empty. None if it's still empty after that.
+    pub async fn pop_wait(&self, queue: &str, timeout: Duration) -> Result<Option<String>, DsError> {
+        let start = Instant::now();
+        loop {
+            match self.pop(queue).await {
+                Ok(Some(value)) => return Ok(Some(value)),
+                // another process is writing, perhaps pushing, so it's worth trying again
+                Ok(None) | Err(DsError::StoreLocked) if start.elapsed() < timeout => tokio::time::sleep(POLL_INTERVAL).await,
+                res => return res,
+            }
+        }
+    }
+
+    /// How many values a queue holds
+    pub async fn queue_len(&self, queue: &str) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_queue_table(&mut conn).await?;
+        self.queue_len_in(&mut conn, queue).await
+    }
+
+    async fn queue_len_in(&self, conn: &mut SqliteConnection, queue: &str) -> Result<u64, DsError> {
+        Ok(self.fetch_one(conn, QueryBuilder::new(format!(r#"
+                SELECT count(*) AS len FROM {} WHERE queue =
+            "#, self.queue_table()))
+            .push_bind(queue)
+            .build())
+            .await?
+            .get::<i64, &str>("len") as u64)
+    }
+}
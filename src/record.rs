@@ -0,0 +1,36 @@
+use crate::{KVLite, DsError, ListOptions, RecordMeta};
+
+const META_COLUMNS: &str = "key, length(CAST(value AS BLOB)) AS size, expires_at, content_type, created_at, updated_at, tag, pinned, source";
+
+/// A record's metadata, read without its value, which is only read by load_value. Listing these
+/// is cheap however large the values are.
+#[derive(Clone)]
+pub struct Record<'s> {
+    store: &'s KVLite,
+    pub meta: RecordMeta,
+}
+
+impl Record<'_> {
+    /// Read the record's value as it is now, failing with NotFound if it's been deleted or has
+    /// expired since its metadata was read
+    pub async fn load_value(&self) -> Result<Vec<u8>, DsError> {
+        let mut conn = self.store.pool.acquire().await?;
+        self.store.get_bytes_in(&mut conn, &self.meta.key).await
+    }
+}
+
+impl KVLite {
+    /// A record's metadata, with its value left to be loaded when it's needed
+    pub async fn get_meta(&self, key: &str) -> Result<Record<'_>, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let row = self.fetch_record(&mut conn, "", key).await?;
+        Ok(Record { store: self, meta: Self::record_meta(&row) })
+    }
+
+    /// The metadata of a listing's records, with their values left to be loaded when they're needed
+    pub async fn list_meta(&self, options: &ListOptions) -> Result<Vec<Record<'_>>, DsError> {
+        let rows = self.list(META_COLUMNS, options).await?;
+        Ok(rows.iter().map(|row| Record { store: self, meta: Self::record_meta(row) }).collect())
+    }
+}
@@ -0,0 +1,115 @@
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Table, ValueType, now, push_live};
+
+/// What deleting a record does to the records in reference stores naming it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnReferenced {
+    /// Delete the record, leaving references to it dangling
+    #[default]
+    Ignore,
+    /// Refuse to delete a record that is referenced
+    Restrict,
+    /// Also delete the records referencing it, and the records referencing those
+    Cascade,
+}
+
+impl KVLite {
+    /// The store a store's values name keys of, if it's a reference store
+    async fn referenced_store(&self, conn: &mut SqliteConnection, store: &Table) -> Result<Option<Table>, DsError> {
+        Ok(match self.load_setting(conn, store, "type").await?.and_then(|value_type| value_type.parse().ok()) {
+            Some(ValueType::Reference(Some(target))) => Some(Table::new(&target)),
+            Some(ValueType::Reference(None)) => Some(store.clone()),
+            _ => None,
+        })
+    }
+
+    /// Records of a reference store whose value names a key missing from the store it references,
+    /// as (key, value). Empty if the store isn't a reference store.
+    pub async fn dangling(&self) -> Result<Vec<(String, String)>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let target = match self.referenced_store(&mut conn, &self.kv_name).await? {
+            Some(target) => target,
+            None => return Ok(vec![]),
+        };
+
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key, value FROM {} AS s
+            "#, self.readable_records()));
+        push_live(&mut query, &mut false);
+        // references name keys exactly, whatever the collation of the store they reference
+        query.push(format!(" AND NOT EXISTS (SELECT 1 FROM {} AS t WHERE t.key = s.value COLLATE BINARY AND (t.expires_at IS NULL OR t.expires_at > ", target))
+            .push_bind(now())
+            .push(")) ORDER BY key");
+        let rows = self.fetch_all(&mut conn, query.build()).await?;
+        Ok(rows.iter().map(|row| (row.get("key"), row.get("value"))).collect())
+    }
+
+    /// Delete a record, deciding what happens to the records in reference stores naming it.
+    /// Returns the records deleted because they referenced it, as (store, key).
+    pub async fn del_with(&self, key: &str, on_referenced: OnReferenced) -> Result<Vec<(String, String)>, DsError> {
+        if on_referenced == OnReferenced::Ignore {
+            self.del(key).await?;
+            return Ok(vec![]);
+        }
+        let key = &self.canonical_key(key)[..];
+
+        let mut conn = self.pool.acquire().await?;
+        self.secure_connection(&mut conn).await?;
+        // every reference store, with the store it references
+        let mut links = vec![];
+        for store in self.stores().await? {
+            let store = Table::new(&store);
+            if let Some(target) = self.referenced_store(&mut conn, &store).await? {
+                links.push((store, target));
+            }
+        }
+
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        self.del_in(&mut tx, key).await?;
+
+        let mut pending = vec![(self.kv_name.clone(), key.to_string())];
+        let mut deleted = vec![];
+        while let Some((store, key)) = pending.pop() {
+            for (referrer, _) in links.iter().filter(|(_, target)| target.name == store.name) {
+                let mut query = QueryBuilder::new(format!(r#"
+                        SELECT key FROM {} WHERE value =
+                    "#, referrer));
+                query.push_bind(key.as_str());
+                push_live(&mut query, &mut true);
+                let referring = self.fetch_all(&mut tx, query.build()).await?;
+
+                for row in referring {
+                    let referring_key: String = row.get("key");
+                    if on_referenced == OnReferenced::Restrict {
+                        tx.rollback().await?;
+                        return Err(DsError::Sql(sqlx::Error::Decode(format!("{} is referenced by {} in {}", key, referring_key, referrer.name).into())));
+                    }
+                    self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                            DELETE FROM {} WHERE key=
+                        "#, referrer))
+                        .push_bind(referring_key.as_str())
+                        .build())
+                        .await?;
+                    pending.push((referrer.clone(), referring_key.clone()));
+                    deleted.push((referrer.name.clone(), referring_key));
+                }
+            }
+        }
+        tx.commit().await?;
+        let mine: Vec<&str> = deleted.iter()
+            .filter(|(store, _)| *store == self.kv_name.name)
+            .map(|(_, key)| key.as_str())
+            .chain([key])
+            .collect();
+        self.scrub(&mut conn, &mine).await?;
+
+        self.notify(ChangeKind::Delete, key, None);
+        for (store, key) in &deleted {
+            if *store == self.kv_name.name {
+                self.notify(ChangeKind::Delete, key, None);
+            }
+        }
+        Ok(deleted)
+    }
+}
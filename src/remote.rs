@@ -0,0 +1,403 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+use sqlx::{QueryBuilder, Row};
+#[cfg(feature = "mysql")]
+use sqlx::mysql::{MySql, MySqlPool};
+#[cfg(feature = "postgres")]
+use sqlx::postgres::{PgPool, Postgres};
+use crate::{DsError, Filter, KvBackend, SetOptions, glob_match};
+
+/// Seconds since the unix epoch, the unit expires_at is stored in
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs() as i64)
+}
+
+/// Errors from a server database. Their codes aren't SQLite's, so they're never taken as a
+/// locked or read-only datastore.
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+fn remote_error(e: sqlx::Error) -> DsError {
+    match e {
+        sqlx::Error::RowNotFound => DsError::NotFound,
+        sqlx::Error::Io(e) => DsError::Io(e),
+        e => DsError::Sql(e),
+    }
+}
+
+/// Keys passing the parts of a filter a server database doesn't check, at most limit of them
+fn filter_keys(rows: Vec<(String, Vec<u8>)>, filter: &Filter, limit: Option<u64>) -> Vec<String> {
+    rows.into_iter()
+        .filter(|(key, value)| {
+            filter.glob.as_ref().is_none_or(|glob| glob_match(glob, key))
+                && filter.value_contains.as_ref().is_none_or(|contains| {
+                    contains.is_empty() || value.windows(contains.len()).any(|window| window == contains.as_bytes())
+                })
+        })
+        .map(|(key, _)| key)
+        .take(limit.map_or(usize::MAX, |limit| limit as usize))
+        .collect()
+}
+
+fn invalid_store_name(store: &str) -> bool {
+    store.is_empty() || !store.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A store kept in a table of a PostgreSQL database, with the record operations of KvBackend, so a
+/// team can share one in a server they already run. Values are kept as bytea.
+#[cfg(feature = "postgres")]
+pub struct PostgresBackend {
+    pool: PgPool,
+    table: String,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresBackend {
+    /// Connect to a postgres:// URL and open a store in it, creating its table if it doesn't exist.
+    /// Store names are limited to letters, digits and _.
+    pub async fn connect(url: &str, store: &str) -> Result<PostgresBackend, DsError> {
+        if invalid_store_name(store) {
+            return Err(DsError::InvalidStoreName(store.to_string()));
+        }
+        let pool = PgPool::connect(url).await.map_err(remote_error)?;
+        let table = format!("\"{}\"", store);
+        sqlx::query(&format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value BYTEA NOT NULL, expires_at BIGINT)
+            "#, table))
+            .execute(&pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(PostgresBackend { pool, table })
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl KvBackend for PostgresBackend {
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        let row = QueryBuilder::<Postgres>::new(format!("SELECT value FROM {} WHERE key = ", self.table))
+            .push_bind(key)
+            .push(" AND (expires_at IS NULL OR expires_at > ")
+            .push_bind(now())
+            .push(")")
+            .build()
+            .fetch_one(&self.pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(row.get("value"))
+    }
+
+    async fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        if let Some(tag) = options.tag {
+            tag.check(value)?;
+        }
+        QueryBuilder::<Postgres>::new(format!("INSERT INTO {} (key, value, expires_at) VALUES (", self.table))
+            .push_bind(key)
+            .push(",")
+            .push_bind(value)
+            .push(",")
+            .push_bind(options.ttl.map(|ttl| now() + ttl.as_secs() as i64))
+            .push(") ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at")
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<(), DsError> {
+        QueryBuilder::<Postgres>::new(format!("DELETE FROM {} WHERE key = ", self.table))
+            .push_bind(key)
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, DsError> {
+        match KvBackend::get_bytes(self, key).await {
+            Ok(_) => Ok(true),
+            Err(DsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list_keys(&self, filter: &Filter, limit: Option<u64>) -> Result<Vec<String>, DsError> {
+        let mut query = QueryBuilder::<Postgres>::new(format!("SELECT key, value FROM {} WHERE (expires_at IS NULL OR expires_at > ", self.table));
+        query.push_bind(now()).push(")");
+        if let Some(prefix) = &filter.prefix {
+            query.push(" AND left(key, char_length(").push_bind(prefix).push(")) = ").push_bind(prefix);
+        }
+        // byte order, as SQLite's binary collation sorts
+        query.push(r#" ORDER BY key COLLATE "C""#);
+        let rows = query.build().fetch_all(&self.pool).await.map_err(remote_error)?;
+        Ok(filter_keys(rows.iter().map(|row| (row.get("key"), row.get("value"))).collect(), filter, limit))
+    }
+}
+
+/// A store kept in a table of a MySQL or MariaDB database, like PostgresBackend. Keys are at most
+/// 255 characters, compared and sorted by their bytes, and values are kept as LONGBLOB.
+#[cfg(feature = "mysql")]
+pub struct MySqlBackend {
+    pool: MySqlPool,
+    table: String,
+}
+
+#[cfg(feature = "mysql")]
+impl MySqlBackend {
+    /// Connect to a mysql:// URL and open a store in it, creating its table if it doesn't exist.
+    /// Store names are limited to letters, digits and _.
+    pub async fn connect(url: &str, store: &str) -> Result<MySqlBackend, DsError> {
+        if invalid_store_name(store) {
+            return Err(DsError::InvalidStoreName(store.to_string()));
+        }
+        let pool = MySqlPool::connect(url).await.map_err(remote_error)?;
+        let table = format!("`{}`", store);
+        sqlx::query(&format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (`key` VARCHAR(255) CHARACTER SET utf8mb4 COLLATE utf8mb4_bin PRIMARY KEY,
+                    value LONGBLOB NOT NULL, expires_at BIGINT)
+            "#, table))
+            .execute(&pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(MySqlBackend { pool, table })
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl KvBackend for MySqlBackend {
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        let row = QueryBuilder::<MySql>::new(format!("SELECT value FROM {} WHERE `key` = ", self.table))
+            .push_bind(key)
+            .push(" AND (expires_at IS NULL OR expires_at > ")
+            .push_bind(now())
+            .push(")")
+            .build()
+            .fetch_one(&self.pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(row.get("value"))
+    }
+
+    async fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        if let Some(tag) = options.tag {
+            tag.check(value)?;
+        }
+        QueryBuilder::<MySql>::new(format!("INSERT INTO {} (`key`, value, expires_at) VALUES (", self.table))
+            .push_bind(key)
+            .push(",")
+            .push_bind(value)
+            .push(",")
+            .push_bind(options.ttl.map(|ttl| now() + ttl.as_secs() as i64))
+            .push(") ON DUPLICATE KEY UPDATE value = VALUES(value), expires_at = VALUES(expires_at)")
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<(), DsError> {
+        QueryBuilder::<MySql>::new(format!("DELETE FROM {} WHERE `key` = ", self.table))
+            .push_bind(key)
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(remote_error)?;
+        Ok(())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, DsError> {
+        match KvBackend::get_bytes(self, key).await {
+            Ok(_) => Ok(true),
+            Err(DsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list_keys(&self, filter: &Filter, limit: Option<u64>) -> Result<Vec<String>, DsError> {
+        let mut query = QueryBuilder::<MySql>::new(format!("SELECT `key`, value FROM {} WHERE (expires_at IS NULL OR expires_at > ", self.table));
+        query.push_bind(now()).push(")");
+        if let Some(prefix) = &filter.prefix {
+            query.push(" AND LEFT(`key`, CHAR_LENGTH(").push_bind(prefix).push(")) = ").push_bind(prefix);
+        }
+        query.push(" ORDER BY `key`");
+        let rows = query.build().fetch_all(&self.pool).await.map_err(remote_error)?;
+        Ok(filter_keys(rows.iter().map(|row| (row.get("key"), row.get("value"))).collect(), filter, limit))
+    }
+}
+
+/// A store in a libsql database, like those Turso hosts, reached over HTTP with its Hrana
+/// pipeline API. Its table has the columns of a KVLite store, so a datastore uploaded to Turso can
+/// be read through it and values written through it keep their created and updated times.
+#[cfg(feature = "libsql")]
+pub struct LibsqlBackend {
+    client: reqwest::Client,
+    /// The database's pipeline endpoint
+    pipeline: String,
+    token: Option<String>,
+    table: String,
+}
+
+#[cfg(feature = "libsql")]
+impl LibsqlBackend {
+    /// Connect to a libsql://, https:// or http:// URL and open a store in it, creating its table if
+    /// it doesn't exist. libsql:// is reached over https. The token is sent as a bearer token, and
+    /// an authToken in the URL's query is used when none is given.
+    pub async fn connect(url: &str, store: &str, token: Option<String>) -> Result<LibsqlBackend, DsError> {
+        if invalid_store_name(store) {
+            return Err(DsError::InvalidStoreName(store.to_string()));
+        }
+        let (base, query) = url.split_once('?').unwrap_or((url, ""));
+        let token = token.or_else(|| query.split('&')
+            .find_map(|pair| pair.strip_prefix("authToken="))
+            .map(str::to_string));
+        let base = match base.strip_prefix("libsql://") {
+            Some(host) => format!("https://{}", host),
+            None => base.to_string(),
+        };
+        let backend = LibsqlBackend {
+            client: reqwest::Client::new(),
+            pipeline: format!("{}/v2/pipeline", base.trim_end_matches('/')),
+            token,
+            table: format!("\"{}\"", store),
+        };
+        backend.execute(&format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY, value TEXT, expires_at INTEGER, content_type TEXT, created_at INTEGER,
+                    updated_at INTEGER, tag TEXT, pinned INTEGER NOT NULL DEFAULT 0, source TEXT)
+            "#, backend.table), vec![]).await?;
+        Ok(backend)
+    }
+
+    /// Run one statement with positional arguments, returning its rows
+    async fn execute(&self, sql: &str, args: Vec<serde_json::Value>) -> Result<Vec<Vec<serde_json::Value>>, DsError> {
+        let error = |message: String| DsError::Sql(sqlx::Error::Protocol(message));
+        let body = serde_json::json!({
+            "requests": [
+                { "type": "execute", "stmt": { "sql": sql, "args": args } },
+                { "type": "close" },
+            ],
+        });
+        let mut request = self.client.post(&self.pipeline).json(&body);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let res = request.send().await.map_err(std::io::Error::other)?;
+        let status = res.status();
+        let res: serde_json::Value = res.json().await
+            .map_err(|e| error(format!("the database answered {} with a body that isn't a pipeline result: {}", status, e)))?;
+        let result = &res["results"][0];
+        match result["type"].as_str() {
+            Some("ok") => Ok(result["response"]["result"]["rows"].as_array()
+                .map(|rows| rows.iter().map(|row| row.as_array().cloned().unwrap_or_default()).collect())
+                .unwrap_or_default()),
+            Some("error") => Err(error(result["error"]["message"].as_str().unwrap_or("unknown error").to_string())),
+            _ => Err(error(format!("the database answered {}: {}", status, res))),
+        }
+    }
+}
+
+/// Statement arguments in Hrana's encoding
+#[cfg(feature = "libsql")]
+fn hrana_text(text: &str) -> serde_json::Value {
+    serde_json::json!({ "type": "text", "value": text })
+}
+
+#[cfg(feature = "libsql")]
+fn hrana_integer(int: Option<i64>) -> serde_json::Value {
+    match int {
+        // integers are sent as strings so they keep all 64 bits
+        Some(int) => serde_json::json!({ "type": "integer", "value": int.to_string() }),
+        None => serde_json::json!({ "type": "null" }),
+    }
+}
+
+/// Values written as text are kept as text, like KVLite keeps them, anything else as a blob
+#[cfg(feature = "libsql")]
+fn hrana_value(value: &[u8]) -> serde_json::Value {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    match std::str::from_utf8(value) {
+        Ok(text) => hrana_text(text),
+        Err(_) => serde_json::json!({ "type": "blob", "base64": STANDARD.encode(value) }),
+    }
+}
+
+/// The bytes of a text or blob column
+#[cfg(feature = "libsql")]
+fn hrana_bytes(value: &serde_json::Value) -> Result<Vec<u8>, DsError> {
+    use base64::{Engine, alphabet, engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig}};
+    // servers differ on whether blobs are padded
+    const BLOB: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent));
+    match (value["type"].as_str(), &value["value"], &value["base64"]) {
+        (Some("text"), serde_json::Value::String(text), _) => Ok(text.clone().into_bytes()),
+        (Some("blob"), _, serde_json::Value::String(encoded)) => BLOB.decode(encoded)
+            .map_err(|e| DsError::Sql(sqlx::Error::Decode(e.into()))),
+        (Some("null"), _, _) => Ok(vec![]),
+        _ => Err(DsError::Sql(sqlx::Error::Decode(format!("unexpected column {}", value).into()))),
+    }
+}
+
+#[cfg(feature = "libsql")]
+impl KvBackend for LibsqlBackend {
+    async fn get_bytes(&self, key: &str) -> Result<Vec<u8>, DsError> {
+        let rows = self.execute(&format!(r#"
+                SELECT value FROM {} WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)
+            "#, self.table), vec![hrana_text(key), hrana_integer(Some(now()))]).await?;
+        match rows.first().and_then(|row| row.first()) {
+            Some(value) => hrana_bytes(value),
+            None => Err(DsError::NotFound),
+        }
+    }
+
+    async fn set_with(&self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        if let Some(tag) = options.tag {
+            tag.check(value)?;
+        }
+        let now = now();
+        // overwriting keeps when the record was created, unless it had expired
+        self.execute(&format!(r#"
+                INSERT INTO {} (key, value, expires_at, content_type, created_at, updated_at, tag) VALUES (?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = CASE WHEN pinned THEN NULL ELSE excluded.expires_at END,
+                    content_type = excluded.content_type, tag = excluded.tag,
+                    created_at = CASE WHEN expires_at <= ? THEN excluded.created_at ELSE created_at END, updated_at = excluded.updated_at
+            "#, self.table), vec![
+                hrana_text(key),
+                hrana_value(value),
+                hrana_integer(options.ttl.map(|ttl| now + ttl.as_secs() as i64)),
+                options.content_type.as_deref().map_or(serde_json::json!({ "type": "null" }), hrana_text),
+                hrana_integer(Some(now)),
+                hrana_integer(Some(now)),
+                options.tag.map_or(serde_json::json!({ "type": "null" }), |tag| hrana_text(&tag.to_string())),
+                hrana_integer(Some(now)),
+            ]).await?;
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<(), DsError> {
+        self.execute(&format!("DELETE FROM {} WHERE key = ?", self.table), vec![hrana_text(key)]).await?;
+        Ok(())
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, DsError> {
+        match KvBackend::get_bytes(self, key).await {
+            Ok(_) => Ok(true),
+            Err(DsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list_keys(&self, filter: &Filter, limit: Option<u64>) -> Result<Vec<String>, DsError> {
+        let mut sql = format!("SELECT key, value FROM {} WHERE (expires_at IS NULL OR expires_at > ?)", self.table);
+        let mut args = vec![hrana_integer(Some(now()))];
+        if let Some(prefix) = &filter.prefix {
+            sql.push_str(" AND substr(key, 1, length(?)) = ?");
+            args.extend([hrana_text(prefix), hrana_text(prefix)]);
+        }
+        sql.push_str(" ORDER BY key");
+        let mut rows = vec![];
+        for row in self.execute(&sql, args).await? {
+            let key = row.first().and_then(|key| key["value"].as_str()).unwrap_or_default().to_string();
+            rows.push((key, row.get(1).map(hrana_bytes).transpose()?.unwrap_or_default()));
+        }
+        Ok(filter_keys(rows, filter, limit))
+    }
+}
@@ -0,0 +1,292 @@
+use std::{fmt, str::FromStr};
+use serde::{Serialize, Deserialize};
+use sqlx::{sqlite::{SqliteConnection, SqliteRow}, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind};
+
+/// A record written on a primary, as a standby applies it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub key: String,
+    /// The record's value when the change was read, None if it's been deleted or expired since
+    #[serde(with = "base64_value")]
+    pub value: Option<Vec<u8>>,
+    /// Seconds since the unix epoch, like created_at and updated_at
+    pub expires_at: Option<i64>,
+    pub content_type: Option<String>,
+    pub created_at: Option<i64>,
+    pub updated_at: Option<i64>,
+    pub tag: Option<String>,
+    pub pinned: bool,
+    /// Who wrote the record on the primary, kept by the standby in place of its own source
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// Changes read from a primary's change log, or every record of it in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Changes {
+    /// The primary's generation, which a standby refuses to follow backwards
+    pub generation: u64,
+    /// Position in the primary's change log these changes bring a standby up to
+    pub seq: i64,
+    pub changes: Vec<Change>,
+}
+
+/// How far through a primary's change log a client has seen the store, handed to it as
+/// generation:seq so a standby can tell whether it has caught up with what the client read or wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub generation: u64,
+    pub seq: i64,
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.generation, self.seq)
+    }
+}
+
+impl FromStr for Session {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once(':')
+            .and_then(|(generation, seq)| Some(Session { generation: generation.parse().ok()?, seq: seq.parse().ok()? }))
+            .ok_or_else(|| format!("invalid session {}, expected generation:seq", s))
+    }
+}
+
+/// Values are sent as base64, so BLOBs survive JSON
+pub(crate) mod base64_value {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(value: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_some(&STANDARD.encode(value)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| STANDARD.decode(value).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+impl KVLite {
+    /// How many times the store has been promoted from a standby, 0 if never
+    pub async fn generation(&self) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.generation_in(&mut conn).await
+    }
+
+    async fn generation_in(&self, conn: &mut SqliteConnection) -> Result<u64, DsError> {
+        Ok(self.load_setting(conn, &self.kv_name, "generation").await?
+            .and_then(|generation| generation.parse().ok())
+            .unwrap_or(0))
+    }
+
+    /// The primary the store is a standby of, if it is one
+    pub async fn primary(&self) -> Result<Option<String>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.load_setting(&mut conn, &self.kv_name, "primary").await
+    }
+
+    /// Make the store a standby of a primary. Following a different primary than before
+    /// starts again from a snapshot of it, throwing away writes the primaries don't share.
+    pub async fn follow(&self, primary: &str) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        self.create_settings(&mut tx).await?;
+        if self.load_setting(&mut tx, &self.kv_name, "primary").await?.as_deref() != Some(primary) {
+            self.delete_setting(&mut tx, "replica_seq").await?;
+        }
+        self.save_setting(&mut tx, "primary", primary).await?;
+        Ok(tx.commit().await?)
+    }
+
+    /// Stop following the primary and start a new generation, so standbys refuse
+    /// to follow the old primary from then on. Returns the new generation.
+    pub async fn promote(&self) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        self.create_settings(&mut tx).await?;
+        let generation = self.generation_in(&mut tx).await? + 1;
+        self.save_setting(&mut tx, "generation", &generation.to_string()).await?;
+        self.delete_setting(&mut tx, "primary").await?;
+        self.delete_setting(&mut tx, "replica_seq").await?;
+        tx.commit().await?;
+        Ok(generation)
+    }
+
+    /// Position in the primary's change log the standby has applied up to,
+    /// None if it has to start from a snapshot
+    pub async fn replica_seq(&self) -> Result<Option<i64>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(self.load_setting(&mut conn, &self.kv_name, "replica_seq").await?.and_then(|seq| seq.parse().ok()))
+    }
+
+    /// A record as it was written, with the store's value transforms undone
+    /// How far through its primary's change log the store is: on a primary the last change
+    /// written to it, and on a standby the last change of its primary it has applied
+    pub async fn session(&self) -> Result<Session, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let generation = self.generation_in(&mut conn).await?;
+        let seq = match self.load_setting(&mut conn, &self.kv_name, "primary").await? {
+            Some(_) => self.load_setting(&mut conn, &self.kv_name, "replica_seq").await?.and_then(|seq| seq.parse().ok()).unwrap_or(0),
+            None => self.last_seq(&mut conn).await?,
+        };
+        Ok(Session { generation, seq })
+    }
+
+    /// Whether the store has everything a client saw in a session. A session of an older generation
+    /// is of a primary that was superseded, so anything it saw that the store doesn't have is lost.
+    pub async fn caught_up(&self, seen: &Session) -> Result<bool, DsError> {
+        let session = self.session().await?;
+        Ok(seen.generation < session.generation || (seen.generation == session.generation && seen.seq <= session.seq))
+    }
+
+    fn change(&self, row: &SqliteRow) -> Result<Change, DsError> {
+        Ok(Change {
+            key: row.get("key"),
+            value: row.get::<Option<Vec<u8>>, &str>("value").map(|value| self.untransform_value(value)).transpose()?,
+            expires_at: row.get("expires_at"),
+            content_type: row.get("content_type"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+            tag: row.get("tag"),
+            pinned: row.get::<Option<bool>, &str>("pinned").unwrap_or_default(),
+            source: row.get("source"),
+        })
+    }
+
+    /// The last position written to the store's change log, which is kept when the log is pruned
+    async fn last_seq(&self, conn: &mut SqliteConnection) -> Result<i64, DsError> {
+        Ok(self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT seq FROM sqlite_sequence WHERE name=
+            "#)
+            .push_bind(self.kv_name.object("changes").name)
+            .build())
+            .await?
+            .map(|row| row.get("seq"))
+            .unwrap_or(0))
+    }
+
+    /// Up to limit changes logged after a position, each with the record as it is now.
+    /// None if the log no longer goes back that far, or never reached it, and a standby
+    /// has to start again from a snapshot.
+    pub async fn changes_since(&self, after: i64, limit: u64) -> Result<Option<Changes>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let log = self.kv_name.object("changes");
+        let first: Option<i64> = self.fetch_one(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT min(seq) AS first FROM {}
+            "#, log))
+            .build())
+            .await?
+            .get("first");
+        let last = self.last_seq(&mut tx).await?;
+        if after > last || first.map_or(after < last, |first| after < first - 1) {
+            return Ok(None);
+        }
+
+        let rows = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT c.seq, c.key, s.value, s.expires_at, s.content_type, s.created_at, s.updated_at, s.tag, s.pinned, s.source
+                FROM {} AS c LEFT JOIN {} AS s ON s.key = c.key WHERE c.seq >
+            "#, log, self.readable_records()))
+            .push_bind(after)
+            .push(" ORDER BY c.seq LIMIT ")
+            .push_bind(limit as i64)
+            .build())
+            .await?;
+        let generation = self.generation_in(&mut tx).await?;
+        tx.commit().await?;
+        Ok(Some(Changes {
+            generation,
+            seq: rows.last().map_or(after, |row| row.get("seq")),
+            changes: rows.iter().map(|row| self.change(row)).collect::<Result<_, _>>()?,
+        }))
+    }
+
+    /// Every record in the store, with the position in its change log they're up to date with
+    pub async fn replication_snapshot(&self) -> Result<Changes, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let rows = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT * FROM {} ORDER BY key
+            "#, self.readable_records()))
+            .build())
+            .await?;
+        let seq = self.last_seq(&mut tx).await?;
+        let generation = self.generation_in(&mut tx).await?;
+        tx.commit().await?;
+        Ok(Changes { generation, seq, changes: rows.iter().map(|row| self.change(row)).collect::<Result<_, _>>()? })
+    }
+
+    /// Apply a primary's changes in one transaction, or with replace a snapshot of it in place
+    /// of every record. Changes from a primary of an older generation than the store are refused,
+    /// it was superseded by a promotion.
+    pub async fn apply_changes(&self, changes: &Changes, replace: bool) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let generation = self.generation_in(&mut conn).await?;
+        if changes.generation < generation {
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!(
+                "the primary is at generation {}, behind this store's {}, so it was superseded", changes.generation, generation).into())));
+        }
+
+        let mut tx = conn.begin().await?;
+        if replace {
+            self.execute(&mut tx, QueryBuilder::new(format!("DELETE FROM {}", self.kv_name)).build()).await?;
+        }
+        for change in &changes.changes {
+            let value = match &change.value {
+                Some(value) => value,
+                None => {
+                    self.del_in(&mut tx, &change.key).await?;
+                    continue;
+                },
+            };
+            let mut query = QueryBuilder::new(format!(r#"
+                    INSERT INTO {} (key, value, expires_at, content_type, created_at, updated_at, tag, pinned, source) VALUES (
+                "#, self.kv_name));
+            query.push_bind(&change.key).push(",");
+            // values are sent as written, and stored as the standby's own settings have them
+            let mut value = value.clone();
+            if let Some(transformed) = self.transform_value(&value)? {
+                value = transformed;
+            }
+            if let Some(reference) = self.dedup_value(&mut tx, &value).await? {
+                value = reference.into_bytes();
+            }
+            match String::from_utf8(value) {
+                Ok(text) => query.push_bind(text),
+                Err(e) => query.push_bind(e.into_bytes()),
+            };
+            query.push(",").push_bind(change.expires_at)
+                .push(",").push_bind(change.content_type.as_deref())
+                .push(",").push_bind(change.created_at)
+                .push(",").push_bind(change.updated_at)
+                .push(",").push_bind(change.tag.as_deref())
+                .push(",").push_bind(change.pinned)
+                .push(",").push_bind(change.source.as_deref().or(self.source.as_deref()))
+                .push(r#") ON CONFLICT(key) DO UPDATE SET value=excluded.value, expires_at=excluded.expires_at,
+                    content_type=excluded.content_type, created_at=excluded.created_at, updated_at=excluded.updated_at,
+                    tag=excluded.tag, pinned=excluded.pinned, source=excluded.source"#);
+            self.execute(&mut tx, query.build()).await?;
+        }
+        self.create_settings(&mut tx).await?;
+        self.save_setting(&mut tx, "replica_seq", &changes.seq.to_string()).await?;
+        self.save_setting(&mut tx, "generation", &changes.generation.to_string()).await?;
+        tx.commit().await?;
+
+        for change in &changes.changes {
+            match &change.value {
+                Some(value) => self.notify(ChangeKind::Set, &change.key, Some(&String::from_utf8_lossy(value))),
+                None => self.notify(ChangeKind::Delete, &change.key, None),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader}, net::TcpListener};
+use crate::{KVLite, DsError, ListOptions, SetOptions, glob_match};
+
+/// A reply in the Redis serialization protocol
+enum Reply {
+    Ok,
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Ok => out.extend_from_slice(b"+OK\r\n"),
+            Reply::Error(e) => out.extend_from_slice(format!("-ERR {}\r\n", e.replace(['\r', '\n'], " ")).as_bytes()),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(value)) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            },
+            Reply::Array(replies) => {
+                out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    reply.write_to(out);
+                }
+            },
+        }
+    }
+}
+
+/// Read the next command, either a RESP array of bulk strings or an inline command.
+/// Returns None once the client disconnects.
+async fn read_command<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let line = line.trim_end();
+    let count = match line.strip_prefix('*') {
+        Some(count) => count.parse::<usize>().map_err(|_| invalid("invalid array length"))?,
+        None => return Ok(Some(line.split_whitespace().map(|arg| arg.as_bytes().to_vec()).collect())),
+    };
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut header = String::new();
+        reader.read_line(&mut header).await?;
+        let len = header.trim_end()
+            .strip_prefix('$')
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| invalid("expected a bulk string"))?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn to_reply<T>(res: Result<T, DsError>, map: impl FnOnce(T) -> Reply) -> Reply {
+    match res {
+        Ok(value) => map(value),
+        Err(e) => Reply::Error(e.to_string()),
+    }
+}
+
+fn strings(values: Vec<String>) -> Reply {
+    Reply::Array(values.into_iter().map(|value| Reply::Bulk(Some(value.into_bytes()))).collect())
+}
+
+async fn run(store: &KVLite, args: &[Vec<u8>]) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let keys: Vec<String> = args[1..].iter().map(|arg| String::from_utf8_lossy(arg).into_owned()).collect();
+
+    match (name.as_str(), keys.len()) {
+        ("PING", 0) => Reply::Bulk(Some(b"PONG".to_vec())),
+        // clients like redis-cli ask for command docs on connect
+        ("COMMAND", _) => Reply::Array(vec![]),
+        ("GET", 1) => match store.get_bytes(&keys[0]).await {
+            Err(DsError::NotFound) => Reply::Bulk(None),
+            res => to_reply(res, |value| Reply::Bulk(Some(value))),
+        },
+        ("SET", 2) | ("SET", 4) => {
+            let ttl = match keys.get(2).map(|option| option.to_uppercase()) {
+                None => None,
+                Some(option) if option == "EX" => match keys[3].parse() {
+                    Ok(secs) => Some(Duration::from_secs(secs)),
+                    Err(_) => return Reply::Error("value is not an integer or out of range".to_string()),
+                },
+                Some(option) => return Reply::Error(format!("unsupported SET option {}", option)),
+            };
+            let res = store.set_with(&keys[0], &args[2], &SetOptions { ttl, ..Default::default() }).await;
+            to_reply(res.and(store.purge_expired().await.map(|_| ())), |_| Reply::Ok)
+        },
+        ("DEL", 1..) | ("EXISTS", 1..) => {
+            let mut count = 0;
+            for key in &keys {
+                match store.contains(key).await {
+                    Ok(true) => count += 1,
+                    Ok(false) => continue,
+                    Err(e) => return Reply::Error(e.to_string()),
+                }
+                if name == "DEL" {
+                    if let Err(e) = store.del(key).await {
+                        return Reply::Error(e.to_string());
+                    }
+                }
+            }
+            Reply::Integer(count)
+        },
+        ("KEYS", 1) => to_reply(store.keys(&ListOptions { sort: true, ..Default::default() }).await, |rows| {
+            Reply::Array(rows.iter()
+                .map(|row| sqlx::Row::get::<String, &str>(row, "key"))
+                .filter(|key| glob_match(&keys[0], key))
+                .map(|key| Reply::Bulk(Some(key.into_bytes())))
+                .collect())
+        }),
+        ("TTL", 1) => match store.ttl(&keys[0]).await {
+            Err(DsError::NotFound) => Reply::Integer(-2),
+            res => to_reply(res, |ttl| Reply::Integer(ttl.map_or(-1, |ttl| ttl.as_secs() as i64))),
+        },
+        ("LPUSH", 2..) | ("RPUSH", 2..) | ("SADD", 2..) | ("SREM", 2..) => {
+            let values: Vec<&str> = keys[1..].iter().map(String::as_str).collect();
+            let res = match name.as_str() {
+                "LPUSH" => store.lpush(&keys[0], &values).await,
+                "RPUSH" => store.rpush(&keys[0], &values).await,
+                "SADD" => store.sadd(&keys[0], &values).await,
+                _ => store.srem(&keys[0], &values).await,
+            };
+            to_reply(res, |len| Reply::Integer(len as i64))
+        },
+        ("LPOP", 1) => to_reply(store.lpop(&keys[0]).await, |value| Reply::Bulk(value.map(String::into_bytes))),
+        ("LRANGE", 3) => match (keys[1].parse(), keys[2].parse()) {
+            (Ok(start), Ok(stop)) => to_reply(store.lrange(&keys[0], start, stop).await, strings),
+            _ => Reply::Error("value is not an integer or out of range".to_string()),
+        },
+        ("SMEMBERS", 1) => to_reply(store.smembers(&keys[0]).await, strings),
+        ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("KEYS", _) | ("TTL", _)
+            | ("LPUSH", _) | ("RPUSH", _) | ("LPOP", _) | ("LRANGE", _) | ("SADD", _) | ("SREM", _) | ("SMEMBERS", _) =>
+            Reply::Error(format!("wrong number of arguments for '{}' command", name.to_lowercase())),
+        _ => Reply::Error(format!("unknown command '{}'", name.to_lowercase())),
+    }
+}
+
+/// Answer the commands sent over one connection until the client disconnects
+pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(store: &KVLite, stream: S) -> std::io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    while let Some(args) = read_command(&mut reader).await? {
+        if args.is_empty() {
+            continue;
+        }
+        let mut out = vec![];
+        run(store, &args).await.write_to(&mut out);
+        writer.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+/// Serve a store to Redis clients, supporting GET, SET, DEL, EXISTS, KEYS and TTL
+pub async fn serve(store: KVLite, addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let store = Arc::new(store);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = store.clone();
+        tokio::spawn(async move {
+            // a client sending garbage only ends its own connection
+            let _ = handle(&store, stream).await;
+        });
+    }
+}
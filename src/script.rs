@@ -0,0 +1,134 @@
+use std::fmt;
+use rhai::{Engine, Dynamic, EvalAltResult};
+use sqlx::Connection;
+use tokio::sync::{mpsc, oneshot};
+use crate::{KVLite, DsError, ChangeKind, Stored};
+
+#[derive(Debug)]
+pub enum EvalError {
+    Database(DsError),
+    Script(String),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::Database(e) => write!(f, "{}", e),
+            EvalError::Script(e) => write!(f, "script failed: {}", e),
+        }
+    }
+}
+
+impl From<DsError> for EvalError {
+    fn from(e: DsError) -> Self {
+        EvalError::Database(e)
+    }
+}
+
+impl From<sqlx::Error> for EvalError {
+    fn from(e: sqlx::Error) -> Self {
+        EvalError::Database(e.into())
+    }
+}
+
+enum Op {
+    Get(String),
+    Set(String, String),
+    Del(String),
+}
+
+type Request = (Op, oneshot::Sender<Result<Option<String>, String>>);
+
+/// Send an operation to the transaction and wait for its result
+fn call(ops: &mpsc::Sender<Request>, op: Op) -> Result<Option<String>, Box<EvalAltResult>> {
+    let (reply, res) = oneshot::channel();
+    ops.blocking_send((op, reply)).map_err(|e| e.to_string())?;
+    Ok(res.blocking_recv().map_err(|e| e.to_string())??)
+}
+
+fn run(script: &str, ops: mpsc::Sender<Request>) -> Result<String, Box<EvalAltResult>> {
+    let mut engine = Engine::new();
+
+    let get = ops.clone();
+    engine.register_fn("get", move |key: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+        Ok(call(&get, Op::Get(key.to_string()))?.map_or(Dynamic::UNIT, Dynamic::from))
+    });
+    let contains = ops.clone();
+    engine.register_fn("contains", move |key: &str| -> Result<bool, Box<EvalAltResult>> {
+        Ok(call(&contains, Op::Get(key.to_string()))?.is_some())
+    });
+    let set = ops.clone();
+    engine.register_fn("set", move |key: &str, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+        call(&set, Op::Set(key.to_string(), value.to_string())).map(|_| ())
+    });
+    engine.register_fn("del", move |key: &str| -> Result<(), Box<EvalAltResult>> {
+        call(&ops, Op::Del(key.to_string())).map(|_| ())
+    });
+
+    let res = engine.eval::<Dynamic>(script)?;
+    Ok(if res.is_unit() { String::new() } else { res.to_string() })
+}
+
+impl KVLite {
+    /// Run a Rhai script against the store in a single transaction.
+    /// The script can call get, contains, set and del, and its value is returned as a string.
+    /// Nothing is written if the script fails.
+    pub async fn eval(&self, script: &str) -> Result<String, EvalError> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+
+        // rhai functions are synchronous, so the script runs on a blocking thread
+        // and sends each operation back here to run in the transaction
+        let (ops, mut requests) = mpsc::channel::<Request>(1);
+        let script = script.to_string();
+        let task = tokio::task::spawn_blocking(move || run(&script, ops).map_err(|e| e.to_string()));
+
+        let mut changes = Vec::new();
+        let mut failed = None;
+        while let Some((op, reply)) = requests.recv().await {
+            let res = match op {
+                Op::Get(key) => match self.get_in(&mut tx, &self.canonical_key(&key)).await {
+                    Ok(value) => Ok(Some(value)),
+                    Err(DsError::NotFound) => Ok(None),
+                    Err(e) => Err(e),
+                },
+                Op::Set(key, value) => {
+                    let key = self.canonical_key(&key).into_owned();
+                    self.set_in(&mut tx, &key, value.as_str(), Stored::default()).await
+                        .map(|_| changes.push((ChangeKind::Set, key, Some(value))))
+                        .map(|_| None)
+                },
+                Op::Del(key) => {
+                    let key = self.canonical_key(&key).into_owned();
+                    self.del_in(&mut tx, &key).await
+                        .map(|_| changes.push((ChangeKind::Delete, key, None)))
+                        .map(|_| None)
+                },
+            };
+            let _ = reply.send(res.map_err(|e| {
+                let msg = e.to_string();
+                failed.get_or_insert(e);
+                msg
+            }));
+        }
+
+        let res = task.await.map_err(|e| EvalError::Script(e.to_string()))?;
+        if let Some(e) = failed {
+            tx.rollback().await?;
+            return Err(EvalError::Database(e));
+        }
+        match res {
+            Ok(value) => {
+                tx.commit().await?;
+                for (kind, key, value) in changes {
+                    self.notify(kind, &key, value.as_deref());
+                }
+                Ok(value)
+            },
+            Err(e) => {
+                tx.rollback().await?;
+                Err(EvalError::Script(e))
+            },
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use sqlx::{sqlite::{SqliteConnection, SqliteRow}, QueryBuilder};
+use crate::{KVLite, DsError, push_live};
+
+impl KVLite {
+    /// Index the store's values in an FTS5 table, if they aren't yet. Triggers keep the index
+    /// in sync with every write from any process, so it's only built from scratch once.
+    async fn create_search_index(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        let search = self.kv_name.object("search");
+        let exists = self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(search.name.as_str())
+            .build())
+            .await?
+            .is_some();
+        if exists {
+            return Ok(());
+        }
+
+        // the index only holds tokens, values are read back from the store by rowid
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE VIRTUAL TABLE {search} USING fts5(value, content='{content}', content_rowid=rowid);
+                CREATE TRIGGER {insert} AFTER INSERT ON {table} BEGIN
+                    INSERT INTO {index} (rowid, value) VALUES (NEW.rowid, NEW.value);
+                END;
+                CREATE TRIGGER {update} AFTER UPDATE OF value ON {table} BEGIN
+                    INSERT INTO {index} ({index}, rowid, value) VALUES ('delete', OLD.rowid, OLD.value);
+                    INSERT INTO {index} (rowid, value) VALUES (NEW.rowid, NEW.value);
+                END;
+                CREATE TRIGGER {delete} AFTER DELETE ON {table} BEGIN
+                    INSERT INTO {index} ({index}, rowid, value) VALUES ('delete', OLD.rowid, OLD.value);
+                END;
+                INSERT INTO {search} ({index}) VALUES ('rebuild');
+            "#,
+                search = search,
+                content = self.kv_name.name.replace('\'', "''"),
+                insert = self.kv_name.object("search_insert"),
+                update = self.kv_name.object("search_update"),
+                delete = self.kv_name.object("search_delete"),
+                table = self.kv_name.unqualified(),
+                index = search.unqualified()))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Records whose values match an FTS5 query, best match first, as key and value rows.
+    /// The first search of a store indexes it, after which the index is kept up to date on every write.
+    /// A store that transforms or deduplicates its values can't be searched.
+    pub async fn search(&self, query: &str, limit: Option<u64>) -> Result<Vec<SqliteRow>, DsError> {
+        // the index is of values as they're stored
+        self.check_untransformed("full-text search")?;
+        let mut conn = self.pool.acquire().await?;
+        self.create_search_index(&mut conn).await?;
+
+        let search = self.kv_name.object("search");
+        let mut select = QueryBuilder::new(format!(r#"
+                SELECT s.key AS key, s.value AS value FROM {} AS s JOIN {} AS f ON f.rowid = s.rowid WHERE f.value MATCH
+            "#, self.readable_records(), search));
+        select.push_bind(query);
+        push_live(&mut select, &mut true);
+        select.push(" ORDER BY f.rank");
+        if let Some(limit) = limit {
+            select.push(" LIMIT ").push_bind(limit as i64);
+        }
+        self.fetch_all(&mut conn, select.build()).await
+    }
+}
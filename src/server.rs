@@ -0,0 +1,1087 @@
+use std::{collections::HashMap, fs, future::Future, io, net::SocketAddr, str::FromStr, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+use axum::{Router, Json, routing::{get, post}, extract::{Extension, FromRef, Path, Query, Request, State}, http::{Method, StatusCode, HeaderMap, HeaderName, HeaderValue, Uri, header},
+    body::{Body, Bytes, to_bytes}, middleware::{self, Next}, response::{Html, IntoResponse, Redirect, Response}};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::Row;
+use tokio::{net::TcpListener, sync::{RwLock, mpsc, oneshot}, task::JoinHandle};
+use tokio_stream::StreamExt;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use crate::{KVLite, DsError, Role, BatchOp, BatchResult, Changes, Session, ListOptions, Filter, RecordMeta, RecordWrite, SetOptions, glob_match};
+
+mod auth;
+pub use auth::{Htpasswd, Jwt};
+
+type Store = State<Arc<KVLite>>;
+
+/// How long a standby waits between polls of its primary once it's caught up
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+/// Changes a standby asks its primary for at a time
+const FOLLOW_BATCH: u64 = 1000;
+/// Largest request body accepted when no other limit is set, in bytes
+pub const DEFAULT_MAX_BODY: usize = 2 * 1024 * 1024;
+/// Changes listed by GET /changes when no limit is given
+const RECENT_CHANGES: u64 = 100;
+/// Size from which a PUT body of a known length is streamed into the store instead of read into memory first
+const STREAMED_BODY: u64 = 64 * 1024;
+
+/// Header a client is handed how far it has seen the store in, and sends back to be served reads
+/// that have caught up with it
+const SESSION_HEADER: &str = "session-token";
+/// How long a standby waits to catch up with a client's session before redirecting its read to the primary
+const SESSION_WAIT: Duration = Duration::from_secs(2);
+/// How often a standby checks whether it has caught up with a client's session
+const SESSION_POLL: Duration = Duration::from_millis(50);
+/// How long the response to a write sent with an Idempotency-Key is replayed to retries of it
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The dashboard served at GET /ui, which calls the API from the browser
+const UI: &str = include_str!("server/ui.html");
+
+/// A client allowed to use the server, sending its secret as `Authorization: Bearer <secret>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// Name usage is reported under
+    pub name: String,
+    pub secret: String,
+    /// Requests allowed before the server answers 429 Too Many Requests
+    pub max_requests: Option<u64>,
+    /// Bytes of values allowed to be written before the server answers 429 Too Many Requests
+    pub max_bytes: Option<u64>,
+}
+
+impl FromStr for Token {
+    type Err = String;
+
+    /// Parse name:secret, optionally followed by :max_requests and :max_bytes, either of which can be left empty
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (name, secret) = match (parts.next(), parts.next()) {
+            (Some(name), Some(secret)) if !name.is_empty() && !secret.is_empty() => (name, secret),
+            _ => return Err(format!("invalid token {}, expected name:secret[:max_requests[:max_bytes]]", s)),
+        };
+        let mut limit = || match parts.next() {
+            None | Some("") => Ok(None),
+            Some(limit) => limit.parse().map(Some).map_err(|_| format!("invalid limit {} in token {}", limit, name)),
+        };
+        let token = Token { name: name.to_string(), secret: secret.to_string(), max_requests: limit()?, max_bytes: limit()? };
+        match parts.next() {
+            Some(_) => Err(format!("invalid token {}, expected name:secret[:max_requests[:max_bytes]]", name)),
+            None => Ok(token),
+        }
+    }
+}
+
+impl Token {
+    /// Read a file of tokens, one a line, skipping blank lines and # comments
+    pub fn load(path: &str) -> Result<Vec<Token>, String> {
+        fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path, e))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::parse)
+            .collect()
+    }
+}
+
+/// Who may use the server, checked against each backend in turn: static tokens,
+/// an htpasswd file for basic auth, then JWTs from an OpenID Connect issuer.
+/// With none of them anyone can.
+#[derive(Debug, Default)]
+pub struct Auth {
+    pub tokens: Vec<Token>,
+    /// File of more tokens, one a line, read by load_files
+    pub token_file: Option<String>,
+    /// The tokens last read from token_file
+    pub file_tokens: Vec<Token>,
+    pub htpasswd: Option<Htpasswd>,
+    /// File htpasswd is read from by load_files
+    pub htpasswd_file: Option<String>,
+    pub jwt: Option<Jwt>,
+    /// Secret that may read GET /admin/usage, which isn't served without one
+    pub admin_secret: Option<String>,
+    /// Roles of clients by token:<name>, user:<name> or jwt:<subject>, usually the store's acl.
+    /// When there are any, clients without one are refused, and without any every client can
+    /// read and write.
+    pub roles: HashMap<String, Role>,
+}
+
+/// Who a request is from, with the quotas it's held to
+pub(crate) struct Identity {
+    name: String,
+    /// What the request's writes are recorded as coming from, like token:<name>
+    pub(crate) source: String,
+    max_requests: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+/// The source of an authenticated request's writes, handed on from authorize
+#[derive(Debug, Clone)]
+struct Source(String);
+
+impl Auth {
+    pub fn is_open(&self) -> bool {
+        self.tokens.is_empty() && self.token_file.is_none() && self.htpasswd.is_none() && self.htpasswd_file.is_none() && self.jwt.is_none()
+    }
+
+    /// Read token_file and htpasswd_file again, replacing what was read from them before.
+    /// If either can't be read, neither is replaced.
+    pub fn load_files(&mut self) -> Result<(), String> {
+        let tokens = self.token_file.as_deref().map(Token::load).transpose()?;
+        let htpasswd = self.htpasswd_file.as_deref().map(Htpasswd::load).transpose()?;
+        if let Some(tokens) = tokens {
+            self.file_tokens = tokens;
+        }
+        if htpasswd.is_some() {
+            self.htpasswd = htpasswd;
+        }
+        Ok(())
+    }
+
+    /// Whether a request is sent with the admin secret as its bearer token
+    fn is_admin(&self, headers: &HeaderMap) -> bool {
+        matches!((bearer(headers), &self.admin_secret), (Some(sent), Some(secret)) if auth::same_secret(sent, secret))
+    }
+
+    /// What a client may do, None if it has no role while others do
+    pub(crate) fn role(&self, identity: &Identity) -> Option<Role> {
+        match self.roles.is_empty() {
+            true => Some(Role::ReadWrite),
+            false => self.roles.get(&identity.source).copied(),
+        }
+    }
+
+    pub(crate) async fn identify(&self, headers: &HeaderMap) -> Option<Identity> {
+        let secret = bearer(headers);
+        if let Some(token) = secret.and_then(|secret| self.tokens.iter().chain(&self.file_tokens).find(|token| auth::same_secret(secret, &token.secret))) {
+            return Some(Identity { name: token.name.clone(), source: format!("token:{}", token.name), max_requests: token.max_requests, max_bytes: token.max_bytes });
+        }
+        let (name, kind) = match (secret, &self.jwt, &self.htpasswd) {
+            (Some(secret), Some(jwt), _) => (jwt.subject(secret).await?, "jwt"),
+            (None, _, Some(htpasswd)) => (auth::basic_user(htpasswd, headers)?, "user"),
+            _ => return None,
+        };
+        Some(Identity { source: format!("{}:{}", kind, name), name, max_requests: None, max_bytes: None })
+    }
+}
+
+/// How bursts of writes are grouped into one transaction. The first write of a group waits
+/// up to max_delay for others to join it, and a group is written as soon as it has max_writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coalesce {
+    pub max_delay: Duration,
+    pub max_writes: usize,
+}
+
+/// Writes waiting to be grouped, each with where to send its result
+type Writes = mpsc::UnboundedSender<(RecordWrite, oneshot::Sender<Result<(), DsError>>)>;
+
+/// Write the groups of writes received until every sender is dropped. If a group fails,
+/// its writes are retried one at a time so only the writes at fault fail.
+async fn coalesce(store: Arc<KVLite>, mut writes: mpsc::UnboundedReceiver<(RecordWrite, oneshot::Sender<Result<(), DsError>>)>, coalesce: Coalesce) {
+    while let Some(first) = writes.recv().await {
+        let mut group = vec![first];
+        let deadline = tokio::time::sleep(coalesce.max_delay);
+        tokio::pin!(deadline);
+        while group.len() < coalesce.max_writes {
+            tokio::select! {
+                write = writes.recv() => match write {
+                    Some(write) => group.push(write),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+
+        let (group, replies): (Vec<_>, Vec<_>) = group.into_iter().unzip();
+        match store.write_all(&group).await {
+            Ok(()) => for reply in replies {
+                let _ = reply.send(Ok(()));
+            },
+            Err(e) if group.len() == 1 => if let Some(reply) = replies.into_iter().next() {
+                let _ = reply.send(Err(e));
+            },
+            Err(_) => for (write, reply) in group.into_iter().zip(replies) {
+                let _ = reply.send(store.write_all(std::slice::from_ref(&write)).await);
+            },
+        }
+        let _ = store.purge_expired().await;
+    }
+}
+
+/// What's logged of the requests a server answers, each as a line of JSON on stderr
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Logging {
+    /// Log every request
+    pub access: bool,
+    /// Log requests taking at least this long, marked as slow, whether or not every request is
+    pub slow: Option<Duration>,
+}
+
+impl Logging {
+    fn is_off(&self) -> bool {
+        !self.access && self.slow.is_none()
+    }
+
+    /// The line logged for a request, if it's logged: when, what was asked for and of which key,
+    /// the response's status, how long it took in milliseconds and who the client was
+    pub(crate) fn entry(&self, method: &Method, uri: &Uri, status: StatusCode, client: Option<&str>, took: Duration) -> Option<serde_json::Value> {
+        let slow = self.slow.is_some_and(|slow| took >= slow);
+        if !self.access && !slow {
+            return None;
+        }
+        let mut entry = json!({
+            "at": crate::history::epoch_secs(SystemTime::now()),
+            "method": method.as_str(),
+            "path": uri.path(),
+            "key": uri.path().strip_prefix("/keys/"),
+            "status": status.as_u16(),
+            "ms": took.as_secs_f64() * 1000.0,
+            "client": client,
+        });
+        if slow {
+            entry["slow"] = json!(true);
+        }
+        Some(entry)
+    }
+}
+
+/// Log requests as logging says to
+async fn log_request(State(logging): State<Logging>, request: Request, next: Next) -> Response {
+    let (method, uri) = (request.method().clone(), request.uri().clone());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let client = response.extensions().get::<Source>().map(|Source(source)| source.as_str());
+    if let Some(entry) = logging.entry(&method, &uri, response.status(), client, started.elapsed()) {
+        eprintln!("{}", entry);
+    }
+    response
+}
+
+/// A primary a server follows as a standby, applying its change log and redirecting writes to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Standby {
+    /// Where the primary is served, like http://primary:8080
+    pub primary: String,
+    /// Bearer token sent to the primary
+    pub token: Option<String>,
+}
+
+/// What the HTTP API accepts from clients, anything else is refused with a 4xx before it reaches the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest request body in bytes, refused with 413 Payload Too Large
+    pub max_body: usize,
+    /// What's done with values that aren't valid UTF-8
+    pub utf8: Utf8Policy,
+    /// Longest key in bytes that can be written, refused with 400 Bad Request
+    pub max_key: Option<usize>,
+    /// Globs keys written have to match one of, refused with 400 Bad Request. Any key if empty
+    pub key_patterns: Vec<String>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { max_body: DEFAULT_MAX_BODY, utf8: Utf8Policy::default(), max_key: None, key_patterns: vec![] }
+    }
+}
+
+/// What's done with written values that aren't valid UTF-8
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Store them as BLOBs
+    #[default]
+    Allow,
+    /// Refuse them with 422 Unprocessable Entity
+    Reject,
+    /// Replace each invalid sequence with U+FFFD
+    Replace,
+}
+
+impl FromStr for Utf8Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Utf8Policy::Allow),
+            "reject" => Ok(Utf8Policy::Reject),
+            "replace" => Ok(Utf8Policy::Replace),
+            _ => Err(format!("unknown UTF-8 policy {}, expected allow, reject or replace", s)),
+        }
+    }
+}
+
+impl Limits {
+    fn check_key(&self, key: &str) -> Result<(), String> {
+        if let Some(max) = self.max_key.filter(|max| key.len() > *max) {
+            return Err(format!("the key is {} bytes, over the limit of {}", key.len(), max));
+        }
+        if !self.key_patterns.is_empty() && !self.key_patterns.iter().any(|pattern| glob_match(pattern, key)) {
+            return Err(format!("the key doesn't match any of the allowed patterns {}", self.key_patterns.join(", ")));
+        }
+        Ok(())
+    }
+
+    fn check_value(&self, value: Bytes) -> Result<Vec<u8>, String> {
+        match (self.utf8, std::str::from_utf8(&value)) {
+            (Utf8Policy::Allow, _) | (_, Ok(_)) => Ok(value.to_vec()),
+            (Utf8Policy::Reject, Err(e)) => Err(format!("the value isn't valid UTF-8: {}", e)),
+            (Utf8Policy::Replace, Err(_)) => Ok(String::from_utf8_lossy(&value).into_owned().into_bytes()),
+        }
+    }
+}
+
+/// Browser origins allowed to call the API directly, with the methods and request headers they may use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cors {
+    /// Origins like https://dashboard.example.com, or * for any
+    pub origins: Vec<HeaderValue>,
+    pub methods: Vec<Method>,
+    pub headers: Vec<HeaderName>,
+}
+
+impl Cors {
+    fn layer(&self) -> CorsLayer {
+        let origins = match self.origins.iter().any(|origin| origin == "*") {
+            true => AllowOrigin::any(),
+            false => AllowOrigin::list(self.origins.clone()),
+        };
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(self.methods.clone())
+            .allow_headers(self.headers.clone())
+    }
+}
+
+/// Read a request's body, refusing it with 413 Payload Too Large once it's over max bytes
+async fn read_body(body: Body, max: usize) -> Result<Bytes, Response> {
+    let mut chunks = body.into_data_stream();
+    let mut read = vec![];
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+        if read.len() + chunk.len() > max {
+            return Err(too_large(max));
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read.into())
+}
+
+fn too_large(max: usize) -> Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, format!("the request body is over the limit of {} bytes", max)).into_response()
+}
+
+/// The length of a request's body, if it says
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Poll the primary for changes and apply them, for as long as the server runs
+async fn follow(store: Arc<KVLite>, standby: Standby) {
+    let client = reqwest::Client::new();
+    loop {
+        if let Err(e) = catch_up(&store, &client, &standby).await {
+            eprintln!("following {}: {}", standby.primary, e);
+        }
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+/// Apply the primary's changes until there are none left, starting from a snapshot of it
+/// when the standby hasn't followed it before or has fallen behind its change log
+async fn catch_up(store: &KVLite, client: &reqwest::Client, standby: &Standby) -> Result<(), String> {
+    let primary = standby.primary.trim_end_matches('/');
+    let get = |path: String| {
+        let request = client.get(format!("{}{}", primary, path));
+        match &standby.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    };
+    loop {
+        let after = store.replica_seq().await.map_err(|e| e.to_string())?;
+        let mut changes = None;
+        if let Some(after) = after {
+            let response = get(format!("/replication/changes?after={}&limit={}", after, FOLLOW_BATCH)).send().await.map_err(|e| e.to_string())?;
+            // the primary's log doesn't go back far enough, so it's snapshotted instead
+            if response.status() != StatusCode::GONE {
+                changes = Some(response.error_for_status().map_err(|e| e.to_string())?.json::<Changes>().await.map_err(|e| e.to_string())?);
+            }
+        }
+        let (changes, replace) = match changes {
+            Some(changes) => (changes, false),
+            None => {
+                let response = get("/replication/snapshot".to_string()).send().await.map_err(|e| e.to_string())?;
+                (response.error_for_status().map_err(|e| e.to_string())?.json::<Changes>().await.map_err(|e| e.to_string())?, true)
+            },
+        };
+        let done = replace || (changes.changes.len() as u64) < FOLLOW_BATCH;
+        if replace || !changes.changes.is_empty() || Some(changes.seq) != after {
+            store.apply_changes(&changes, replace).await.map_err(|e| e.to_string())?;
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// What a token has used since the server started
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub requests: u64,
+    pub bytes_written: u64,
+}
+
+/// A write sent with an Idempotency-Key, and how it was answered once it has been
+struct Idempotent {
+    method: Method,
+    uri: Uri,
+    at: Instant,
+    response: Option<(StatusCode, HeaderMap, Bytes)>,
+}
+
+/// Writes sent with an Idempotency-Key, by the source of the client that sent them and the key
+type Idempotency = Arc<Mutex<HashMap<(String, String), Idempotent>>>;
+
+#[derive(Clone)]
+struct AppState {
+    store: Arc<KVLite>,
+    auth: Arc<RwLock<Auth>>,
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+    /// Where writes go to be grouped, when they're coalesced
+    writes: Option<Writes>,
+    /// The primary writes are redirected to, when the server is a standby
+    primary: Option<String>,
+    limits: Arc<Limits>,
+    idempotency: Idempotency,
+}
+
+impl FromRef<AppState> for Arc<KVLite> {
+    fn from_ref(state: &AppState) -> Arc<KVLite> {
+        state.store.clone()
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+/// Check the request's token and count it against the token's quotas
+async fn authorize(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    // the auth is only held while checking the request, so a reload doesn't wait for it to be served
+    let identity = {
+        let auth = state.auth.read().await;
+        if auth.is_open() {
+            drop(auth);
+            return next.run(request).await;
+        }
+        let identity = match auth.identify(request.headers()).await {
+            Some(identity) => identity,
+            None if auth.htpasswd.is_some() => return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, r#"Basic realm="dsr""#)]).into_response(),
+            None => return StatusCode::UNAUTHORIZED.into_response(),
+        };
+        let writing = !matches!(request.method(), &Method::GET | &Method::HEAD);
+        match auth.role(&identity) {
+            Some(Role::ReadWrite) => (),
+            Some(Role::ReadOnly) if !writing => (),
+            _ => return StatusCode::FORBIDDEN.into_response(),
+        }
+        identity
+    };
+
+    // a body of a known length is counted by it and handed on as it is, to be streamed, and any
+    // other is read here to count it
+    let (mut parts, body) = request.into_parts();
+    let (body, len) = match content_length(&parts.headers) {
+        Some(len) if len > state.limits.max_body as u64 => return too_large(state.limits.max_body),
+        Some(len) => (body, len),
+        None => match read_body(body, state.limits.max_body).await {
+            Ok(body) => {
+                let len = body.len() as u64;
+                (Body::from(body), len)
+            },
+            Err(response) => return response,
+        },
+    };
+    let written = if matches!(parts.method, Method::PUT | Method::POST) { len } else { 0 };
+    {
+        let mut usage = state.usage.lock().unwrap();
+        let usage = usage.entry(identity.name).or_default();
+        if identity.max_requests.is_some_and(|max| usage.requests >= max)
+            || identity.max_bytes.is_some_and(|max| usage.bytes_written + written > max) {
+            return StatusCode::TOO_MANY_REQUESTS.into_response();
+        }
+        usage.requests += 1;
+        usage.bytes_written += written;
+    }
+    parts.extensions.insert(Source(identity.source.clone()));
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    // for the access log
+    response.extensions_mut().insert(Source(identity.source));
+    response
+}
+
+/// Hand each client a Session-Token of how far it has seen the store, and have a standby serve
+/// requests sending one only once it has caught up with it, waiting up to SESSION_WAIT before
+/// redirecting them to the primary, so clients read their own writes.
+async fn session(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let seen = match request.headers().get(SESSION_HEADER).map(|seen| seen.to_str().map_err(|e| e.to_string()).and_then(str::parse::<Session>)) {
+        Some(Ok(seen)) => Some(seen),
+        Some(Err(e)) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        None => None,
+    };
+    if let (Some(seen), Some(_)) = (seen, &state.primary) {
+        let deadline = Instant::now() + SESSION_WAIT;
+        loop {
+            match state.store.caught_up(&seen).await {
+                Ok(true) => break,
+                Ok(false) if Instant::now() < deadline => tokio::time::sleep(SESSION_POLL).await,
+                Ok(false) => return redirect_write(&state, request.uri()).unwrap_or_else(|| StatusCode::SERVICE_UNAVAILABLE.into_response()),
+                Err(e) => return error_response(e),
+            }
+        }
+    }
+
+    let mut response = next.run(request).await;
+    // read after the request, so it's at least as far as anything it read or wrote
+    if response.status().is_success() {
+        if let Ok(session) = state.store.session().await {
+            if let Ok(session) = HeaderValue::from_str(&session.to_string()) {
+                response.headers_mut().insert(SESSION_HEADER, session);
+            }
+        }
+    }
+    response
+}
+
+/// Answer a retry of a write sent with an Idempotency-Key with the response to the first, instead
+/// of applying it again. A key is the client's own, and can't be reused for a different write,
+/// or while the first is still being handled. Writes that fail with a server error or are
+/// redirected aren't remembered, so they can be retried.
+async fn idempotent(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let key = match request.headers().get("idempotency-key").map(HeaderValue::to_str) {
+        Some(Ok(key)) if request.method() != Method::GET && request.method() != Method::HEAD => key.to_string(),
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "the Idempotency-Key isn't valid").into_response(),
+        _ => return next.run(request).await,
+    };
+    let source = request.extensions().get::<Source>().map(|Source(source)| source.clone()).unwrap_or_default();
+    let id = (source, key);
+    {
+        let mut writes = state.idempotency.lock().unwrap();
+        writes.retain(|_, write| write.at.elapsed() < IDEMPOTENCY_TTL);
+        match writes.get(&id) {
+            Some(write) if write.method != request.method() || write.uri != *request.uri() =>
+                return (StatusCode::UNPROCESSABLE_ENTITY, "the Idempotency-Key was used for a different request").into_response(),
+            Some(Idempotent { response: Some((status, headers, body)), .. }) => return (*status, headers.clone(), body.clone()).into_response(),
+            Some(_) => return (StatusCode::CONFLICT, "a request with the Idempotency-Key is still being handled").into_response(),
+            None => {
+                writes.insert(id.clone(), Idempotent { method: request.method().clone(), uri: request.uri().clone(), at: Instant::now(), response: None });
+            },
+        }
+    }
+
+    let response = next.run(request).await;
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX).await;
+    let mut writes = state.idempotency.lock().unwrap();
+    match body {
+        Ok(body) if !parts.status.is_server_error() && !parts.status.is_redirection() => {
+            if let Some(write) = writes.get_mut(&id) {
+                write.response = Some((parts.status, parts.headers.clone(), body.clone()));
+            }
+            Response::from_parts(parts, Body::from(body))
+        },
+        Ok(body) => {
+            writes.remove(&id);
+            Response::from_parts(parts, Body::from(body))
+        },
+        Err(e) => {
+            writes.remove(&id);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        },
+    }
+}
+
+/// Requests and bytes written by each token, with their quotas, and by each user
+/// or JWT subject that's used the server
+async fn usage(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let auth = state.auth.read().await;
+    if !auth.is_admin(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let usage = state.usage.lock().unwrap();
+    let mut report = auth.tokens.iter().chain(&auth.file_tokens)
+        .map(|token| {
+            let used = usage.get(&token.name).copied().unwrap_or_default();
+            (token.name.clone(), json!({
+                "requests": used.requests,
+                "bytes_written": used.bytes_written,
+                "max_requests": token.max_requests,
+                "max_bytes": token.max_bytes,
+            }))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    for (name, used) in usage.iter() {
+        if !report.contains_key(name) {
+            report.insert(name.clone(), json!({
+                "requests": used.requests,
+                "bytes_written": used.bytes_written,
+                "max_requests": null,
+                "max_bytes": null,
+            }));
+        }
+    }
+    Json(report).into_response()
+}
+
+/// The roles a store's acl gives clients, refusing one granted to a name that doesn't say
+/// which backend authenticates the client, as earlier versions allowed
+pub async fn acl_roles(store: &KVLite) -> Result<HashMap<String, Role>, DsError> {
+    let acl = store.acl().await?;
+    if let Some((name, _)) = acl.iter().find(|(name, _)| !crate::acl::qualified(name)) {
+        return Err(DsError::Sql(sqlx::Error::Configuration(format!("the acl gives {} a role, which could be a token, user or JWT subject, revoke it and grant token:{}, user:{} or jwt:{} instead", name, name, name, name).into())));
+    }
+    Ok(acl.into_iter().collect())
+}
+
+/// Re-read what can change while the server runs: the token and htpasswd files, the store's acl
+/// and its hooks, without dropping connections or the requests being served
+async fn reload(store: &KVLite, auth: &RwLock<Auth>) -> Result<(), DsError> {
+    let roles = acl_roles(store).await?;
+    {
+        let mut auth = auth.write().await;
+        if !roles.is_empty() && auth.is_open() {
+            return Err(DsError::Sql(sqlx::Error::Configuration("the store's acl gives clients roles, but the server doesn't authenticate them".into())));
+        }
+        auth.load_files().map_err(|e| DsError::Sql(sqlx::Error::Configuration(e.into())))?;
+        auth.roles = roles;
+    }
+    store.reload_hooks().await
+}
+
+/// Reload the server's configuration, answering 204 No Content once it's been read
+async fn admin_reload(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !state.auth.read().await.is_admin(&headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match reload(&state.store, &state.auth).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Turn a database error into a response, with missing records as 404
+/// and a datastore locked by another process as 503
+fn error_response(e: DsError) -> Response {
+    match error_status(&e) {
+        StatusCode::NOT_FOUND => StatusCode::NOT_FOUND.into_response(),
+        status => (status, e.to_string()).into_response(),
+    }
+}
+
+fn error_status(e: &DsError) -> StatusCode {
+    match e {
+        DsError::NotFound => StatusCode::NOT_FOUND,
+        DsError::StoreLocked => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Deserialize)]
+struct KeysQuery {
+    prefix: Option<String>,
+    limit: Option<u64>,
+    after: Option<String>,
+}
+
+async fn list_keys(State(store): Store, Query(query): Query<KeysQuery>) -> Response {
+    let options = ListOptions {
+        sort: true,
+        limit: query.limit,
+        after: query.after,
+        filter: Filter { prefix: query.prefix, ..Default::default() },
+        ..Default::default()
+    };
+    match store.keys(&options).await {
+        Ok(rows) => Json(rows.iter().map(|row| row.get::<String, &str>("key")).collect::<Vec<_>>()).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// A record's value, streamed out of the store a chunk at a time
+async fn get_key(State(store): Store, Path(key): Path<String>) -> Response {
+    match store.stream_value(&key).await {
+        Ok((RecordMeta { content_type: Some(content_type), .. }, value)) => ([(header::CONTENT_TYPE, content_type)], Body::from_stream(value)).into_response(),
+        Ok((_, value)) => Body::from_stream(value).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct PutQuery {
+    /// Seconds before the record expires
+    ttl: Option<u64>,
+}
+
+/// Apply a write, grouped with others if writes are coalesced
+async fn write(state: &AppState, write: RecordWrite) -> Result<(), DsError> {
+    let writes = match &state.writes {
+        Some(writes) => writes,
+        None => {
+            state.store.write_all(std::slice::from_ref(&write)).await?;
+            return state.store.purge_expired().await.map(|_| ());
+        },
+    };
+    let (reply, result) = oneshot::channel();
+    writes.send((write, reply)).map_err(|_| sqlx::Error::PoolClosed)?;
+    result.await.map_err(|_| sqlx::Error::PoolClosed)?
+}
+
+/// A redirect of a write, or a read the server hasn't caught up with, to the primary, when the server is a standby
+fn redirect_write(state: &AppState, uri: &Uri) -> Option<Response> {
+    let primary = state.primary.as_deref()?;
+    Some(Redirect::temporary(&format!("{}{}", primary.trim_end_matches('/'), uri)).into_response())
+}
+
+async fn put_key(State(state): State<AppState>, Path(key): Path<String>, Query(query): Query<PutQuery>, uri: Uri, source: Option<Extension<Source>>, headers: HeaderMap, body: Body) -> Response {
+    if let Some(redirect) = redirect_write(&state, &uri) {
+        return redirect;
+    }
+    if let Err(e) = state.limits.check_key(&key) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let options = SetOptions {
+        ttl: query.ttl.map(Duration::from_secs),
+        content_type: headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string),
+        source: source.map(|Extension(Source(source))| source),
+        ..Default::default()
+    };
+
+    // large bodies are written as they arrive, unless they're checked as UTF-8 or grouped with other writes
+    let len = content_length(&headers);
+    if let Some(len) = len.filter(|len| *len >= STREAMED_BODY) {
+        if len > state.limits.max_body as u64 {
+            return too_large(state.limits.max_body);
+        }
+        if state.limits.utf8 == Utf8Policy::Allow && state.writes.is_none() {
+            let chunks = body.into_data_stream().map(|chunk| chunk.map_err(|e| DsError::Io(io::Error::other(e))));
+            let res = match state.store.set_from_stream(&key, len, chunks, &options).await {
+                Ok(()) => state.store.purge_expired().await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            return match res {
+                Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                Err(e) => error_response(e),
+            };
+        }
+    }
+    let value = match read_body(body, state.limits.max_body).await {
+        Ok(body) => match state.limits.check_value(body) {
+            Ok(value) => value,
+            Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+        },
+        Err(response) => return response,
+    };
+    match write(&state, RecordWrite::Set { key, value, options }).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn delete_key(State(state): State<AppState>, Path(key): Path<String>, uri: Uri) -> Response {
+    if let Some(redirect) = redirect_write(&state, &uri) {
+        return redirect;
+    }
+    match write(&state, RecordWrite::Delete(key)).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// One of the operations of a POST /batch, like {"op": "set", "key": "a", "value": "1"}
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchRequest {
+    Set { key: String, value: String },
+    Get { key: String },
+    Contains { key: String },
+    Delete { key: String },
+}
+
+impl BatchRequest {
+    fn into_op(self) -> BatchOp {
+        match self {
+            BatchRequest::Set { key, value } => BatchOp::Set(key, value),
+            BatchRequest::Get { key } => BatchOp::Get(key),
+            BatchRequest::Contains { key } => BatchOp::Contains(key),
+            BatchRequest::Delete { key } => BatchOp::Delete(key),
+        }
+    }
+}
+
+/// Run operations in order in one transaction, answering with each one's result:
+/// {"value": ...} for a get, {"contains": ...} for contains and {} for a set or delete.
+/// If any fails nothing is written, and the response says which one it was.
+async fn batch(State(state): State<AppState>, uri: Uri, source: Option<Extension<Source>>, Json(ops): Json<Vec<BatchRequest>>) -> Response {
+    if let Some(redirect) = redirect_write(&state, &uri) {
+        return redirect;
+    }
+    let ops: Vec<BatchOp> = ops.into_iter().map(BatchRequest::into_op).collect();
+    for (i, op) in ops.iter().enumerate() {
+        if let BatchOp::Set(key, _) | BatchOp::Delete(key) = op {
+            if let Err(e) = state.limits.check_key(key) {
+                return (StatusCode::BAD_REQUEST, format!("operation {}: {}", i, e)).into_response();
+            }
+        }
+    }
+    let options = SetOptions { source: source.map(|Extension(Source(source))| source), ..Default::default() };
+
+    let failed = |i: usize, e: DsError| (error_status(&e), format!("operation {}: {}", i, e)).into_response();
+    let mut tx = match state.store.transaction().await {
+        Ok(tx) => tx,
+        Err(e) => return error_response(e),
+    };
+    let mut results = Vec::with_capacity(ops.len());
+    for (i, op) in ops.iter().enumerate() {
+        // sets are recorded as coming from the client, like a PUT
+        let result = match op {
+            BatchOp::Set(key, value) => tx.set_with(key, value.as_bytes(), &options).await.map(|_| BatchResult::Done),
+            op => tx.apply(op).await,
+        };
+        results.push(match result {
+            Ok(BatchResult::Done) => json!({}),
+            Ok(BatchResult::Value(value)) => json!({ "value": value }),
+            Ok(BatchResult::Contains(contains)) => json!({ "contains": contains }),
+            Err(e) => return failed(i, e),
+        });
+    }
+    match tx.commit().await {
+        Ok(()) => {
+            let _ = state.store.purge_expired().await;
+            Json(results).into_response()
+        },
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<u64>,
+}
+
+/// Records whose values match a full-text query, best match first
+async fn search(State(store): Store, Query(query): Query<SearchQuery>) -> Response {
+    match store.search(&query.q, query.limit).await {
+        Ok(rows) => match rows.iter()
+            .map(|row| Ok(json!({ "key": row.get::<String, &str>("key"), "value": String::from_utf8_lossy(&store.untransform_value(row.get("value"))?) })))
+            .collect::<Result<Vec<_>, DsError>>() {
+            Ok(records) => Json(records).into_response(),
+            Err(e) => error_response(e),
+        },
+        Err(e) => error_response(e),
+    }
+}
+
+/// Size of the datastore file and of the store served, leaving out the datastore's other
+/// stores, which clients of this one may not know of
+async fn stats(State(store): Store) -> Response {
+    let mut stats = match store.datastore_stats().await {
+        Ok(stats) => stats,
+        Err(e) => return error_response(e),
+    };
+    stats.stores.retain(|served| served.store == store.kv_name.name);
+    Json(json!({
+        "file_size": stats.file_size,
+        "records": stats.records(),
+        "key_bytes": stats.key_bytes(),
+        "value_bytes": stats.value_bytes(),
+        "stores": stats.stores.iter().map(|store| json!({
+            "store": store.store,
+            "records": store.records,
+            "key_bytes": store.key_bytes,
+            "value_bytes": store.value_bytes,
+        })).collect::<Vec<_>>(),
+    })).into_response()
+}
+
+#[derive(Deserialize)]
+struct RecentQuery {
+    limit: Option<u64>,
+}
+
+/// The latest changes to the store, newest first
+async fn recent_changes(State(store): Store, Query(query): Query<RecentQuery>) -> Response {
+    match store.recent_changes(query.limit.unwrap_or(RECENT_CHANGES)).await {
+        Ok(changes) => Json(changes.iter()
+            .map(|change| json!({ "kind": change.kind.to_string(), "key": change.key, "value": change.value }))
+            .collect::<Vec<_>>()).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn ui() -> Html<&'static str> {
+    Html(UI)
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    after: i64,
+    limit: Option<u64>,
+}
+
+async fn changes(State(store): Store, Query(query): Query<ChangesQuery>) -> Response {
+    match store.changes_since(query.after, query.limit.unwrap_or(FOLLOW_BATCH)).await {
+        Ok(Some(changes)) => Json(changes).into_response(),
+        Ok(None) => (StatusCode::GONE, "the change log doesn't go back that far, start from a snapshot").into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn snapshot(State(store): Store) -> Response {
+    match store.replication_snapshot().await {
+        Ok(snapshot) => Json(snapshot).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// How a store is served over HTTP
+#[derive(Debug, Default)]
+pub struct Options {
+    pub auth: Auth,
+    pub limits: Limits,
+    /// Origins browsers may call the API from, and their preflight requests are answered without auth
+    pub cors: Option<Cors>,
+    pub coalescing: Option<Coalesce>,
+    pub standby: Option<Standby>,
+    /// Serve a dashboard for browsing and editing records at GET /ui
+    pub ui: bool,
+    pub logging: Logging,
+}
+
+/// Routes exposing a store over HTTP:
+/// GET /keys lists keys, GET, PUT and DELETE /keys/{key} read and write a record,
+/// POST /batch runs gets, sets and deletes in one transaction, retries of writes sent with an
+/// Idempotency-Key are answered as the first was without being applied again,
+/// responses carry a Session-Token that a standby serves requests sending only once it's caught up with,
+/// GET /search, /stats and /changes search values, size up the datastore and list the latest changes,
+/// GET /replication/changes and /replication/snapshot serve standbys the change log and every record,
+/// and GET /admin/usage reports each token's usage and POST /admin/reload reloads the configuration
+/// when there's an admin secret.
+/// Coalescing writes or following a primary spawns a task, so must be done inside a tokio runtime.
+pub fn router(store: Arc<KVLite>, options: Options) -> Router {
+    routes(store, options).0
+}
+
+/// The router, with the auth it reloads and the task writing coalesced writes if there is one,
+/// which finishes once the router and every clone of it have been dropped and the writes waiting
+/// have been written
+fn routes(store: Arc<KVLite>, options: Options) -> (Router, Arc<RwLock<Auth>>, Option<JoinHandle<()>>) {
+    let Options { auth, limits, cors, coalescing, standby, ui: serve_ui, logging } = options;
+    let (writes, coalescing) = match coalescing {
+        Some(coalescing) => {
+            let (writes, received) = mpsc::unbounded_channel();
+            (Some(writes), Some(tokio::spawn(coalesce(store.clone(), received, coalescing))))
+        },
+        None => (None, None),
+    };
+    let primary = standby.as_ref().map(|standby| standby.primary.clone());
+    if let Some(standby) = standby {
+        tokio::spawn(follow(store.clone(), standby));
+    }
+    let admin = auth.admin_secret.is_some();
+    let state = AppState { store, auth: Arc::new(RwLock::new(auth)), usage: Arc::default(), writes, primary, limits: Arc::new(limits), idempotency: Arc::default() };
+    let mut router = Router::new()
+        .route("/keys", get(list_keys))
+        .route("/keys/{*key}", get(get_key).put(put_key).delete(delete_key))
+        .route("/batch", post(batch))
+        .route("/search", get(search))
+        .route("/stats", get(stats))
+        .route("/changes", get(recent_changes))
+        .route("/replication/changes", get(changes))
+        .route("/replication/snapshot", get(snapshot))
+        .route_layer(middleware::from_fn_with_state(state.clone(), idempotent))
+        .route_layer(middleware::from_fn_with_state(state.clone(), session))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize));
+    if admin {
+        router = router
+            .route("/admin/usage", get(usage))
+            .route("/admin/reload", post(admin_reload));
+    }
+    // the page itself holds nothing, it asks for a token to call the API with
+    if serve_ui {
+        router = router.route("/ui", get(ui));
+    }
+    if let Some(cors) = cors {
+        router = router.layer(cors.layer());
+    }
+    if !logging.is_off() {
+        router = router.layer(middleware::from_fn_with_state(logging, log_request));
+    }
+    let auth = state.auth.clone();
+    (router.with_state(state), auth, coalescing)
+}
+
+/// Serve a store over HTTP until the process is sent SIGINT or SIGTERM, then stop taking
+/// connections, finish the requests already made and the writes waiting to be coalesced, and
+/// checkpoint the write-ahead log, so a supervised restart loses nothing. SIGHUP reloads the
+/// configuration as POST /admin/reload does.
+pub async fn serve(store: KVLite, addr: SocketAddr, options: Options) -> io::Result<()> {
+    serve_on(Arc::new(store), TcpListener::bind(addr).await?, options, shutdown_signal()).await
+}
+
+pub(crate) async fn serve_on(store: Arc<KVLite>, listener: TcpListener, options: Options, shutdown: impl Future<Output = ()> + Send + 'static) -> io::Result<()> {
+    let (router, auth, coalescing) = routes(store.clone(), options);
+    #[cfg(unix)]
+    let hangups = tokio::spawn(reload_on_hangup(store.clone(), auth));
+    #[cfg(not(unix))]
+    drop(auth);
+    axum::serve(listener, router).with_graceful_shutdown(shutdown).await?;
+    #[cfg(unix)]
+    hangups.abort();
+    if let Some(coalescing) = coalescing {
+        coalescing.await.map_err(io::Error::other)?;
+    }
+    store.checkpoint().await.map_err(io::Error::other)?;
+    store.pool.close().await;
+    Ok(())
+}
+
+/// Reload the configuration whenever the process is sent SIGHUP, keeping the one it has if the
+/// new one can't be read
+#[cfg(unix)]
+async fn reload_on_hangup(store: Arc<KVLite>, auth: Arc<RwLock<Auth>>) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => return eprintln!("warning: SIGHUP won't reload the configuration: {}", e),
+    };
+    while hangups.recv().await.is_some() {
+        match reload(&store, &auth).await {
+            Ok(()) => eprintln!("reloaded the configuration"),
+            Err(e) => eprintln!("warning: couldn't reload the configuration: {}", e),
+        }
+    }
+}
+
+/// Resolves once the process is sent SIGINT, or SIGTERM where there is one
+async fn shutdown_signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            },
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => (),
+        _ = terminate => (),
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::HashMap, fs, str::FromStr, time::{Duration, Instant}};
+use axum::http::{HeaderMap, header};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use jsonwebtoken::{DecodingKey, Validation, jwk::JwkSet};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// How long an issuer's keys are kept before a token signed with an unknown key fetches them again
+const JWKS_REFRESH: Duration = Duration::from_secs(60);
+
+/// Users allowed to use the server with HTTP basic auth, read from an htpasswd file.
+/// Passwords hashed with bcrypt (htpasswd -B) or SHA-1 (htpasswd -s) are understood.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+}
+
+impl Htpasswd {
+    /// Read an htpasswd file
+    pub fn load(path: &str) -> Result<Htpasswd, String> {
+        fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path, e))?
+            .parse()
+    }
+
+    /// Whether a user's password matches the one hashed for them
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let hash = match self.users.get(user) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        match hash.strip_prefix("{SHA}") {
+            Some(sha) => sha == STANDARD.encode(Sha1::digest(password.as_bytes())),
+            None => bcrypt::verify(password, hash).unwrap_or(false),
+        }
+    }
+}
+
+impl FromStr for Htpasswd {
+    type Err = String;
+
+    /// Parse user:hash lines, skipping blank lines and # comments
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut users = HashMap::new();
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (user, hash) = line.split_once(':').ok_or_else(|| format!("invalid htpasswd line {}, expected user:hash", line))?;
+            if !(hash.starts_with("{SHA}") || ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))) {
+                return Err(format!("the password of {} isn't hashed with bcrypt or SHA-1, hash it with htpasswd -B", user));
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        Ok(Htpasswd { users })
+    }
+}
+
+/// Check a request's basic auth against an htpasswd file, returning the user
+pub(super) fn basic_user(htpasswd: &Htpasswd, headers: &HeaderMap) -> Option<String> {
+    let credentials = headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+    htpasswd.verify(user, password).then(|| user.to_string())
+}
+
+/// Whether a secret a client sent is the one expected, taking as long wherever they differ.
+/// Their SHA-256 digests are compared rather than the secrets, so their lengths aren't given away either.
+pub(super) fn same_secret(sent: &str, expected: &str) -> bool {
+    let (sent, expected) = (Sha256::digest(sent.as_bytes()), Sha256::digest(expected.as_bytes()));
+    sent.iter().zip(expected.iter()).fold(0, |differ, (a, b)| differ | (a ^ b)) == 0
+}
+
+/// Bearer tokens accepted when they're JWTs signed by an OpenID Connect issuer for an audience.
+/// The issuer's keys are found through its discovery document and cached.
+#[derive(Debug)]
+pub struct Jwt {
+    pub issuer: String,
+    pub audience: String,
+    client: reqwest::Client,
+    keys: tokio::sync::Mutex<Keys>,
+}
+
+#[derive(Debug, Default)]
+struct Keys {
+    set: Option<JwkSet>,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+impl Jwt {
+    pub fn new(issuer: &str, audience: &str) -> Jwt {
+        Jwt {
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            client: reqwest::Client::new(),
+            keys: Default::default(),
+        }
+    }
+
+    /// The subject of a token, if it's signed by one of the issuer's keys for the audience and hasn't expired
+    pub async fn subject(&self, token: &str) -> Option<String> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let key = self.key(header.kid.as_deref()).await?;
+        // a key only verifies the algorithms of its own family, so the header can't swap it for another
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        jsonwebtoken::decode::<Claims>(token, &key, &validation).ok().map(|token| token.claims.sub)
+    }
+
+    /// The issuer's key with an id, or its only key for tokens without one
+    async fn key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        let find = |set: &JwkSet| match kid {
+            Some(kid) => set.find(kid),
+            None => set.keys.first().filter(|_| set.keys.len() == 1),
+        }.and_then(|jwk| DecodingKey::from_jwk(jwk).ok());
+
+        let mut keys = self.keys.lock().await;
+        if let Some(key) = keys.set.as_ref().and_then(find) {
+            return Some(key);
+        }
+        // an unknown key may be a rotated one, but the issuer isn't asked again more than once a minute
+        if keys.fetched_at.is_some_and(|at| at.elapsed() < JWKS_REFRESH) {
+            return None;
+        }
+        keys.fetched_at = Some(Instant::now());
+        match self.fetch_keys().await {
+            Ok(set) => keys.set = Some(set),
+            Err(e) => eprintln!("fetching the keys of {}: {}", self.issuer, e),
+        }
+        keys.set.as_ref().and_then(find)
+    }
+
+    async fn fetch_keys(&self) -> Result<JwkSet, reqwest::Error> {
+        let discovery: Discovery = self.client.get(format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/')))
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+        self.client.get(discovery.jwks_uri).send().await?.error_for_status()?.json().await
+    }
+}
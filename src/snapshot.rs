@@ -0,0 +1,255 @@
+use std::time::SystemTime;
+use sqlx::{sqlite::{SqliteConnection, SqliteRow}, Connection, QueryBuilder, Row, Sqlite};
+use crate::{KVLite, DsError, ChangeKind, Explain, Filter, ListOptions, Table, dedup, now, push_live, to_time};
+
+/// A read-only view of a store as it was when KVLite::snapshot took it, unchanged by anything
+/// written since, so a listing can be read in pages or several reads compared without other
+/// writers getting in between. It holds one of the pool's connections and a read transaction
+/// until it's dropped.
+///
+/// Writers only carry on alongside a snapshot in WAL journal mode. In the other journal modes
+/// they wait for it to be dropped, failing with StoreLocked after the busy timeout. Records still
+/// expire as time passes, their TTLs are checked against the time of each read.
+pub struct Snapshot<'s> {
+    store: &'s KVLite,
+    tx: sqlx::Transaction<'static, Sqlite>,
+}
+
+impl KVLite {
+    /// Take a snapshot of the store to read from
+    pub async fn snapshot(&self) -> Result<Snapshot<'_>, DsError> {
+        let mut tx = self.pool.begin().await?;
+        // sqlite only fixes what a transaction sees at its first read
+        self.fetch_optional(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT 1 FROM {} LIMIT 1
+            "#, self.kv_name))
+            .build())
+            .await?;
+        Ok(Snapshot { store: self, tx })
+    }
+}
+
+impl Snapshot<'_> {
+    pub async fn get(&mut self, key: &str) -> Result<String, DsError> {
+        let key = &self.store.canonical_key(key)[..];
+        self.store.get_in(&mut self.tx, key).await
+    }
+
+    pub async fn get_bytes(&mut self, key: &str) -> Result<Vec<u8>, DsError> {
+        let key = &self.store.canonical_key(key)[..];
+        self.store.get_bytes_in(&mut self.tx, key).await
+    }
+
+    pub async fn contains(&mut self, key: &str) -> Result<bool, DsError> {
+        match self.get_bytes(key).await {
+            Ok(_) => Ok(true),
+            Err(DsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&mut self, columns: &str, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.store.check_listing(options)?;
+        let mut query = KVLite::list_query(&self.store.kv_name, self.store.dedup.is_some(), columns, options);
+        self.store.fetch_all(&mut self.tx, query.build()).await
+    }
+
+    pub async fn keys(&mut self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.list("key", options).await
+    }
+
+    pub async fn values(&mut self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.list("value", options).await
+    }
+
+    pub async fn records(&mut self, options: &ListOptions) -> Result<Vec<SqliteRow>, DsError> {
+        self.list("key,value,created_at,updated_at", options).await
+    }
+
+    /// Number of live records matching a filter
+    pub async fn count(&mut self, filter: &Filter) -> Result<u64, DsError> {
+        self.store.check_filter(filter)?;
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT count(*) AS count FROM {}
+            "#, self.store.kv_name));
+        let mut has_where = false;
+        push_live(&mut query, &mut has_where);
+        filter.push_conditions(&self.store.kv_name, self.store.blobs().as_ref(), &mut query, &mut has_where);
+        Ok(self.store.fetch_one(&mut self.tx, query.build()).await?.get::<i64, &str>("count") as u64)
+    }
+}
+
+/// A copy of a store kept inside the datastore under a name by KVLite::save_snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedSnapshot {
+    pub name: String,
+    pub taken_at: SystemTime,
+    /// Live records the store had when it was taken
+    pub records: u64,
+}
+
+/// Columns of a store's records, copied to and from its saved snapshots
+const RECORD_COLUMNS: &str = "key, value, expires_at, content_type, created_at, updated_at, tag, pinned, source";
+
+impl KVLite {
+    async fn create_saved_snapshots(&self, conn: &mut SqliteConnection) -> Result<(), DsError> {
+        // keyed by snapshot and key, so the records table isn't listed as a store
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {} (name TEXT PRIMARY KEY, taken_at INTEGER NOT NULL, records INTEGER NOT NULL);
+                CREATE TABLE IF NOT EXISTS {} (snapshot TEXT NOT NULL, key TEXT NOT NULL, value, expires_at INTEGER, content_type TEXT,
+                    created_at INTEGER, updated_at INTEGER, tag TEXT, pinned INTEGER NOT NULL DEFAULT 0, source TEXT, PRIMARY KEY (snapshot, key));
+            "#, self.kv_name.object("snapshots"), self.kv_name.object("snapshot_records")))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Copy the store's live records to a snapshot kept in the datastore under a name, for
+    /// restore_snapshot to bring back later. Fails if the store already has a snapshot by that
+    /// name. Returns how many records were copied.
+    pub async fn save_snapshot(&self, name: &str) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_saved_snapshots(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        let taken = self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                INSERT OR IGNORE INTO {} (name, taken_at, records) VALUES (
+            "#, self.kv_name.object("snapshots")))
+            .push_bind(name)
+            .push(",")
+            .push_bind(now())
+            .push(", 0)")
+            .build())
+            .await?;
+        if taken.rows_affected() == 0 && self.explain == Explain::Off {
+            tx.rollback().await?;
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!("there's already a snapshot named {}", name).into())));
+        }
+
+        let mut query = QueryBuilder::new(format!(r#"
+                INSERT INTO {} (snapshot, {columns}) SELECT 
+            "#, self.kv_name.object("snapshot_records"), columns = RECORD_COLUMNS));
+        query.push_bind(name).push(format!(", {} FROM {}", RECORD_COLUMNS, self.kv_name));
+        push_live(&mut query, &mut false);
+        let records = self.execute(&mut tx, query.build()).await?.rows_affected();
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                UPDATE {} SET records = 
+            "#, self.kv_name.object("snapshots")))
+            .push_bind(records as i64)
+            .push(" WHERE name = ")
+            .push_bind(name)
+            .build())
+            .await?;
+        tx.commit().await?;
+        Ok(records)
+    }
+
+    /// The store's saved snapshots, oldest first
+    pub async fn saved_snapshots(&self) -> Result<Vec<SavedSnapshot>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_saved_snapshots(&mut conn).await?;
+        Ok(self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT name, taken_at, records FROM {} ORDER BY taken_at, name
+            "#, self.kv_name.object("snapshots")))
+            .build())
+            .await?
+            .iter()
+            .map(|row| SavedSnapshot {
+                name: row.get("name"),
+                taken_at: to_time(row.get("taken_at")),
+                records: row.get::<i64, &str>("records") as u64,
+            })
+            .collect())
+    }
+
+    async fn check_saved_snapshot(&self, conn: &mut SqliteConnection, name: &str) -> Result<(), DsError> {
+        self.fetch_one(conn, QueryBuilder::new(format!(r#"
+                SELECT name FROM {} WHERE name = 
+            "#, self.kv_name.object("snapshots")))
+            .push_bind(name)
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Replace every record in the store with those of a saved snapshot in one transaction, keeping
+    /// the snapshot. Records that have expired since it was taken stay expired. Returns how many
+    /// records were restored.
+    pub async fn restore_snapshot(&self, name: &str) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_saved_snapshots(&mut conn).await?;
+        self.secure_connection(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        let deleted = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                DELETE FROM {} RETURNING key
+            "#, self.kv_name))
+            .build())
+            .await?;
+        if let Err(e) = self.check_saved_snapshot(&mut tx, name).await {
+            tx.rollback().await?;
+            return Err(e);
+        }
+        let restored = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                INSERT INTO {} ({columns}) SELECT {columns} FROM {} WHERE snapshot = 
+            "#, self.kv_name, self.kv_name.object("snapshot_records"), columns = RECORD_COLUMNS))
+            .push_bind(name)
+            .push(" RETURNING key, value")
+            .build())
+            .await?;
+        let keys: Vec<&str> = deleted.iter().map(|row| row.get("key")).collect();
+        self.scrub(&mut tx, &keys).await?;
+        tx.commit().await?;
+
+        for key in keys {
+            self.notify(ChangeKind::Delete, key, None);
+        }
+        for row in &restored {
+            let value: Vec<u8> = row.get("value");
+            self.notify(ChangeKind::Set, row.get("key"), Some(&String::from_utf8_lossy(&value)));
+        }
+        Ok(restored.len() as u64)
+    }
+
+    /// Compare the store with a saved snapshot, like diff, with the snapshot as the other store
+    pub async fn diff_snapshot(&self, name: &str) -> Result<Vec<SqliteRow>, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_saved_snapshots(&mut conn).await?;
+        self.check_saved_snapshot(&mut conn, name).await?;
+        let records = dedup::resolved_table(&self.kv_name.object("snapshot_records"), self.blobs().as_ref(), &["snapshot", "key", "expires_at"]);
+        self.execute(&mut conn, QueryBuilder::new(format!(r#"
+                CREATE TEMP TABLE snapshot_diff AS SELECT key, value, expires_at FROM {} WHERE snapshot = 
+            "#, records))
+            .push_bind(name)
+            .build())
+            .await?;
+        let snapshot_diff = Table::in_schema("temp", "snapshot_diff");
+        let res = match self.untransform_table(&mut conn, &snapshot_diff).await {
+            Ok(()) => self.diff_in(&mut conn, &snapshot_diff, None).await,
+            Err(e) => Err(e),
+        };
+        self.execute(&mut conn, QueryBuilder::new("DROP TABLE temp.snapshot_diff").build()).await?;
+        res
+    }
+
+    /// Delete a saved snapshot, returning whether there was one by the name
+    pub async fn drop_snapshot(&self, name: &str) -> Result<bool, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_saved_snapshots(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE snapshot = 
+            "#, self.kv_name.object("snapshot_records")))
+            .push_bind(name)
+            .build())
+            .await?;
+        let dropped = self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE name = 
+            "#, self.kv_name.object("snapshots")))
+            .push_bind(name)
+            .build())
+            .await?
+            .rows_affected() > 0;
+        tx.commit().await?;
+        Ok(dropped)
+    }
+}
@@ -0,0 +1,208 @@
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Table, now};
+
+/// Which way KVLite::sync copies records between two stores
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStrategy {
+    /// Copy this store's records that the other is missing or has different
+    Push,
+    /// Copy the other store's records that this one is missing or has different
+    Pull,
+    /// Copy the records either store is missing to it. Records that differ are conflicts, unless
+    /// newest_wins, when the one updated last is copied over the other.
+    TwoWay { newest_wins: bool },
+}
+
+/// What a sync copied, by key
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Records copied from this store to the other
+    pub pushed: Vec<String>,
+    /// Records copied from the other store to this one
+    pub pulled: Vec<String>,
+    /// Records that differ and were left as they are in both stores
+    pub conflicts: Vec<String>,
+}
+
+const COLUMNS: &str = "key, value, expires_at, content_type, created_at, updated_at, tag, pinned, source";
+
+/// How a copied record overwrites one with the same key
+const OVERWRITE: &str = r#" ON CONFLICT (key) DO UPDATE SET
+    value = excluded.value, expires_at = excluded.expires_at, content_type = excluded.content_type,
+    created_at = excluded.created_at, updated_at = excluded.updated_at, tag = excluded.tag,
+    pinned = excluded.pinned, source = excluded.source"#;
+
+impl KVLite {
+    /// Bring this store and the store of the same name in another datastore file in line, which is
+    /// created if it doesn't exist, in one transaction over both. Records are copied whole, keeping
+    /// when they were created and updated, so a later sync sees them as the same. Expired records
+    /// are left out, and deletes aren't synced, as a record deleted from one store can't be told
+    /// apart from one the other store set since.
+    pub async fn sync(&self, filename: &str, strategy: SyncStrategy) -> Result<SyncReport, DsError> {
+        let path = filename.strip_prefix("sqlite://")
+            .or_else(|| filename.strip_prefix("sqlite:"))
+            .unwrap_or(filename);
+
+        let mut conn = self.pool.acquire().await?;
+        self.execute(&mut conn, QueryBuilder::new("ATTACH DATABASE ")
+            .push_bind(path)
+            .push(" AS sync")
+            .build())
+            .await?;
+        let res = self.sync_records(&mut conn, &Table::in_schema("sync", &self.kv_name.name), strategy).await;
+        self.execute(&mut conn, QueryBuilder::new("DETACH DATABASE sync")
+            .build())
+            .await?;
+
+        res
+    }
+
+    async fn sync_records(&self, conn: &mut SqliteConnection, other: &Table, strategy: SyncStrategy) -> Result<SyncReport, DsError> {
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        let expired = self.purge(&mut tx).await?;
+        match self.upgrade_store_table(&mut tx, other).await {
+            Err(DsError::NotFound) => self.create_store_table(&mut tx, other).await?,
+            res => res?,
+        }
+
+        // each condition needs its own bind of now
+        let live = |alias: &str| format!("({alias}.expires_at IS NULL OR {alias}.expires_at > ?)", alias=alias);
+        let differ = ["CAST(a.value AS BLOB)", "a.content_type", "a.tag", "a.expires_at", "a.pinned"]
+            .map(|column| format!("{} IS NOT {}", column, column.replace("a.", "b.")))
+            .join(" OR ");
+        let now = now();
+        let store = self.comparable_records(&mut tx, "sync_store", &["key", "expires_at", "content_type", "updated_at", "tag", "pinned"]).await?;
+        let mut builder = QueryBuilder::new(format!(r#"
+                SELECT key, '+' AS change, NULL AS newer FROM {store} AS a WHERE {live_a}
+                    AND key NOT IN (SELECT key FROM {other} AS b WHERE {live_b})
+                UNION ALL
+                SELECT key, '-' AS change, NULL AS newer FROM {other} AS b WHERE {live_b}
+                    AND key NOT IN (SELECT key FROM {store} AS a WHERE {live_a})
+                UNION ALL
+                SELECT a.key, '~' AS change,
+                    CASE WHEN a.updated_at > b.updated_at THEN '+' WHEN b.updated_at > a.updated_at THEN '-' END AS newer
+                    FROM {store} AS a JOIN {other} AS b ON a.key=b.key
+                    WHERE ({differ}) AND {live_a} AND {live_b}
+                ORDER BY key
+            "#, store=store, other=other, live_a=live("a"), live_b=live("b"), differ=differ));
+        let mut query = builder.build();
+        for _ in 0..6 {
+            query = query.bind(now);
+        }
+
+        let rows = self.fetch_all(&mut tx, query).await?;
+        self.execute(&mut tx, QueryBuilder::new("DROP TABLE IF EXISTS temp.sync_store").build()).await?;
+
+        let mut report = SyncReport::default();
+        for row in rows {
+            let key = row.get("key");
+            let change = match (row.get::<&str, &str>("change"), strategy) {
+                ("~", SyncStrategy::TwoWay { newest_wins: true }) => row.get::<Option<&str>, &str>("newer").unwrap_or("~"),
+                (change, SyncStrategy::TwoWay { .. }) => change,
+                ("+" | "~", SyncStrategy::Push) => "+",
+                ("-" | "~", SyncStrategy::Pull) => "-",
+                // a record only in the store being copied to is left alone
+                _ => continue,
+            };
+            match change {
+                "+" => report.pushed.push(key),
+                "-" => report.pulled.push(key),
+                _ => report.conflicts.push(key),
+            }
+        }
+
+        self.copy_records(&mut tx, &self.kv_name, other, &report.pushed).await?;
+        let pulled = self.copy_records(&mut tx, other, &self.kv_name, &report.pulled).await?;
+        tx.commit().await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        for (key, value) in &pulled {
+            self.notify(ChangeKind::Set, key, Some(value));
+        }
+        Ok(report)
+    }
+
+    /// Copy whole records from one store table to another, overwriting any with the same keys.
+    /// Returns the keys and values written.
+    pub(crate) async fn copy_records(&self, conn: &mut SqliteConnection, src: &Table, dst: &Table, keys: &[String]) -> Result<Vec<(String, String)>, DsError> {
+        if self.dedup.is_some() || !self.value_transforms.is_empty() {
+            return self.copy_values(conn, src, dst, keys).await;
+        }
+        let mut copied = vec![];
+        // well within how many parameters sqlite takes in one statement
+        for keys in keys.chunks(500) {
+            let mut query = QueryBuilder::new(format!(r#"
+                    INSERT INTO {} ({columns}) SELECT {columns} FROM {} WHERE key IN (
+                "#, dst, src, columns=COLUMNS));
+            let mut separated = query.separated(", ");
+            for key in keys {
+                separated.push_bind(key);
+            }
+            query.push(")").push(OVERWRITE).push(" RETURNING key, CAST(value AS BLOB) AS value");
+            copied.extend(self.fetch_all(conn, query.build()).await?
+                .iter()
+                .map(|row| (row.get("key"), String::from_utf8_lossy(row.get("value")).into_owned())));
+        }
+        Ok(copied)
+    }
+
+    /// Copy records like copy_records one at a time, for a store that doesn't keep every value as
+    /// it was written: values copied out of it are read as written, and values copied into it are
+    /// transformed and kept in its blobs like the values it's given
+    async fn copy_values(&self, conn: &mut SqliteConnection, src: &Table, dst: &Table, keys: &[String]) -> Result<Vec<(String, String)>, DsError> {
+        let mut copied = vec![];
+        for keys in keys.chunks(500) {
+            let mut query = QueryBuilder::new(format!(r#"
+                    SELECT {} FROM {} WHERE key IN (
+                "#, COLUMNS, src));
+            let mut separated = query.separated(", ");
+            for key in keys {
+                separated.push_bind(key);
+            }
+            query.push(")");
+            for row in self.fetch_all(conn, query.build()).await? {
+                let key: String = row.get("key");
+                let mut value: Vec<u8> = row.get("value");
+                if src == &self.kv_name {
+                    value = self.resolve_value(conn, value).await?;
+                    value = self.untransform_value(value)?;
+                }
+                let written = String::from_utf8_lossy(&value).into_owned();
+                if dst == &self.kv_name {
+                    if let Some(transformed) = self.transform_value(&value)? {
+                        value = transformed;
+                    }
+                    if let Some(reference) = self.dedup_value(conn, &value).await? {
+                        value = reference.into_bytes();
+                    }
+                }
+
+                let mut insert = QueryBuilder::new(format!(r#"
+                        INSERT INTO {} ({}) VALUES (
+                    "#, dst, COLUMNS));
+                insert.push_bind(key.clone()).push(", ");
+                match String::from_utf8(value) {
+                    Ok(text) => insert.push_bind(text),
+                    Err(e) => insert.push_bind(e.into_bytes()),
+                };
+                let mut separated = insert.separated(", ");
+                separated.push_unseparated(", ");
+                separated
+                    .push_bind(row.get::<Option<i64>, &str>("expires_at"))
+                    .push_bind(row.get::<Option<String>, &str>("content_type"))
+                    .push_bind(row.get::<Option<i64>, &str>("created_at"))
+                    .push_bind(row.get::<Option<i64>, &str>("updated_at"))
+                    .push_bind(row.get::<Option<String>, &str>("tag"))
+                    .push_bind(row.get::<i64, &str>("pinned"))
+                    .push_bind(row.get::<Option<String>, &str>("source"));
+                insert.push(")").push(OVERWRITE);
+                self.execute(conn, insert.build()).await?;
+                copied.push((key, written));
+            }
+        }
+        Ok(copied)
+    }
+}
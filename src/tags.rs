@@ -0,0 +1,83 @@
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, Table};
+
+impl KVLite {
+    fn key_tags_table(&self) -> Table {
+        self.kv_name.object("key_tags")
+    }
+
+    /// Create the table joining a store's keys to their tags, whose rows go with the record they tag
+    pub(crate) async fn create_key_tags(&self, conn: &mut SqliteConnection, kv_name: &Table) -> Result<(), DsError> {
+        // trigger bodies can only refer to tables in their own schema, so they use unqualified names
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                CREATE TABLE IF NOT EXISTS {tags} (key TEXT NOT NULL, tag TEXT NOT NULL, PRIMARY KEY (key, tag));
+                CREATE INDEX IF NOT EXISTS {index} ON {unqualified} (tag, key);
+                CREATE TRIGGER IF NOT EXISTS {delete} AFTER DELETE ON {table} BEGIN
+                    DELETE FROM {unqualified} WHERE key = OLD.key;
+                END;
+            "#, tags = kv_name.object("key_tags"), index = kv_name.object("key_tags_by_tag"), unqualified = kv_name.object("key_tags").unqualified(),
+                delete = kv_name.object("key_tags_delete"), table = kv_name.unqualified()))
+            .build())
+            .await?;
+        Ok(())
+    }
+
+    /// Give a record some tags, so it can be listed with others sharing one whatever its key, with a
+    /// key_tag filter. Returns how many of them it didn't already have.
+    pub async fn tag_key(&self, key: &str, tags: &[&str]) -> Result<u64, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        self.fetch_record(&mut tx, "", key).await?;
+
+        let mut added = 0;
+        for tag in tags {
+            added += self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    INSERT OR IGNORE INTO {} (key, tag) VALUES (
+                "#, self.key_tags_table()))
+                .push_bind(key)
+                .push(",")
+                .push_bind(*tag)
+                .push(")")
+                .build())
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    /// Take some tags off a record, returning how many of them it had
+    pub async fn untag_key(&self, key: &str, tags: &[&str]) -> Result<u64, DsError> {
+        if tags.is_empty() {
+            return Ok(0);
+        }
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        let mut query = QueryBuilder::new(format!(r#"
+                DELETE FROM {} WHERE key =
+            "#, self.key_tags_table()));
+        query.push_bind(key).push(" AND tag IN (");
+        let mut separated = query.separated(", ");
+        for tag in tags {
+            separated.push_bind(*tag);
+        }
+        query.push(")");
+        Ok(self.execute(&mut conn, query.build()).await?.rows_affected())
+    }
+
+    /// The tags of a record, in order
+    pub async fn key_tags(&self, key: &str) -> Result<Vec<String>, DsError> {
+        let key = &self.canonical_key(key)[..];
+        let mut conn = self.pool.acquire().await?;
+        self.fetch_record(&mut conn, "", key).await?;
+        let rows = self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT tag FROM {} WHERE key =
+            "#, self.key_tags_table()))
+            .push_bind(key)
+            .push(" ORDER BY tag")
+            .build())
+            .await?;
+        Ok(rows.iter().map(|row| row.get("tag")).collect())
+    }
+}
@@ -0,0 +1,77 @@
+use std::{fmt, str::FromStr, time::Duration};
+use crate::{KVLite, DsError, Filter, ValueType};
+
+/// A set of store options for a common kind of store, applied to a new store at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Template {
+    /// Records expire a day after they're last set unless given another TTL, up to 64 KiB each
+    Sessions,
+    /// Text values up to 16 KiB, deleted securely and never versioned. The datastore should also
+    /// be encrypted, which is chosen when it's opened rather than per store.
+    Secrets,
+    /// JSON values up to 1 MiB, versioned so any change can be rolled back
+    Config,
+}
+
+impl Template {
+    fn default_ttl(&self) -> Option<Duration> {
+        match self {
+            Template::Sessions => Some(Duration::from_secs(24 * 60 * 60)),
+            Template::Secrets | Template::Config => None,
+        }
+    }
+
+    fn value_type(&self) -> ValueType {
+        match self {
+            Template::Sessions => ValueType::Any,
+            Template::Secrets => ValueType::Text,
+            Template::Config => ValueType::Json,
+        }
+    }
+
+    fn max_value_size(&self) -> u64 {
+        match self {
+            Template::Sessions => 64 * 1024,
+            Template::Secrets => 16 * 1024,
+            Template::Config => 1024 * 1024,
+        }
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Template::Sessions => write!(f, "sessions"),
+            Template::Secrets => write!(f, "secrets"),
+            Template::Config => write!(f, "config"),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sessions" => Ok(Template::Sessions),
+            "secrets" => Ok(Template::Secrets),
+            "config" => Ok(Template::Config),
+            _ => Err(format!("unknown template {}, expected sessions, secrets or config", s)),
+        }
+    }
+}
+
+impl KVLite {
+    /// Set up the store as a template describes: its value type, the size of its values, its
+    /// default TTL and whether it's versioned and deleted securely. The store must still be empty.
+    pub async fn apply_template(&mut self, template: Template) -> Result<(), DsError> {
+        if self.count(&Filter::default()).await? > 0 {
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!("{} already has records, templates are for new stores", self.kv_name.name).into())));
+        }
+        self.set_value_type(template.value_type()).await?;
+        self.set_max_value_size(Some(template.max_value_size())).await?;
+        self.set_default_ttl(template.default_ttl()).await?;
+        self.set_versioned(template == Template::Config).await?;
+        self.set_secure_delete(template == Template::Secrets).await
+    }
+}
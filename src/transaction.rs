@@ -0,0 +1,108 @@
+use sqlx::Sqlite;
+use crate::{KVLite, DsError, ChangeKind, SetOptions, Stored, BatchOp, BatchResult};
+
+/// Reads and writes to a store that all happen in one SQLite transaction, from KVLite::transaction.
+/// Nothing is written unless it's committed, dropping it rolls everything back.
+///
+/// Write before reading where you can: once the transaction has read, a write fails with
+/// StoreLocked if another connection wrote to the datastore in between.
+pub struct Transaction<'s> {
+    store: &'s KVLite,
+    tx: sqlx::Transaction<'static, Sqlite>,
+    /// Changes to tell subscribers about once they're committed
+    changes: Vec<(ChangeKind, String, Option<String>)>,
+}
+
+impl KVLite {
+    /// Start a transaction on the store
+    pub async fn transaction(&self) -> Result<Transaction<'_>, DsError> {
+        Ok(Transaction {
+            store: self,
+            tx: self.pool.begin().await?,
+            changes: vec![],
+        })
+    }
+}
+
+impl Transaction<'_> {
+    pub async fn get(&mut self, key: &str) -> Result<String, DsError> {
+        let key = &self.store.canonical_key(key)[..];
+        self.store.get_in(&mut self.tx, key).await
+    }
+
+    pub async fn get_bytes(&mut self, key: &str) -> Result<Vec<u8>, DsError> {
+        let key = &self.store.canonical_key(key)[..];
+        self.store.get_bytes_in(&mut self.tx, key).await
+    }
+
+    pub async fn contains(&mut self, key: &str) -> Result<bool, DsError> {
+        match self.get_bytes(key).await {
+            Ok(_) => Ok(true),
+            Err(DsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn set(&mut self, key: &str, value: &str) -> Result<(), DsError> {
+        let key = &self.store.canonical_key(key)[..];
+        self.store.set_in(&mut self.tx, key, value, Stored::default()).await?;
+        self.changes.push((ChangeKind::Set, key.to_string(), Some(value.to_string())));
+        Ok(())
+    }
+
+    /// Set a record from bytes with a TTL or content type, like KVLite::set_with
+    pub async fn set_with(&mut self, key: &str, value: &[u8], options: &SetOptions) -> Result<(), DsError> {
+        let key = &self.store.canonical_key(key)[..];
+        if let Some(tag) = options.tag {
+            tag.check(value)?;
+        }
+        let stored = Stored::from_options(options);
+        match std::str::from_utf8(value) {
+            Ok(text) => self.store.set_in(&mut self.tx, key, text, stored).await?,
+            Err(_) => self.store.set_in(&mut self.tx, key, value, stored).await?,
+        }
+        self.changes.push((ChangeKind::Set, key.to_string(), Some(String::from_utf8_lossy(value).into_owned())));
+        Ok(())
+    }
+
+    pub async fn del(&mut self, key: &str) -> Result<(), DsError> {
+        let key = &self.store.canonical_key(key)[..];
+        self.store.del_in(&mut self.tx, key).await?;
+        self.changes.push((ChangeKind::Delete, key.to_string(), None));
+        Ok(())
+    }
+
+    /// Run one of a batch's commands in the transaction
+    pub async fn apply(&mut self, op: &BatchOp) -> Result<BatchResult, DsError> {
+        Ok(match op {
+            BatchOp::Set(key, value) => {
+                self.set(key, value).await?;
+                BatchResult::Done
+            },
+            BatchOp::Get(key) => match self.get(key).await {
+                Ok(value) => BatchResult::Value(Some(value)),
+                Err(DsError::NotFound) => BatchResult::Value(None),
+                Err(e) => return Err(e),
+            },
+            BatchOp::Contains(key) => BatchResult::Contains(self.contains(key).await?),
+            BatchOp::Delete(key) => {
+                self.del(key).await?;
+                BatchResult::Done
+            },
+        })
+    }
+
+    /// Write everything done in the transaction
+    pub async fn commit(self) -> Result<(), DsError> {
+        self.tx.commit().await?;
+        for (kind, key, value) in &self.changes {
+            self.store.notify(*kind, key, value.as_deref());
+        }
+        Ok(())
+    }
+
+    /// Throw away everything done in the transaction
+    pub async fn rollback(self) -> Result<(), DsError> {
+        Ok(self.tx.rollback().await?)
+    }
+}
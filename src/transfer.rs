@@ -0,0 +1,114 @@
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Filter, Table, IMPORT_BATCH, push_live};
+
+impl KVLite {
+    /// Copy the live records matching a filter to another store, in this datastore or, given its
+    /// file name, another one, creating the store if it doesn't exist, and delete them from this
+    /// store too if move_records. Records are copied whole, overwriting any with the same keys,
+    /// IMPORT_BATCH at a time, each batch copied and deleted in one transaction, so an interrupted
+    /// transfer can be run again to finish it. Returns how many records were transferred. Their
+    /// tags aren't, and a store that transforms or deduplicates its values is refused on either
+    /// side, as is one that transforms its keys being transferred to, as the other store couldn't
+    /// read them.
+    pub async fn transfer(&self, to_store: &str, filename: Option<&str>, filter: &Filter, move_records: bool) -> Result<u64, DsError> {
+        self.check_untransformed("transfer")?;
+        let mut conn = self.pool.acquire().await?;
+
+        let path = match filename {
+            Some(filename) => filename.strip_prefix("sqlite://")
+                .or_else(|| filename.strip_prefix("sqlite:"))
+                .unwrap_or(filename),
+            None if to_store == self.kv_name.name => return Err(DsError::Sql(sqlx::Error::Configuration("records can't be transferred to the store they're in".into()))),
+            None => {
+                let dst = Table::new(to_store);
+                self.check_store_name(&mut conn, &dst).await?;
+                self.check_destination(&mut conn, &dst).await?;
+                return self.transfer_records(&mut conn, &dst, filter, move_records).await;
+            },
+        };
+        self.execute(&mut conn, QueryBuilder::new("ATTACH DATABASE ")
+            .push_bind(path)
+            .push(" AS transfer")
+            .build())
+            .await?;
+        let dst = Table::in_schema("transfer", to_store);
+        let res = match self.check_store_name(&mut conn, &dst).await {
+            Ok(()) => match self.check_destination(&mut conn, &dst).await {
+                Ok(()) => self.transfer_records(&mut conn, &dst, filter, move_records).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        self.execute(&mut conn, QueryBuilder::new("DETACH DATABASE transfer")
+            .build())
+            .await?;
+
+        res
+    }
+
+    /// Fail if the store transferred to transforms or deduplicates what's written to it, as the
+    /// records would be copied as this store keeps them
+    async fn check_destination(&self, conn: &mut SqliteConnection, dst: &Table) -> Result<(), DsError> {
+        let settings = dst.object("settings");
+        if !self.table_exists(conn, &settings).await? {
+            return Ok(());
+        }
+        let stored = self.fetch_optional(conn, QueryBuilder::new(format!(r#"
+                SELECT name FROM {} WHERE name IN ('key_transform', 'value_transform', 'dedup')
+            "#, settings))
+            .build())
+            .await?;
+        match stored {
+            Some(_) => Err(DsError::Sql(sqlx::Error::Configuration(format!(
+                "records can't be transferred to {}, it transforms or deduplicates them", dst.name).into()))),
+            None => Ok(()),
+        }
+    }
+
+    async fn transfer_records(&self, conn: &mut SqliteConnection, dst: &Table, filter: &Filter, move_records: bool) -> Result<u64, DsError> {
+        let mut tx = conn.begin().await?;
+        match self.upgrade_store_table(&mut tx, dst).await {
+            Err(DsError::NotFound) => self.create_store_table(&mut tx, dst).await?,
+            res => res?,
+        }
+        tx.commit().await?;
+
+        let mut query = QueryBuilder::new(format!(r#"
+                SELECT key FROM {}
+            "#, self.kv_name));
+        let mut has_where = false;
+        push_live(&mut query, &mut has_where);
+        filter.push_conditions(&self.kv_name, self.blobs().as_ref(), &mut query, &mut has_where);
+        let keys: Vec<String> = self.fetch_all(conn, query.push(" ORDER BY key").build())
+            .await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+
+        for batch in keys.chunks(IMPORT_BATCH) {
+            let mut tx = conn.begin().await?;
+            self.copy_records(&mut tx, &self.kv_name, dst, batch).await?;
+            if move_records {
+                // well within how many parameters sqlite takes in one statement
+                for keys in batch.chunks(500) {
+                    let mut query = QueryBuilder::new(format!(r#"
+                            DELETE FROM {} WHERE key IN (
+                        "#, self.kv_name));
+                    let mut separated = query.separated(", ");
+                    for key in keys {
+                        separated.push_bind(key);
+                    }
+                    self.execute(&mut tx, query.push(")").build()).await?;
+                }
+            }
+            tx.commit().await?;
+
+            if move_records {
+                for key in batch {
+                    self.notify(ChangeKind::Delete, key, None);
+                }
+            }
+        }
+        Ok(keys.len() as u64)
+    }
+}
@@ -0,0 +1,574 @@
+use std::{borrow::Cow, collections::HashMap, fmt, io::{self, Read, Write}, str::FromStr};
+use base64::{Engine, engine::general_purpose::STANDARD};
+#[cfg(feature = "value-encryption")]
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, AeadCore, aead::{Aead, OsRng}};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+#[cfg(feature = "value-encryption")]
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Filter, ListOptions, SortBy, Table, IMPORT_BATCH};
+
+/// A change made to every key given to a store, so callers that spell a key differently still
+/// name the same record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyTransform {
+    Lowercase,
+    /// Remove whitespace from both ends
+    Trim,
+    /// Lowercase each /-separated part, replacing every run of characters that aren't letters or
+    /// digits with a single - and dropping them from the ends
+    Slugify,
+    /// Remove the prefix once if the key starts with it
+    StripPrefix(String),
+}
+
+impl KeyTransform {
+    pub fn apply(&self, key: &str) -> String {
+        match self {
+            KeyTransform::Lowercase => key.to_lowercase(),
+            KeyTransform::Trim => key.trim().to_string(),
+            KeyTransform::Slugify => key.split('/').map(slugify).collect::<Vec<_>>().join("/"),
+            KeyTransform::StripPrefix(prefix) => key.strip_prefix(prefix.as_str()).unwrap_or(key).to_string(),
+        }
+    }
+}
+
+fn slugify(part: &str) -> String {
+    let mut slug = String::with_capacity(part.len());
+    let mut dash = false;
+    for c in part.chars() {
+        if !c.is_alphanumeric() {
+            dash = true;
+            continue;
+        }
+        if dash && !slug.is_empty() {
+            slug.push('-');
+        }
+        dash = false;
+        slug.extend(c.to_lowercase());
+    }
+    slug
+}
+
+impl fmt::Display for KeyTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyTransform::Lowercase => write!(f, "lowercase"),
+            KeyTransform::Trim => write!(f, "trim"),
+            KeyTransform::Slugify => write!(f, "slugify"),
+            KeyTransform::StripPrefix(prefix) => write!(f, "strip-prefix:{}", prefix),
+        }
+    }
+}
+
+impl FromStr for KeyTransform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(prefix) = s.strip_prefix("strip-prefix:") {
+            return Ok(KeyTransform::StripPrefix(prefix.to_string()));
+        }
+        match s.to_lowercase().as_str() {
+            "lowercase" => Ok(KeyTransform::Lowercase),
+            "trim" => Ok(KeyTransform::Trim),
+            "slugify" => Ok(KeyTransform::Slugify),
+            _ => Err(format!("unknown key transform {}, expected lowercase, trim, slugify or strip-prefix:<prefix>", s)),
+        }
+    }
+}
+
+impl KVLite {
+    /// The key a record given this one is stored under, after the store's key transforms
+    pub fn canonical_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        let mut key = Cow::Borrowed(key);
+        for transform in &self.key_transforms {
+            key = Cow::Owned(transform.apply(&key));
+        }
+        key
+    }
+
+    /// The transforms applied to every key given to the store, in order
+    pub fn key_transforms(&self) -> &[KeyTransform] {
+        &self.key_transforms
+    }
+
+    /// Apply these transforms, in order, to the key of every record read, written or deleted from
+    /// now on, replacing any the store had. Saved with the store. Records already in it are renamed
+    /// to their new keys in one transaction, which fails without renaming any if two would end up
+    /// with the same key. Returns how many were renamed. Prefixes and patterns that filter listings
+    /// aren't transformed.
+    pub async fn set_key_transforms(&mut self, transforms: Vec<KeyTransform>) -> Result<u64, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.create_settings(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        let expired = self.purge(&mut tx).await?;
+
+        let keys: Vec<String> = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT key FROM {}
+            "#, self.kv_name))
+            .build())
+            .await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+        let mut targets: HashMap<String, &str> = HashMap::with_capacity(keys.len());
+        let mut renamed = vec![];
+        for key in &keys {
+            let target = transforms.iter().fold(key.clone(), |key, transform| transform.apply(&key));
+            if let Some(other) = targets.insert(target.clone(), key) {
+                tx.rollback().await?;
+                return Err(DsError::Sql(sqlx::Error::Configuration(format!("{} and {} would both become {}", other, key, target).into())));
+            }
+            if target != *key {
+                renamed.push((key.as_str(), target));
+            }
+        }
+
+        if !renamed.is_empty() {
+            // move the records through a temporary table so keys being renamed can't collide with each other
+            self.execute(&mut tx, QueryBuilder::new("CREATE TEMP TABLE rekeyed (old TEXT PRIMARY KEY, new TEXT)").build()).await?;
+            for batch in renamed.chunks(IMPORT_BATCH) {
+                let mut query = QueryBuilder::new("INSERT INTO temp.rekeyed (old, new) ");
+                query.push_values(batch, |mut row, (old, new)| {
+                    row.push_bind(*old).push_bind(new.as_str());
+                });
+                self.execute(&mut tx, query.build()).await?;
+            }
+            self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    CREATE TEMP TABLE renamed AS SELECT * FROM {} WHERE key IN (SELECT old FROM temp.rekeyed)
+                "#, self.kv_name))
+                .build())
+                .await?;
+            self.execute(&mut tx, QueryBuilder::new(r#"
+                    UPDATE temp.renamed SET key = (SELECT new FROM temp.rekeyed WHERE old = renamed.key)
+                "#)
+                .build())
+                .await?;
+            self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    DELETE FROM {} WHERE key IN (SELECT old FROM temp.rekeyed)
+                "#, self.kv_name))
+                .build())
+                .await?;
+            self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                    INSERT INTO {} SELECT * FROM temp.renamed
+                "#, self.kv_name))
+                .build())
+                .await?;
+            self.execute(&mut tx, QueryBuilder::new("DROP TABLE temp.renamed").build()).await?;
+            self.execute(&mut tx, QueryBuilder::new("DROP TABLE temp.rekeyed").build()).await?;
+        }
+
+        match transforms.is_empty() {
+            true => self.delete_setting(&mut tx, "key_transform").await?,
+            false => {
+                let saved: Vec<String> = transforms.iter().map(KeyTransform::to_string).collect();
+                self.save_setting(&mut tx, "key_transform", &serde_json::to_string(&saved).unwrap_or_default()).await?;
+            },
+        }
+        tx.commit().await?;
+
+        for key in &expired {
+            self.notify(ChangeKind::Expired, key, None);
+        }
+        for (old, new) in &renamed {
+            self.notify(ChangeKind::Delete, old, None);
+            self.notify(ChangeKind::Set, new, None);
+        }
+        self.key_transforms = transforms;
+        Ok(renamed.len() as u64)
+    }
+}
+
+/// Transforms saved with a store by set_key_transforms, skipping any this build doesn't know
+pub(crate) fn parse_key_transforms(saved: &str) -> Vec<KeyTransform> {
+    serde_json::from_str::<Vec<String>>(saved)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|transform| transform.parse().ok())
+        .collect()
+}
+
+/// A step a store's values pass through on their way into the datastore, undone in reverse order
+/// when they're read, so values can be compressed, encrypted and encoded without callers knowing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueTransform {
+    /// Compress with gzip
+    Gzip,
+    /// Encrypt with ChaCha20-Poly1305, keyed by the passphrase given to the store handle
+    #[cfg(feature = "value-encryption")]
+    Encrypt,
+    /// Encode as standard base64, so what's stored is text
+    Base64,
+    /// Compress values of at least this many bytes, leaving smaller ones as written so they can
+    /// still be read and filtered in SQL. Each value's codec's magic number at its start flags
+    /// whether it's compressed, so the codec and size can change without rewriting records.
+    Compress(Codec, u64),
+}
+
+/// How a compress transform compresses values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Bytes every gzip stream starts with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Bytes every zstd frame starts with
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+impl Codec {
+    fn compress(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(value)?;
+                encoder.finish()
+            },
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::encode_all(value, 0),
+        }
+    }
+}
+
+/// Whether a value starts with a codec's magic number, and so reads as compressed
+fn is_compressed(value: &[u8]) -> bool {
+    value.starts_with(&GZIP_MAGIC) || value.starts_with(&ZSTD_MAGIC)
+}
+
+/// Decompress a value with the codec its magic number names, or return it as it is if it has none
+fn decompress(value: &[u8]) -> io::Result<Vec<u8>> {
+    if value.starts_with(&GZIP_MAGIC) {
+        let mut decoded = vec![];
+        GzDecoder::new(value).read_to_end(&mut decoded)?;
+        return Ok(decoded);
+    }
+    if value.starts_with(&ZSTD_MAGIC) {
+        #[cfg(feature = "zstd")]
+        return zstd::decode_all(value);
+        #[cfg(not(feature = "zstd"))]
+        return Err(io::Error::other("it's compressed with zstd, which needs the zstd feature"));
+    }
+    Ok(value.to_vec())
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Gzip => write!(f, "gzip"),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl ValueTransform {
+    #[cfg_attr(not(feature = "value-encryption"), allow(unused_variables))]
+    fn encode(&self, value: &[u8], key: Option<&[u8; 32]>) -> Result<Vec<u8>, DsError> {
+        let error = |e: String| DsError::Sql(sqlx::Error::Decode(format!("value can't be transformed with {}: {}", self, e).into()));
+        match self {
+            ValueTransform::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(value).map_err(|e| error(e.to_string()))?;
+                encoder.finish().map_err(|e| error(e.to_string()))
+            },
+            #[cfg(feature = "value-encryption")]
+            ValueTransform::Encrypt => {
+                let cipher = ChaCha20Poly1305::new(key.ok_or_else(missing_passphrase)?.into());
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                // the nonce is kept in front of the ciphertext, it's needed to decrypt
+                let mut encrypted = nonce.to_vec();
+                encrypted.extend(cipher.encrypt(&nonce, value).map_err(|e| error(e.to_string()))?);
+                Ok(encrypted)
+            },
+            ValueTransform::Base64 => Ok(STANDARD.encode(value).into_bytes()),
+            // a small value that looks compressed is compressed anyway, so it's read back as written
+            ValueTransform::Compress(_, min_size) if (value.len() as u64) < *min_size && !is_compressed(value) => Ok(value.to_vec()),
+            ValueTransform::Compress(codec, _) => {
+                let compressed = codec.compress(value).map_err(|e| error(e.to_string()))?;
+                match compressed.len() < value.len() || is_compressed(value) {
+                    true => Ok(compressed),
+                    false => Ok(value.to_vec()),
+                }
+            },
+        }
+    }
+
+    #[cfg_attr(not(feature = "value-encryption"), allow(unused_variables))]
+    fn decode(&self, value: &[u8], key: Option<&[u8; 32]>) -> Result<Vec<u8>, DsError> {
+        let error = |e: String| DsError::Sql(sqlx::Error::Decode(format!("value isn't valid {}: {}", self, e).into()));
+        match self {
+            ValueTransform::Gzip => {
+                let mut decoded = vec![];
+                GzDecoder::new(value).read_to_end(&mut decoded).map_err(|e| error(e.to_string()))?;
+                Ok(decoded)
+            },
+            #[cfg(feature = "value-encryption")]
+            ValueTransform::Encrypt => {
+                let cipher = ChaCha20Poly1305::new(key.ok_or_else(missing_passphrase)?.into());
+                if value.len() < NONCE_LEN {
+                    return Err(error("too short".to_string()));
+                }
+                let (nonce, encrypted) = value.split_at(NONCE_LEN);
+                cipher.decrypt(nonce.into(), encrypted).map_err(|_| error("the passphrase is wrong or the value was changed".to_string()))
+            },
+            ValueTransform::Base64 => STANDARD.decode(value).map_err(|e| error(e.to_string())),
+            ValueTransform::Compress(..) => decompress(value).map_err(|e| error(e.to_string())),
+        }
+    }
+}
+
+/// Bytes of the nonce stored in front of an encrypted value
+#[cfg(feature = "value-encryption")]
+const NONCE_LEN: usize = 12;
+
+#[cfg(feature = "value-encryption")]
+fn missing_passphrase() -> DsError {
+    DsError::Sql(sqlx::Error::Configuration("the store's values are encrypted, give the handle a passphrase with set_value_passphrase".into()))
+}
+
+impl fmt::Display for ValueTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueTransform::Gzip => write!(f, "gzip"),
+            #[cfg(feature = "value-encryption")]
+            ValueTransform::Encrypt => write!(f, "encrypt"),
+            ValueTransform::Base64 => write!(f, "base64"),
+            #[cfg(feature = "zstd")]
+            ValueTransform::Compress(Codec::Zstd, 0) => write!(f, "zstd"),
+            ValueTransform::Compress(codec, min_size) => write!(f, "{}:{}", codec, min_size),
+        }
+    }
+}
+
+impl FromStr for ValueTransform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        if let Some((codec, min_size)) = s.split_once(':') {
+            let min_size = min_size.parse().map_err(|_| format!("{} isn't a number of bytes to compress values from", min_size))?;
+            return match codec {
+                "gzip" => Ok(ValueTransform::Compress(Codec::Gzip, min_size)),
+                #[cfg(feature = "zstd")]
+                "zstd" => Ok(ValueTransform::Compress(Codec::Zstd, min_size)),
+                _ => Err(format!("unknown or disabled codec {}, expected gzip or zstd", codec)),
+            };
+        }
+        match s.as_str() {
+            "gzip" | "compress" => Ok(ValueTransform::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(ValueTransform::Compress(Codec::Zstd, 0)),
+            #[cfg(feature = "value-encryption")]
+            "encrypt" => Ok(ValueTransform::Encrypt),
+            "base64" | "encode" => Ok(ValueTransform::Base64),
+            _ => Err(format!("unknown or disabled value transform {}, expected gzip, gzip:<bytes>, zstd, zstd:<bytes>, encrypt or base64", s)),
+        }
+    }
+}
+
+/// Pass a value through transforms in order
+fn encode_value(transforms: &[ValueTransform], key: Option<&[u8; 32]>, value: &[u8]) -> Result<Vec<u8>, DsError> {
+    let mut value = value.to_vec();
+    for transform in transforms {
+        value = transform.encode(&value, key)?;
+    }
+    Ok(value)
+}
+
+/// Undo transforms on a value, last first
+fn decode_value(transforms: &[ValueTransform], key: Option<&[u8; 32]>, mut value: Vec<u8>) -> Result<Vec<u8>, DsError> {
+    for transform in transforms.iter().rev() {
+        value = transform.decode(&value, key)?;
+    }
+    Ok(value)
+}
+
+impl KVLite {
+    /// The transforms every value written to the store passes through, in order
+    pub fn value_transforms(&self) -> &[ValueTransform] {
+        &self.value_transforms
+    }
+
+    /// Key values encrypted by the store's encrypt transform with a passphrase. It's never saved, so
+    /// every handle to a store that encrypts its values needs it before reading or writing them.
+    #[cfg(feature = "value-encryption")]
+    pub fn set_value_passphrase(&mut self, passphrase: &str) {
+        self.value_key = Some(Sha256::digest(passphrase.as_bytes()).into());
+    }
+
+    #[cfg(feature = "value-encryption")]
+    fn value_key(&self) -> Option<&[u8; 32]> {
+        self.value_key.as_ref()
+    }
+
+    #[cfg(not(feature = "value-encryption"))]
+    fn value_key(&self) -> Option<&[u8; 32]> {
+        None
+    }
+
+    /// A value as it's stored, after the store's value transforms, or None if it has none
+    pub(crate) fn transform_value(&self, value: &[u8]) -> Result<Option<Vec<u8>>, DsError> {
+        if self.value_transforms.is_empty() {
+            return Ok(None);
+        }
+        encode_value(&self.value_transforms, self.value_key(), value).map(Some)
+    }
+
+    /// A value as it was written, undoing the store's value transforms. Rows read by listings and
+    /// searches hold values as they're stored, so each is read as written with this.
+    pub fn untransform_value(&self, value: Vec<u8>) -> Result<Vec<u8>, DsError> {
+        decode_value(&self.value_transforms, self.value_key(), value)
+    }
+
+    /// Undo the store's value transforms on every value in a table of them, such as a copy of its
+    /// records, so they're compared or exported as written
+    pub(crate) async fn untransform_table(&self, conn: &mut SqliteConnection, table: &Table) -> Result<(), DsError> {
+        if self.value_transforms.is_empty() {
+            return Ok(());
+        }
+        let rows = self.fetch_all(conn, QueryBuilder::new(format!(r#"
+                SELECT rowid, value FROM {} WHERE value IS NOT NULL
+            "#, table))
+            .build())
+            .await?;
+        for row in &rows {
+            let mut query = QueryBuilder::new(format!(r#"
+                    UPDATE {} SET value =
+                "#, table));
+            match String::from_utf8(self.untransform_value(row.get("value"))?) {
+                Ok(text) => query.push_bind(text),
+                Err(e) => query.push_bind(e.into_bytes()),
+            };
+            self.execute(conn, query.push(" WHERE rowid = ").push_bind(row.get::<i64, &str>("rowid")).build()).await?;
+        }
+        Ok(())
+    }
+
+    /// What to compare the store's records with others from, given the columns needed besides
+    /// value: its records as readable_records reads them, or if it transforms its values, a
+    /// temporary table by the name holding a copy of them as written, for the caller to drop
+    pub(crate) async fn comparable_records(&self, conn: &mut SqliteConnection, name: &str, columns: &[&str]) -> Result<String, DsError> {
+        if self.value_transforms.is_empty() {
+            return Ok(self.readable_records());
+        }
+        let copy = Table::in_schema("temp", name);
+        // left behind if a value failed to decode last time
+        self.execute(conn, QueryBuilder::new(format!(r#"
+                DROP TABLE IF EXISTS {copy};
+                CREATE TABLE {copy} AS SELECT value, {} FROM {}
+            "#, columns.join(", "), self.readable_records(), copy = copy))
+            .build())
+            .await?;
+        self.untransform_table(conn, &copy).await?;
+        Ok(copy.to_string())
+    }
+
+    /// Undoes the store's value transforms like untransform_value, but can outlive the handle, for streams
+    pub(crate) fn value_decoder(&self) -> impl Fn(Vec<u8>) -> Result<Vec<u8>, DsError> + Send + 'static {
+        let (transforms, key) = (self.value_transforms.clone(), self.value_key().copied());
+        move |value| decode_value(&transforms, key.as_ref(), value)
+    }
+
+    /// Fail if the store transforms or deduplicates its values, for commands that change or measure
+    /// a value in SQL and would see only the bytes stored
+    pub(crate) fn check_untransformed(&self, command: &str) -> Result<(), DsError> {
+        if self.dedup.is_some() {
+            return Err(DsError::Sql(sqlx::Error::Configuration(format!("{} can't be used on a store that deduplicates its values", command).into())));
+        }
+        match self.value_transforms.is_empty() {
+            true => Ok(()),
+            false => Err(DsError::Sql(sqlx::Error::Configuration(format!("{} can't be used on a store that transforms its values", command).into()))),
+        }
+    }
+
+    /// Fail if a filter matches values in SQL and the store transforms them, as it would only see
+    /// them as they're stored
+    pub(crate) fn check_filter(&self, filter: &Filter) -> Result<(), DsError> {
+        match filter.value_contains.is_some() && !self.value_transforms.is_empty() {
+            true => Err(DsError::Sql(sqlx::Error::Configuration("value filters can't be used on a store that transforms its values".into()))),
+            false => Ok(()),
+        }
+    }
+
+    /// Fail if a listing filters or sorts by values in SQL and the store transforms them, like check_filter
+    pub(crate) fn check_listing(&self, options: &ListOptions) -> Result<(), DsError> {
+        self.check_filter(&options.filter)?;
+        match (options.sort || options.reverse) && options.by == SortBy::Value && !self.value_transforms.is_empty() {
+            true => Err(DsError::Sql(sqlx::Error::Configuration("a store that transforms its values can't be sorted by them".into()))),
+            false => Ok(()),
+        }
+    }
+
+    /// Pass every value written to the store from now on through these transforms, in order, and
+    /// undo them in reverse when values are read, replacing any the store had. Saved with the store.
+    /// Records already in it are rewritten in one transaction. Value filters, sorting by value,
+    /// full-text search, incr, append, prepend, strlen and cas refuse to run. A store
+    /// with a type or a max value size can't transform its values, as they're checked as stored.
+    pub async fn set_value_transforms(&mut self, transforms: Vec<ValueTransform>) -> Result<u64, DsError> {
+        let key = self.value_key().copied();
+        let mut conn = self.pool.acquire().await?;
+        if !transforms.is_empty() {
+            self.check_unconstrained(&mut conn, "transform its values").await?;
+        }
+        self.create_settings(&mut conn).await?;
+        let mut tx = conn.begin().await?;
+        // write before reading, sqlite won't wait for the write lock in a transaction that has already read
+        match transforms.is_empty() {
+            true => self.delete_setting(&mut tx, "value_transform").await?,
+            false => {
+                let saved: Vec<String> = transforms.iter().map(ValueTransform::to_string).collect();
+                self.save_setting(&mut tx, "value_transform", &serde_json::to_string(&saved).unwrap_or_default()).await?;
+            },
+        }
+
+        let rows = self.fetch_all(&mut tx, QueryBuilder::new(format!(r#"
+                SELECT key, value FROM {}
+            "#, self.kv_name))
+            .build())
+            .await?;
+        let mut rewritten = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let value = decode_value(&self.value_transforms, key.as_ref(), row.get("value"))
+                .and_then(|value| match transforms.is_empty() {
+                    true => Ok(value),
+                    false => encode_value(&transforms, key.as_ref(), &value),
+                });
+            match value {
+                Ok(value) => rewritten.push((row.get::<String, &str>("key"), value)),
+                Err(e) => {
+                    tx.rollback().await?;
+                    return Err(e);
+                },
+            }
+        }
+        for (record, value) in &rewritten {
+            let mut query = QueryBuilder::new(format!(r#"
+                    UPDATE {} SET value =
+                "#, self.kv_name));
+            match std::str::from_utf8(value) {
+                Ok(text) => query.push_bind(text),
+                Err(_) => query.push_bind(value.as_slice()),
+            };
+            if let Err(e) = self.execute(&mut tx, query.push(" WHERE key = ").push_bind(record.as_str()).build()).await {
+                tx.rollback().await?;
+                return Err(e);
+            }
+        }
+        tx.commit().await?;
+
+        self.value_transforms = transforms;
+        Ok(rewritten.len() as u64)
+    }
+}
+
+/// Transforms saved with a store by set_value_transforms. Values can't be read by a build that
+/// doesn't know one of them, so an unknown transform is an error rather than skipped.
+pub(crate) fn parse_value_transforms(saved: &str) -> Result<Vec<ValueTransform>, DsError> {
+    serde_json::from_str::<Vec<String>>(saved)
+        .unwrap_or_default()
+        .iter()
+        .map(|transform| transform.parse().map_err(|e: String| DsError::Sql(sqlx::Error::Configuration(e.into()))))
+        .collect()
+}
@@ -0,0 +1,288 @@
+use std::{io, time::{Duration, SystemTime}};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListState, Paragraph, Wrap},
+};
+use sqlx::Row;
+use crate::{KVLite, DsError, ListOptions, RecordMeta, SetOptions};
+
+/// Keys moved by PageUp and PageDown
+const PAGE: usize = 20;
+
+/// What typed keys do
+pub(crate) enum Mode {
+    Browse,
+    /// Typing the search, which narrows the list as it changes
+    Search,
+    /// Typing the key the selected record is renamed to
+    Rename(String),
+    /// Waiting for y to delete the selected record
+    Delete,
+}
+
+/// The record shown in the preview pane
+pub(crate) struct Preview {
+    pub(crate) key: String,
+    pub(crate) value: Vec<u8>,
+    pub(crate) meta: RecordMeta,
+}
+
+pub(crate) struct App {
+    pub(crate) keys: Vec<String>,
+    /// Indexes in keys of those matching the search
+    pub(crate) shown: Vec<usize>,
+    pub(crate) list: ListState,
+    pub(crate) search: String,
+    pub(crate) mode: Mode,
+    pub(crate) preview: Option<Preview>,
+    pub(crate) status: String,
+}
+
+impl App {
+    pub(crate) fn new() -> App {
+        App { keys: vec![], shown: vec![], list: ListState::default(), search: String::new(), mode: Mode::Browse, preview: None, status: String::new() }
+    }
+
+    pub(crate) fn selected(&self) -> Option<&str> {
+        self.list.selected().and_then(|i| self.shown.get(i)).map(|&i| self.keys[i].as_str())
+    }
+
+    /// Narrow the list to the keys containing the search, ignoring case, keeping the selected key
+    /// selected if it still matches
+    pub(crate) fn apply_search(&mut self) {
+        let selected = self.selected().map(str::to_string);
+        let search = self.search.to_lowercase();
+        self.shown = (0..self.keys.len()).filter(|&i| self.keys[i].to_lowercase().contains(&search)).collect();
+        let at = selected.and_then(|key| self.shown.iter().position(|&i| self.keys[i] == key));
+        self.list.select(match self.shown.is_empty() {
+            true => None,
+            false => Some(at.unwrap_or(0)),
+        });
+    }
+
+    fn step(&mut self, by: isize) {
+        if self.shown.is_empty() {
+            return;
+        }
+        let at = self.list.selected().unwrap_or(0) as isize + by;
+        self.list.select(Some(at.clamp(0, self.shown.len() as isize - 1) as usize));
+    }
+}
+
+/// Browse a store in the terminal: a list of its keys narrowed by typing a search after /, the
+/// selected record's value and metadata beside it, e to change the value with edit, which is given
+/// the value and returns the new one or None to leave it, r to rename and d to delete. Edits that
+/// race another writer are refused rather than overwriting its change.
+pub async fn run(store: &KVLite, edit: impl Fn(&[u8]) -> io::Result<Option<Vec<u8>>>) -> io::Result<()> {
+    let mut app = App::new();
+    reload(store, &mut app).await;
+
+    let mut terminal = ratatui::try_init()?;
+    let res = browse(store, &mut terminal, &mut app, &edit).await;
+    ratatui::restore();
+    res
+}
+
+async fn browse(store: &KVLite, terminal: &mut DefaultTerminal, app: &mut App, edit: &impl Fn(&[u8]) -> io::Result<Option<Vec<u8>>>) -> io::Result<()> {
+    loop {
+        if app.selected() != app.preview.as_ref().map(|preview| preview.key.as_str()) {
+            app.preview = match app.selected().map(str::to_string) {
+                Some(key) => match store.get_with_meta(&key).await {
+                    Ok((value, meta)) => Some(Preview { key, value, meta }),
+                    Err(e) => {
+                        app.status = format!("{}: {}", key, e);
+                        None
+                    },
+                },
+                None => None,
+            };
+        }
+        terminal.draw(|frame| draw(frame, app))?;
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Ok(());
+        }
+        app.status.clear();
+        match &mut app.mode {
+            Mode::Search => match key.code {
+                KeyCode::Enter => app.mode = Mode::Browse,
+                KeyCode::Esc => {
+                    app.search.clear();
+                    app.apply_search();
+                    app.mode = Mode::Browse;
+                },
+                KeyCode::Backspace => {
+                    app.search.pop();
+                    app.apply_search();
+                },
+                KeyCode::Char(c) => {
+                    app.search.push(c);
+                    app.apply_search();
+                },
+                code => move_selection(app, code),
+            },
+            Mode::Rename(new) => match key.code {
+                KeyCode::Enter => {
+                    let new = std::mem::take(new);
+                    app.mode = Mode::Browse;
+                    if let Some(old) = app.selected().map(str::to_string) {
+                        rename(store, app, &old, &new).await;
+                    }
+                },
+                KeyCode::Esc => app.mode = Mode::Browse,
+                KeyCode::Backspace => {
+                    new.pop();
+                },
+                KeyCode::Char(c) => new.push(c),
+                _ => (),
+            },
+            Mode::Delete => {
+                app.mode = Mode::Browse;
+                if let (KeyCode::Char('y'), Some(key)) = (key.code, app.selected().map(str::to_string)) {
+                    app.status = match store.del(&key).await {
+                        Ok(()) => format!("deleted {}", key),
+                        Err(e) => format!("{} was not deleted: {}", key, e),
+                    };
+                    reload(store, app).await;
+                }
+            },
+            Mode::Browse => match key {
+                KeyEvent { code: KeyCode::Char('q') | KeyCode::Esc, .. } => return Ok(()),
+                KeyEvent { code: KeyCode::Char('/'), .. } => app.mode = Mode::Search,
+                KeyEvent { code: KeyCode::Char('r'), .. } if app.selected().is_some() => app.mode = Mode::Rename(app.selected().unwrap_or_default().to_string()),
+                KeyEvent { code: KeyCode::Char('d'), .. } if app.selected().is_some() => app.mode = Mode::Delete,
+                KeyEvent { code: KeyCode::Char('R'), .. } => {
+                    reload(store, app).await;
+                    app.status = format!("{} keys", app.keys.len());
+                },
+                KeyEvent { code: KeyCode::Char('e') | KeyCode::Enter, .. } => match app.preview.take() {
+                    Some(preview) if std::str::from_utf8(&preview.value).is_err() => app.status = format!("{} isn't text and can't be edited", preview.key),
+                    Some(preview) => {
+                        // the editor needs the terminal back while it runs
+                        ratatui::restore();
+                        let edited = edit(&preview.value);
+                        *terminal = ratatui::try_init()?;
+                        app.status = match edited {
+                            Ok(Some(value)) if value != preview.value => save(store, &preview, &value).await,
+                            Ok(_) => format!("{} was not changed", preview.key),
+                            Err(e) => format!("{} was not changed: {}", preview.key, e),
+                        };
+                    },
+                    None => (),
+                },
+                KeyEvent { code, .. } => move_selection(app, code),
+            },
+        }
+    }
+}
+
+pub(crate) fn move_selection(app: &mut App, code: KeyCode) {
+    match code {
+        KeyCode::Down | KeyCode::Char('j') => app.step(1),
+        KeyCode::Up | KeyCode::Char('k') => app.step(-1),
+        KeyCode::PageDown => app.step(PAGE as isize),
+        KeyCode::PageUp => app.step(-(PAGE as isize)),
+        KeyCode::Home | KeyCode::Char('g') => app.step(isize::MIN / 2),
+        KeyCode::End | KeyCode::Char('G') => app.step(isize::MAX / 2),
+        _ => (),
+    }
+}
+
+/// Read the keys again, after they've changed
+pub(crate) async fn reload(store: &KVLite, app: &mut App) {
+    match store.keys(&ListOptions { sort: true, ..Default::default() }).await {
+        Ok(rows) => app.keys = rows.iter().map(|row| row.get("key")).collect(),
+        Err(e) => app.status = format!("the keys couldn't be read: {}", e),
+    }
+    app.preview = None;
+    app.apply_search();
+}
+
+/// Write back an edited value only if the record wasn't changed since it was shown, keeping its
+/// metadata and the TTL it has left, as the edit command does
+pub(crate) async fn save(store: &KVLite, preview: &Preview, value: &[u8]) -> String {
+    let old = String::from_utf8_lossy(&preview.value);
+    let ttl = preview.meta.expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now()).unwrap_or_default().max(Duration::from_secs(1)));
+    let options = SetOptions { ttl, content_type: preview.meta.content_type.clone(), tag: preview.meta.tag, ..Default::default() };
+    match store.cas_with(&preview.key, Some(&old), value, &options).await {
+        Ok(true) => format!("saved {}", preview.key),
+        Ok(false) => format!("{} was changed while it was being edited and was not saved", preview.key),
+        Err(e) => format!("{} was not saved: {}", preview.key, e),
+    }
+}
+
+pub(crate) async fn rename(store: &KVLite, app: &mut App, old: &str, new: &str) {
+    app.status = match store.rename(old, new, false).await {
+        Ok(true) => format!("renamed {} to {}", old, new),
+        Ok(false) => format!("{} already exists", new),
+        Err(DsError::NotFound) => format!("{} no longer exists", old),
+        Err(e) => format!("{} was not renamed: {}", old, e),
+    };
+    reload(store, app).await;
+    if let Some(at) = app.shown.iter().position(|&i| app.keys[i] == new) {
+        app.list.select(Some(at));
+    }
+}
+
+fn draw(frame: &mut Frame, app: &mut App) {
+    let [search, body, footer] = Layout::vertical([Constraint::Length(3), Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+    let [keys, value] = Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(body);
+
+    let searching = matches!(app.mode, Mode::Search);
+    let title = format!(" search ({} of {}) ", app.shown.len(), app.keys.len());
+    let search_block = Block::bordered().title(title);
+    frame.render_widget(Paragraph::new(app.search.as_str()).block(match searching {
+        true => search_block.yellow(),
+        false => search_block,
+    }), search);
+
+    let items: Vec<&str> = app.shown.iter().map(|&i| app.keys[i].as_str()).collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(" keys "))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(list, keys, &mut app.list);
+
+    let (title, text) = match &app.preview {
+        Some(preview) => (format!(" {} ", preview.key), preview_text(preview)),
+        None => (" value ".to_string(), vec![]),
+    };
+    frame.render_widget(Paragraph::new(text).block(Block::bordered().title(title)).wrap(Wrap { trim: false }), value);
+
+    let footer_text = match &app.mode {
+        Mode::Rename(new) => format!("rename to: {}", new),
+        Mode::Delete => format!("delete {}? y to confirm", app.selected().unwrap_or_default()),
+        _ if !app.status.is_empty() => app.status.clone(),
+        Mode::Search => "type to search, enter to keep it, esc to clear it".to_string(),
+        Mode::Browse => "/ search  e edit  r rename  d delete  R reload  q quit".to_string(),
+    };
+    frame.render_widget(Paragraph::new(footer_text).dim(), footer);
+}
+
+/// The metadata of a record above its value, or its size if the value isn't text
+fn preview_text(preview: &Preview) -> Vec<Line<'_>> {
+    let meta = &preview.meta;
+    let mut details = vec![format!("{} bytes", meta.size)];
+    if let Some(ttl) = meta.expires_at.map(|expires_at| expires_at.duration_since(SystemTime::now()).unwrap_or_default()) {
+        details.push(format!("expires in {}s", ttl.as_secs()));
+    }
+    if let Some(tag) = meta.tag {
+        details.push(format!("type {}", tag));
+    }
+    if let Some(content_type) = &meta.content_type {
+        details.push(content_type.clone());
+    }
+    let mut lines = vec![Line::from(details.join(", ")).dim(), Line::default()];
+    match std::str::from_utf8(&preview.value) {
+        Ok(text) => lines.extend(text.lines().map(Line::from)),
+        Err(_) => lines.push(Line::from("binary value, not shown").italic()),
+    }
+    lines
+}
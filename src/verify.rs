@@ -0,0 +1,188 @@
+use sqlx::{sqlite::SqliteConnection, Connection, QueryBuilder, Row};
+use crate::{KVLite, DsError, ChangeKind, Table};
+
+/// What verify did about a corrupted record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// Its value was put back from the latest version in its history matching its checksum
+    Restored(i64),
+    /// It was deleted, having no version matching its checksum to put back
+    Dropped,
+}
+
+/// A record whose value doesn't match the checksum stored when it was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corrupted {
+    pub key: String,
+    /// None unless verify was asked to repair it
+    pub repair: Option<Repair>,
+}
+
+/// What verify found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Problems SQLite's full integrity check finds in the datastore file
+    pub problems: Vec<String>,
+    /// The store's records failing their checksums, in key order. Always empty for a store
+    /// without checksums.
+    pub corrupted: Vec<Corrupted>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty() && self.corrupted.iter().all(|corrupted| corrupted.repair.is_some())
+    }
+}
+
+impl KVLite {
+    fn checksum_table(&self) -> Table {
+        self.kv_name.object("checksums")
+    }
+
+    async fn has_checksums(&self, conn: &mut SqliteConnection) -> Result<bool, DsError> {
+        Ok(self.fetch_optional(conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="trigger" AND name=
+            "#)
+            .push_bind(self.kv_name.object("checksums_insert").name)
+            .build())
+            .await?
+            .is_some())
+    }
+
+    /// Whether a checksum of every value is stored as it's written, for verify
+    pub async fn is_checksummed(&self) -> Result<bool, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        self.has_checksums(&mut conn).await
+    }
+
+    /// Start storing a SHA-256 checksum of every value as it's written, beginning with the records
+    /// already in the store. They're kept by triggers, so writes from every process are checksummed,
+    /// and like an audited store's, only dsr can write to the store. Turning checksums off deletes them.
+    pub async fn set_checksummed(&self, checksummed: bool) -> Result<(), DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let sql = match checksummed {
+            true => format!(r#"
+                    CREATE TABLE IF NOT EXISTS {checksums} (key TEXT PRIMARY KEY COLLATE {collation}, checksum TEXT);
+                    -- made again, so turning checksums on replaces triggers that failed on overwrites
+                    DROP TRIGGER IF EXISTS {insert};
+                    DROP TRIGGER IF EXISTS {update};
+                    CREATE TRIGGER IF NOT EXISTS {insert} AFTER INSERT ON {table} BEGIN
+                        UPDATE {unqualified} SET checksum = dsr_sha256(NEW.value) WHERE key = NEW.key;
+                        INSERT INTO {unqualified} (key, checksum) SELECT NEW.key, dsr_sha256(NEW.value)
+                            WHERE NOT EXISTS (SELECT 1 FROM {unqualified} WHERE key = NEW.key);
+                    END;
+                    CREATE TRIGGER IF NOT EXISTS {update} AFTER UPDATE ON {table} BEGIN
+                        DELETE FROM {unqualified} WHERE key = OLD.key AND OLD.key IS NOT NEW.key;
+                        UPDATE {unqualified} SET checksum = dsr_sha256(NEW.value) WHERE key = NEW.key;
+                        INSERT INTO {unqualified} (key, checksum) SELECT NEW.key, dsr_sha256(NEW.value)
+                            WHERE NOT EXISTS (SELECT 1 FROM {unqualified} WHERE key = NEW.key);
+                    END;
+                    CREATE TRIGGER IF NOT EXISTS {delete} AFTER DELETE ON {table} BEGIN
+                        DELETE FROM {unqualified} WHERE key = OLD.key;
+                    END;
+                    INSERT OR REPLACE INTO {checksums} (key, checksum) SELECT key, dsr_sha256(value) FROM {table};
+                "#,
+                checksums = self.checksum_table(),
+                unqualified = self.checksum_table().unqualified(),
+                collation = self.collation.sql(),
+                table = self.kv_name,
+                insert = self.kv_name.object("checksums_insert"),
+                update = self.kv_name.object("checksums_update"),
+                delete = self.kv_name.object("checksums_delete")),
+            false => format!(r#"
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TRIGGER IF EXISTS {};
+                    DROP TABLE IF EXISTS {};
+                "#, self.kv_name.object("checksums_insert"), self.kv_name.object("checksums_update"), self.kv_name.object("checksums_delete"),
+                    self.checksum_table()),
+        };
+        let mut tx = conn.begin().await?;
+        self.execute(&mut tx, QueryBuilder::new(sql).build()).await?;
+        Ok(tx.commit().await?)
+    }
+
+    /// Run SQLite's full integrity check over the datastore file and check every record of the store
+    /// against its checksum. With repair, each corrupted record is put back from the latest version in
+    /// its history matching its checksum, or deleted if the store isn't versioned or has none.
+    pub async fn verify(&self, repair: bool) -> Result<Verification, DsError> {
+        let mut conn = self.pool.acquire().await?;
+        let problems = self.fetch_all(&mut conn, QueryBuilder::new("PRAGMA integrity_check").build()).await?
+            .iter()
+            .map(|row| row.get::<String, usize>(0))
+            .filter(|problem| problem != "ok")
+            .collect();
+        if !self.has_checksums(&mut conn).await? {
+            return Ok(Verification { problems, corrupted: vec![] });
+        }
+
+        let keys: Vec<String> = self.fetch_all(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT t.key FROM {} t JOIN {} c ON c.key = t.key WHERE c.checksum IS NOT dsr_sha256(t.value) ORDER BY t.key
+            "#, self.kv_name, self.checksum_table()))
+            .build())
+            .await?
+            .iter()
+            .map(|row| row.get("key"))
+            .collect();
+        if !repair {
+            let corrupted = keys.into_iter().map(|key| Corrupted { key, repair: None }).collect();
+            return Ok(Verification { problems, corrupted });
+        }
+
+        let history = self.kv_name.object("history");
+        let versioned = self.fetch_optional(&mut conn, QueryBuilder::new(r#"
+                SELECT name FROM sqlite_master WHERE type="table" AND name=
+            "#)
+            .push_bind(history.name.as_str())
+            .build())
+            .await?
+            .is_some();
+        let mut corrupted = vec![];
+        for key in keys {
+            let mut tx = conn.begin().await?;
+            let version: Option<i64> = match versioned {
+                true => self.fetch_optional(&mut tx, QueryBuilder::new(format!(r#"
+                        SELECT h.version FROM {} h JOIN {} c ON c.key = h.key
+                        WHERE h.key =
+                    "#, history, self.checksum_table()))
+                    .push_bind(key.as_str())
+                    .push(" AND NOT h.deleted AND h.value IS NOT NULL AND dsr_sha256(h.value) = c.checksum ORDER BY h.version DESC LIMIT 1")
+                    .build())
+                    .await?
+                    .map(|row| row.get("version")),
+                false => None,
+            };
+            let repair = match version {
+                Some(version) => {
+                    self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                            UPDATE {} SET value = (SELECT value FROM {} WHERE key =
+                        "#, self.kv_name, history))
+                        .push_bind(key.as_str())
+                        .push(" AND version = ")
+                        .push_bind(version)
+                        .push(") WHERE key = ")
+                        .push_bind(key.as_str())
+                        .build())
+                        .await?;
+                    Repair::Restored(version)
+                },
+                None => {
+                    self.execute(&mut tx, QueryBuilder::new(format!(r#"
+                            DELETE FROM {} WHERE key =
+                        "#, self.kv_name))
+                        .push_bind(key.as_str())
+                        .build())
+                        .await?;
+                    Repair::Dropped
+                },
+            };
+            tx.commit().await?;
+            match repair {
+                Repair::Restored(_) => self.notify(ChangeKind::Set, &key, None),
+                Repair::Dropped => self.notify(ChangeKind::Delete, &key, None),
+            }
+            corrupted.push(Corrupted { key, repair: Some(repair) });
+        }
+        Ok(Verification { problems, corrupted })
+    }
+}
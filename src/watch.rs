@@ -0,0 +1,146 @@
+use std::{collections::HashMap, fmt, str::FromStr, time::Duration};
+use sqlx::{QueryBuilder, Row};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, wrappers::ReceiverStream};
+use crate::{KVLite, DsError, ChangeEvent, ChangeKind, ValueTag, CHANGE_CAPACITY, glob_match};
+
+/// Which changes a watch streams, parsed once from an expression of space-separated terms:
+/// `prefix:<prefix>`, `glob:<pattern>` (or a bare pattern), `kind:set|delete|expired`,
+/// `tag:<tag>` and `changed`. Every term must match, except kind: and tag: terms, of which any
+/// one must. An empty expression matches every change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeFilter {
+    pub prefixes: Vec<String>,
+    /// Glob patterns using * and ?, as glob_match takes
+    pub globs: Vec<String>,
+    pub kinds: Vec<ChangeKind>,
+    /// Tags of the record set. Deletes and expiries have no record, so these only pass sets.
+    pub tags: Vec<ValueTag>,
+    /// Drop sets that give a key the value it had at its last change in the stream
+    pub changed_only: bool,
+}
+
+impl ChangeFilter {
+    /// The changes to keys matching a glob pattern
+    pub fn glob(pattern: &str) -> ChangeFilter {
+        ChangeFilter { globs: vec![pattern.to_string()], ..Default::default() }
+    }
+
+    /// Whether a change to a record with this tag passes, before changed_only is applied
+    pub fn matches(&self, event: &ChangeEvent, tag: Option<ValueTag>) -> bool {
+        self.prefixes.iter().all(|prefix| event.key.starts_with(prefix.as_str()))
+            && self.globs.iter().all(|pattern| glob_match(pattern, &event.key))
+            && (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && (self.tags.is_empty() || tag.is_some_and(|tag| self.tags.contains(&tag)))
+    }
+}
+
+impl fmt::Display for ChangeFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let terms = self.prefixes.iter().map(|prefix| format!("prefix:{}", prefix))
+            .chain(self.globs.iter().map(|pattern| format!("glob:{}", pattern)))
+            .chain(self.kinds.iter().map(|kind| format!("kind:{}", kind)))
+            .chain(self.tags.iter().map(|tag| format!("tag:{}", tag)))
+            .chain(self.changed_only.then(|| "changed".to_string()));
+        write!(f, "{}", terms.collect::<Vec<_>>().join(" "))
+    }
+}
+
+impl FromStr for ChangeFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = ChangeFilter::default();
+        for term in s.split_whitespace() {
+            if term == "changed" {
+                filter.changed_only = true;
+            } else if let Some(prefix) = term.strip_prefix("prefix:") {
+                filter.prefixes.push(prefix.to_string());
+            } else if let Some(kind) = term.strip_prefix("kind:") {
+                filter.kinds.push(kind.parse()?);
+            } else if let Some(tag) = term.strip_prefix("tag:") {
+                filter.tags.push(tag.parse()?);
+            } else {
+                filter.globs.push(term.strip_prefix("glob:").unwrap_or(term).to_string());
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl KVLite {
+    /// Stream the changes passing a filter made by any process, by polling the store's change log.
+    /// Only changes made after the call are streamed, and sets carry the record's value and are
+    /// matched against its tag when it's read.
+    pub async fn watch_filtered(&self, filter: &ChangeFilter, interval: Duration) -> Result<impl Stream<Item = Result<ChangeEvent, DsError>> + 'static, DsError> {
+        let log = self.kv_name.object("changes");
+        let mut conn = self.pool.acquire().await?;
+        let mut seq: i64 = self.fetch_one(&mut conn, QueryBuilder::new(format!(r#"
+                SELECT coalesce(max(seq), 0) AS seq FROM {}
+            "#, log))
+            .build())
+            .await?
+            .get("seq");
+        drop(conn);
+
+        let (events, received) = mpsc::channel(CHANGE_CAPACITY);
+        let pool = self.pool.clone();
+        let sql = format!(r#"
+                SELECT c.seq, c.key, c.kind, s.value, s.tag FROM {} AS c LEFT JOIN {} AS s ON s.key = c.key AND c.kind = 'set'
+                WHERE c.seq > ? ORDER BY c.seq
+            "#, log, self.readable_records());
+        let filter = filter.clone();
+        let decode = self.value_decoder();
+        tokio::spawn(async move {
+            // the value each key had at its last change, for changed_only
+            let mut last: HashMap<String, Option<String>> = HashMap::new();
+            // stops once the stream is dropped and a send fails
+            loop {
+                let rows = match sqlx::query(&sql).bind(seq).fetch_all(&pool).await {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        let _ = events.send(Err(e.into())).await;
+                        return;
+                    },
+                };
+                for row in rows {
+                    seq = row.get("seq");
+                    let value = match row.get::<Option<Vec<u8>>, &str>("value").map(&decode).transpose() {
+                        Ok(value) => value,
+                        Err(e) => {
+                            let _ = events.send(Err(e)).await;
+                            return;
+                        },
+                    };
+                    let event = ChangeEvent {
+                        kind: row.get::<String, &str>("kind").parse().unwrap_or(ChangeKind::Set),
+                        key: row.get("key"),
+                        value: value.map(|value| String::from_utf8_lossy(&value).into_owned()),
+                    };
+                    let tag = row.get::<Option<&str>, &str>("tag").and_then(|tag| tag.parse().ok());
+                    // every change is remembered, so a set after a delete the filter drops isn't taken as unchanged
+                    let unchanged = filter.changed_only && {
+                        let value = match event.kind {
+                            ChangeKind::Set => event.value.clone(),
+                            _ => None,
+                        };
+                        let unchanged = value.is_some() && last.get(&event.key) == Some(&value);
+                        last.insert(event.key.clone(), value);
+                        unchanged
+                    };
+                    if unchanged || !filter.matches(&event, tag) {
+                        continue;
+                    }
+                    if events.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+                if events.is_closed() {
+                    return;
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        Ok(ReceiverStream::new(received))
+    }
+}
@@ -0,0 +1,756 @@
+//! The dsr command line, run as a user would, for what's decided in main.rs rather than the library
+
+use std::{fs, io::{BufRead, BufReader, Write}, path::PathBuf, process::{Command, Output, Stdio}, sync::mpsc, time::Duration};
+
+/// A datastore file for a test, removed along with what's kept beside it from an earlier run
+fn datastore(name: &str) -> String {
+    let path = format!("tmp_cli_{}.db", name);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", path, suffix));
+    }
+    let _ = fs::remove_dir_all(format!("{}.intents", path));
+    path
+}
+
+/// dsr with environment variables, away from the user's config and datastore
+fn command(args: &[&str], env: &[(&str, &str)]) -> Command {
+    let home = std::env::temp_dir().join("dsr-cli-home");
+    let mut command = Command::new(env!("CARGO_BIN_EXE_dsr"));
+    command.args(args)
+        .env("HOME", &home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_DATA_HOME", home.join(".local/share"))
+        .env_remove("DS_RUST_DB")
+        .env_remove("DS_RUST_STORE")
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+/// Run dsr with stdin and environment variables
+fn run(args: &[&str], stdin: &[u8], env: &[(&str, &str)]) -> Output {
+    let mut child = command(args, env).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Run dsr on a datastore
+fn dsr(ds: &str, args: &[&str]) -> Output {
+    run(&[&["--ds", ds], args].concat(), b"", &[])
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn test_recover() {
+    let ds = datastore("recover");
+    assert!(dsr(&ds, &["set", "app.name", "ds"]).status.success());
+    // a command that finishes leaves nothing journaled
+    assert!(dsr(&ds, &["rename-prefix", "app.", "web."]).status.success());
+    assert!(!PathBuf::from(format!("{}.intents", ds)).exists());
+    assert_eq!(stdout(&dsr(&ds, &["recover"])), "");
+
+    // runs that were interrupted, as they're left journaled
+    let intents = PathBuf::from(format!("{}.intents", ds));
+    fs::create_dir_all(&intents).unwrap();
+    let intent = |args: &[&str], started_at: u64, resumable: bool| serde_json::json!({
+        "args": args,
+        "dir": std::env::current_dir().unwrap(),
+        "started_at": started_at,
+        "resumable": resumable,
+    }).to_string();
+    fs::write(intents.join("1.json"), intent(&["--ds", &ds, "rename-prefix", "web.", "api."], 1, true)).unwrap();
+    fs::write(intents.join("2.json"), intent(&["--ds", &ds, "import", "-"], 2, false)).unwrap();
+    // one still being written isn't read
+    fs::write(intents.join("3.partial"), "{").unwrap();
+
+    let listed = dsr(&ds, &["--output", "json", "recover"]);
+    let listed: serde_json::Value = serde_json::from_slice(&listed.stdout).unwrap();
+    assert_eq!(listed.as_array().unwrap().iter().map(|intent| intent["resumable"].as_bool().unwrap()).collect::<Vec<_>>(), vec![true, false]);
+    assert_eq!(stdout(&dsr(&ds, &["recover"])).lines().count(), 2);
+
+    // the resumable one is run again, and the one that read stdin is kept for rollback
+    let resumed = dsr(&ds, &["recover", "--resume"]);
+    assert!(resumed.status.success());
+    assert!(stdout(&resumed).starts_with(&format!("resuming: dsr --ds {} rename-prefix web. api.", ds)));
+    assert!(stderr(&resumed).contains("can't resume"));
+    assert_eq!(stdout(&dsr(&ds, &["get", "api.name"])), "ds\n");
+    assert_eq!(stdout(&dsr(&ds, &["recover"])).lines().count(), 1);
+
+    let dropped = dsr(&ds, &["recover", "--rollback"]);
+    assert_eq!(stdout(&dropped), format!("dropped: dsr --ds {} import -\n", ds));
+    assert_eq!(stdout(&dsr(&ds, &["recover"])), "");
+    fs::remove_file(intents.join("3.partial")).unwrap();
+    let _ = fs::remove_dir(&intents);
+}
+
+#[test]
+fn test_profiles() {
+    let work = datastore("profiles_work");
+    let home = datastore("profiles_default");
+    let other = datastore("profiles_other");
+    let dir = std::env::current_dir().unwrap();
+    let config_home = std::env::temp_dir().join(format!("dsr-cli-profiles-{}", std::process::id()));
+    fs::create_dir_all(config_home.join("ds-rust")).unwrap();
+    fs::write(config_home.join("ds-rust/config.toml"), format!(r#"
+        [profiles.default]
+        ds = "{}"
+
+        [profiles.work]
+        ds = "{}"
+        store = "tasks"
+        output = "json"
+    "#, dir.join(&home).display(), dir.join(&work).display())).unwrap();
+    let config = config_home.to_str().unwrap();
+    let dsr = |args: &[&str], env: &[(&str, &str)]| run(args, b"", &[&[("XDG_CONFIG_HOME", config)], env].concat());
+
+    // the default profile is used without --profile, and --profile picks another
+    assert!(dsr(&["set", "name", "home"], &[]).status.success());
+    assert!(dsr(&["--profile", "work", "set", "name", "work"], &[]).status.success());
+    assert_eq!(stdout(&dsr(&["--ds", &home, "get", "name"], &[])), "home\n");
+    assert_eq!(stdout(&dsr(&["--ds", &work, "--store", "tasks", "get", "name"], &[])), "work\n");
+    assert_eq!(stdout(&dsr(&["--profile", "work", "get", "name"], &[])), "\"work\"\n");
+
+    // flags and environment variables win over the profile
+    assert_eq!(stdout(&dsr(&["--profile", "work", "--output", "text", "get", "name"], &[])), "work\n");
+    assert!(dsr(&["--profile", "work", "set", "name", "other"], &[("DS_RUST_DB", &other), ("DS_RUST_STORE", "store")]).status.success());
+    assert_eq!(stdout(&dsr(&["--ds", &other, "get", "name"], &[])), "other\n");
+    assert_eq!(stdout(&dsr(&["get", "name"], &[("DS_RUST_DB", &other)])), "other\n");
+    assert_eq!(stdout(&dsr(&["--profile", "work", "get", "name"], &[])), "\"work\"\n");
+
+    let missing = dsr(&["--profile", "play", "get", "name"], &[]);
+    assert_eq!(missing.status.code(), Some(2));
+    assert!(stderr(&missing).contains("there's no profile play"));
+    fs::write(config_home.join("ds-rust/config.toml"), "[profiles.work]\npath = \"work.db\"\n").unwrap();
+    assert!(stderr(&dsr(&["--profile", "work", "get", "name"], &[])).contains("unknown field `path`"));
+    fs::remove_dir_all(&config_home).unwrap();
+}
+
+#[tokio::test]
+async fn test_exit_status() -> Result<(), sqlx::Error> {
+    use sqlx::Connection;
+
+    let ds = datastore("exit_status");
+    assert!(dsr(&ds, &["set", "name", "ds"]).status.success());
+
+    let found = dsr(&ds, &["contains", "name"]);
+    assert_eq!((found.status.code(), stdout(&found)), (Some(0), "true\n".to_string()));
+    let missing = dsr(&ds, &["contains", "other"]);
+    assert_eq!((missing.status.code(), stdout(&missing)), (Some(1), "false\n".to_string()));
+    let quiet = dsr(&ds, &["contains", "--quiet", "other"]);
+    assert_eq!((quiet.status.code(), stdout(&quiet)), (Some(1), String::new()));
+    assert_eq!(dsr(&ds, &["contains", "--quiet", "name"]).status.code(), Some(0));
+
+    // a missing record is said on stderr, not stdout, unless quiet
+    let missing = dsr(&ds, &["get", "other"]);
+    assert_eq!((missing.status.code(), stdout(&missing), stderr(&missing)), (Some(1), String::new(), "other not found\n".to_string()));
+    let quiet = dsr(&ds, &["get", "--quiet", "other"]);
+    assert_eq!((quiet.status.code(), stdout(&quiet), stderr(&quiet)), (Some(1), String::new(), String::new()));
+
+    let failed = dsr(&ds, &["--store", "sqlite_master", "keys"]);
+    assert_eq!((failed.status.code(), stdout(&failed)), (Some(2), String::new()));
+    assert!(stderr(&failed).starts_with("error: "));
+
+    // a datastore another connection is writing to gives 3, for scripts to retry
+    let mut conn = sqlx::SqliteConnection::connect(&format!("sqlite://{}", ds)).await?;
+    sqlx::query("BEGIN EXCLUSIVE").execute(&mut conn).await?;
+    let locked = dsr(&ds, &["--busy-timeout", "100", "set", "name", "dsr"]);
+    assert_eq!(locked.status.code(), Some(3));
+    assert!(stderr(&locked).contains("locked"));
+    sqlx::query("ROLLBACK").execute(&mut conn).await?;
+    assert_eq!(stdout(&dsr(&ds, &["get", "name"])), "ds\n");
+    Ok(())
+}
+
+#[test]
+fn test_load() {
+    let ds = datastore("load");
+    let csv: String = (0..1500).map(|i| format!("user{},{}\n", i, i)).collect();
+    let loaded = run(&["--ds", &ds, "load", "--csv", "-"], csv.as_bytes(), &[]);
+    assert!(loaded.status.success());
+    assert!(stdout(&loaded).starts_with("loaded 1500 records in "));
+    assert!(stdout(&loaded).ends_with(" rows/s\n"));
+    assert_eq!(stdout(&dsr(&ds, &["count"])), "1500\n");
+
+    // JSON as export writes it, reporting the rate as JSON
+    let other = datastore("load_json");
+    assert!(dsr(&other, &["set", "kept", "1"]).status.success());
+    assert!(dsr(&other, &["set", "user1", "one"]).status.success());
+    let file = format!("{}.json", other);
+    assert!(dsr(&other, &["export", "--file", &file]).status.success());
+    let loaded = dsr(&ds, &["--output", "json", "load", "--json", &file, "--replace"]);
+    let report: serde_json::Value = serde_json::from_slice(&loaded.stdout).unwrap();
+    assert_eq!(report["records"], 2);
+    assert!(report["rows_per_sec"].as_f64().unwrap() > 0.0);
+    assert_eq!(stdout(&dsr(&ds, &["keys"])), "kept\nuser1\n");
+    assert_eq!(stdout(&dsr(&ds, &["get", "user1"])), "one\n");
+    fs::remove_file(&file).unwrap();
+
+    // a file that isn't CSV loads nothing
+    let failed = run(&["--ds", &ds, "load", "--csv", "-"], b"no comma\n", &[]);
+    assert_eq!(failed.status.code(), Some(2));
+    assert_eq!(stdout(&dsr(&ds, &["count"])), "2\n");
+}
+
+#[test]
+fn test_expire_daemon() {
+    let ds = datastore("expire_daemon");
+    assert!(dsr(&ds, &["set", "session", "abc", "--ttl", "1"]).status.success());
+    assert!(dsr(&ds, &["set", "name", "ds"]).status.success());
+
+    let mut daemon = command(&["--ds", &ds, "expire-daemon", "--interval", "1"], &[]).spawn().unwrap();
+    let (lines, received) = mpsc::channel();
+    let out = BufReader::new(daemon.stdout.take().unwrap());
+    std::thread::spawn(move || for line in out.lines() {
+        let _ = lines.send(line.unwrap());
+    });
+    assert_eq!(received.recv_timeout(Duration::from_secs(10)).unwrap(), "expired session");
+    assert_eq!(stdout(&dsr(&ds, &["keys"])), "name\n");
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+
+    // --exec runs a command for each expired record instead
+    let log = format!("{}.log", ds);
+    let _ = fs::remove_file(&log);
+    assert!(dsr(&ds, &["set", "token", "xyz", "--ttl", "1"]).status.success());
+    let mut exec = command(&["--ds", &ds, "expire-daemon", "--interval", "1", "--exec", &format!("echo $DSR_KEY >> {}", log)], &[]).spawn().unwrap();
+    let start = std::time::Instant::now();
+    while fs::read_to_string(&log).unwrap_or_default() != "token\n" {
+        assert!(start.elapsed() < Duration::from_secs(10), "the command wasn't run for the expired record");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    exec.kill().unwrap();
+    exec.wait().unwrap();
+    fs::remove_file(&log).unwrap();
+}
+
+#[test]
+fn test_sampling() {
+    let ds = datastore("sampling");
+    for i in 0..20 {
+        assert!(dsr(&ds, &["set", &format!("job{:02}", i), &i.to_string()]).status.success());
+    }
+    assert!(dsr(&ds, &["set", "other", "x"]).status.success());
+
+    let key = stdout(&dsr(&ds, &["randomkey", "--prefix", "job"]));
+    assert!(key.starts_with("job") && key.ends_with('\n'));
+    assert_eq!(stdout(&dsr(&ds, &["randomkey", "--prefix", "oth"])), "other\n");
+    let none = dsr(&ds, &["randomkey", "--prefix", "none"]);
+    assert_eq!((none.status.code(), stdout(&none)), (Some(1), String::new()));
+
+    let sample = stdout(&dsr(&ds, &["sample", "5", "--prefix", "job"]));
+    let mut sampled: Vec<_> = sample.lines().collect();
+    assert_eq!(sampled.len(), 5);
+    assert!(sampled.iter().all(|line| line.split_once(',').is_some_and(|(key, value)| *key == format!("job{:02}", value.parse::<u32>().unwrap()))));
+    sampled.sort();
+    sampled.dedup();
+    assert_eq!(sampled.len(), 5);
+    let keys: serde_json::Value = serde_json::from_slice(&dsr(&ds, &["--output", "json", "sample", "50", "--keys"]).stdout).unwrap();
+    assert_eq!(keys.as_array().unwrap().len(), 21);
+
+    // every key turns up in a random order
+    let mut shuffled: Vec<_> = stdout(&dsr(&ds, &["keys", "--sort", "random"])).lines().map(str::to_string).collect();
+    shuffled.sort();
+    assert_eq!(shuffled, stdout(&dsr(&ds, &["keys"])).lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_env() {
+    let ds = datastore("env");
+    let tricky = "it's \"quoted\" $HOME `date`\nand multi-line";
+    assert!(dsr(&ds, &["set", "app.db-host", "db.local"]).status.success());
+    assert!(dsr(&ds, &["set", "app.motd", tricky]).status.success());
+    assert!(dsr(&ds, &["set", "other", "x"]).status.success());
+
+    let exported = stdout(&dsr(&ds, &["env", "export", "--prefix", "app."]));
+    assert!(exported.starts_with("export APP_DB_HOST=db.local\nexport APP_MOTD="));
+    // what the shell makes of it is the value as it was
+    let script = "eval \"$1\"; printf '%s|%s' \"$APP_DB_HOST\" \"$APP_MOTD\"";
+    let evaluated = Command::new("sh").arg("-c").arg(script).arg("sh").arg(&exported).output().unwrap();
+    assert_eq!(String::from_utf8(evaluated.stdout).unwrap(), format!("db.local|{}", tricky));
+
+    let stripped: serde_json::Value = serde_json::from_slice(&dsr(&ds, &["--output", "json", "env", "export", "--prefix", "app.", "--strip-prefix"]).stdout).unwrap();
+    assert_eq!(stripped, serde_json::json!({ "DB_HOST": "db.local", "MOTD": tricky }));
+    assert!(dsr(&ds, &["set", "app.db_host", "other"]).status.success());
+    let clash = dsr(&ds, &["env", "export", "--prefix", "app."]);
+    assert_eq!(clash.status.code(), Some(2));
+    assert!(stderr(&clash).contains("would both be exported as APP_DB_HOST"));
+
+    let file = format!("{}.env", ds);
+    fs::write(&file, "# settings\n\nexport PORT=8080\nNAME='my app'\n").unwrap();
+    assert!(dsr(&ds, &["env", "import", &file, "--prefix", "web."]).status.success());
+    let mut imported: Vec<String> = stdout(&dsr(&ds, &["keys", "--prefix", "web."])).lines().map(String::from).collect();
+    imported.sort();
+    assert_eq!(imported, vec!["web.NAME", "web.PORT"]);
+    assert_eq!(stdout(&dsr(&ds, &["get", "web.NAME"])), "my app\n");
+    fs::write(&file, "not a variable\n").unwrap();
+    assert!(stderr(&dsr(&ds, &["env", "import", &file])).contains("isn't a dotenv file"));
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn test_explain() {
+    let ds = datastore("explain");
+    assert!(dsr(&ds, &["set", "a", "1"]).status.success());
+
+    // writes are printed rather than run
+    let set = dsr(&ds, &["--explain", "set", "b", "2"]);
+    assert!(set.status.success());
+    assert!(stderr(&set).lines().any(|line| line.starts_with(r#"INSERT INTO "store" (key, value"#)));
+    let delete = dsr(&ds, &["--explain", "delete", "a"]);
+    assert!(stderr(&delete).lines().any(|line| line.starts_with(r#"DELETE FROM "store""#)));
+    assert_eq!(stdout(&dsr(&ds, &["keys"])), "a\n");
+
+    // reads are run, each statement on one line and its plan indented under it
+    let get = dsr(&ds, &["--explain", "--query-plan", "get", "a"]);
+    assert_eq!(stdout(&get), "1\n");
+    let printed = stderr(&get);
+    let lines: Vec<&str> = printed.lines().collect();
+    let select = lines.iter().position(|line| line.starts_with("SELECT value, key") && line.contains(r#"FROM "store" WHERE key= ?"#)).unwrap();
+    assert!(lines[select + 1].starts_with("  SEARCH store USING INDEX"));
+    assert!(!stderr(&dsr(&ds, &["--explain", "get", "a"])).contains("SEARCH"));
+
+    assert_eq!(dsr(&ds, &["--query-plan", "get", "a"]).status.code(), Some(2));
+}
+
+#[test]
+fn test_output_json() {
+    let ds = datastore("output_json");
+    let json = |args: &[&str]| {
+        let out = dsr(&ds, &[&["--output", "json"], args].concat());
+        (out.status.code(), serde_json::from_slice::<serde_json::Value>(&out.stdout).ok())
+    };
+    assert!(dsr(&ds, &["set", "a", "1"]).status.success());
+    assert!(dsr(&ds, &["set", "b", "two \"quoted\"\nlines", "--ttl", "100"]).status.success());
+
+    assert_eq!(json(&["get", "b"]), (Some(0), Some(serde_json::json!("two \"quoted\"\nlines"))));
+    assert_eq!(json(&["get", "missing"]), (Some(1), None));
+    assert_eq!(json(&["contains", "a"]), (Some(0), Some(serde_json::json!(true))));
+    assert_eq!(json(&["contains", "missing"]), (Some(1), Some(serde_json::json!(false))));
+    let (_, ttl) = json(&["ttl", "b"]);
+    assert!((90..=100).contains(&ttl.unwrap().as_u64().unwrap()));
+    assert_eq!(json(&["ttl", "a"]), (Some(0), Some(serde_json::Value::Null)));
+    assert_eq!(json(&["keys", "--sort"]), (Some(0), Some(serde_json::json!(["a", "b"]))));
+    assert_eq!(json(&["records", "--sort"]).1, Some(serde_json::json!([{ "key": "a", "value": "1" }, { "key": "b", "value": "two \"quoted\"\nlines" }])));
+}
+
+#[test]
+fn test_set_stdin() {
+    let ds = datastore("set_stdin");
+    let value = |key: &str| serde_json::from_slice::<String>(&dsr(&ds, &["--output", "json", "get", key]).stdout).unwrap();
+
+    // the value is read as it is, trailing newlines and all
+    assert!(run(&["--ds", &ds, "set", "dash", "-"], b"a\nb\n", &[]).status.success());
+    assert_eq!(value("dash"), "a\nb\n");
+    assert!(run(&["--ds", &ds, "set", "flag", "--stdin", "--ttl", "60"], b"x\n\n", &[]).status.success());
+    assert_eq!(value("flag"), "x\n\n");
+    assert!(stdout(&dsr(&ds, &["ttl", "flag"])).trim().parse::<u64>().unwrap() <= 60);
+    assert!(run(&["--ds", &ds, "set", "empty", "-"], b"", &[]).status.success());
+    assert_eq!(value("empty"), "");
+
+    assert_eq!(run(&["--ds", &ds, "set", "both", "value", "--stdin"], b"x", &[]).status.code(), Some(2));
+    assert_eq!(dsr(&ds, &["set", "neither"]).status.code(), Some(2));
+    assert_eq!(stdout(&dsr(&ds, &["keys", "--sort"])), "dash\nempty\nflag\n");
+}
+
+#[test]
+fn test_content_type() {
+    let ds = datastore("content_type");
+    assert!(dsr(&ds, &["set", "doc", r#"{"a":[1,2]}"#, "--content-type", "application/json"]).status.success());
+    assert!(dsr(&ds, &["set", "bytes", "hi", "--content-type", "application/octet-stream"]).status.success());
+    assert!(dsr(&ds, &["set", "untyped", r#"{"a":1}"#]).status.success());
+
+    // the type decides how get prints the value when stdout isn't a terminal
+    assert_eq!(stdout(&dsr(&ds, &["get", "doc"])), "{\n  \"a\": [\n    1,\n    2\n  ]\n}\n");
+    assert_eq!(stdout(&dsr(&ds, &["get", "bytes"])), "aGk=\n");
+    assert_eq!(stdout(&dsr(&ds, &["get", "untyped"])), "{\"a\":1}\n");
+
+    assert!(stdout(&dsr(&ds, &["stat", "doc"])).lines().any(|line| line == "content-type: application/json"));
+    assert!(stdout(&dsr(&ds, &["stat", "untyped"])).lines().any(|line| line == "content-type: none"));
+    let stat: serde_json::Value = serde_json::from_slice(&dsr(&ds, &["--output", "json", "stat", "bytes"]).stdout).unwrap();
+    assert_eq!(stat["content_type"], "application/octet-stream");
+}
+
+#[test]
+fn test_get_raw() {
+    let ds = datastore("get_raw");
+    assert!(dsr(&ds, &["set", "doc", r#"{"a":1}"#, "--content-type", "application/json"]).status.success());
+    assert!(run(&["--ds", &ds, "set", "bytes", "-", "--content-type", "application/octet-stream"], &[0, 255, b'\n'], &[]).status.success());
+
+    // --raw writes the bytes as they were set, without pretty-printing, encoding or a newline
+    assert_eq!(dsr(&ds, &["get", "doc", "--raw"]).stdout, br#"{"a":1}"#);
+    assert_eq!(dsr(&ds, &["get", "bytes", "--raw"]).stdout, [0, 255, b'\n']);
+    assert_eq!(stdout(&dsr(&ds, &["get", "bytes"])), "AP8K\n");
+    assert_eq!(dsr(&ds, &["get", "missing", "--raw"]).status.code(), Some(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_set_edit() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let ds = datastore("set_edit");
+    // an editor that fails unless it's given an empty buffer, and fills it with $EDITED
+    let editor = format!("{}.editor", ds);
+    fs::write(&editor, "#!/bin/sh\n[ -s \"$1\" ] && exit 1\nprintf '%b' \"$EDITED\" > \"$1\"\n").unwrap();
+    fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+    let editor = fs::canonicalize(&editor).unwrap();
+    let edit = |key: &str, edited: &str| run(&["--ds", &ds, "set", key, "--edit"], b"", &[("VISUAL", editor.to_str().unwrap()), ("EDITED", edited)]);
+
+    // the newline editors end files with is dropped, the value's own are kept
+    assert!(edit("multi", "first\\n\\nthird\\n").status.success());
+    assert_eq!(serde_json::from_slice::<String>(&dsr(&ds, &["--output", "json", "get", "multi"]).stdout).unwrap(), "first\n\nthird");
+    assert!(edit("crlf", "one\\r\\n").status.success());
+    assert_eq!(stdout(&dsr(&ds, &["get", "crlf"])), "one\n");
+
+    let empty = edit("empty", "\\n");
+    assert!(empty.status.success());
+    assert_eq!(stdout(&empty), "empty was not set, the value was empty\n");
+    assert_eq!(dsr(&ds, &["get", "empty"]).status.code(), Some(1));
+
+    let failed = run(&["--ds", &ds, "set", "failed", "--edit"], b"", &[("VISUAL", "false")]);
+    assert_eq!(failed.status.code(), Some(2));
+    assert!(!stdout(&dsr(&ds, &["keys"])).contains("failed"));
+    assert_eq!(dsr(&ds, &["set", "both", "value", "--edit"]).status.code(), Some(2));
+    fs::remove_file(editor).unwrap();
+}
+
+#[test]
+fn test_set_value_from() {
+    let ds = datastore("set_value_from");
+    let value = |key: &str| serde_json::from_slice::<String>(&dsr(&ds, &["--output", "json", "get", key]).stdout).unwrap();
+
+    assert!(run(&["--ds", &ds, "set", "piped", "--value-from", "stdin"], b"from stdin\n", &[]).status.success());
+    assert_eq!(value("piped"), "from stdin\n");
+    let file = format!("{}.value", ds);
+    fs::write(&file, "from a file").unwrap();
+    assert!(dsr(&ds, &["set", "read", "--value-from", &format!("file:{}", file)]).status.success());
+    assert_eq!(value("read"), "from a file");
+    fs::remove_file(&file).unwrap();
+    assert!(run(&["--ds", &ds, "set", "token", "--value-from", "env:DSR_TEST_TOKEN", "--secret"], b"", &[("DSR_TEST_TOKEN", "s3cret")]).status.success());
+    assert_eq!(value("token"), "s3cret");
+
+    let unset = dsr(&ds, &["set", "unset", "--value-from", "env:DSR_TEST_UNSET"]);
+    assert_eq!(unset.status.code(), Some(2));
+    assert!(stderr(&unset).contains("DSR_TEST_UNSET"));
+    assert!(stderr(&dsr(&ds, &["set", "bad", "--value-from", "env:"])).contains("unknown value source env:"));
+    assert_eq!(dsr(&ds, &["set", "both", "value", "--value-from", "stdin"]).status.code(), Some(2));
+
+    // a secret given as an argument is set, with a warning
+    let argued = dsr(&ds, &["set", "argued", "s3cret", "--secret"]);
+    assert!(argued.status.success());
+    assert!(stderr(&argued).starts_with("warning: the secret value of argued was given on the command line"));
+    assert!(stderr(&dsr(&ds, &["set", "plain", "value"])).is_empty());
+    assert_eq!(stdout(&dsr(&ds, &["keys", "--sort"])), "argued\npiped\nplain\nread\ntoken\n");
+}
+
+#[test]
+fn test_msgpack() {
+    let ds = datastore("msgpack");
+    assert!(dsr(&ds, &["set", "a", "1"]).status.success());
+    assert!(dsr(&ds, &["set", "b", "two\nlines"]).status.success());
+    let exported = dsr(&ds, &["export", "--format", "msgpack"]);
+    if !cfg!(feature = "msgpack") {
+        assert_eq!(exported.status.code(), Some(2));
+        assert!(stderr(&exported).contains("unknown or disabled format msgpack"));
+        return;
+    }
+
+    let file = format!("{}.msgpack", ds);
+    fs::write(&file, &exported.stdout).unwrap();
+    assert!(dsr(&ds, &["--store", "copy", "import", "--format", "msgpack", &file]).status.success());
+    assert_eq!(stdout(&dsr(&ds, &["--store", "copy", "--output", "json", "records", "--sort"])), stdout(&dsr(&ds, &["--output", "json", "records", "--sort"])));
+
+    // a cut off file imports nothing, not even the records it has whole
+    fs::write(&file, &exported.stdout[..exported.stdout.len() - 1]).unwrap();
+    let truncated = dsr(&ds, &["--store", "truncated", "import", "--format", "msgpack", &file]);
+    assert_eq!(truncated.status.code(), Some(2));
+    assert!(stderr(&truncated).contains("unexpected end of file"));
+    assert_eq!(stdout(&dsr(&ds, &["--store", "truncated", "keys"])), "");
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn test_count_and_stats() {
+    let ds = datastore("count_and_stats");
+    for (key, value) in [("app.a", "1"), ("app.b", "22"), ("other", "xyz")] {
+        assert!(dsr(&ds, &["set", key, value]).status.success());
+    }
+    assert!(dsr(&ds, &["--store", "second", "set", "k", "v"]).status.success());
+    assert!(dsr(&ds, &["set", "gone", "x", "--ttl", "0"]).status.success());
+
+    assert_eq!(stdout(&dsr(&ds, &["count"])), "3\n");
+    assert_eq!(stdout(&dsr(&ds, &["count", "--prefix", "app."])), "2\n");
+    assert_eq!(stdout(&dsr(&ds, &["--store", "second", "--output", "json", "count"])), "1\n");
+
+    let stats = stdout(&dsr(&ds, &["stats"]));
+    let lines: Vec<&str> = stats.lines().collect();
+    assert!(lines[0].starts_with("file size: "));
+    assert_eq!(lines[1..], ["records: 4", "key bytes: 16", "value bytes: 7", "second: 1 records, 1 key bytes, 1 value bytes", "store: 3 records, 15 key bytes, 6 value bytes"]);
+    let stats: serde_json::Value = serde_json::from_slice(&dsr(&ds, &["--output", "json", "stats"]).stdout).unwrap();
+    assert_eq!(stats["file_size"].as_u64(), Some(fs::metadata(&ds).unwrap().len()));
+    assert_eq!((stats["records"].as_u64(), stats["stores"][1]["store"].as_str(), stats["stores"][1]["value_bytes"].as_u64()), (Some(4), Some("store"), Some(6)));
+}
+
+#[test]
+fn test_parquet() {
+    let ds = datastore("parquet");
+    assert!(dsr(&ds, &["set", "a", "1"]).status.success());
+    assert!(dsr(&ds, &["set", "b", "two"]).status.success());
+    let file = format!("{}.parquet", ds);
+    let exported = dsr(&ds, &["export", "--format", "parquet", "--file", &file]);
+    #[cfg(not(feature = "parquet"))]
+    {
+        assert_eq!(exported.status.code(), Some(2));
+        assert!(stderr(&exported).contains("unknown or disabled format parquet"));
+    }
+    #[cfg(feature = "parquet")]
+    {
+        use parquet::{file::reader::{FileReader, SerializedFileReader}, record::Field};
+
+        assert!(exported.status.success());
+        let reader = SerializedFileReader::new(fs::File::open(&file).unwrap()).unwrap();
+        let rows: Vec<Vec<(String, Field)>> = reader.get_row_iter(None).unwrap()
+            .map(|row| row.unwrap().into_columns())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        for (row, (key, value)) in rows.iter().zip([("a", "1"), ("b", "two")]) {
+            assert_eq!(row[..2], [("key".to_string(), Field::Str(key.to_string())), ("value".to_string(), Field::Str(value.to_string()))]);
+            // records set by this version know when they were written
+            assert!(matches!(row[2], (ref name, Field::TimestampMillis(_)) if name == "created_at"));
+            assert!(matches!(row[3], (ref name, Field::TimestampMillis(_)) if name == "updated_at"));
+        }
+
+        let imported = dsr(&ds, &["import", "--format", "parquet", &file]);
+        assert_eq!(imported.status.code(), Some(2));
+        assert!(stderr(&imported).contains("records can't be imported from parquet"));
+        fs::remove_file(&file).unwrap();
+    }
+}
+
+#[test]
+fn test_ls() {
+    let ds = datastore("ls");
+    let work = datastore("ls_work");
+    let dir = std::env::current_dir().unwrap();
+    let config_home = std::env::temp_dir().join(format!("dsr-cli-ls-{}", std::process::id()));
+    fs::create_dir_all(config_home.join("ds-rust")).unwrap();
+    // same is the datastore given with --ds, written another way
+    fs::write(config_home.join("ds-rust/config.toml"), format!(r#"
+        [profiles.work]
+        ds = "{}"
+
+        [profiles.same]
+        ds = "sqlite://{}"
+    "#, dir.join(&work).display(), dir.join(&ds).display())).unwrap();
+    let config = config_home.to_str().unwrap();
+    let dsr = |args: &[&str]| run(&[&["--ds", &ds], args].concat(), b"", &[("XDG_CONFIG_HOME", config)]);
+    assert!(dsr(&["set", "a", "1"]).status.success());
+    assert!(dsr(&["--store", "second", "set", "b", "2"]).status.success());
+    assert!(dsr(&["--store", "second", "set", "c", "3"]).status.success());
+    assert!(run(&["--ds", &work, "set", "d", "4"], b"", &[]).status.success());
+
+    assert_eq!(stdout(&dsr(&["ls"])), "second: 2 records\nstore: 1 records\n");
+    let default = config_home.join("ds-rust/ds.db");
+    let listed = stdout(&dsr(&["ls", "--all"]));
+    let lines: Vec<&str> = listed.lines().collect();
+    assert_eq!(lines[..6], [
+        format!("{} (same)", ds).as_str(), "  second: 2 records", "  store: 1 records",
+        format!("{} (work)", dir.join(&work).display()).as_str(), "  store: 1 records",
+        default.to_str().unwrap(),
+    ]);
+    // ls doesn't create the default datastore to list it
+    assert!(lines[6].starts_with("  couldn't open: "));
+    assert!(!default.exists());
+
+    let listed: serde_json::Value = serde_json::from_slice(&dsr(&["--output", "json", "ls", "--all"]).stdout).unwrap();
+    assert_eq!(listed[1], serde_json::json!({ "ds": dir.join(&work), "profiles": ["work"], "stores": [{ "store": "store", "records": 1 }] }));
+    assert!(listed[2]["error"].is_string());
+    fs::remove_dir_all(&config_home).unwrap();
+}
+
+#[test]
+fn test_record_format() {
+    let ds = datastore("record_format");
+    let tricky = "it's $HOME\nand a, comma";
+    assert!(dsr(&ds, &["set", "APP_NAME", "ds"]).status.success());
+    assert!(dsr(&ds, &["set", "APP_MOTD", tricky]).status.success());
+
+    // quoted values survive being eval'd by the shell
+    let exports = stdout(&dsr(&ds, &["records", "--prefix", "APP_", "--sort", "--format", "export {key}={value}", "--shell-quote"]));
+    assert_eq!(exports, "export APP_MOTD='it'\\''s $HOME\nand a, comma'\nexport APP_NAME=ds\n");
+    let evaluated = Command::new("sh").arg("-c").arg("eval \"$1\"; printf '%s|%s' \"$APP_NAME\" \"$APP_MOTD\"").arg("sh").arg(&exports).output().unwrap();
+    assert_eq!(String::from_utf8(evaluated.stdout).unwrap(), format!("ds|{}", tricky));
+
+    let delimited = dsr(&ds, &["records", "--sort", "--format", "{key}:{value}", "--null-delimited"]).stdout;
+    assert_eq!(delimited, format!("APP_MOTD:{}\0APP_NAME:ds\0", tricky).into_bytes());
+    assert_eq!(stdout(&dsr(&ds, &["get", "APP_NAME", "--format", "{{{key}}} {value}"])), "{APP_NAME} ds\n");
+    let updated = stdout(&dsr(&ds, &["get", "APP_NAME", "--format", "{updated_at}"]));
+    assert!(updated.ends_with("Z\n") && updated.len() == "2024-06-01T12:00:00Z\n".len());
+
+    let unknown = dsr(&ds, &["records", "--format", "{size}"]);
+    assert_eq!(unknown.status.code(), Some(2));
+    assert!(stderr(&unknown).contains("unknown field {size}"));
+    assert_eq!(dsr(&ds, &["records", "--shell-quote"]).status.code(), Some(2));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_completions() {
+    let ds = datastore("completions");
+    for key in ["app.a", "app.b", "svc:db"] {
+        assert!(dsr(&ds, &["set", key, "1"]).status.success());
+    }
+    assert!(dsr(&ds, &["--store", "second", "set", "other", "1"]).status.success());
+
+    // what bash offers for a line typed up to its end, running dsr from PATH as an installed one would be
+    let bin = PathBuf::from(env!("CARGO_BIN_EXE_dsr")).parent().unwrap().to_path_buf();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    let complete = |line: &str| {
+        let script = r#"source <(dsr completions bash); COMP_LINE=$LINE; COMP_POINT=${#COMP_LINE}; _dsr; printf '%s\n' "${COMPREPLY[@]}""#;
+        let home = std::env::temp_dir().join("dsr-cli-home");
+        let out = Command::new("bash").arg("-c").arg(script)
+            .env("PATH", &path)
+            .env("LINE", line)
+            .env("XDG_CONFIG_HOME", home.join(".config"))
+            .env_remove("DS_RUST_DB")
+            .env_remove("DS_RUST_STORE")
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    };
+    assert_eq!(complete(&format!("dsr --ds {} get app.", ds)), "app.a\napp.b\n");
+    // keys are completed with the store given and past the flags of other commands
+    assert_eq!(complete(&format!("dsr --ds {} --store second contains ", ds)), "other\n");
+    assert_eq!(complete(&format!("dsr --timing --ds {} delete a", ds)), "app.a\napp.b\n");
+    // bash splits words at colons, so only what follows the colon is offered
+    assert_eq!(complete(&format!("dsr --ds {} get svc:", ds)), "db\n");
+    assert_eq!(complete("dsr ge"), "get\ngetset\n");
+    assert_eq!(complete("dsr get --ra"), "--raw\n");
+    assert!(complete("dsr store ").lines().any(|line| line == "create"));
+
+    let zsh = stdout(&dsr(&ds, &["completions", "zsh"]));
+    assert!(zsh.starts_with("#compdef dsr\n") && zsh.contains("'get:Get the value of a record'"));
+    let fish = stdout(&dsr(&ds, &["completions", "fish"]));
+    assert!(fish.lines().any(|line| line.starts_with("complete -c dsr -n '__fish_seen_subcommand_from ") && line.contains(" get ") && line.ends_with("-f -a '(__dsr_keys)'")));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_edit() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let ds = datastore("edit");
+    // an editor running $EDIT on the file it's given
+    let editor = format!("{}.editor", ds);
+    fs::write(&editor, "#!/bin/sh\neval \"$EDIT\"\n").unwrap();
+    fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+    let editor = fs::canonicalize(&editor).unwrap();
+    let edit = |args: &[&str], script: &str| run(&[&["--ds", &ds, "edit"], args].concat(), b"", &[("VISUAL", editor.to_str().unwrap()), ("EDIT", script), ("DSR", env!("CARGO_BIN_EXE_dsr")), ("DS", &ds)]);
+
+    // the value is edited in place, keeping the record's TTL and content type
+    assert!(dsr(&ds, &["set", "doc", "draft", "--ttl", "600", "--content-type", "text/plain"]).status.success());
+    assert!(edit(&["doc"], r#"printf '%s, edited\n' "$(cat "$1")" > "$1""#).status.success());
+    let stat: serde_json::Value = serde_json::from_slice(&dsr(&ds, &["--output", "json", "stat", "doc"]).stdout).unwrap();
+    assert_eq!(stdout(&dsr(&ds, &["get", "doc"])), "draft, edited\n");
+    assert_eq!(stat["content_type"], "text/plain");
+    assert!(stat["ttl"].as_u64().unwrap() > 590);
+
+    assert_eq!(stdout(&edit(&["doc"], ":")), "doc was not changed\n");
+    assert_eq!(stdout(&edit(&["doc"], r#": > "$1""#)), "doc was not changed, the value was empty\n");
+    assert_eq!(stdout(&dsr(&ds, &["get", "doc"])), "draft, edited\n");
+
+    // a write made while the editor is open wins over the edit
+    let raced = edit(&["doc"], r#""$DSR" --ds "$DS" set doc theirs; printf mine > "$1""#);
+    assert_eq!(raced.status.code(), Some(1));
+    assert_eq!(stderr(&raced), "doc was changed while it was being edited and was not set\n");
+    assert_eq!(stdout(&dsr(&ds, &["get", "doc"])), "theirs\n");
+
+    assert_eq!(edit(&["new"], "printf created > \"$1\"").status.code(), Some(1));
+    assert!(edit(&["new", "--create"], "printf created > \"$1\"").status.success());
+    assert_eq!(stdout(&dsr(&ds, &["get", "new"])), "created\n");
+    assert!(run(&["--ds", &ds, "set", "bytes", "-"], &[0, 255], &[]).status.success());
+    let binary = edit(&["bytes"], "printf text > \"$1\"");
+    assert_eq!(binary.status.code(), Some(2));
+    assert!(stderr(&binary).contains("bytes isn't text and can't be edited"));
+    fs::remove_file(editor).unwrap();
+}
+
+#[test]
+fn test_diff() {
+    let ds = datastore("diff");
+    let copy = datastore("diff_copy");
+    for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+        assert!(dsr(&ds, &["set", key, value]).status.success());
+    }
+    let (json, csv) = (format!("{}.json", ds), format!("{}.csv", ds));
+    assert!(dsr(&ds, &["export", "--file", &json]).status.success());
+    assert!(dsr(&ds, &["export", "--format", "csv", "--file", &csv]).status.success());
+    fs::copy(&ds, &copy).unwrap();
+    assert!(dsr(&ds, &["set", "b", "20"]).status.success());
+    assert!(dsr(&ds, &["delete", "c"]).status.success());
+    assert!(dsr(&ds, &["set", "d", "4"]).status.success());
+
+    // against an export, in the format its name says, or another datastore file
+    let changes = "~ b\n- c\n+ d\n";
+    assert_eq!(stdout(&dsr(&ds, &["diff", &json])), changes);
+    assert_eq!(stdout(&dsr(&ds, &["diff", &csv])), changes);
+    assert_eq!(stdout(&dsr(&ds, &["diff", &copy])), changes);
+    assert_eq!(stdout(&dsr(&ds, &["diff", &json, "--values"])), "~ b\n  - 2\n  + 20\n- c\n  - 3\n+ d\n  + 4\n");
+    let diff: serde_json::Value = serde_json::from_slice(&dsr(&ds, &["--output", "json", "diff", &copy, "--values"]).stdout).unwrap();
+    assert_eq!(diff, serde_json::json!([
+        { "change": "~", "key": "b", "value": "20", "other_value": "2" },
+        { "change": "-", "key": "c", "value": null, "other_value": "3" },
+        { "change": "+", "key": "d", "value": "4", "other_value": null },
+    ]));
+
+    assert!(dsr(&ds, &["--store", "other", "set", "d", "4"]).status.success());
+    assert_eq!(stdout(&dsr(&ds, &["diff", "--against", "other"])), "+ a\n+ b\n");
+    assert_eq!(stdout(&dsr(&copy, &["diff", &json])), "");
+    assert_eq!(dsr(&ds, &["diff", &format!("{}.missing", ds)]).status.code(), Some(2));
+    fs::remove_file(json).unwrap();
+    fs::remove_file(csv).unwrap();
+}
+
+/// get, set, delete, contains and keys on server databases, checked when there's one to reach,
+/// given by the URL in DSR_TEST_POSTGRES_URL or DSR_TEST_MYSQL_URL
+#[cfg(any(feature = "postgres", feature = "mysql"))]
+#[test]
+fn test_remote_datastores() {
+    let databases = [
+        #[cfg(feature = "postgres")]
+        ("DSR_TEST_POSTGRES_URL", "PostgreSQL"),
+        #[cfg(feature = "mysql")]
+        ("DSR_TEST_MYSQL_URL", "MySQL"),
+    ];
+    for (var, name) in databases {
+        let Ok(url) = std::env::var(var) else { continue };
+        let dsr = |args: &[&str]| run(&[&["--ds", &url, "--store", "dsr_cli_test"], args].concat(), b"", &[]);
+        for key in ["app.name", "app.port", "other"] {
+            assert!(dsr(&["delete", key]).status.success());
+        }
+
+        assert!(dsr(&["set", "app.name", "ds"]).status.success());
+        assert!(dsr(&["set", "app.port", "8080", "--ttl", "60", "--type", "int"]).status.success());
+        assert_eq!(dsr(&["set", "other", "x", "--type", "int"]).status.code(), Some(2));
+        assert_eq!(stdout(&dsr(&["get", "app.name"])), "ds\n");
+        assert_eq!(stdout(&dsr(&["keys", "--prefix", "app."])), "app.name\napp.port\n");
+        assert_eq!(dsr(&["contains", "other"]).status.code(), Some(1));
+        assert!(dsr(&["delete", "app.name"]).status.success());
+        assert_eq!(stderr(&dsr(&["get", "app.name"])), "app.name not found\n");
+        assert_eq!(stdout(&dsr(&["get", "app.name", "--default", "none"])), "none\n");
+
+        let unsupported = dsr(&["incr", "count"]);
+        assert_eq!(unsupported.status.code(), Some(2));
+        assert!(stderr(&unsupported).contains(&format!("work with a {} datastore", name)));
+        assert!(dsr(&["delete", "app.port"]).status.success());
+    }
+}